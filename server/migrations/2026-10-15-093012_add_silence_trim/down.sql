@@ -0,0 +1,2 @@
+ALTER TABLE videos DROP COLUMN silence_trim_start;
+ALTER TABLE videos DROP COLUMN silence_trim_end;
@@ -0,0 +1,2 @@
+ALTER TABLE videos ADD COLUMN silence_trim_start FLOAT;
+ALTER TABLE videos ADD COLUMN silence_trim_end FLOAT;
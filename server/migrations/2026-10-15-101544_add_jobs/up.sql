@@ -0,0 +1,14 @@
+CREATE TABLE jobs (
+       	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+       	stage VARCHAR NOT NULL,
+       	status VARCHAR NOT NULL,
+       	user_id VARCHAR NOT NULL,
+       	video_hash VARCHAR,
+       	src_file VARCHAR NOT NULL,
+       	dst VARCHAR,
+       	video_bitrate INTEGER,
+       	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+       	updated DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+       	details VARCHAR NOT NULL DEFAULT ''
+);
+CREATE INDEX ix_job_status ON jobs (status);
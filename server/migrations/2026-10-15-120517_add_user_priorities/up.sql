@@ -0,0 +1,4 @@
+CREATE TABLE user_priorities (
+    user_id VARCHAR(255) NOT NULL PRIMARY KEY,
+    priority INTEGER NOT NULL DEFAULT 0
+);
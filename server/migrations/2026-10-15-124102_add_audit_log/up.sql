@@ -0,0 +1,10 @@
+CREATE TABLE audit_log (
+       	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+       	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+       	user_id VARCHAR NOT NULL,
+       	action VARCHAR NOT NULL,
+       	ref_video_hash VARCHAR,
+       	details VARCHAR NOT NULL DEFAULT ''
+);
+CREATE INDEX ix_audit_log_created ON audit_log (created);
+CREATE INDEX ix_audit_log_ref_video_hash ON audit_log (ref_video_hash);
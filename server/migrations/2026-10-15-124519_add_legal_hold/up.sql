@@ -0,0 +1 @@
+ALTER TABLE videos ADD COLUMN legal_hold BOOLEAN NOT NULL DEFAULT 0;
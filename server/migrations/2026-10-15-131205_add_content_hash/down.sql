@@ -0,0 +1,2 @@
+DROP INDEX videos_content_hash;
+ALTER TABLE videos DROP COLUMN content_hash;
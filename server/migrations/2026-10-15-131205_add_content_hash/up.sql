@@ -0,0 +1,2 @@
+ALTER TABLE videos ADD COLUMN content_hash TEXT;
+CREATE INDEX videos_content_hash ON videos(content_hash);
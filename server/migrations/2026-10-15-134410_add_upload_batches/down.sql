@@ -0,0 +1,2 @@
+DROP TABLE upload_batch_files;
+DROP TABLE upload_batches;
@@ -0,0 +1,19 @@
+CREATE TABLE upload_batches (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id VARCHAR NOT NULL,
+	title VARCHAR NOT NULL DEFAULT '',
+	cancelled BOOLEAN NOT NULL DEFAULT 0,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	finished DATETIME
+);
+CREATE TABLE upload_batch_files (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	batch_id INTEGER NOT NULL REFERENCES upload_batches(id) ON DELETE CASCADE,
+	filename VARCHAR NOT NULL,
+	status VARCHAR NOT NULL,
+	src_file VARCHAR,
+	video_hash VARCHAR,
+	details VARCHAR NOT NULL DEFAULT ''
+);
+CREATE INDEX ix_upload_batch_files_batch ON upload_batch_files (batch_id);
+CREATE INDEX ix_upload_batch_files_src ON upload_batch_files (src_file);
@@ -0,0 +1,10 @@
+CREATE TABLE subtitles (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	video_hash VARCHAR NOT NULL,
+	language VARCHAR,
+	title VARCHAR NOT NULL DEFAULT '',
+	origin VARCHAR NOT NULL,
+	added DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	FOREIGN KEY(video_hash) REFERENCES videos (video_hash)
+);
+CREATE INDEX ix_subtitles_video_hash ON subtitles (video_hash);
@@ -0,0 +1,2 @@
+ALTER TABLE videos DROP COLUMN loudnorm_target;
+ALTER TABLE jobs DROP COLUMN loudnorm;
@@ -0,0 +1,2 @@
+ALTER TABLE videos ADD COLUMN loudnorm_target FLOAT;
+ALTER TABLE jobs ADD COLUMN loudnorm VARCHAR;
@@ -0,0 +1,9 @@
+CREATE TABLE video_sources (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	video_hash VARCHAR NOT NULL,
+	source_video_hash VARCHAR NOT NULL,
+	position INTEGER NOT NULL,
+	FOREIGN KEY(video_hash) REFERENCES videos (video_hash)
+);
+CREATE INDEX ix_video_sources_video_hash ON video_sources (video_hash);
+CREATE INDEX ix_video_sources_source ON video_sources (source_video_hash);
@@ -0,0 +1,3 @@
+ALTER TABLE videos DROP COLUMN color_primaries;
+ALTER TABLE videos DROP COLUMN color_transfer;
+ALTER TABLE videos DROP COLUMN hdr_format;
@@ -0,0 +1,3 @@
+ALTER TABLE videos ADD COLUMN color_primaries VARCHAR;
+ALTER TABLE videos ADD COLUMN color_transfer VARCHAR;
+ALTER TABLE videos ADD COLUMN hdr_format VARCHAR;
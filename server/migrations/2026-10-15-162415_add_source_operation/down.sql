@@ -0,0 +1 @@
+ALTER TABLE video_sources DROP COLUMN operation;
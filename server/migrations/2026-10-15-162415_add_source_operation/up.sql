@@ -0,0 +1 @@
+ALTER TABLE video_sources ADD COLUMN operation VARCHAR NOT NULL DEFAULT '';
@@ -0,0 +1,3 @@
+ALTER TABLE videos DROP COLUMN vfr_fps_min;
+ALTER TABLE videos DROP COLUMN vfr_fps_max;
+ALTER TABLE videos DROP COLUMN vfr_fps_avg;
@@ -0,0 +1,3 @@
+ALTER TABLE videos ADD COLUMN vfr_fps_min FLOAT;
+ALTER TABLE videos ADD COLUMN vfr_fps_max FLOAT;
+ALTER TABLE videos ADD COLUMN vfr_fps_avg FLOAT;
@@ -0,0 +1 @@
+ALTER TABLE videos ADD COLUMN image_sequence VARCHAR;
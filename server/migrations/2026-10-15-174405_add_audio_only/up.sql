@@ -0,0 +1 @@
+ALTER TABLE videos ADD COLUMN audio_only BOOLEAN NOT NULL DEFAULT 0;
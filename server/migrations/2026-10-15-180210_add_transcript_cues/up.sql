@@ -0,0 +1,12 @@
+CREATE TABLE transcript_cues (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	subtitle_id INTEGER NOT NULL,
+	video_hash VARCHAR NOT NULL,
+	language VARCHAR,
+	start_time FLOAT NOT NULL,
+	end_time FLOAT NOT NULL,
+	text VARCHAR NOT NULL,
+	FOREIGN KEY(subtitle_id) REFERENCES subtitles (id)
+);
+CREATE INDEX ix_transcript_cues_subtitle_id ON transcript_cues (subtitle_id);
+CREATE INDEX ix_transcript_cues_video_hash ON transcript_cues (video_hash);
@@ -0,0 +1,4 @@
+ALTER TABLE comments DROP COLUMN region;
+ALTER TABLE comments DROP COLUMN page;
+ALTER TABLE videos DROP COLUMN page_count;
+ALTER TABLE videos DROP COLUMN still_kind;
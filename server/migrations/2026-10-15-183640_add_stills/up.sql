@@ -0,0 +1,4 @@
+ALTER TABLE videos ADD COLUMN still_kind VARCHAR;
+ALTER TABLE videos ADD COLUMN page_count INTEGER;
+ALTER TABLE comments ADD COLUMN page INTEGER;
+ALTER TABLE comments ADD COLUMN region VARCHAR;
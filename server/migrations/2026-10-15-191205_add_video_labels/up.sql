@@ -0,0 +1,11 @@
+CREATE TABLE video_labels (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	video_hash VARCHAR NOT NULL,
+	kind VARCHAR NOT NULL,
+	label VARCHAR NOT NULL,
+	start_time FLOAT NOT NULL,
+	end_time FLOAT NOT NULL,
+	confidence FLOAT,
+	FOREIGN KEY(video_hash) REFERENCES videos (video_hash)
+);
+CREATE INDEX ix_video_labels_video_hash ON video_labels (video_hash);
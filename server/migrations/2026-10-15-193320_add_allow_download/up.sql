@@ -0,0 +1 @@
+ALTER TABLE videos ADD COLUMN allow_download BOOLEAN NOT NULL DEFAULT 1;
@@ -0,0 +1,3 @@
+DROP TABLE overlay_presets;
+ALTER TABLE videos DROP COLUMN folder_id;
+DROP TABLE folders;
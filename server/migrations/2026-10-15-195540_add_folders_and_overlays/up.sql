@@ -0,0 +1,25 @@
+CREATE TABLE folders (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id VARCHAR NOT NULL,
+	title VARCHAR NOT NULL,
+	parent_id INTEGER REFERENCES folders(id),
+	overlay_defaults VARCHAR,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+CREATE INDEX ix_folders_user ON folders (user_id);
+ALTER TABLE videos ADD COLUMN folder_id INTEGER REFERENCES folders(id);
+
+CREATE TABLE overlay_presets (
+	name VARCHAR NOT NULL PRIMARY KEY,
+	title VARCHAR NOT NULL,
+	kind VARCHAR NOT NULL,
+	value REAL NOT NULL,
+	default_on BOOLEAN NOT NULL DEFAULT 0
+);
+INSERT INTO overlay_presets (name, title, kind, value, default_on) VALUES
+	('action_safe', 'Action safe (93%)', 'safe_area', 0.035, 0),
+	('title_safe', 'Title safe (90%)', 'safe_area', 0.05, 1),
+	('center_cut_4x3', '4:3 center cut', 'aspect', 1.3333, 0),
+	('social_9x16', '9:16 vertical (Stories, Reels, Shorts)', 'aspect', 0.5625, 0),
+	('social_4x5', '4:5 portrait feed', 'aspect', 0.8, 0),
+	('social_1x1', '1:1 square', 'aspect', 1.0, 0);
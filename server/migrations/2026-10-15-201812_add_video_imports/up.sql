@@ -0,0 +1,9 @@
+CREATE TABLE video_imports (
+	video_hash VARCHAR NOT NULL PRIMARY KEY,
+	origin VARCHAR NOT NULL,
+	origin_video_hash VARCHAR NOT NULL,
+	imported_by VARCHAR NOT NULL,
+	created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	FOREIGN KEY(video_hash) REFERENCES videos (video_hash)
+);
+CREATE INDEX ix_video_imports_origin ON video_imports (origin, origin_video_hash);
@@ -0,0 +1,3 @@
+DROP TABLE federated_objects;
+DROP TABLE folder_syncs;
+DROP TABLE federation_peers;
@@ -0,0 +1,31 @@
+CREATE TABLE federation_peers (
+	name VARCHAR NOT NULL PRIMARY KEY,
+	url VARCHAR NOT NULL,
+	secret VARCHAR NOT NULL,
+	self_name VARCHAR NOT NULL,
+	created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE folder_syncs (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	folder_id INTEGER NOT NULL,
+	peer VARCHAR NOT NULL,
+	remote_folder_id INTEGER NOT NULL,
+	cursor BIGINT NOT NULL DEFAULT 0,
+	last_sync TIMESTAMP,
+	last_error VARCHAR,
+	FOREIGN KEY(folder_id) REFERENCES folders (id),
+	FOREIGN KEY(peer) REFERENCES federation_peers (name),
+	UNIQUE(folder_id, peer)
+);
+
+CREATE TABLE federated_objects (
+	peer VARCHAR NOT NULL,
+	kind VARCHAR NOT NULL,
+	remote_id VARCHAR NOT NULL,
+	local_id VARCHAR NOT NULL,
+	pending_folder_id INTEGER,
+	created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY(peer, kind, remote_id)
+);
+CREATE INDEX ix_federated_objects_local ON federated_objects (peer, kind, local_id);
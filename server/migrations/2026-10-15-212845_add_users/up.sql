@@ -0,0 +1,10 @@
+CREATE TABLE users (
+	user_id VARCHAR(255) NOT NULL PRIMARY KEY,
+	username VARCHAR NOT NULL,
+	is_admin BOOLEAN NOT NULL DEFAULT 0,
+	disabled BOOLEAN NOT NULL DEFAULT 0,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	last_seen DATETIME
+);
+-- User called 'admin' has been the administrator so far
+INSERT INTO users (user_id, username, is_admin) VALUES ('admin', 'Administrator', 1);
@@ -0,0 +1,3 @@
+DROP INDEX messages_created;
+DROP INDEX audit_log_created;
+DROP TABLE job_stats;
@@ -0,0 +1,11 @@
+-- Daily totals of finished jobs, kept after the jobs themselves expire (see --job-retention)
+CREATE TABLE job_stats (
+	day DATE NOT NULL,
+	stage VARCHAR(64) NOT NULL,
+	status VARCHAR(64) NOT NULL,
+	n_jobs INTEGER NOT NULL DEFAULT 0,
+	total_secs BIGINT NOT NULL DEFAULT 0,
+	PRIMARY KEY (day, stage, status)
+);
+CREATE INDEX audit_log_created ON audit_log (created);
+CREATE INDEX messages_created ON messages (created);
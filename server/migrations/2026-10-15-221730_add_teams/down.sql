@@ -0,0 +1,4 @@
+DROP TABLE team_folders;
+DROP TABLE team_videos;
+DROP TABLE team_members;
+DROP TABLE teams;
@@ -0,0 +1,27 @@
+CREATE TABLE teams (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	name VARCHAR NOT NULL UNIQUE,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+
+CREATE TABLE team_members (
+	team_id INTEGER NOT NULL REFERENCES teams(id),
+	user_id VARCHAR NOT NULL,
+	is_team_admin BOOLEAN NOT NULL DEFAULT 0,
+	PRIMARY KEY (team_id, user_id)
+);
+CREATE INDEX ix_team_members_user ON team_members (user_id);
+
+-- Videos and folders shared with a team (folders include their subfolders)
+CREATE TABLE team_videos (
+	team_id INTEGER NOT NULL REFERENCES teams(id),
+	video_hash VARCHAR NOT NULL REFERENCES videos(video_hash),
+	shared_by VARCHAR NOT NULL,
+	PRIMARY KEY (team_id, video_hash)
+);
+CREATE TABLE team_folders (
+	team_id INTEGER NOT NULL REFERENCES teams(id),
+	folder_id INTEGER NOT NULL REFERENCES folders(id),
+	shared_by VARCHAR NOT NULL,
+	PRIMARY KEY (team_id, folder_id)
+);
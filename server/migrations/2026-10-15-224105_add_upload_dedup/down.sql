@@ -0,0 +1,2 @@
+DROP TABLE video_links;
+DROP TABLE pending_uploads;
@@ -0,0 +1,19 @@
+-- Uploads identical to a video the user can see, waiting for user to choose
+-- between linking the existing video and storing a copy
+CREATE TABLE pending_uploads (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id VARCHAR NOT NULL,
+	src_file VARCHAR NOT NULL,
+	duplicate_of VARCHAR NOT NULL REFERENCES videos(video_hash),
+	loudnorm VARCHAR,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+CREATE INDEX ix_pending_uploads_user ON pending_uploads (user_id);
+
+-- Other users' videos listed among user's own
+CREATE TABLE video_links (
+	user_id VARCHAR NOT NULL,
+	video_hash VARCHAR NOT NULL REFERENCES videos(video_hash),
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	PRIMARY KEY (user_id, video_hash)
+);
@@ -0,0 +1,12 @@
+-- Tokenized links for viewing a video without an account
+CREATE TABLE share_links (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	token VARCHAR NOT NULL UNIQUE,
+	video_hash VARCHAR NOT NULL REFERENCES videos(video_hash),
+	created_by VARCHAR NOT NULL,
+	allow_comments BOOLEAN NOT NULL DEFAULT 0,
+	password_hash VARCHAR,
+	expires DATETIME,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+CREATE INDEX ix_share_links_video ON share_links (video_hash);
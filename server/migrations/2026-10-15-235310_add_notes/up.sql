@@ -0,0 +1,13 @@
+-- Private notes on videos, visible only to their author until published as comments
+CREATE TABLE notes (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	video_hash VARCHAR NOT NULL REFERENCES videos(video_hash),
+	user_id VARCHAR NOT NULL,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	edited DATETIME,
+	note VARCHAR NOT NULL,
+	timecode VARCHAR,
+	page INTEGER,
+	region VARCHAR
+);
+CREATE INDEX ix_notes_video_user ON notes (video_hash, user_id);
@@ -0,0 +1,2 @@
+-- 'internal' comments are only shown to the owner's side (see models::comment_visibility)
+ALTER TABLE comments ADD COLUMN visibility VARCHAR NOT NULL DEFAULT 'external';
@@ -0,0 +1,10 @@
+-- Identities of share link guests. Their user ID is 'guest:<id>'.
+CREATE TABLE guests (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	share_link_id INTEGER NOT NULL,
+	name VARCHAR NOT NULL,
+	key VARCHAR NOT NULL UNIQUE,
+	promoted_to VARCHAR,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+CREATE INDEX ix_guests_link ON guests (share_link_id);
@@ -0,0 +1 @@
+DROP TABLE closed_reviews;
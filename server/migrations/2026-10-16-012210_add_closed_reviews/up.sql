@@ -0,0 +1,6 @@
+-- Videos whose review round is closed: no new comments until the owner reopens it
+CREATE TABLE closed_reviews (
+    video_hash VARCHAR NOT NULL PRIMARY KEY,
+    closed_by VARCHAR NOT NULL,
+    closed TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
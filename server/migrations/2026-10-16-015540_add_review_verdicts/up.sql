@@ -0,0 +1,11 @@
+-- Reviewers' verdicts on videos (see models::review_verdict), one per reviewer
+CREATE TABLE review_verdicts (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	video_hash VARCHAR NOT NULL,
+	user_id VARCHAR NOT NULL,
+	username VARCHAR NOT NULL,
+	verdict VARCHAR NOT NULL,
+	note VARCHAR,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	UNIQUE (video_hash, user_id)
+);
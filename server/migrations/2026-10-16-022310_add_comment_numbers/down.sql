@@ -0,0 +1,2 @@
+DROP TABLE comment_numbers;
+ALTER TABLE comments DROP COLUMN number;
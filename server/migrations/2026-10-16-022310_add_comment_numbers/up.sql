@@ -0,0 +1,10 @@
+-- Human readable number of a comment within its video ("#12"). Not reused after deletion.
+ALTER TABLE comments ADD COLUMN number INTEGER NOT NULL DEFAULT 0;
+UPDATE comments SET number = (SELECT COUNT(*) FROM comments AS c WHERE c.video_hash = comments.video_hash AND c.id <= comments.id);
+
+-- Last comment number given out on each video
+CREATE TABLE comment_numbers (
+	video_hash VARCHAR NOT NULL PRIMARY KEY,
+	last_number INTEGER NOT NULL
+);
+INSERT INTO comment_numbers (video_hash, last_number) SELECT video_hash, MAX(number) FROM comments GROUP BY video_hash;
@@ -0,0 +1,9 @@
+-- Free-form tags on videos (e.g. show, episode, department). Case-insensitive.
+CREATE TABLE video_tags (
+	video_hash VARCHAR NOT NULL,
+	tag VARCHAR NOT NULL COLLATE NOCASE,
+	added_by VARCHAR NOT NULL,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	PRIMARY KEY (video_hash, tag)
+);
+CREATE INDEX ix_video_tags_tag ON video_tags (tag);
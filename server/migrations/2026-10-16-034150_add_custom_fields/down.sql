@@ -0,0 +1,2 @@
+DROP TABLE video_custom_fields;
+DROP TABLE custom_fields;
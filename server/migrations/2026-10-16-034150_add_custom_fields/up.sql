@@ -0,0 +1,13 @@
+-- Admin-defined metadata fields (e.g. "Shot code", "Department") filled per video
+CREATE TABLE custom_fields (
+	name VARCHAR NOT NULL PRIMARY KEY,
+	title VARCHAR NOT NULL,
+	field_type VARCHAR NOT NULL,
+	options VARCHAR
+);
+
+-- Values of custom fields for a video, as a JSON object (field name -> value)
+CREATE TABLE video_custom_fields (
+	video_hash VARCHAR NOT NULL PRIMARY KEY,
+	field_values VARCHAR NOT NULL DEFAULT '{}'
+);
@@ -0,0 +1,2 @@
+DROP INDEX ix_users_search_key;
+ALTER TABLE users DROP COLUMN search_key;
@@ -0,0 +1,3 @@
+-- Lowercase, diacritic-free name and ID for user lookup (filled in by server on startup)
+ALTER TABLE users ADD COLUMN search_key VARCHAR NOT NULL DEFAULT '';
+CREATE INDEX ix_users_search_key ON users (search_key);
@@ -0,0 +1,3 @@
+-- When user got the first login onboarding. Users that have logged in already count as onboarded.
+ALTER TABLE users ADD COLUMN onboarded DATETIME;
+UPDATE users SET onboarded = last_seen WHERE last_seen IS NOT NULL;
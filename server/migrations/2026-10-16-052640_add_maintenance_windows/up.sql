@@ -0,0 +1,10 @@
+-- Scheduled maintenance, shown on public status page
+CREATE TABLE maintenance_windows (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	starts DATETIME NOT NULL,
+	ends DATETIME NOT NULL,
+	message VARCHAR NOT NULL DEFAULT '',
+	created_by VARCHAR NOT NULL,
+	created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX maintenance_windows_ends ON maintenance_windows (ends);
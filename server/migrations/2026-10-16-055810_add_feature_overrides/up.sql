@@ -0,0 +1,10 @@
+-- Admin's feature flag settings, overriding the --features config.
+-- Target is "*" (everyone), "user:<user id>" or "team:<team id>".
+CREATE TABLE feature_overrides (
+	feature VARCHAR NOT NULL,
+	target VARCHAR NOT NULL,
+	enabled BOOLEAN NOT NULL,
+	set_by VARCHAR NOT NULL,
+	created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY (feature, target)
+);
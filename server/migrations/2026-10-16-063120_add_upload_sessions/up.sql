@@ -0,0 +1,14 @@
+-- Uploads that can be paused and resumed (see api_server::upload_sessions).
+-- Received data is kept in a partial file until total_size bytes have arrived.
+CREATE TABLE upload_sessions (
+	id VARCHAR NOT NULL PRIMARY KEY,
+	user_id VARCHAR NOT NULL,
+	filename VARCHAR NOT NULL,
+	total_size BIGINT NOT NULL,
+	received BIGINT NOT NULL DEFAULT 0,
+	status VARCHAR NOT NULL,
+	loudnorm VARCHAR,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	updated DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+CREATE INDEX ix_upload_sessions_user ON upload_sessions (user_id);
@@ -0,0 +1 @@
+DROP TABLE throughput_stats;
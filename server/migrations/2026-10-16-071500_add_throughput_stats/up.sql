@@ -0,0 +1,12 @@
+-- Hourly pipeline throughput, for capacity planning (see api_server::capacity).
+-- Sums over jobs finished during the hour: seconds of video processed, seconds
+-- workers spent processing, and seconds jobs waited in queue before that.
+CREATE TABLE throughput_stats (
+	hour DATETIME NOT NULL,
+	stage VARCHAR NOT NULL,
+	n_jobs INTEGER NOT NULL DEFAULT 0,
+	video_secs DOUBLE NOT NULL DEFAULT 0,
+	work_secs DOUBLE NOT NULL DEFAULT 0,
+	wait_secs DOUBLE NOT NULL DEFAULT 0,
+	PRIMARY KEY (hour, stage)
+);
@@ -0,0 +1,2 @@
+DROP TABLE collab_participants;
+DROP TABLE collab_rooms;
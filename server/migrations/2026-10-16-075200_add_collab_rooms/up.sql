@@ -0,0 +1,18 @@
+-- Live collaborative review sessions (see api_server::collab_state), kept in the DB so that
+-- another server instance can take over a collab when the one hosting it goes down.
+CREATE TABLE collab_rooms (
+	collab_id VARCHAR NOT NULL PRIMARY KEY,
+	video_hash VARCHAR NOT NULL,
+	host VARCHAR NOT NULL,
+	paused BOOLEAN NOT NULL DEFAULT 1,
+	seek_time DOUBLE,
+	page INTEGER,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	updated DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+CREATE TABLE collab_participants (
+	collab_id VARCHAR NOT NULL,
+	user_id VARCHAR NOT NULL,
+	user_name VARCHAR NOT NULL,
+	PRIMARY KEY (collab_id, user_id)
+);
@@ -0,0 +1,7 @@
+-- Users mentioned (@user) in comments, see api_server::mentions
+CREATE TABLE comment_mentions (
+	comment_id INTEGER NOT NULL,
+	user_id VARCHAR NOT NULL,
+	PRIMARY KEY (comment_id, user_id)
+);
+CREATE INDEX ix_comment_mentions_user ON comment_mentions (user_id);
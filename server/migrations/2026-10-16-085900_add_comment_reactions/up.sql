@@ -0,0 +1,8 @@
+-- Emoji reactions to comments, one of each emoji per user
+CREATE TABLE comment_reactions (
+	comment_id INTEGER NOT NULL,
+	user_id VARCHAR NOT NULL,
+	emoji VARCHAR NOT NULL,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	PRIMARY KEY (comment_id, user_id, emoji)
+);
@@ -0,0 +1,10 @@
+-- Earlier texts of edited comments, see DB::edit_comment
+CREATE TABLE comment_revisions (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	comment_id INTEGER NOT NULL,
+	comment VARCHAR NOT NULL,
+	written DATETIME NOT NULL,
+	replaced DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	replaced_by VARCHAR
+);
+CREATE INDEX ix_comment_revisions_comment ON comment_revisions (comment_id);
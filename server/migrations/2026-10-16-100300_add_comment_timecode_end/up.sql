@@ -0,0 +1,2 @@
+-- End of the time range a comment is about (in/out), `timecode` being the start
+ALTER TABLE comments ADD COLUMN timecode_end VARCHAR;
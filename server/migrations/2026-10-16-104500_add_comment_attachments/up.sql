@@ -0,0 +1,12 @@
+-- Files attached to comments, stored in <videos>/<video_hash>/attachments/<id>/
+CREATE TABLE comment_attachments (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	comment_id INTEGER NOT NULL,
+	video_hash VARCHAR NOT NULL,
+	user_id VARCHAR NOT NULL,
+	filename VARCHAR NOT NULL,
+	mime_type VARCHAR NOT NULL,
+	size INTEGER NOT NULL,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+CREATE INDEX ix_comment_attachments_comment ON comment_attachments (comment_id);
@@ -0,0 +1,2 @@
+DROP INDEX ix_video_added_by_userid_time;
+DROP INDEX ix_comment_video_hash;
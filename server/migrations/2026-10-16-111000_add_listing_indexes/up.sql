@@ -0,0 +1,3 @@
+-- For paged video and comment listings (DB::get_user_videos_page, DB::get_video_comments_page)
+CREATE INDEX ix_comment_video_hash ON comments (video_hash);
+CREATE INDEX ix_video_added_by_userid_time ON videos (added_by_userid, added_time);
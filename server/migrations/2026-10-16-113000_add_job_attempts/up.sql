@@ -0,0 +1,11 @@
+-- Transcode attempts of a job, see video_compressor::TRANSCODE_ATTEMPTS
+CREATE TABLE job_attempts (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	job_id INTEGER NOT NULL,
+	attempt INTEGER NOT NULL,
+	settings VARCHAR NOT NULL,
+	success BOOLEAN NOT NULL,
+	stderr VARCHAR NOT NULL,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+CREATE INDEX ix_job_attempts_job ON job_attempts (job_id);
@@ -0,0 +1,2 @@
+-- Transcode preset (see video_pipeline::transcode_presets) for videos uploaded into the folder. NULL inherits from parent.
+ALTER TABLE folders ADD COLUMN transcode_preset VARCHAR;
@@ -0,0 +1,8 @@
+-- How the transcoded (proxy) video was encoded, as JSON (see video_compressor::TranscodeSettings::encoding_json).
+-- Separate from videos, which is at diesel's column limit.
+CREATE TABLE proxy_encodings (
+	video_hash VARCHAR NOT NULL PRIMARY KEY,
+	encoding VARCHAR NOT NULL,
+	created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	FOREIGN KEY(video_hash) REFERENCES videos (video_hash)
+);
@@ -0,0 +1,2 @@
+-- Overlays to burn in (JSON of video_pipeline::burn_in::BurnIn), so they're kept when the job is resumed after restart
+ALTER TABLE jobs ADD COLUMN burn_in VARCHAR;
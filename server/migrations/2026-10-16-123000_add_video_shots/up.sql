@@ -0,0 +1,9 @@
+CREATE TABLE video_shots (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	video_hash VARCHAR NOT NULL,
+	start_time FLOAT NOT NULL,
+	end_time FLOAT NOT NULL,
+	thumb VARCHAR,
+	FOREIGN KEY(video_hash) REFERENCES videos (video_hash)
+);
+CREATE INDEX ix_video_shots_video_hash ON video_shots (video_hash);
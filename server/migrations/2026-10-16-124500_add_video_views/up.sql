@@ -0,0 +1,7 @@
+-- How far each user has read the comments of a video
+CREATE TABLE video_views (
+	user_id VARCHAR NOT NULL,
+	video_hash VARCHAR NOT NULL REFERENCES videos(video_hash),
+	seen_until DATETIME NOT NULL,
+	PRIMARY KEY (user_id, video_hash)
+);
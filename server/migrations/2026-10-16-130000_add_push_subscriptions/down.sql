@@ -0,0 +1,2 @@
+DROP TABLE push_prefs;
+DROP TABLE push_subscriptions;
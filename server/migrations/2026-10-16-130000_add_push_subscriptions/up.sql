@@ -0,0 +1,17 @@
+-- Browsers' Web Push subscriptions (one per browser profile, see api_server::web_push)
+CREATE TABLE push_subscriptions (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	user_id VARCHAR NOT NULL,
+	endpoint VARCHAR NOT NULL UNIQUE,
+	p256dh VARCHAR NOT NULL,
+	auth VARCHAR NOT NULL,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+CREATE INDEX ix_push_subscriptions_user_id ON push_subscriptions (user_id);
+-- Which events users want pushed. No row = nothing.
+CREATE TABLE push_prefs (
+	user_id VARCHAR NOT NULL PRIMARY KEY,
+	mentions BOOLEAN NOT NULL DEFAULT 0,
+	comments BOOLEAN NOT NULL DEFAULT 0,
+	processing BOOLEAN NOT NULL DEFAULT 0
+);
@@ -0,0 +1,2 @@
+DROP TABLE tracker_outbox;
+DROP TABLE tracker_notes;
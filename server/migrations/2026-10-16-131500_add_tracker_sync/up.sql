@@ -0,0 +1,20 @@
+-- Comments pushed to a production tracker (ShotGrid, ftrack) as notes, and note replies
+-- pulled back as comments (see api_server::tracker_sync)
+CREATE TABLE tracker_notes (
+	comment_id INTEGER NOT NULL PRIMARY KEY,
+	video_hash VARCHAR NOT NULL,
+	tracker VARCHAR NOT NULL,
+	remote_id VARCHAR NOT NULL,
+	reply_to VARCHAR,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	UNIQUE (tracker, remote_id)
+);
+-- Comments waiting to be pushed, retried with backoff
+CREATE TABLE tracker_outbox (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	comment_id INTEGER NOT NULL UNIQUE,
+	video_hash VARCHAR NOT NULL,
+	attempts INTEGER NOT NULL DEFAULT 0,
+	next_attempt DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	last_error VARCHAR
+);
@@ -0,0 +1,2 @@
+DROP TABLE review_invitees;
+DROP TABLE review_sessions;
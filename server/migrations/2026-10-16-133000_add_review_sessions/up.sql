@@ -0,0 +1,20 @@
+-- Collab sessions scheduled for a later time, with invited users (see api_server::review_schedule).
+-- The collab room is opened when a session starts.
+CREATE TABLE review_sessions (
+	id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	collab_id VARCHAR NOT NULL UNIQUE,
+	video_hash VARCHAR NOT NULL,
+	host VARCHAR NOT NULL,
+	host_name VARCHAR NOT NULL,
+	title VARCHAR NOT NULL,
+	starts DATETIME NOT NULL,
+	duration_min INTEGER NOT NULL,
+	opened DATETIME,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+CREATE INDEX review_sessions_starts ON review_sessions (starts);
+CREATE TABLE review_invitees (
+	session_id INTEGER NOT NULL,
+	user_id VARCHAR NOT NULL,
+	PRIMARY KEY (session_id, user_id)
+);
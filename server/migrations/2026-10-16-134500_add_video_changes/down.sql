@@ -0,0 +1,16 @@
+DROP TRIGGER video_changes_videos_insert;
+DROP TRIGGER video_changes_videos_update;
+DROP TRIGGER video_changes_videos_delete;
+DROP TRIGGER video_changes_video_tags_insert;
+DROP TRIGGER video_changes_video_tags_delete;
+DROP TRIGGER video_changes_video_links_insert;
+DROP TRIGGER video_changes_video_links_delete;
+DROP TRIGGER video_changes_comments_insert;
+DROP TRIGGER video_changes_comments_update;
+DROP TRIGGER video_changes_comments_delete;
+DROP TRIGGER video_changes_video_views_insert;
+DROP TRIGGER video_changes_video_views_update;
+DROP TRIGGER video_changes_video_custom_fields_insert;
+DROP TRIGGER video_changes_video_custom_fields_update;
+DROP TRIGGER video_changes_video_custom_fields_delete;
+DROP TABLE video_changes;
@@ -0,0 +1,23 @@
+-- Journal of changes that affect video listings, for pushing them to subscribed clients as deltas
+-- (see api_server::video_list_sub). Filled by triggers, so changes from all writers get in.
+CREATE TABLE video_changes (
+	seq INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	video_hash VARCHAR NOT NULL,
+	created DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL
+);
+CREATE INDEX video_changes_created ON video_changes (created);
+CREATE TRIGGER video_changes_videos_insert AFTER INSERT ON videos BEGIN INSERT INTO video_changes (video_hash) VALUES (NEW.video_hash); END;
+CREATE TRIGGER video_changes_videos_update AFTER UPDATE ON videos BEGIN INSERT INTO video_changes (video_hash) VALUES (NEW.video_hash); END;
+CREATE TRIGGER video_changes_videos_delete AFTER DELETE ON videos BEGIN INSERT INTO video_changes (video_hash) VALUES (OLD.video_hash); END;
+CREATE TRIGGER video_changes_video_tags_insert AFTER INSERT ON video_tags BEGIN INSERT INTO video_changes (video_hash) VALUES (NEW.video_hash); END;
+CREATE TRIGGER video_changes_video_tags_delete AFTER DELETE ON video_tags BEGIN INSERT INTO video_changes (video_hash) VALUES (OLD.video_hash); END;
+CREATE TRIGGER video_changes_video_links_insert AFTER INSERT ON video_links BEGIN INSERT INTO video_changes (video_hash) VALUES (NEW.video_hash); END;
+CREATE TRIGGER video_changes_video_links_delete AFTER DELETE ON video_links BEGIN INSERT INTO video_changes (video_hash) VALUES (OLD.video_hash); END;
+CREATE TRIGGER video_changes_comments_insert AFTER INSERT ON comments BEGIN INSERT INTO video_changes (video_hash) VALUES (NEW.video_hash); END;
+CREATE TRIGGER video_changes_comments_update AFTER UPDATE OF visibility ON comments BEGIN INSERT INTO video_changes (video_hash) VALUES (NEW.video_hash); END;
+CREATE TRIGGER video_changes_comments_delete AFTER DELETE ON comments BEGIN INSERT INTO video_changes (video_hash) VALUES (OLD.video_hash); END;
+CREATE TRIGGER video_changes_video_views_insert AFTER INSERT ON video_views BEGIN INSERT INTO video_changes (video_hash) VALUES (NEW.video_hash); END;
+CREATE TRIGGER video_changes_video_views_update AFTER UPDATE ON video_views BEGIN INSERT INTO video_changes (video_hash) VALUES (NEW.video_hash); END;
+CREATE TRIGGER video_changes_video_custom_fields_insert AFTER INSERT ON video_custom_fields BEGIN INSERT INTO video_changes (video_hash) VALUES (NEW.video_hash); END;
+CREATE TRIGGER video_changes_video_custom_fields_update AFTER UPDATE ON video_custom_fields BEGIN INSERT INTO video_changes (video_hash) VALUES (NEW.video_hash); END;
+CREATE TRIGGER video_changes_video_custom_fields_delete AFTER DELETE ON video_custom_fields BEGIN INSERT INTO video_changes (video_hash) VALUES (OLD.video_hash); END;
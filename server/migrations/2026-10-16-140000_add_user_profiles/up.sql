@@ -0,0 +1,7 @@
+-- Users' own display names, avatars and time zones (see api_server::profiles). No row = defaults.
+CREATE TABLE user_profiles (
+	user_id VARCHAR NOT NULL PRIMARY KEY,
+	display_name VARCHAR,
+	avatar VARCHAR,
+	timezone VARCHAR
+);
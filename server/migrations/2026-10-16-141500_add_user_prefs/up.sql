@@ -0,0 +1,8 @@
+-- Users' client settings, as JSON values by key (see api_server::user_prefs)
+CREATE TABLE user_prefs (
+	user_id VARCHAR NOT NULL,
+	key VARCHAR NOT NULL,
+	value VARCHAR NOT NULL,
+	updated DATETIME DEFAULT (CURRENT_TIMESTAMP) NOT NULL,
+	PRIMARY KEY (user_id, key)
+);
@@ -0,0 +1,2 @@
+-- Legal hold on a folder applies to all videos in it and its subfolders
+ALTER TABLE folders ADD COLUMN legal_hold BOOLEAN NOT NULL DEFAULT 0;
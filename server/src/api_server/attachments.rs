@@ -0,0 +1,165 @@
+use std::path::Path;
+use data_url::DataUrl;
+use warp::http::StatusCode;
+
+use crate::database::{models, DB};
+use crate::database::error::DBError;
+use crate::video_pipeline::review_package::safe_filename;
+use super::file_server::Denied;
+use super::server_state::ServerState;
+
+// Comments can have small files attached (reference images, LUTs, audio snippets). Clients send
+// them as data URIs (`attach_comment_file`), like drawings. They're stored in the video's dir, as
+// `attachments/<id>/<name>`, and served by `/videos` as downloads with their original names.
+// Attachments of internal comments are only served to those who may see the comment (or with a
+// signed URL, which they get only if they do). Clients of share link guests add their share query
+// to attachment URLs, as for other files.
+
+/// Max size of an attached file
+pub const MAX_SIZE: usize = 10 * 1024 * 1024;
+
+/// Max files per comment
+pub const MAX_PER_COMMENT: usize = 5;
+
+/// Color lookup table formats, which browsers send as text or octet-stream
+const LUT_EXTENSIONS: &[&str] = &["cube", "3dl", "csp", "lut"];
+
+/// May a file be attached: images (except SVG, which can carry scripts), audio, PDFs and LUTs
+pub fn is_allowed_type(mime_type: &str, filename: &str) -> bool
+{
+    let ext = Path::new(filename).extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+    match mime_type.split_once('/') {
+        Some(("image", sub)) => !sub.starts_with("svg"),
+        Some(("audio", _)) => true,
+        Some(("application", "pdf")) => true,
+        _ => LUT_EXTENSIONS.contains(&ext.as_str()) && matches!(mime_type, "text/plain" | "application/octet-stream"),
+    }
+}
+
+/// Path of an attachment in its video's dir
+pub fn rel_path(a: &models::CommentAttachment) -> String
+{
+    let name = safe_filename(&a.filename);
+    format!("attachments/{}/{}", a.id, if name.is_empty() { "file".into() } else { name })
+}
+
+/// Attachments of a comment for clients, with URLs to get them from
+pub fn comment_json(server: &ServerState, comment_id: i32) -> anyhow::Result<serde_json::Value>
+{
+    let res = server.db.get_comment_attachments(comment_id)?.iter().map(|a| {
+        let mut fields = a.to_json()?;
+        fields["url"] = server.asset_url(&a.video_hash, &rel_path(a)).into();
+        Ok(fields)
+    }).collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(res.into())
+}
+
+/// Decode a file sent as a data URI, check it against the limits and store it.
+///
+/// # Returns
+/// * `Ok(Ok(attachment))` - Stored
+/// * `Ok(Err(reason))` - Refused, to tell the user
+pub async fn store(server: &ServerState, c: &models::Comment, user_id: &str, filename: &str, data_uri: &str) -> anyhow::Result<Result<models::CommentAttachment, String>>
+{
+    if data_uri.len() > MAX_SIZE / 3 * 4 + 1024 {
+        return Ok(Err(format!("File is too big (max {} MB)", MAX_SIZE / 1024 / 1024)));
+    }
+    let Ok(uri) = DataUrl::process(data_uri) else { return Ok(Err("Invalid data URI".into())) };
+    let mime_type = format!("{}/{}", uri.mime_type().type_, uri.mime_type().subtype);
+    if filename.trim().is_empty() || !is_allowed_type(&mime_type, filename) {
+        return Ok(Err(format!("File type not allowed: '{}' ({})", filename, mime_type)));
+    }
+    let Ok((data, _)) = uri.decode_to_vec() else { return Ok(Err("Invalid data URI".into())) };
+    if data.is_empty() || data.len() > MAX_SIZE {
+        return Ok(Err(format!("File is empty or too big (max {} MB)", MAX_SIZE / 1024 / 1024)));
+    }
+    if server.db.get_comment_attachments(c.id)?.len() >= MAX_PER_COMMENT {
+        return Ok(Err(format!("At most {} files per comment", MAX_PER_COMMENT)));
+    }
+
+    let a = server.db.add_comment_attachment(&models::CommentAttachmentInsert {
+        comment_id: c.id,
+        video_hash: c.video_hash.clone(),
+        user_id: user_id.into(),
+        filename: filename.trim().into(),
+        mime_type,
+        size: data.len() as i32,
+    })?;
+    let path = server.videos_dir.join(&a.video_hash).join(rel_path(&a));
+    let write_res = async {
+        async_std::fs::create_dir_all(path.parent().unwrap()).await?;
+        async_std::fs::write(&path, data).await
+    }.await;
+    if let Err(e) = write_res {
+        server.db.del_comment_attachment(a.id)?;
+        anyhow::bail!("Failed to write attachment file: {}", e);
+    }
+    Ok(Ok(a))
+}
+
+/// Delete an attachment, file and record
+pub fn remove(server: &ServerState, a: &models::CommentAttachment) -> anyhow::Result<()>
+{
+    let dir = server.videos_dir.join(&a.video_hash).join("attachments").join(a.id.to_string());
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    server.db.del_comment_attachment(a.id)?;
+    Ok(())
+}
+
+/// May a user see internal comments on a video (owner, admins, and teams it's shared with)
+fn user_sees_internal(db: &DB, v: &models::Video, user_id: &str) -> bool
+{
+    v.added_by_userid.as_deref() == Some(user_id)
+        || db.is_user_admin(user_id).unwrap_or(false)
+        || db.is_video_shared_with_user(v, user_id).unwrap_or(false)
+}
+
+/// Look up the attachment a served path (`<video_hash>/attachments/<id>/<name>`) is for.
+///
+/// # Returns
+/// * `(attachment, internal)` - Attachment, and whether it's on an internal comment
+pub fn of_path(server: &ServerState, v: &models::Video, path: &str) -> Result<(models::CommentAttachment, bool), Denied>
+{
+    const NOT_FOUND: Denied = Denied(StatusCode::NOT_FOUND, "No such attachment");
+    let id = path.split('/').nth(2).and_then(|id| id.parse::<i32>().ok()).ok_or(NOT_FOUND)?;
+    let db_err = |e: DBError| match e {
+        DBError::NotFound() => NOT_FOUND,
+        e => {
+            tracing::error!(details=%e, "DB error while checking attachment access.");
+            Denied(StatusCode::INTERNAL_SERVER_ERROR, "Internal error")
+        }
+    };
+    let a = server.db.get_comment_attachment(id).map_err(db_err)?;
+    if a.video_hash != v.video_hash || path != format!("{}/{}", v.video_hash, rel_path(&a)) {
+        return Err(NOT_FOUND);
+    }
+    let internal = server.db.get_comment(a.comment_id).map_err(db_err)?.is_internal();
+    Ok((a, internal))
+}
+
+/// Access check for an internal comment's attachment, for requests without a signature or share link
+pub fn check_internal(server: &ServerState, v: &models::Video, user_id: &str) -> Result<(), Denied>
+{
+    match user_sees_internal(&server.db, v, user_id) {
+        true => Ok(()),
+        false => Err(Denied(StatusCode::NOT_FOUND, "No such attachment")),
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_attachment_types()
+{
+    assert!(is_allowed_type("image/png", "ref.png"));
+    assert!(is_allowed_type("audio/mpeg", "take2.mp3"));
+    assert!(is_allowed_type("application/pdf", "notes.pdf"));
+    assert!(is_allowed_type("application/octet-stream", "grade.CUBE"));
+    assert!(!is_allowed_type("image/svg+xml", "logo.svg"));
+    assert!(!is_allowed_type("text/html", "page.html"));
+    assert!(!is_allowed_type("application/octet-stream", "run.exe"));
+    assert!(!is_allowed_type("text/plain", "cube"));
+}
@@ -0,0 +1,77 @@
+use std::path::PathBuf;
+
+use crate::database::models;
+use crate::video_pipeline::playable_file;
+use crate::video_pipeline::audio_mux::{self, AudioMuxMode};
+use super::server_state::ServerState;
+use super::stitch::{notify_error, submit_derived_video};
+
+/// Output filename for a video with new audio, e.g. "clip.mov" + "mix_v2.wav" -> "clip - mix_v2.mkv"
+fn output_filename(video: &models::Video, audio_file: &std::path::Path) -> String
+{
+    let video_stem = video.orig_filename.as_deref()
+        .and_then(|f| std::path::Path::new(f).file_stem().map(|s| s.to_string_lossy().to_string()))
+        .unwrap_or_else(|| video.video_hash.clone());
+    let audio_stem = audio_file.file_stem().unwrap_or_default().to_string_lossy();
+    format!("{video_stem} - {audio_stem}.mkv")
+}
+
+/// Mux an uploaded audio mix into an existing video in a background thread, and submit the result
+/// to the processing pipeline as a new video. The original video is recorded as its source.
+///
+/// # Arguments
+/// * `server` - Server state
+/// * `user_id` - User who gets the new video
+/// * `video` - Video to take the picture from
+/// * `mode` - Replace old audio, or keep it as additional tracks
+/// * `audio_file` - Uploaded audio file, in its own upload dir. Removed after muxing.
+pub fn spawn_audio_replace(server: ServerState, user_id: String, video: models::Video, mode: AudioMuxMode, audio_file: PathBuf)
+{
+    std::thread::spawn(move || {
+        let _span = tracing::info_span!("audio_replace", user=%user_id, video=%video.video_hash).entered();
+        let Some(dir) = audio_file.parent().map(|d| d.to_path_buf()) else { return; };
+        let dst = dir.join(output_filename(&video, &audio_file));
+        let audio_name = audio_file.file_name().unwrap_or_default().to_string_lossy().to_string();
+
+        let res = playable_file(&video, &server.videos_dir)
+            .and_then(|src| audio_mux::mux_audio(&src, &audio_file, mode, video.duration, &dst, &server.sandbox))
+            .and_then(|_| {
+                if let Err(e) = std::fs::remove_file(&audio_file) {
+                    tracing::warn!(details=%e, "Failed to remove uploaded audio file.");
+                }
+                let operation = match mode {
+                    AudioMuxMode::Replace => format!("Audio replaced with '{}'", audio_name),
+                    AudioMuxMode::Add => format!("Audio track '{}' added", audio_name),
+                };
+                submit_derived_video(&server, &user_id, dst, std::slice::from_ref(&video.video_hash), &operation)
+            });
+
+        if let Err(e) = res {
+            tracing::info!(details=%e, "Audio replacement failed.");
+            if let Err(e) = std::fs::remove_dir_all(&dir) {
+                tracing::warn!(details=%e, "Failed to remove upload dir.");
+            }
+            notify_error(&server, &user_id, "Audio replacement failed", e);
+        }
+    });
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_audio_replace_output_filename()
+{
+    let v = models::Video {
+        id: 1, video_hash: "abc".into(), added_by_userid: None, added_by_username: None,
+        added_time: chrono::NaiveDateTime::default(), recompression_done: None, thumb_sheet_dims: None,
+        orig_filename: Some("clip.final.mov".into()), title: None, total_frames: None, duration: None,
+        fps: None, raw_metadata_all: None, silence_trim_start: None, silence_trim_end: None,
+        loudness_lufs: None, legal_hold: false, content_hash: None, loudnorm_target: None,
+        color_primaries: None, color_transfer: None, hdr_format: None,
+        vfr_fps_min: None, vfr_fps_max: None, vfr_fps_avg: None, image_sequence: None, audio_only: false, still_kind: None, page_count: None,
+        allow_download: true, folder_id: None, trashed: None };
+    assert_eq!(output_filename(&v, std::path::Path::new("/up/x/mix_v2.wav")), "clip.final - mix_v2.mkv");
+    let v = models::Video { orig_filename: None, ..v };
+    assert_eq!(output_filename(&v, std::path::Path::new("/up/x/mix.flac")), "abc - mix.mkv");
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use serde_json::json;
+
+use crate::database::models::ThroughputStat;
+
+// Capacity planning report (`admin_capacity_report`), from hourly throughput statistics that
+// the video pipeline records for each finished transcode (`throughput_stats` table).
+//
+// Processing speed is measured as minutes of video a worker processes per minute of work.
+// Times the number of workers, that gives how many minutes of video per hour the current
+// hardware can take. Demand is the minutes of video processed per hour, averaged per day,
+// with a linear trend fitted over the report period. When the trend crosses the target
+// utilization of capacity, the hardware no longer keeps up: uploads don't arrive evenly, so
+// queues grow long before demand reaches 100%. Queue wait times and the busiest hour are
+// reported too, as they show saturation before the averages do.
+
+/// Default share of capacity demand may use before the hardware is considered too slow
+pub const DEFAULT_TARGET_UTILIZATION: f64 = 0.7;
+
+/// Max number of days to project the trend into the future
+const MAX_PROJECTION_DAYS: f64 = 3650.0;
+
+/// Daily totals of throughput
+#[derive(Debug, Default, Clone, PartialEq)]
+struct DayTotals {
+    n_jobs: i64,
+    video_secs: f64,
+    work_secs: f64,
+    wait_secs: f64,
+}
+
+/// Least squares fit of y = a + b*x.
+///
+/// # Returns
+/// (a, b), or None if there are less than two distinct x values
+fn linear_fit(points: &[(f64, f64)]) -> Option<(f64, f64)>
+{
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxx = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f64>();
+    if points.len() < 2 || sxx == 0.0 {
+        return None;
+    }
+    let sxy = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum::<f64>();
+    let b = sxy / sxx;
+    Some((mean_y - b * mean_x, b))
+}
+
+/// Make a capacity report.
+///
+/// # Arguments
+/// * `stats` - Hourly throughput of one stage, oldest first
+/// * `n_workers` - Current number of workers in the stage
+/// * `target_utilization` - Share of capacity (0-1] considered full
+/// * `today` - Last day of the report period (days without jobs after the first one count as zero demand)
+pub fn capacity_report(stats: &[ThroughputStat], n_workers: usize, target_utilization: f64, today: chrono::NaiveDate) -> serde_json::Value
+{
+    let mut days: BTreeMap<chrono::NaiveDate, DayTotals> = BTreeMap::new();
+    for s in stats {
+        let d = days.entry(s.hour.date()).or_default();
+        d.n_jobs += s.n_jobs as i64;
+        d.video_secs += s.video_secs;
+        d.work_secs += s.work_secs;
+        d.wait_secs += s.wait_secs;
+    }
+    if let Some(first) = days.keys().next().copied() {
+        for d in first.iter_days().take_while(|d| *d <= today) {
+            days.entry(d).or_default();
+        }
+    }
+
+    let total_video = days.values().map(|d| d.video_secs).sum::<f64>();
+    let total_work = days.values().map(|d| d.work_secs).sum::<f64>();
+    let speed = (total_work > 0.0).then(|| total_video / total_work);
+    let capacity = speed.map(|s| s * 60.0 * n_workers as f64);  // video minutes per hour
+
+    let daily = days.iter().map(|(day, d)| json!({
+        "day": day.to_string(),
+        "n_jobs": d.n_jobs,
+        "video_minutes": d.video_secs / 60.0,
+        "video_minutes_per_hour": d.video_secs / 60.0 / 24.0,
+        "avg_wait_secs": if d.n_jobs > 0 { d.wait_secs / d.n_jobs as f64 } else { 0.0 },
+        "utilization": if n_workers > 0 { d.work_secs / (n_workers as f64 * 86400.0) } else { 0.0 },
+    })).collect::<Vec<_>>();
+
+    let peak_hour = stats.iter().max_by(|a, b| a.work_secs.total_cmp(&b.work_secs)).map(|s| json!({
+        "hour": s.hour.timestamp(),
+        "utilization": if n_workers > 0 { s.work_secs / (n_workers as f64 * 3600.0) } else { 0.0 },
+        "avg_wait_secs": if s.n_jobs > 0 { s.wait_secs / s.n_jobs as f64 } else { 0.0 },
+    }));
+
+    // Demand trend, in video minutes per hour, with today as x = 0
+    let points = days.iter()
+        .map(|(day, d)| ((*day - today).num_days() as f64, d.video_secs / 60.0 / 24.0))
+        .collect::<Vec<_>>();
+    let trend = linear_fit(&points);
+    let demand_now = trend.map(|(a, _)| a.max(0.0));
+
+    let days_left = match (trend, capacity) {
+        (Some((a, b)), Some(cap)) => {
+            let limit = cap * target_utilization;
+            if a >= limit { Some(0.0) }
+            else if b > 0.0 && (limit - a) / b <= MAX_PROJECTION_DAYS { Some(((limit - a) / b).ceil()) }
+            else { None }
+        },
+        _ => None,
+    };
+
+    json!({
+        "n_workers": n_workers,
+        "target_utilization": target_utilization,
+        "realtime_factor": speed,
+        "capacity_video_minutes_per_hour": capacity,
+        "demand_video_minutes_per_hour": demand_now,
+        "trend_per_day": trend.map(|(_, b)| b),
+        "utilization": match (demand_now, capacity) { (Some(d), Some(c)) if c > 0.0 => Some(d / c), _ => None },
+        "days_until_saturated": days_left,
+        "saturation_date": days_left.map(|n| (today + chrono::Duration::days(n as i64)).to_string()),
+        "peak_hour": peak_hour,
+        "daily": daily,
+    })
+}
+
+
+// Unit tests =====================================================================================
+
+#[cfg(test)]
+fn test_stat(day: u32, hour: u32, n_jobs: i32, video_secs: f64, work_secs: f64) -> ThroughputStat {
+    ThroughputStat {
+        hour: chrono::NaiveDate::from_ymd_opt(2026, 3, day).unwrap().and_hms_opt(hour, 0, 0).unwrap(),
+        stage: "transcode".into(),
+        n_jobs, video_secs, work_secs,
+        wait_secs: 30.0 * n_jobs as f64,
+    }
+}
+
+#[test]
+fn test_linear_fit()
+{
+    assert_eq!(linear_fit(&[(0.0, 1.0), (1.0, 3.0), (2.0, 5.0)]), Some((1.0, 2.0)));
+    assert_eq!(linear_fit(&[(1.0, 1.0)]), None);
+    assert_eq!(linear_fit(&[(1.0, 1.0), (1.0, 2.0)]), None);
+    assert_eq!(linear_fit(&[]), None);
+}
+
+#[test]
+fn test_capacity_report()
+{
+    let today = chrono::NaiveDate::from_ymd_opt(2026, 3, 10).unwrap();
+
+    // Video is transcoded at 2x realtime. Demand grows by 24 video minutes per day
+    // (1 video minute per hour), from 24 min/day on day 1.
+    let stats = (1..=10).map(|d| test_stat(d, 12, 2, 60.0 * 24.0 * d as f64, 30.0 * 24.0 * d as f64)).collect::<Vec<_>>();
+    let r = capacity_report(&stats, 2, 0.5, today);
+    assert_eq!(r["realtime_factor"], 2.0);
+    assert_eq!(r["capacity_video_minutes_per_hour"], 240.0);
+    assert_eq!(r["daily"].as_array().unwrap().len(), 10);
+    assert_eq!(r["daily"][0]["video_minutes"], 24.0);
+    assert_eq!(r["daily"][0]["avg_wait_secs"], 30.0);
+    assert!((r["demand_video_minutes_per_hour"].as_f64().unwrap() - 10.0).abs() < 1e-9);
+    assert!((r["trend_per_day"].as_f64().unwrap() - 1.0).abs() < 1e-9);
+    // 10 now, limit is 120 => 110 days
+    assert_eq!(r["days_until_saturated"], 110.0);
+    assert_eq!(r["saturation_date"], "2026-06-28");
+    assert_eq!(r["peak_hour"]["utilization"], 30.0 * 24.0 * 10.0 / 7200.0);
+
+    // Already over the limit
+    let r = capacity_report(&stats, 2, 0.01, today);
+    assert_eq!(r["days_until_saturated"], 0.0);
+
+    // Shrinking demand never saturates. Days without jobs count as zero demand.
+    let stats = vec![test_stat(1, 0, 1, 600.0, 300.0), test_stat(2, 5, 1, 300.0, 150.0)];
+    let r = capacity_report(&stats, 1, DEFAULT_TARGET_UTILIZATION, today);
+    assert_eq!(r["daily"].as_array().unwrap().len(), 10);
+    assert!(r["trend_per_day"].as_f64().unwrap() < 0.0);
+    assert!(r["days_until_saturated"].is_null());
+
+    // No data
+    let r = capacity_report(&[], 4, DEFAULT_TARGET_UTILIZATION, today);
+    assert!(r["realtime_factor"].is_null());
+    assert!(r["days_until_saturated"].is_null());
+    assert!(r["peak_hour"].is_null());
+}
@@ -0,0 +1,80 @@
+use anyhow::bail;
+use serde_json::json;
+
+use super::server_state::ServerState;
+use crate::database::models;
+
+// Collab rooms are kept in the DB (participants, host, the video they're bound to and last
+// reported playback state), in addition to the senders in `ServerState`. When running several
+// instances on a shared DB and the one hosting a collab goes down, clients reconnect to another
+// one and rejoin. That instance then finds the room: the video binding still holds, the original
+// host stays host, and joiners are sent the last playback state (`collab_cmd` with `resumed`),
+// so the review continues where it was instead of starting from scratch.
+//
+// Participants are removed when they leave, or disconnect from a running server. Disconnects
+// caused by this instance shutting down (or crashing) keep them, for the takeover. Rooms nobody
+// has joined or reported to in `ROOM_TTL_HOURS` are considered abandoned and deleted.
+
+/// Hours after which an idle room is deleted
+pub const ROOM_TTL_HOURS: i64 = 12;
+
+/// Record a user joining a collab, taking over the room if another instance was hosting it.
+///
+/// # Returns
+/// * `models::CollabRoom` - Room to join, possibly with playback state to resume
+/// * `Err` - Room is for another video, or DB error
+pub fn join(server: &ServerState, collab_id: &str, video_hash: &str, user_id: &str, user_name: &str) -> anyhow::Result<models::CollabRoom>
+{
+    let stale_before = chrono::Utc::now().naive_utc() - chrono::Duration::hours(ROOM_TTL_HOURS);
+    match server.db.del_stale_collab_rooms(stale_before) {
+        Ok(0) => {},
+        Ok(n) => tracing::debug!(n_rooms=n, "Deleted abandoned collab rooms."),
+        Err(e) => tracing::error!(details=%e, "Failed to delete abandoned collab rooms."),
+    }
+    let room = server.db.join_collab_room(collab_id, video_hash, user_id, user_name)?;
+    if room.video_hash != video_hash {
+        bail!("Mismatching video hash for pre-existing collab");
+    }
+    Ok(room)
+}
+
+/// Playback state to send a joining session, as a `collab_cmd`. None if nobody has reported one yet.
+pub fn resume_cmd(server: &ServerState, room: &models::CollabRoom) -> Option<serde_json::Value>
+{
+    let seek_time = room.seek_time?;
+    let participants = server.db.get_collab_participants(&room.collab_id).unwrap_or_else(|e| {
+        tracing::error!(details=%e, "Failed to get collab participants.");
+        vec![]
+    });
+    let host_name = participants.iter().find(|p| p.user_id == room.host).map(|p| p.user_name.clone()).unwrap_or(room.host.clone());
+    let mut cmd = json!({
+        "paused": room.paused,
+        "seek_time": seek_time,
+        "from_user": host_name,
+        "from_profile": super::profiles::profile_json(server, &room.host, &host_name),
+        "resumed": true,
+        "participants": participants.iter().map(|p| &p.user_name).collect::<Vec<_>>(),
+        "participant_profiles": super::profiles::profiles_json(server,
+            &participants.iter().map(|p| (p.user_id.clone(), p.user_name.clone())).collect::<Vec<_>>()),
+    });
+    if let Some(page) = room.page {
+        cmd["page"] = json!(page);
+    }
+    Some(cmd)
+}
+
+/// Save playback state reported by a participant
+pub fn report(server: &ServerState, collab_id: &str, paused: bool, seek_time: f64, page: Option<i32>)
+{
+    if let Err(e) = server.db.set_collab_playback(collab_id, paused, seek_time, page) {
+        tracing::warn!(collab=collab_id, details=%e, "Failed to save collab playback state.");
+    }
+}
+
+/// Remove a participant from a room (deleting it if they were the last one)
+pub fn leave(server: &ServerState, collab_id: &str, user_id: &str)
+{
+    if let Err(e) = server.db.leave_collab_room(collab_id, user_id) {
+        tracing::error!(collab=collab_id, details=%e, "Failed to remove collab participant.");
+    }
+}
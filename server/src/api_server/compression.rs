@@ -0,0 +1,200 @@
+use warp::ws::Message;
+
+// Some messages get big: video listings of large libraries, full comment lists, metadata.
+// Websocket permessage-deflate isn't available in the server's websocket library, so clients
+// can ask for gzip instead when connecting (`/api/ws?compress=gzip`, see `protocol`). Messages of
+// at least `MIN_COMPRESS_BYTES` are then sent as binary frames of gzipped JSON (or MessagePack, see
+// `msgpack`). Smaller ones are sent as usual. Gzip starts with bytes 1f 8b, so clients can tell
+// it apart from MessagePack (whose messages start with a map).
+//
+// The encoder is a simple one (LZ77 with fixed Huffman codes), but JSON compresses well
+// even with that, and it needs no extra dependencies.
+
+/// Text messages at least this long are compressed
+pub const MIN_COMPRESS_BYTES: usize = 8 * 1024;
+
+/// Compress a long text or binary message into a binary gzip message. Others are returned as is.
+pub fn compress_message(msg: Message) -> Message {
+    if !(msg.is_text() || msg.is_binary()) || msg.as_bytes().len() < MIN_COMPRESS_BYTES {
+        return msg;
+    }
+    let gz = gzip(msg.as_bytes());
+    if gz.len() < msg.as_bytes().len() { Message::binary(gz) } else { msg }
+}
+
+/// Gzip data (RFC 1952), in one fixed Huffman DEFLATE block (RFC 1951)
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];  // No mtime or name, unknown OS
+    out.extend(deflate(data));
+    out.extend(crc32(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Writes bits LSB first, as DEFLATE wants
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    n_bits: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, n_bits: u32) {
+        self.acc |= (value as u64) << self.n_bits;
+        self.n_bits += n_bits;
+        while self.n_bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.n_bits -= 8;
+        }
+    }
+
+    /// Huffman codes go MSB first
+    fn put_code(&mut self, code: u32, n_bits: u32) {
+        self.put(code.reverse_bits() >> (32 - n_bits), n_bits);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.n_bits > 0 { self.out.push(self.acc as u8); }
+        self.out
+    }
+}
+
+const LEN_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LEN_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+/// How many earlier positions to try for a match. More compresses better, but slower.
+const MAX_CHAIN: usize = 32;
+
+/// Literal/length symbol in fixed Huffman code
+fn put_litlen(w: &mut BitWriter, sym: u32) {
+    match sym {
+        0..=143 => w.put_code(0x30 + sym, 8),
+        144..=255 => w.put_code(0x190 + sym - 144, 9),
+        256..=279 => w.put_code(sym - 256, 7),
+        _ => w.put_code(0xc0 + sym - 280, 8),
+    }
+}
+
+fn put_match(w: &mut BitWriter, len: usize, dist: usize) {
+    let li = LEN_BASE.iter().rposition(|b| *b as usize <= len).unwrap_or(0);
+    put_litlen(w, 257 + li as u32);
+    w.put((len - LEN_BASE[li] as usize) as u32, LEN_EXTRA[li] as u32);
+    let di = DIST_BASE.iter().rposition(|b| *b as usize <= dist).unwrap_or(0);
+    w.put_code(di as u32, 5);
+    w.put((dist - DIST_BASE[di] as usize) as u32, DIST_EXTRA[di] as u32);
+}
+
+fn hash3(d: &[u8]) -> usize {
+    let v = (d[0] as u32) << 16 | (d[1] as u32) << 8 | d[2] as u32;
+    (v.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Raw DEFLATE stream of one final block with fixed Huffman codes, greedy LZ77 matching
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter { out: Vec::with_capacity(data.len() / 4), acc: 0, n_bits: 0 };
+    w.put(1, 1);  // BFINAL
+    w.put(1, 2);  // BTYPE = fixed Huffman
+
+    const NONE: usize = usize::MAX;
+    let mut head = vec![NONE; 1 << HASH_BITS];
+    let mut prev = vec![NONE; WINDOW];
+    let insert = |pos: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>| {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash3(&data[pos..]);
+            prev[pos % WINDOW] = head[h];
+            head[h] = pos;
+        }
+    };
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let (mut best_len, mut best_dist) = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            let max_len = MAX_MATCH.min(data.len() - pos);
+            let mut cand = head[hash3(&data[pos..])];
+            for _ in 0..MAX_CHAIN {
+                if cand == NONE || pos - cand > WINDOW - 1 { break; }
+                let len = data[cand..].iter().zip(&data[pos..pos + max_len]).take_while(|(a, b)| a == b).count();
+                if len > best_len {
+                    (best_len, best_dist) = (len, pos - cand);
+                    if len == max_len { break; }
+                }
+                let next = prev[cand % WINDOW];
+                if next == NONE || next >= cand { break; }
+                cand = next;
+            }
+        }
+        if best_len >= MIN_MATCH {
+            put_match(&mut w, best_len, best_dist);
+            for p in pos..pos + best_len { insert(p, &mut head, &mut prev); }
+            pos += best_len;
+        } else {
+            put_litlen(&mut w, data[pos] as u32);
+            insert(pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+    put_litlen(&mut w, 256);  // End of block
+    w.finish()
+}
+
+
+// Unit tests =====================================================================================
+
+/// Decompress with system gzip, for checking output
+#[cfg(test)]
+pub(crate) fn gunzip(gz: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+    let mut child = std::process::Command::new("gzip").arg("-dc")
+        .stdin(std::process::Stdio::piped()).stdout(std::process::Stdio::piped()).spawn().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let gz = gz.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&gz).unwrap());
+    let out = child.wait_with_output().unwrap();
+    writer.join().unwrap();
+    assert!(out.status.success(), "gzip -d failed");
+    out.stdout
+}
+
+#[test]
+fn test_gzip()
+{
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+    let videos = (0..2000).map(|i| serde_json::json!({
+        "video_hash": format!("{:x}", i * 7919), "title": format!("Shot {i} / final comp"),
+        "added_by_user_id": "alice", "duration": {"duration": 12.5, "fps": "24", "frames": 300} })).collect::<Vec<_>>();
+    let json = serde_json::json!({ "cmd": "user_videos", "data": { "videos": videos } }).to_string();
+    let gz = gzip(json.as_bytes());
+    assert!(gz.len() * 5 < json.len(), "Compressed {} -> {}", json.len(), gz.len());
+    assert_eq!(gunzip(&gz), json.as_bytes());
+
+    // Edge cases: empty, short, long runs, binary
+    let rand_bytes = (0..70_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect::<Vec<_>>();
+    for data in [vec![], b"a".to_vec(), vec![b'x'; 100_000], b"abcabcabcab".repeat(5000), rand_bytes] {
+        assert_eq!(gunzip(&gzip(&data)), data);
+    }
+
+    // Only long text messages are compressed
+    assert!(compress_message(Message::text(json.clone())).is_binary());
+    assert!(compress_message(Message::text("{\"cmd\":\"echo\"}")).is_text());
+    assert!(compress_message(Message::close()).is_close());
+}
@@ -0,0 +1,47 @@
+use crate::database::models;
+use crate::video_pipeline::{playable_file, has_audio_track};
+use crate::video_pipeline::conform::{self, ConformMode};
+use super::server_state::ServerState;
+use super::stitch::{notify_error, submit_derived_video};
+
+/// Output filename for a conformed video, e.g. "clip.mov" at 24 fps -> "clip (24 fps).mp4"
+fn output_filename(video: &models::Video, dst_fps: f64) -> String
+{
+    let stem = video.orig_filename.as_deref()
+        .and_then(|f| std::path::Path::new(f).file_stem().map(|s| s.to_string_lossy().to_string()))
+        .unwrap_or_else(|| video.video_hash.clone());
+    format!("{stem} ({} fps).mp4", (dst_fps * 1000.0).round() / 1000.0)
+}
+
+/// Change frame rate of a video in a background thread, and submit the result to the processing
+/// pipeline as a new video. Original is kept as is, and recorded as the source of the new one.
+///
+/// # Arguments
+/// * `server` - Server state
+/// * `user_id` - User who gets the new video
+/// * `video` - Video to conform
+/// * `src_fps` - Frame rate to interpret the source as
+/// * `dst_fps` - New frame rate
+/// * `mode` - Interpret (retime) or convert
+pub fn spawn_conform(server: ServerState, user_id: String, video: models::Video, src_fps: f64, dst_fps: f64, mode: ConformMode)
+{
+    std::thread::spawn(move || {
+        let _span = tracing::info_span!("conform", user=%user_id, video=%video.video_hash).entered();
+        let new_dir = server.upload_dir.join(uuid::Uuid::new_v4().to_string());
+        let dst = new_dir.join(output_filename(&video, dst_fps));
+        let operation = format!("Frame rate conformed from {src_fps} to {dst_fps} fps ({mode})");
+
+        let res = std::fs::create_dir_all(&new_dir).map_err(|e| e.to_string())
+            .and_then(|_| playable_file(&video, &server.videos_dir))
+            .and_then(|src| conform::conform(&src, src_fps, dst_fps, mode, has_audio_track(&video), &dst, &server.sandbox))
+            .and_then(|_| submit_derived_video(&server, &user_id, dst, std::slice::from_ref(&video.video_hash), &operation));
+
+        if let Err(e) = res {
+            tracing::info!(details=%e, "Frame rate conform failed.");
+            if let Err(e) = std::fs::remove_dir_all(&new_dir) {
+                tracing::warn!(details=%e, "Failed to remove conform output dir.");
+            }
+            notify_error(&server, &user_id, "Frame rate conform failed", e);
+        }
+    });
+}
@@ -0,0 +1,54 @@
+use warp::Filter;
+use warp::http::StatusCode;
+
+use crate::database::{models, DB};
+use super::file_server::{self, Denied};
+use super::server_state::ServerState;
+
+
+/// Can user download files of a video? Owner, admins and members of teams it's shared with
+/// always can, others if owner allows it.
+pub fn may_download(db: &DB, v: &models::Video, user_id: &str, is_admin: bool) -> bool {
+    v.allow_download || v.added_by_userid.as_deref() == Some(user_id) || is_admin
+        || db.is_video_shared_with_user(v, user_id).unwrap_or(false)
+}
+
+/// Download URLs of the original and transcoded (proxy) files of a video, for client.
+/// `proxy` is null if the video was not transcoded.
+pub fn download_urls(url_base: &str, v: &models::Video) -> serde_json::Value {
+    let base = format!("{}/api/download/{}", url_base, v.video_hash);
+    serde_json::json!({
+        "orig": v.orig_filename.as_ref().map(|f| format!("{}/orig/{}", base, urlencoding::encode(f))),
+        "proxy": v.recompression_done.as_ref().map(|_| format!("{}/video.mp4", base)),
+    })
+}
+
+/// Check that path (`<video_hash>/orig/<filename>` or `<video_hash>/video.mp4`)
+/// refers to a downloadable file of a video the user may download.
+///
+/// # Returns
+/// Filename for the downloaded file
+fn check_access(server: &ServerState, user_id: &str, path: &str) -> Result<Option<String>, Denied>
+{
+    let v = file_server::video_of_path(server, path)?;
+    if !may_download(&server.db, &v, user_id, server.db.is_user_admin(user_id).unwrap_or(false)) {
+        return Err(Denied(StatusCode::FORBIDDEN, "Download not allowed"));
+    }
+    let parts = path.split('/').collect::<Vec<_>>();
+    let filename = match parts[1..] {
+        ["video.mp4"] if v.recompression_done.is_some() => format!("{}.mp4", v.title.as_deref().unwrap_or(&v.video_hash)),
+        ["orig", f] if v.orig_filename.as_deref() == Some(f) => f.to_string(),
+        _ => return Err(Denied(StatusCode::NOT_FOUND, "No such file")),
+    };
+    tracing::info!(user=%user_id, video=%v.video_hash, file=%path, "Download.");
+    Ok(Some(filename))
+}
+
+/// Warp filter for downloading original and transcoded files of a video, with permission checks
+/// (see `may_download`). Files are served as attachments, and support range requests.
+pub fn download_filter(server: ServerState) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+{
+    let videos_dir = server.videos_dir.clone();
+    file_server::serve_dir(warp::path("api").and(warp::path("download")), videos_dir,
+        move |user_id, path, _query| check_access(&server, user_id, path))
+}
@@ -0,0 +1,126 @@
+use anyhow::{anyhow, bail};
+use serde::Serialize;
+
+use crate::database::{DB, EventFilter};
+
+/// Row in the admin event console. Unifies audit log entries and user messages.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventRow {
+    pub source: &'static str,
+    pub id: i32,
+    pub created: i64,
+    pub user_id: String,
+    pub kind: String,
+    pub video_hash: Option<String>,
+    pub message: String,
+    pub details: String,
+}
+
+pub const SOURCE_AUDIT: &str = "audit";
+pub const SOURCE_MESSAGES: &str = "messages";
+
+/// Parse search filter from API message data.
+/// Times (`since`, `until`) are Unix timestamps (seconds, UTC).
+pub fn parse_filter(data: &serde_json::Value) -> anyhow::Result<EventFilter>
+{
+    let opt_str = |key: &str| data[key].as_str().filter(|s| !s.is_empty()).map(|s| s.to_string());
+    let opt_time = |key: &str| -> anyhow::Result<Option<chrono::NaiveDateTime>> {
+        match data[key].as_i64() {
+            None => Ok(None),
+            Some(ts) => chrono::NaiveDateTime::from_timestamp_opt(ts, 0)
+                .map(Some).ok_or(anyhow!("Invalid timestamp in '{}'", key)),
+        }};
+    let limit = data["limit"].as_i64().unwrap_or(EventFilter::default().limit);
+    if !(1..=10_000).contains(&limit) { bail!("limit must be 1..10000"); }
+
+    Ok(EventFilter {
+        user_id: opt_str("user_id"),
+        kind: opt_str("kind"),
+        video_hash: opt_str("video_hash"),
+        since: opt_time("since")?,
+        until: opt_time("until")?,
+        text: opt_str("text"),
+        limit,
+    })
+}
+
+/// Search audit log and/or user messages, merge results newest first.
+///
+/// # Arguments
+/// * `db` - Database
+/// * `filter` - Search filter
+/// * `sources` - Which tables to search (`SOURCE_AUDIT`, `SOURCE_MESSAGES`)
+pub fn search_events(db: &DB, filter: &EventFilter, sources: &[&str]) -> anyhow::Result<Vec<EventRow>>
+{
+    let mut rows = vec![];
+    for src in sources {
+        match *src {
+            SOURCE_AUDIT => rows.extend(db.search_audit_events(filter)?.into_iter().map(|e| EventRow {
+                source: SOURCE_AUDIT,
+                id: e.id,
+                created: e.created.timestamp(),
+                user_id: e.user_id,
+                kind: e.action,
+                video_hash: e.ref_video_hash,
+                message: String::new(),
+                details: e.details })),
+            SOURCE_MESSAGES => rows.extend(db.search_messages(filter)?.into_iter().map(|m| EventRow {
+                source: SOURCE_MESSAGES,
+                id: m.id,
+                created: m.created.timestamp(),
+                user_id: m.user_id,
+                kind: m.event_name,
+                video_hash: m.ref_video_hash,
+                message: m.message,
+                details: m.details })),
+            other => bail!("Unknown event source '{}'", other),
+        }
+    }
+    rows.sort_by(|a, b| b.created.cmp(&a.created).then(b.id.cmp(&a.id)));
+    rows.truncate(filter.limit as usize);
+    Ok(rows)
+}
+
+/// Format event rows as CSV (RFC 4180), with a header row.
+pub fn to_csv(rows: &[EventRow]) -> String
+{
+    fn esc(s: &str) -> String {
+        if s.contains([',', '"', '\n', '\r']) { format!("\"{}\"", s.replace('"', "\"\"")) }
+        else { s.to_string() }
+    }
+    let mut out = String::from("source,id,created,user_id,kind,video_hash,message,details\r\n");
+    for r in rows {
+        let created = chrono::NaiveDateTime::from_timestamp_opt(r.created, 0)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default();
+        out += &[r.source.to_string(), r.id.to_string(), created, esc(&r.user_id), esc(&r.kind),
+            esc(r.video_hash.as_deref().unwrap_or("")), esc(&r.message), esc(&r.details)].join(",");
+        out += "\r\n";
+    }
+    out
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_events_to_csv()
+{
+    let rows = vec![EventRow {
+        source: SOURCE_MESSAGES, id: 7, created: 0, user_id: "user.num1".into(), kind: "error".into(),
+        video_hash: None, message: "Upload failed".into(), details: "Bad file, \"garbage.mp4\"\nsecond line".into() }];
+    let csv = to_csv(&rows);
+    let mut lines = csv.split("\r\n");
+    assert_eq!(lines.next().unwrap(), "source,id,created,user_id,kind,video_hash,message,details");
+    assert_eq!(lines.next().unwrap(), "messages,7,1970-01-01 00:00:00,user.num1,error,,Upload failed,\"Bad file, \"\"garbage.mp4\"\"\nsecond line\"");
+}
+
+#[test]
+fn test_parse_event_filter()
+{
+    let f = parse_filter(&serde_json::json!({"user_id": "bob", "text": "", "since": 86400, "limit": 5})).unwrap();
+    assert_eq!(f.user_id.as_deref(), Some("bob"));
+    assert!(f.text.is_none());
+    assert_eq!(f.since.unwrap().timestamp(), 86400);
+    assert_eq!(f.limit, 5);
+    assert!(parse_filter(&serde_json::json!({"limit": 0})).is_err());
+}
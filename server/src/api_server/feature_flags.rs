@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use anyhow::{anyhow, bail};
+use sha2::{Digest, Sha256};
+
+use crate::database::error::DBError;
+use super::server_state::ServerState;
+
+// Big features can be rolled out gradually on one deployment. Defaults come from config
+// (`--features`), e.g.
+//
+//   features = collab=on, transcripts=25%, stitch=off
+//
+// and admins can override them (`admin_set_feature`) for everyone, a team or a single user.
+// Precedence is user override, then team overrides (enabled wins if user's teams disagree),
+// then override for everyone, then config. Features not in config are on.
+//
+// Percentage rollouts pick users by a stable hash of feature name and user id, so users
+// keep their result between logins, and raising the percentage only adds users.
+//
+// Commands of a disabled feature are refused in `msg_dispatch`, and the client gets
+// the user's enabled features in `welcome`, to hide the UI for the others.
+
+/// Known features, and the commands that need them
+pub const FEATURES: &[(&str, &[&str])] = &[
+    ("collab", &["join_collab", "collab_report", "schedule_review"]),
+    ("transcripts", &["search_transcripts"]),
+    ("video_diff", &["diff_videos"]),
+    ("stitch", &["stitch_videos"]),
+    ("url_ingest", &["ingest_url"]),
+];
+
+/// Override target for everyone
+pub const TARGET_ALL: &str = "*";
+
+/// Who gets a feature, by config
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rollout {
+    On,
+    Off,
+    /// On for this percentage of users
+    Percent(u8),
+}
+
+impl std::fmt::Display for Rollout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rollout::On => write!(f, "on"),
+            Rollout::Off => write!(f, "off"),
+            Rollout::Percent(p) => write!(f, "{p}%"),
+        }
+    }
+}
+
+impl std::str::FromStr for Rollout {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Rollout, String> {
+        match s.trim().to_lowercase().as_str() {
+            "on" | "true" => Ok(Rollout::On),
+            "off" | "false" => Ok(Rollout::Off),
+            v => match v.strip_suffix('%').and_then(|p| p.trim().parse::<u8>().ok()) {
+                Some(100) => Ok(Rollout::On),
+                Some(0) => Ok(Rollout::Off),
+                Some(p) if p < 100 => Ok(Rollout::Percent(p)),
+                _ => Err(format!("expected 'on', 'off' or a percentage, got '{s}'")),
+            }
+        }
+    }
+}
+
+/// Configured rollout of each feature
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeatureFlags {
+    rollouts: BTreeMap<&'static str, Rollout>,
+}
+
+impl FeatureFlags {
+    /// Parse config value, a comma separated list of `name=on|off|N%`. Plain `name` means on.
+    pub fn parse(s: &str) -> anyhow::Result<FeatureFlags> {
+        let mut rollouts = BTreeMap::new();
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (name, value) = item.split_once('=').unwrap_or((item, "on"));
+            let name = known_feature(name.trim()).ok_or_else(|| anyhow!("Unknown feature '{}' (known: {})", name.trim(), feature_names().join(", ")))?;
+            let rollout = value.parse::<Rollout>().map_err(|e| anyhow!("Feature '{}': {}", name, e))?;
+            if rollouts.insert(name, rollout).is_some() {
+                bail!("Feature '{}' given twice", name);
+            }
+        }
+        Ok(FeatureFlags { rollouts })
+    }
+
+    pub fn rollout(&self, feature: &str) -> Rollout {
+        self.rollouts.get(feature).copied().unwrap_or(Rollout::On)
+    }
+
+    /// Is feature on for user by config (no overrides)
+    pub fn config_enabled(&self, feature: &str, user_id: &str) -> bool {
+        match self.rollout(feature) {
+            Rollout::On => true,
+            Rollout::Off => false,
+            Rollout::Percent(p) => rollout_bucket(feature, user_id) < p,
+        }
+    }
+
+    /// Rollout of all known features, for admin
+    pub fn to_json(&self) -> serde_json::Value {
+        FEATURES.iter().map(|(name, _)| (name.to_string(), serde_json::json!(self.rollout(name).to_string())))
+            .collect::<serde_json::Map<_, _>>().into()
+    }
+}
+
+/// User's stable position (0-99) in a feature's percentage rollout
+fn rollout_bucket(feature: &str, user_id: &str) -> u8 {
+    let hash = Sha256::digest(format!("{feature}:{user_id}").as_bytes());
+    (u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % 100) as u8
+}
+
+fn known_feature(name: &str) -> Option<&'static str> {
+    FEATURES.iter().map(|(n, _)| *n).find(|n| *n == name)
+}
+
+pub fn feature_names() -> Vec<&'static str> {
+    FEATURES.iter().map(|(n, _)| *n).collect()
+}
+
+/// Feature a command belongs to, if any
+pub fn feature_for_command(cmd: &str) -> Option<&'static str> {
+    FEATURES.iter().find(|(_, cmds)| cmds.contains(&cmd)).map(|(n, _)| *n)
+}
+
+/// Check an override target: `*`, `user:<user id>` or `team:<team id>` (of an existing team).
+///
+/// # Returns
+/// * `Ok(Err(msg))` - Invalid target, with a message for the user
+pub fn check_target(server: &ServerState, target: &str) -> Result<Result<(), String>, DBError> {
+    if target == TARGET_ALL {
+        return Ok(Ok(()));
+    }
+    match target.split_once(':') {
+        Some(("user", uid)) if !uid.trim().is_empty() => Ok(Ok(())),
+        Some(("team", tid)) => match tid.parse::<i32>() {
+            Ok(tid) => match server.db.get_team(tid) {
+                Ok(_) => Ok(Ok(())),
+                Err(DBError::NotFound()) => Ok(Err("No such team.".into())),
+                Err(e) => Err(e),
+            },
+            Err(_) => Ok(Err(format!("Invalid team id in '{target}'."))),
+        },
+        _ => Ok(Err(format!("Invalid target '{target}' (use '*', 'user:<id>' or 'team:<id>')."))),
+    }
+}
+
+/// Get features enabled for a user, with admin's overrides applied.
+pub fn enabled_features(server: &ServerState, user_id: &str) -> Result<Vec<&'static str>, DBError> {
+    let mut targets = vec![TARGET_ALL.to_string(), format!("user:{user_id}")];
+    targets.extend(server.db.get_teams(Some(user_id))?.iter().map(|t| format!("team:{}", t.id)));
+    let overrides = server.db.get_feature_overrides(Some(&targets))?;
+    let flags = server.config.features();
+
+    Ok(FEATURES.iter().map(|(name, _)| *name).filter(|name| {
+        let of_kind = |kind: &str| overrides.iter()
+            .filter(|o| o.feature == *name && (o.target == kind || o.target.starts_with(&format!("{kind}:"))))
+            .map(|o| o.enabled).collect::<Vec<_>>();
+        let teams = of_kind("team");
+        of_kind("user").first().copied()
+            .or(if teams.is_empty() { None } else { Some(teams.contains(&true)) })
+            .or(of_kind(TARGET_ALL).first().copied())
+            .unwrap_or_else(|| flags.config_enabled(name, user_id))
+    }).collect())
+}
+
+/// Is a feature enabled for a user, with admin's overrides applied
+pub fn is_enabled(server: &ServerState, feature: &str, user_id: &str) -> Result<bool, DBError> {
+    Ok(enabled_features(server, user_id)?.contains(&feature))
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_feature_flags_config()
+{
+    let ff = FeatureFlags::parse("collab=off, transcripts = 25%, stitch").unwrap();
+    assert_eq!(ff.rollout("collab"), Rollout::Off);
+    assert_eq!(ff.rollout("transcripts"), Rollout::Percent(25));
+    assert_eq!(ff.rollout("stitch"), Rollout::On);
+    assert_eq!(ff.rollout("video_diff"), Rollout::On);
+    assert_eq!(ff.to_json()["transcripts"], "25%");
+    assert_eq!(FeatureFlags::parse("").unwrap(), FeatureFlags::default());
+    assert_eq!(FeatureFlags::parse("collab=100%").unwrap().rollout("collab"), Rollout::On);
+
+    assert!(FeatureFlags::parse("nosuch=on").is_err());
+    assert!(FeatureFlags::parse("collab=maybe").is_err());
+    assert!(FeatureFlags::parse("collab=101%").is_err());
+    assert!(FeatureFlags::parse("collab=on,collab=off").is_err());
+
+    // Percentage rollout is stable and roughly right
+    let n_on = (0..1000).filter(|i| ff.config_enabled("transcripts", &format!("user{i}"))).count();
+    assert!((150..350).contains(&n_on), "{n_on} users of 1000 for 25%");
+    assert_eq!(ff.config_enabled("transcripts", "alice"), ff.config_enabled("transcripts", "alice"));
+    assert!(!ff.config_enabled("collab", "alice"));
+
+    assert_eq!(feature_for_command("join_collab"), Some("collab"));
+    assert_eq!(feature_for_command("add_comment"), None);
+}
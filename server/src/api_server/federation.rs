@@ -0,0 +1,477 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use std::sync::atomic::Ordering::Relaxed;
+use chrono::naive::serde::{ts_seconds, ts_seconds_option};
+use serde::{Deserialize, Serialize};
+use warp::{Filter, Reply};
+use warp::http::StatusCode;
+
+use crate::database::models::{self, federated_kind};
+use crate::database::error::DBError;
+use crate::video_pipeline::{IncomingFile, calc_video_hash, calc_content_hash};
+use super::file_server::{self, Denied};
+use super::server_state::ServerState;
+use super::url_signing::{UrlSigner, unix_now};
+
+/// How often synced folders are pulled from peers
+const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long signed federation requests and file links stay valid
+const REQUEST_TTL_SECS: u64 = 600;
+
+/// Reference to a video or comment in a change feed. Either the sending instance's own object,
+/// or one it received from the requester earlier (by the requester's ID).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjRef {
+    Mine(String),
+    Yours(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeedVideo {
+    pub video_hash: String,
+    pub title: Option<String>,
+    /// Path and signed query for downloading the original file, relative to peer's URL base
+    pub file: String,
+    /// Videos this one was made from (see `DB::add_video_sources`)
+    pub sources: Vec<ObjRef>,
+    pub operation: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeedComment {
+    pub id: i32,
+    pub video: ObjRef,
+    pub parent: Option<ObjRef>,
+    pub user_id: String,
+    pub username: String,
+    pub comment: String,
+    pub timecode: Option<String>,
+    #[serde(default)]
+    pub timecode_end: Option<String>,
+    pub page: Option<i32>,
+    pub region: Option<String>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+    #[serde(with = "ts_seconds_option")]
+    pub edited: Option<chrono::NaiveDateTime>,
+}
+
+/// Changes in a synced folder, as seen by the requesting peer.
+/// Objects received from the requester are only referred to, not sent back.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Feed {
+    /// Time to ask for changes since, next time
+    pub cursor: i64,
+    pub videos: Vec<FeedVideo>,
+    /// New and edited comments
+    pub comments: Vec<FeedComment>,
+    /// IDs of all (non-received) comments in the folder, for detecting deletions
+    pub alive_comments: Vec<i32>,
+}
+
+fn signer(peer: &models::FederationPeer) -> Result<UrlSigner, String> {
+    UrlSigner::new(peer.secret.as_bytes(), REQUEST_TTL_SECS)
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&').filter_map(|p| p.split_once('=')).find(|(k, _)| *k == name)
+        .and_then(|(_, v)| urlencoding::decode(v).ok()).map(|v| v.into_owned())
+}
+
+/// URL encode each segment of a path
+fn encode_path(path: &str) -> String {
+    path.split('/').map(|p| urlencoding::encode(p).into_owned()).collect::<Vec<_>>().join("/")
+}
+
+fn internal_error(e: impl std::fmt::Display) -> Denied {
+    tracing::error!(details=%e, "Error while serving federation request.");
+    Denied(StatusCode::INTERNAL_SERVER_ERROR, "Internal error")
+}
+
+/// What a feed request signs: the folder asked for, and the requester's folder it's synced to,
+/// so a peer can only pull a folder into the one it was set up to sync with
+pub fn changes_signed_path(folder_id: i32, requester_folder_id: i32) -> String {
+    format!("/api/federation/folders/{folder_id}/changes?folder={requester_folder_id}")
+}
+
+/// Check a signed request (`peer`, `expires` and `sig` query parameters) from a peer
+fn authenticate(server: &ServerState, path: &str, query: &str) -> Result<models::FederationPeer, Denied>
+{
+    let name = query_param(query, "peer").ok_or(Denied(StatusCode::UNAUTHORIZED, "Missing peer name"))?;
+    let peer = match server.db.get_federation_peer(&name) {
+        Ok(p) => p,
+        Err(DBError::NotFound()) => return Err(Denied(StatusCode::UNAUTHORIZED, "Unknown peer")),
+        Err(e) => return Err(internal_error(e)),
+    };
+    signer(&peer).map_err(internal_error)?
+        .verify(path, query, unix_now()).map_err(|msg| Denied(StatusCode::UNAUTHORIZED, msg))?;
+    Ok(peer)
+}
+
+/// Reference to a local object, for a feed to `peer`
+fn to_ref(server: &ServerState, peer: &str, kind: &str, local: &str) -> Result<ObjRef, DBError> {
+    Ok(match server.db.get_federated_remote_id(peer, kind, local)? {
+        Some(remote) => ObjRef::Yours(remote),
+        None => ObjRef::Mine(local.into()),
+    })
+}
+
+/// Local ID of an object referred to in `peer`'s feed, if we have it.
+/// Our own objects are only accepted if the peer can know them: received from it, or in `folder` (synced with it).
+fn from_ref(server: &ServerState, peer: &str, folder: &models::Folder, kind: &str, r: &ObjRef) -> Result<Option<String>, DBError> {
+    let local = match r {
+        ObjRef::Mine(remote) => return server.db.get_federated_local_id(peer, kind, remote),
+        ObjRef::Yours(local) => local,
+    };
+    let in_folder = |vh: &str| match server.db.get_video(vh) {
+        Ok(v) => Ok(v.folder_id == Some(folder.id)),
+        Err(DBError::NotFound()) => Ok(false),
+        Err(e) => Err(e),
+    };
+    let known = server.db.get_federated_remote_id(peer, kind, local)?.is_some() || match kind {
+        federated_kind::VIDEO => in_folder(local)?,
+        federated_kind::COMMENT => match server.db.get_comment(local.parse().unwrap_or(-1)) {
+            Ok(c) => in_folder(&c.video_hash)?,
+            Err(DBError::NotFound()) => false,
+            Err(e) => return Err(e),
+        },
+        _ => false,
+    };
+    if !known {
+        tracing::warn!(peer, kind, local, "Peer referred to an object outside the synced folder. Ignored.");
+    }
+    Ok(known.then(|| local.clone()))
+}
+
+/// Collect changes in a folder since `since` (Unix seconds, in our time) for a peer.
+pub fn make_feed(server: &ServerState, peer: &models::FederationPeer, folder_id: i32, since: i64) -> Result<Feed, String>
+{
+    let db_err = |e: DBError| format!("DB error: {e}");
+    let now = unix_now();
+    let since = chrono::NaiveDateTime::from_timestamp_opt(since, 0).unwrap_or_default();
+    let signer = signer(peer)?;
+    let mut feed = Feed { cursor: now as i64, ..Default::default() };
+
+    for v in server.db.get_folder_videos(folder_id).map_err(db_err)? {
+        let vh = &v.video_hash;
+        let video_ref = to_ref(server, &peer.name, federated_kind::VIDEO, vh).map_err(db_err)?;
+        if let (ObjRef::Mine(_), Some(orig)) = (&video_ref, &v.orig_filename) {
+            let path = format!("/api/federation/files/{}/orig/{}", vh, orig);
+            let sources = server.db.get_video_sources(vh).map_err(db_err)?.iter()
+                .map(|s| to_ref(server, &peer.name, federated_kind::VIDEO, s)).collect::<Result<Vec<_>, _>>().map_err(db_err)?;
+            feed.videos.push(FeedVideo {
+                video_hash: vh.clone(),
+                title: v.title.clone(),
+                file: format!("{}?peer={}&{}", encode_path(&path), urlencoding::encode(&peer.name), signer.sign(&path, now)),
+                sources,
+                operation: server.db.get_video_operation(vh).map_err(db_err)?,
+            });
+        }
+        for c in server.db.get_video_comments(vh).map_err(db_err)?.into_iter().filter(|c| !c.is_internal()) {
+            if server.db.get_federated_remote_id(&peer.name, federated_kind::COMMENT, &c.id.to_string()).map_err(db_err)?.is_some() {
+                continue;   // Came from the requester
+            }
+            feed.alive_comments.push(c.id);
+            if c.created < since && c.edited.is_none_or(|e| e < since) {
+                continue;
+            }
+            let parent = c.parent_id.map(|p| to_ref(server, &peer.name, federated_kind::COMMENT, &p.to_string()))
+                .transpose().map_err(db_err)?;
+            feed.comments.push(FeedComment {
+                id: c.id,
+                video: video_ref.clone(),
+                parent,
+                user_id: c.user_id,
+                username: c.username,
+                comment: c.comment,
+                timecode: c.timecode,
+                timecode_end: c.timecode_end,
+                page: c.page,
+                region: c.region,
+                created: c.created,
+                edited: c.edited,
+            });
+        }
+    }
+    Ok(feed)
+}
+
+/// Serve a change feed for a folder synced with the requesting peer
+fn handle_changes(server: &ServerState, folder_id: i32, query: &str) -> Result<Feed, Denied>
+{
+    let requester_folder_id = query_param(query, "folder").and_then(|f| f.parse::<i32>().ok())
+        .ok_or(Denied(StatusCode::BAD_REQUEST, "Missing requesting folder"))?;
+    let peer = authenticate(server, &changes_signed_path(folder_id, requester_folder_id), query)?;
+    match server.db.get_folder_sync(folder_id, &peer.name) {
+        Ok(sync) if sync.remote_folder_id == requester_folder_id => {},
+        Ok(_) => return Err(Denied(StatusCode::FORBIDDEN, "Folder is not synced with your folder")),
+        Err(DBError::NotFound()) => return Err(Denied(StatusCode::FORBIDDEN, "Folder is not synced with you")),
+        Err(e) => return Err(internal_error(e)),
+    }
+    let since = query_param(query, "since").and_then(|s| s.parse::<i64>().ok()).unwrap_or(0);
+    tracing::debug!(peer=%peer.name, folder_id, since, "Sending folder changes to peer.");
+    make_feed(server, &peer, folder_id, since).map_err(internal_error)
+}
+
+/// Access check for original files downloaded by peers: signed by the peer, and in a folder synced with it
+fn check_federated_file(server: &ServerState, path: &str, query: &str) -> Result<Option<String>, Denied>
+{
+    let peer = authenticate(server, &format!("/api/federation/files/{path}"), query)?;
+    let v = file_server::video_of_path(server, path)?;
+    let synced = v.folder_id.is_some_and(|f| server.db.get_folder_sync(f, &peer.name).is_ok());
+    if !synced || path.split('/').nth(1) != Some("orig") {
+        return Err(Denied(StatusCode::FORBIDDEN, "Video is not synced with you"));
+    }
+    Ok(None)
+}
+
+/// Warp filter for the federation API, for syncing folders between Clapshot instances.
+///
+/// Each instance pulls changes of the folders it syncs from the peer's `/api/federation/folders/<id>/changes`
+/// feed (see `sync_folder`), and the peer does the same the other way around. Requests are signed with a secret
+/// shared with the peer (see `url_signing::UrlSigner`), and a peer can only read folders synced with it,
+/// into the folder they're paired with.
+///
+/// Synced: videos (original files, re-ingested locally), their version links, and comments with edits and deletions.
+/// Conflicting comment edits are resolved by last writer wins.
+/// Not synced: subfolders, drawings and video deletions (a deleted local copy is not received again, though).
+pub fn federation_filter(server: ServerState) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone
+{
+    let feed_server = server.clone();
+    let rt_changes = warp::path!("api" / "federation" / "folders" / i32 / "changes")
+        .and(warp::get())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(move |folder_id: i32, query: String| {
+            match handle_changes(&feed_server, folder_id, &query) {
+                Ok(feed) => warp::reply::json(&feed).into_response(),
+                Err(Denied(status, msg)) => warp::reply::with_status(msg.to_string(), status).into_response(),
+            }
+        });
+
+    // Served as binary, so the peer's URL download accepts any media type (e.g. stills)
+    let files_server = server.clone();
+    let rt_files = file_server::serve_dir(warp::path("api").and(warp::path("federation")).and(warp::path("files")),
+            server.videos_dir.clone(), move |_user_id, path, query| check_federated_file(&files_server, path, query))
+        .map(|r| warp::reply::with_header(r, "content-type", "application/octet-stream"));
+
+    rt_changes.or(rt_files)
+}
+
+/// Get changes of a synced folder from the peer
+fn fetch_feed(peer: &models::FederationPeer, sync: &models::FolderSync) -> Result<Feed, String>
+{
+    let signed_path = changes_signed_path(sync.remote_folder_id, sync.folder_id);
+    let url = format!("{}{}&peer={}&since={}&{}", peer.url.trim_end_matches('/'), signed_path,
+        urlencoding::encode(&peer.self_name), sync.cursor, signer(peer)?.sign(&signed_path, unix_now()));
+    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs(60)).build().map_err(|e| e.to_string())?;
+    let resp = client.get(url).send().map_err(|e| format!("Request to peer failed: {e}"))?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        return Err(format!("Peer replied {}: {}", status, resp.text().unwrap_or_default()));
+    }
+    resp.json::<Feed>().map_err(|e| format!("Invalid reply from peer: {e}"))
+}
+
+/// Download a video from the peer and submit it for ingest, as the folder owner's.
+/// Identical videos the owner already has are linked instead.
+fn receive_video(server: &ServerState, peer: &models::FederationPeer, folder: &models::Folder, fv: &FeedVideo) -> Result<(), String>
+{
+    let db_err = |e: DBError| format!("DB error: {e}");
+    let url = reqwest::Url::parse(&format!("{}{}", peer.url.trim_end_matches('/'), fv.file)).map_err(|e| format!("Bad file URL: {e}"))?;
+    let file = super::url_ingest::download_url(&url, &server.upload_dir, None, false, |_, _| {})
+        .map_err(|e| format!("Failed to download video '{}': {}", fv.video_hash, e))?;
+    let dir = file.parent().ok_or("Bad download path")?.to_path_buf();
+    let remove_dir = || std::fs::remove_dir_all(&dir).unwrap_or_else(|e| tracing::warn!(details=%e, "Failed to remove download dir."));
+
+    let owner = &folder.user_id;
+    let vh = calc_video_hash(&file, owner).map_err(|e| e.to_string())?;
+    let content_hash = calc_content_hash(&file).map_err(|e| e.to_string())?;
+    let existing = match server.db.get_video(&vh) {
+        Ok(v) => Some(v.video_hash),
+        Err(_) => server.db.get_user_videos_by_content_hash(owner, &content_hash).map_err(db_err)?
+            .into_iter().next().map(|v| v.video_hash),
+    };
+    let local_vh = existing.clone().unwrap_or(vh);
+    server.db.add_federated_object(&models::FederatedObjectInsert {
+        peer: peer.name.clone(),
+        kind: federated_kind::VIDEO.into(),
+        remote_id: fv.video_hash.clone(),
+        local_id: local_vh.clone(),
+        pending_folder_id: Some(folder.id),
+    }).map_err(db_err)?;
+
+    if existing.is_some() {
+        tracing::info!(remote=%fv.video_hash, video_hash=%local_vh, "Peer's video already exists here, linked it.");
+        remove_dir();
+        return Ok(());
+    }
+    let mut sources = vec![];
+    for s in &fv.sources {
+        if let Some(s) = from_ref(server, &peer.name, folder, federated_kind::VIDEO, s).map_err(db_err)? { sources.push(s); }
+    }
+    if !sources.is_empty() {
+        let op = fv.operation.as_deref().filter(|o| !o.is_empty()).unwrap_or("Derived");
+        server.db.add_video_sources(&local_vh, &sources, &format!("{} (synced from {})", op, peer.name)).map_err(db_err)?;
+    }
+    tracing::info!(remote=%fv.video_hash, video_hash=%local_vh, "Submitting video received from peer.");
+    server.upload_tx.send(IncomingFile { file_path: file, user_id: owner.clone(), ..Default::default() })
+        .map_err(|_| "Internal error: couldn't submit video for processing".to_string())
+}
+
+/// Apply a change feed from a peer to a synced folder.
+///
+/// # Returns
+/// Number of changes applied
+pub(crate) fn apply_feed(server: &ServerState, peer: &models::FederationPeer, folder: &models::Folder, feed: &Feed) -> Result<usize, String>
+{
+    let db = &server.db;
+    let db_err = |e: DBError| format!("DB error: {e}");
+    let mut n_changes = 0;
+
+    // New videos. Once received, never again (even if the local copy was deleted).
+    for fv in &feed.videos {
+        if db.get_federated_local_id(&peer.name, federated_kind::VIDEO, &fv.video_hash).map_err(db_err)?.is_none() {
+            receive_video(server, peer, folder, fv)?;
+            n_changes += 1;
+        }
+    }
+
+    // New and edited comments, parents before replies
+    let mut comments = feed.comments.iter().collect::<Vec<_>>();
+    comments.sort_by_key(|c| c.id);
+    for fc in comments {
+        let remote_id = fc.id.to_string();
+        if let Some(local) = db.get_federated_local_id(&peer.name, federated_kind::COMMENT, &remote_id).map_err(db_err)? {
+            // Known comment (unless deleted here): last writer wins
+            let Ok(c) = db.get_comment(local.parse().unwrap_or(-1)) else { continue };
+            let remote_time = fc.edited.unwrap_or(fc.created);
+            if c.comment != fc.comment && remote_time > c.edited.unwrap_or(c.created) {
+                db.set_comment_text(c.id, &fc.comment, remote_time).map_err(db_err)?;
+                n_changes += 1;
+            }
+            continue;
+        }
+        let Some(vh) = from_ref(server, &peer.name, folder, federated_kind::VIDEO, &fc.video).map_err(db_err)? else {
+            tracing::debug!(comment=fc.id, "Skipping comment on a video not received from peer.");
+            continue;
+        };
+        let parent_id = match &fc.parent {
+            Some(p) => from_ref(server, &peer.name, folder, federated_kind::COMMENT, p).map_err(db_err)?
+                .and_then(|p| p.parse::<i32>().ok()).filter(|p| db.get_comment(*p).is_ok()),
+            None => None,
+        };
+        let new_id = db.add_imported_comment(&models::CommentInsert {
+            video_hash: vh,
+            parent_id,
+            user_id: format!("{}@{}", fc.user_id, peer.name),
+            username: fc.username.clone(),
+            comment: fc.comment.clone(),
+            timecode: fc.timecode.clone(),
+            timecode_end: fc.timecode_end.clone(),
+            drawing: None,
+            page: fc.page,
+            region: fc.region.clone(),
+            visibility: models::comment_visibility::EXTERNAL.into(),
+        }, fc.created, fc.edited).map_err(db_err)?;
+        db.add_federated_object(&models::FederatedObjectInsert {
+            peer: peer.name.clone(),
+            kind: federated_kind::COMMENT.into(),
+            remote_id,
+            local_id: new_id.to_string(),
+            pending_folder_id: None,
+        }).map_err(db_err)?;
+        n_changes += 1;
+    }
+
+    // Comments deleted at peer (on videos in this folder, or on their way here)
+    let received_videos = db.get_federated_objects(&peer.name, federated_kind::VIDEO).map_err(db_err)?;
+    let folder_videos = db.get_folder_videos(folder.id).map_err(db_err)?.into_iter().map(|v| v.video_hash)
+        .chain(received_videos.iter().filter(|o| o.pending_folder_id == Some(folder.id)).map(|o| o.local_id.clone()))
+        .collect::<HashSet<_>>();
+    let alive = feed.alive_comments.iter().map(|id| id.to_string()).collect::<HashSet<_>>();
+    for obj in db.get_federated_objects(&peer.name, federated_kind::COMMENT).map_err(db_err)? {
+        if alive.contains(&obj.remote_id) { continue; }
+        let Ok(c) = db.get_comment(obj.local_id.parse().unwrap_or(-1)) else { continue };
+        if folder_videos.contains(&c.video_hash) {
+            db.del_comment(c.id).map_err(db_err)?;
+            n_changes += 1;
+        }
+    }
+
+    // Put received videos into the folder once they're ingested (unless owner already placed them somewhere)
+    for obj in received_videos.iter().filter(|o| o.pending_folder_id == Some(folder.id)) {
+        match db.get_video(&obj.local_id) {
+            Ok(v) => {
+                if v.folder_id.is_none() { db.set_video_folder(&v.video_hash, Some(folder.id)).map_err(db_err)?; }
+                db.clear_federated_pending_folder(&peer.name, &obj.remote_id).map_err(db_err)?;
+            },
+            Err(DBError::NotFound()) => {},     // Not ingested yet
+            Err(e) => return Err(db_err(e)),
+        }
+    }
+    Ok(n_changes)
+}
+
+/// Pull changes of a synced folder from the peer, apply them, and record the result.
+/// Blocks until done.
+///
+/// # Returns
+/// Number of changes applied
+pub fn sync_folder(server: &ServerState, sync: &models::FolderSync) -> Result<usize, String>
+{
+    let _span = tracing::info_span!("folder_sync", folder=sync.folder_id, peer=%sync.peer).entered();
+    let res = (|| {
+        let peer = server.db.get_federation_peer(&sync.peer).map_err(|e| format!("Peer '{}': {}", sync.peer, e))?;
+        let folder = server.db.get_folder(sync.folder_id).map_err(|e| format!("Folder {}: {}", sync.folder_id, e))?;
+        let feed = fetch_feed(&peer, sync)?;
+        apply_feed(server, &peer, &folder, &feed).map(|n| (feed.cursor, n))
+    })();
+    let db_res = match &res {
+        Ok((cursor, n)) => {
+            if *n > 0 { tracing::info!(n_changes=n, "Folder synced."); }
+            server.db.set_folder_sync_result(sync.id, Some(*cursor), None)
+        },
+        Err(e) => {
+            tracing::warn!(details=%e, "Folder sync failed.");
+            server.db.set_folder_sync_result(sync.id, None, Some(e))
+        },
+    };
+    db_res.unwrap_or_else(|e| tracing::error!(details=%e, "Failed to save folder sync result."));
+    res.map(|(_, n)| n)
+}
+
+/// Sync all synced folders with their peers periodically, until server terminates
+pub fn run_sync_loop(server: ServerState)
+{
+    let _span = tracing::info_span!("FEDERATION").entered();
+    let mut last_run: Option<Instant> = None;
+    while !server.terminate_flag.load(Relaxed) {
+        if last_run.is_none_or(|t| t.elapsed() >= SYNC_INTERVAL) {
+            last_run = Some(Instant::now());
+            match server.db.get_folder_syncs(None) {
+                Ok(syncs) => for s in syncs {
+                    if server.terminate_flag.load(Relaxed) { break; }
+                    sync_folder(&server, &s).ok();
+                },
+                Err(e) => tracing::error!(details=%e, "Failed to get folder syncs."),
+            }
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_federation_obj_ref()
+{
+    assert_eq!(serde_json::to_string(&ObjRef::Mine("abc".into())).unwrap(), r#"{"mine":"abc"}"#);
+    assert_eq!(serde_json::from_str::<ObjRef>(r#"{"yours":"12"}"#).unwrap(), ObjRef::Yours("12".into()));
+    assert_eq!(query_param("peer=studio%20b&since=5", "peer").as_deref(), Some("studio b"));
+    assert_eq!(query_param("peer=a", "since"), None);
+    assert_eq!(encode_path("/api/x/a b.mp4"), "/api/x/a%20b.mp4");
+}
@@ -0,0 +1,181 @@
+use std::path::{Component, Path, PathBuf};
+use warp::{Filter, Reply};
+use warp::http::{header, HeaderMap, HeaderValue, StatusCode};
+
+use crate::database::models;
+use crate::database::error::DBError;
+use super::parse_auth_headers;
+use super::server_state::ServerState;
+
+/// Cache-Control for served media. Private (per-user auth), revalidated with ETag after expiry.
+const CACHE_CONTROL: &str = "private, max-age=3600";
+
+/// Request rejected by an access check, with status and message for the client
+#[derive(Debug)]
+pub struct Denied(pub StatusCode, pub &'static str);
+impl warp::reject::Reject for Denied {}
+
+/// Client already has the current version of the file (If-None-Match matched)
+#[derive(Debug)]
+struct NotModified(String);
+impl warp::reject::Reject for NotModified {}
+
+/// Weak ETag for a file, from its size and modification time
+fn file_etag(path: &Path) -> Option<String> {
+    let meta = std::fs::metadata(path).ok().filter(|m| m.is_file())?;
+    let mtime = meta.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?;
+    Some(format!("W/\"{:x}-{:x}\"", meta.len(), mtime.as_millis()))
+}
+
+/// Does If-None-Match header match the ETag? (Weak comparison, as for GET/HEAD.)
+fn etag_matches(hdrs: &HeaderMap, etag: &str) -> bool {
+    let strip = |t: &str| t.trim().trim_start_matches("W/").to_string();
+    hdrs.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim() == "*" || v.split(',').any(|t| strip(t) == strip(etag)))
+}
+
+/// Look up the video a served path (`<video_hash>/...`) belongs to
+pub fn video_of_path(server: &ServerState, path: &str) -> Result<models::Video, Denied> {
+    match server.db.get_video(path.split('/').next().unwrap_or_default()) {
+        Ok(v) => Ok(v),
+        Err(DBError::NotFound()) => Err(Denied(StatusCode::NOT_FOUND, "No such video")),
+        Err(e) => {
+            tracing::error!(details=%e, "DB error while checking file access.");
+            Err(Denied(StatusCode::INTERNAL_SERVER_ERROR, "Internal error"))
+        }
+    }
+}
+
+/// Warp filter for serving files from `dir` under `prefix`, with an access check per request.
+/// Supports range requests (seeking in the player), ETag / If-None-Match revalidation, and sets Cache-Control.
+///
+/// # Arguments
+/// * `prefix` - Path filter the files are served under (e.g. `/videos`)
+/// * `dir` - Directory to serve
+/// * `check` - Called with user ID, (decoded) path relative to `dir` and query string. Returns a filename
+///   to serve the file as attachment with, None to serve inline, or `Denied`.
+pub fn serve_dir<P, C>(prefix: P, dir: PathBuf, check: C) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone
+    where P: Filter<Extract = (), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+          C: Fn(&str, &str, &str) -> Result<Option<String>, Denied> + Clone + Send + Sync + 'static
+{
+    let dir_cln = dir.clone();
+    prefix
+        .and(warp::get().or(warp::head()).unify())
+        .and(warp::header::headers_cloned())
+        .and(warp::path::peek())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and_then(move |hdrs: HeaderMap, path: warp::path::Peek, query: String| {
+            let (dir, check) = (dir_cln.clone(), check.clone());
+            async move {
+                let path = urlencoding::decode(path.as_str())
+                    .map_err(|_| warp::reject::custom(Denied(StatusCode::BAD_REQUEST, "Invalid path")))?.to_string();
+                if Path::new(&path).components().any(|c| !matches!(c, Component::Normal(_))) {
+                    return Err(warp::reject::custom(Denied(StatusCode::BAD_REQUEST, "Invalid path")));
+                }
+                let (user_id, _) = parse_auth_headers(&hdrs);
+                let attachment = check(&user_id, &path, &query).map_err(|d| {
+                    tracing::info!(user=%user_id, file=%path, status=%d.0, "File access denied.");
+                    warp::reject::custom(d)
+                })?;
+                let etag = file_etag(&dir.join(&path));
+                if let Some(etag) = etag.as_ref().filter(|e| etag_matches(&hdrs, e)) {
+                    return Err(warp::reject::custom(NotModified(etag.clone())));
+                }
+                Ok::<_, warp::Rejection>((attachment, etag))
+            }
+        })
+        .untuple_one()
+        .and(warp::fs::dir(dir))
+        .map(|attachment: Option<String>, etag: Option<String>, file: warp::fs::File| {
+            let mut res = file.into_response();
+            let hdrs = res.headers_mut();
+            hdrs.insert(header::CACHE_CONTROL, HeaderValue::from_static(CACHE_CONTROL));
+            if let Some(v) = etag.and_then(|e| HeaderValue::from_str(&e).ok()) {
+                hdrs.insert(header::ETAG, v);
+            }
+            let disposition = attachment.map(|f| format!("attachment; filename*=UTF-8''{}", urlencoding::encode(&f)));
+            if let Some(v) = disposition.and_then(|d| HeaderValue::from_str(&d).ok()) {
+                hdrs.insert(header::CONTENT_DISPOSITION, v);
+            }
+            res
+        })
+        .recover(|r: warp::Rejection| async move {
+            if let Some(Denied(status, msg)) = r.find::<Denied>() {
+                Ok(warp::reply::with_status(msg.to_string(), *status).into_response())
+            } else if let Some(NotModified(etag)) = r.find::<NotModified>() {
+                let mut res = warp::reply::with_status("", StatusCode::NOT_MODIFIED).into_response();
+                if let Ok(v) = HeaderValue::from_str(etag) { res.headers_mut().insert(header::ETAG, v); }
+                res.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static(CACHE_CONTROL));
+                Ok(res)
+            } else {
+                Err(r)
+            }
+        })
+}
+
+/// Access check for the `/videos` directory (player, thumbnails, subtitles, clips...).
+/// Anyone can view a video they have the hash (link) of, but originals of transcoded videos
+/// and review packages follow the owner's download permission (see `download::may_download`).
+///
+/// If URL signing is enabled, a valid signature grants access (it was given to an authorized user
+/// recently, see `ServerState::asset_url`), and anonymous requests must have one.
+/// Requests with a share link (see `share_links::check_asset_query`) are allowed only while the
+/// link is valid, and never get originals, packages or internal comments' attachments.
+///
+/// Comment attachments (see `attachments`) are served as downloads with their original names.
+pub fn check_video_file(server: &ServerState, user_id: &str, path: &str, query: &str) -> Result<Option<String>, Denied>
+{
+    let v = video_of_path(server, path)?;
+    let (attachment, internal) = match path.split('/').nth(1) {
+        Some("attachments") => super::attachments::of_path(server, &v, path).map(|(a, internal)| (Some(a.filename), internal))?,
+        _ => (None, false),
+    };
+    let restricted = match path.split('/').nth(1) {
+        Some("orig") => v.recompression_done.is_some(),   // Untranscoded original is the playback file
+        Some("packages") | Some("clips") => true,
+        _ => false,
+    };
+    if let Some(res) = super::share_links::check_asset_query(server, &v.video_hash, query) {
+        res?;
+        return match restricted || internal {
+            true => Err(Denied(StatusCode::FORBIDDEN, "Download not allowed")),
+            false => Ok(attachment),
+        };
+    }
+    if let Some(signer) = &server.url_signer {
+        match signer.verify(&format!("/videos/{path}"), query, super::url_signing::unix_now()) {
+            Ok(()) => return Ok(attachment),
+            Err(msg) if user_id == "anonymous" || query.contains("sig=") => return Err(Denied(StatusCode::FORBIDDEN, msg)),
+            Err(_) => {},
+        }
+    }
+    if restricted && !super::download::may_download(&server.db, &v, user_id, server.db.is_user_admin(user_id).unwrap_or(false)) {
+        return Err(Denied(StatusCode::FORBIDDEN, "Download not allowed"));
+    }
+    if internal {
+        super::attachments::check_internal(server, &v, user_id)?;
+    }
+    Ok(attachment)
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_etag_matching()
+{
+    let mut hdrs = HeaderMap::new();
+    assert!(!etag_matches(&hdrs, "W/\"a-b\""));
+    hdrs.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"x\", W/\"a-b\""));
+    assert!(etag_matches(&hdrs, "W/\"a-b\""));
+    assert!(!etag_matches(&hdrs, "W/\"a-c\""));
+    hdrs.insert(header::IF_NONE_MATCH, HeaderValue::from_static("*"));
+    assert!(etag_matches(&hdrs, "W/\"a-c\""));
+
+    let dir = tempfile::tempdir().unwrap();
+    let f = dir.path().join("f.txt");
+    assert_eq!(file_etag(&f), None);
+    std::fs::write(&f, b"hello").unwrap();
+    assert!(file_etag(&f).unwrap().starts_with("W/\"5-"));
+    assert_eq!(file_etag(dir.path()), None);
+}
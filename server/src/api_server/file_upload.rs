@@ -4,28 +4,165 @@ use futures::stream::TryStreamExt;
 use mpart_async::server::MultipartStream;
 use std::convert::Infallible;
 use std::path::{Path, PathBuf};
+use crossbeam_channel::TrySendError;
 
 use crate::video_pipeline::IncomingFile;
+use crate::video_pipeline::queues::BUSY_MSG;
+use crate::storage::{StorageError, FULL_MSG};
+use crate::video_pipeline::audio_mux::AudioMuxMode;
+use crate::database::models::batch_file_status;
 use super::parse_auth_headers;
+use super::server_state::ServerState;
 
 
 /// Warp filter for multipart/form-data file upload
 /// 
+/// Enforces user quotas (see `quota::Quotas`) before and during the upload.
+/// Refuses uploads with 429 when the video pipeline is too busy to take more,
+/// and with 507 when storage is full (see `storage`).
+///
 /// # Arguments
-/// * `upload_dir` - Path to the directory where the uploaded files will be stored
-/// * `upload_done` - Channel to submit the uploaded file path to further processing
+/// * `server` - Server state (upload dir, DB, quotas and channel to submit the uploaded file path to further processing)
 /// * `mime` - Parsed mime options from the request
 /// * `hdrs` - Authentication headers to be used for identifying the uploader
 /// * `body` - The request body (stream)
+/// * `trace_id` - Trace of the upload, continued by the video pipeline (see `telemetry`)
 pub async fn handle_multipart_upload(
-    upload_dir: std::path::PathBuf,
-    upload_done: crossbeam_channel::Sender<IncomingFile>,
+    server: ServerState,
     mime: mime::Mime,
     hdrs: HeaderMap,
-    body: impl warp::Stream<Item = Result<impl bytes::Buf, warp::Error>> + Unpin)
+    body: impl warp::Stream<Item = Result<impl bytes::Buf, warp::Error>> + Unpin,
+    trace_id: String)
         -> Result<warp::reply::WithStatus<String>, Infallible>
 {
     let (user_id, _) = parse_auth_headers(&hdrs);
+    let upload_dir = server.upload_dir.clone();
+    if server.terminate_flag.load(std::sync::atomic::Ordering::Relaxed) {
+        return Ok(warp::reply::with_status(super::SHUTDOWN_MSG.into(), warp::http::StatusCode::SERVICE_UNAVAILABLE));
+    }
+    if server.db.is_user_disabled(&user_id).unwrap_or(false) {
+        return Ok(warp::reply::with_status("User is disabled".into(), warp::http::StatusCode::FORBIDDEN));
+    }
+    // Backpressure: refuse early if the pipeline can't take more (see `video_pipeline::queues`)
+    if server.upload_tx.is_full() {
+        tracing::warn!(user=%user_id, "Pipeline intake queue full. Refusing upload.");
+        return Ok(warp::reply::with_status(BUSY_MSG.into(), warp::http::StatusCode::TOO_MANY_REQUESTS));
+    }
+    if server.storage.full().is_some() {
+        tracing::warn!(user=%user_id, "Out of storage space. Refusing upload.");
+        return Ok(warp::reply::with_status(FULL_MSG.into(), warp::http::StatusCode::INSUFFICIENT_STORAGE));
+    }
+    let is_admin = server.db.is_user_admin(&user_id).unwrap_or(false);
+
+    // Optional: override server's audio loudness normalization setting
+    let loudnorm = match hdrs.get("X-Loudness-Target").map(|v| v.to_str().unwrap_or_default().parse::<crate::video_pipeline::LoudnormOpt>()) {
+        None => Default::default(),
+        Some(Ok(l)) => l,
+        Some(Err(msg)) => return Ok(warp::reply::with_status(msg, warp::http::StatusCode::BAD_REQUEST)),
+    };
+
+    // Optional: overlays to burn in (e.g. "timecode,filename"), instead of server's default
+    let burn_in = match hdrs.get("X-Burn-In").map(|v| v.to_str().unwrap_or_default().parse::<crate::video_pipeline::burn_in::BurnInFlags>()) {
+        None => None,
+        Some(Ok(b)) => Some(b),
+        Some(Err(msg)) => return Ok(warp::reply::with_status(msg, warp::http::StatusCode::BAD_REQUEST)),
+    };
+
+    // Optional: frame rate for an image sequence upload (several numbered frames in one request)
+    let sequence_fps = match hdrs.get("X-Sequence-Fps").map(|v| crate::video_pipeline::conform::parse_fps(v.to_str().unwrap_or_default())) {
+        None => None,
+        Some(Ok(fps)) => Some(fps),
+        Some(Err(msg)) => return Ok(warp::reply::with_status(msg, warp::http::StatusCode::BAD_REQUEST)),
+    };
+
+    // Optional: only report what ingesting the file would do (metadata, transcoding, quota), then discard it
+    let dry_run = hdrs.get("X-Dry-Run").map(|v| ["1", "true", "yes"].contains(&v.to_str().unwrap_or_default().to_lowercase().as_str())).unwrap_or(false);
+
+    // Optional: file is part of an upload batch
+    let batch_file_id = match hdrs.get("X-Batch-File-Id").map(|v| v.to_str().unwrap_or_default().parse::<i32>()) {
+        None => None,
+        Some(Ok(id)) => Some(id),
+        Some(Err(_)) => return Ok(warp::reply::with_status("Invalid X-Batch-File-Id".into(), warp::http::StatusCode::BAD_REQUEST)),
+    };
+    if let Some(id) = batch_file_id {
+        let bf = server.db.get_upload_batch_file(id).ok();
+        let batch = bf.as_ref().and_then(|f| server.db.get_upload_batch(f.batch_id).ok());
+        match (bf, batch) {
+            (Some(bf), Some(batch)) if batch.user_id == user_id => {
+                if batch.cancelled || bf.status != batch_file_status::PENDING {
+                    let state = if batch.cancelled { "cancelled" } else { &bf.status };
+                    return Ok(warp::reply::with_status(format!("Batch file is {state}"), warp::http::StatusCode::CONFLICT));
+                }
+            },
+            _ => return Ok(warp::reply::with_status("No such batch file".into(), warp::http::StatusCode::FORBIDDEN)),
+        }
+    }
+
+    // Optional: video goes into this folder, and is transcoded with the folder's preset
+    let folder_id = match hdrs.get("X-Folder-Id").map(|v| v.to_str().unwrap_or_default().parse::<i32>()) {
+        None => None,
+        Some(Ok(id)) => match server.db.get_folder(id) {
+            Ok(f) if f.user_id == user_id || is_admin => Some(f.id),
+            _ => return Ok(warp::reply::with_status("No such folder".into(), warp::http::StatusCode::FORBIDDEN)),
+        },
+        Some(Err(_)) => return Ok(warp::reply::with_status("Invalid X-Folder-Id".into(), warp::http::StatusCode::BAD_REQUEST)),
+    };
+
+    // Optional: uploaded file is a new audio mix for an existing video. Result is a new video.
+    let replace_audio = match hdrs.get("X-Replace-Audio-Of").map(|v| v.to_str().unwrap_or_default()) {
+        None => None,
+        Some(vh) => {
+            if batch_file_id.is_some() {
+                return Ok(warp::reply::with_status("Audio replacement can't be part of an upload batch".into(), warp::http::StatusCode::BAD_REQUEST));
+            }
+            let mode = match hdrs.get("X-Audio-Mode").map(|v| v.to_str().unwrap_or_default().parse::<AudioMuxMode>()) {
+                None => AudioMuxMode::default(),
+                Some(Ok(m)) => m,
+                Some(Err(msg)) => return Ok(warp::reply::with_status(msg, warp::http::StatusCode::BAD_REQUEST)),
+            };
+            match server.db.get_video(vh) {
+                Ok(v) if v.added_by_userid.as_deref() == Some(user_id.as_str()) || is_admin => Some((v, mode)),
+                Ok(_) => return Ok(warp::reply::with_status("Can only replace audio of your own videos".into(), warp::http::StatusCode::FORBIDDEN)),
+                Err(_) => return Ok(warp::reply::with_status("No such video".into(), warp::http::StatusCode::NOT_FOUND)),
+            }
+        }
+    };
+
+    // Optional: uploaded file is a review package (zip) exported from another instance.
+    // Comment authors can be mapped to local users, or matched to local users with the same ID
+    // (`X-Import-Match-Users`), but only admin can attribute comments to others.
+    let import_package = hdrs.get("X-Import-Package").map(|v| ["1", "true", "yes"].contains(&v.to_str().unwrap_or_default().to_lowercase().as_str())).unwrap_or(false);
+    let import_user_map = match hdrs.get("X-Import-User-Map").map(|v| super::package_import::parse_user_map(v.to_str().unwrap_or_default())) {
+        None => Default::default(),
+        Some(Ok(m)) => m,
+        Some(Err(msg)) => return Ok(warp::reply::with_status(msg, warp::http::StatusCode::BAD_REQUEST)),
+    };
+    let import_match_users = hdrs.get("X-Import-Match-Users").map(|v| ["1", "true", "yes"].contains(&v.to_str().unwrap_or_default().to_lowercase().as_str())).unwrap_or(false);
+    if !is_admin && (import_match_users || import_user_map.values().any(|local| *local != user_id)) {
+        return Ok(warp::reply::with_status("Can only attribute imported comments to yourself".into(), warp::http::StatusCode::FORBIDDEN));
+    }
+
+    // Check quotas that can be checked before receiving any data
+    let mut max_bytes = None;
+    let quotas = server.config.quotas();
+    if !quotas.is_unlimited() {
+        let usage = match crate::quota::get_user_usage(&server.db, &server.videos_dir, &user_id) {
+            Ok(u) => u,
+            Err(e) => {
+                tracing::error!(details=%e, "Failed to get user usage for quota check.");
+                return Ok(warp::reply::with_status("Internal error: quota check failed".into(), warp::http::StatusCode::INTERNAL_SERVER_ERROR));
+            }
+        };
+        if !dry_run {
+            if let Err(msg) = quotas.check_new_job(&usage) {
+                return Ok(warp::reply::with_status(msg, warp::http::StatusCode::TOO_MANY_REQUESTS));
+            }
+        }
+        if let Err(msg) = quotas.check_new_file(&usage, 0) {
+            return Ok(warp::reply::with_status(msg, warp::http::StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        max_bytes = quotas.max_new_file_bytes(&usage);
+    }
 
     let boundary = mime.get_param("boundary").map(|v| v.to_string());
     let boundary = match boundary {
@@ -35,6 +172,12 @@ pub async fn handle_multipart_upload(
 
     let mut stream = MultipartStream::new(boundary, body.map_ok(|mut buf| buf.copy_to_bytes(buf.remaining())));
     let mut uploaded_file: PathBuf = PathBuf::new();
+    let mut n_sidecars = 0;
+    let mut n_frames = 0;
+
+    // Unique upload dir. Subtitle files uploaded in the same request are put next to the video, as sidecars.
+    let uuid = uuid::Uuid::new_v4();
+    let new_dir = async_std::path::PathBuf::from(&upload_dir).join(uuid.to_string());
 
     while let Ok(Some(mut field)) = stream.try_next().await {
         match field.name().unwrap_or("unknown".into()).as_ref() {
@@ -48,20 +191,18 @@ pub async fn handle_multipart_upload(
                     Ok(filename) =>
                     {
                         let path = Path::new(&filename);
-                        if path.file_name() != Some(path.as_os_str()) {
+                        if !crate::video_pipeline::is_plain_file_name(&filename) {
                             return Ok(warp::reply::with_status("Filename must not contain path".into(), warp::http::StatusCode::BAD_REQUEST));
                         }
 
-                        // Make a unique upload dir
-                        let uuid = uuid::Uuid::new_v4();
-                        let new_dir = async_std::path::PathBuf::from(&upload_dir).join(uuid.to_string());
                         let dst =  new_dir.join(path.file_name().unwrap());
                         if dst.exists().await {
-                            tracing::error!("Upload dst '{}' already exists, even tough it was prefixed with uuid4. Bug??", dst.display());
-                            return Ok(warp::reply::with_status("Internal error: file already exists".into(), warp::http::StatusCode::INTERNAL_SERVER_ERROR));
+                            tracing::warn!("Upload dst '{}' already exists. Same filename twice in one upload?", dst.display());
+                            return Ok(warp::reply::with_status("Duplicate filename in upload".into(), warp::http::StatusCode::BAD_REQUEST));
                         }
                         if let Err(e) = async_std::fs::create_dir_all(&new_dir).await {
                             tracing::error!("Failed to create upload dir: {}", e);
+                            if let Some(reply) = storage_error_reply(&server, &e) { return Ok(reply); }
                             return Ok(warp::reply::with_status("Internal error: failed to create upload dir".into(), warp::http::StatusCode::INTERNAL_SERVER_ERROR));
                         }
 
@@ -70,6 +211,7 @@ pub async fn handle_multipart_upload(
                             Err(e) => {
                                 let msg = format!("Failed to create file '{}': {}", dst.display(), e);
                                 tracing::error!(msg);
+                                if let Some(reply) = storage_error_reply(&server, &e) { return Ok(reply); }
                                 return Ok(warp::reply::with_status(msg, warp::http::StatusCode::INTERNAL_SERVER_ERROR));
                             },
                             Ok(mut f) => 
@@ -79,10 +221,17 @@ pub async fn handle_multipart_upload(
 
                                 // Read chunks from HTTP
                                 let read_all_chunks = async move {
+                                    let mut n_bytes = 0u64;
                                     while let Some(chunk) = field.next().await {
                                         match chunk {
-                                            Ok(data) => { buff_tx.send(data).await.unwrap(); },
-                                            Err(e) => { return Err(e.to_string()); }
+                                            Ok(data) => {
+                                                n_bytes += data.len() as u64;
+                                                if max_bytes.map(|m| n_bytes > m).unwrap_or(false) {
+                                                    return Err((format!("File exceeds quota (max {} bytes allowed)", max_bytes.unwrap_or(0)), None));
+                                                }
+                                                buff_tx.send(data).await.unwrap();
+                                            },
+                                            Err(e) => { return Err((e.to_string(), None)); }
                                     }}; Ok(())  // buff_tx dropped
                                 };
 
@@ -90,24 +239,35 @@ pub async fn handle_multipart_upload(
                                 let write_all_chunks = async move {
                                     while let Some(data) = buff_rx.recv().await {
                                         futures_util::AsyncWriteExt::write_all(&mut f, &data).await
-                                            .map_err(|e| e.to_string())?;
+                                            .map_err(|e| (e.to_string(), StorageError::from_io(&e)))?;
                                     }; Ok(())
                                 };
 
                                 // Run both tasks in parallel, cleanup on error
-                                if let Err(e) = tokio::try_join!(read_all_chunks, write_all_chunks)
+                                if let Err((e, storage_err)) = tokio::try_join!(read_all_chunks, write_all_chunks)
                                 {
                                     tracing::error!("Upload failed: {}", e);
                                     // Remove the file & dir, since it's incomplete
                                     if let Err(e) = async_std::fs::remove_file(&dst).await {
                                         tracing::warn!("Failed to remove incomplete upload file: {}", e);
-                                    } else if let Err(e) = async_std::fs::remove_dir(new_dir).await {
+                                    } else if let Err(e) = async_std::fs::remove_dir(&new_dir).await {
                                         tracing::warn!("Failed to remove incomplete upload dir: {}", e);
                                     }
+                                    if let Some(se) = storage_err {
+                                        server.report_storage_error(se);
+                                        return Ok(warp::reply::with_status(FULL_MSG.into(), warp::http::StatusCode::INSUFFICIENT_STORAGE));
+                                    }
                                     return Ok(warp::reply::with_status(format!("Upload failed: {e}"), warp::http::StatusCode::BAD_REQUEST));
                                 }
                                 tracing::info!("File uploaded: '{:?}'", dst);
-                                uploaded_file = dst.into();
+                                if crate::video_pipeline::subtitles::is_subtitle_file(dst.as_ref()) {
+                                    n_sidecars += 1;
+                                } else {
+                                    if crate::video_pipeline::image_sequence::is_sequence_frame(dst.as_ref()) {
+                                        n_frames += 1;
+                                    }
+                                    uploaded_file = dst.into();
+                                }
                             }
                         };
                     }
@@ -119,9 +279,108 @@ pub async fn handle_multipart_upload(
         }
     }
 
-    if let Err(e) = upload_done.send(IncomingFile{ file_path: uploaded_file, user_id: user_id }) {
-        tracing::error!("Failed to send upload ok signal: {:?}", e);
-        return Ok(warp::reply::with_status("Internal error: failed to send upload ok signal".into(), warp::http::StatusCode::INTERNAL_SERVER_ERROR));
+    if n_sidecars > 0 && uploaded_file.as_os_str().is_empty() {
+        if let Err(e) = async_std::fs::remove_dir_all(&new_dir).await {
+            tracing::warn!("Failed to remove upload dir: {}", e);
+        }
+        return Ok(warp::reply::with_status("Subtitles must be uploaded together with a video".into(), warp::http::StatusCode::BAD_REQUEST));
+    }
+
+    if dry_run {
+        return Ok(dry_run_report(server, IncomingFile{ file_path: uploaded_file, user_id, loudnorm, folder_id, burn_in, ..Default::default() },
+            batch_file_id.is_some() || replace_audio.is_some() || import_package || n_frames > 1, new_dir.into()).await);
+    }
+
+    if import_package {
+        if batch_file_id.is_some() || replace_audio.is_some() || n_frames > 1 || n_sidecars > 0 || uploaded_file.as_os_str().is_empty() {
+            if let Err(e) = async_std::fs::remove_dir_all(&new_dir).await {
+                tracing::warn!("Failed to remove upload dir: {}", e);
+            }
+            return Ok(warp::reply::with_status("Review package must be uploaded on its own".into(), warp::http::StatusCode::BAD_REQUEST));
+        }
+        super::package_import::spawn_package_import(server.clone(), user_id, uploaded_file, import_user_map, import_match_users);
+        return Ok(warp::reply::with_status("Ok".into(), warp::http::StatusCode::OK));
+    }
+
+    // Several numbered frames = image sequence. Upload dir is assembled into a movie by the pipeline.
+    if n_frames > 1 {
+        if batch_file_id.is_some() || replace_audio.is_some() {
+            if let Err(e) = async_std::fs::remove_dir_all(&new_dir).await {
+                tracing::warn!("Failed to remove upload dir: {}", e);
+            }
+            return Ok(warp::reply::with_status("Image sequences must be uploaded on their own".into(), warp::http::StatusCode::BAD_REQUEST));
+        }
+        uploaded_file = new_dir.clone().into();
+    }
+
+    if let Some(id) = batch_file_id {
+        if let Err(e) = server.db.set_upload_batch_file_status(id, batch_file_status::PROCESSING, Some(&uploaded_file.to_string_lossy()), None, "") {
+            tracing::error!(details=%e, "Failed to update upload batch file status.");
+        }
+    }
+    if let Some((video, mode)) = replace_audio {
+        if uploaded_file.as_os_str().is_empty() {
+            return Ok(warp::reply::with_status("No audio file in upload".into(), warp::http::StatusCode::BAD_REQUEST));
+        }
+        super::audio_replace::spawn_audio_replace(server.clone(), user_id, video, mode, uploaded_file);
+        return Ok(warp::reply::with_status("Ok".into(), warp::http::StatusCode::OK));
+    }
+
+    // Identical to a video shared with the user? Offer to link it instead of storing a copy.
+    // (Not for upload batches, which track each file to the end, nor when the copy would get overlays burned in.)
+    if batch_file_id.is_none() && uploaded_file.is_file() && server.config.burn_in().resolve(burn_in).is_empty() {
+        let (srv, uid, file) = (server.clone(), user_id.clone(), uploaded_file.clone());
+        match tokio::task::spawn_blocking(move || super::upload_dedup::check_upload(&srv, &uid, &file, loudnorm)).await {
+            Ok(Ok(None)) => {},
+            Ok(Ok(Some(reply))) => return Ok(warp::reply::with_status(reply.to_string(), warp::http::StatusCode::OK)),
+            Ok(Err(e)) => tracing::error!(details=%e, "Duplicate check failed. Processing upload as usual."),
+            Err(e) => tracing::error!(details=%e, "Duplicate check panicked. Processing upload as usual."),
+        }
+    }
+    match server.upload_tx.try_send(IncomingFile{ file_path: uploaded_file, user_id, loudnorm, sequence_fps, folder_id, burn_in, trace_id: Some(trace_id), ..Default::default() }) {
+        Ok(_) => {},
+        Err(TrySendError::Full(_)) => {
+            tracing::warn!("Pipeline intake queue filled up during upload. Discarding it.");
+            if let Err(e) = async_std::fs::remove_dir_all(&new_dir).await {
+                tracing::warn!("Failed to remove upload dir: {}", e);
+            }
+            return Ok(warp::reply::with_status(BUSY_MSG.into(), warp::http::StatusCode::TOO_MANY_REQUESTS));
+        },
+        Err(e) => {
+            tracing::error!("Failed to send upload ok signal: {:?}", e);
+            return Ok(warp::reply::with_status("Internal error: failed to send upload ok signal".into(), warp::http::StatusCode::INTERNAL_SERVER_ERROR));
+        }
     }
     Ok(warp::reply::with_status("Ok".into(), warp::http::StatusCode::OK))
 }
+
+/// If an I/O error means storage is full, mark it so and make a reply for client
+fn storage_error_reply(server: &ServerState, e: &std::io::Error) -> Option<warp::reply::WithStatus<String>>
+{
+    let se = StorageError::from_io(e)?;
+    server.report_storage_error(se);
+    Some(warp::reply::with_status(FULL_MSG.into(), warp::http::StatusCode::INSUFFICIENT_STORAGE))
+}
+
+/// Run preflight checks on an uploaded file and remove it. Replies with a JSON report.
+async fn dry_run_report(server: ServerState, file: IncomingFile, unsupported: bool, upload_dir: PathBuf) -> warp::reply::WithStatus<String>
+{
+    let res = if unsupported {
+        Err((warp::http::StatusCode::BAD_REQUEST, "Dry run is only supported for single video uploads".to_string()))
+    } else if file.file_path.as_os_str().is_empty() {
+        Err((warp::http::StatusCode::BAD_REQUEST, "No video file in upload".to_string()))
+    } else {
+        let srv = server.clone();
+        tokio::task::spawn_blocking(move || crate::video_pipeline::preflight::preflight(
+                &file, &srv.policy, &srv.db, &srv.videos_dir, &srv.sandbox, &srv.config.get()))
+            .await.unwrap_or_else(|e| Err(e.to_string()))
+            .map_err(|e| (warp::http::StatusCode::UNPROCESSABLE_ENTITY, e))
+    };
+    if let Err(e) = async_std::fs::remove_dir_all(&upload_dir).await {
+        tracing::warn!("Failed to remove dry run upload dir: {}", e);
+    }
+    match res {
+        Ok(report) => warp::reply::with_status(report.to_string(), warp::http::StatusCode::OK),
+        Err((status, msg)) => warp::reply::with_status(msg, status),
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::server_state::ServerState;
+
+// Messages to users (errors, processing progress, notifications) are written in English in the
+// code, and translated when sent, to the language the user has chosen in client settings
+// (`ui.language` in `user_prefs`, e.g. "fi" or "pt-BR"). Messages are stored in English, so
+// listing old ones (`list_my_messages`) shows them in the current language.
+//
+// English is built in. Other languages are added as catalogs in `locales/` in data dir, one
+// JSON file per language (`fi.json`, `pt-BR.json`), loaded at startup. A catalog maps English
+// messages to translations. Parts that vary (file names, numbers, errors) are `{}` in both;
+// translations can reorder them as `{0}`, `{1}`...:
+//
+//   { "No such video.": "Videota ei löydy.",
+//     "File is too big (max {} MB)": "Tiedosto on liian suuri (enintään {} Mt)",
+//     "'{}' failed: {}": "{1} ({0} epäonnistui)" }
+//
+// Messages missing from a catalog are sent in English. Regional variants fall back to their
+// language ("pt-BR" to "pt"). Message details (tool output, technical errors) aren't translated.
+
+/// Language of the messages in code
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// Translations of one language
+#[derive(Default)]
+struct Catalog {
+    exact: HashMap<String, String>,
+    /// Messages with `{}`, split at them, most specific first
+    patterns: Vec<(Vec<String>, String)>,
+}
+
+impl Catalog {
+    fn from_json(json: &str) -> anyhow::Result<Catalog> {
+        let entries: HashMap<String, String> = serde_json::from_str(json)?;
+        let mut cat = Catalog::default();
+        for (src, dst) in entries {
+            if src.contains("{}") {
+                cat.patterns.push((src.split("{}").map(String::from).collect(), dst));
+            } else {
+                cat.exact.insert(src, dst);
+            }
+        }
+        let literal_len = |parts: &Vec<String>| parts.iter().map(String::len).sum::<usize>();
+        cat.patterns.sort_by(|a, b| literal_len(&b.0).cmp(&literal_len(&a.0)).then(a.0.cmp(&b.0)));
+        Ok(cat)
+    }
+
+    fn translate(&self, msg: &str) -> Option<String> {
+        if let Some(t) = self.exact.get(msg) {
+            return Some(t.clone());
+        }
+        self.patterns.iter().find_map(|(parts, dst)| Some(fill(dst, &match_pattern(parts, msg)?)))
+    }
+}
+
+/// Match a message against a pattern split at its `{}`s. Returns the texts in place of them.
+fn match_pattern<'a>(parts: &[String], msg: &'a str) -> Option<Vec<&'a str>> {
+    let (first, last) = (parts.first()?, parts.last()?);
+    let mut rest = msg.strip_prefix(first.as_str())?;
+    let mut args = vec![];
+    for part in &parts[1..parts.len() - 1] {
+        let (arg, after) = rest.split_once(part.as_str())?;
+        args.push(arg);
+        rest = after;
+    }
+    args.push(rest.strip_suffix(last.as_str())?);
+    Some(args)
+}
+
+/// Put texts into a translation's `{}` (in order) and `{0}`, `{1}`... (by index)
+fn fill(dst: &str, args: &[&str]) -> String {
+    let mut res = String::with_capacity(dst.len());
+    let (mut rest, mut next) = (dst, 0);
+    while let Some(start) = rest.find('{') {
+        res.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = after.find('}').filter(|e| after[..*e].chars().all(|c| c.is_ascii_digit()));
+        match end {
+            Some(end) => {
+                let i = if end == 0 { next += 1; next - 1 } else { after[..end].parse().unwrap_or(usize::MAX) };
+                res.push_str(args.get(i).copied().unwrap_or_default());
+                rest = &after[end + 1..];
+            },
+            None => { res.push('{'); rest = after; },
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+/// Lowercase, with '-' separator ("pt_BR" -> "pt-br")
+fn normalize(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_lowercase()
+}
+
+/// Translation catalogs of all languages
+#[derive(Default)]
+pub struct Catalogs {
+    by_locale: HashMap<String, Catalog>,
+}
+
+impl Catalogs {
+
+    /// Load catalogs (`<locale>.json`) from a dir. Missing dir means English only.
+    /// Catalogs that fail to load are skipped, with an error logged.
+    pub fn load(dir: &Path) -> Catalogs {
+        let mut res = Catalogs::default();
+        let Ok(entries) = std::fs::read_dir(dir) else { return res; };
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.extension().is_some_and(|e| e == "json")) {
+            let locale = normalize(&path.file_stem().unwrap_or_default().to_string_lossy());
+            match std::fs::read_to_string(&path).map_err(anyhow::Error::from).and_then(|json| Catalog::from_json(&json)) {
+                Ok(cat) => {
+                    tracing::info!(locale, n_messages=cat.exact.len() + cat.patterns.len(), "Loaded translation catalog.");
+                    res.by_locale.insert(locale, cat);
+                },
+                Err(e) => tracing::error!(file=%path.display(), details=%e, "Failed to load translation catalog. Skipping."),
+            }
+        }
+        res
+    }
+
+    /// Languages messages can be sent in, English first
+    pub fn locales(&self) -> Vec<String> {
+        let mut res = self.by_locale.keys().filter(|l| l.as_str() != DEFAULT_LOCALE).cloned().collect::<Vec<_>>();
+        res.sort();
+        res.insert(0, DEFAULT_LOCALE.into());
+        res
+    }
+
+    /// Translate an English message. Returns it as is, if there's no translation.
+    pub fn translate(&self, locale: &str, msg: &str) -> String {
+        let locale = normalize(locale);
+        let lang = locale.split('-').next().unwrap_or_default();
+        [locale.as_str(), lang].iter()
+            .find_map(|l| self.by_locale.get(*l)?.translate(msg))
+            .unwrap_or_else(|| msg.to_string())
+    }
+}
+
+/// Language a user wants messages in (`ui.language` setting), or `DEFAULT_LOCALE`
+pub fn user_locale(server: &ServerState, user_id: &str) -> String {
+    let pref = server.db.get_user_pref(user_id, "ui.language").unwrap_or_else(|e| {
+        tracing::error!(user=user_id, details=%e, "Failed to get user's language.");
+        None
+    });
+    pref.and_then(|v| serde_json::from_str::<String>(&v).ok()).unwrap_or(DEFAULT_LOCALE.into())
+}
+
+/// Translate a message to a user's language
+pub fn tr(server: &ServerState, user_id: &str, msg: &str) -> String {
+    match server.i18n.by_locale.is_empty() {
+        true => msg.to_string(),    // English only
+        false => server.i18n.translate(&user_locale(server, user_id), msg),
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_translation()
+{
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("fi.json"), serde_json::json!({
+        "No such video.": "Videota ei löydy.",
+        "File is too big (max {} MB)": "Tiedosto on liian suuri (enintään {} Mt)",
+        "'{}' failed: {}": "{1} ({0} epäonnistui)",
+        "'{}' failed: No such video.": "Videota ei löydy ({})",
+        "Literal {braces} and {}": "Aaltosulkeet {braces} ja {}",
+    }).to_string()).unwrap();
+    std::fs::write(dir.path().join("pt_BR.json"), r#"{"No such video.": "Vídeo não encontrado."}"#).unwrap();
+    std::fs::write(dir.path().join("broken.json"), "{").unwrap();
+    std::fs::write(dir.path().join("notes.txt"), "not a catalog").unwrap();
+
+    let cats = Catalogs::load(dir.path());
+    assert_eq!(cats.locales(), vec!["en", "fi", "pt-br"]);
+    assert_eq!(cats.translate("fi", "No such video."), "Videota ei löydy.");
+    assert_eq!(cats.translate("FI-fi", "No such video."), "Videota ei löydy.", "Region falls back to language");
+    assert_eq!(cats.translate("pt-BR", "No such video."), "Vídeo não encontrado.");
+    assert_eq!(cats.translate("pt", "No such video."), "No such video.", "Language doesn't fall to region");
+    assert_eq!(cats.translate("en", "No such video."), "No such video.");
+    assert_eq!(cats.translate("sv", "No such video."), "No such video.");
+    assert_eq!(cats.translate("fi", "Unknown message"), "Unknown message");
+
+    // Patterns
+    assert_eq!(cats.translate("fi", "File is too big (max 10 MB)"), "Tiedosto on liian suuri (enintään 10 Mt)");
+    assert_eq!(cats.translate("fi", "'rename_video' failed: Disk full"), "Disk full (rename_video epäonnistui)");
+    assert_eq!(cats.translate("fi", "'del_video' failed: No such video."), "Videota ei löydy (del_video)", "Most specific wins");
+    assert_eq!(cats.translate("fi", "Literal {braces} and x"), "Aaltosulkeet {braces} ja x");
+    assert_eq!(cats.translate("fi", "File is too big"), "File is too big");
+
+    assert!(Catalogs::load(&dir.path().join("missing")).locales() == vec!["en"]);
+}
@@ -0,0 +1,109 @@
+use serde_json::json;
+
+use crate::database::{models, DB};
+use crate::database::error::DBResult;
+use super::server_state::ServerState;
+
+// Comments can mention users with "@name", where name is a user ID or (space-less) user name,
+// ignoring case. Mentions of known, enabled users are stored (`comment_mentions`), and the
+// mentioned users get a "mention" message (to their open sessions, or saved for later), a push
+// notification (if they opted in, see `web_push`) and a "mention" webhook event, e.g. for an
+// email gateway. Editing a comment only notifies users it didn't mention before. Users that may
+// not see an internal comment aren't mentioned in it.
+
+/// Max number of users notified per comment, so a comment can't spam everyone
+const MAX_MENTIONS: usize = 20;
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '.' | '-')
+}
+
+/// Names mentioned in comment text, lowercase, without duplicates, in order of appearance.
+/// An '@' right after a name character (e.g. in an email address) doesn't start a mention.
+pub fn parse_mentions(text: &str) -> Vec<String>
+{
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut res: Vec<String> = Vec::new();
+    for (i, c) in chars.iter().enumerate() {
+        if *c != '@' || (i > 0 && is_name_char(chars[i - 1])) {
+            continue;
+        }
+        let name = chars[i + 1..].iter().take_while(|c| is_name_char(**c)).collect::<String>();
+        let name = name.trim_end_matches(['.', '-']).to_lowercase();
+        if !name.is_empty() && !res.contains(&name) {
+            res.push(name);
+        }
+    }
+    res
+}
+
+/// Known, enabled users matching mentioned names (by user ID or user name)
+fn resolve(db: &DB, names: &[String]) -> DBResult<Vec<models::User>>
+{
+    if names.is_empty() {
+        return Ok(vec![]);
+    }
+    Ok(db.get_users()?.into_iter()
+        .filter(|u| !u.disabled)
+        .filter(|u| names.iter().any(|n| *n == u.user_id.to_lowercase() || *n == u.username.to_lowercase()))
+        .take(MAX_MENTIONS)
+        .collect())
+}
+
+/// May a user see internal comments on a video (like `sees_internal_comments` for sessions)
+fn sees_internal(db: &DB, v: &models::Video, u: &models::User) -> DBResult<bool>
+{
+    Ok(u.is_admin || v.added_by_userid.as_deref() == Some(u.user_id.as_str()) || db.is_video_shared_with_user(v, &u.user_id)?)
+}
+
+/// Store users mentioned in a new or edited comment, and notify the ones it didn't mention before.
+///
+/// # Returns
+/// * User IDs mentioned in the comment
+pub fn update_mentions(server: &ServerState, v: &models::Video, c: &models::Comment) -> anyhow::Result<Vec<String>>
+{
+    let old = server.db.get_comment_mentions(c.id)?;
+    let mut users = resolve(&server.db, &parse_mentions(&c.comment))?;
+    if c.is_internal() {
+        let mut visible = Vec::new();
+        for u in users {
+            if sees_internal(&server.db, v, &u)? { visible.push(u); }
+        }
+        users = visible;
+    }
+    let uids = users.iter().map(|u| u.user_id.clone()).collect::<Vec<_>>();
+    server.db.set_comment_mentions(c.id, &uids)?;
+
+    let title = v.title.clone().unwrap_or(v.video_hash.clone());
+    for u in users.iter().filter(|u| !old.contains(&u.user_id) && u.user_id != c.user_id) {
+        server.push_user_message(&models::MessageInsert {
+            event_name: "mention".into(),
+            user_id: u.user_id.clone(),
+            ref_video_hash: Some(v.video_hash.clone()),
+            ref_comment_id: Some(c.id),
+            message: format!("{} mentioned you on '{}'", c.username, title),
+            details: c.comment.clone(),
+            ..Default::default() }, true)?;
+        super::web_push::notify(server, &u.user_id, super::web_push::push_kind::MENTION,
+            &format!("{} mentioned you on '{}'", c.username, title), &c.comment, Some(&v.video_hash));
+        if let Some(hook) = server.config.webhook() {
+            hook.send("mention", json!({
+                "video_hash": v.video_hash, "title": title, "comment_id": c.id, "comment": c.comment,
+                "user_id": u.user_id, "user_name": u.username, "by": c.user_id, "by_name": c.username }));
+        }
+    }
+    Ok(uids)
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_parse_mentions()
+{
+    assert_eq!(parse_mentions("@alice, can you check this? cc @Bob and @user.num1."), vec!["alice", "bob", "user.num1"]);
+    assert_eq!(parse_mentions("@alice @ALICE @alice-"), vec!["alice"]);
+    assert_eq!(parse_mentions("mail me@example.com, or @ here, or @@x"), vec!["x"]);
+    assert_eq!(parse_mentions("(@jää)"), vec!["jää"]);
+    assert!(parse_mentions("no mentions").is_empty());
+}
@@ -9,16 +9,17 @@ use std::sync::{Arc, RwLock};
 use std::sync::atomic::{AtomicBool};
 use futures_util::stream::StreamExt;
 use futures_util::SinkExt;
+use tracing::Instrument;
 use warp::ws::{Message};
 use warp::http::HeaderMap;
 use std::sync::atomic::Ordering::Relaxed;
 
 use base64::{Engine as _, engine::general_purpose as Base64GP};
 
-use std::path::{PathBuf};
 use anyhow::{anyhow, bail};
 
 mod server_state;
+mod sender_map;
 use server_state::ServerState;
 
 mod ws_handers;
@@ -32,15 +33,56 @@ pub mod test_utils;
 pub mod tests;
 
 mod file_upload;
+mod video_diff;
+mod event_console;
+mod url_ingest;
+mod stitch;
+mod audio_replace;
+mod conform;
+mod file_server;
+mod download;
+mod package_import;
+pub mod url_signing;
+pub mod federation;
+pub mod trash;
+pub mod retention;
+pub mod reconcile;
+pub mod upload_sweeper;
+pub mod upload_dedup;
+pub mod upload_sessions;
+pub mod share_links;
+pub mod webhook;
+pub mod notifier;
+pub mod organizer;
+pub mod tracker_sync;
+pub mod review_schedule;
+pub mod session_resume;
+pub mod protocol;
+pub mod compression;
+pub mod msgpack;
+pub mod video_list_sub;
+pub mod web_push;
+pub mod onboarding;
+pub mod status_page;
+pub mod feature_flags;
+pub mod capacity;
+pub mod rate_limit;
+pub mod session_limits;
+pub mod mentions;
+pub mod collab_state;
+pub mod attachments;
+pub mod profiles;
+pub mod user_prefs;
+pub mod i18n;
 use file_upload::handle_multipart_upload;
 
+pub(crate) const SHUTDOWN_MSG: &str = "Server is shutting down. Please reconnect in a moment.";
+
 use crate::database::{models, DB};
-use crate::video_pipeline::IncomingFile;
 
 type Res<T> = anyhow::Result<T>;
 type WsMsgSender = tokio::sync::mpsc::UnboundedSender<Message>;
-type SenderList = Vec<WsMsgSender>;
-type SenderListMap = Arc<RwLock<HashMap<String, SenderList>>>;
+type SenderListMap = Arc<sender_map::SenderMap>;
 type StringToStringMap = Arc<RwLock<HashMap<String, String>>>;
 
 
@@ -49,11 +91,17 @@ pub enum SendTo<'a> {
     CurCollab(),
     UserId(&'a str),
     VideoHash(&'a str),
+    /// Viewers of a video that may see its internal comments
+    VideoHashInternal(&'a str),
     MsgSender(&'a WsMsgSender),
 }
 
 #[derive (Clone, Debug)]
-pub enum UserMessageTopic { Ok(), Error(), Progress(), VideoUpdated() }
+pub enum UserMessageTopic {
+    Ok(), Error(), Progress(), VideoUpdated(),
+    /// Like Ok, but the video has also become playable (posted to chat, see `notifier`)
+    VideoReady(),
+}
 
 /// Message from other server modules to user(s)
 #[derive (Clone, Debug)]
@@ -71,40 +119,69 @@ pub struct WsSessionArgs<'a> {
     server: ServerState,
     user_id: &'a str,
     user_name: &'a str,
+    is_admin: bool,
+    /// Share link of a guest session (no account), None for users
+    guest: Option<models::ShareLink>,
+    /// Message schema agreed with the client (see `protocol`)
+    protocol: protocol::Protocol,
     cur_video_hash: Option<String>,
     cur_collab_id: Option<String>,
+    /// Video list the client gets deltas of (see `video_list_sub`)
+    video_list_sub: Option<video_list_sub::VideoListSub>,
     video_session_guard: Option<Box<tokio::sync::Mutex<dyn Send>>>,
     collab_session_guard: Option<Box<tokio::sync::Mutex<dyn Send>>>,
 }
 
 impl WsSessionArgs<'_> {
 
+    /// URL of a file in a video's dir, for client (see `ServerState::asset_url`).
+    /// Guests get their share link in it, for file access checks.
+    pub fn asset_url(&self, vh: &str, rel_path: &str) -> String
+    {
+        let url = self.server.asset_url(vh, rel_path);
+        match &self.guest {
+            Some(link) => format!("{}{}{}", url, if url.contains('?') { '&' } else { '?' }, share_links::asset_query(link)),
+            None => url,
+        }
+    }
+
+    /// `error` message for this session's user, in their language (see `i18n`)
+    pub fn error_msg(&self, msg: &str) -> Message
+    {
+        let msg = i18n::tr(&self.server, self.user_id, msg);
+        Message::text(serde_json::json!({ "cmd": "error", "data": { "message": msg } }).to_string())
+    }
+
     /// Send a command to client websocket(s).
     /// 
     /// If send_to is a string, it is interpreted either as a video hash or user id. Returns the
     /// number of sessions the message was sent to.
     /// 
     /// - If it turns out to be a video hash, the message is sent to all websocket
-    ///     that are watching it.
+    ///   that are watching it.
     /// - If it's a user id, the message is sent to all websocket connections that user has open.
     /// - If it's a MsgSender, the message is sent to that connection only.
     /// - If it's a SendTo::CurSession, the message is sent to the current session only.
     /// - If it's a SendTo::CurCollab, the message is sent to all users in the current collab session.
+    /// - If it's a SendTo::VideoHashInternal, the message is sent to viewers that may see internal comments.
     pub fn emit_cmd(&self, cmd: &str, data: &serde_json::Value, send_to: SendTo) -> Res<u32>
     {
         let msg = serde_json::json!({ "cmd": cmd, "data": data });
         let msg = Message::text(msg.to_string());
         match send_to {
             SendTo::CurSession() => { self.sender.send(msg)?; Ok(1u32) },
-            SendTo::CurCollab() => { self.server.send_to_all_collab_users(&self.cur_collab_id, &msg) },
-            SendTo::UserId(user_id) => { self.server.send_to_all_user_sessions(user_id, &msg) },
-            SendTo::VideoHash(video_hash) => { self.server.send_to_all_video_sessions(video_hash, &msg) },
+            SendTo::CurCollab() => { Ok(self.server.send_to_all_collab_users(&self.cur_collab_id, &msg).sent) },
+            SendTo::UserId(user_id) => { Ok(self.server.send_to_all_user_sessions(user_id, &msg).sent) },
+            SendTo::VideoHash(video_hash) => { Ok(self.server.send_to_all_video_sessions(video_hash, &msg).sent) },
+            SendTo::VideoHashInternal(video_hash) => { Ok(self.server.send_to_internal_video_sessions(video_hash, &msg).sent) },
             SendTo::MsgSender(sender) => { sender.send(msg)?; Ok(1u32) },
         }
     }
     
+    /// Send a message to a user, in their language (see `i18n`), and optionally save it (in English).
     pub fn push_notify_message(&self, msg: &models::MessageInsert, persist: bool) -> Res<()> {
-        let send_res = self.emit_cmd("message", &msg.to_json()?, SendTo::UserId(&msg.user_id));
+        let translated = models::MessageInsert { message: i18n::tr(&self.server, &msg.user_id, &msg.message), ..msg.clone() };
+        let send_res = self.emit_cmd("message", &translated.to_json()?, SendTo::UserId(&msg.user_id));
         if let Ok(sent_count) = send_res {
             if persist {
                 self.server.db.add_message(&models::MessageInsert {
@@ -116,9 +193,21 @@ impl WsSessionArgs<'_> {
         send_res.map(|_| ())
     }
 
-    pub async fn emit_new_comment(&self, mut c: models::Comment, send_to: SendTo<'_>) -> Res<()> {
+    /// Send a comment to client(s). Internal comments sent to a video's viewers only go to
+    /// those who may see them.
+    pub async fn emit_new_comment(&self, c: models::Comment, send_to: SendTo<'_>) -> Res<()> {
+        let send_to = match send_to {
+            SendTo::VideoHash(vh) if c.is_internal() => SendTo::VideoHashInternal(vh),
+            other => other,
+        };
+        let fields = self.comment_json(c).await?;
+        self.emit_cmd("new_comment", &fields , send_to).map(|_| ())
+    }
+
+    /// Comment as JSON for clients, with drawing (as data URI), reactions and attachments
+    pub async fn comment_json(&self, mut c: models::Comment) -> Res<serde_json::Value> {
         if let Some(drawing) = &mut c.drawing {
-            if drawing != "" {
+            if !drawing.is_empty() {
                 // If drawing is present, read it from disk and encode it into a data URI.
                 if !drawing.starts_with("data:") {
                     let path = self.server.videos_dir.join(&c.video_hash).join("drawings").join(&drawing);
@@ -137,8 +226,16 @@ impl WsSessionArgs<'_> {
             }
         }
         let mut fields = c.to_json()?;
+        if let Some(region) = &c.region {
+            fields["region"] = serde_json::from_str(region).unwrap_or_default();
+        }
         fields["comment_id"] = fields["id"].take();  // swap id with comment_id, because the client expects comment_id        
-        self.emit_cmd("new_comment", &fields , send_to).map(|_| ())
+        fields["guest"] = serde_json::json!(c.is_guest());
+        fields["is_edited"] = serde_json::json!(c.is_edited());
+        fields["attachments"] = attachments::comment_json(&self.server, c.id)?;
+        fields["reactions"] = models::comment_reaction::summarize(&self.server.db.get_comment_reactions(c.id)?);
+        fields["author"] = profiles::profile_json(&self.server, &c.user_id, &c.username);
+        Ok(fields)
     }
 
 }
@@ -156,8 +253,78 @@ async fn handle_ws_session(
         sid: String,
         user_id: String,
         username: String,
+        guest_login: Option<GuestLogin>,
+        protocol: Result<protocol::Protocol, String>,
         server_state: ServerState)
 {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let protocol = match protocol {
+        Ok(p) => p,
+        Err(msg) => {
+            tracing::info!(details=msg, "Unsupported client protocol. Closing session.");
+            let msg = i18n::tr(&server_state, &user_id, &msg);
+            ws_tx.send(Message::text(serde_json::json!({"cmd": "error", "data": {"message": msg}}).to_string())).await.ok();
+            return;
+        }
+    };
+    let (user_id, username, is_admin, guest, guest_identity) = match guest_login {
+        Some(login) => match share_links::open_link(&server_state, &login.token, login.password.as_deref()) {
+            Ok(link) => match share_links::guest_identity(&server_state, &link, login.guest_key.as_deref(), login.name.as_deref()) {
+                Ok(g) => {
+                    tracing::info!(link=link.id, video=%link.video_hash, guest=g.id, "Guest session on a share link.");
+                    (g.user_id(), g.name.clone(), false, Some(link), Some(g))
+                },
+                Err(e) => {
+                    tracing::error!(details=%e, "Error getting guest identity. Closing session.");
+                    return;
+                }
+            },
+            Err(file_server::Denied(_, msg)) => {
+                tracing::info!(details=msg, "Share link rejected. Closing session.");
+                ws_tx.send(Message::text(serde_json::json!({"cmd": "error", "data": {"message": msg}}).to_string())).await.ok();
+                return;
+            }
+        },
+        None if user_id.starts_with(models::GUEST_USER_PREFIX) => {
+            tracing::warn!(user=%user_id, "User ID looks like a guest's. Closing session.");
+            ws_tx.send(Message::text(r#"{"cmd":"error", "data":{"message": "Invalid user ID"}}"#)).await.ok();
+            return;
+        },
+        None => {
+            let user = match server_state.db.touch_user(&user_id, &username) {
+                Ok(u) => u,
+                Err(e) => {
+                    tracing::error!(details=%e, "Error updating user in DB. Closing session.");
+                    return;
+                }
+            };
+            if user.disabled {
+                tracing::info!(user=%user_id, "User is disabled. Closing session.");
+                let msg = i18n::tr(&server_state, &user_id, "User is disabled");
+                ws_tx.send(Message::text(serde_json::json!({"cmd": "error", "data": {"message": msg}}).to_string())).await.ok();
+                return;
+            }
+            (user_id, username, user.is_admin, None, None)
+        }
+    };
+    if server_state.terminate_flag.load(Relaxed) {
+        tracing::info!("Server is shutting down. Closing session.");
+        send_shutdown_notice(&mut ws_tx).await;
+        return;
+    }
+    if let Some(max) = server_state.config.sessions().max_user_connections {
+        if server_state.user_session_count(&user_id) >= max {
+            tracing::info!(user=%user_id, max, "Too many connections. Closing session.");
+            let msg = i18n::tr(&server_state, &user_id, session_limits::TOO_MANY_CONNECTIONS_MSG);
+            ws_tx.send(Message::text(serde_json::json!({"cmd": "error", "data": {"message": msg}}).to_string())).await.ok();
+            return;
+        }
+    }
+    let first_login = guest.is_none() && server_state.db.mark_user_onboarded(&user_id).unwrap_or_else(|e| {
+        tracing::error!(details=%e, "Error checking first login.");
+        false
+    });
+
     let (msgq_tx, mut msgq_rx) = tokio::sync::mpsc::unbounded_channel();
     let mut ses = WsSessionArgs {
         sid: &sid,
@@ -165,41 +332,107 @@ async fn handle_ws_session(
         server: server_state,
         user_id: &user_id,
         user_name: &username,
+        is_admin,
+        guest,
+        protocol,
         cur_video_hash: None,
         cur_collab_id: None,
+        video_list_sub: None,
         video_session_guard: None,
         collab_session_guard: None,
     };
 
     let _user_session_guard = ses.server.register_user_session(&user_id, msgq_tx.clone());
-    let (mut ws_tx, mut ws_rx) = ws.split();
 
-    // Let the client know user's id and name
+    // Token for resuming this session after a disconnect (see `session_resume`). Guests reload instead.
+    let resume_token = ses.server.config.sessions().resume_window.filter(|_| ses.guest.is_none() && ses.protocol.has("resume"))
+        .map(|window| ses.server.resume.new_session(&user_id, window, std::time::Instant::now()));
+
+    // Let the client know user's id and name (and the video, for guests), what features they can use, and the agreed protocol
+    let features = feature_flags::enabled_features(&ses.server, &user_id).unwrap_or_else(|e| {
+        tracing::error!(details=%e, "Error getting enabled features.");
+        vec![]
+    });
+    let guest_info = ses.guest.as_ref().zip(guest_identity.as_ref()).map(|(l, g)| serde_json::json!({
+        "video_hash": l.video_hash, "allow_comments": l.allow_comments, "guest_id": g.id, "guest_key": g.key }));
     if let Err(e) = ses.emit_cmd("welcome", 
-            &serde_json::json!({ "user_id": user_id, "username": username }), 
+            &serde_json::json!({ "user_id": user_id, "username": username, "is_admin": is_admin, "guest": guest_info, "first_login": first_login, "features": features,
+                "resume_token": resume_token, "seq": ses.server.resume.current_seq(), "protocol": ses.protocol.to_json(),
+                "profile": profiles::profile_json(&ses.server, &user_id, &username),
+                "locale": i18n::user_locale(&ses.server, &user_id), "locales": ses.server.i18n.locales() }), 
             SendTo::CurSession()) {
         tracing::error!(details=%e, "Error sending welcome message. Closing session.");
         return;
     }
+    if first_login {
+        if let Err(e) = onboarding::onboard_user(&ses) {
+            tracing::error!(details=%e, "Error onboarding new user.");
+        }
+    }
+    // Offer to continue unfinished uploads (e.g. after laptop went to sleep)
+    match ses.server.db.get_upload_sessions(Some(&user_id)) {
+        Ok(sessions) if !sessions.is_empty() => {
+            let sessions = sessions.iter().filter_map(|s| s.to_json().ok()).collect::<Vec<_>>();
+            if let Err(e) = ses.emit_cmd("upload_sessions", &serde_json::json!({ "sessions": sessions }), SendTo::CurSession()) {
+                tracing::error!(details=%e, "Error sending upload sessions.");
+            }
+        },
+        Ok(_) => {},
+        Err(e) => tracing::error!(details=%e, "Error getting upload sessions."),
+    }
+
+    let mut limiter = rate_limit::SessionLimiter::new(std::time::Instant::now());
+    // Cleared when the server closes the session on purpose, so it can't be resumed
+    let mut resumable = true;
+    let mut idle_timer = session_limits::IdleTimer::new(std::time::Instant::now());
 
     loop
     {
         tokio::select!
         {
-            // Termination flag set? Exit.
+            // Termination flag set? Flush queued messages, tell the client and exit.
             _ = tokio::time::sleep(std::time::Duration::from_millis(100)) => {
                 if ses.server.terminate_flag.load(Relaxed) {
                     tracing::info!("Termination flag set. Closing session.");
+                    while let Ok(msg) = msgq_rx.try_recv() {
+                        let Some(msg) = ses.protocol.adapt(msg) else { continue; };
+                        if msg.is_close() || ws_tx.send(msg).await.is_err() { break; }
+                    }
+                    send_shutdown_notice(&mut ws_tx).await;
                     break;
-             }},
+                }
+                // Silent client? Ping, then give up (see `session_limits`).
+                match idle_timer.check(ses.server.config.sessions().idle_timeout, std::time::Instant::now()) {
+                    session_limits::IdleVerdict::Active => {},
+                    session_limits::IdleVerdict::Ping => {
+                        if ws_tx.send(Message::ping(Vec::new())).await.is_err() { break; }
+                    },
+                    session_limits::IdleVerdict::Close => {
+                        tracing::info!("No answer from idle client. Closing session.");
+                        ws_tx.send(ses.error_msg(session_limits::IDLE_MSG)).await.ok();
+                        break;
+                    },
+                }
+                if let Err(e) = video_list_sub::check(&mut ses).await {
+                    tracing::error!(details=%e, "Error sending video list changes.");
+                }
+            },
 
             // Message in queue? Send to client.
             Some(msg) = msgq_rx.recv() => {
+                // Left out for this client's protocol?
+                let Some(msg) = ses.protocol.adapt(msg) else { continue; };
                 tracing::debug!(msg = abbrv(msg.to_str().unwrap_or("<msg.to_str() failed>")), "Sending message to client.");
+                let is_close = msg.is_close();
                 if let Err(e) = ws_tx.send(msg).await {
                     tracing::error!(details=%e, "Error sending message - closing session.");
                     break;
                 }
+                if is_close {
+                    tracing::info!("Session closed by server.");
+                    resumable = false;
+                    break;
+                }
             },
 
             // Message from client? Handle it.
@@ -210,14 +443,14 @@ async fn handle_ws_session(
                         break;
                     },
                     Ok(msg) => {
-                        if msg.is_text() {
+                        idle_timer.activity(std::time::Instant::now());
+                        if let Some(json) = ses.protocol.parse(&msg) {
 
-                            fn parse_msg(msg: &Message) -> Res<(String, serde_json::Value)> {
-                                let msg_str = msg.to_str().unwrap_or("!!msg was supposed to .is_text()!!");
-                                let json: serde_json::Value = serde_json::from_str(msg_str)?;
+                            fn parse_msg(json: Res<serde_json::Value>) -> Res<(String, serde_json::Value)> {
+                                let json = json?;
                                 let cmd = json["cmd"].as_str().ok_or(anyhow!("Missing cmd"))?.trim().to_string();
 
-                                if cmd.len() == 0 || cmd.len() > 64 { bail!("Bad cmd") }
+                                if cmd.is_empty() || cmd.len() > 64 { bail!("Bad cmd") }
                                 let data = json.get("data").unwrap_or(&serde_json::json!({})).clone();
 
                                 // Check data fields for length. Only "drawing" is allowed to be long.
@@ -227,7 +460,7 @@ async fn handle_ws_session(
                                 Ok((cmd, data))
                             }
 
-                            let (cmd, data) = match parse_msg(&msg) {
+                            let (cmd, data) = match parse_msg(json) {
                                 Ok((cmd, data)) => (cmd, data),
                                 Err(e) => {
                                     tracing::warn!(details=%e, "Error parsing JSON message. Closing session.");
@@ -235,26 +468,47 @@ async fn handle_ws_session(
                                         tokio::time::sleep(std::time::Duration::from_secs(5)).await;
                                     }
                                     let answ = format!("Invalid message, bye -- {}", e);
-                                    ws_tx.send(Message::text(format!(r#"{{"cmd":"error", "data":{{"message": "{}"}}}}"#, answ))).await.ok();
+                                    ws_tx.send(ses.error_msg(&answ)).await.ok();
+                                    resumable = false;
                                     break;
                                 }
                             };
                             tracing::debug!(cmd=%cmd, "Msg from client.");
 
-                            if let Err(e) = msg_dispatch(&cmd, &data, &mut ses).await {
+                            match limiter.check(&cmd, &ses.server.config.rate_limits(), std::time::Instant::now()) {
+                                rate_limit::Verdict::Allow => {},
+                                rate_limit::Verdict::Drop(notify) => {
+                                    tracing::debug!(cmd=%cmd, "Rate limit exceeded. Dropping command.");
+                                    // Through the queue, to keep order with replies to earlier commands
+                                    if notify && ses.sender.send(ses.error_msg(rate_limit::SLOW_DOWN_MSG)).is_err() { break; }
+                                    continue;
+                                },
+                                rate_limit::Verdict::Disconnect => {
+                                    tracing::warn!(user=%ses.user_id, cmd=%cmd, "Client keeps flooding commands. Closing session.");
+                                    ws_tx.send(ses.error_msg("Too many requests, bye")).await.ok();
+                                    resumable = false;
+                                    break;
+                                },
+                            }
+
+                            // Each command is a trace of its own (see `telemetry`), its ID works as a request ID in logs
+                            let cmd_span = tracing::info_span!("cmd", cmd=%cmd, user=%ses.user_id, trace_id=%crate::telemetry::new_trace_id());
+                            if let Err(e) = msg_dispatch(&cmd, &data, &mut ses).instrument(cmd_span).await {
                                     if let Some(e) = e.downcast_ref::<tokio::sync::mpsc::error::SendError<Message>>() {
                                         tracing::error!("[{}] Error sending message. Closing session. -- {}", sid, e);
                                         break;
                                     } else {
                                         let answ = format!("Error handling command '{}'.", cmd);
                                         tracing::warn!("[{}] {}: {}", sid, answ, e);
-                                        if ws_tx.send(Message::text(format!(r#"{{"cmd":"error", "data":{{"message": "{}"}}}}"#, answ))).await.is_err() { break; };
+                                        if ws_tx.send(ses.error_msg(&answ)).await.is_err() { break; };
                                     }
                                 };
 
                         } else if msg.is_close() {
                             tracing::info!("Got websocket close message.");
                             break
+                        } else if msg.is_pong() || msg.is_ping() {
+                            // Keepalive, counted as activity above
                         } else {
                             tracing::error!(msg=?msg, "Got unexpected message - closing session.");
                             break
@@ -271,6 +525,26 @@ async fn handle_ws_session(
             }
         }
     }
+
+    if let Some(token) = &resume_token {
+        ses.server.resume.session_ended(token, ses.cur_video_hash.clone(), ses.cur_collab_id.clone(), resumable, std::time::Instant::now());
+    }
+
+    // Left a collab by disconnecting. If the server is shutting down, keep the participant for another instance to take over.
+    if let Some(collab_id) = &ses.cur_collab_id {
+        if !ses.server.terminate_flag.load(Relaxed) {
+            collab_state::leave(&ses.server, collab_id, &user_id);
+        }
+    }
+}
+
+/// Share link login of a guest session, from `/api/ws` query string (see `share_links`)
+struct GuestLogin {
+    token: String,
+    password: Option<String>,
+    name: Option<String>,
+    /// Resume an earlier guest identity (see `share_links::guest_identity`)
+    guest_key: Option<String>,
 }
 
 /// Extract user id and name from HTTP headers (set by nginx)
@@ -287,24 +561,102 @@ fn parse_auth_headers(hdrs: &HeaderMap) -> (String, String)
         None
     }
 
-    let user_id = match try_get_first_named_hdr(&hdrs, vec!["X-Remote-User-Id", "X_Remote_User_Id", "HTTP_X_REMOTE_USER_ID"]) {
+    let user_id = match try_get_first_named_hdr(hdrs, vec!["X-Remote-User-Id", "X_Remote_User_Id", "HTTP_X_REMOTE_USER_ID"]) {
         Some(id) => id,
         None => {
             tracing::warn!("Missing X-Remote-User-Id in HTTP headers. Using 'anonymous' instead.");
             "anonymous".into()
         }};
-    let user_name = try_get_first_named_hdr(&hdrs, vec!["X-Remote-User-Name", "X_Remote_User_Name", "HTTP_X_REMOTE_USER_NAME"])
+    let user_name = try_get_first_named_hdr(hdrs, vec!["X-Remote-User-Name", "X_Remote_User_Name", "HTTP_X_REMOTE_USER_NAME"])
         .unwrap_or_else(|| user_id.clone());
     
     (user_id, user_name)
 }
 
+/// Send a message from the video pipeline to the user and all watchers of the video.
+/// Messages to a user (except progress reports) are also saved in the DB.
+fn relay_user_message(server_state: &ServerState, m: UserMessage)
+{
+    let topic_str = match m.topic{
+        UserMessageTopic::Ok() | UserMessageTopic::VideoReady() => "ok",
+        UserMessageTopic::Error() => "error",
+        UserMessageTopic::Progress() => "progress",
+        UserMessageTopic::VideoUpdated() => "video_updated",
+    };
+
+    let msg = models::MessageInsert  {
+        event_name: topic_str.into(),
+        user_id: m.user_id.clone().unwrap_or("".into()),
+        message: m.msg.clone(),
+        details: m.details.clone().unwrap_or("".into()),
+        seen: false, ref_comment_id: None,
+        ref_video_hash: m.video_hash.clone()
+    };
+    // Sent in the language of the user it's for (also to others watching the video), saved in English
+    let translated = models::MessageInsert {
+        message: m.user_id.as_ref().map(|uid| i18n::tr(server_state, uid, &msg.message)).unwrap_or(msg.message.clone()),
+        ..msg.clone()
+    };
+
+    if let (UserMessageTopic::VideoReady(), Some(vh)) = (&m.topic, &m.video_hash) {
+        match server_state.db.get_video(vh) {
+            Ok(v) => notifier::notify(server_state, notifier::chat_event::VIDEO_READY, &v, None),
+            Err(e) => tracing::error!(video=vh, details=%e, "Failed to get video for chat notification."),
+        }
+    }
+
+    // Message to all watchers of a video
+    if let Some(vh) = m.video_hash {
+        if let Ok(data) = &translated.to_json() {
+            let msg = Message::text(serde_json::json!({
+                "cmd": "message", "data": data }).to_string());
+            server_state.send_to_all_video_sessions(&vh, &msg);
+        }        
+    };
+
+    // Message to a single user
+    // Save it to the database, marking it as seen if sending it to the user succeeds
+    if let Some(user_id) = m.user_id {
+        let mut user_was_online = false;
+        if let Ok(data) = translated.to_json() {
+            let msg = Message::text(serde_json::json!({
+                "cmd": "message", "data": data }).to_string());
+            user_was_online = server_state.send_to_all_user_sessions(&user_id, &msg).sent > 0;
+        }
+        if matches!(m.topic, UserMessageTopic::Ok() | UserMessageTopic::VideoReady() | UserMessageTopic::Error()) {
+            // Details are sometimes JSON for the client, not text for people
+            let body = msg.details.as_str();
+            let body = if serde_json::from_str::<serde_json::Value>(body).is_ok() { "" } else { body };
+            web_push::notify(server_state, &user_id, web_push::push_kind::PROCESSING, &msg.message, body, msg.ref_video_hash.as_deref());
+        }
+        if !matches!(m.topic, UserMessageTopic::Progress()) {
+            let msg = models::MessageInsert {
+                seen: msg.seen || user_was_online,
+                ..msg
+            };
+            if let Err(e) = server_state.db.add_message(&msg) {
+                tracing::error!(details=%e, "Failed to save user notification in DB.");
+            }
+        }
+    };
+}
+
+/// Tell a client that the server is going down, and close the connection.
+async fn send_shutdown_notice<S>(ws_tx: &mut S)
+    where S: futures_util::Sink<Message> + Unpin
+{
+    ws_tx.send(Message::text(serde_json::json!({
+        "cmd": "server_shutdown", "data": { "message": SHUTDOWN_MSG }}).to_string())).await.ok();
+    ws_tx.send(Message::close()).await.ok();
+}
+
 /// Handle HTTP requests, read authentication headers and dispatch to WebSocket handler.
+/// If `host_videos` is set, also serve the videos dir (otherwise left to a separate web server).
 async fn run_api_server_async(
     server_state: ServerState,
     user_msg_rx: crossbeam_channel::Receiver<UserMessage>,
-    upload_results_tx: crossbeam_channel::Sender<IncomingFile>,
-    port: u16)
+    port: u16,
+    host_videos: bool)
 {
     let session_counter = Arc::new(RwLock::new(0u64));
     let server_state_cln1 = server_state.clone();
@@ -314,27 +666,51 @@ async fn run_api_server_async(
 
     let rt_health = warp::path("api").and(warp::path("health")).map(|| "I'm alive!");
 
-    let upload_dir = server_state.upload_dir.clone();
+    let upload_state = server_state.clone();
     let rt_upload = warp::path("api").and(warp::path("upload"))
         .and(warp::post())
-        .and(warp::any().map(move || upload_dir.clone()))
-        .and(warp::any().map(move || upload_results_tx.clone()))
+        .and(warp::any().map(move || upload_state.clone()))
         .and(warp::header::<mime::Mime>("content-type"))
         .and(warp::header::headers_cloned())
         .and(warp::body::stream())
-        .and_then(handle_multipart_upload);
+        .and_then(|server, mime, hdrs: HeaderMap, body| {
+            let trace_id = crate::telemetry::trace_id_from_headers(&hdrs);
+            let span = tracing::info_span!("upload", trace_id=%trace_id);
+            handle_multipart_upload(server, mime, hdrs, body, trace_id).instrument(span)
+        });
 
-    let rt_videos = warp::path("videos").and(
-        warp::fs::dir(server_state_cln1.videos_dir.clone())
-            .with(warp::log("videos")));
+    let rt_download = download::download_filter(server_state.clone());
+
+    let videos_state = server_state.clone();
+    let videos_enabled = warp::any().and_then(move || async move {
+            if host_videos { Ok(()) } else { Err(warp::reject::not_found()) }
+        }).untuple_one();
+    let rt_videos = file_server::serve_dir(warp::path("videos").and(videos_enabled), server_state.videos_dir.clone(),
+            move |user_id, path, query| file_server::check_video_file(&videos_state, user_id, path, query))
+        .with(warp::log("videos"));
+
+    let avatars_state = server_state.clone();
+    let rt_avatars = file_server::serve_dir(warp::path("avatars"), profiles::avatars_dir(&server_state),
+            move |user_id, path, query| profiles::check_avatar_file(&avatars_state, user_id, path, query))
+        .with(warp::log("avatars"));
+
+    let rt_federation = federation::federation_filter(server_state.clone());
+
+    let rt_status = status_page::status_filter(server_state.clone());
+
+    let rt_upload_session = upload_sessions::upload_session_filter(server_state.clone());
 
     let rt_api_ws = warp::path("api").and(warp::path("ws"))
         .and(warp::header::headers_cloned())
+        .and(warp::query::<HashMap<String, String>>())
         .and(warp::ws())
-        .map (move|hdrs: HeaderMap, ws: warp::ws::Ws| {
+        .map (move|hdrs: HeaderMap, mut query: HashMap<String, String>, ws: warp::ws::Ws| {
 
             // Get user ID and username (from reverse proxy)
             let (user_id, user_name) = parse_auth_headers(&hdrs);
+            let guest_login = query.remove("share").map(|token| GuestLogin {
+                token, password: query.remove("password"), name: query.remove("name"), guest_key: query.remove("guest_key") });
+            let protocol = protocol::Protocol::negotiate(&query);
 
             // Increment session counter
             let sid = {
@@ -349,19 +725,19 @@ async fn run_api_server_async(
                 // even though we're using async/await
                 tokio::task::spawn_blocking( move || {
                     let _span = tracing::info_span!("ws_session", sid=%sid, user=%user_id).entered();
-                    block_on(handle_ws_session(ws, sid, user_id, user_name, server_state));
+                    block_on(handle_ws_session(ws, sid, user_id, user_name, guest_login, protocol, server_state));
                 }).await.unwrap_or_else(|e| {
                     tracing::error!(details=%e, "Error joining handle_ws_session thread."); });
             })
         });
 
-    let routes = rt_health.or(rt_api_ws).or(rt_upload).or(rt_videos);
+    let routes = rt_health.or(rt_api_ws).or(rt_upload).or(rt_download).or(rt_videos).or(rt_avatars).or(rt_federation).or(rt_status).or(rt_upload_session);
 
     let routes = routes.with(warp::log("api_server"))
         .with(warp::cors()
         .allow_any_origin()
-        .allow_methods(vec!["GET", "POST"])
-        .allow_headers(vec!["x-file-name"]));
+        .allow_methods(vec!["GET", "POST", "PUT"])
+        .allow_headers(vec!["traceparent", "x-file-name", "x-batch-file-id", "x-loudness-target", "x-replace-audio-of", "x-audio-mode", "x-sequence-fps", "x-dry-run", "x-upload-offset", "range", "if-none-match"]));
 
     let (_addr, server) = warp::serve(routes)
         .bind_with_graceful_shutdown(([127, 0, 0, 1], port), async move {
@@ -370,62 +746,23 @@ async fn run_api_server_async(
             }
         });
 
+    // Keeps relaying after termination flag is set, until the pipeline has finished
+    // its jobs in progress and closed the channel, so their results get saved in DB.
     let server_state = server_state_cln2;
     let msg_relay = async move {
-        while !server_state.terminate_flag.load(Relaxed) {
+        loop {
             tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-            while let Ok(m) = user_msg_rx.try_recv() {
-                let topic_str = match m.topic{
-                    UserMessageTopic::Ok() => "ok",
-                    UserMessageTopic::Error() => "error",
-                    UserMessageTopic::Progress() => "progress",
-                    UserMessageTopic::VideoUpdated() => "video_updated",
-                };
-
-                let msg = models::MessageInsert  {
-                    event_name: topic_str.into(),
-                    user_id: m.user_id.clone().unwrap_or("".into()),
-                    message: m.msg.clone(),
-                    details: m.details.clone().unwrap_or("".into()),
-                    seen: false, ref_comment_id: None,
-                    ref_video_hash: m.video_hash.clone()
-                };
-
-                // Message to all watchers of a video
-                if let Some(vh) = m.video_hash {
-                    if let Ok(data) = &msg.to_json() {
-                        let msg = Message::text(serde_json::json!({
-                            "cmd": "message", "data": data }).to_string());
-                        if let Err(_) = server_state.send_to_all_video_sessions(&vh, &msg) {
-                            tracing::error!(video=vh, "Failed to send notification to video hash.");
-                        }
-                    }        
-                };
-
-                // Message to a single user
-                // Save it to the database, marking it as seen if sending it to the user succeeds
-                if let Some(user_id) = m.user_id {
-                    let mut user_was_online = false;
-                    if let Ok(data) = msg.to_json() {
-                        let msg = Message::text(serde_json::json!({
-                            "cmd": "message", "data": data }).to_string());
-                        match server_state.send_to_all_user_sessions(&user_id, &msg) {
-                            Ok(session_cnt) => { user_was_online = session_cnt>0 },
-                            Err(e) => tracing::error!(user=user_id, details=%e, "Failed to send user notification."),
-                        }
-                    }
-                    if !matches!(m.topic, UserMessageTopic::Progress()) {
-                        let msg = models::MessageInsert {
-                            seen: msg.seen || user_was_online,
-                            ..msg
-                        };
-                        if let Err(e) = server_state.db.add_message(&msg) {
-                            tracing::error!(details=%e, "Failed to save user notification in DB.");
-                        }
+            loop {
+                match user_msg_rx.try_recv() {
+                    Ok(m) => relay_user_message(&server_state, m),
+                    Err(crossbeam_channel::TryRecvError::Empty) => break,
+                    Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                        if server_state.terminate_flag.load(Relaxed) { return; }
+                        break;
                     }
-                };
+                }
             }
-        };
+        }
     };
 
     tokio::join!(server, msg_relay);
@@ -436,20 +773,22 @@ async fn run_api_server_async(
 #[tokio::main]
 pub async fn run_forever(
     db: Arc<DB>,
-    videos_dir: PathBuf,
-    upload_dir: PathBuf,
+    opts: crate::config::StartupOptions,
+    pipeline: crate::video_pipeline::PipelineLink,
     user_msg_rx: crossbeam_channel::Receiver<UserMessage>,
-    upload_res_tx: crossbeam_channel::Sender<IncomingFile>,
-    terminate_flag: Arc<AtomicBool>,
-    url_base: String,
-    port: u16)
+    config: Arc<crate::config::LiveConfig>,
+    terminate_flag: Arc<AtomicBool>)
 {
-    assert!(!url_base.ends_with('/')); // Should have been stripped by caller
+    assert!(!opts.url_base.ends_with('/')); // Should have been stripped by caller
     let _span = tracing::info_span!("API").entered();
-    let state = ServerState::new( db,
-        &videos_dir,
-        &upload_dir,
-        &url_base,
-        terminate_flag );
-    run_api_server_async(state, user_msg_rx, upload_res_tx, port).await
+    let state = ServerState::new(db, &opts, pipeline, config, terminate_flag);
+    let sync_state = state.clone();
+    std::thread::spawn(move || federation::run_sync_loop(sync_state));
+    let retention_state = state.clone();
+    std::thread::spawn(move || retention::run_retention_loop(retention_state, opts.retention));
+    let tracker_state = state.clone();
+    std::thread::spawn(move || tracker_sync::run_tracker_loop(tracker_state));
+    let review_state = state.clone();
+    std::thread::spawn(move || review_schedule::run_review_loop(review_state));
+    run_api_server_async(state, user_msg_rx, opts.port, opts.host_videos).await
 }
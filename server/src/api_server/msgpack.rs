@@ -0,0 +1,211 @@
+use anyhow::{anyhow, bail};
+use serde_json::Value;
+
+// MessagePack (https://msgpack.org) wire format, for clients that ask for it when connecting
+// (`/api/ws?format=msgpack`, see `protocol`). It's smaller and faster to parse than JSON, which
+// helps with frequent collab messages (seek, play, drawing). Messages are the same as in JSON,
+// just encoded differently, in binary frames both ways.
+//
+// Only what JSON can represent is supported: no binary or extension types (except that binary
+// strings from clients are read as UTF-8 text).
+
+/// Max nesting of arrays and maps in messages from clients
+const MAX_DEPTH: usize = 32;
+
+/// Encode a JSON value as MessagePack
+pub fn encode(v: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(v, &mut out);
+    out
+}
+
+fn encode_len(out: &mut Vec<u8>, len: usize, fix: Option<(u8, usize)>, codes: [u8; 3]) {
+    match fix {
+        Some((base, max)) if len <= max => out.push(base | len as u8),
+        _ if len <= u8::MAX as usize && codes[0] != 0 => out.extend([codes[0], len as u8]),
+        _ if len <= u16::MAX as usize => { out.push(codes[1]); out.extend((len as u16).to_be_bytes()); },
+        _ => { out.push(codes[2]); out.extend((len as u32).to_be_bytes()); },
+    }
+}
+
+fn encode_into(v: &Value, out: &mut Vec<u8>) {
+    match v {
+        Value::Null => out.push(0xc0),
+        Value::Bool(b) => out.push(if *b { 0xc3 } else { 0xc2 }),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                match u {
+                    0..=0x7f => out.push(u as u8),
+                    0x80..=0xff => out.extend([0xcc, u as u8]),
+                    0x100..=0xffff => { out.push(0xcd); out.extend((u as u16).to_be_bytes()); },
+                    0x1_0000..=0xffff_ffff => { out.push(0xce); out.extend((u as u32).to_be_bytes()); },
+                    _ => { out.push(0xcf); out.extend(u.to_be_bytes()); },
+                }
+            } else if let Some(i) = n.as_i64() {
+                // Negative, as non-negative ones are u64
+                match i {
+                    -32..=-1 => out.push(i as u8),
+                    -0x80..=-33 => out.extend([0xd0, i as u8]),
+                    -0x8000..=-0x81 => { out.push(0xd1); out.extend((i as i16).to_be_bytes()); },
+                    -0x8000_0000..=-0x8001 => { out.push(0xd2); out.extend((i as i32).to_be_bytes()); },
+                    _ => { out.push(0xd3); out.extend(i.to_be_bytes()); },
+                }
+            } else {
+                out.push(0xcb);
+                out.extend(n.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        },
+        Value::String(s) => {
+            encode_len(out, s.len(), Some((0xa0, 31)), [0xd9, 0xda, 0xdb]);
+            out.extend(s.as_bytes());
+        },
+        Value::Array(a) => {
+            encode_len(out, a.len(), Some((0x90, 15)), [0, 0xdc, 0xdd]);
+            a.iter().for_each(|x| encode_into(x, out));
+        },
+        Value::Object(o) => {
+            encode_len(out, o.len(), Some((0x80, 15)), [0, 0xde, 0xdf]);
+            for (k, x) in o {
+                encode_into(&Value::String(k.clone()), out);
+                encode_into(x, out);
+            }
+        },
+    }
+}
+
+/// Decode a MessagePack message into a JSON value
+pub fn decode(data: &[u8]) -> anyhow::Result<Value> {
+    let mut rd = Reader { data, pos: 0 };
+    let v = rd.value(0)?;
+    if rd.pos != data.len() { bail!("Trailing bytes after MessagePack value"); }
+    Ok(v)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&[u8]> {
+        let end = self.pos.checked_add(n).filter(|e| *e <= self.data.len()).ok_or(anyhow!("Truncated MessagePack"))?;
+        let res = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(res)
+    }
+
+    fn uint(&mut self, n: usize) -> anyhow::Result<u64> {
+        Ok(self.take(n)?.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    fn int(&mut self, n: usize) -> anyhow::Result<i64> {
+        let u = self.uint(n)?;
+        let shift = 64 - 8 * n as u32;
+        Ok(((u << shift) as i64) >> shift)  // Sign extend
+    }
+
+    fn str(&mut self, len: usize) -> anyhow::Result<Value> {
+        Ok(Value::String(std::str::from_utf8(self.take(len)?)?.to_string()))
+    }
+
+    fn array(&mut self, len: usize, depth: usize) -> anyhow::Result<Value> {
+        // Each item takes at least a byte, so don't trust longer lengths for allocating
+        let mut res = Vec::with_capacity(len.min(self.data.len() - self.pos));
+        for _ in 0..len { res.push(self.value(depth + 1)?); }
+        Ok(Value::Array(res))
+    }
+
+    fn map(&mut self, len: usize, depth: usize) -> anyhow::Result<Value> {
+        let mut res = serde_json::Map::new();
+        for _ in 0..len {
+            let Value::String(k) = self.value(depth + 1)? else { bail!("MessagePack map key is not a string"); };
+            res.insert(k, self.value(depth + 1)?);
+        }
+        Ok(Value::Object(res))
+    }
+
+    fn value(&mut self, depth: usize) -> anyhow::Result<Value> {
+        if depth > MAX_DEPTH { bail!("MessagePack nested too deep"); }
+        let b = self.take(1)?[0];
+        Ok(match b {
+            0x00..=0x7f => Value::from(b),
+            0x80..=0x8f => self.map((b & 0x0f) as usize, depth)?,
+            0x90..=0x9f => self.array((b & 0x0f) as usize, depth)?,
+            0xa0..=0xbf => self.str((b & 0x1f) as usize)?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xc4 | 0xd9 => { let n = self.uint(1)? as usize; self.str(n)? },
+            0xc5 | 0xda => { let n = self.uint(2)? as usize; self.str(n)? },
+            0xc6 | 0xdb => { let n = self.uint(4)? as usize; self.str(n)? },
+            0xca => Value::from(f32::from_bits(self.uint(4)? as u32) as f64),
+            0xcb => Value::from(f64::from_bits(self.uint(8)?)),
+            0xcc => Value::from(self.uint(1)?),
+            0xcd => Value::from(self.uint(2)?),
+            0xce => Value::from(self.uint(4)?),
+            0xcf => Value::from(self.uint(8)?),
+            0xd0 => Value::from(self.int(1)?),
+            0xd1 => Value::from(self.int(2)?),
+            0xd2 => Value::from(self.int(4)?),
+            0xd3 => Value::from(self.int(8)?),
+            0xdc => { let n = self.uint(2)? as usize; self.array(n, depth)? },
+            0xdd => { let n = self.uint(4)? as usize; self.array(n, depth)? },
+            0xde => { let n = self.uint(2)? as usize; self.map(n, depth)? },
+            0xdf => { let n = self.uint(4)? as usize; self.map(n, depth)? },
+            0xe0..=0xff => Value::from(b as i8),
+            _ => bail!("Unsupported MessagePack type 0x{b:02x}"),
+        })
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_msgpack()
+{
+    use serde_json::json;
+
+    // Example from msgpack.org
+    let v = json!({"compact": true, "schema": 0});
+    let mut expected = vec![0x82, 0xa7];
+    expected.extend(b"compact");
+    expected.extend([0xc3, 0xa6]);
+    expected.extend(b"schema");
+    expected.push(0x00);
+    assert_eq!(encode(&v), expected);
+
+    assert_eq!(encode(&json!(-1)), vec![0xff]);
+    assert_eq!(encode(&json!(200)), vec![0xcc, 200]);
+    assert_eq!(encode(&json!(-200)), vec![0xd1, 0xff, 0x38]);
+    assert_eq!(encode(&json!(1.5)), [vec![0xcb], 1.5f64.to_be_bytes().to_vec()].concat());
+
+    // Round trips
+    let long = "x".repeat(70_000);
+    for v in [
+        json!(null), json!(false), json!(0), json!(127), json!(128), json!(65_536), json!(u64::MAX),
+        json!(-32), json!(-33), json!(-129), json!(-40_000), json!(i64::MIN), json!(-0.25), json!(12.5),
+        json!(""), json!("ääkköset"), json!("a".repeat(32)), json!("b".repeat(300)), json!(long),
+        json!([]), json!((0..20).collect::<Vec<_>>()), json!((0..70_000).map(|_| 1).collect::<Vec<_>>()),
+        json!({"cmd": "collab_cmd", "data": {"paused": false, "seek_time": 12.041666, "drawing": null, "from_user": "Alice"}}),
+        serde_json::Value::Object((0..20).map(|i| (format!("k{i}"), json!({"n": [i, -i]}))).collect()),
+    ] {
+        assert_eq!(decode(&encode(&v)).unwrap(), v);
+    }
+
+    // Smaller than JSON for collab messages
+    let collab = json!({"cmd": "collab_cmd", "data": {"paused": false, "seek_time": 12.041666, "from_user": "Alice"}});
+    assert!(encode(&collab).len() < collab.to_string().len());
+
+    // Binary strings from clients are read as text
+    assert_eq!(decode(&[0xc4, 2, b'h', b'i']).unwrap(), json!("hi"));
+
+    // Bad input
+    assert!(decode(&[0xa5, b'a']).is_err(), "Truncated");
+    assert!(decode(&[0xdd, 0xff, 0xff, 0xff, 0xff]).is_err(), "Long array, no items");
+    assert!(decode(&[0x81, 0x01, 0x02]).is_err(), "Non-string key");
+    assert!(decode(&[0xc0, 0xc0]).is_err(), "Trailing bytes");
+    assert!(decode(&[0xc1]).is_err(), "Unused type");
+    assert!(decode(&[0xa2, 0xff, 0xfe]).is_err(), "Not UTF-8");
+    assert!(decode(&[0x91; 100]).is_err(), "Too deep");
+}
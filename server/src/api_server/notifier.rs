@@ -0,0 +1,243 @@
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::database::models;
+use super::server_state::ServerState;
+
+// Posts review activity to Slack or Mattermost channels, through their incoming webhooks: videos
+// that are ready to review, and new comments (internal ones are left out, as chat channels can
+// have people that may not see them). Configured with `--chat-notify FILE` (JSON, reloadable):
+//
+//   {"routes": [
+//     {"url": "https://hooks.slack.com/services/...", "channel": "#reviews"},
+//     {"url": "https://chat.example.com/hooks/...", "format": "mattermost",
+//      "folders": [12, 40], "events": ["comment"]}]}
+//
+// Routes with `folders` get activity of videos in those folders (and their subfolders); the
+// innermost folder with routes wins. Videos in no routed folder go to routes without `folders`.
+// `format` is "slack" (default) or "mattermost", and `events` "video_ready" and/or "comment"
+// (default both). Delivery is best effort, like webhooks.
+
+/// Events that can be posted
+pub mod chat_event {
+    pub const VIDEO_READY: &str = "video_ready";
+    pub const COMMENT: &str = "comment";
+    pub const ALL: &[&str] = &[VIDEO_READY, COMMENT];
+}
+
+/// Comment text is cut to this many chars
+const MAX_COMMENT_CHARS: usize = 500;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatFormat {
+    #[default]
+    Slack,
+    Mattermost,
+}
+
+impl ChatFormat {
+    /// Link in the chat's markup
+    fn link(&self, url: &str, text: &str) -> String {
+        match self {
+            ChatFormat::Slack => format!("<{url}|{}>", self.escape(text)),
+            ChatFormat::Mattermost => format!("[{}]({url})", self.escape(text)),
+        }
+    }
+
+    fn bold(&self, text: &str) -> String {
+        match self {
+            ChatFormat::Slack => format!("*{}*", self.escape(text)),
+            ChatFormat::Mattermost => format!("**{}**", self.escape(text)),
+        }
+    }
+
+    /// Escape user content, so it can't break (or inject) markup
+    fn escape(&self, text: &str) -> String {
+        match self {
+            ChatFormat::Slack => text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"),
+            ChatFormat::Mattermost => text.chars().fold(String::new(), |mut s, c| {
+                if "\\`*_[]()#<>".contains(c) { s.push('\\'); }
+                s.push(c);
+                s
+            }),
+        }
+    }
+}
+
+/// An incoming webhook to post to
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ChatRoute {
+    pub url: String,
+    #[serde(default)]
+    pub format: ChatFormat,
+    /// Channel to post to, instead of the webhook's default
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// Only videos in these folders (by ID, including subfolders). Empty = videos in no routed folder.
+    #[serde(default)]
+    pub folders: Vec<i32>,
+    /// Events to post (see `chat_event`). Empty = all.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+impl ChatRoute {
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event)
+    }
+}
+
+/// Chat webhooks to post review activity to
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ChatNotifier {
+    #[serde(default)]
+    pub routes: Vec<ChatRoute>,
+}
+
+impl ChatNotifier {
+    pub fn from_file(path: &Path) -> anyhow::Result<ChatNotifier> {
+        let json = std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to read '{}': {}", path.display(), e))?;
+        ChatNotifier::from_json(&json)
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<ChatNotifier> {
+        let res: ChatNotifier = serde_json::from_str(json)?;
+        for r in &res.routes {
+            let url = reqwest::Url::parse(&r.url).map_err(|e| anyhow!("Invalid webhook URL '{}': {}", r.url, e))?;
+            if !["http", "https"].contains(&url.scheme()) { bail!("Webhook URL must be http or https: '{}'", r.url); }
+            if let Some(e) = r.events.iter().find(|e| !chat_event::ALL.contains(&e.as_str())) {
+                bail!("Unknown event '{e}' (known: {})", chat_event::ALL.join(", "));
+            }
+        }
+        Ok(res)
+    }
+
+    /// Routes for an event about a video
+    ///
+    /// # Arguments
+    /// * `event` - Event name (see `chat_event`)
+    /// * `folder_path` - Video's folder and its parents, innermost first (see `DB::get_folder_path`)
+    pub fn routes_for(&self, event: &str, folder_path: &[i32]) -> Vec<&ChatRoute>
+    {
+        let matching = |f: Option<i32>| self.routes.iter().filter(move |r| match f {
+            Some(f) => r.folders.contains(&f),
+            None => r.folders.is_empty(),
+        });
+        let routes = folder_path.iter().map(|f| matching(Some(*f)).collect::<Vec<_>>())
+            .find(|routes| !routes.is_empty())
+            .unwrap_or_else(|| matching(None).collect());
+        routes.into_iter().filter(|r| r.wants(event)).collect()
+    }
+}
+
+/// Message about a video, in route's format
+fn message(route: &ChatRoute, event: &str, v: &models::Video, c: Option<&models::Comment>, url_base: &str) -> String
+{
+    let fmt = route.format;
+    let title = v.title.as_deref().unwrap_or(&v.video_hash);
+    let link = fmt.link(&format!("{url_base}/?vid={}", v.video_hash), "Open in Clapshot");
+    match (event, c) {
+        (chat_event::COMMENT, Some(c)) => {
+            let text = match c.comment.chars().count() > MAX_COMMENT_CHARS {
+                true => c.comment.chars().take(MAX_COMMENT_CHARS - 1).collect::<String>() + "…",
+                false => c.comment.clone(),
+            };
+            let at = c.timecode.as_ref().map(|tc| format!(" at {}", fmt.escape(tc))).unwrap_or_default();
+            let quote = text.lines().map(|l| format!("> {}", fmt.escape(l))).collect::<Vec<_>>().join("\n");
+            format!("{} commented on {}{at}: {link}\n{quote}", fmt.escape(&c.username), fmt.bold(title))
+        },
+        _ => {
+            let by = v.added_by_username.as_deref().map(|u| format!(" (uploaded by {})", fmt.escape(u))).unwrap_or_default();
+            format!("{} is ready for review{by}: {link}", fmt.bold(title))
+        },
+    }
+}
+
+fn post(route: &ChatRoute, text: &str) -> anyhow::Result<()>
+{
+    let mut body = json!({ "text": text, "username": "Clapshot" });
+    if let Some(ch) = &route.channel {
+        body["channel"] = json!(ch);
+    }
+    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs(30)).build()?;
+    client.post(&route.url).json(&body).send()?.error_for_status()?;
+    Ok(())
+}
+
+/// Post an event about a video to the chat routes that want it, in background.
+///
+/// # Arguments
+/// * `server` - Server state
+/// * `event` - Event name (see `chat_event`)
+/// * `v` - The video
+/// * `c` - New comment, for `chat_event::COMMENT`
+pub fn notify(server: &ServerState, event: &str, v: &models::Video, c: Option<&models::Comment>)
+{
+    let chat = server.config.chat_notifier();
+    if chat.routes.is_empty() || c.is_some_and(|c| c.is_internal()) {
+        return;
+    }
+    let folder_path = match server.db.get_folder_path(v.folder_id) {
+        Ok(p) => p,
+        Err(e) => { tracing::error!(video=v.video_hash, details=%e, "Failed to get folder path for chat notification."); return; },
+    };
+    let posts = chat.routes_for(event, &folder_path).into_iter()
+        .map(|r| (r.clone(), message(r, event, v, c, &server.url_base))).collect::<Vec<_>>();
+    if posts.is_empty() {
+        return;
+    }
+    let event = event.to_string();
+    std::thread::spawn(move || {
+        for (route, text) in posts {
+            if let Err(e) = post(&route, &text) {
+                tracing::warn!(event, details=%e, "Chat notification failed.");
+            }
+        }
+    });
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_chat_routes()
+{
+    assert!(ChatNotifier::from_json(r#"{"routes": [{"url": "ftp://x"}]}"#).is_err());
+    assert!(ChatNotifier::from_json(r#"{"routes": [{"url": "https://x", "events": ["upload"]}]}"#).is_err());
+    assert!(ChatNotifier::from_json(r#"{"routes": [{"url": "https://x", "format": "irc"}]}"#).is_err());
+    assert_eq!(ChatNotifier::from_json("{}").unwrap(), ChatNotifier::default());
+
+    let chat = ChatNotifier::from_json(r#"{"routes": [
+        {"url": "https://hooks.example/default"},
+        {"url": "https://hooks.example/comments", "events": ["comment"]},
+        {"url": "https://hooks.example/project", "format": "mattermost", "channel": "proj", "folders": [1]},
+        {"url": "https://hooks.example/sub", "folders": [2, 3], "events": ["video_ready"]}]}"#).unwrap();
+    let urls = |event: &str, path: &[i32]| chat.routes_for(event, path).iter().map(|r| r.url.rsplit('/').next().unwrap().to_string()).collect::<Vec<_>>();
+    assert_eq!(urls(chat_event::VIDEO_READY, &[]), vec!["default"]);
+    assert_eq!(urls(chat_event::COMMENT, &[99]), vec!["default", "comments"]);
+    assert_eq!(urls(chat_event::COMMENT, &[5, 1]), vec!["project"]);
+    assert_eq!(urls(chat_event::VIDEO_READY, &[2, 1]), vec!["sub"]);
+    assert!(urls(chat_event::COMMENT, &[2, 1]).is_empty(), "Innermost routed folder wins, even without the event");
+}
+
+#[test]
+fn test_chat_message_format()
+{
+    let (_db, _data_dir, videos, mut comments) = crate::database::tests::make_test_db();
+    let v = models::Video { title: Some("Ep <1> & co".into()), added_by_username: Some("Alice".into()), ..videos[0].clone() };
+    let link = format!("https://clap.example/?vid={}", v.video_hash);
+    let slack = ChatRoute { url: "https://x".into(), format: ChatFormat::Slack, channel: None, folders: vec![], events: vec![] };
+    assert_eq!(message(&slack, chat_event::VIDEO_READY, &v, None, "https://clap.example"),
+        format!("*Ep &lt;1&gt; &amp; co* is ready for review (uploaded by Alice): <{link}|Open in Clapshot>"));
+
+    let mm = ChatRoute { format: ChatFormat::Mattermost, ..slack };
+    let c = models::Comment { username: "Bob".into(), comment: "Too dark\n*here*".into(), timecode: Some("00:00:01:00".into()), ..comments.remove(0) };
+    assert_eq!(message(&mm, chat_event::COMMENT, &v, Some(&c), "https://clap.example"),
+        format!("Bob commented on **Ep \\<1\\> & co** at 00:00:01:00: [Open in Clapshot]({link})\n> Too dark\n> \\*here\\*"));
+}
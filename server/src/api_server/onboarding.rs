@@ -0,0 +1,102 @@
+use std::path::Path;
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::database::models;
+use crate::database::error::DBError;
+use super::{Res, SendTo, WsSessionArgs};
+
+// New users get welcome content on their first login, so they don't land in an empty,
+// unexplained workspace. The content is configured with `--onboarding FILE` (JSON):
+//
+//   {"message": "Welcome to the studio's review tool! ...",
+//    "sample_videos": ["<video hash>", ...],
+//    "links": [{"title": "How to review", "url": "https://wiki.example.com/clapshot"}]}
+//
+// Sample videos are added to the new user's list (like deduplicated uploads, see `upload_dedup`),
+// the message is saved in their messages, and all of it is sent to the client as `onboarding`.
+
+/// Welcome content for new users
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Onboarding {
+    /// Welcome text
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Videos (by hash) to add to new users' lists
+    #[serde(default)]
+    pub sample_videos: Vec<String>,
+    /// Quick links, e.g. to guides
+    #[serde(default)]
+    pub links: Vec<QuickLink>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuickLink {
+    pub title: String,
+    pub url: String,
+}
+
+impl Onboarding {
+    pub fn from_file(path: &Path) -> anyhow::Result<Onboarding> {
+        let json = std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to read '{}': {}", path.display(), e))?;
+        Onboarding::from_json(&json)
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Onboarding> {
+        let res: Onboarding = serde_json::from_str(json)?;
+        for l in &res.links {
+            if l.title.trim().is_empty() { bail!("Link '{}' has no title", l.url); }
+            let url = reqwest::Url::parse(&l.url).map_err(|e| anyhow!("Invalid link URL '{}': {}", l.url, e))?;
+            if !["http", "https"].contains(&url.scheme()) { bail!("Link URL must be http or https: '{}'", l.url); }
+        }
+        Ok(res)
+    }
+}
+
+/// Give user the onboarding content, if configured. Call on their first login
+/// (see `DB::mark_user_onboarded`), after the `welcome` message.
+pub fn onboard_user(ses: &WsSessionArgs<'_>) -> Res<()> {
+    let Some(ob) = &ses.server.onboarding else { return Ok(()) };
+    let mut videos = vec![];
+    for vh in &ob.sample_videos {
+        match ses.server.db.get_video(vh) {
+            Ok(v) if v.trashed.is_none() => {
+                if v.added_by_userid.as_deref() != Some(ses.user_id) {
+                    ses.server.db.add_video_link(ses.user_id, vh)?;
+                }
+                videos.push(serde_json::json!({ "video_hash": v.video_hash, "title": v.title }));
+            },
+            Ok(_) | Err(DBError::NotFound()) => { tracing::warn!(video=%vh, "Onboarding sample video not found. Skipping it."); },
+            Err(e) => { bail!(e); }
+        }
+    }
+    if let Some(msg) = &ob.message {
+        ses.server.push_user_message(&models::MessageInsert {
+            user_id: ses.user_id.into(),
+            event_name: "info".into(),
+            message: msg.clone(),
+            ..Default::default()
+        }, true)?;
+    }
+    tracing::info!(user=%ses.user_id, n_videos=videos.len(), "Onboarded new user.");
+    ses.emit_cmd("onboarding", &serde_json::json!({
+        "message": ob.message,
+        "sample_videos": videos,
+        "links": ob.links }), SendTo::CurSession())?;
+    Ok(())
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_onboarding_config()
+{
+    let ob = Onboarding::from_json(r#"{"message": "Hi", "links": [{"title": "Guide", "url": "https://example.com/guide"}]}"#).unwrap();
+    assert_eq!(ob.message.as_deref(), Some("Hi"));
+    assert!(ob.sample_videos.is_empty());
+    assert_eq!(Onboarding::from_json("{}").unwrap(), Onboarding::default());
+    assert!(Onboarding::from_json(r#"{"links": [{"title": "Guide", "url": "javascript:alert(1)"}]}"#).is_err());
+    assert!(Onboarding::from_json(r#"{"links": [{"title": " ", "url": "https://example.com"}]}"#).is_err());
+    assert!(Onboarding::from_json(r#"{"message": 1}"#).is_err());
+}
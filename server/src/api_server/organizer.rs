@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context};
+use hyper::body::HttpBody;
+
+use crate::database::{models, DB};
+
+// Client for an external "organizer" service (gRPC), that the server consults on library events
+// so studios can plug in their production-tracking logic without forking the server. The
+// interface is in `proto/organizer.proto`. Calls are plain unary gRPC over h2c, with the few
+// protobuf messages encoded by hand (module `pb` below), to keep the build free of protoc.
+//
+// The organizer can't grant anything the server wouldn't, only deny and rearrange. If it's
+// down or slow, the server logs a warning and carries on with its defaults.
+
+const SERVICE: &str = "clapshot.organizer.Organizer";
+
+/// How long to wait for an organizer call
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// gRPC status for methods the organizer doesn't implement (= keep defaults)
+const GRPC_UNIMPLEMENTED: u32 = 12;
+
+/// Actions that are checked with the organizer
+pub mod organizer_action {
+    pub const VIEW: &str = "view";
+    pub const COMMENT: &str = "comment";
+}
+
+/// Organizer's changes to a new video
+#[derive(Debug, Default, PartialEq)]
+pub struct IngestResponse {
+    pub title: Option<String>,
+    pub folder_id: Option<i32>,
+}
+
+/// Organizer's changes to a video listing
+#[derive(Debug, Default, PartialEq)]
+pub struct ListResponse {
+    pub hidden_video_hashes: Vec<String>,
+    /// Video hash -> label
+    pub labels: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Organizer {
+    url: hyper::Uri,
+    /// Allow actions if the permission check fails (see `check_permission`)
+    pub fail_open: bool,
+}
+
+impl Organizer {
+    pub fn new(url: &str) -> anyhow::Result<Organizer> {
+        let url = url.trim().trim_end_matches('/').parse::<hyper::Uri>()?;
+        if url.scheme_str() != Some("http") || url.host().is_none() {
+            bail!("Organizer URL must be http://HOST:PORT (plaintext HTTP/2)");
+        }
+        Ok(Organizer { url, fail_open: false })
+    }
+
+    /// Allow actions when the permission check fails or times out, instead of denying them
+    pub fn fail_open(self, fail_open: bool) -> Organizer {
+        Organizer { fail_open, ..self }
+    }
+
+    /// Make an unary gRPC call.
+    ///
+    /// # Returns
+    /// * `Ok(Some(msg))` - Encoded response message
+    /// * `Ok(None)` - Organizer doesn't implement the method
+    async fn call(&self, method: &str, msg: Vec<u8>) -> anyhow::Result<Option<Vec<u8>>>
+    {
+        let client = hyper::Client::builder().http2_only(true).build_http::<hyper::Body>();
+        let req = hyper::Request::post(format!("{}/{SERVICE}/{method}", self.url))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(hyper::Body::from(pb::frame(&msg)))?;
+
+        let res = tokio::time::timeout(TIMEOUT, async {
+            let res = client.request(req).await?;
+            if !res.status().is_success() { bail!("HTTP status {}", res.status()); }
+            // Errors can come in headers only, without body and trailers
+            if let Some(status) = grpc_status(res.headers())? {
+                return Ok(Err(status));
+            }
+            let mut body = res.into_body();
+            let mut data = vec![];
+            while let Some(chunk) = body.data().await {
+                data.extend_from_slice(&chunk?);
+            }
+            let trailers = body.trailers().await?.ok_or(anyhow!("No gRPC trailers"))?;
+            Ok(match grpc_status(&trailers)? {
+                Some(status) => Err(status),
+                None => Ok(data),
+            })
+        }).await.map_err(|_| anyhow!("Timed out after {:?}", TIMEOUT))??;
+
+        match res {
+            Ok(data) => Ok(Some(pb::unframe(&data)?)),
+            Err((GRPC_UNIMPLEMENTED, _)) => Ok(None),
+            Err((code, msg)) => bail!("gRPC status {code}: {msg}"),
+        }
+    }
+
+    /// Tell organizer about a new video.
+    pub async fn video_ingested(&self, v: &models::Video) -> anyhow::Result<IngestResponse>
+    {
+        let mut req = pb::Writer::default();
+        req.message(1, &video_msg(v));
+        let Some(res) = self.call("VideoIngested", req.0).await? else { return Ok(IngestResponse::default()); };
+        let mut out = IngestResponse::default();
+        for (field, val) in pb::read(&res)? {
+            match field {
+                1 => out.title = Some(val.string()?),
+                2 => out.folder_id = Some(val.int32()?),
+                _ => {},
+            }
+        }
+        Ok(out)
+    }
+
+    /// Let organizer hide and label videos in a user's listing.
+    pub async fn list_videos(&self, user_id: &str, videos: &[models::Video]) -> anyhow::Result<ListResponse>
+    {
+        let mut req = pb::Writer::default();
+        req.string(1, user_id);
+        for v in videos {
+            req.message(2, &video_msg(v));
+        }
+        let Some(res) = self.call("ListVideos", req.0).await? else { return Ok(ListResponse::default()); };
+        let mut out = ListResponse::default();
+        for (field, val) in pb::read(&res)? {
+            match field {
+                1 => out.hidden_video_hashes.push(val.string()?),
+                2 => {
+                    let (mut k, mut v) = (String::new(), String::new());
+                    for (f, entry_val) in pb::read(val.bytes()?)? {
+                        match f {
+                            1 => k = entry_val.string()?,
+                            2 => v = entry_val.string()?,
+                            _ => {},
+                        }
+                    }
+                    out.labels.insert(k, v);
+                },
+                _ => {},
+            }
+        }
+        Ok(out)
+    }
+
+    /// Ask organizer if user may do something to a video.
+    ///
+    /// # Arguments
+    /// * `action` - See `organizer_action`
+    ///
+    /// # Returns
+    /// * `Ok(None)` - Allowed
+    /// * `Ok(Some(reason))` - Denied
+    pub async fn check_permission(&self, user_id: &str, action: &str, video_hash: &str) -> anyhow::Result<Option<String>>
+    {
+        let mut req = pb::Writer::default();
+        req.string(1, user_id);
+        req.string(2, action);
+        req.string(3, video_hash);
+        let Some(res) = self.call("CheckPermission", req.0).await? else { return Ok(None); };
+        let (mut deny, mut reason) = (false, String::new());
+        for (field, val) in pb::read(&res)? {
+            match field {
+                1 => deny = val.varint()? != 0,
+                2 => reason = val.string()?,
+                _ => {},
+            }
+        }
+        Ok(deny.then(|| if reason.is_empty() { "Denied by organizer".into() } else { reason }))
+    }
+
+    /// Tell organizer about a new video, and apply the title and folder it picks.
+    /// Blocks the calling (non-async) thread until done.
+    pub fn apply_video_ingested(&self, db: &DB, vh: &str) -> anyhow::Result<()>
+    {
+        let v = db.get_video(vh)?;
+        let org = self.clone();
+        // Own thread and runtime, so this works whether the caller is in a Tokio runtime or not
+        let res = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread().enable_all().build()?
+                .block_on(org.video_ingested(&v))
+        }).join().map_err(|_| anyhow!("Organizer call panicked"))??;
+
+        if let Some(title) = res.title.filter(|t| !t.trim().is_empty()) {
+            db.rename_video(vh, title.trim())?;
+        }
+        if let Some(fid) = res.folder_id {
+            db.get_folder(fid).context(format!("Organizer picked folder {fid}"))?;
+            db.set_video_folder(vh, Some(fid))?;
+        }
+        Ok(())
+    }
+}
+
+fn video_msg(v: &models::Video) -> Vec<u8> {
+    let mut w = pb::Writer::default();
+    w.string(1, &v.video_hash);
+    w.string(2, v.title.as_deref().unwrap_or_default());
+    w.string(3, v.added_by_userid.as_deref().unwrap_or_default());
+    w.string(4, v.orig_filename.as_deref().unwrap_or_default());
+    if let Some(fid) = v.folder_id { w.int32(5, fid); }
+    w.0
+}
+
+/// gRPC status (code, message) from headers or trailers, if not OK
+fn grpc_status(hdrs: &hyper::HeaderMap) -> anyhow::Result<Option<(u32, String)>> {
+    let Some(code) = hdrs.get("grpc-status") else { return Ok(None); };
+    let code = code.to_str()?.parse::<u32>()?;
+    let msg = hdrs.get("grpc-message").and_then(|m| m.to_str().ok())
+        .map(|m| urlencoding::decode(m).map(|m| m.into_owned()).unwrap_or(m.into()))
+        .unwrap_or_default();
+    Ok((code != 0).then_some((code, msg)))
+}
+
+/// Minimal protobuf wire format: varints and length-delimited fields, which is all the
+/// organizer messages use.
+pub(crate) mod pb {
+    use anyhow::{anyhow, bail};
+
+    #[derive(Default)]
+    pub struct Writer(pub Vec<u8>);
+
+    impl Writer {
+        fn varint(&mut self, mut v: u64) {
+            while v >= 0x80 {
+                self.0.push((v as u8) | 0x80);
+                v >>= 7;
+            }
+            self.0.push(v as u8);
+        }
+
+        fn bytes(&mut self, field: u32, b: &[u8]) {
+            self.varint(((field as u64) << 3) | 2);
+            self.varint(b.len() as u64);
+            self.0.extend_from_slice(b);
+        }
+
+        /// String field (proto3: left out if empty)
+        pub fn string(&mut self, field: u32, s: &str) {
+            if !s.is_empty() { self.bytes(field, s.as_bytes()); }
+        }
+
+        pub fn message(&mut self, field: u32, msg: &[u8]) {
+            self.bytes(field, msg);
+        }
+
+        pub fn int32(&mut self, field: u32, v: i32) {
+            self.varint((field as u64) << 3);
+            self.varint(v as i64 as u64);
+        }
+
+        /// (Only in responses, so only needed by the fake organizer of tests)
+        #[cfg(test)]
+        pub fn bool(&mut self, field: u32, v: bool) {
+            if v {
+                self.varint((field as u64) << 3);
+                self.varint(1);
+            }
+        }
+    }
+
+    pub enum Value<'a> {
+        Varint(u64),
+        Bytes(&'a [u8]),
+    }
+
+    impl<'a> Value<'a> {
+        pub fn varint(&self) -> anyhow::Result<u64> {
+            match self { Value::Varint(v) => Ok(*v), _ => bail!("Expected varint") }
+        }
+        pub fn int32(&self) -> anyhow::Result<i32> {
+            Ok(self.varint()? as i64 as i32)
+        }
+        pub fn bytes(&self) -> anyhow::Result<&'a [u8]> {
+            match self { Value::Bytes(b) => Ok(b), _ => bail!("Expected length-delimited field") }
+        }
+        pub fn string(&self) -> anyhow::Result<String> {
+            Ok(String::from_utf8(self.bytes()?.to_vec())?)
+        }
+    }
+
+    fn read_varint(buf: &[u8], pos: &mut usize) -> anyhow::Result<u64> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = *buf.get(*pos).ok_or(anyhow!("Truncated varint"))?;
+            *pos += 1;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 { return Ok(v); }
+        }
+        bail!("Varint too long")
+    }
+
+    /// Fields of a message, in order. Fixed size fields (unused here) are skipped.
+    pub fn read(buf: &[u8]) -> anyhow::Result<Vec<(u32, Value<'_>)>> {
+        let mut res = vec![];
+        let mut pos = 0;
+        while pos < buf.len() {
+            let key = read_varint(buf, &mut pos)?;
+            let field = (key >> 3) as u32;
+            match key & 7 {
+                0 => res.push((field, Value::Varint(read_varint(buf, &mut pos)?))),
+                1 => pos += 8,
+                2 => {
+                    let len = read_varint(buf, &mut pos)? as usize;
+                    let b = buf.get(pos..pos.saturating_add(len)).ok_or(anyhow!("Truncated field"))?;
+                    res.push((field, Value::Bytes(b)));
+                    pos += len;
+                },
+                5 => pos += 4,
+                wt => bail!("Unsupported wire type {wt}"),
+            }
+        }
+        if pos > buf.len() { bail!("Truncated field"); }
+        Ok(res)
+    }
+
+    /// gRPC message framing: uncompressed flag and big endian length
+    pub fn frame(msg: &[u8]) -> Vec<u8> {
+        let mut res = Vec::with_capacity(msg.len() + 5);
+        res.push(0);
+        res.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+        res.extend_from_slice(msg);
+        res
+    }
+
+    pub fn unframe(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if data.len() < 5 { bail!("No gRPC message in response"); }
+        if data[0] != 0 { bail!("Compressed gRPC messages are not supported"); }
+        let len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+        Ok(data.get(5..5 + len).ok_or(anyhow!("Truncated gRPC message"))?.to_vec())
+    }
+}
+
+/// Fake organizer for tests: serves gRPC calls with `handler(method, request)`,
+/// which returns an encoded response or None for UNIMPLEMENTED.
+#[cfg(test)]
+pub(crate) async fn spawn_test_organizer<F>(handler: F) -> String
+    where F: Fn(&str, &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static
+{
+    use std::sync::Arc;
+    let handler = Arc::new(handler);
+    let make_svc = hyper::service::make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move { Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+            let handler = handler.clone();
+            async move {
+                let method = req.uri().path().rsplit('/').next().unwrap_or_default().to_string();
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let res = handler(&method, &pb::unframe(&body).unwrap());
+                let (mut tx, body) = hyper::Body::channel();
+                let status = if res.is_some() { "0" } else { "12" };
+                tokio::spawn(async move {
+                    if let Some(msg) = res { tx.send_data(pb::frame(&msg).into()).await.ok(); }
+                    let mut trailers = hyper::HeaderMap::new();
+                    trailers.insert("grpc-status", status.parse().unwrap());
+                    tx.send_trailers(trailers).await.ok();
+                });
+                Ok::<_, std::convert::Infallible>(hyper::Response::builder()
+                    .header("content-type", "application/grpc").body(body).unwrap())
+            }
+        })) }
+    });
+    let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).http2_only(true).serve(make_svc);
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    url
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_protobuf_wire_format()
+{
+    let mut w = pb::Writer::default();
+    w.string(1, "abc");
+    w.string(2, "");
+    w.int32(3, -2);
+    w.bool(4, true);
+    w.bool(5, false);
+    w.int32(300, 150);
+    assert_eq!(&w.0[..5], &[0x0a, 3, b'a', b'b', b'c']);
+    assert_eq!(&w.0[w.0.len()-4..], &[0xe0, 0x12, 0x96, 0x01], "Field 300 = 150");
+
+    let fields = pb::read(&w.0).unwrap();
+    assert_eq!(fields.iter().map(|(f, _)| *f).collect::<Vec<_>>(), vec![1, 3, 4, 300]);
+    assert_eq!(fields[0].1.string().unwrap(), "abc");
+    assert_eq!(fields[1].1.int32().unwrap(), -2);
+    assert_eq!(fields[2].1.varint().unwrap(), 1);
+    assert_eq!(fields[3].1.int32().unwrap(), 150);
+
+    assert!(pb::read(&[0x0a, 5, b'x']).is_err());
+    assert_eq!(pb::unframe(&pb::frame(&w.0)).unwrap(), w.0);
+    assert!(pb::unframe(&[1, 0, 0, 0, 0]).is_err());
+
+    assert!(Organizer::new("https://organizer:50051").is_err());
+    assert!(Organizer::new("organizer:50051").is_err());
+    assert!(Organizer::new("http://127.0.0.1:50051/").is_ok());
+}
+
+#[tokio::test]
+async fn test_organizer_ingest()
+{
+    let (db, _data_dir, videos, _comments) = crate::database::tests::make_test_db();
+    let folder = db.add_folder(&models::FolderInsert { user_id: "user.num1".into(), title: "SQ010".into(), parent_id: None }).unwrap();
+    let vh = videos[0].video_hash.clone();
+    let (vh_cln, fid) = (vh.clone(), folder.id);
+    let url = spawn_test_organizer(move |method, req| {
+        assert_eq!(method, "VideoIngested");
+        let video = pb::read(pb::read(req).unwrap()[0].1.bytes().unwrap()).unwrap();
+        assert_eq!(video[0].1.string().unwrap(), vh_cln);
+        let mut res = pb::Writer::default();
+        res.string(1, "SQ010_SH020 v3");
+        res.int32(2, fid);
+        Some(res.0)
+    }).await;
+
+    let org = Organizer::new(&url).unwrap();
+    let db = std::sync::Arc::new(db);
+    let (db_cln, vh_cln) = (db.clone(), vh.clone());
+    tokio::task::spawn_blocking(move || org.apply_video_ingested(&db_cln, &vh_cln)).await.unwrap().unwrap();
+    let v = db.get_video(&vh).unwrap();
+    assert_eq!((v.title.as_deref(), v.folder_id), (Some("SQ010_SH020 v3"), Some(folder.id)));
+
+    // Unimplemented method and unreachable organizer
+    let url = spawn_test_organizer(|_, _| None).await;
+    assert_eq!(Organizer::new(&url).unwrap().check_permission("user.num1", "view", &vh).await.unwrap(), None);
+    let port = portpicker::pick_unused_port().unwrap();
+    assert!(Organizer::new(&format!("http://127.0.0.1:{port}")).unwrap().check_permission("user.num1", "view", &vh).await.is_err());
+}
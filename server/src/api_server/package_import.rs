@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::database::models;
+use crate::video_pipeline::{IncomingFile, calc_video_hash, calc_content_hash};
+use crate::video_pipeline::review_package::{PACKAGE_FORMAT, PACKAGE_FORMAT_VERSION, safe_filename};
+use crate::video_pipeline::subtitles::is_subtitle_file;
+use super::server_state::ServerState;
+use super::stitch::notify_error;
+
+/// Parse attribution mapping "remote_user=local_user,..." (`X-Import-User-Map` header)
+pub fn parse_user_map(s: &str) -> Result<HashMap<String, String>, String>
+{
+    s.split(',').map(str::trim).filter(|p| !p.is_empty()).map(|p| match p.split_once('=') {
+        Some((r, l)) if !r.trim().is_empty() && !l.trim().is_empty() => Ok((r.trim().to_string(), l.trim().to_string())),
+        _ => Err(format!("Invalid user mapping '{p}', expected 'remote_user=local_user'")),
+    }).collect()
+}
+
+/// Short name of the instance a package came from, e.g. "https://review.studio-a.com/" -> "review.studio-a.com"
+fn origin_name(origin: &str) -> String
+{
+    let host = origin.split_once("://").map(|(_, r)| r).unwrap_or(origin);
+    let host = host.split('/').next().unwrap_or_default().trim();
+    if host.is_empty() { "imported".into() } else { host.into() }
+}
+
+/// Local user ID for a comment author. Unmapped authors are kept apart from local users, as "<user>@<origin>".
+fn local_user_id(remote: &str, user_map: &HashMap<String, String>, origin: &str) -> String
+{
+    user_map.get(remote).cloned().unwrap_or_else(|| format!("{}@{}", remote, origin_name(origin)))
+}
+
+/// Regular file (not a symlink, which an archive could point anywhere)
+fn is_regular_file(p: &Path) -> bool {
+    std::fs::symlink_metadata(p).map(|m| m.is_file()).unwrap_or(false)
+}
+
+/// Regular files in a dir, sorted
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let mut res = std::fs::read_dir(dir).map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path())
+        .filter(|p| is_regular_file(p)).collect::<Vec<_>>()).unwrap_or_default();
+    res.sort();
+    res
+}
+
+/// Find the dir with `metadata.json` in an unpacked package: root, or the single top level folder
+fn package_root(dir: &Path) -> Result<PathBuf, String>
+{
+    if is_regular_file(&dir.join("metadata.json")) { return Ok(dir.into()); }
+    let subdirs = std::fs::read_dir(dir).map_err(|e| format!("Failed to read package: {e}"))?
+        .filter_map(|e| e.ok()).map(|e| e.path())
+        .filter(|p| std::fs::symlink_metadata(p).map(|m| m.is_dir()).unwrap_or(false))
+        .collect::<Vec<_>>();
+    match &subdirs[..] {
+        [d] if is_regular_file(&d.join("metadata.json")) => Ok(d.clone()),
+        _ => Err("Not a review package (metadata.json not found)".into()),
+    }
+}
+
+/// Unpack a package zip into `dst` with `unzip`. Unzip itself refuses paths outside `dst`.
+fn unpack(zip: &Path, dst: &Path, server: &ServerState) -> Result<(), String>
+{
+    let mut cmd = server.sandbox.command(&["unzip", "-q", "-o"], &[dst.parent().unwrap_or(dst)]);
+    cmd.arg(zip).arg("-d").arg(dst);
+    tracing::debug!("Exec: {:?}", cmd);
+    match cmd.output() {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) => Err(format!("Failed to unpack package: {}", String::from_utf8_lossy(&out.stderr).lines().last().unwrap_or(""))),
+        Err(e) => Err(format!("Failed to execute unzip: {e}")),
+    }
+}
+
+/// Local hashes of the source videos (earlier versions) of a package's video. Sources are found if
+/// they were imported from the same origin before, or if the package was exported from this instance.
+fn local_sources(server: &ServerState, origin: &str, sources: &[String]) -> Vec<String>
+{
+    sources.iter().filter_map(|s| {
+        if origin == server.url_base && server.db.get_video(s).is_ok() { return Some(s.clone()); }
+        server.db.get_imported_copies(origin, s).ok().and_then(|c| c.into_iter().next())
+    }).collect()
+}
+
+/// Import an uploaded review package (see `review_package::make_request`): unpack it, store its
+/// comments with attribution mapped to local users, link it to earlier imported versions, and submit the
+/// video (with captions and drawings next to it) to the processing pipeline like a regular upload.
+///
+/// # Arguments
+/// * `server` - Server state
+/// * `user_id` - User who gets the imported video
+/// * `zip` - Uploaded package, in its own upload dir
+/// * `user_map` - Remote user ID -> local user ID, for comment authors
+/// * `match_users` - Authors not in `user_map` who have an account here (same user ID) keep it
+///
+/// # Returns
+/// Hash of the new video and number of imported comments
+fn import_package(server: &ServerState, user_id: &str, zip: &Path, user_map: &HashMap<String, String>, match_users: bool) -> Result<(String, usize), String>
+{
+    let dir = zip.parent().ok_or("Bad upload path")?.to_path_buf();
+    let unpack_dir = dir.join("package");
+    unpack(zip, &unpack_dir, server)?;
+    std::fs::remove_file(zip).unwrap_or_else(|e| tracing::warn!(details=%e, "Failed to remove package zip."));
+
+    let root = package_root(&unpack_dir)?;
+    let metadata = std::fs::read_to_string(root.join("metadata.json")).map_err(|e| e.to_string())
+        .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Invalid metadata.json: {e}"))?;
+    if metadata["format"] != PACKAGE_FORMAT || metadata["format_version"].as_u64().is_none_or(|v| v > PACKAGE_FORMAT_VERSION) {
+        return Err("Unsupported package format".into());
+    }
+    let comments = match root.join("comments.json") {
+        f if is_regular_file(&f) => std::fs::read_to_string(&f).map_err(|e| e.to_string())
+            .and_then(|s| serde_json::from_str::<Vec<models::Comment>>(&s).map_err(|e| e.to_string()))
+            .map_err(|e| format!("Invalid comments.json: {e}"))?,
+        _ => vec![],
+    };
+    let origin = metadata["origin"].as_str().unwrap_or_default().to_string();
+    let mut user_map = user_map.clone();
+    if match_users {
+        for c in &comments {
+            if !user_map.contains_key(&c.user_id) && server.db.get_user(&c.user_id).is_ok() {
+                user_map.insert(c.user_id.clone(), c.user_id.clone());
+            }
+        }
+    }
+
+    // Video file, named after the original title
+    let media = files_in(&root).into_iter()
+        .find(|p| p.file_stem().is_some_and(|s| s == "proxy" || s == "original"))
+        .ok_or("No video in package")?;
+    let ext = media.extension().map(|e| e.to_string_lossy().to_string()).unwrap_or("mp4".into());
+    let title = metadata["title"].as_str().or(metadata["orig_filename"].as_str()).unwrap_or_default();
+    let name = Path::new(title).file_stem().map(|s| safe_filename(&s.to_string_lossy())).unwrap_or_default();
+    let name = if name.is_empty() { "imported".to_string() } else { name };
+    let video_file = dir.join(format!("{name}.{ext}"));
+    std::fs::rename(&media, &video_file).map_err(|e| format!("Failed to move video: {e}"))?;
+
+    let vh = calc_video_hash(&video_file, user_id).map_err(|e| e.to_string())?;
+    if server.db.get_video(&vh).is_ok() {
+        return Err("You already have this video".into());
+    }
+    let content_hash = calc_content_hash(&video_file).map_err(|e| e.to_string())?;
+    if let Some(v) = server.db.get_user_videos_by_content_hash(user_id, &content_hash).map_err(|e| e.to_string())?.first() {
+        return Err(format!("You already have this video, as '{}'", v.title.as_deref().unwrap_or(&v.video_hash)));
+    }
+
+    // Captions as subtitle sidecars ("<id>_<lang>.vtt" -> "<name>.<n>.<lang>.vtt")
+    for (i, f) in files_in(&root.join("captions")).iter().filter(|f| is_subtitle_file(f)).enumerate() {
+        let stem = f.file_stem().unwrap_or_default().to_string_lossy();
+        let lang = stem.split_once('_').map(|(_, l)| l).filter(|l| !l.is_empty());
+        let dst = match lang {
+            Some(l) => format!("{}.{}.{}.{}", name, i + 1, l, f.extension().unwrap_or_default().to_string_lossy()),
+            None => format!("{}.{}.vtt", name, i + 1),
+        };
+        std::fs::rename(f, dir.join(dst)).map_err(|e| format!("Failed to move caption file: {e}"))?;
+    }
+
+    // Drawings, moved into the video dir on ingest
+    let drawings_dir = dir.join("drawings");
+    let mut drawings = HashMap::new();
+    for c in &comments {
+        let Some(d) = c.drawing.as_deref().map(safe_filename).filter(|d| !d.is_empty()) else { continue };
+        let src = root.join("drawings").join(&d);
+        if is_regular_file(&src) {
+            std::fs::create_dir_all(&drawings_dir).map_err(|e| format!("Failed to create drawings dir: {e}"))?;
+            std::fs::rename(&src, drawings_dir.join(&d)).map_err(|e| format!("Failed to move drawing: {e}"))?;
+        }
+        if drawings_dir.join(&d).is_file() { drawings.insert(c.id, d); }
+    }
+    std::fs::remove_dir_all(&unpack_dir).unwrap_or_else(|e| tracing::warn!(details=%e, "Failed to remove unpacked package."));
+
+    // Comments, parents before replies
+    let store_comments = || -> Result<usize, String> {
+        let mut comments = comments.iter().collect::<Vec<_>>();
+        comments.sort_by_key(|c| c.id);
+        let mut new_ids = HashMap::new();
+        for c in comments {
+            let new_id = server.db.add_imported_comment(&models::CommentInsert {
+                video_hash: vh.clone(),
+                parent_id: c.parent_id.and_then(|p| new_ids.get(&p).copied()),
+                user_id: local_user_id(&c.user_id, &user_map, &origin),
+                username: c.username.clone(),
+                comment: c.comment.clone(),
+                timecode: c.timecode.clone(),
+                timecode_end: c.timecode_end.clone(),
+                drawing: drawings.get(&c.id).cloned(),
+                page: c.page,
+                region: c.region.clone(),
+                visibility: models::comment_visibility::EXTERNAL.into(),
+            }, c.created, c.edited).map_err(|e| format!("DB error: {e}"))?;
+            new_ids.insert(c.id, new_id);
+        }
+        Ok(new_ids.len())
+    };
+    let res = store_comments().and_then(|n_comments| {
+        // Provenance: where it came from, and which earlier versions it was made from
+        if let Some(origin_vh) = metadata["video_hash"].as_str() {
+            server.db.add_video_import(&models::VideoImportInsert {
+                video_hash: vh.clone(), origin: origin.clone(), origin_video_hash: origin_vh.into(), imported_by: user_id.into(),
+            }).map_err(|e| format!("DB error: {e}"))?;
+        }
+        let sources = metadata["sources"].as_array().map(|s| s.iter().filter_map(|s| s.as_str().map(String::from)).collect::<Vec<_>>()).unwrap_or_default();
+        let sources = local_sources(server, &origin, &sources);
+        if !sources.is_empty() {
+            let op = metadata["operation"].as_str().filter(|o| !o.is_empty()).unwrap_or("Derived");
+            server.db.add_video_sources(&vh, &sources, &format!("{} (imported from {})", op, origin_name(&origin)))
+                .map_err(|e| format!("DB error: {e}"))?;
+        }
+        tracing::info!(video_hash=%vh, n_comments, n_sources=sources.len(), "Submitting imported video.");
+        server.upload_tx.send(IncomingFile { file_path: video_file, user_id: user_id.into(), ..Default::default() })
+            .map_err(|_| "Internal error: couldn't submit video for processing".to_string())?;
+        Ok(n_comments)
+    });
+    if res.is_err() {
+        // Video isn't in DB yet, this only removes what was imported
+        server.db.del_video_and_comments(&vh).unwrap_or_else(|e| tracing::error!(details=%e, "Failed to clean up failed import."));
+    }
+    res.map(|n| (vh, n))
+}
+
+/// Import an uploaded review package in a background thread (see `import_package`).
+/// User is notified of the result. Upload dir is removed on failure.
+pub fn spawn_package_import(server: ServerState, user_id: String, zip: PathBuf, user_map: HashMap<String, String>, match_users: bool)
+{
+    std::thread::spawn(move || {
+        let _span = tracing::info_span!("package_import", user=%user_id).entered();
+        match import_package(&server, &user_id, &zip, &user_map, match_users) {
+            Ok((vh, n_comments)) => {
+                if let Err(e) = server.push_user_message(&models::MessageInsert {
+                        event_name: "ok".into(),
+                        user_id: user_id.clone(),
+                        ref_video_hash: Some(vh),
+                        message: "Review package imported".into(),
+                        details: format!("{n_comments} comments"),
+                        ..Default::default() }, true) {
+                    tracing::error!(details=%e, "Failed to send message to user.");
+                }
+            },
+            Err(e) => {
+                tracing::info!(details=%e, "Package import failed.");
+                if let Some(dir) = zip.parent() {
+                    std::fs::remove_dir_all(dir).unwrap_or_else(|e| tracing::warn!(details=%e, "Failed to remove upload dir."));
+                }
+                notify_error(&server, &user_id, "Package import failed", e);
+            }
+        }
+    });
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_package_import_mapping()
+{
+    let map = parse_user_map(" alice=bob, carol = dan ,").unwrap();
+    assert_eq!(map.get("alice").map(String::as_str), Some("bob"));
+    assert_eq!(map.get("carol").map(String::as_str), Some("dan"));
+    assert!(parse_user_map("alice").is_err());
+    assert!(parse_user_map("=bob").is_err());
+    assert!(parse_user_map("").unwrap().is_empty());
+
+    assert_eq!(origin_name("https://review.studio-a.com/"), "review.studio-a.com");
+    assert_eq!(origin_name("http://127.0.0.1:8095"), "127.0.0.1:8095");
+    assert_eq!(origin_name(""), "imported");
+    assert_eq!(local_user_id("alice", &map, "https://a.com"), "bob");
+    assert_eq!(local_user_id("admin", &map, "https://a.com"), "admin@a.com");
+
+    let dir = tempfile::tempdir().unwrap();
+    assert!(package_root(dir.path()).is_err());
+    std::fs::create_dir_all(dir.path().join("pkg")).unwrap();
+    std::fs::write(dir.path().join("pkg").join("metadata.json"), "{}").unwrap();
+    assert_eq!(package_root(dir.path()).unwrap(), dir.path().join("pkg"));
+    std::os::unix::fs::symlink("/etc/passwd", dir.path().join("pkg").join("proxy.mp4")).unwrap();
+    assert!(files_in(&dir.path().join("pkg")).iter().all(|f| f.file_name().unwrap() == "metadata.json"));
+}
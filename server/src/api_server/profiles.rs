@@ -0,0 +1,145 @@
+use std::path::PathBuf;
+use data_url::DataUrl;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use warp::http::StatusCode;
+
+use crate::database::models;
+use super::file_server::Denied;
+use super::server_state::ServerState;
+
+// Users can set a display name, avatar and time zone for themselves (`set_user_profile`). Names
+// from the auth proxy are often login names, so the display name is shown instead wherever
+// other users see them: comments (`author`), collab commands and join/leave messages
+// (`from_profile`) and collab participants (`participant_profiles`).
+//
+// Avatars are sent as data URIs, like comment attachments, and stored in `avatars/` next to the
+// videos dir, named by a hash of the content. They're served by `/avatars` to anyone with the URL
+// (or with a signed one, if URL signing is enabled), and new images get new URLs, so they can
+// be cached for long. Files no profile uses anymore are deleted.
+
+/// Max size of an avatar image
+pub const MAX_AVATAR_SIZE: usize = 1024 * 1024;
+
+/// Max length of a display name (characters)
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Avatar image types, with the file extensions they're stored with
+const AVATAR_TYPES: &[(&str, &str)] = &[("image/png", "png"), ("image/jpeg", "jpg"), ("image/webp", "webp"), ("image/gif", "gif")];
+
+/// Where avatar images are stored
+pub fn avatars_dir(server: &ServerState) -> PathBuf
+{
+    server.videos_dir.parent().unwrap_or(&server.videos_dir).join("avatars")
+}
+
+/// Is `tz` an IANA time zone name ("Europe/Helsinki", "UTC") or a UTC offset ("+02:00", "-0330").
+/// Names aren't checked against the time zone database, only their form.
+pub fn is_valid_timezone(tz: &str) -> bool
+{
+    if let Some(offset) = tz.strip_prefix(['+', '-']) {
+        let digits = offset.replacen(':', "", 1);
+        return digits.len() == 4 && digits.chars().all(|c| c.is_ascii_digit())
+            && digits[..2].parse::<u32>().is_ok_and(|h| h <= 14) && digits[2..].parse::<u32>().is_ok_and(|m| m < 60);
+    }
+    tz.len() <= 64 && tz.split('/').all(|part| !part.is_empty()
+        && part.starts_with(|c: char| c.is_ascii_alphabetic())
+        && part.chars().all(|c| c.is_ascii_alphanumeric() || "_-+".contains(c)))
+}
+
+/// Profile of a user for clients, with the name from the auth proxy (or comment) as fallback
+pub fn profile_json(server: &ServerState, user_id: &str, user_name: &str) -> serde_json::Value
+{
+    let p = server.db.get_user_profile(user_id).unwrap_or_else(|e| {
+        tracing::error!(user=user_id, details=%e, "Failed to get user profile.");
+        models::UserProfile { user_id: user_id.into(), ..Default::default() }
+    });
+    to_json(server, &p, user_name)
+}
+
+/// Profiles of several users (ID and fallback name), in the same order
+pub fn profiles_json(server: &ServerState, users: &[(String, String)]) -> Vec<serde_json::Value>
+{
+    let profiles = server.db.get_user_profiles(&users.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>()).unwrap_or_else(|e| {
+        tracing::error!(details=%e, "Failed to get user profiles.");
+        vec![]
+    });
+    users.iter().map(|(id, name)| match profiles.iter().find(|p| &p.user_id == id) {
+        Some(p) => to_json(server, p, name),
+        None => to_json(server, &models::UserProfile { user_id: id.clone(), ..Default::default() }, name),
+    }).collect()
+}
+
+fn to_json(server: &ServerState, p: &models::UserProfile, user_name: &str) -> serde_json::Value
+{
+    json!({
+        "user_id": p.user_id,
+        "display_name": p.display_name.as_deref().unwrap_or(user_name),
+        "avatar_url": p.avatar.as_ref().map(|f| server.avatar_url(f)),
+        "timezone": p.timezone,
+    })
+}
+
+/// Decode an avatar image sent as a data URI, check it and store it.
+///
+/// # Returns
+/// * `Ok(Ok(file))` - Stored, as file name in avatars dir
+/// * `Ok(Err(reason))` - Refused, to tell the user
+pub async fn store_avatar(server: &ServerState, data_uri: &str) -> anyhow::Result<Result<String, String>>
+{
+    if data_uri.len() > MAX_AVATAR_SIZE / 3 * 4 + 1024 {
+        return Ok(Err(format!("Avatar is too big (max {} kB)", MAX_AVATAR_SIZE / 1024)));
+    }
+    let Ok(uri) = DataUrl::process(data_uri) else { return Ok(Err("Invalid data URI".into())) };
+    let mime_type = format!("{}/{}", uri.mime_type().type_, uri.mime_type().subtype);
+    let Some((_, ext)) = AVATAR_TYPES.iter().find(|(t, _)| *t == mime_type) else {
+        return Ok(Err(format!("Avatar must be a PNG, JPEG, WebP or GIF image, not {}", mime_type)));
+    };
+    let Ok((data, _)) = uri.decode_to_vec() else { return Ok(Err("Invalid data URI".into())) };
+    if data.is_empty() || data.len() > MAX_AVATAR_SIZE {
+        return Ok(Err(format!("Avatar is empty or too big (max {} kB)", MAX_AVATAR_SIZE / 1024)));
+    }
+    let file = format!("{}.{}", &hex::encode(Sha256::digest(&data))[..32], ext);
+    let dir = avatars_dir(server);
+    async_std::fs::create_dir_all(&dir).await?;
+    async_std::fs::write(dir.join(&file), data).await?;
+    Ok(Ok(file))
+}
+
+/// Delete an avatar file, unless some profile still uses it
+pub fn remove_unused_avatar(server: &ServerState, file: &str) -> anyhow::Result<()>
+{
+    let path = avatars_dir(server).join(file);
+    if !server.db.is_avatar_in_use(file)? && path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Access check for the `/avatars` directory. Anyone with the URL may get an avatar, but if
+/// URL signing is enabled, anonymous requests need a valid signature (as for `/videos`).
+pub fn check_avatar_file(server: &ServerState, user_id: &str, path: &str, query: &str) -> Result<Option<String>, Denied>
+{
+    if let Some(signer) = &server.url_signer {
+        match signer.verify(&format!("/avatars/{path}"), query, super::url_signing::unix_now()) {
+            Ok(()) => return Ok(None),
+            Err(msg) if user_id == "anonymous" || query.contains("sig=") => return Err(Denied(StatusCode::FORBIDDEN, msg)),
+            Err(_) => {},
+        }
+    }
+    Ok(None)
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_timezone_validation()
+{
+    for tz in ["UTC", "Europe/Helsinki", "America/Argentina/Buenos_Aires", "Etc/GMT+2", "+02:00", "-0330", "+14:00"] {
+        assert!(is_valid_timezone(tz), "{tz}");
+    }
+    for tz in ["", "/", "Europe/", "../etc/passwd", "Europe Helsinki", "+2", "+15:00", "-01:60", "2Europe/X", &"A".repeat(65)] {
+        assert!(!is_valid_timezone(tz), "{tz}");
+    }
+}
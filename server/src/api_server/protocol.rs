@@ -0,0 +1,238 @@
+use std::collections::{BTreeSet, HashMap};
+use warp::ws::Message;
+
+use super::{compression, msgpack};
+
+// Desktop and CLI clients are updated on their own schedule, so the server speaks each client's
+// version of the message schema. Clients tell what they support when connecting, in `/api/ws` query:
+//
+//   /api/ws?proto=1&caps=resume,reactions&compress=gzip&format=msgpack
+//
+// - `proto` - Highest schema version the client understands. The session uses that or
+//   `PROTOCOL_VERSION`, whichever is lower. Clients older than `MIN_PROTOCOL_VERSION` are refused
+//   with an error asking to upgrade.
+// - `caps` - Optional message groups (`CAPABILITIES`) the client handles. Commands and fields of
+//   the others are left out of messages to it. Unknown ones are ignored, for clients newer than
+//   the server.
+// - `compress` - Compression methods the client can decompress. Only `gzip` is supported
+//   (see `compression`). Off by default.
+// - `format` - Wire format: `json` (default) or `msgpack` (see `msgpack`). Unsupported ones fall
+//   back to JSON, so clients should check `welcome` (which is in the agreed format) or just
+//   handle both.
+//
+// Clients that don't say (like the web client served with the server) get the current version
+// with all capabilities. The negotiated protocol is in `welcome`.
+//
+// When the schema changes incompatibly, bump `PROTOCOL_VERSION` and add a step to
+// `Protocol::adapt` that turns messages back into the old form for older clients. Additions that
+// old clients can do without go into `CAPABILITIES` instead.
+
+/// Current message schema version
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest schema version still served
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Known capabilities, with the commands and fields (top level or in `data`) that need them
+pub const CAPABILITIES: &[(&str, &[&str], &[&str])] = &[
+    ("resume", &["resumed", "resume_failed"], &["seq", "resume_token"]),
+    ("reactions", &["comment_reactions"], &["reactions"]),
+    ("attachments", &["comment_attachments"], &["attachments"]),
+    ("collab", &["collab_cmd"], &[]),
+    ("review_sessions", &["review_session", "review_sessions"], &[]),
+    ("upload_sessions", &["upload_session", "upload_sessions"], &[]),
+    ("profiles", &["user_profile"], &["author", "from_profile", "participant_profiles", "profile"]),
+];
+
+/// Message schema spoken with a client
+#[derive(Debug, Clone, PartialEq)]
+pub struct Protocol {
+    pub version: u32,
+    capabilities: BTreeSet<&'static str>,
+    /// Send long messages gzipped
+    pub gzip: bool,
+    /// Messages are MessagePack in binary frames, instead of JSON
+    pub msgpack: bool,
+}
+
+impl Default for Protocol {
+    /// Current version, all capabilities (client didn't negotiate)
+    fn default() -> Self {
+        Protocol { version: PROTOCOL_VERSION, capabilities: CAPABILITIES.iter().map(|(c, _, _)| *c).collect(), gzip: false, msgpack: false }
+    }
+}
+
+impl Protocol {
+
+    /// Agree on a protocol with a client, from its `proto`, `caps`, `compress` and `format` query parameters.
+    ///
+    /// # Returns
+    /// * `Err` - Message for the client, if it's too old or sent a bad version
+    pub fn negotiate(query: &HashMap<String, String>) -> Result<Protocol, String> {
+        let (proto, caps) = (query.get("proto"), query.get("caps"));
+        let gzip = query.get("compress").is_some_and(|c| c.split(',').any(|m| m.trim().eq_ignore_ascii_case("gzip")));
+        let msgpack = query.get("format").is_some_and(|f| f.trim().eq_ignore_ascii_case("msgpack"));
+        if proto.is_none() && caps.is_none() {
+            return Ok(Protocol { gzip, msgpack, ..Protocol::default() });
+        }
+        let version = match proto.map(|p| p.trim().parse::<u32>()) {
+            None => PROTOCOL_VERSION,
+            Some(Ok(v)) if v < MIN_PROTOCOL_VERSION => return Err(format!(
+                "Client protocol version {v} is no longer supported (server needs {MIN_PROTOCOL_VERSION}-{PROTOCOL_VERSION}). Please upgrade the client.")),
+            Some(Ok(v)) => v.min(PROTOCOL_VERSION),
+            Some(Err(_)) => return Err("Bad protocol version".into()),
+        };
+        let capabilities = match caps {
+            None => Protocol::default().capabilities,
+            Some(caps) => {
+                let asked = caps.split(',').map(str::trim).collect::<Vec<_>>();
+                CAPABILITIES.iter().map(|(c, _, _)| *c).filter(|c| asked.contains(c)).collect()
+            }
+        };
+        Ok(Protocol { version, capabilities, gzip, msgpack })
+    }
+
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "version": self.version,
+            "min_version": MIN_PROTOCOL_VERSION,
+            "max_version": PROTOCOL_VERSION,
+            "capabilities": self.capabilities,
+            "format": if self.msgpack { "msgpack" } else { "json" },
+            "compression": self.gzip.then_some("gzip"),
+            "compress_min_bytes": self.gzip.then_some(compression::MIN_COMPRESS_BYTES),
+            "server_version": env!("CARGO_PKG_VERSION") })
+    }
+
+    /// Turn a message into this protocol's form, in the agreed format and compressed if the
+    /// client asked for it. Messages that aren't JSON (e.g. close) are only compressed.
+    ///
+    /// # Returns
+    /// * `None` - Client doesn't get this message at all
+    pub fn adapt(&self, msg: Message) -> Option<Message> {
+        let mut msg = self.adapt_schema(msg)?;
+        if self.msgpack {
+            if let Some(json) = msg.to_str().ok().and_then(|s| serde_json::from_str::<serde_json::Value>(s).ok()) {
+                msg = Message::binary(msgpack::encode(&json));
+            }
+        }
+        Some(if self.gzip { compression::compress_message(msg) } else { msg })
+    }
+
+    /// Parse a message from the client, JSON in a text frame, or MessagePack in a binary one
+    /// if that was agreed.
+    ///
+    /// # Returns
+    /// * `None` - Not a message for this protocol (e.g. ping or close)
+    pub fn parse(&self, msg: &Message) -> Option<anyhow::Result<serde_json::Value>> {
+        if msg.is_text() {
+            Some(serde_json::from_str(msg.to_str().unwrap_or_default()).map_err(Into::into))
+        } else if msg.is_binary() && self.msgpack {
+            Some(msgpack::decode(msg.as_bytes()))
+        } else {
+            None
+        }
+    }
+
+    fn adapt_schema(&self, msg: Message) -> Option<Message> {
+        let full = Protocol::default();
+        if (self.version, &self.capabilities) == (full.version, &full.capabilities) || !msg.is_text() {
+            return Some(msg);
+        }
+        let Ok(mut json) = serde_json::from_str::<serde_json::Value>(msg.to_str().unwrap_or_default()) else {
+            return Some(msg);
+        };
+        let cmd = json["cmd"].as_str().unwrap_or_default().to_string();
+        for (_, cmds, fields) in CAPABILITIES.iter().filter(|(c, _, _)| !self.has(c)) {
+            if cmds.contains(&cmd.as_str()) {
+                return None;
+            }
+            for f in *fields {
+                if let Some(o) = json.as_object_mut() { o.remove(*f); }
+                strip_field(&mut json["data"], f);
+            }
+        }
+        // No older schema versions yet. Downgrade steps go here, newest first.
+        Some(Message::text(json.to_string()))
+    }
+}
+
+/// Remove a field from an object, or from the objects in an array (e.g. comment lists)
+fn strip_field(v: &mut serde_json::Value, field: &str) {
+    match v {
+        serde_json::Value::Object(o) => {
+            o.remove(field);
+            for x in o.values_mut().filter(|x| x.is_array()) { strip_field(x, field); }
+        },
+        serde_json::Value::Array(a) => a.iter_mut().for_each(|x| if x.is_object() { strip_field(x, field) }),
+        _ => {},
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_protocol_negotiation()
+{
+    let query = |q: &[(&str, &str)]| q.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>();
+
+    // Clients that don't negotiate get everything
+    let full = Protocol::negotiate(&query(&[])).unwrap();
+    assert_eq!(full, Protocol::default());
+    assert!(CAPABILITIES.iter().all(|(c, _, _)| full.has(c)));
+
+    let p = Protocol::negotiate(&query(&[("proto", "99"), ("caps", "reactions, resume,teleport")])).unwrap();
+    assert_eq!(p.version, PROTOCOL_VERSION);
+    assert!(p.has("reactions") && p.has("resume") && !p.has("attachments") && !p.has("teleport"));
+    assert_eq!(p.to_json()["capabilities"], serde_json::json!(["reactions", "resume"]));
+    assert_eq!(Protocol::negotiate(&query(&[("proto", "1")])).unwrap().version, 1);
+    assert!(Protocol::negotiate(&query(&[("proto", "1")])).unwrap().has("collab"), "No caps = all");
+    assert!(Protocol::negotiate(&query(&[("proto", "0")])).unwrap_err().contains("upgrade"));
+    assert!(Protocol::negotiate(&query(&[("proto", "x")])).is_err());
+
+    // Adapting messages
+    let p = Protocol::negotiate(&query(&[("proto", "1"), ("caps", "")])).unwrap();
+    let msg = |j: serde_json::Value| Message::text(j.to_string());
+    let adapted = |m: Message| p.adapt(m).map(|m| serde_json::from_str::<serde_json::Value>(m.to_str().unwrap()).unwrap());
+    assert_eq!(adapted(msg(serde_json::json!({"seq": 5, "cmd": "new_comment", "data": {"comment": "hi", "reactions": [], "attachments": []}}))),
+        Some(serde_json::json!({"cmd": "new_comment", "data": {"comment": "hi"}})));
+    assert_eq!(adapted(msg(serde_json::json!({"cmd": "list_comments", "data": {"comments": [{"id": 1, "reactions": {}}]}}))),
+        Some(serde_json::json!({"cmd": "list_comments", "data": {"comments": [{"id": 1}]}})));
+    assert_eq!(adapted(msg(serde_json::json!({"cmd": "welcome", "data": {"user_id": "u", "resume_token": "t", "seq": 1}}))),
+        Some(serde_json::json!({"cmd": "welcome", "data": {"user_id": "u"}})));
+    assert!(adapted(msg(serde_json::json!({"cmd": "collab_cmd", "data": {}}))).is_none());
+    assert!(p.adapt(Message::close()).unwrap().is_close());
+
+    // Full protocol passes messages as is
+    let m = msg(serde_json::json!({"seq": 5, "cmd": "collab_cmd", "data": {}}));
+    assert_eq!(full.adapt(m.clone()), Some(m));
+
+    // Compression is only on when asked for, also without other negotiation
+    assert!(!full.gzip);
+    let gz = Protocol::negotiate(&query(&[("compress", "deflate, gzip")])).unwrap();
+    assert!(gz.gzip && gz.has("collab"));
+    assert_eq!(gz.to_json()["compression"], "gzip");
+    let long = msg(serde_json::json!({"cmd": "user_videos", "data": {"videos": vec!["abcdef"; 5000]}}));
+    assert!(gz.adapt(long.clone()).unwrap().is_binary());
+    assert!(full.adapt(long).unwrap().is_text());
+
+    // MessagePack both ways, falls back to JSON for unknown formats
+    let mp = Protocol::negotiate(&query(&[("format", "msgpack")])).unwrap();
+    assert!(mp.msgpack && mp.has("collab") && !mp.gzip);
+    let m = msg(serde_json::json!({"cmd": "collab_cmd", "data": {"seek_time": 1.5}}));
+    let bin = mp.adapt(m.clone()).unwrap();
+    assert!(bin.is_binary());
+    assert_eq!(mp.parse(&bin).unwrap().unwrap(), serde_json::json!({"cmd": "collab_cmd", "data": {"seek_time": 1.5}}));
+    assert_eq!(mp.parse(&m).unwrap().unwrap()["cmd"], "collab_cmd", "JSON from client still OK");
+    assert!(mp.adapt(Message::text("Echo: hi")).unwrap().is_text());
+    assert!(full.parse(&bin).is_none());
+    assert!(mp.parse(&Message::binary(vec![0xc1])).unwrap().is_err());
+    let cbor = Protocol::negotiate(&query(&[("format", "cbor")])).unwrap();
+    assert!(!cbor.msgpack && cbor.adapt(m).unwrap().is_text());
+    assert_eq!(cbor.to_json()["format"], "json");
+}
@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+use anyhow::{anyhow, bail};
+
+// Websocket commands are rate limited per connection (`--ws-rate-limits`), e.g.
+//
+//   ws-rate-limits = add_comment=1:10, collab_report=10:50, *=20:100
+//
+// gives each listed command a token bucket of `rate` commands per second, with bursts of up
+// to `burst`, and all other commands a shared one (`*`). Unlisted commands are unlimited
+// if there's no `*`.
+//
+// Commands over the limit are dropped, and the client is told to slow down (once per streak,
+// so that the replies can't be used for amplification either). A client that keeps flooding
+// after that (FLOOD_BURST dropped commands, refilled at FLOOD_RATE per second) is disconnected.
+// This keeps a single connection from making the server fan out comments or collab seeks
+// (`send_to_all_*`) to everyone else at whatever rate it likes.
+
+/// Dropped commands allowed in a burst before disconnecting
+const FLOOD_BURST: f64 = 50.0;
+/// Dropped commands per second allowed in the long run
+const FLOOD_RATE: f64 = 1.0;
+
+/// Key for the limit of unlisted commands
+pub const OTHER_COMMANDS: &str = "*";
+
+/// Message for clients whose commands are dropped
+pub const SLOW_DOWN_MSG: &str = "Too many requests. Slow down.";
+
+/// Rate limit of a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    /// Commands per second
+    pub rate: f64,
+    /// Max commands in a burst
+    pub burst: f64,
+}
+
+/// Configured limits by command name (or `OTHER_COMMANDS`)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimits {
+    limits: BTreeMap<String, Limit>,
+}
+
+impl RateLimits {
+    /// Parse config value, a comma separated list of `cmd=RATE:BURST`, or "off" for no limits.
+    pub fn parse(s: &str) -> anyhow::Result<RateLimits> {
+        let mut limits = BTreeMap::new();
+        if s.trim() == "off" {
+            return Ok(RateLimits { limits });
+        }
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (cmd, value) = item.split_once('=').ok_or_else(|| anyhow!("Expected cmd=RATE:BURST, got '{item}'"))?;
+            let (rate, burst) = value.split_once(':').ok_or_else(|| anyhow!("Expected RATE:BURST for '{}', got '{}'", cmd.trim(), value.trim()))?;
+            let num = |v: &str| v.trim().parse::<f64>().ok().filter(|v| v.is_finite() && *v > 0.0)
+                .ok_or_else(|| anyhow!("Invalid limit for '{}': '{}'", cmd.trim(), value.trim()));
+            let limit = Limit { rate: num(rate)?, burst: num(burst)?.max(1.0) };
+            if limits.insert(cmd.trim().to_string(), limit).is_some() {
+                bail!("Limit for '{}' given twice", cmd.trim());
+            }
+        }
+        Ok(RateLimits { limits })
+    }
+
+    /// Key of the bucket a command is counted in, and its limit. None if unlimited.
+    fn limit_for<'a>(&'a self, cmd: &'a str) -> Option<(&'a str, Limit)> {
+        self.limits.get(cmd).map(|l| (cmd, *l))
+            .or_else(|| self.limits.get(OTHER_COMMANDS).map(|l| (OTHER_COMMANDS, *l)))
+    }
+
+    /// Limits as `cmd` -> "RATE:BURST", for admin
+    pub fn to_json(&self) -> serde_json::Value {
+        self.limits.iter().map(|(cmd, l)| (cmd.clone(), serde_json::json!(format!("{}:{}", l.rate, l.burst))))
+            .collect::<serde_json::Map<_, _>>().into()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: Limit, now: Instant) -> TokenBucket {
+        TokenBucket { tokens: limit.burst, last: now }
+    }
+
+    /// Refill for the time passed since last call, and take a token if there's one
+    fn try_take(&mut self, limit: Limit, now: Instant) -> bool {
+        let secs = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + secs * limit.rate).min(limit.burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// What to do with a command from client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Drop the command. True if client should be told (first dropped command in a row).
+    Drop(bool),
+    /// Client keeps flooding, close the session
+    Disconnect,
+}
+
+/// Rate limiter state of one Websocket session
+#[derive(Debug)]
+pub struct SessionLimiter {
+    buckets: HashMap<String, TokenBucket>,
+    flood: TokenBucket,
+    dropping: bool,
+}
+
+impl SessionLimiter {
+    pub fn new(now: Instant) -> SessionLimiter {
+        SessionLimiter {
+            buckets: HashMap::new(),
+            flood: TokenBucket::full(Limit { rate: FLOOD_RATE, burst: FLOOD_BURST }, now),
+            dropping: false,
+        }
+    }
+
+    /// Count a command from client against the limits.
+    pub fn check(&mut self, cmd: &str, limits: &RateLimits, now: Instant) -> Verdict {
+        let Some((key, limit)) = limits.limit_for(cmd) else { return Verdict::Allow; };
+        let bucket = self.buckets.entry(key.to_string()).or_insert_with(|| TokenBucket::full(limit, now));
+        if bucket.try_take(limit, now) {
+            self.dropping = false;
+            return Verdict::Allow;
+        }
+        if !self.flood.try_take(Limit { rate: FLOOD_RATE, burst: FLOOD_BURST }, now) {
+            return Verdict::Disconnect;
+        }
+        let first = !self.dropping;
+        self.dropping = true;
+        Verdict::Drop(first)
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_rate_limits_config()
+{
+    let rl = RateLimits::parse("add_comment=1:10, collab_report = 10:50.5, *=20:100").unwrap();
+    assert_eq!(rl.limit_for("add_comment"), Some(("add_comment", Limit { rate: 1.0, burst: 10.0 })));
+    assert_eq!(rl.limit_for("collab_report").unwrap().1.burst, 50.5);
+    assert_eq!(rl.limit_for("echo"), Some((OTHER_COMMANDS, Limit { rate: 20.0, burst: 100.0 })));
+    assert_eq!(rl.to_json()["add_comment"], "1:10");
+    assert_eq!(RateLimits::parse("off").unwrap(), RateLimits::default());
+    assert_eq!(RateLimits::parse("add_comment=1:10").unwrap().limit_for("echo"), None);
+
+    assert!(RateLimits::parse("add_comment=1").is_err());
+    assert!(RateLimits::parse("add_comment=0:10").is_err());
+    assert!(RateLimits::parse("add_comment=x:10").is_err());
+    assert!(RateLimits::parse("add_comment").is_err());
+    assert!(RateLimits::parse("echo=1:1,echo=2:2").is_err());
+}
+
+#[test]
+fn test_session_limiter()
+{
+    let rl = RateLimits::parse("add_comment=1:3, *=100:100").unwrap();
+    let t0 = Instant::now();
+    let at = |ms: u64| t0 + std::time::Duration::from_millis(ms);
+    let mut sl = SessionLimiter::new(t0);
+
+    // Burst, then dropped (client told once)
+    for _ in 0..3 { assert_eq!(sl.check("add_comment", &rl, t0), Verdict::Allow); }
+    assert_eq!(sl.check("add_comment", &rl, t0), Verdict::Drop(true));
+    assert_eq!(sl.check("add_comment", &rl, t0), Verdict::Drop(false));
+
+    // Other commands have their own bucket
+    assert_eq!(sl.check("echo", &rl, t0), Verdict::Allow);
+
+    // Refills with time
+    assert_eq!(sl.check("add_comment", &rl, at(1000)), Verdict::Allow);
+    assert_eq!(sl.check("add_comment", &rl, at(1000)), Verdict::Drop(true));
+
+    // Unlimited without a limit
+    let mut sl = SessionLimiter::new(t0);
+    for _ in 0..1000 { assert_eq!(sl.check("echo", &RateLimits::default(), t0), Verdict::Allow); }
+
+    // Flooding disconnects
+    let rl = RateLimits::parse("echo=1:1").unwrap();
+    let mut sl = SessionLimiter::new(t0);
+    let verdicts = (0..100).map(|_| sl.check("echo", &rl, t0)).collect::<Vec<_>>();
+    assert_eq!(verdicts.iter().filter(|v| matches!(v, Verdict::Drop(_))).count(), FLOOD_BURST as usize);
+    assert_eq!(verdicts.last(), Some(&Verdict::Disconnect));
+}
@@ -0,0 +1,255 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde::Serialize;
+
+use crate::database::models;
+use super::server_state::ServerState;
+use super::trash;
+use super::upload_sweeper::{self, abandoned_uploads, dir_entries, older_than, upload_files_in_use};
+
+// Reconciliation cross-checks stored files against the DB, to find what crashes, restores from
+// backup and manual fiddling leave behind:
+//
+//  - orphaned files: video dirs of videos that aren't in DB (and their trash dirs), upload dirs
+//    that no unfinished job or pending upload refers to, and partial files of removed upload sessions,
+//  - dangling DB rows: comments, subtitles etc. of videos that aren't in DB anymore,
+//  - videos whose files are missing.
+//
+// Admins run it with `admin_reconcile` (a report, or removal with `"remove": true`), and it runs
+// daily (`--reconcile`), telling admins if it found something. Files touched within the last day
+// are left out, so uploads and ingests in progress aren't mistaken for orphans. Videos with missing
+// files are only reported, as their comments may be worth restoring the files from backup for.
+
+/// How often scheduled reconciliation runs
+pub const RUN_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+/// Files touched more recently than this are not considered orphaned
+const MIN_ORPHAN_AGE: Duration = Duration::from_secs(24 * 3600);
+
+/// What scheduled reconciliation does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReconcileMode {
+    #[default]
+    Off,
+    /// Tell admins what was found
+    Report,
+    /// Remove orphaned files and dangling DB rows, and tell admins
+    Remove,
+}
+
+impl std::str::FromStr for ReconcileMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(ReconcileMode::Off),
+            "report" => Ok(ReconcileMode::Report),
+            "remove" => Ok(ReconcileMode::Remove),
+            _ => Err(format!("Unknown reconcile mode '{}'. Use off, report or remove.", s)),
+        }
+    }
+}
+
+/// File or dir that nothing in DB refers to
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OrphanFile {
+    /// Path under data dir, e.g. "videos/abc123"
+    pub path: String,
+    pub bytes: u64,
+    pub reason: String,
+}
+
+/// Video in DB whose files are gone
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MissingFiles {
+    pub video_hash: String,
+    pub title: Option<String>,
+    pub owner: Option<String>,
+    pub trashed: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub orphan_files: Vec<OrphanFile>,
+    /// Table name -> number of rows
+    pub dangling_rows: BTreeMap<String, usize>,
+    pub missing_files: Vec<MissingFiles>,
+    /// Orphaned files and dangling rows were removed (not just reported)
+    pub removed: bool,
+    pub freed_bytes: u64,
+    /// Problems removing things
+    pub errors: Vec<String>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.orphan_files.is_empty() && self.dangling_rows.is_empty() && self.missing_files.is_empty() && self.errors.is_empty()
+    }
+
+    /// One line summary, for logs and admin notifications
+    pub fn summary(&self) -> String {
+        format!("{} orphaned files ({} MB){}, {} dangling DB rows, {} videos with missing files{}.",
+            self.orphan_files.len(), self.orphan_files.iter().map(|o| o.bytes).sum::<u64>() / (1024 * 1024),
+            if self.removed { " removed" } else { "" },
+            self.dangling_rows.values().sum::<usize>(), self.missing_files.len(),
+            if self.errors.is_empty() { String::new() } else { format!(", {} errors", self.errors.len()) })
+    }
+}
+
+/// Latest modification time of a file, or of anything in a dir (recursively).
+/// Symlinks are not followed.
+pub(crate) fn last_modified(path: &Path) -> Option<SystemTime>
+{
+    let md = path.symlink_metadata().ok()?;
+    let mut latest = md.modified().ok()?;
+    if md.is_dir() {
+        for e in std::fs::read_dir(path).ok()?.flatten() {
+            latest = latest.max(last_modified(&e.path())?);
+        }
+    }
+    Some(latest)
+}
+
+/// Find orphaned files in videos and upload dirs.
+///
+/// # Arguments
+/// * `videos_dir` - Videos dir (video dirs, and their trash dirs under trash/)
+/// * `upload_dir` - Upload dir (upload dirs, and partial files of upload sessions under partial/)
+/// * `video_hashes` - All videos in DB, trashed or not
+/// * `in_use` - Files in upload dir that DB refers to (unfinished jobs, pending uploads)
+/// * `session_ids` - IDs of upload sessions in DB
+/// * `now` - Current time, for age check
+///
+/// # Returns
+/// Orphaned files with the reason, in path order
+fn find_orphans(videos_dir: &Path, upload_dir: &Path, video_hashes: &HashSet<String>, in_use: &[PathBuf], session_ids: &HashSet<String>, now: SystemTime) -> Vec<(PathBuf, String)>
+{
+    let name = |p: &Path| p.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+
+    let mut candidates = vec![];
+    let trash_dir = videos_dir.join("trash");
+    for p in dir_entries(videos_dir).into_iter().filter(|p| *p != trash_dir) {
+        if !video_hashes.contains(&name(&p)) { candidates.push((p, "Video is not in DB".to_string())); }
+    }
+    for p in dir_entries(&trash_dir) {
+        if !video_hashes.contains(&name(&p)) { candidates.push((p, "Trashed video is not in DB".to_string())); }
+    }
+    candidates.extend(abandoned_uploads(upload_dir, in_use, session_ids));
+    candidates.into_iter().filter(|(p, _)| older_than(p, MIN_ORPHAN_AGE, now)).collect()
+}
+
+/// Cross-check files against DB, and optionally remove orphaned files and dangling DB rows.
+///
+/// # Arguments
+/// * `server` - Server state
+/// * `remove` - Remove what was found (otherwise just report it)
+pub fn reconcile(server: &ServerState, remove: bool) -> anyhow::Result<Report>
+{
+    let db = &server.db;
+    let videos = db.get_all_videos(None)?;
+    let video_hashes = videos.iter().map(|v| v.video_hash.clone()).collect::<HashSet<_>>();
+    let in_use = upload_files_in_use(db)?;
+    let session_ids = db.get_upload_sessions(None)?.into_iter().map(|s| s.id).collect::<HashSet<_>>();
+    let data_dir = server.videos_dir.parent().unwrap_or(&server.videos_dir);
+
+    let mut report = Report { removed: remove, ..Default::default() };
+    for (path, reason) in find_orphans(&server.videos_dir, &server.upload_dir, &video_hashes, &in_use, &session_ids, SystemTime::now()) {
+        let bytes = match remove {
+            true => match upload_sweeper::remove(&path) {
+                Ok(b) => { report.freed_bytes += b; b },
+                Err(e) => { report.errors.push(format!("Failed to remove '{}': {}", path.display(), e)); 0 },
+            },
+            false => upload_sweeper::disk_usage(&path),
+        };
+        report.orphan_files.push(OrphanFile { path: path.strip_prefix(data_dir).unwrap_or(&path).to_string_lossy().to_string(), bytes, reason });
+    }
+
+    report.dangling_rows = db.del_dangling_video_rows(!remove)?.into_iter().map(|(t, n)| (t.to_string(), n)).collect();
+
+    for v in videos {
+        let trashed = v.trashed.is_some();
+        let has_files = server.videos_dir.join(&v.video_hash).is_dir() || (trashed && trash::trash_dir(server, &v.video_hash).is_dir());
+        if !has_files {
+            report.missing_files.push(MissingFiles { video_hash: v.video_hash, title: v.title, owner: v.added_by_userid, trashed });
+        }
+    }
+    Ok(report)
+}
+
+/// Run reconciliation on schedule (see `ReconcileMode`), and tell admins if something was found
+pub fn run_scheduled(server: &ServerState, mode: ReconcileMode)
+{
+    if mode == ReconcileMode::Off {
+        return;
+    }
+    let report = match reconcile(server, mode == ReconcileMode::Remove) {
+        Ok(r) => r,
+        Err(e) => { tracing::error!(details=%e, "Reconciliation failed."); return; }
+    };
+    if report.is_clean() {
+        tracing::info!("Reconciliation found nothing to fix.");
+        return;
+    }
+    tracing::warn!(summary=%report.summary(), "Reconciliation found problems.");
+    if report.removed {
+        audit_removal(server, "system", &report);
+    }
+    for admin in crate::storage::admin_ids(&server.db) {
+        let msg = models::MessageInsert {
+            user_id: admin,
+            event_name: "info".into(),
+            message: "Storage reconciliation found problems. See admin_reconcile for details.".into(),
+            details: report.summary(),
+            ..Default::default()
+        };
+        if let Err(e) = server.push_user_message(&msg, true) {
+            tracing::error!(details=%e, "Failed to tell admin about reconciliation.");
+        }
+    }
+}
+
+/// Record removal of orphaned files and dangling rows in audit log
+pub fn audit_removal(server: &ServerState, user_id: &str, report: &Report)
+{
+    let res = server.db.add_audit_event(&models::AuditEventInsert {
+        user_id: user_id.into(),
+        action: models::audit_action::RECONCILE.into(),
+        ref_video_hash: None,
+        details: report.summary(),
+    });
+    if let Err(e) = res {
+        tracing::error!(details=%e, "Failed to audit reconciliation.");
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_find_orphans()
+{
+    let dir = tempfile::tempdir().unwrap();
+    let (videos_dir, upload_dir) = (dir.path().join("videos"), dir.path().join("upload"));
+    for d in ["videos/known/orig", "videos/gone/orig", "videos/trash/gone2", "videos/trash/trashed", "upload/in_use", "upload/stale", "upload/active", "upload/partial"] {
+        std::fs::create_dir_all(dir.path().join(d)).unwrap();
+    }
+    for f in ["videos/gone/orig/a.mov", "upload/in_use/b.mov", "upload/stale/c.mov", "upload/active/d.mov", "upload/partial/s1.part", "upload/partial/s2.part"] {
+        std::fs::write(dir.path().join(f), "DATA").unwrap();
+    }
+    let hashes = ["known", "trashed"].into_iter().map(String::from).collect::<HashSet<_>>();
+    let in_use = vec![upload_dir.join("in_use").join("b.mov")];
+    let sessions = HashSet::from(["s1".to_string()]);
+
+    // Everything is fresh
+    let now = SystemTime::now();
+    assert!(find_orphans(&videos_dir, &upload_dir, &hashes, &in_use, &sessions, now).is_empty());
+
+    // Dir with a file still being written to is not orphaned, though the dir itself is old
+    let later = now + MIN_ORPHAN_AGE + Duration::from_secs(60);
+    std::fs::File::options().write(true).open(upload_dir.join("active").join("d.mov")).unwrap().set_modified(later).unwrap();
+    let orphans = find_orphans(&videos_dir, &upload_dir, &hashes, &in_use, &sessions, later);
+    let names = orphans.iter().map(|(p, _)| p.strip_prefix(dir.path()).unwrap().to_string_lossy().to_string()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["videos/gone", "videos/trash/gone2", "upload/stale", "upload/partial/s2.part"]);
+
+    assert_eq!("Remove".parse::<ReconcileMode>(), Ok(ReconcileMode::Remove));
+    assert!("delete".parse::<ReconcileMode>().is_err());
+}
@@ -0,0 +1,104 @@
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::{Duration, Instant};
+
+use super::server_state::ServerState;
+use super::trash;
+use super::reconcile;
+
+/// How often expired data is looked for
+const RUN_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How many days to keep each kind of data. `None` = forever.
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    /// Deleted videos in trash
+    pub trash_days: Option<u32>,
+    /// Audit log. Entries about videos under legal hold are kept regardless.
+    pub audit_days: Option<u32>,
+    /// User messages (notifications, processing progress and errors)
+    pub message_days: Option<u32>,
+    /// Finished processing jobs. Added to daily job statistics before deletion.
+    pub job_days: Option<u32>,
+    /// Hours to keep uploads that nothing is processing (see `upload_sweeper`)
+    pub stale_upload_hours: Option<u32>,
+    /// Originals of videos that failed to ingest (in rejected dir)
+    pub rejected_days: Option<u32>,
+    /// Orphaned files and dangling DB rows (checked daily, see `reconcile`)
+    pub reconcile: reconcile::ReconcileMode,
+}
+
+impl Retention {
+    /// Parse days from a string. 0 means forever.
+    pub fn parse_days(s: &str) -> Result<Option<u32>, String> {
+        match s.trim().parse::<u32>() {
+            Ok(0) => Ok(None),
+            Ok(d) => Ok(Some(d)),
+            Err(_) => Err(format!("Invalid number of days: '{}'", s)),
+        }
+    }
+}
+
+fn cutoff(days: u32) -> chrono::NaiveDateTime {
+    chrono::Utc::now().naive_utc() - chrono::Duration::days(days as i64)
+}
+
+/// Delete everything that's older than its retention period (and expired share links), and store
+/// pending uploads that have waited too long (see `upload_dedup::expire_pending`). Also removes
+/// stale upload sessions (see `upload_sessions::expire_stale`), abandoned uploads and old rejected
+/// files (see `upload_sweeper`), and old entries of the video change journal (see `video_list_sub`).
+pub fn enforce(server: &ServerState, r: &Retention)
+{
+    super::upload_dedup::expire_pending(server);
+    super::upload_sessions::expire_stale(server);
+    super::upload_sweeper::sweep(server, r.stale_upload_hours, r.rejected_days);
+    if let Some(days) = r.trash_days {
+        trash::purge_expired(server, days);
+    }
+    let log_res = |what: &str, res: crate::database::error::DBResult<usize>| match res {
+        Ok(0) => {},
+        Ok(n) => tracing::info!(count=n, "Deleted expired {}.", what),
+        Err(e) => tracing::error!(details=%e, "Failed to delete expired {}.", what),
+    };
+    log_res("share links", server.db.del_share_links_expired_before(chrono::Utc::now().naive_utc()));
+    let keep_changes = chrono::Duration::seconds(super::video_list_sub::KEEP_CHANGES.as_secs() as i64);
+    log_res("video change journal entries", server.db.del_video_changes_before(chrono::Utc::now().naive_utc() - keep_changes));
+    if let Some(days) = r.audit_days {
+        log_res("audit events", server.db.del_audit_events_before(cutoff(days)));
+    }
+    if let Some(days) = r.message_days {
+        log_res("messages", server.db.del_messages_before(cutoff(days)));
+    }
+    if let Some(days) = r.job_days {
+        log_res("jobs", server.db.aggregate_and_del_jobs_before(cutoff(days)));
+    }
+}
+
+/// Enforce retention (and reconcile files with DB) periodically, until server terminates
+pub fn run_retention_loop(server: ServerState, r: Retention)
+{
+    let _span = tracing::info_span!("RETENTION").entered();
+    let mut last_run: Option<Instant> = None;
+    let mut last_reconcile: Option<Instant> = None;
+    while !server.terminate_flag.load(Relaxed) {
+        if last_run.is_none_or(|t| t.elapsed() >= RUN_INTERVAL) {
+            last_run = Some(Instant::now());
+            enforce(&server, &r);
+        }
+        if last_reconcile.is_none_or(|t| t.elapsed() >= reconcile::RUN_INTERVAL) {
+            last_reconcile = Some(Instant::now());
+            reconcile::run_scheduled(&server, r.reconcile);
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_retention_parse_days() {
+    assert_eq!(Retention::parse_days("0"), Ok(None));
+    assert_eq!(Retention::parse_days("30"), Ok(Some(30)));
+    assert!(Retention::parse_days("-1").is_err());
+    assert!(Retention::parse_days("forever").is_err());
+}
@@ -0,0 +1,211 @@
+use std::time::{Duration, Instant};
+use std::sync::atomic::Ordering::Relaxed;
+use serde_json::json;
+
+use crate::database::models;
+use super::server_state::ServerState;
+
+// Review sessions can be scheduled for a later time (`schedule_review`), with invited users.
+// Invitees get a message, and a "review_scheduled" webhook event carries an iCalendar (ICS)
+// invite for them, e.g. for an email gateway to attach. When the session starts, its collab room
+// is opened (persisted, see `collab_state`) with the scheduler as host, and everyone is told to
+// join it. Cancelling sends an ICS that removes the event from calendars.
+
+/// Longest session that can be scheduled
+pub const MAX_DURATION_MIN: i32 = 8 * 60;
+
+/// Max number of invited users per session
+pub const MAX_INVITEES: usize = 50;
+
+/// How far ahead sessions can be scheduled
+pub const MAX_DAYS_AHEAD: i64 = 365;
+
+/// Ended sessions are kept (and listed) this long
+const KEEP_HOURS: i64 = 24;
+
+/// How often sessions are checked for starting
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Event names of user messages and webhook events about review sessions
+pub mod review_event {
+    pub const SCHEDULED: &str = "review_scheduled";
+    pub const CANCELLED: &str = "review_cancelled";
+    pub const STARTED: &str = "review_started";
+}
+
+/// Link that opens the video and joins the session's collab
+pub fn session_url(url_base: &str, s: &models::ReviewSession) -> String {
+    format!("{url_base}/?vid={}&collab={}", s.video_hash, s.collab_id)
+}
+
+fn ics_time(t: &chrono::NaiveDateTime) -> String {
+    t.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escape TEXT value (RFC 5545, 3.3.11)
+fn ics_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(';', "\\;").replace(',', "\\,").replace("\r\n", "\\n").replace('\n', "\\n")
+}
+
+/// Fold a content line to max 75 octets per line, not splitting characters (RFC 5545, 3.1)
+fn ics_fold(line: &str) -> String {
+    let mut res = String::new();
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            res.push_str("\r\n ");
+            len = 1;
+        }
+        res.push(c);
+        len += c.len_utf8();
+    }
+    res + "\r\n"
+}
+
+/// iCalendar file for a session. A cancelled one removes the event from calendars that have it.
+pub fn make_ics(s: &models::ReviewSession, url_base: &str, cancelled: bool) -> String {
+    let url = session_url(url_base, s);
+    let lines = [
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".into(),
+        "PRODID:-//Clapshot//Review sessions//EN".into(),
+        format!("METHOD:{}", if cancelled { "CANCEL" } else { "PUBLISH" }),
+        "BEGIN:VEVENT".into(),
+        format!("UID:{}@clapshot", s.collab_id),
+        format!("SEQUENCE:{}", cancelled as i32),
+        format!("DTSTAMP:{}", ics_time(&chrono::Utc::now().naive_utc())),
+        format!("DTSTART:{}", ics_time(&s.starts)),
+        format!("DTEND:{}", ics_time(&s.ends())),
+        format!("SUMMARY:{}", ics_escape(&s.title)),
+        format!("DESCRIPTION:{}", ics_escape(&format!("Review session hosted by {}.\nJoin: {url}", s.host_name))),
+        format!("URL:{url}"),
+        format!("STATUS:{}", if cancelled { "CANCELLED" } else { "CONFIRMED" }),
+        "END:VEVENT".into(),
+        "END:VCALENDAR".into(),
+    ];
+    lines.iter().map(|l| ics_fold(l)).collect()
+}
+
+/// Session as JSON for clients, with invitees, join link and ICS
+pub fn session_json(server: &ServerState, s: &models::ReviewSession) -> anyhow::Result<serde_json::Value> {
+    let mut res = s.to_json()?;
+    res["invitees"] = json!(server.db.get_review_invitees(s.id)?);
+    res["url"] = json!(session_url(&server.url_base, s));
+    res["ics"] = json!(make_ics(s, &server.url_base, false));
+    Ok(res)
+}
+
+/// Tell invitees (and for `STARTED`, the host) about a session, and post a webhook event with
+/// an ICS invite (or cancellation) for them.
+///
+/// # Arguments
+/// * `event` - See `review_event`
+pub fn notify(server: &ServerState, s: &models::ReviewSession, invitees: &[String], event: &str) -> anyhow::Result<()> {
+    let when = s.starts.format("%Y-%m-%d %H:%M UTC");
+    let message = match event {
+        review_event::SCHEDULED => format!("{} invited you to review '{}' on {when}", s.host_name, s.title),
+        review_event::CANCELLED => format!("Review '{}' on {when} was cancelled", s.title),
+        _ => format!("Review '{}' is starting. Join now!", s.title),
+    };
+    let mut recipients = invitees.to_vec();
+    if event == review_event::STARTED {
+        recipients.insert(0, s.host.clone());
+    }
+    for uid in &recipients {
+        server.push_user_message(&models::MessageInsert {
+            event_name: event.into(),
+            user_id: uid.clone(),
+            ref_video_hash: Some(s.video_hash.clone()),
+            message: message.clone(),
+            details: session_url(&server.url_base, s),
+            ..Default::default() }, true)?;
+    }
+    if let Some(hook) = server.config.webhook() {
+        let users = invitees.iter().map(|uid| {
+            let name = server.db.get_user(uid).map(|u| u.username).unwrap_or(uid.clone());
+            json!({ "user_id": uid, "user_name": name })
+        }).collect::<Vec<_>>();
+        hook.send(event, json!({
+            "session": s.to_json()?, "invitees": users, "url": session_url(&server.url_base, s),
+            "ics": make_ics(s, &server.url_base, event == review_event::CANCELLED),
+            "ics_filename": format!("review-{}.ics", s.id) }));
+    }
+    Ok(())
+}
+
+/// Open collab rooms of sessions that have started, and tell participants to join.
+/// Sessions that ended before they could be opened (e.g. server was down) are skipped.
+///
+/// # Returns
+/// Number of sessions opened
+pub fn open_due_sessions(server: &ServerState) -> anyhow::Result<usize> {
+    let mut n_opened = 0;
+    for s in server.db.get_due_review_sessions()? {
+        server.db.set_review_session_opened(s.id)?;
+        if s.ends() < chrono::Utc::now().naive_utc() {
+            tracing::info!(session=s.id, "Review session ended before it could be opened. Skipping.");
+            continue;
+        }
+        server.db.open_collab_room(&s.collab_id, &s.video_hash, &s.host)?;
+        notify(server, &s, &server.db.get_review_invitees(s.id)?, review_event::STARTED)?;
+        tracing::info!(session=s.id, collab=s.collab_id, "Scheduled review session opened.");
+        n_opened += 1;
+    }
+    Ok(n_opened)
+}
+
+/// Open scheduled sessions when they start, and delete old ones, until server terminates
+pub fn run_review_loop(server: ServerState)
+{
+    let _span = tracing::info_span!("REVIEW_SCHEDULE").entered();
+    let mut last_check: Option<Instant> = None;
+    while !server.terminate_flag.load(Relaxed) {
+        if last_check.is_none_or(|t| t.elapsed() >= CHECK_INTERVAL) {
+            last_check = Some(Instant::now());
+            if let Err(e) = open_due_sessions(&server) {
+                tracing::error!(details=%e, "Failed to open scheduled review sessions.");
+            }
+            let before = chrono::Utc::now().naive_utc() - chrono::Duration::minutes(MAX_DURATION_MIN as i64) - chrono::Duration::hours(KEEP_HOURS);
+            match server.db.del_past_review_sessions(before) {
+                Ok(0) => {},
+                Ok(n) => tracing::debug!(n_sessions=n, "Deleted old review sessions."),
+                Err(e) => tracing::error!(details=%e, "Failed to delete old review sessions."),
+            }
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_review_ics()
+{
+    let s = models::ReviewSession {
+        id: 3,
+        collab_id: "c0ffee".into(),
+        video_hash: "abc123".into(),
+        host: "alice".into(),
+        host_name: "Alice".into(),
+        title: "Dailies; reel 2, final".into(),
+        starts: chrono::NaiveDate::from_ymd_opt(2026, 11, 2).unwrap().and_hms_opt(14, 30, 0).unwrap(),
+        duration_min: 90,
+        opened: None,
+        created: chrono::Utc::now().naive_utc(),
+    };
+    let ics = make_ics(&s, "https://clap.example", false);
+    assert!(ics.starts_with("BEGIN:VCALENDAR\r\n") && ics.ends_with("END:VCALENDAR\r\n"));
+    assert!(ics.split("\r\n").all(|l| l.len() <= 75));
+    let unfolded = ics.replace("\r\n ", "");
+    for line in ["UID:c0ffee@clapshot", "DTSTART:20261102T143000Z", "DTEND:20261102T160000Z",
+            "SUMMARY:Dailies\\; reel 2\\, final", "URL:https://clap.example/?vid=abc123&collab=c0ffee", "STATUS:CONFIRMED"] {
+        assert!(unfolded.contains(&format!("\r\n{line}\r\n")), "Missing {line}");
+    }
+    assert!(unfolded.contains("DESCRIPTION:Review session hosted by Alice.\\nJoin: https://clap.example/"));
+
+    let cancel = make_ics(&s, "https://clap.example", true).replace("\r\n ", "");
+    assert!(cancel.contains("\r\nMETHOD:CANCEL\r\n") && cancel.contains("\r\nSTATUS:CANCELLED\r\n") && cancel.contains("\r\nSEQUENCE:1\r\n"));
+
+    assert_eq!(ics_fold(&"ä".repeat(40)).split("\r\n").map(|l| l.len()).collect::<Vec<_>>(), vec![74, 7, 0]);
+}
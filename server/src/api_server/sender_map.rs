@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::{Arc, RwLock};
+
+use super::{Message, WsMsgSender};
+
+// Websocket sessions by key (user id, video hash, collab id), for fanning out messages.
+//
+// Keys are spread over shards with a lock each, so busy videos and collabs don't contend on a
+// single lock. Each key's sender list is immutable and shared (`Arc`): joining or leaving replaces
+// it, and sending only holds the shard's read lock long enough to clone the `Arc`. Messages are
+// then queued to the sessions without any lock held.
+//
+// A dead sender (session whose connection is gone, but whose guard hasn't removed it yet) doesn't
+// stop the others from getting the message. It's skipped, and pruned from the list after the send.
+
+const N_SHARDS: usize = 16;
+
+type Shard = RwLock<HashMap<String, Arc<[WsMsgSender]>>>;
+
+/// Result of sending a message to all senders of a key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendCounts {
+    /// Sessions the message was queued to
+    pub sent: u32,
+    /// Dead sessions skipped
+    pub failed: u32,
+}
+
+pub struct SenderMap {
+    shards: Vec<Shard>,
+    hasher: RandomState,
+}
+
+impl SenderMap {
+    pub fn new() -> SenderMap {
+        SenderMap { shards: (0..N_SHARDS).map(|_| RwLock::new(HashMap::new())).collect(), hasher: RandomState::new() }
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        &self.shards[self.hasher.hash_one(key) as usize % N_SHARDS]
+    }
+
+    /// Current senders of a key (a snapshot, not updated by later adds and removes)
+    pub fn senders(&self, key: &str) -> Arc<[WsMsgSender]> {
+        // Lists are replaced whole, so a panic elsewhere while holding the lock can't leave one half-updated
+        let shard = self.shard(key).read().unwrap_or_else(|e| e.into_inner());
+        shard.get(key).cloned().unwrap_or_else(|| Arc::new([]))
+    }
+
+    pub fn add(&self, key: &str, sender: WsMsgSender) {
+        let mut shard = self.shard(key).write().unwrap_or_else(|e| e.into_inner());
+        let list = shard.get(key).map(|l| l.iter().cloned().chain([sender.clone()]).collect())
+            .unwrap_or_else(|| Arc::from([sender]));
+        shard.insert(key.to_string(), list);
+    }
+
+    pub fn remove(&self, key: &str, sender: &WsMsgSender) {
+        let mut shard = self.shard(key).write().unwrap_or_else(|e| e.into_inner());
+        if let Some(list) = shard.get(key) {
+            let list = list.iter().filter(|s| !s.same_channel(sender)).cloned().collect::<Arc<[_]>>();
+            if list.is_empty() { shard.remove(key); } else { shard.insert(key.to_string(), list); }
+        }
+    }
+
+    /// Remove senders whose session is gone
+    fn prune(&self, key: &str) {
+        let mut shard = self.shard(key).write().unwrap_or_else(|e| e.into_inner());
+        if let Some(list) = shard.get(key) {
+            let list = list.iter().filter(|s| !s.is_closed()).cloned().collect::<Arc<[_]>>();
+            if list.is_empty() { shard.remove(key); } else { shard.insert(key.to_string(), list); }
+        }
+    }
+
+    /// Send a message to all senders of a key.
+    /// Dead senders are skipped (and pruned), so they don't keep the message from the others.
+    pub fn send(&self, key: &str, msg: &Message) -> SendCounts {
+        let mut res = SendCounts::default();
+        for sender in self.senders(key).iter() {
+            match sender.send(msg.clone()) {
+                Ok(_) => res.sent += 1,
+                Err(_) => res.failed += 1,
+            }
+        }
+        if res.failed > 0 {
+            tracing::debug!(key, failed=res.failed, "Pruning dead senders.");
+            self.prune(key);
+        }
+        res
+    }
+}
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_sender_map()
+{
+    let map = SenderMap::new();
+    let (tx1, mut rx1) = tokio::sync::mpsc::unbounded_channel();
+    let (tx2, mut rx2) = tokio::sync::mpsc::unbounded_channel();
+    map.add("video1", tx1.clone());
+    map.add("video1", tx2.clone());
+    map.add("video2", tx2.clone());
+
+    assert_eq!(map.send("video1", &Message::text("hello")), SendCounts { sent: 2, failed: 0 });
+    assert_eq!(rx1.try_recv().unwrap().to_str().unwrap(), "hello");
+    assert_eq!(rx2.try_recv().unwrap().to_str().unwrap(), "hello");
+    assert_eq!(map.send("nosuch", &Message::text("hello")), SendCounts::default());
+
+    // Snapshot is not affected by later changes
+    let snapshot = map.senders("video1");
+    map.remove("video1", &tx1);
+    assert_eq!(snapshot.len(), 2);
+    assert_eq!(map.senders("video1").len(), 1);
+    assert!(map.senders("video1")[0].same_channel(&tx2));
+
+    map.remove("video1", &tx2);
+    assert!(map.senders("video1").is_empty());
+    assert_eq!(map.senders("video2").len(), 1);
+
+    // Dead sender doesn't block the others, and is pruned
+    let (tx3, mut rx3) = tokio::sync::mpsc::unbounded_channel();
+    map.add("video2", tx1);
+    map.add("video2", tx3);
+    drop(rx1);
+    assert_eq!(map.send("video2", &Message::text("hi")), SendCounts { sent: 2, failed: 1 });
+    assert_eq!(rx2.try_recv().unwrap().to_str().unwrap(), "hi");
+    assert_eq!(rx3.try_recv().unwrap().to_str().unwrap(), "hi");
+    assert_eq!(map.senders("video2").len(), 2);
+    assert_eq!(map.send("video2", &Message::text("hi")), SendCounts { sent: 2, failed: 0 });
+
+    drop(rx2);
+    drop(rx3);
+    assert_eq!(map.send("video2", &Message::text("hi")), SendCounts { sent: 0, failed: 2 });
+    assert!(map.senders("video2").is_empty());
+}
@@ -1,13 +1,27 @@
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 use std::sync::atomic::{AtomicBool};
 
 use tokio::sync::Mutex;
 use anyhow::anyhow;
 
-use super::{WsMsgSender, SenderList, SenderListMap, StringToStringMap, Res};
+use super::{WsMsgSender, SenderListMap, StringToStringMap, Res};
+use super::sender_map::{SenderMap, SendCounts};
 use crate::database::DB;
+use crate::config::{LiveConfig, StartupOptions};
+use crate::database::models;
+use crate::video_pipeline::{IncomingFile, IngestPolicy, PipelineLink};
+use crate::video_pipeline::ExportRequest;
+use crate::video_pipeline::sandbox::Sandbox;
+use crate::video_pipeline::queues::QueueDepths;
+use crate::storage::StorageStatus;
+use super::url_signing::{UrlSigner, unix_now};
+use super::onboarding::Onboarding;
+use super::upload_sweeper::SweepStats;
+use super::web_push::WebPush;
+use super::session_resume::{self, ResumeStore};
+use super::i18n::Catalogs;
 
 /// Lists of all active connections and other server state vars
 #[derive (Clone)]
@@ -16,78 +30,186 @@ pub struct ServerState {
     pub db: Arc<DB>,
     pub videos_dir: PathBuf,
     pub upload_dir: PathBuf,
+    pub upload_tx: crossbeam_channel::Sender<IncomingFile>,
+    /// Export requests (clips, review packages) to the pipeline
+    pub export_tx: crossbeam_channel::Sender<ExportRequest>,
     pub url_base: String,
+    /// Signs URLs of video files, if they are served by something that can't check users (see `asset_url`)
+    pub url_signer: Option<UrlSigner>,
+    /// Settings that can be reloaded at runtime (quotas, webhook etc.)
+    pub config: Arc<LiveConfig>,
+    /// For external tools run by the API server (e.g. stitching)
+    pub sandbox: Sandbox,
+    /// Pipeline's processing settings, for predicting ingest results (preflight)
+    pub policy: IngestPolicy,
+    /// Welcome content for new users' first login, if any
+    pub onboarding: Option<Onboarding>,
+    /// Pipeline queue depths, for metrics. `upload_tx` is the (bounded) intake queue.
+    pub queues: Arc<QueueDepths>,
+    /// Is storage full (uploads refused, see `storage`)
+    pub storage: Arc<StorageStatus>,
+    /// Upload sessions currently receiving data (see `upload_sessions`), to refuse concurrent writers
+    pub upload_writers: Arc<std::sync::Mutex<HashSet<String>>>,
+    /// Number of URL downloads running per user (see `url_ingest`)
+    pub url_ingests: Arc<std::sync::Mutex<HashMap<String, usize>>>,
+    /// What the stale upload sweeper has removed (see `upload_sweeper`), for metrics
+    pub sweep_stats: Arc<SweepStats>,
+    /// Server's VAPID identity, for push notifications to browsers (see `web_push`)
+    pub web_push: Arc<WebPush>,
+    /// Recent events and disconnected sessions, for resuming them (see `session_resume`)
+    pub resume: Arc<ResumeStore>,
+    /// Translations of user messages (see `i18n`)
+    pub i18n: Arc<Catalogs>,
     user_id_to_senders: SenderListMap,
     video_hash_to_senders: SenderListMap,
+    /// Viewers that may see internal comments (subset of `video_hash_to_senders`)
+    internal_video_hash_to_senders: SenderListMap,
     collab_id_to_senders: SenderListMap,
     collab_id_to_video_hash: StringToStringMap,
 }
 
 impl ServerState {
 
-    pub fn new(db: Arc<DB>, videos_dir: &Path, upload_dir: &Path, url_base: &str, terminate_flag: Arc<AtomicBool>) -> ServerState {
+    pub fn new(db: Arc<DB>, opts: &StartupOptions, pipeline: PipelineLink, config: Arc<LiveConfig>, terminate_flag: Arc<AtomicBool>) -> ServerState {
         ServerState {
             db,
-            videos_dir: videos_dir.to_path_buf(),
-            upload_dir: upload_dir.to_path_buf(),
+            videos_dir: opts.data_dir.join("videos"),
+            upload_dir: opts.data_dir.join("upload"),
+            upload_tx: pipeline.upload_tx,
+            export_tx: pipeline.export_tx,
             terminate_flag,
-            url_base: url_base.to_string(),
-            user_id_to_senders: Arc::new(RwLock::new(HashMap::<String, SenderList>::new())),
-            video_hash_to_senders: Arc::new(RwLock::new(HashMap::<String, SenderList>::new())),
-            collab_id_to_senders: Arc::new(RwLock::new(HashMap::<String, SenderList>::new())),
+            url_base: opts.url_base.clone(),
+            url_signer: opts.url_signer.clone(),
+            config,
+            sandbox: opts.sandbox,
+            policy: opts.ingest,
+            onboarding: opts.onboarding.clone(),
+            queues: pipeline.queues,
+            storage: pipeline.storage,
+            upload_writers: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            url_ingests: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            sweep_stats: Arc::new(SweepStats::default()),
+            web_push: Arc::new(WebPush::new(&opts.data_dir, &opts.url_base)),
+            resume: Arc::new(ResumeStore::new()),
+            i18n: Arc::new(Catalogs::load(&opts.data_dir.join("locales"))),
+            user_id_to_senders: Arc::new(SenderMap::new()),
+            video_hash_to_senders: Arc::new(SenderMap::new()),
+            internal_video_hash_to_senders: Arc::new(SenderMap::new()),
+            collab_id_to_senders: Arc::new(SenderMap::new()),
             collab_id_to_video_hash: Arc::new(RwLock::new(HashMap::<String, String>::new())),
         }
     }
 
+    /// URL of a file in a video's dir, for client. Signed with an expiry time if URL signing is enabled.
+    ///
+    /// # Arguments
+    /// * `vh` - Video hash
+    /// * `rel_path` - Path of the file in video's dir (not URL encoded), e.g. "thumbs/thumb.webp"
+    pub fn asset_url(&self, vh: &str, rel_path: &str) -> String
+    {
+        self.signed_url(&format!("/videos/{}/{}", vh, rel_path))
+    }
+
+    /// URL of a user's avatar image (see `profiles`), signed like `asset_url`
+    pub fn avatar_url(&self, file: &str) -> String
+    {
+        self.signed_url(&format!("/avatars/{}", file))
+    }
+
+    /// URL for a path (not URL encoded) under URL base, signed if URL signing is enabled
+    fn signed_url(&self, path: &str) -> String
+    {
+        let encoded = path.split('/').map(|p| urlencoding::encode(p).into_owned()).collect::<Vec<_>>().join("/");
+        match &self.url_signer {
+            Some(signer) => format!("{}{}?{}", self.url_base, encoded, signer.sign(path, unix_now())),
+            None => format!("{}{}", self.url_base, encoded),
+        }
+    }
+
     /// Register a new sender (API connection) for a user_id. One user can have multiple connections.
     /// Returns a guard that will remove the sender when dropped.
     pub fn register_user_session(&self, user_id: &str, sender: WsMsgSender) -> Box<Mutex<dyn Send>> {
         self.add_sender_to_maplist(user_id, sender, &self.user_id_to_senders)
     }
 
+    /// Number of sessions user_id has open.
+    pub fn user_session_count(&self, user_id: &str) -> usize {
+        self.user_id_to_senders.senders(user_id).len()
+    }
+
     /// Send a message to all sessions user_id has open.
-    /// Bails out with error if any of the senders fail.
-    /// Returns the number of messages sent.
-    pub fn send_to_all_user_sessions(&self, user_id: &str, msg: &super::Message) -> Res<u32> {
-        let mut total_sent = 0u32;
-        let map = self.user_id_to_senders.read().map_err(|e| anyhow!("Sender map poisoned: {}", e))?;
-        for sender in map.get(user_id).unwrap_or(&vec![]).iter() {
-            sender.send(msg.clone())?;
-            total_sent += 1; };
-        Ok(total_sent)
+    /// Dead sessions are skipped and pruned. Returns the number of messages sent and skipped.
+    pub fn send_to_all_user_sessions(&self, user_id: &str, msg: &super::Message) -> SendCounts {
+        self.user_id_to_senders.send(user_id, &self.record_event(&session_resume::user_key(user_id), msg))
+    }
+
+    /// Number a fanned out message and keep it for resuming sessions, if enabled (see `session_resume`)
+    fn record_event(&self, key: &str, msg: &super::Message) -> super::Message {
+        match self.config.sessions().resume_window {
+            Some(window) => self.resume.record(key, msg, window, std::time::Instant::now()),
+            None => msg.clone(),
+        }
+    }
+
+    /// Send a user message (notification) to all sessions of `msg.user_id`, optionally saving it in DB.
+    /// Like `WsSessionArgs::push_notify_message`, but usable outside of a WebSocket session.
+    pub fn push_user_message(&self, msg: &models::MessageInsert, persist: bool) -> Res<()> {
+        let ws_msg = super::Message::text(serde_json::json!({ "cmd": "message", "data": msg.to_json()? }).to_string());
+        let sent_count = self.send_to_all_user_sessions(&msg.user_id, &ws_msg).sent;
+        if persist {
+            self.db.add_message(&models::MessageInsert { seen: msg.seen || sent_count > 0, ..msg.clone() })?;
+        }
+        Ok(())
+    }
+
+    /// Mark storage full (pausing uploads and pipeline intake, see `storage`), and alert admins if it wasn't already.
+    pub fn report_storage_error(&self, err: crate::storage::StorageError) {
+        if !self.storage.report(err) {
+            return;
+        }
+        for admin in crate::storage::admin_ids(&self.db) {
+            let msg = models::MessageInsert {
+                user_id: admin,
+                event_name: "error".into(),
+                message: crate::storage::ADMIN_FULL_MSG.into(),
+                details: err.to_string(),
+                ..Default::default()
+            };
+            if let Err(e) = self.push_user_message(&msg, true) {
+                tracing::error!(details=%e, "Failed to alert admin about storage.");
+            }
+        }
     }
 
     /// Send a message to all sessions that are collaboratively viewing a video.
-    /// Bails out with error if any of the senders fail.
-    /// Returns the number of messages sent.
-    pub fn send_to_all_collab_users(&self, collab_id: &Option<String>, msg: &super::Message) -> Res<u32> {
-        let mut total_sent = 0u32;
-        if let Some(collab_id) = collab_id {
-            let map = self.collab_id_to_senders.read().map_err(|e| anyhow!("Sender map poisoned: {}", e))?;
-            for sender in map.get(collab_id).unwrap_or(&vec![]).iter() {
-                sender.send(msg.clone())?;
-                total_sent += 1; };
+    /// Dead sessions are skipped and pruned. Returns the number of messages sent and skipped.
+    pub fn send_to_all_collab_users(&self, collab_id: &Option<String>, msg: &super::Message) -> SendCounts {
+        match collab_id {
+            Some(collab_id) => self.collab_id_to_senders.send(collab_id, &self.record_event(&session_resume::collab_key(collab_id), msg)),
+            None => SendCounts::default(),
         }
-        Ok(total_sent)
     }
 
     /// Register a new sender (API connection) as a viewer for a video.
     /// One video can have multiple viewers (including the same user, using different connections).
+    /// If `sees_internal` is set, the viewer also gets internal comments (see `send_to_internal_video_sessions`).
     /// Returns a guard that will remove the sender when dropped.
-    pub fn link_session_to_video(&self, video_hash: &str, sender: WsMsgSender) -> Box<Mutex<dyn Send>> {
-        self.add_sender_to_maplist(video_hash, sender, &self.video_hash_to_senders)
+    pub fn link_session_to_video(&self, video_hash: &str, sender: WsMsgSender, sees_internal: bool) -> Box<Mutex<dyn Send>> {
+        let guard = self.add_sender_to_maplist(video_hash, sender.clone(), &self.video_hash_to_senders);
+        match sees_internal {
+            true => Box::new(Mutex::new((guard, self.add_sender_to_maplist(video_hash, sender, &self.internal_video_hash_to_senders)))),
+            false => guard,
+        }
     }
 
     /// Remove video hash mappings from all collabs that have no more viewers.
     fn garbage_collect_collab_video_map(&self) {
         let mut map = self.collab_id_to_video_hash.write().unwrap();
-        let senders = self.collab_id_to_senders.read().unwrap();
-        map.retain(|collab_id, _| !senders.get(collab_id).unwrap_or(&vec![]).is_empty());
+        map.retain(|collab_id, _| !self.collab_id_to_senders.senders(collab_id).is_empty());
     }
 
     pub fn sender_is_collab_participant(&self, collab_id: &str, sender: &WsMsgSender) -> bool {
-        let senders = self.collab_id_to_senders.read().unwrap();
-        senders.get(collab_id).unwrap_or(&vec![]).iter().any(|s| s.same_channel(sender))
+        self.collab_id_to_senders.senders(collab_id).iter().any(|s| s.same_channel(sender))
     }
 
     pub fn link_session_to_collab(&self, collab_id: &str, video_hash: &str, sender: WsMsgSender) -> Res<Box<Mutex<dyn Send>>> {
@@ -106,31 +228,25 @@ impl ServerState {
     }
 
     /// Send a message to all sessions that are viewing a video.
-    /// Bails out with error if any of the senders fail.
-    /// Returns the number of messages sent.
-    pub fn send_to_all_video_sessions(&self, video_hash: &str, msg: &super::Message) -> Res<u32> {
-        let mut total_sent = 0u32;
-        let map = self.video_hash_to_senders.read().map_err(|e| anyhow!("Sender map poisoned: {}", e))?;
-        for sender in map.get(video_hash).unwrap_or(&vec![]).iter() {
-            sender.send(msg.clone())?;
-            total_sent += 1; };
-        Ok(total_sent)
+    /// Dead sessions are skipped and pruned. Returns the number of messages sent and skipped.
+    pub fn send_to_all_video_sessions(&self, video_hash: &str, msg: &super::Message) -> SendCounts {
+        self.video_hash_to_senders.send(video_hash, &self.record_event(&session_resume::video_key(video_hash), msg))
+    }
+
+    /// Send a message to sessions viewing a video that may see its internal comments.
+    /// Returns the number of messages sent and skipped, like `send_to_all_video_sessions`.
+    pub fn send_to_internal_video_sessions(&self, video_hash: &str, msg: &super::Message) -> SendCounts {
+        self.internal_video_hash_to_senders.send(video_hash, &self.record_event(&session_resume::video_internal_key(video_hash), msg))
     }
 
     // Common implementations for the above add functions.
     fn add_sender_to_maplist(&self, key: &str, sender: WsMsgSender, maplist: &SenderListMap) -> Box<Mutex<dyn Send>> {
-        let mut list = maplist.write().unwrap();
-        let senders = list.entry(key.to_string()).or_insert(Vec::new());
-        senders.push(sender.clone());
+        maplist.add(key, sender.clone());
 
         struct Guard { maplist: SenderListMap, sender: WsMsgSender, key: String }
         impl Drop for Guard {
             fn drop(&mut self) {
-                if let Ok(mut list) = self.maplist.write() {
-                    let senders = list.entry(self.key.to_string()).or_insert(Vec::new());
-                    senders.retain(|s| !self.sender.same_channel(&s));
-                    if senders.len() == 0 { list.remove(&self.key); }
-                } else { tracing::error!("SenderListMap was poisoned! Leaving a dangling API session."); }
+                self.maplist.remove(&self.key, &self.sender);
             }}
         Box::new(Mutex::new(Guard { maplist: maplist.clone(), sender: sender.clone(), key: key.to_string() }))
     }
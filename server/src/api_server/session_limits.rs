@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+
+// Limits on Websocket sessions, so that forgotten browser tabs and dead connections don't pile up
+// server state (entries in the user, video and collab sender maps) forever:
+//
+// - `--max-user-connections`: a user's connections beyond this are refused.
+// - `--ws-idle-timeout`: a session the client hasn't sent anything to (commands or pongs) in this
+//   long is pinged. Browsers answer pings by themselves, so open tabs stay connected, but if
+//   there's still no answer after `PING_GRACE` (or the timeout, if shorter), the connection is
+//   assumed dead (e.g. laptop lid closed, NAT mapping dropped) and the session is closed.
+// - `--ws-resume-window`: how long a disconnected session can be resumed (see `session_resume`).
+
+/// Max time to wait for an answer to a ping
+const PING_GRACE: Duration = Duration::from_secs(30);
+
+/// Messages for clients whose sessions are refused or closed
+pub const TOO_MANY_CONNECTIONS_MSG: &str = "Too many open connections. Close some Clapshot tabs and try again.";
+pub const IDLE_MSG: &str = "Session closed due to inactivity.";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SessionLimits {
+    /// Max concurrent connections per user, None for unlimited
+    pub max_user_connections: Option<usize>,
+    /// How long a session may be silent before it's pinged, None to never ping
+    pub idle_timeout: Option<Duration>,
+    /// How long disconnected sessions can be resumed, None to not keep events for that
+    pub resume_window: Option<Duration>,
+}
+
+impl SessionLimits {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "max_user_connections": self.max_user_connections,
+            "idle_timeout_secs": self.idle_timeout.map(|t| t.as_secs_f64()),
+            "resume_window_secs": self.resume_window.map(|t| t.as_secs_f64()),
+        })
+    }
+}
+
+/// What to do with a session, see `IdleTimer::check`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdleVerdict {
+    Active,
+    /// Silent for the idle timeout, ping client
+    Ping,
+    /// No answer to ping either, close the session
+    Close,
+}
+
+/// Tracks when client was last heard of, in one Websocket session
+#[derive(Debug)]
+pub struct IdleTimer {
+    last_activity: Instant,
+    pinged: bool,
+}
+
+impl IdleTimer {
+    pub fn new(now: Instant) -> IdleTimer {
+        IdleTimer { last_activity: now, pinged: false }
+    }
+
+    /// Got something (command, pong) from client
+    pub fn activity(&mut self, now: Instant) {
+        self.last_activity = now;
+        self.pinged = false;
+    }
+
+    /// Check if the session has been silent for too long. Returns `Ping` only once per silence.
+    pub fn check(&mut self, idle_timeout: Option<Duration>, now: Instant) -> IdleVerdict {
+        let Some(timeout) = idle_timeout else { return IdleVerdict::Active; };
+        let silent = now.saturating_duration_since(self.last_activity);
+        if silent < timeout {
+            IdleVerdict::Active
+        } else if !self.pinged {
+            self.pinged = true;
+            IdleVerdict::Ping
+        } else if silent >= timeout + timeout.min(PING_GRACE) {
+            IdleVerdict::Close
+        } else {
+            IdleVerdict::Active
+        }
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_idle_timer()
+{
+    let t0 = Instant::now();
+    let at = |s: u64| t0 + Duration::from_secs(s);
+    let mut it = IdleTimer::new(t0);
+    assert_eq!(it.check(None, at(100000)), IdleVerdict::Active);
+
+    let timeout = Some(Duration::from_secs(300));
+    assert_eq!(it.check(timeout, at(299)), IdleVerdict::Active);
+    assert_eq!(it.check(timeout, at(300)), IdleVerdict::Ping);
+    assert_eq!(it.check(timeout, at(301)), IdleVerdict::Active);
+
+    // Answered
+    it.activity(at(310));
+    assert_eq!(it.check(timeout, at(600)), IdleVerdict::Active);
+    assert_eq!(it.check(timeout, at(610)), IdleVerdict::Ping);
+
+    // Didn't answer within grace time
+    assert_eq!(it.check(timeout, at(639)), IdleVerdict::Active);
+    assert_eq!(it.check(timeout, at(640)), IdleVerdict::Close);
+
+    // Grace time is at most the timeout
+    let mut it = IdleTimer::new(t0);
+    let timeout = Some(Duration::from_secs(10));
+    assert_eq!(it.check(timeout, at(10)), IdleVerdict::Ping);
+    assert_eq!(it.check(timeout, at(20)), IdleVerdict::Close);
+}
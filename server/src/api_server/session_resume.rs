@@ -0,0 +1,215 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::{Duration, Instant};
+
+use super::Message;
+
+// Clients that lose their connection (flaky Wi-Fi, laptop sleep, proxy restart) can resume their
+// session instead of reloading everything:
+//
+// - Each session gets a resume token in `welcome`.
+// - Events fanned out to a user's sessions, a video's viewers or a collab are numbered (`seq`
+//   next to `cmd`, increasing over all events) and kept for `--ws-resume-window` seconds, at most
+//   `MAX_EVENTS_PER_KEY` per user / video / collab. `welcome` and `open_video` have the current
+//   `seq` too, so clients know where they are even if they haven't got any events yet.
+// - A client that reconnects within the window sends `resume` with its old token and the highest
+//   `seq` it has seen. The new session rejoins the old one's video and collab, and gets the events
+//   it missed, followed by `resumed`. If the token is unknown or expired, or some of the missed
+//   events are no longer kept, it gets `resume_failed` instead, and should reload as on a fresh
+//   connection.
+//
+// Replayed events can overlap with ones that arrive live right after resuming, so clients skip
+// events whose `seq` they've already seen. Sessions closed by the server (e.g. revoked share link)
+// can't be resumed, and neither can sessions of another server instance.
+
+/// Max number of events kept per user, video or collab
+const MAX_EVENTS_PER_KEY: usize = 256;
+
+/// Expired events are swept from all keys every this many events
+const SWEEP_EVERY: u64 = 256;
+
+/// Event buffer keys
+pub fn user_key(user_id: &str) -> String { format!("user:{user_id}") }
+pub fn video_key(video_hash: &str) -> String { format!("video:{video_hash}") }
+/// Internal comments, for viewers that may see them
+pub fn video_internal_key(video_hash: &str) -> String { format!("video_internal:{video_hash}") }
+pub fn collab_key(collab_id: &str) -> String { format!("collab:{collab_id}") }
+
+/// Events of one key, oldest first
+#[derive(Default)]
+struct Ring {
+    events: VecDeque<(u64, Instant, Message)>,
+    /// Highest `seq` evicted to make room (not by age)
+    dropped_upto: u64,
+}
+
+/// What a disconnected session was doing, for resuming it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumableSession {
+    pub user_id: String,
+    pub video_hash: Option<String>,
+    pub collab_id: Option<String>,
+    /// None while still connected
+    disconnected: Option<Instant>,
+}
+
+pub struct ResumeStore {
+    last_seq: AtomicU64,
+    events: Mutex<HashMap<String, Ring>>,
+    sessions: Mutex<HashMap<String, ResumableSession>>,
+}
+
+impl Default for ResumeStore {
+    fn default() -> Self { ResumeStore::new() }
+}
+
+/// Message with `seq` added, or None if it's not a JSON object
+fn with_seq(msg: &Message, seq: u64) -> Option<Message> {
+    let rest = msg.to_str().ok()?.strip_prefix('{')?;
+    if rest.trim_start().starts_with('}') { return None; }
+    Some(Message::text(format!("{{\"seq\":{seq},{rest}")))
+}
+
+impl ResumeStore {
+    pub fn new() -> ResumeStore {
+        ResumeStore { last_seq: AtomicU64::new(0), events: Mutex::new(HashMap::new()), sessions: Mutex::new(HashMap::new()) }
+    }
+
+    /// `seq` of the latest event
+    pub fn current_seq(&self) -> u64 {
+        self.last_seq.load(Relaxed)
+    }
+
+    /// Number an event and keep it for replay.
+    ///
+    /// # Arguments
+    /// * `key` - Who it's for, e.g. `video_key(vh)`
+    /// * `window` - How long events are kept
+    ///
+    /// # Returns
+    /// Message to send, with `seq`. Messages that aren't JSON objects (e.g. close) are returned as is, and not kept.
+    pub fn record(&self, key: &str, msg: &Message, window: Duration, now: Instant) -> Message {
+        if !msg.is_text() { return msg.clone(); }
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        // Numbered under the lock, so each key's events are in order
+        let seq = self.last_seq.load(Relaxed) + 1;
+        let Some(numbered) = with_seq(msg, seq) else { return msg.clone(); };
+        self.last_seq.store(seq, Relaxed);
+
+        let ring = events.entry(key.to_string()).or_default();
+        while ring.events.front().is_some_and(|(_, t, _)| now.saturating_duration_since(*t) > window) {
+            ring.events.pop_front();
+        }
+        if ring.events.len() >= MAX_EVENTS_PER_KEY {
+            if let Some((s, _, _)) = ring.events.pop_front() { ring.dropped_upto = s; }
+        }
+        ring.events.push_back((seq, now, numbered.clone()));
+
+        // Events older than the window can't be missed by a session that's still resumable: it was
+        // connected when they were sent. So expired ones can go, with their `dropped_upto`.
+        if seq.is_multiple_of(SWEEP_EVERY) {
+            events.retain(|_, r| {
+                r.events.retain(|(_, t, _)| now.saturating_duration_since(*t) <= window);
+                !r.events.is_empty()
+            });
+        }
+        numbered
+    }
+
+    /// Events after `last_seq` for any of the keys, in order.
+    ///
+    /// # Returns
+    /// * `None` - Some of them are no longer kept
+    pub fn events_since(&self, keys: &[String], last_seq: u64) -> Option<Vec<Message>> {
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        let mut res = Vec::new();
+        for ring in keys.iter().filter_map(|k| events.get(k)) {
+            if ring.dropped_upto > last_seq { return None; }
+            res.extend(ring.events.iter().filter(|(s, _, _)| *s > last_seq).map(|(s, _, m)| (*s, m.clone())));
+        }
+        res.sort_by_key(|(s, _)| *s);
+        Some(res.into_iter().map(|(_, m)| m).collect())
+    }
+
+    /// Start tracking a new session.
+    ///
+    /// # Returns
+    /// Resume token for the client
+    pub fn new_session(&self, user_id: &str, window: Duration, now: Instant) -> String {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.retain(|_, s| s.disconnected.is_none_or(|t| now.saturating_duration_since(t) <= window));
+        sessions.insert(token.clone(), ResumableSession { user_id: user_id.into(), video_hash: None, collab_id: None, disconnected: None });
+        token
+    }
+
+    /// Session was disconnected. It can be resumed until the window passes, unless `resumable` is false.
+    pub fn session_ended(&self, token: &str, video_hash: Option<String>, collab_id: Option<String>, resumable: bool, now: Instant) {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        match (resumable, sessions.get_mut(token)) {
+            (true, Some(s)) => { *s = ResumableSession { video_hash, collab_id, disconnected: Some(now), ..s.clone() }; },
+            _ => { sessions.remove(token); },
+        }
+    }
+
+    /// Take a disconnected session over, for resuming it. A token can only be used once.
+    ///
+    /// # Returns
+    /// * `None` - No such session, not disconnected, expired, or another user's
+    pub fn take_session(&self, token: &str, user_id: &str, window: Duration, now: Instant) -> Option<ResumableSession> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        match sessions.get(token) {
+            Some(s) if s.user_id == user_id && s.disconnected.is_some_and(|t| now.saturating_duration_since(t) <= window) => sessions.remove(token),
+            _ => None,
+        }
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_resume_store()
+{
+    let store = ResumeStore::new();
+    let window = Duration::from_secs(60);
+    let t0 = Instant::now();
+    let ev = |cmd: &str| Message::text(serde_json::json!({ "cmd": cmd, "data": {} }).to_string());
+    let seq_of = |m: &Message| serde_json::from_str::<serde_json::Value>(m.to_str().unwrap()).unwrap()["seq"].as_u64().unwrap();
+
+    let m = store.record(&video_key("v1"), &ev("new_comment"), window, t0);
+    assert_eq!((seq_of(&m), store.current_seq()), (1, 1));
+    assert_eq!(serde_json::from_str::<serde_json::Value>(m.to_str().unwrap()).unwrap()["cmd"], "new_comment");
+    store.record(&user_key("u1"), &ev("message"), window, t0);
+    store.record(&video_key("v2"), &ev("new_comment"), window, t0);
+    store.record(&video_key("v1"), &ev("del_comment"), window, t0);
+    assert!(store.record(&video_key("v1"), &Message::close(), window, t0).is_close());
+    assert_eq!(store.current_seq(), 4);
+
+    // Only asked keys, in order, after last seen
+    let keys = [video_key("v1"), user_key("u1"), collab_key("c1")];
+    assert_eq!(store.events_since(&keys, 0).unwrap().iter().map(seq_of).collect::<Vec<_>>(), vec![1, 2, 4]);
+    assert_eq!(store.events_since(&keys, 2).unwrap().iter().map(seq_of).collect::<Vec<_>>(), vec![4]);
+
+    // Evicted for room -> can't replay from before that
+    for _ in 0..MAX_EVENTS_PER_KEY {
+        store.record(&video_key("v1"), &ev("new_comment"), window, t0);
+    }
+    assert!(store.events_since(&keys, 3).is_none());
+    assert_eq!(store.events_since(&keys, store.current_seq() - 1).unwrap().len(), 1);
+
+    // Sessions: only disconnected ones, by same user, within window, once
+    let tok = store.new_session("u1", window, t0);
+    assert!(store.take_session(&tok, "u1", window, t0).is_none(), "Still connected");
+    store.session_ended(&tok, Some("v1".into()), None, true, t0);
+    assert!(store.take_session(&tok, "u2", window, t0).is_none());
+    assert!(store.take_session(&tok, "u1", window, t0 + Duration::from_secs(61)).is_none());
+    let s = store.take_session(&tok, "u1", window, t0 + Duration::from_secs(30)).unwrap();
+    assert_eq!((s.user_id.as_str(), s.video_hash.as_deref()), ("u1", Some("v1")));
+    assert!(store.take_session(&tok, "u1", window, t0).is_none());
+
+    let tok = store.new_session("u1", window, t0);
+    store.session_ended(&tok, None, None, false, t0);
+    assert!(store.take_session(&tok, "u1", window, t0).is_none(), "Closed by server");
+}
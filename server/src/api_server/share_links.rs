@@ -0,0 +1,188 @@
+use sha2::{Digest, Sha256};
+use warp::http::StatusCode;
+
+use crate::database::models;
+use crate::database::error::{DBError, DBResult};
+use super::file_server::Denied;
+use super::server_state::ServerState;
+
+// Public share links let guests without an account view one video (and optionally comment on it).
+//
+// Guests open a WebSocket session with `/api/ws?share=<token>[&password=...][&name=...][&guest_key=...]`,
+// which the reverse proxy must let through without authentication. Each guest gets an identity
+// (see `guest_identity`) that their comments are written under. File URLs they get have `share`
+// (and for password protected links, `key`) query parameters, checked by `check_asset_query`
+// when the server hosts the videos dir.
+
+/// Commands guest sessions may use. Others are refused in `msg_dispatch`.
+pub const GUEST_COMMANDS: &[&str] = &["open_video", "list_comments", "add_comment", "logout"];
+
+const PBKDF2_ROUNDS: u32 = 10_000;
+
+/// New random link token
+pub fn new_token() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// PBKDF2-HMAC-SHA256, 32 bytes of output (0 rounds is taken as 1)
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], rounds: u32) -> [u8; 32]
+{
+    let rounds = std::num::NonZeroU32::new(rounds).unwrap_or(std::num::NonZeroU32::MIN);
+    let mut res = [0u8; 32];
+    ring::pbkdf2::derive(ring::pbkdf2::PBKDF2_HMAC_SHA256, rounds, salt, password, &mut res);
+    res
+}
+
+/// Hash a link password for storing in DB, as `pbkdf2-sha256$<rounds>$<salt>$<hash>`
+pub fn hash_password(password: &str) -> String
+{
+    let salt = new_token();
+    format!("pbkdf2-sha256${}${}${}", PBKDF2_ROUNDS, salt, hex::encode(pbkdf2_sha256(password.as_bytes(), salt.as_bytes(), PBKDF2_ROUNDS)))
+}
+
+/// Check a password against a hash from `hash_password`
+pub fn verify_password(password: &str, hash: &str) -> bool
+{
+    let parts = hash.split('$').collect::<Vec<_>>();
+    let [ "pbkdf2-sha256", rounds, salt, expected ] = parts[..] else { return false; };
+    let Ok(rounds) = rounds.parse::<u32>() else { return false; };
+    let got = hex::encode(pbkdf2_sha256(password.as_bytes(), salt.as_bytes(), rounds));
+    ring::constant_time::verify_slices_are_equal(got.as_bytes(), expected.as_bytes()).is_ok()
+}
+
+/// Max length of a guest's display name
+const MAX_GUEST_NAME_LEN: usize = 64;
+
+/// Identity for a guest session on a link. Resumes an earlier one if `key` (given to the guest in
+/// welcome message) matches a guest of the same link that hasn't been promoted to a user yet,
+/// otherwise makes a new one.
+///
+/// # Arguments
+/// * `key` - Guest key from an earlier session
+/// * `name` - Display name the guest entered, if any. Renames a resumed identity.
+pub fn guest_identity(server: &ServerState, link: &models::ShareLink, key: Option<&str>, name: Option<&str>) -> DBResult<models::Guest>
+{
+    let name = name.map(|n| n.trim().chars().take(MAX_GUEST_NAME_LEN).collect::<String>()).filter(|n| !n.is_empty());
+    if let Some(key) = key {
+        match server.db.get_guest_by_key(key) {
+            Ok(g) if g.share_link_id == link.id && g.promoted_to.is_none() => {
+                return match name {
+                    Some(n) if n != g.name => {
+                        server.db.set_guest_name(g.id, &n)?;
+                        Ok(models::Guest { name: n, ..g })
+                    },
+                    _ => Ok(g),
+                };
+            },
+            Ok(_) | Err(DBError::NotFound()) => {},
+            Err(e) => return Err(e),
+        }
+    }
+    server.db.add_guest(&models::GuestInsert {
+        share_link_id: link.id,
+        name: name.unwrap_or_else(|| "Guest".into()),
+        key: new_token(),
+    })
+}
+
+/// Disconnect all guests on a link
+pub fn close_guest_sessions(server: &ServerState, link: &models::ShareLink) -> anyhow::Result<()>
+{
+    for g in server.db.get_guests(Some(link.id))? {
+        server.send_to_all_user_sessions(&g.user_id(), &warp::ws::Message::close());
+    }
+    Ok(())
+}
+
+/// Key that proves a password was given, for file requests of password protected links.
+/// Derived from the (secret) password hash, so only the server can make it.
+fn access_key(link: &models::ShareLink) -> Option<String> {
+    link.password_hash.as_ref().map(|h| hex::encode(Sha256::digest(format!("{}:{}", link.token, h)))[..32].to_string())
+}
+
+/// Query parameters to add to file URLs given to guests
+pub fn asset_query(link: &models::ShareLink) -> String {
+    match access_key(link) {
+        Some(key) => format!("share={}&key={}", link.token, key),
+        None => format!("share={}", link.token),
+    }
+}
+
+/// Look up a link by token and check that it hasn't expired
+pub fn get_valid_link(server: &ServerState, token: &str) -> Result<models::ShareLink, Denied>
+{
+    let link = match server.db.get_share_link_by_token(token) {
+        Ok(l) => l,
+        Err(DBError::NotFound()) => return Err(Denied(StatusCode::NOT_FOUND, "No such share link")),
+        Err(e) => {
+            tracing::error!(details=%e, "DB error while checking share link.");
+            return Err(Denied(StatusCode::INTERNAL_SERVER_ERROR, "Internal error"));
+        }
+    };
+    if link.expires.is_some_and(|t| t <= chrono::Utc::now().naive_utc()) {
+        return Err(Denied(StatusCode::FORBIDDEN, "Share link expired"));
+    }
+    Ok(link)
+}
+
+/// Check token and password of a guest session
+pub fn open_link(server: &ServerState, token: &str, password: Option<&str>) -> Result<models::ShareLink, Denied>
+{
+    let link = get_valid_link(server, token)?;
+    if let Some(hash) = &link.password_hash {
+        match password {
+            None => return Err(Denied(StatusCode::UNAUTHORIZED, "Password required")),
+            Some(pw) if !verify_password(pw, hash) => return Err(Denied(StatusCode::FORBIDDEN, "Wrong password")),
+            Some(_) => {},
+        }
+    }
+    Ok(link)
+}
+
+/// Access check for a file of video `vh` requested with a share link (`share` query parameter).
+///
+/// # Returns
+/// * `None` - Not a share link request
+/// * `Some(Ok(()))` - Link is valid for the video
+/// * `Some(Err(Denied))` - Link is revoked, expired, for another video or the key is wrong
+pub fn check_asset_query(server: &ServerState, vh: &str, query: &str) -> Option<Result<(), Denied>>
+{
+    let param = |name: &str| query.split('&').filter_map(|p| p.split_once('=')).find(|(k, _)| *k == name).map(|(_, v)| v);
+    let token = param("share")?;
+    Some(get_valid_link(server, token).and_then(|link| {
+        if link.video_hash != vh {
+            Err(Denied(StatusCode::FORBIDDEN, "Share link is for another video"))
+        } else if access_key(&link).is_some_and(|k| param("key") != Some(k.as_str())) {
+            Err(Denied(StatusCode::FORBIDDEN, "Invalid share link key"))
+        } else {
+            Ok(())
+        }
+    }))
+}
+
+/// Link info for its owner, with the URL to give out
+pub fn link_json(server: &ServerState, link: &models::ShareLink) -> anyhow::Result<serde_json::Value>
+{
+    let mut fields = link.to_json()?;
+    fields["has_password"] = serde_json::json!(link.password_hash.is_some());
+    fields["url"] = serde_json::json!(format!("{}/?share={}", server.url_base, link.token));
+    Ok(fields)
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_link_passwords()
+{
+    // RFC 7914 section 11 test vector
+    assert_eq!(hex::encode(pbkdf2_sha256(b"passwd", b"salt", 1)), "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+
+    let h = hash_password("secret");
+    assert!(h.starts_with("pbkdf2-sha256$"));
+    assert_ne!(h, hash_password("secret"), "Salt should differ");
+    assert!(verify_password("secret", &h));
+    assert!(!verify_password("Secret", &h));
+    assert!(!verify_password("secret", "plain"));
+    assert!(!verify_password("secret", &h.replace("pbkdf2", "md5")));
+}
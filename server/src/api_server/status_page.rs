@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use warp::{Filter, Reply};
+use warp::http::StatusCode;
+
+use crate::doctor::{free_disk_bytes, MIN_FREE_DISK_BYTES};
+use super::server_state::ServerState;
+
+/// How long a computed status is reused. Also sent as `max-age`, so proxies can cache it too.
+const CACHE_SECS: u64 = 15;
+
+/// Max status requests per client in a `RATE_WINDOW`
+const RATE_LIMIT: u32 = 60;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Video processing is considered stuck if the oldest unfinished job is older than this
+const MAX_JOB_AGE_MINS: i64 = 30;
+
+/// How far ahead scheduled maintenance is announced
+const ANNOUNCE_DAYS: i64 = 14;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PublicMaintenance {
+    pub starts: i64,
+    pub ends: i64,
+    pub message: String,
+}
+
+/// Service status for the public status page. Only tells what users can expect, not why.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PublicStatus {
+    /// "up", "degraded" or "maintenance"
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Current and upcoming maintenance windows
+    pub maintenance: Vec<PublicMaintenance>,
+}
+
+/// Work out current service status.
+///
+/// Maintenance if a maintenance window is active, degraded if the server is shutting down,
+/// video processing seems stuck, disk is almost full or the database fails, and up otherwise.
+pub fn compute_status(server: &ServerState, now: chrono::NaiveDateTime) -> PublicStatus
+{
+    let windows = match server.db.get_maintenance_windows(now) {
+        Ok(w) => w,
+        Err(e) => {
+            tracing::error!(details=%e, "Status page: failed to get maintenance windows.");
+            return PublicStatus { status: "degraded", message: Some("Service is experiencing problems.".into()), maintenance: vec![] };
+        }
+    };
+    let maintenance = windows.iter()
+        .filter(|w| w.starts < now + chrono::Duration::days(ANNOUNCE_DAYS))
+        .map(|w| PublicMaintenance { starts: w.starts.timestamp(), ends: w.ends.timestamp(), message: w.message.clone() })
+        .collect::<Vec<_>>();
+
+    if let Some(w) = windows.iter().find(|w| w.starts <= now) {
+        let message = if w.message.is_empty() { "Scheduled maintenance in progress.".into() } else { w.message.clone() };
+        return PublicStatus { status: "maintenance", message: Some(message), maintenance };
+    }
+
+    let degraded = |msg: &str| PublicStatus { status: "degraded", message: Some(msg.into()), maintenance: maintenance.clone() };
+    if server.terminate_flag.load(Relaxed) {
+        return degraded("Server is restarting.");
+    }
+    match server.db.get_unfinished_jobs() {
+        Err(e) => {
+            tracing::error!(details=%e, "Status page: failed to get unfinished jobs.");
+            return degraded("Service is experiencing problems.");
+        },
+        Ok(jobs) => if jobs.first().is_some_and(|j| now - j.created > chrono::Duration::minutes(MAX_JOB_AGE_MINS)) {
+            return degraded("Video processing is delayed.");
+        }
+    }
+    if free_disk_bytes(&server.videos_dir).is_ok_and(|free| free < MIN_FREE_DISK_BYTES) {
+        return degraded("Uploads may fail or be delayed.");
+    }
+    PublicStatus { status: "up", message: None, maintenance }
+}
+
+/// Fixed window request counter per client
+struct RateLimiter {
+    window_start: Instant,
+    counts: HashMap<String, u32>,
+}
+
+impl RateLimiter {
+    fn new(now: Instant) -> RateLimiter {
+        RateLimiter { window_start: now, counts: HashMap::new() }
+    }
+
+    /// Count a request from client, and return false if it went over the limit
+    fn allow(&mut self, client: &str, now: Instant) -> bool {
+        if now.duration_since(self.window_start) >= RATE_WINDOW {
+            self.counts.clear();
+            self.window_start = now;
+        }
+        let count = self.counts.entry(client.to_string()).or_insert(0);
+        *count += 1;
+        *count <= RATE_LIMIT
+    }
+}
+
+/// Client address for rate limiting. First `X-Forwarded-For` entry if behind a reverse proxy.
+fn client_key(forwarded_for: Option<&str>, remote: Option<SocketAddr>) -> String {
+    forwarded_for.and_then(|f| f.split(',').next()).map(|f| f.trim().to_string()).filter(|f| !f.is_empty())
+        .or(remote.map(|a| a.ip().to_string()))
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// Warp filter for `GET /api/status`, a public status page API (no login needed).
+///
+/// Status is computed at most once per `CACHE_SECS` no matter how many clients poll it,
+/// and each client address is limited to `RATE_LIMIT` requests per minute.
+pub fn status_filter(server: ServerState) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone
+{
+    let cache: Arc<Mutex<Option<(Instant, PublicStatus)>>> = Arc::new(Mutex::new(None));
+    let limiter = Arc::new(Mutex::new(RateLimiter::new(Instant::now())));
+
+    warp::path!("api" / "status")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::addr::remote())
+        .map(move |forwarded_for: Option<String>, remote: Option<SocketAddr>| {
+            let now = Instant::now();
+            if !limiter.lock().unwrap().allow(&client_key(forwarded_for.as_deref(), remote), now) {
+                let reply = warp::reply::with_status("Too many requests", StatusCode::TOO_MANY_REQUESTS);
+                return warp::reply::with_header(reply, "retry-after", RATE_WINDOW.as_secs().to_string()).into_response();
+            }
+            let status = {
+                let mut cache = cache.lock().unwrap();
+                match &*cache {
+                    Some((at, st)) if now.duration_since(*at) < Duration::from_secs(CACHE_SECS) => st.clone(),
+                    _ => {
+                        let st = compute_status(&server, chrono::Utc::now().naive_utc());
+                        *cache = Some((now, st.clone()));
+                        st
+                    }
+                }
+            };
+            warp::reply::with_header(warp::reply::json(&status), "cache-control", format!("public, max-age={CACHE_SECS}")).into_response()
+        })
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_status_rate_limiter()
+{
+    let t0 = Instant::now();
+    let mut rl = RateLimiter::new(t0);
+    for _ in 0..RATE_LIMIT {
+        assert!(rl.allow("1.2.3.4", t0));
+    }
+    assert!(!rl.allow("1.2.3.4", t0));
+    assert!(rl.allow("5.6.7.8", t0));
+    assert!(rl.allow("1.2.3.4", t0 + RATE_WINDOW));
+}
+
+#[test]
+fn test_status_client_key()
+{
+    let addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+    assert_eq!(client_key(Some("1.2.3.4, 10.0.0.2"), Some(addr)), "1.2.3.4");
+    assert_eq!(client_key(Some(""), Some(addr)), "10.0.0.1");
+    assert_eq!(client_key(None, None), "unknown");
+}
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+
+use crate::database::models;
+use crate::video_pipeline::{IncomingFile, calc_video_hash};
+use crate::video_pipeline::stitcher::{self, StitchSource};
+use super::server_state::ServerState;
+
+/// Make a safe output filename from user given title
+fn output_filename(title: &str) -> String
+{
+    let name = title.trim().chars()
+        .map(|c| if c.is_alphanumeric() || " -_.()".contains(c) { c } else { '_' })
+        .collect::<String>();
+    let name = name.trim_start_matches('.').trim();
+    let name = if name.is_empty() { "stitched" } else { name };
+    if name.to_lowercase().ends_with(".mp4") { name.to_string() } else { format!("{name}.mp4") }
+}
+
+/// Send an error message to user about a failed background job
+pub(super) fn notify_error(server: &ServerState, user_id: &str, msg: &str, details: String)
+{
+    if let Err(e) = server.push_user_message(&models::MessageInsert {
+            event_name: "error".into(),
+            user_id: user_id.into(),
+            message: msg.into(),
+            details,
+            ..Default::default() }, true) {
+        tracing::error!(details=%e, "Failed to send error message to user.");
+    }
+}
+
+/// Record provenance of a video made from other videos, and submit it to the processing pipeline
+/// like a regular upload.
+///
+/// # Arguments
+/// * `server` - Server state
+/// * `user_id` - User who gets the new video
+/// * `file` - New video file, in upload dir
+/// * `source_hashes` - Videos the new one was made from
+/// * `operation` - Human readable description of what was done
+pub(super) fn submit_derived_video(server: &ServerState, user_id: &str, file: PathBuf, source_hashes: &[String], operation: &str) -> Result<(), String>
+{
+    let vh = calc_video_hash(&file, user_id).map_err(|e| e.to_string())?;
+    tracing::info!(file=%file.display(), video_hash=%vh, "Submitting derived video.");
+    if let Err(e) = server.db.add_video_sources(&vh, source_hashes, operation) {
+        tracing::error!(details=%e, "Failed to record sources of derived video.");
+    }
+    server.upload_tx.send(IncomingFile { file_path: file, user_id: user_id.into(), ..Default::default() })
+        .map_err(|e| {
+            tracing::error!(details=%e, "Failed to submit derived video for processing.");
+            "Internal error: couldn't submit video for processing".to_string()
+        })
+}
+
+/// Stitch videos in a background thread, and submit the result to the processing pipeline
+/// like a regular upload. Source videos are recorded as provenance of the new video.
+///
+/// # Arguments
+/// * `server` - Server state
+/// * `user_id` - User who gets the new video
+/// * `sources` - Videos to stitch, in order
+/// * `title` - Title (and filename) for the new video
+pub fn spawn_stitch(server: ServerState, user_id: String, sources: Vec<StitchSource>, title: String)
+{
+    std::thread::spawn(move || {
+        let _span = tracing::info_span!("stitch", user=%user_id, n_sources=sources.len()).entered();
+        let new_dir = server.upload_dir.join(uuid::Uuid::new_v4().to_string());
+        let dst = new_dir.join(output_filename(&title));
+        let source_hashes = sources.iter().map(|s| s.video_hash.clone()).collect::<Vec<_>>();
+
+        let res = std::fs::create_dir_all(&new_dir).map_err(|e| e.to_string())
+            .and_then(|_| stitcher::stitch(&sources, &dst, &server.sandbox))
+            .and_then(|_| submit_derived_video(&server, &user_id, dst, &source_hashes, &format!("Stitched from {} videos", sources.len())));
+
+        if let Err(e) = res {
+            tracing::info!(details=%e, "Stitching failed.");
+            if let Err(e) = std::fs::remove_dir_all(&new_dir) {
+                tracing::warn!(details=%e, "Failed to remove stitch output dir.");
+            }
+            notify_error(&server, &user_id, "Stitching failed", e);
+        }
+    });
+}
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_stitch_output_filename()
+{
+    assert_eq!(output_filename("Reel v2"), "Reel v2.mp4");
+    assert_eq!(output_filename("final.MP4"), "final.MP4");
+    assert_eq!(output_filename(""), "stitched.mp4");
+    assert_eq!(output_filename("../../etc/passwd"), "_.._etc_passwd.mp4");
+    for t in ["../x", "a/b", "..", "/", "\\..\\x"] {
+        let name = output_filename(t);
+        assert_eq!(std::path::Path::new(&name).file_name().unwrap().to_string_lossy(), name, "{t}");
+    }
+}
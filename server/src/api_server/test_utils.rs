@@ -17,9 +17,13 @@ pub(crate) struct ApiTestState {
     pub(crate) db: Arc<DB>,
     pub(crate) user_msg_tx: crossbeam_channel::Sender<UserMessage>,
     pub(crate) upload_res_rx: crossbeam_channel::Receiver<IncomingFile>,
+    pub(crate) export_rx: crossbeam_channel::Receiver<crate::video_pipeline::ExportRequest>,
     pub(crate) videos_dir: PathBuf,
     pub(crate) upload_dir: PathBuf,
     pub(crate) terminate_flag: Arc<AtomicBool>,
+    pub(crate) config: Arc<crate::config::LiveConfig>,
+    pub(crate) opts: crate::config::StartupOptions,
+    pub(crate) storage: Arc<crate::storage::StorageStatus>,
     pub(crate) videos: Vec<models::Video>,
     pub(crate) comments: Vec<models::Comment>,
     pub(crate) url_base: String,
@@ -35,7 +39,7 @@ pub(crate) async fn read(ws: &mut WsClient) -> Option<String> {
             Ok(Some(m)) => Some(m.expect("Failed to read server message")).map(|m| m.to_string()),
             _ => None,
     };
-    let res_str = res.as_ref().map(|s| s.as_str()).unwrap_or("<none>");
+    let res_str = res.as_deref().unwrap_or("<none>");
     println!("<--- [Client got]: {res_str}");
     res
 }
@@ -68,11 +72,19 @@ pub(crate) async fn write(ws: &mut WsClient, msg: &str) {
 }
 
 pub(crate) async fn connect_client_ws(ws_url: &str, user_id: &str) -> WsClient {
+    let mut ws = connect_client_ws_raw(ws_url, user_id).await;
+    tracing::info!("TEST: Client connected. Waiting for 'welcome'...");
+    assert!( expect_msg(&mut ws).await.to_lowercase().contains("welcome"));
+    ws
+}
+
+/// Connect without waiting for 'welcome', for sessions the server may refuse
+pub(crate) async fn connect_client_ws_raw(ws_url: &str, user_id: &str) -> WsClient {
     use tokio_tungstenite::tungstenite::http;
     use tokio_tungstenite::connect_async;
     
     let request = http::Request::builder()
-    .uri(ws_url.clone())
+    .uri(ws_url)
     .header("Host", "127.0.0.1")
     .header("HTTP_X_REMOTE_USER_ID", user_id)
     .header("HTTP_X_REMOTE_USER_NAME", "User Num1")
@@ -82,38 +94,48 @@ pub(crate) async fn connect_client_ws(ws_url: &str, user_id: &str) -> WsClient {
     .header("Sec-WebSocket-Key", "1234567890")    
     .body(()).unwrap();
 
-    let (mut ws, _) = connect_async(request).await.unwrap();
-
-    tracing::info!("TEST: Client connected. Waiting for 'welcome'...");
-    assert!( expect_msg(&mut ws).await.to_lowercase().contains("welcome"));
-
+    let (ws, _) = connect_async(request).await.unwrap();
     ws
 }
 
 macro_rules! api_test {
     ([$ws:ident, $state:ident] $($body:tt)*) => {
+        api_test!{[$ws, $state, None] $($body)*}
+    };
+    ([$ws:ident, $state:ident, $signer:expr] $($body:tt)*) => {
+        api_test!{[$ws, $state, $signer, None] $($body)*}
+    };
+    ([$ws:ident, $state:ident, $signer:expr, $onboarding:expr] $($body:tt)*) => {
         {
             let (db, data_dir, videos, comments) = make_test_db();
 
             let port = portpicker::pick_unused_port().expect("No TCP ports free");
             let (user_msg_tx, user_msg_rx) = crossbeam_channel::unbounded();
             let (upload_res_tx, upload_res_rx) = crossbeam_channel::unbounded();
+            let (export_tx, export_rx) = crossbeam_channel::unbounded();
             let terminate_flag = Arc::new(AtomicBool::new(false));
+            let config = crate::config::LiveConfig::fixed(Default::default());
+            let storage = Arc::new(crate::storage::StorageStatus::new(&data_dir));
             let url_base = format!("http://127.0.0.1:{port}");
             let ws_url = url_base.replace("http", "ws") + "/api/ws";
             let videos_dir = data_dir.join("videos");
             let upload_dir = data_dir.join("upload");
     
-            let server_state = ServerState::new( db.clone(),
-                &videos_dir.clone(),
-                &upload_dir.clone(),
-                &url_base.clone(),
-                terminate_flag.clone());
+            let opts = crate::config::StartupOptions {
+                data_dir: data_dir.to_path_buf(),
+                url_base: url_base.clone(),
+                url_signer: $signer,
+                onboarding: $onboarding,
+                ..Default::default()
+            };
+            let link = crate::video_pipeline::PipelineLink { upload_tx: upload_res_tx, export_tx, queues: Default::default(), storage: storage.clone() };
+            let server_state = ServerState::new(db.clone(), &opts, link, config.clone(), terminate_flag.clone());
     
-            let $state = ApiTestState { db, user_msg_tx, upload_res_rx, videos_dir, upload_dir, terminate_flag, videos, comments, url_base, port, ws_url };
-            let api = async move { run_api_server_async(server_state, user_msg_rx, upload_res_tx, port).await; Ok(()) };
+            let $state = ApiTestState { db, user_msg_tx, upload_res_rx, export_rx, videos_dir, upload_dir, terminate_flag, config, opts, storage, videos, comments, url_base, port, ws_url };
+            let api = async move { run_api_server_async(server_state, user_msg_rx, port, true).await; Ok(()) };
             
             let tst = tokio::spawn(async move {
+                let $state = $state;  // Move all of it, so `user_msg_tx` is dropped when test ends
                 tracing::info!("TEST: Client connecting to {}", $state.ws_url);
                 #[allow(unused_mut)]
                 let mut $ws = connect_client_ws(&$state.ws_url, "user.num1").await;
@@ -142,7 +164,7 @@ pub(crate) async fn open_video(ws: &mut WsClient, vh: &str) -> (String, serde_js
     let (cmd, data) = expect_cmd_data(ws).await;
     assert_eq!(cmd, "open_video");
     while let Some((cmt_cmd, cmt_data)) = read_cmd_data(ws).await {
-        assert_eq!(cmt_cmd, "new_comment");
+        assert!(cmt_cmd == "new_comment" || cmt_cmd == "new_note");
         assert_eq!(cmt_data["video_hash"], vh);
     }
     (cmd.to_string(), data)
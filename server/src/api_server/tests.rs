@@ -11,9 +11,10 @@ use crate::database::error::DBError;
 use crate::api_server::{UserMessage, UserMessageTopic, run_api_server_async};
 use crate::api_server::server_state::ServerState;
 use crate::database::models;
+use crate::video_pipeline::ExportRequest;
 use crate::database::tests::make_test_db;
 
-use crate::api_server::test_utils::{ApiTestState, expect_msg, expect_cmd_data, expect_no_msg, write, open_video, connect_client_ws};
+use crate::api_server::test_utils::{ApiTestState, expect_msg, expect_cmd_data, expect_no_msg, read, read_cmd_data, write, open_video, connect_client_ws, connect_client_ws_raw};
 
 // ---------------------------------------------------------------------------------------------
 
@@ -82,14 +83,15 @@ async fn test_api_del_video()
 
         // Delete one successfully
         {
-            assert!(ts.db.get_video(&ts.videos[0].video_hash).is_ok());
+            assert!(ts.db.get_video(&ts.videos.last().unwrap().video_hash).is_ok());
             write(&mut ws, &format!(r#"{{"cmd":"del_video","data":{{"video_hash":"{}"}}}}"#, ts.videos[0].video_hash)).await;
             let (_cmd, data) = expect_cmd_data(&mut ws).await;
             assert_eq!(data["event_name"], "ok");
             assert!(!data["details"].as_str().unwrap().contains("WARNING"));
 
-            // Make sure the dir is gone
-            assert!(matches!(ts.db.get_video(&ts.videos[0].video_hash).unwrap_err(), DBError::NotFound()));
+            // Make sure it's marked as trashed and the dir is gone
+            assert!(ts.db.get_video(&ts.videos[0].video_hash).unwrap().trashed.is_some());
+            assert!(!ts.videos_dir.join(&ts.videos[0].video_hash).exists());
 
             // Make sure it's in trash, and DB row was backed up on disk
             let trash_dir = ts.videos_dir.join("trash");
@@ -150,6 +152,73 @@ async fn test_api_open_video()
     }
 }
 
+#[tokio::test]
+#[traced_test]
+async fn test_api_subtitles()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        std::fs::create_dir_all(ts.videos_dir.join(&vh)).unwrap();
+        let (_cmd, data) = open_video(&mut ws, &vh).await;
+        assert_eq!(data["subtitles"], serde_json::json!([]));
+
+        // Attach SRT, get it back as WebVTT
+        let srt = "1\n00:00:01,000 --> 00:00:02,500\nHello\n";
+        write(&mut ws, &serde_json::json!({"cmd": "add_subtitle", "data": {"video_hash": vh, "filename": "test0.fi.srt", "contents": srt}}).to_string()).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "new_subtitle");
+        assert_eq!(data["language"], "fi");
+        assert_eq!(data["origin"], models::subtitle_origin::UPLOAD);
+        let sub_id = data["id"].as_i64().unwrap();
+        assert!(data["url"].as_str().unwrap().ends_with(&format!("/videos/{vh}/subs/{sub_id}.vtt")));
+        let vtt_file = ts.videos_dir.join(&vh).join("subs").join(format!("{sub_id}.vtt"));
+        assert!(std::fs::read_to_string(&vtt_file).unwrap().contains("00:00:01.000 --> 00:00:02.500"));
+
+        // Garbage is rejected
+        write(&mut ws, &serde_json::json!({"cmd": "add_subtitle", "data": {"video_hash": vh, "filename": "x.srt", "contents": "hello"}}).to_string()).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Other users see it, but can't delete it
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        let (_cmd, data) = open_video(&mut ws2, &vh).await;
+        assert_eq!(data["subtitles"][0]["id"], sub_id);
+        write(&mut ws2, &format!(r#"{{"cmd":"del_subtitle","data":{{"id":{sub_id}}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+
+        write(&mut ws, &format!(r#"{{"cmd":"del_subtitle","data":{{"id":{sub_id}}}}}"#)).await;
+        let (cmd, _data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "del_subtitle");
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "del_subtitle");
+        assert_eq!(data["id"], sub_id);
+        assert!(!vtt_file.exists());
+        assert!(ts.db.get_video_subtitles(&vh).unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_video_shots()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let (_cmd, data) = open_video(&mut ws, &vh).await;
+        assert_eq!(data["shots"], serde_json::json!([]));
+
+        ts.db.set_video_shots(&vh, &[
+            models::VideoShotInsert { video_hash: vh.clone(), start_time: 0.0, end_time: 2.5, thumb: Some("shots/0000.webp".into()) },
+            models::VideoShotInsert { video_hash: vh.clone(), start_time: 2.5, end_time: 6.0, thumb: None },
+        ]).unwrap();
+        let (_cmd, data) = open_video(&mut ws, &vh).await;
+        assert_eq!(data["shots"][0]["end"], 2.5);
+        assert!(data["shots"][0]["thumb_url"].as_str().unwrap().ends_with(&format!("/videos/{vh}/shots/0000.webp")));
+        assert_eq!(data["shots"][1]["start"], 2.5);
+        assert!(data["shots"][1]["thumb_url"].is_null());
+    }
+}
+
 #[tokio::test]
 #[traced_test]
 async fn test_api_open_bad_video()
@@ -190,6 +259,27 @@ async fn test_api_rename_video()
 }
 
 
+#[tokio::test]
+#[traced_test]
+async fn test_api_diff_videos()
+{
+    api_test! {[ws, ts]
+        let (va, vb) = (&ts.videos[1], &ts.videos[2]);
+        write(&mut ws, &format!(r#"{{"cmd":"diff_videos","data":{{"video_hash_a":"{}","video_hash_b":"{}"}}}}"#, va.video_hash, vb.video_hash)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "video_diff");
+        assert_eq!(data["video_hash_b"], vb.video_hash);
+        let summary = data["summary"].as_array().unwrap();
+        assert!(summary.contains(&serde_json::json!("1000 frames longer")));
+        assert!(summary.contains(&serde_json::json!("now 4 fps")));
+
+        // Unknown video
+        write(&mut ws, &format!(r#"{{"cmd":"diff_videos","data":{{"video_hash_a":"{}","video_hash_b":"nonexistent"}}}}"#, va.video_hash)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+    }
+}
+
 
 #[tokio::test]
 #[traced_test]
@@ -384,5 +474,3625 @@ async fn test_multipart_upload()
         let mut contents = String::new();
         std::io::Read::read_to_string(&mut file, &mut contents).unwrap();
         assert_eq!(contents, file_body);
+
+        // Paths are refused, also Windows style ones
+        for name in ["../testfile.mp4", "..\\testfile.mp4", "C:\\Videos\\testfile.mp4", "\\\\server\\share\\testfile.mp4"] {
+            let some_file = multipart::Part::stream(file_body).file_name(name).mime_str("video/mp4").unwrap();
+            let form = multipart::Form::new().part("fileupload", some_file);
+            let response = Client::new().post(format!("http://127.0.0.1:{}/api/upload", ts.port)).multipart(form).send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST, "{name}");
+        }
+        assert!(ts.upload_res_rx.is_empty());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_upload_busy()
+{
+    api_test! {[_ws, ts]
+        // Pipeline intake queue is full. Uploads are refused before receiving any data.
+        let queues = Arc::new(crate::video_pipeline::queues::QueueDepths::new(1));
+        let (link, _intake) = crate::video_pipeline::PipelineLink::new(queues, ts.storage.clone());
+        link.upload_tx.send(crate::video_pipeline::IncomingFile::default()).unwrap();
+        let server = ServerState::new(ts.db.clone(), &ts.opts, link, ts.config.clone(), ts.terminate_flag.clone());
+
+        let mut hdrs = warp::http::HeaderMap::new();
+        hdrs.insert("X-Remote-User-Id", "user.num1".parse().unwrap());
+        let body = futures::stream::empty::<Result<bytes::Bytes, warp::Error>>();
+        let reply = super::file_upload::handle_multipart_upload(server, "multipart/form-data; boundary=x".parse().unwrap(), hdrs, body, "t".into()).await.unwrap();
+        assert_eq!(warp::Reply::into_response(reply).status(), warp::http::StatusCode::TOO_MANY_REQUESTS);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_storage_full()
+{
+    api_test! {[_ws, ts]
+        // Out of space while writing somewhere. Admins are told, once.
+        let (link, _intake) = crate::video_pipeline::PipelineLink::new(Default::default(), ts.storage.clone());
+        let server = ServerState::new(ts.db.clone(), &ts.opts, link, ts.config.clone(), ts.terminate_flag.clone());
+        server.report_storage_error(crate::storage::StorageError::NoSpace);
+        server.report_storage_error(crate::storage::StorageError::NoSpace);
+        let msgs = ts.db.get_user_messages("admin").unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].message, crate::storage::ADMIN_FULL_MSG);
+        assert!(ts.db.get_user_messages("user.num1").unwrap().is_empty());
+
+        // Uploads are refused until space is freed
+        let url = format!("http://127.0.0.1:{}/api/upload", ts.port);
+        let some_file = multipart::Part::stream("Testfile 1234").file_name("testfile.mp4").mime_str("video/mp4").unwrap();
+        let form = multipart::Form::new().part("fileupload", some_file);
+        let response = Client::new().post(url).multipart(form).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::INSUFFICIENT_STORAGE);
+        assert!(ts.upload_res_rx.is_empty());
+
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"admin_queue_status","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["storage"]["full"], true);
+        assert_eq!(data["storage"]["error"], "No space left on device");
+
+        assert!(ts.storage.try_resume(|_| Ok(crate::storage::RESUME_FREE_BYTES)));
+        let some_file = multipart::Part::stream("Testfile 1234").file_name("testfile.mp4").mime_str("video/mp4").unwrap();
+        let form = multipart::Form::new().part("fileupload", some_file);
+        let response = Client::new().post(format!("http://127.0.0.1:{}/api/upload", ts.port)).multipart(form).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_upload_session()
+{
+    api_test! {[ws, ts]
+        write(&mut ws, r#"{"cmd":"create_upload_session","data":{"filename":"big.mp4","size":10}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "upload_session");
+        assert_eq!(data["received"], 0);
+        let id = data["id"].as_str().unwrap().to_string();
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "upload_sessions");
+        assert_eq!(data["sessions"].as_array().unwrap().len(), 1);
+
+        let put = |user: &'static str, offset: u64, body: &'static str| {
+            let url = format!("http://127.0.0.1:{}/api/upload_session/{}", ts.port, id);
+            Client::new().put(url)
+                .header("X-Remote-User-Id", user)
+                .header("X-Upload-Offset", offset.to_string())
+                .body(body).send()
+        };
+        assert_eq!(put("user.num2", 0, "0123").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        let res = put("user.num1", 0, "0123").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.json::<serde_json::Value>().await.unwrap()["received"], 4);
+
+        // Wrong offset is refused, with the right one in reply
+        let res = put("user.num1", 2, "23").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::CONFLICT);
+        assert_eq!(res.json::<serde_json::Value>().await.unwrap()["received"], 4);
+
+        // Paused session refuses data
+        write(&mut ws, &format!(r#"{{"cmd":"pause_upload_session","data":{{"id":"{id}"}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "upload_sessions");
+        assert_eq!(data["sessions"][0]["status"], "paused");
+        assert_eq!(put("user.num1", 4, "456789").await.unwrap().status(), reqwest::StatusCode::CONFLICT);
+
+        // Listed on login, and resume tells the offset
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num1").await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "upload_sessions");
+        assert_eq!(data["sessions"][0]["received"], 4);
+        write(&mut ws2, &format!(r#"{{"cmd":"resume_upload_session","data":{{"id":"{id}"}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "upload_session");
+        assert_eq!(data["status"], "active");
+        assert_eq!(data["received"], 4);
+
+        // Rest of the data completes the upload
+        let res = put("user.num1", 4, "456789").await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.json::<serde_json::Value>().await.unwrap()["complete"], true);
+        let up_res = ts.upload_res_rx.recv_timeout(std::time::Duration::from_secs(2)).unwrap();
+        assert_eq!(std::fs::read_to_string(&up_res.file_path).unwrap(), "0123456789");
+        assert!(up_res.file_path.ends_with("big.mp4"));
+        for n_sessions in [1, 0] {  // after resume, after completion
+            let (cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(cmd, "upload_sessions");
+            assert_eq!(data["sessions"].as_array().unwrap().len(), n_sessions);
+        }
+
+        // Cancel removes partial data
+        write(&mut ws, r#"{"cmd":"create_upload_session","data":{"filename":"other.mp4","size":10}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        let id2 = data["id"].as_str().unwrap().to_string();
+        expect_cmd_data(&mut ws).await;
+        let partial = ts.upload_dir.join("partial").join(format!("{id2}.part"));
+        assert!(partial.exists());
+        write(&mut ws, &format!(r#"{{"cmd":"cancel_upload_session","data":{{"id":"{id2}"}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        assert!(!partial.exists());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_upload_batch()
+{
+    api_test! {[ws, ts]
+        write(&mut ws, r#"{"cmd":"create_upload_batch","data":{"title":"Dailies","files":["a.mp4","b.mp4"]}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "upload_batch");
+        assert_eq!(data["n_total"], 2);
+        assert_eq!(data["n_pending"], 2);
+        let batch_id = data["batch"]["id"].as_i64().unwrap();
+        let file_id = data["files"][0]["id"].as_i64().unwrap();
+
+        // Upload first file as part of the batch
+        let upload = |user: &'static str, file_id: i64| {
+            let url = format!("http://127.0.0.1:{}/api/upload", ts.port);
+            let part = multipart::Part::stream("Testfile").file_name("a.mp4").mime_str("video/mp4").unwrap();
+            Client::new().post(url)
+                .header("X-Remote-User-Id", user)
+                .header("X-Batch-File-Id", file_id.to_string())
+                .multipart(multipart::Form::new().part("fileupload", part)).send()
+        };
+        assert_eq!(upload("user.num2", file_id).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(upload("user.num1", file_id).await.unwrap().status(), reqwest::StatusCode::OK);
+        assert_eq!(upload("user.num1", file_id).await.unwrap().status(), reqwest::StatusCode::CONFLICT);
+        let up_res = ts.upload_res_rx.recv_timeout(std::time::Duration::from_secs(2)).unwrap();
+        let bf = ts.db.get_upload_batch_file(file_id as i32).unwrap();
+        assert_eq!(bf.status, models::batch_file_status::PROCESSING);
+        assert_eq!(bf.src_file, Some(up_res.file_path.to_string_lossy().to_string()));
+
+        // Other users can't see or cancel it
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws2, &format!(r#"{{"cmd":"cancel_upload_batch","data":{{"batch_id":{batch_id}}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Cancel. Second file is skipped, first one is still processing
+        write(&mut ws, &format!(r#"{{"cmd":"cancel_upload_batch","data":{{"batch_id":{batch_id}}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "upload_batch");
+        assert_eq!(data["n_cancelled"], 1);
+        assert_eq!(data["n_processing"], 1);
+        assert!(data["batch"]["finished"].is_null());
+
+        // Processing finishes -> batch is complete
+        let (summary, just_finished) = crate::upload_batch::record_processing_result(&ts.db, &up_res.file_path, Ok("HASH0")).unwrap().unwrap();
+        assert!(just_finished);
+        let (msg, details) = summary.completion_msg();
+        assert!(msg.contains("1 video added"));
+        assert!(details.contains("HASH0"));
+
+        write(&mut ws, &format!(r#"{{"cmd":"get_upload_batch","data":{{"batch_id":{batch_id}}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["n_done"], 1);
+        assert_eq!(data["files"][0]["video_hash"], "HASH0");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_user_priority()
+{
+    api_test! {[ws, ts]
+        // Normal users can't set priorities
+        write(&mut ws, r#"{"cmd":"set_user_priority","data":{"user_id":"user.num1","priority":10}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert_eq!(ts.db.get_user_priority("user.num1").unwrap(), 0);
+
+        // Admin can
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"set_user_priority","data":{"user_id":"user.num2","priority":10}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "ok");
+        assert_eq!(ts.db.get_user_priority("user.num2").unwrap(), 10);
+
+        write(&mut ws_admin, r#"{"cmd":"list_user_priorities","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "user_priorities");
+        assert_eq!(data["priorities"][0]["user_id"], "user.num2");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_legal_hold()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+
+        // Owner can't set legal hold
+        write(&mut ws, &format!(r#"{{"cmd":"set_legal_hold","data":{{"video_hash":"{}","hold":false}}}}"#, vh)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Admin sets hold, owner can't delete
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, &format!(r#"{{"cmd":"set_legal_hold","data":{{"video_hash":"{}","hold":true,"reason":"Case 123"}}}}"#, vh)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "ok");
+        assert!(ts.db.get_video(&vh).unwrap().legal_hold);
+
+        write(&mut ws, &format!(r#"{{"cmd":"del_video","data":{{"video_hash":"{}"}}}}"#, vh)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert!(ts.db.get_video(&vh).is_ok());
+
+        // Lift hold, then deletion works
+        write(&mut ws_admin, &format!(r#"{{"cmd":"set_legal_hold","data":{{"video_hash":"{}","hold":false}}}}"#, vh)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "ok");
+        write(&mut ws, &format!(r#"{{"cmd":"del_video","data":{{"video_hash":"{}"}}}}"#, vh)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+
+        // All attempts were audited
+        let actions = ts.db.get_video_audit_events(&vh).unwrap().into_iter().map(|e| e.action).collect::<Vec<_>>();
+        assert_eq!(actions, vec![
+            models::audit_action::LEGAL_HOLD_DENIED, models::audit_action::LEGAL_HOLD_SET,
+            models::audit_action::DELETE_VIDEO_BLOCKED, models::audit_action::LEGAL_HOLD_LIFTED,
+            models::audit_action::DELETE_VIDEO]);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_get_my_usage()
+{
+    api_test! {[ws, ts]
+        std::fs::write(ts.videos_dir.join(&ts.videos[0].video_hash).join("video.mp4"), [0u8; 1000]).unwrap();
+        write(&mut ws, r#"{"cmd":"get_my_usage","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "user_usage");
+        assert_eq!(data["usage"]["n_videos"], 3);
+        assert!(data["usage"]["used_bytes"].as_u64().unwrap() >= 1000);
+        assert_eq!(data["usage"]["trash_bytes"], 0);
+        assert!(data["quotas"]["max_total_bytes"].is_null());
+
+        // Trashed videos still count, until purged
+        let used = data["usage"]["used_bytes"].as_u64().unwrap();
+        write(&mut ws, &format!(r#"{{"cmd":"del_video","data":{{"video_hash":"{}"}}}}"#, ts.videos[0].video_hash)).await;
+        expect_cmd_data(&mut ws).await;
+        write(&mut ws, r#"{"cmd":"get_my_usage","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["usage"]["n_videos"], 2);
+        assert!(data["usage"]["used_bytes"].as_u64().unwrap() >= used, "DB row backup is added, too");
+        assert!(data["usage"]["trash_bytes"].as_u64().unwrap() >= 1000);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_stitch_videos()
+{
+    api_test! {[ws, ts]
+        let stitch = |hashes: &[&str]| serde_json::json!({"cmd": "stitch_videos", "data": {"video_hashes": hashes, "title": "Reel"}}).to_string();
+        let (own0, other, own2) = (ts.videos[0].video_hash.as_str(), ts.videos[1].video_hash.as_str(), ts.videos[2].video_hash.as_str());
+
+        // Too few videos, missing video, someone else's video
+        for bad in [vec![own0], vec![own0, "nonexisting"], vec![own0, other]] {
+            write(&mut ws, &stitch(&bad)).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(data["event_name"], "error");
+        }
+
+        // Valid request is accepted. Source files don't exist here, so ffmpeg fails afterwards.
+        write(&mut ws, &stitch(&[own2, own0])).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert_eq!(data["message"], "Stitching failed");
+
+        // Provenance is sent with video info
+        ts.db.add_video_sources(own0, &[own2.to_string()], "Stitched").unwrap();
+        let (_cmd, data) = open_video(&mut ws, own0).await;
+        assert_eq!(data["sources"], serde_json::json!([own2]));
+        assert_eq!(data["derived_by"], "Stitched");
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num1").await;
+        let (_cmd, data) = open_video(&mut ws2, own2).await;
+        assert_eq!(data["derived"], serde_json::json!([own0]));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_replace_audio()
+{
+    api_test! {[ws, ts]
+        let upload = |user: &'static str, vh: &str, mode: &'static str| {
+            let url = format!("http://127.0.0.1:{}/api/upload", ts.port);
+            let part = multipart::Part::stream("Testfile").file_name("mix_v2.wav").mime_str("audio/wav").unwrap();
+            Client::new().post(url)
+                .header("X-Remote-User-Id", user)
+                .header("X-Replace-Audio-Of", vh)
+                .header("X-Audio-Mode", mode)
+                .multipart(multipart::Form::new().part("fileupload", part)).send()
+        };
+        let vh = ts.videos[0].video_hash.clone();
+        assert_eq!(upload("user.num1", "nonexisting", "replace").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(upload("user.num2", &vh, "replace").await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(upload("user.num1", &vh, "remix").await.unwrap().status(), reqwest::StatusCode::BAD_REQUEST);
+
+        // Accepted, but muxing fails here (no video file). Audio file must not go to the normal pipeline.
+        assert_eq!(upload("user.num1", &vh, "add").await.unwrap().status(), reqwest::StatusCode::OK);
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert_eq!(data["message"], "Audio replacement failed");
+        assert!(ts.upload_res_rx.try_recv().is_err());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_conform_video()
+{
+    api_test! {[ws, ts]
+        let conform = |vh: &str, fps: &str, mode: &str| serde_json::json!({"cmd": "conform_video", "data": {"video_hash": vh, "fps": fps, "mode": mode}}).to_string();
+        let vh = ts.videos[2].video_hash.as_str();  // 4 fps
+
+        for bad in [conform(vh, "fast", ""), conform(vh, "5", "stretch"), conform(vh, "4", ""),
+                    conform(vh, "24", "interpret"), conform(&ts.videos[1].video_hash, "5", "")] {
+            write(&mut ws, &bad).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(data["event_name"], "error", "{bad}");
+        }
+
+        // Accepted, but fails here as there's no video file
+        write(&mut ws, &conform(vh, "5", "convert")).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert_eq!(data["message"], "Frame rate conform failed");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_upload_image_sequence()
+{
+    api_test! {[_ws, ts]
+        let upload = |names: &[&str], fps: &'static str| {
+            let url = format!("http://127.0.0.1:{}/api/upload", ts.port);
+            let form = names.iter().fold(multipart::Form::new(), |form, name| {
+                form.part("fileupload", multipart::Part::stream("Testframe").file_name(name.to_string()).mime_str("image/png").unwrap())
+            });
+            Client::new().post(url)
+                .header("X-Remote-User-Id", "user.num1")
+                .header("X-Sequence-Fps", fps)
+                .multipart(form).send()
+        };
+        assert_eq!(upload(&["shot_0001.png", "shot_0002.png"], "fast").await.unwrap().status(), reqwest::StatusCode::BAD_REQUEST);
+
+        // Several frames -> the whole upload dir goes to pipeline, with requested frame rate
+        assert_eq!(upload(&["shot_0001.png", "shot_0002.png", "shot_0003.png"], "25").await.unwrap().status(), reqwest::StatusCode::OK);
+        let f = ts.upload_res_rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        assert!(f.file_path.is_dir());
+        assert_eq!(f.sequence_fps, Some(25.0));
+
+        // Single frame is just a file
+        assert_eq!(upload(&["poster_0001.png"], "25").await.unwrap().status(), reqwest::StatusCode::OK);
+        let f = ts.upload_res_rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        assert!(f.file_path.is_file());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_upload_dry_run()
+{
+    api_test! {[_ws, ts]
+        let upload = |extra: Option<(&'static str, String)>| {
+            let url = format!("http://127.0.0.1:{}/api/upload", ts.port);
+            let part = multipart::Part::stream("Not a video").file_name("clip.mov").mime_str("video/quicktime").unwrap();
+            let req = Client::new().post(url)
+                .header("X-Remote-User-Id", "user.num1")
+                .header("X-Dry-Run", "true");
+            let req = match extra { Some((k, v)) => req.header(k, v), None => req };
+            req.multipart(multipart::Form::new().part("fileupload", part)).send()
+        };
+        let res = upload(Some(("X-Replace-Audio-Of", ts.videos[0].video_hash.clone()))).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        // Unreadable file is reported as such. Nothing is ingested, and upload is removed.
+        let res = upload(None).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(ts.upload_res_rx.try_recv().is_err());
+        assert_eq!(std::fs::read_dir(&ts.upload_dir).unwrap().count(), 0);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_search_transcripts()
+{
+    api_test! {[ws, ts]
+        use crate::video_pipeline::subtitles::{store_subtitle, SubtitleTrack};
+        for (i, text) in ["Is this the final render?", "Final render approved"].iter().enumerate() {
+            let vtt = format!("WEBVTT\n\n00:04:12.500 --> 00:04:15.000\n{text}\n");
+            store_subtitle(&ts.db, &ts.videos_dir, &ts.videos[i].video_hash,
+                &SubtitleTrack { language: Some("en".into()), title: "English".into(), origin: models::subtitle_origin::UPLOAD, vtt }).unwrap();
+        }
+
+        // Own videos only
+        write(&mut ws, r#"{"cmd":"search_transcripts","data":{"query":"final render"}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "transcript_search_results");
+        assert_eq!(data["hits"].as_array().unwrap().len(), 1);
+        let hit = &data["hits"][0];
+        assert_eq!(hit["video_hash"], ts.videos[0].video_hash);
+        assert_eq!(hit["timecode"], "00:04:12");
+        assert_eq!(hit["start"], 252.5);
+        assert_eq!(hit["language"], "en");
+
+        // Other user's video by hash
+        write(&mut ws, &serde_json::json!({"cmd": "search_transcripts", "data": {"query": "approved", "video_hash": ts.videos[1].video_hash}}).to_string()).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["hits"][0]["text"], "Final render approved");
+
+        write(&mut ws, r#"{"cmd":"search_transcripts","data":{"query":"final render","language":"fi"}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert!(data["hits"].as_array().unwrap().is_empty());
+
+        // Admin searches everything
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"search_transcripts","data":{"query":"FINAL"}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["hits"].as_array().unwrap().len(), 2);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_still_comments()
+{
+    api_test! {[ws, ts]
+        ts.db.add_video(&models::VideoInsert {
+            video_hash: "still0".into(), added_by_userid: Some("user.num1".into()), orig_filename: Some("script.pdf".into()),
+            still_kind: Some(models::still_kind::PDF.into()), page_count: Some(3), ..Default::default() }).unwrap();
+        let (_cmd, data) = open_video(&mut ws, "still0").await;
+        assert_eq!(data["pages"].as_array().unwrap().len(), 3);
+        assert!(data["pages"][2].as_str().unwrap().ends_with("/videos/still0/pages/page-3.png"));
+
+        // Spatial comment on page 2
+        write(&mut ws, r#"{"cmd":"add_comment","data":{"video_hash":"still0","comment":"Typo here","page":2,"region":{"x":0.1,"y":0.2,"w":0.3,"h":0.4}}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "new_comment");
+        assert_eq!(data["page"], 2);
+        assert_eq!(data["region"]["w"], 0.3);
+        assert!(data["timecode"].is_null());
+
+        // Page defaults to 1
+        write(&mut ws, r#"{"cmd":"add_comment","data":{"video_hash":"still0","comment":"Nice","region":{"x":0.5,"y":0.5}}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["page"], 1);
+        assert!(data["region"]["w"].is_null());
+
+        // Bad anchors
+        for bad in [r#""timecode":"00:00:01:00""#, r#""page":4"#, r#""region":{"x":0.9,"y":0.1,"w":0.2,"h":0.1}"#, r#""region":{"x":2,"y":0}"#] {
+            write(&mut ws, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"still0","comment":"x",{bad}}}}}"#)).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(data["event_name"], "error", "{bad}");
+        }
+        write(&mut ws, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{}","comment":"x","page":1}}}}"#, ts.videos[0].video_hash)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_export_clip()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[1].video_hash.clone();   // 100 seconds long
+        let export = |args: &str| format!(r#"{{"cmd":"export_clip","data":{{"video_hash":"{vh}",{args}}}}}"#);
+
+        write(&mut ws, &export(r#""start":10,"end":12.5,"format":"gif""#)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "clip_export_queued");
+        let url = data["url"].as_str().unwrap();
+        assert!(url.starts_with(&format!("{}/videos/{vh}/clips/clip_10-12_", ts.url_base)) && url.ends_with(".gif"));
+
+        let Ok(ExportRequest::Clip(req)) = ts.export_rx.try_recv() else { panic!("Expected a clip export request") };
+        assert_eq!(req.url, url);
+        assert_eq!((req.start, req.end, req.width), (10.0, 12.5, 480));
+        assert_eq!(req.src, ts.videos_dir.join(&vh).join("orig").join("test1.mp4"));
+        assert!(req.dst.starts_with(ts.videos_dir.join(&vh).join("clips")));
+
+        // End is clamped to video duration
+        write(&mut ws, &export(r#""start":90,"end":200,"format":"mp4","width":1280"#)).await;
+        let (cmd, _data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "clip_export_queued");
+        assert!(matches!(ts.export_rx.try_recv(), Ok(ExportRequest::Clip(req)) if req.end == 100.0));
+
+        for bad in [r#""start":10,"end":5"#, r#""start":0,"end":60,"format":"gif""#, r#""start":0,"end":1,"format":"avi""#,
+                    r#""start":150,"end":160"#, r#""start":0,"end":1,"width":5000"#] {
+            write(&mut ws, &export(bad)).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(data["event_name"], "error", "{bad}");
+        }
+        assert!(ts.export_rx.try_recv().is_err());
+
+        // Not allowed if owner denies downloads
+        ts.db.set_video_allow_download(&vh, false).unwrap();
+        write(&mut ws, &export(r#""start":10,"end":12.5,"format":"gif""#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert_eq!(data["message"], "Owner doesn't allow downloading this video. Cannot export clip.");
+        assert!(ts.export_rx.try_recv().is_err());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_search_labels()
+{
+    api_test! {[ws, ts]
+        for (i, conf) in [Some(0.9), Some(0.3)].iter().enumerate() {
+            let vh = &ts.videos[i].video_hash;
+            ts.db.set_video_labels(vh, &[
+                models::VideoLabelInsert { video_hash: vh.clone(), kind: "face".into(), label: "Alice".into(), start_time: 61.0, end_time: 65.0, confidence: *conf },
+                models::VideoLabelInsert { video_hash: vh.clone(), kind: "object".into(), label: "bicycle".into(), start_time: 3.0, end_time: 4.0, confidence: None },
+            ]).unwrap();
+        }
+
+        // Own videos only
+        write(&mut ws, r#"{"cmd":"search_labels","data":{"query":"alice"}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "label_search_results");
+        assert_eq!(data["hits"].as_array().unwrap().len(), 1);
+        let hit = &data["hits"][0];
+        assert_eq!(hit["video_hash"], ts.videos[0].video_hash);
+        assert_eq!(hit["kind"], "face");
+        assert_eq!(hit["timecode"], "00:01:01");
+
+        // List all labels of a video, filtered by kind
+        write(&mut ws, &serde_json::json!({"cmd": "search_labels", "data": {"video_hash": ts.videos[1].video_hash, "kind": "Object"}}).to_string()).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["hits"].as_array().unwrap().len(), 1);
+        assert_eq!(data["hits"][0]["label"], "bicycle");
+
+        // Admin searches everything, with confidence threshold
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"search_labels","data":{"kind":"face","min_confidence":0.5}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["hits"].as_array().unwrap().len(), 1);
+        assert_eq!(data["hits"][0]["confidence"].as_f64().unwrap() as f32, 0.9);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_download()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let orig_dir = ts.videos_dir.join(&vh).join("orig");
+        std::fs::create_dir_all(&orig_dir).unwrap();
+        std::fs::write(orig_dir.join("test0.mp4"), b"0123456789").unwrap();
+
+        let get = |user: &'static str, path: String, range: Option<&'static str>| {
+            let mut req = Client::new().get(format!("{}/api/download/{}", ts.url_base, path)).header("X-Remote-User-Id", user);
+            if let Some(r) = range { req = req.header("Range", r); }
+            req.send()
+        };
+
+        // Owner gets an attachment, with range support
+        let (_cmd, data) = open_video(&mut ws, &vh).await;
+        assert_eq!(data["download_urls"]["orig"], format!("{}/api/download/{}/orig/test0.mp4", ts.url_base, vh));
+        assert!(data["download_urls"]["proxy"].is_null());
+        let res = get("user.num1", format!("{vh}/orig/test0.mp4"), None).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.headers()["content-disposition"], "attachment; filename*=UTF-8''test0.mp4");
+        assert_eq!(res.text().await.unwrap(), "0123456789");
+        let res = get("user.num1", format!("{vh}/orig/test0.mp4"), Some("bytes=2-4")).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.text().await.unwrap(), "234");
+
+        // Only the video's own files
+        assert_eq!(get("user.num1", format!("{vh}/video.mp4"), None).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("user.num1", format!("{vh}/orig/other.mp4"), None).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("user.num1", "nonexisting/orig/test0.mp4".into(), None).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+
+        // Others can download until owner denies it. Admin always can.
+        assert_eq!(get("user.num2", format!("{vh}/orig/test0.mp4"), None).await.unwrap().status(), reqwest::StatusCode::OK);
+        write(&mut ws, &format!(r#"{{"cmd":"set_allow_download","data":{{"video_hash":"{}","allow":false}}}}"#, vh)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        assert_eq!(get("user.num2", format!("{vh}/orig/test0.mp4"), None).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(get("admin", format!("{vh}/orig/test0.mp4"), None).await.unwrap().status(), reqwest::StatusCode::OK);
+
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        let (_cmd, data) = open_video(&mut ws2, &vh).await;
+        assert!(data.get("download_urls").is_none());
+        write(&mut ws2, &format!(r#"{{"cmd":"set_allow_download","data":{{"video_hash":"{}","allow":true}}}}"#, vh)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+        assert!(!ts.db.get_video(&vh).unwrap().allow_download);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_folders_and_overlays()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let enabled_overlays = |data: &serde_json::Value| data["overlays"].as_array().unwrap().iter()
+            .filter(|o| o["enabled"] == true).map(|o| o["name"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+
+        // Deployment defaults
+        let (_cmd, data) = open_video(&mut ws, &vh).await;
+        assert_eq!(enabled_overlays(&data), vec!["title_safe"]);
+
+        write(&mut ws, r#"{"cmd":"create_folder","data":{"title":"Social cuts"}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "user_folders");
+        let fid = data["folders"][0]["id"].as_i64().unwrap();
+
+        // Folder defaults apply to videos moved into it
+        write(&mut ws, &format!(r#"{{"cmd":"set_folder_overlays","data":{{"folder_id":{fid},"presets":["social_9x16","nonexisting"]}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        write(&mut ws, &format!(r#"{{"cmd":"set_folder_overlays","data":{{"folder_id":{fid},"presets":["social_9x16","social_4x5"]}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        write(&mut ws, &format!(r#"{{"cmd":"move_to_folder","data":{{"video_hash":"{vh}","folder_id":{fid}}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        let (_cmd, data) = open_video(&mut ws, &vh).await;
+        assert_eq!(data["folder_id"], fid);
+        let mut enabled = enabled_overlays(&data);
+        enabled.sort();
+        assert_eq!(enabled, vec!["social_4x5", "social_9x16"]);
+
+        // Other users can't use the folder
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws2, &format!(r#"{{"cmd":"move_to_folder","data":{{"video_hash":"{}","folder_id":{fid}}}}}"#, ts.videos[1].video_hash)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+        write(&mut ws2, &format!(r#"{{"cmd":"del_folder","data":{{"folder_id":{fid}}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Only admin edits presets
+        let preset = r#"{"cmd":"set_overlay_preset","data":{"name":"scope","title":"2.39:1 scope","kind":"aspect","value":2.39,"default_on":true}}"#;
+        write(&mut ws, preset).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, preset).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "overlay_presets");
+        assert!(data["presets"].as_array().unwrap().iter().any(|p| p["name"] == "scope"));
+        write(&mut ws_admin, r#"{"cmd":"set_overlay_preset","data":{"name":"bad","kind":"safe_area","value":0.7}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Deleting folder moves video back to root, with deployment defaults
+        write(&mut ws, &format!(r#"{{"cmd":"del_folder","data":{{"folder_id":{fid}}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        let (_cmd, data) = open_video(&mut ws, &vh).await;
+        assert!(data["folder_id"].is_null());
+        assert_eq!(enabled_overlays(&data), vec!["scope", "title_safe"]);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_export_package()
+{
+    api_test! {[ws, ts]
+        use crate::video_pipeline::review_package;
+        let vh = ts.videos[0].video_hash.clone();
+        let export = |vh: &str, burn_in: bool| format!(r#"{{"cmd":"export_package","data":{{"video_hash":"{vh}","burn_in":{burn_in}}}}}"#);
+
+        write(&mut ws, &export(&vh, true)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "package_export_queued");
+        assert_eq!(data["burn_in"], true);
+        let url = data["url"].as_str().unwrap();
+        assert!(url.starts_with(&format!("{}/videos/{vh}/packages/test0.mp4_review_", ts.url_base)) && url.ends_with(".zip"));
+
+        let Ok(ExportRequest::Package(mut req)) = ts.export_rx.try_recv() else { panic!("Expected a package export request") };
+        assert_eq!(req.url, url);
+        assert_eq!(req.files[0], (ts.videos_dir.join(&vh).join("orig").join("test0.mp4"), "proxy.mp4".to_string()));
+        let text = |name: &str| req.texts.iter().find(|t| t.0 == name).unwrap().1.clone();
+        let n_comments = ts.db.get_video_comments(&vh).unwrap().len();
+        assert_eq!(text("comments.csv").lines().count(), 1 + n_comments);
+        let meta: serde_json::Value = serde_json::from_str(&text("metadata.json")).unwrap();
+        assert_eq!(meta["video_hash"], vh);
+        assert_eq!(meta["exported_by"], "user.num1");
+
+        // Build it (without burn-in, which needs ffmpeg)
+        std::fs::create_dir_all(ts.videos_dir.join(&vh).join("orig")).unwrap();
+        std::fs::write(ts.videos_dir.join(&vh).join("orig").join("test0.mp4"), b"video").unwrap();
+        req.files.retain(|f| f.0.exists());
+        req.burn_in_src = None;
+        review_package::build_package(&req, &Default::default()).unwrap();
+        assert!(req.zip_path().is_file());
+
+        // Original instead of proxy, for handoff
+        ts.db.set_video_recompressed(&vh, None).unwrap();
+        write(&mut ws, &export(&vh, false).replace(r#""burn_in":false"#, r#""burn_in":false,"original":true"#)).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.0, "package_export_queued");
+        let Ok(ExportRequest::Package(req)) = ts.export_rx.try_recv() else { panic!("Expected a package export request") };
+        assert_eq!(req.files[0], (ts.videos_dir.join(&vh).join("orig").join("test0.mp4"), "original.mp4".to_string()));
+        let meta: serde_json::Value = serde_json::from_str(&req.texts.iter().find(|t| t.0 == "metadata.json").unwrap().1).unwrap();
+        assert_eq!(meta["media"], "original");
+        let v = ts.db.get_video(&vh).unwrap();
+        let req = review_package::make_request(&ts.db, &ts.videos_dir, &ts.url_base, &v, "user.num1", false, false).unwrap();
+        assert_eq!(req.files[0], (ts.videos_dir.join(&vh).join("video.mp4"), "proxy.mp4".to_string()));
+        std::fs::remove_file(ts.videos_dir.join(&vh).join("orig").join("test0.mp4")).unwrap();
+        assert!(review_package::make_request(&ts.db, &ts.videos_dir, &ts.url_base, &v, "user.num1", false, true).is_err());
+
+        // Not allowed if owner denies downloads
+        ts.db.set_video_allow_download(&vh, false).unwrap();
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws2, &export(&vh, false)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+        write(&mut ws2, &export("nonexisting", false)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+        assert!(ts.export_rx.try_recv().is_err());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_serve_videos_dir()
+{
+    api_test! {[_ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let vdir = ts.videos_dir.join(&vh);
+        for (f, contents) in [("thumbs/thumb.webp", "thumbnail"), ("orig/test0.mp4", "original"), ("video.mp4", "0123456789")] {
+            std::fs::create_dir_all(vdir.join(f).parent().unwrap()).unwrap();
+            std::fs::write(vdir.join(f), contents).unwrap();
+        }
+        let get = |user: &'static str, path: &str, hdr: Option<(&'static str, String)>| {
+            let mut req = Client::new().get(format!("{}/videos/{}", ts.url_base, path)).header("X-Remote-User-Id", user);
+            if let Some((k, v)) = hdr { req = req.header(k, v); }
+            req.send()
+        };
+
+        // Caching headers, revalidation
+        let res = get("user.num2", &format!("{vh}/thumbs/thumb.webp"), None).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.headers()["cache-control"], "private, max-age=3600");
+        let etag = res.headers()["etag"].to_str().unwrap().to_string();
+        assert_eq!(res.text().await.unwrap(), "thumbnail");
+        let res = get("user.num2", &format!("{vh}/thumbs/thumb.webp"), Some(("If-None-Match", etag.clone()))).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()["etag"].to_str().unwrap(), etag);
+
+        // Seeking
+        ts.db.set_video_recompressed(&vh, None).unwrap();
+        let res = get("user.num2", &format!("{vh}/video.mp4"), Some(("Range", "bytes=5-".into()))).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.text().await.unwrap(), "56789");
+
+        // Original of a transcoded video follows download permission
+        assert_eq!(get("user.num2", &format!("{vh}/orig/test0.mp4"), None).await.unwrap().status(), reqwest::StatusCode::OK);
+        ts.db.set_video_allow_download(&vh, false).unwrap();
+        assert_eq!(get("user.num2", &format!("{vh}/orig/test0.mp4"), None).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(get("user.num1", &format!("{vh}/orig/test0.mp4"), None).await.unwrap().status(), reqwest::StatusCode::OK);
+        assert_eq!(get("user.num2", &format!("{vh}/video.mp4"), None).await.unwrap().status(), reqwest::StatusCode::OK);
+
+        // Only files of existing videos
+        assert_eq!(get("user.num1", "nonexisting/video.mp4", None).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("user.num1", &format!("{vh}/%2E%2E%2F{vh}%2Fvideo.mp4"), None).await.unwrap().status(), reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(get("user.num1", &format!("{vh}/missing.mp4"), None).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_import_package()
+{
+    api_test! {[_ws, ts]
+        use crate::video_pipeline::review_package;
+        let v = ts.videos[0].clone();
+        ts.db.add_video_sources(&v.video_hash, &[ts.videos[1].video_hash.clone()], "Stitched from 1 videos").unwrap();
+        std::fs::create_dir_all(ts.videos_dir.join(&v.video_hash).join("orig")).unwrap();
+        std::fs::write(ts.videos_dir.join(&v.video_hash).join("orig").join("test0.mp4"), b"video").unwrap();
+        let req = review_package::make_request(&ts.db, &ts.videos_dir, &ts.url_base, &v, "user.num1", false, false).unwrap();
+        review_package::build_package(&req, &Default::default()).unwrap();
+
+        let upload = |file: std::path::PathBuf, user_map: &'static str| {
+            let part = multipart::Part::bytes(std::fs::read(file).unwrap()).file_name("package.zip").mime_str("application/zip").unwrap();
+            Client::new().post(format!("{}/api/upload", ts.url_base))
+                .header("X-Remote-User-Id", "user.num2")
+                .header("X-Import-Package", "1")
+                .header("X-Import-User-Map", user_map)
+                .multipart(multipart::Form::new().part("fileupload", part)).send()
+        };
+        assert_eq!(upload(req.zip_path(), "user.num1=user.num1").await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(upload(req.zip_path(), "user.num1=user.num2").await.unwrap().status(), reqwest::StatusCode::OK);
+
+        // Video goes to pipeline as a regular upload, with its comments and provenance already stored
+        let f = ts.upload_res_rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+        assert_eq!(f.user_id, "user.num2");
+        assert_eq!(f.file_path.file_name().unwrap(), "test0.mp4");
+        assert_eq!(std::fs::read(&f.file_path).unwrap(), b"video");
+        assert!(!f.file_path.parent().unwrap().join("package").exists());
+        let vh = crate::video_pipeline::calc_video_hash(&f.file_path, "user.num2").unwrap();
+
+        let orig = ts.db.get_video_comments(&v.video_hash).unwrap();
+        let imported = ts.db.get_video_comments(&vh).unwrap();
+        assert_eq!(imported.len(), orig.len());
+        let origin = ts.url_base.trim_start_matches("http://");
+        for (o, i) in orig.iter().zip(imported.iter()) {
+            assert_eq!((&i.comment, &i.username, i.created), (&o.comment, &o.username, o.created));
+            assert_eq!(i.user_id, if o.user_id == "user.num1" { "user.num2".to_string() } else { format!("{}@{}", o.user_id, origin) });
+            assert_eq!(i.parent_id.is_some(), o.parent_id.is_some());
+            if let Some(d) = i.drawing.as_ref().filter(|d| !d.is_empty()) {
+                assert!(f.file_path.parent().unwrap().join("drawings").join(d).is_file());
+            }
+        }
+        assert_eq!(ts.db.get_video_import(&vh).unwrap().origin_video_hash, v.video_hash);
+        assert_eq!(ts.db.get_video_sources(&vh).unwrap(), vec![ts.videos[1].video_hash.clone()]);
+
+        // Authors with an account here keep their user ID, if admin asks for it
+        let upload_matching = |user: &'static str| {
+            let part = multipart::Part::bytes(std::fs::read(req.zip_path()).unwrap()).file_name("package.zip").mime_str("application/zip").unwrap();
+            Client::new().post(format!("{}/api/upload", ts.url_base))
+                .header("X-Remote-User-Id", user)
+                .header("X-Import-Package", "1")
+                .header("X-Import-Match-Users", "true")
+                .multipart(multipart::Form::new().part("fileupload", part)).send()
+        };
+        assert_eq!(upload_matching("user.num2").await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        assert!(ts.db.get_user("user.num1").is_ok());
+        assert!(ts.db.get_user("user.num2").is_err());
+        assert_eq!(upload_matching("admin").await.unwrap().status(), reqwest::StatusCode::OK);
+        let f = ts.upload_res_rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+        let vh = crate::video_pipeline::calc_video_hash(&f.file_path, "admin").unwrap();
+        for (o, i) in orig.iter().zip(ts.db.get_video_comments(&vh).unwrap().iter()) {
+            assert_eq!(i.user_id, if o.user_id == "user.num1" { o.user_id.clone() } else { format!("{}@{}", o.user_id, origin) });
+            assert_eq!((i.created, i.edited), (o.created, o.edited));
+        }
+
+        // Not a package
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        let junk = ts.upload_dir.join("junk.zip");
+        std::fs::write(&junk, b"not a zip").unwrap();
+        assert_eq!(upload(junk, "").await.unwrap().status(), reqwest::StatusCode::OK);
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+        assert_eq!(data["message"], "Package import failed");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_signed_urls()
+{
+    api_test! {[ws, ts, Some(crate::api_server::url_signing::UrlSigner::new(b"0123456789abcdef", 600).unwrap())]
+        let vh = ts.videos[0].video_hash.clone();
+        let orig = ts.videos_dir.join(&vh).join("orig");
+        std::fs::create_dir_all(&orig).unwrap();
+        std::fs::write(orig.join("test0.mp4"), "video").unwrap();
+
+        let (_cmd, data) = open_video(&mut ws, &vh).await;
+        let url = data["video_url"].as_str().unwrap().to_string();
+        assert!(url.starts_with(&format!("{}/videos/{}/orig/test0.mp4?expires=", ts.url_base, vh)) && url.contains("&sig="));
+        let get = |url: String, user: Option<&'static str>| {
+            let req = Client::new().get(url);
+            match user { Some(u) => req.header("X-Remote-User-Id", u), None => req }.send()
+        };
+
+        // Signed URL works without user (e.g. through a CDN), unsigned only for a known user
+        let res = get(url.clone(), None).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "video");
+        let unsigned = url.split('?').next().unwrap().to_string();
+        assert_eq!(get(unsigned.clone(), None).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(get(unsigned, Some("user.num2")).await.unwrap().status(), reqwest::StatusCode::OK);
+
+        // Signature is bound to the path
+        let forged = url.replace("orig/test0.mp4", "video.mp4");
+        assert_eq!(get(forged, Some("user.num2")).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        let tampered = url.replace("sig=", "sig=0");
+        assert_eq!(get(tampered, None).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_federation()
+{
+    api_test! {[ws, ts]
+        use crate::api_server::federation;
+        use models::federated_kind::{VIDEO, COMMENT};
+
+        // Instance federates with itself: folder A (user.num1) and B (user.num2) sync with each other
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        let set_peer = format!(r#"{{"cmd":"set_federation_peer","data":{{"name":"self","url":"{}","secret":"0123456789abcdef","self_name":"self"}}}}"#, ts.url_base);
+        write(&mut ws, &set_peer).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        write(&mut ws_admin, &set_peer).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "federation_peers");
+        assert_eq!(data["peers"][0]["name"], "self");
+        assert!(data["peers"][0].get("secret").is_none());
+
+        let mkfolder = |user: &str| ts.db.add_folder(&models::FolderInsert { user_id: user.into(), title: "Shared".into(), parent_id: None }).unwrap();
+        let (fa, fb) = (mkfolder("user.num1"), mkfolder("user.num2"));
+        let set_sync = format!(r#"{{"cmd":"set_folder_sync","data":{{"folder_id":{},"peer":"self","remote_folder_id":{}}}}}"#, fa.id, fb.id);
+        write(&mut ws, &set_sync).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert!(data["message"].as_str().unwrap().contains("Only admin"), "Folder owner can't pair folders");
+        write(&mut ws_admin, &set_sync).await;
+        let (cmd, _data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "user_folders");
+        assert_eq!(ts.db.get_folder_sync(fa.id, "self").unwrap().remote_folder_id, fb.id);
+        ts.db.add_folder_sync(&models::FolderSyncInsert { folder_id: fb.id, peer: "self".into(), remote_folder_id: fa.id }).unwrap();
+
+        let v = ts.videos[0].clone();
+        ts.db.set_video_folder(&v.video_hash, Some(fa.id)).unwrap();
+        std::fs::create_dir_all(ts.videos_dir.join(&v.video_hash).join("orig")).unwrap();
+        std::fs::write(ts.videos_dir.join(&v.video_hash).join("orig").join("test0.mp4"), b"video").unwrap();
+
+        // Feed requires a valid signature from a peer the folder is synced with
+        let changes = |fid: i32, query: String| Client::new().get(format!("{}/api/federation/folders/{}/changes?{}", ts.url_base, fid, query)).send();
+        let signer = crate::api_server::url_signing::UrlSigner::new(b"0123456789abcdef", 60).unwrap();
+        let sign = |fid: i32, requester: i32| format!("folder={}&{}", requester,
+            signer.sign(&federation::changes_signed_path(fid, requester), crate::api_server::url_signing::unix_now()));
+        assert_eq!(changes(fa.id, format!("peer=self&folder={}", fb.id)).await.unwrap().status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(changes(fa.id, format!("peer=other&{}", sign(fa.id, fb.id))).await.unwrap().status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(changes(fb.id, format!("peer=self&{}", sign(fa.id, fb.id))).await.unwrap().status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(changes(fa.id, format!("peer=self&{}", sign(fa.id, fb.id).replace(&format!("folder={}", fb.id), "folder=0"))).await.unwrap().status(),
+            reqwest::StatusCode::UNAUTHORIZED, "Requesting folder is signed");
+        let other = mkfolder("user.num1");
+        assert_eq!(changes(other.id, format!("peer=self&{}", sign(other.id, fb.id))).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(changes(fa.id, format!("peer=self&{}", sign(fa.id, other.id))).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(changes(fa.id, format!("peer=self&{}", sign(fa.id, fb.id))).await.unwrap().status(), reqwest::StatusCode::OK);
+        let unsigned_file = format!("{}/api/federation/files/{}/orig/test0.mp4?peer=self", ts.url_base, v.video_hash);
+        assert_eq!(Client::new().get(unsigned_file).send().await.unwrap().status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let (link, intake) = crate::video_pipeline::PipelineLink::new(Default::default(), ts.storage.clone());
+        let server = ServerState::new(ts.db.clone(), &ts.opts, link, ts.config.clone(), ts.terminate_flag.clone());
+        let sync = |fid: i32| {
+            let (server, s) = (server.clone(), ts.db.get_folder_syncs(Some(fid)).unwrap().remove(0));
+            tokio::task::spawn_blocking(move || federation::sync_folder(&server, &s))
+        };
+
+        // B receives A's video and its comments
+        assert!(sync(fb.id).await.unwrap().unwrap() > 0);
+        let f = intake.upload_rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+        assert_eq!((f.user_id.as_str(), std::fs::read(&f.file_path).unwrap()), ("user.num2", b"video".to_vec()));
+        let vh_b = crate::video_pipeline::calc_video_hash(&f.file_path, "user.num2").unwrap();
+        assert_eq!(ts.db.get_federated_local_id("self", VIDEO, &v.video_hash).unwrap(), Some(vh_b.clone()));
+        let orig = ts.db.get_video_comments(&v.video_hash).unwrap();
+        let copies = ts.db.get_video_comments(&vh_b).unwrap();
+        assert_eq!(copies.len(), orig.len());
+        assert!(copies.iter().all(|c| c.user_id.ends_with("@self") && c.drawing.is_none()));
+        assert_eq!(copies.iter().filter(|c| c.parent_id.is_some()).count(), orig.iter().filter(|c| c.parent_id.is_some()).count());
+        assert!(ts.db.get_folder_sync(fb.id, "self").unwrap().cursor > 0);
+
+        // Nothing new -> no changes, video not downloaded again
+        assert_eq!(sync(fb.id).await.unwrap().unwrap(), 0);
+        assert!(intake.upload_rx.try_recv().is_err());
+
+        // Pipeline ingests B's copy. A edits and deletes comments, B comments on its copy.
+        ts.db.add_video(&models::VideoInsert { video_hash: vh_b.clone(), added_by_userid: Some("user.num2".into()), ..Default::default() }).unwrap();
+        let (edited, deleted) = (orig[0].id, orig[1].id);
+        ts.db.set_comment_text(edited, "Edited at A", chrono::Utc::now().naive_utc() + chrono::Duration::seconds(1)).unwrap();
+        ts.db.del_comment(deleted).unwrap();
+        let reply_id = ts.db.add_comment(&models::CommentInsert { video_hash: vh_b.clone(), parent_id: Some(copies[0].id),
+            user_id: "user.num2".into(), username: "User Number2".into(), comment: "From B".into(),
+            timecode: None, timecode_end: None, drawing: None, page: None, region: None, visibility: models::comment_visibility::EXTERNAL.into() }).unwrap();
+
+        assert!(sync(fb.id).await.unwrap().unwrap() >= 2);
+        let local_of = |remote: i32| ts.db.get_federated_local_id("self", COMMENT, &remote.to_string()).unwrap().unwrap().parse::<i32>().unwrap();
+        assert_eq!(ts.db.get_comment(local_of(edited)).unwrap().comment, "Edited at A");
+        assert!(matches!(ts.db.get_comment(local_of(deleted)), Err(DBError::NotFound())));
+        assert_eq!(ts.db.get_video(&vh_b).unwrap().folder_id, Some(fb.id));
+
+        // A receives B's reply on A's own video, but not its own comments back
+        let n_before = ts.db.get_video_comments(&v.video_hash).unwrap().len();
+        assert_eq!(sync(fa.id).await.unwrap().unwrap(), 1);
+        let on_a = ts.db.get_video_comments(&v.video_hash).unwrap();
+        assert_eq!(on_a.len(), n_before + 1);
+        let reply = ts.db.get_comment(local_of(reply_id)).unwrap();
+        assert_eq!((reply.video_hash.as_str(), reply.user_id.as_str(), reply.parent_id), (v.video_hash.as_str(), "user.num2@self", Some(orig[0].id)));
+        assert!(intake.upload_rx.try_recv().is_err());
+
+        // Peer can't refer to our objects outside the synced folder
+        let unrelated = ts.videos[1].clone();
+        let unrelated_comments = ts.db.get_video_comments(&unrelated.video_hash).unwrap();
+        let forged = |id: i32, video: &str, parent: Option<federation::ObjRef>| federation::FeedComment { id, video: federation::ObjRef::Yours(video.into()), parent,
+            user_id: "user.num2".into(), username: "User Number2".into(), comment: "Forged".into(), timecode: None, timecode_end: None, page: None, region: None,
+            created: chrono::Utc::now().naive_utc(), edited: None };
+        let feed = federation::Feed { cursor: 0, videos: vec![], alive_comments: vec![9001, 9002],
+            comments: vec![forged(9001, &unrelated.video_hash, None), forged(9002, &v.video_hash, Some(federation::ObjRef::Yours(unrelated_comments[0].id.to_string())))] };
+        let peer = ts.db.get_federation_peer("self").unwrap();
+        let fa_now = ts.db.get_folder(fa.id).unwrap();
+        let apply = |folder: models::Folder, feed: federation::Feed| {
+            let (server, peer) = (server.clone(), peer.clone());
+            tokio::task::spawn_blocking(move || federation::apply_feed(&server, &peer, &folder, &feed))
+        };
+        apply(fa_now, feed).await.unwrap().unwrap();
+        assert_eq!(ts.db.get_video_comments(&unrelated.video_hash).unwrap().len(), unrelated_comments.len());
+        let forged_on_a = ts.db.get_comment(local_of(9002)).unwrap();
+        assert_eq!((forged_on_a.video_hash.as_str(), forged_on_a.parent_id), (v.video_hash.as_str(), None));
+
+        let fd = mkfolder("admin");
+        let path = format!("/api/federation/files/{}/orig/test0.mp4", v.video_hash);
+        let feed = federation::Feed { videos: vec![federation::FeedVideo { video_hash: "forged".into(), title: None, operation: None,
+            file: format!("{}?peer=self&{}", path, signer.sign(&path, crate::api_server::url_signing::unix_now())),
+            sources: vec![federation::ObjRef::Yours(unrelated.video_hash.clone()), federation::ObjRef::Yours(v.video_hash.clone())] }], ..Default::default() };
+        assert_eq!(apply(fd, feed).await.unwrap().unwrap(), 1);
+        let f = intake.upload_rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+        let vh_d = crate::video_pipeline::calc_video_hash(&f.file_path, "admin").unwrap();
+        assert!(ts.db.get_video_sources(&vh_d).unwrap().is_empty(), "Sources outside the folder are ignored");
+
+        // Another local folder can't pull A, though it's synced with the same peer
+        let fc = mkfolder("user.num2");
+        ts.db.add_folder_sync(&models::FolderSyncInsert { folder_id: fc.id, peer: "self".into(), remote_folder_id: fa.id }).unwrap();
+        assert!(sync(fc.id).await.unwrap().unwrap_err().contains("403"));
+        assert!(intake.upload_rx.try_recv().is_err());
+        assert!(ts.db.get_folder_videos(fc.id).unwrap().is_empty());
+
+        // Deleting the peer stops syncing
+        write(&mut ws_admin, r#"{"cmd":"del_federation_peer","data":{"name":"self"}}"#).await;
+        expect_cmd_data(&mut ws_admin).await;
+        assert!(ts.db.get_folder_syncs(None).unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_trash()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let n_comments = ts.db.get_video_comments(&vh).unwrap().len();
+        let del = |vh: &str, purge: bool| format!(r#"{{"cmd":"del_video","data":{{"video_hash":"{}","purge":{}}}}}"#, vh, purge);
+        write(&mut ws, &del(&vh, false)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["message"], "Video moved to trash.");
+        assert!(ts.videos_dir.join("trash").join(&vh).join("drawings").is_dir());
+
+        // Hidden from video list, can't be opened, listed in trash
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert!(data["videos"].as_array().unwrap().iter().all(|v| v["video_hash"] != vh));
+        write(&mut ws, &format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{}"}}}}"#, vh)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        write(&mut ws, r#"{"cmd":"list_trash","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "trash");
+        assert_eq!(data["videos"][0]["video_hash"], vh);
+        assert!(data["videos"][0]["trashed"].is_number());
+        write(&mut ws, &del(&vh, false)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Only owner or admin restores
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        let restore = format!(r#"{{"cmd":"restore_video","data":{{"video_hash":"{}"}}}}"#, vh);
+        write(&mut ws2, &restore).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+        write(&mut ws, &restore).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["message"], "Video restored.");
+        assert!(ts.videos_dir.join(&vh).join("drawings").is_dir());
+        assert!(ts.db.get_video(&vh).unwrap().trashed.is_none());
+        assert_eq!(ts.db.get_video_comments(&vh).unwrap().len(), n_comments);
+
+        // Only admin purges right away
+        write(&mut ws, &del(&vh, true)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, &del(&vh, true)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["message"], "Video purged.");
+        assert!(matches!(ts.db.get_video(&vh), Err(DBError::NotFound())));
+        assert!(!ts.videos_dir.join(&vh).exists());
+
+        // Expired videos are purged automatically, except under legal hold
+        let (vh2, vh3) = (ts.videos[2].video_hash.clone(), ts.videos[4].video_hash.clone());
+        for v in [&vh2, &vh3] {
+            write(&mut ws, &del(v, false)).await;
+            expect_cmd_data(&mut ws).await;
+        }
+        ts.db.set_video_legal_hold(&vh3, true).unwrap();
+        let (link, _intake) = crate::video_pipeline::PipelineLink::new(Default::default(), ts.storage.clone());
+        let server = ServerState::new(ts.db.clone(), &ts.opts, link, ts.config.clone(), ts.terminate_flag.clone());
+        assert_eq!(crate::api_server::trash::purge_expired(&server, 1), 0);
+        assert_eq!(crate::api_server::trash::purge_expired(&server, 0), 1);
+        assert!(matches!(ts.db.get_video(&vh2), Err(DBError::NotFound())));
+        assert!(!ts.videos_dir.join("trash").join(&vh2).exists());
+        assert!(ts.db.get_video(&vh3).unwrap().trashed.is_some());
+        let actions = ts.db.get_video_audit_events(&vh).unwrap().into_iter().map(|e| e.action).collect::<Vec<_>>();
+        assert_eq!(actions, vec![models::audit_action::DELETE_VIDEO, models::audit_action::RESTORE_VIDEO, models::audit_action::PURGE_VIDEO]);
+        assert_eq!(ts.db.get_video_audit_events(&vh2).unwrap().last().unwrap().user_id, "system");
+        let skipped = ts.db.get_video_audit_events(&vh3).unwrap().pop().unwrap();
+        assert_eq!((skipped.action.as_str(), skipped.user_id.as_str()), (models::audit_action::DELETE_VIDEO_BLOCKED, "system"));
+
+        // Folder hold is inherited, too
+        ts.db.set_video_legal_hold(&vh3, false).unwrap();
+        let folder = ts.db.add_folder(&models::FolderInsert { user_id: "user.num1".into(), title: "Case".into(), parent_id: None }).unwrap();
+        ts.db.set_video_folder(&vh3, Some(folder.id)).unwrap();
+        write(&mut ws_admin, &format!(r#"{{"cmd":"set_legal_hold","data":{{"folder_id":{},"hold":true}}}}"#, folder.id)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["message"], "Legal hold set.");
+        assert_eq!(crate::api_server::trash::purge_expired(&server, 0), 0);
+        write(&mut ws_admin, &del(&vh3, true)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert!(data["message"].as_str().unwrap().contains("legal hold"));
+        assert!(ts.db.get_folder(folder.id).unwrap().legal_hold);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_admin()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+
+        // Router rejects admin commands from non-admins
+        for cmd in ["admin_list_users", "admin_list_videos", "admin_queue_status", "list_federation_peers", "search_events", "list_user_priorities"] {
+            write(&mut ws, &format!(r#"{{"cmd":"{}","data":{{}}}}"#, cmd)).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(data["event_name"], "error");
+            assert!(data["message"].as_str().unwrap().contains("Only admin"), "{cmd}");
+        }
+        write(&mut ws, &format!(r#"{{"cmd":"admin_reassign_video","data":{{"video_hash":"{}","user_id":"user.num1"}}}}"#, ts.videos[1].video_hash)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert_eq!(ts.db.get_video(&ts.videos[1].video_hash).unwrap().added_by_userid.as_deref(), Some("user.num2"));
+
+        // Users and their usage, including owners that haven't logged in
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"admin_list_users","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "admin_users");
+        let users = data["users"].as_array().unwrap();
+        let user = |uid: &str| users.iter().find(|u| u["user_id"] == uid).unwrap().clone();
+        assert_eq!(user("admin")["is_admin"], true);
+        assert_eq!(user("user.num1")["is_admin"], false);
+        assert_eq!(user("user.num2")["usage"]["n_videos"], 2);
+
+        write(&mut ws_admin, r#"{"cmd":"admin_list_videos","data":{"user_id":"user.num1"}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "admin_videos");
+        assert_eq!(data["videos"].as_array().unwrap().len(), 3);
+
+        write(&mut ws_admin, r#"{"cmd":"admin_queue_status","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "admin_queue_status");
+        assert!(data["jobs"].is_array());
+        assert_eq!(data["queues"]["depths"]["intake"], 0);
+        assert_eq!(data["queues"]["capacity"], crate::video_pipeline::queues::DEFAULT_CAPACITY);
+        assert_eq!(data["storage"]["full"], false);
+
+        write(&mut ws_admin, r#"{"cmd":"admin_job_stats","data":{"days":7}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "admin_job_stats");
+        assert!(data["stats"].as_array().unwrap().is_empty());
+
+        ts.db.add_throughput(chrono::Utc::now().naive_utc(), models::job_stage::TRANSCODE, 600.0, 300.0, 20.0).unwrap();
+        write(&mut ws_admin, r#"{"cmd":"admin_capacity_report","data":{"days":7}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "admin_capacity_report");
+        assert_eq!(data["stage"], "transcode");
+        assert_eq!(data["realtime_factor"], 2.0);
+        assert_eq!(data["daily"][0]["n_jobs"], 1);
+
+        // Reassign
+        write(&mut ws_admin, &format!(r#"{{"cmd":"admin_reassign_video","data":{{"video_hash":"{}","user_id":"user.num2"}}}}"#, vh)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "ok");
+        let v = ts.db.get_video(&vh).unwrap();
+        assert_eq!(v.added_by_userid.as_deref(), Some("user.num2"));
+        assert_eq!(v.folder_id, None);
+
+        // Grant admin rights, then disable user (closes their session)
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws_admin, r#"{"cmd":"admin_set_user","data":{"user_id":"user.num1","is_admin":true}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "ok");
+        assert!(ts.db.is_user_admin("user.num1").unwrap());
+        expect_cmd_data(&mut ws_admin).await;
+
+        write(&mut ws_admin, r#"{"cmd":"admin_set_user","data":{"user_id":"user.num2","disabled":true}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "ok");
+        assert!(ts.db.is_user_disabled("user.num2").unwrap());
+        let msg = async_std::future::timeout(std::time::Duration::from_secs(1), futures_util::StreamExt::next(&mut ws2)).await.unwrap();
+        assert!(matches!(msg, Some(Ok(m)) if m.is_close()));
+        expect_cmd_data(&mut ws_admin).await;
+
+        // Can't lock oneself out
+        write(&mut ws_admin, r#"{"cmd":"admin_set_user","data":{"user_id":"admin","is_admin":false}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "error");
+        assert!(ts.db.is_user_admin("admin").unwrap());
+
+        let events = ts.db.search_audit_events(&Default::default()).unwrap();
+        assert!(events.iter().any(|e| e.action == models::audit_action::REASSIGN_VIDEO));
+        assert_eq!(events.iter().filter(|e| e.action == models::audit_action::USER_UPDATED).count(), 2);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_teams()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();   // Owned by user.num1 (ws)
+
+        write(&mut ws, r#"{"cmd":"admin_create_team","data":{"name":"Editors"}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"admin_create_team","data":{"name":"Editors","admin_user_id":"user.num1"}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "ok");
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "teams");
+        let team_id = data["teams"][0]["id"].as_i64().unwrap();
+
+        // Team admin adds a member
+        write(&mut ws, &format!(r#"{{"cmd":"set_team_member","data":{{"team_id":{},"user_id":"user.num2"}}}}"#, team_id)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "teams");
+        assert_eq!(data["teams"][0]["can_manage"], true);
+        assert_eq!(data["teams"][0]["members"].as_array().unwrap().len(), 2);
+
+        // Plain member can't manage members or share others' videos
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws2, &format!(r#"{{"cmd":"set_team_member","data":{{"team_id":{},"user_id":"user.num3"}}}}"#, team_id)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+        let share = format!(r#"{{"cmd":"share_with_team","data":{{"team_id":{},"video_hash":"{}"}}}}"#, team_id, vh);
+        write(&mut ws2, &share).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Owner shares, members see it and can download it (even if owner doesn't allow it for everyone)
+        ts.db.set_video_allow_download(&vh, false).unwrap();
+        let v = ts.db.get_video(&vh).unwrap();
+        assert!(!crate::api_server::download::may_download(&ts.db, &v, "user.num2", false));
+        write(&mut ws, &share).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        assert!(crate::api_server::download::may_download(&ts.db, &v, "user.num2", false));
+        write(&mut ws2, &format!(r#"{{"cmd":"list_team_videos","data":{{"team_id":{}}}}}"#, team_id)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "team_videos");
+        assert_eq!(data["videos"][0]["video_hash"], vh);
+
+        // Non-members don't see the team
+        let mut ws3 = connect_client_ws(&ts.ws_url, "user.num3").await;
+        write(&mut ws3, &format!(r#"{{"cmd":"list_team_videos","data":{{"team_id":{}}}}}"#, team_id)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws3).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Member leaves, loses access
+        write(&mut ws2, &format!(r#"{{"cmd":"del_team_member","data":{{"team_id":{},"user_id":"user.num2"}}}}"#, team_id)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "teams");
+        assert!(data["teams"].as_array().unwrap().is_empty());
+        assert!(!crate::api_server::download::may_download(&ts.db, &v, "user.num2", false));
+
+        // Owner unshares
+        write(&mut ws, &share.replace("share_with_team", "unshare_from_team")).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        assert!(ts.db.get_team_videos(team_id as i32).unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_upload_dedup()
+{
+    api_test! {[ws, ts]
+        use sha2::Digest;
+        let body = "Shared clip";
+        ts.db.add_video(&models::VideoInsert {
+            video_hash: "dupvid".into(),
+            added_by_userid: Some("user.num2".into()),
+            title: Some("Team's copy".into()),
+            content_hash: Some(hex::encode(sha2::Sha256::digest(body))),
+            ..Default::default() }).unwrap();
+        let team = ts.db.add_team("Editors").unwrap();
+        for uid in ["user.num1", "user.num2"] {
+            ts.db.set_team_member(&models::TeamMember { team_id: team.id, user_id: uid.into(), is_team_admin: false }).unwrap();
+        }
+        let upload = |user: &'static str| {
+            let url = format!("http://127.0.0.1:{}/api/upload", ts.port);
+            let part = multipart::Part::stream(body).file_name("clip.mp4").mime_str("video/mp4").unwrap();
+            Client::new().post(url).header("X-Remote-User-Id", user)
+                .multipart(multipart::Form::new().part("fileupload", part)).send()
+        };
+
+        // Not shared yet: processed as usual
+        assert_eq!(upload("user.num1").await.unwrap().status(), reqwest::StatusCode::OK);
+        ts.upload_res_rx.recv_timeout(std::time::Duration::from_secs(2)).unwrap();
+
+        // Shared: upload waits for user's choice
+        ts.db.add_team_video(&models::TeamVideo { team_id: team.id, video_hash: "dupvid".into(), shared_by: "user.num2".into() }).unwrap();
+        let reply: serde_json::Value = upload("user.num1").await.unwrap().json().await.unwrap();
+        let pending_id = reply["pending_upload"]["id"].as_i64().unwrap();
+        assert_eq!(reply["pending_upload"]["duplicate_of"], "dupvid");
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "pending_upload");
+        assert_eq!(data["duplicate_title"], "Team's copy");
+        assert!(ts.upload_res_rx.try_recv().is_err());
+
+        write(&mut ws, r#"{"cmd":"list_pending_uploads","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "pending_uploads");
+        assert_eq!(data["uploads"][0]["filename"], "clip.mp4");
+
+        // Others can't resolve it
+        let resolve = |id: i64, action: &str| format!(r#"{{"cmd":"resolve_pending_upload","data":{{"id":{},"action":"{}"}}}}"#, id, action);
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws2, &resolve(pending_id, "discard")).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Link: existing video is listed, upload is gone
+        let src_file = ts.db.get_pending_upload(pending_id as i32).unwrap().src_file;
+        write(&mut ws, &resolve(pending_id, "link")).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        assert!(!std::path::Path::new(&src_file).exists());
+        write(&mut ws, &resolve(pending_id, "link")).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        let linked = data["videos"].as_array().unwrap().iter().filter(|v| v["linked"] == true).collect::<Vec<_>>();
+        assert_eq!(linked.len(), 1);
+        assert_eq!(linked[0]["video_hash"], "dupvid");
+
+        // Keep: stored as a copy after all
+        let reply: serde_json::Value = upload("user.num1").await.unwrap().json().await.unwrap();
+        expect_cmd_data(&mut ws).await;
+        write(&mut ws, &resolve(reply["pending_upload"]["id"].as_i64().unwrap(), "keep")).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        let up_res = ts.upload_res_rx.recv_timeout(std::time::Duration::from_secs(2)).unwrap();
+        assert_eq!(up_res.file_path.file_name().unwrap(), "clip.mp4");
+        assert!(ts.db.get_pending_uploads(None).unwrap().is_empty());
+
+        write(&mut ws, r#"{"cmd":"unlink_video","data":{"video_hash":"dupvid"}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        assert!(ts.db.get_user_linked_videos("user.num1").unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_comment_visibility()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();   // Owned by user.num1
+        let team = ts.db.add_team("Editors").unwrap();
+        ts.db.set_team_member(&models::TeamMember { team_id: team.id, user_id: "user.num3".into(), is_team_admin: false }).unwrap();
+        ts.db.add_team_video(&models::TeamVideo { team_id: team.id, video_hash: vh.clone(), shared_by: "user.num1".into() }).unwrap();
+        ts.db.add_share_link(&models::ShareLinkInsert { token: "tok".into(), video_hash: vh.clone(), created_by: "user.num1".into(),
+            allow_comments: true, ..Default::default() }).unwrap();
+
+        let mut outsider = connect_client_ws(&ts.ws_url, "user.num2").await;
+        let mut member = connect_client_ws(&ts.ws_url, "user.num3").await;
+        let (mut guest, _) = tokio_tungstenite::connect_async(format!("{}?share=tok", ts.ws_url)).await.unwrap();
+        expect_cmd_data(&mut guest).await;
+        for (w, sees) in [(&mut ws, true), (&mut outsider, false), (&mut member, true), (&mut guest, false)] {
+            let (_cmd, data) = open_video(w, &vh).await;
+            assert_eq!(data["sees_internal"], sees);
+        }
+
+        // Internal comment only reaches the owner's side
+        write(&mut ws, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Budget is tight","visibility":"internal"}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "new_comment");
+        assert_eq!(data["visibility"], "internal");
+        let cid = data["comment_id"].as_i64().unwrap();
+        assert_eq!(expect_cmd_data(&mut member).await.0, "new_comment");
+        expect_no_msg(&mut outsider).await;
+        expect_no_msg(&mut guest).await;
+
+        // Outsiders can't write internal comments, or reply to them
+        for msg in [format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"x","visibility":"internal"}}}}"#),
+                format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"x","parent_id":{cid}}}}}"#),
+                format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"x","visibility":"secret"}}}}"#)] {
+            write(&mut outsider, &msg).await;
+            let (_cmd, data) = expect_cmd_data(&mut outsider).await;
+            assert_eq!(data["event_name"], "error");
+        }
+        write(&mut guest, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"x","visibility":"internal"}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut guest).await;
+        assert_eq!(data["event_name"], "error");
+
+        // ...nor get them in listings
+        let list_comment_ids = |w: &'static str| {
+            let url = ts.ws_url.clone();
+            let vh = vh.clone();
+            async move {
+                let mut w = connect_client_ws(&url, w).await;
+                write(&mut w, &format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{vh}"}}}}"#)).await;
+                let mut ids = vec![];
+                while let Some((cmd, data)) = read_cmd_data(&mut w).await {
+                    if cmd == "new_comment" { ids.push(data["comment_id"].as_i64().unwrap()); }
+                }
+                ids
+            }
+        };
+        assert!(!list_comment_ids("user.num2").await.contains(&cid));
+        assert!(list_comment_ids("user.num3").await.contains(&cid));
+
+        // Member replies (internal), then owner makes the comment external
+        write(&mut member, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Agreed","parent_id":{cid}}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut member).await;
+        assert_eq!(data["visibility"], "internal");
+        expect_cmd_data(&mut ws).await;
+        expect_no_msg(&mut outsider).await;
+
+        write(&mut outsider, &format!(r#"{{"cmd":"set_comment_visibility","data":{{"comment_id":{cid},"visibility":"external"}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut outsider).await;
+        assert_eq!(data["event_name"], "error");
+        write(&mut ws, &format!(r#"{{"cmd":"set_comment_visibility","data":{{"comment_id":{cid},"visibility":"external"}}}}"#)).await;
+        assert_eq!(expect_cmd_data(&mut outsider).await.0, "del_comment");
+        let (cmd, data) = expect_cmd_data(&mut outsider).await;
+        assert_eq!(cmd, "new_comment");
+        assert_eq!(data["comment"], "Budget is tight");
+        assert_eq!(expect_cmd_data(&mut guest).await.0, "del_comment");
+        assert_eq!(expect_cmd_data(&mut guest).await.0, "new_comment");
+        assert!(list_comment_ids("user.num2").await.contains(&cid));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_private_notes()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[1].video_hash.clone();   // Owned by user.num2
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        open_video(&mut ws2, &vh).await;
+        open_video(&mut ws, &vh).await;
+
+        write(&mut ws, &format!(r#"{{"cmd":"add_note","data":{{"video_hash":"{vh}","note":"Color is off?","timecode":"00:00:01:00"}}}}"#)).await;
+        let (cmd, note) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "new_note");
+        assert_eq!(note["timecode"], "00:00:01:00");
+        let nid = note["id"].as_i64().unwrap();
+        expect_no_msg(&mut ws2).await;
+        write(&mut ws, &format!(r#"{{"cmd":"add_note","data":{{"video_hash":"{vh}","note":"x","page":1}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Others can't touch it
+        for msg in [format!(r#"{{"cmd":"edit_note","data":{{"note_id":{nid},"note":"Mine now"}}}}"#),
+                format!(r#"{{"cmd":"publish_note","data":{{"note_id":{nid}}}}}"#)] {
+            write(&mut ws2, &msg).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+            assert_eq!(data["message"], "No such note.");
+        }
+
+        write(&mut ws, &format!(r#"{{"cmd":"edit_note","data":{{"note_id":{nid},"note":"Color is off"}}}}"#)).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.0, "del_note");
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "new_note");
+        assert_eq!(data["note"], "Color is off");
+        assert!(data["edited"].as_i64().is_some());
+
+        // Notes come along when the video is opened again, only for the author
+        write(&mut ws, &format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let mut got_notes = vec![];
+        while let Some((cmd, data)) = read_cmd_data(&mut ws).await {
+            if cmd == "new_note" { got_notes.push(data["id"].as_i64().unwrap()); }
+        }
+        assert_eq!(got_notes, vec![nid]);
+        let mut ws3 = connect_client_ws(&ts.ws_url, "user.num3").await;
+        write(&mut ws3, &format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        while let Some((cmd, _data)) = read_cmd_data(&mut ws3).await {
+            assert_ne!(cmd, "new_note");
+        }
+
+        // Publishing turns it into a comment for everyone
+        let n_comments = ts.db.get_video_comments(&vh).unwrap().len();
+        write(&mut ws, &format!(r#"{{"cmd":"publish_note","data":{{"note_id":{nid}}}}}"#)).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.0, "del_note");
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "new_comment");
+        assert_eq!(data["comment"], "Color is off");
+        assert_eq!(data["timecode"], "00:00:01:00");
+        assert_eq!(data["user_id"], "user.num1");
+        assert_eq!(ts.db.get_video_comments(&vh).unwrap().len(), n_comments + 1);
+        assert!(ts.db.get_user_notes(&vh, "user.num1").unwrap().is_empty());
+
+        write(&mut ws, &format!(r#"{{"cmd":"del_note","data":{{"note_id":{nid}}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["message"], "No such note.");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_share_links()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();   // Owned by user.num1
+        let vdir = ts.videos_dir.join(&vh);
+        for f in ["video.mp4", "orig/test0.mp4"] {
+            std::fs::create_dir_all(vdir.join(f).parent().unwrap()).unwrap();
+            std::fs::write(vdir.join(f), "video").unwrap();
+        }
+        ts.db.set_video_recompressed(&vh, None).unwrap();
+        let connect_guest = |query: String| {
+            let url = format!("{}?{}", ts.ws_url, query);
+            async move { tokio_tungstenite::connect_async(url).await.unwrap().0 }
+        };
+        let get_file = |path_and_query: String| Client::new().get(format!("{}/videos/{}", ts.url_base, path_and_query)).send();
+
+        // Only owner can create links
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws2, &format!(r#"{{"cmd":"create_share_link","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+
+        write(&mut ws, &format!(r#"{{"cmd":"create_share_link","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let (cmd, link) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "share_link");
+        let token = link["token"].as_str().unwrap().to_string();
+        assert_eq!(link["url"], format!("{}/?share={}", ts.url_base, token));
+        assert_eq!(link["has_password"], false);
+        assert!(link.get("password_hash").is_none());
+
+        // View-only guest
+        let mut guest = connect_guest(format!("share={token}&name=Bob")).await;
+        let (cmd, data) = expect_cmd_data(&mut guest).await;
+        assert_eq!(cmd, "welcome");
+        assert_eq!(data["username"], "Bob");
+        assert_eq!(data["guest"]["video_hash"], vh.as_str());
+        assert_eq!(data["guest"]["allow_comments"], false);
+        for msg in [r#"{"cmd":"list_my_videos","data":{}}"#.to_string(),
+                format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{}"}}}}"#, ts.videos[1].video_hash),
+                format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Hi"}}}}"#)] {
+            write(&mut guest, &msg).await;
+            let (_cmd, data) = expect_cmd_data(&mut guest).await;
+            assert_eq!(data["event_name"], "error");
+        }
+        write(&mut guest, &format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut guest).await;
+        assert_eq!(cmd, "open_video");
+        assert!(data["video_url"].as_str().unwrap().ends_with(&format!("/videos/{vh}/video.mp4?share={token}")));
+        assert!(data.get("download_urls").is_none());
+        while read(&mut guest).await.is_some() {}   // Comments
+
+        // Files with the link
+        assert_eq!(get_file(format!("{vh}/video.mp4?share={token}")).await.unwrap().status(), reqwest::StatusCode::OK);
+        assert_eq!(get_file(format!("{vh}/orig/test0.mp4?share={token}")).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(get_file(format!("{vh}/video.mp4?share=bad")).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get_file(format!("{}/video.mp4?share={token}", ts.videos[1].video_hash)).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+
+        // Password protected, commenting allowed
+        write(&mut ws, &format!(r#"{{"cmd":"create_share_link","data":{{"video_hash":"{vh}","password":"pw","allow_comments":true,"expires_hours":24}}}}"#)).await;
+        let (_cmd, link2) = expect_cmd_data(&mut ws).await;
+        assert_eq!(link2["has_password"], true);
+        assert!(link2["expires"].as_i64().is_some());
+        let token2 = link2["token"].as_str().unwrap().to_string();
+        for (query, err) in [(format!("share={token2}"), "Password required"), (format!("share={token2}&password=x"), "Wrong password")] {
+            let mut g = connect_guest(query).await;
+            let (cmd, data) = expect_cmd_data(&mut g).await;
+            assert_eq!(cmd, "error");
+            assert_eq!(data["message"], err);
+        }
+        let mut guest2 = connect_guest(format!("share={token2}&password=pw")).await;
+        expect_cmd_data(&mut guest2).await;
+        write(&mut guest2, &format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut guest2).await;
+        let video_url = data["video_url"].as_str().unwrap().to_string();
+        assert!(video_url.contains("&key="));
+        while read(&mut guest2).await.is_some() {}
+        assert_eq!(Client::new().get(&video_url).send().await.unwrap().status(), reqwest::StatusCode::OK);
+        assert_eq!(get_file(format!("{vh}/video.mp4?share={token2}")).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        write(&mut guest2, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Looks good"}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut guest2).await;
+        assert_eq!(cmd, "new_comment");
+        assert_eq!(data["username"], "Guest");
+        let (cmd, _data) = expect_cmd_data(&mut guest).await;
+        assert_eq!(cmd, "new_comment", "Guests should see new comments, too");
+
+        write(&mut ws, &format!(r#"{{"cmd":"list_share_links","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "share_links");
+        assert_eq!(data["links"].as_array().unwrap().len(), 2);
+
+        // Revoking disconnects guests and stops file access
+        write(&mut ws, &format!(r#"{{"cmd":"revoke_share_link","data":{{"id":{}}}}}"#, link["id"])).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        assert!(read(&mut guest).await.is_none_or(|m| m.is_empty()));
+        assert_eq!(get_file(format!("{vh}/video.mp4?share={token}")).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        let mut g = connect_guest(format!("share={token}")).await;
+        let (_cmd, data) = expect_cmd_data(&mut g).await;
+        assert_eq!(data["message"], "No such share link");
+
+        // Expired
+        let expired = ts.db.add_share_link(&models::ShareLinkInsert { token: "expired".into(), video_hash: vh.clone(), created_by: "user.num1".into(),
+            expires: Some(chrono::Utc::now().naive_utc() - chrono::Duration::hours(1)), ..Default::default() }).unwrap();
+        let mut g = connect_guest(format!("share={}", expired.token)).await;
+        let (_cmd, data) = expect_cmd_data(&mut g).await;
+        assert_eq!(data["message"], "Share link expired");
+        assert_eq!(get_file(format!("{vh}/video.mp4?share=expired")).await.unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_guest_identity()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();   // Owned by user.num1
+        let connect_guest = |query: String| {
+            let url = format!("{}?{}", ts.ws_url, query);
+            async move { tokio_tungstenite::connect_async(url).await.unwrap().0 }
+        };
+        write(&mut ws, &format!(r#"{{"cmd":"create_share_link","data":{{"video_hash":"{vh}","allow_comments":true}}}}"#)).await;
+        let (_cmd, link) = expect_cmd_data(&mut ws).await;
+        let token = link["token"].as_str().unwrap().to_string();
+
+        // Named guest comments
+        let mut guest = connect_guest(format!("share={token}&name=%20Carol%20")).await;
+        let (_cmd, welcome) = expect_cmd_data(&mut guest).await;
+        assert_eq!(welcome["username"], "Carol");
+        let guest_id = welcome["guest"]["guest_id"].as_i64().unwrap();
+        let guest_key = welcome["guest"]["guest_key"].as_str().unwrap().to_string();
+        assert_eq!(welcome["user_id"], format!("guest:{guest_id}"));
+        write(&mut guest, &format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        while read(&mut guest).await.is_some() {}
+        write(&mut guest, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Nice"}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut guest).await;
+        assert_eq!(cmd, "new_comment");
+        assert_eq!(data["username"], "Carol");
+        assert_eq!(data["guest"], true);
+        let cid = data["comment_id"].as_i64().unwrap() as i32;
+
+        // Resuming keeps the identity, renaming if asked; a key for another link doesn't
+        let mut guest2 = connect_guest(format!("share={token}&guest_key={guest_key}&name=Carol%20B")).await;
+        let (_cmd, data) = expect_cmd_data(&mut guest2).await;
+        assert_eq!(data["guest"]["guest_id"], guest_id);
+        assert_eq!(data["username"], "Carol B");
+        write(&mut ws, &format!(r#"{{"cmd":"create_share_link","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let (_cmd, link2) = expect_cmd_data(&mut ws).await;
+        let mut g = connect_guest(format!("share={}&guest_key={guest_key}", link2["token"].as_str().unwrap())).await;
+        let (_cmd, data) = expect_cmd_data(&mut g).await;
+        assert_ne!(data["guest"]["guest_id"], guest_id);
+        assert_eq!(data["username"], "Guest");
+
+        // Users can't pose as guests
+        let (ws_url, uid) = (ts.ws_url.clone(), format!("guest:{guest_id}"));
+        assert!(tokio::spawn(async move { connect_client_ws(&ws_url, &uid).await }).await.is_err());
+
+        // Only admins can list and promote guests
+        for msg in [r#"{"cmd":"admin_list_guests","data":{}}"#.to_string(),
+                format!(r#"{{"cmd":"admin_promote_guest","data":{{"guest_id":{guest_id},"user_id":"user.num2"}}}}"#)] {
+            write(&mut ws, &msg).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(data["event_name"], "error");
+        }
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"admin_list_guests","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "admin_guests");
+        let guests = data["guests"].as_array().unwrap();
+        assert_eq!(guests.len(), 2);
+        let g = guests.iter().find(|g| g["id"] == guest_id).unwrap();
+        assert_eq!((g["name"].as_str(), g["video_hash"].as_str(), g["n_comments"].as_i64()), (Some("Carol B"), Some(vh.as_str()), Some(1)));
+        assert!(g.get("key").is_none());
+
+        write(&mut ws_admin, &format!(r#"{{"cmd":"admin_promote_guest","data":{{"guest_id":{guest_id},"user_id":"guest:99"}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "error");
+        write(&mut ws_admin, &format!(r#"{{"cmd":"admin_promote_guest","data":{{"guest_id":{guest_id},"user_id":"user.num2"}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "ok");
+        assert!(read(&mut guest2).await.is_none_or(|m| m.is_empty()), "Guest sessions should be closed");
+        let c = ts.db.get_comment(cid).unwrap();
+        assert_eq!(c.user_id, "user.num2");
+        assert!(!c.is_guest());
+
+        // Promoted identity can't be resumed
+        let mut g = connect_guest(format!("share={token}&guest_key={guest_key}")).await;
+        let (_cmd, data) = expect_cmd_data(&mut g).await;
+        assert_ne!(data["guest"]["guest_id"], guest_id);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_review_closed()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();   // Owned by user.num1
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        let (_cmd, data) = open_video(&mut ws2, &vh).await;
+        assert!(data["review_closed"].is_null());
+        let n_comments = ts.db.get_video_comments(&vh).unwrap().len();
+
+        // Only owner can close
+        write(&mut ws2, &format!(r#"{{"cmd":"set_review_closed","data":{{"video_hash":"{vh}","closed":true}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+
+        write(&mut ws, &format!(r#"{{"cmd":"set_review_closed","data":{{"video_hash":"{vh}","closed":true}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "review_status");
+        assert_eq!(data["closed"], true);
+
+        // New comments and published notes are rejected, history stays visible
+        write(&mut ws2, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Late"}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "message");
+        assert_eq!(data["event_name"], "error");
+        assert!(data["message"].as_str().unwrap().contains("Review is closed"));
+        write(&mut ws2, &format!(r#"{{"cmd":"add_note","data":{{"video_hash":"{vh}","note":"Later"}}}}"#)).await;
+        let (_cmd, note) = expect_cmd_data(&mut ws2).await;
+        write(&mut ws2, &format!(r#"{{"cmd":"publish_note","data":{{"note_id":{}}}}}"#, note["id"])).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+        assert_eq!(ts.db.get_video_comments(&vh).unwrap().len(), n_comments);
+
+        let mut ws3 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        let (_cmd, data) = open_video(&mut ws3, &vh).await;
+        assert_eq!(data["review_closed"]["closed_by"], "user.num1");
+
+        // Reopen
+        write(&mut ws, &format!(r#"{{"cmd":"set_review_closed","data":{{"video_hash":"{vh}","closed":false}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "ok");
+        let (cmd, data) = expect_cmd_data(&mut ws3).await;
+        assert_eq!(cmd, "review_status");
+        assert_eq!(data["closed"], false);
+        write(&mut ws3, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Late"}}}}"#)).await;
+        let (cmd, _data) = expect_cmd_data(&mut ws3).await;
+        assert_eq!(cmd, "new_comment");
+
+        let actions = ts.db.get_video_audit_events(&vh).unwrap().into_iter().map(|e| e.action).collect::<Vec<_>>();
+        assert_eq!(actions, vec![models::audit_action::REVIEW_CLOSED, models::audit_action::REVIEW_REOPENED]);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_review_verdicts()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();   // Owned by user.num1
+        let set_verdict = |verdict: &str, note: &str| format!(r#"{{"cmd":"set_review_verdict","data":{{"video_hash":"{vh}","verdict":"{verdict}","note":"{note}"}}}}"#);
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        let (_cmd, data) = open_video(&mut ws2, &vh).await;
+        assert_eq!(data["review"]["status"], "pending");
+
+        // Owner can't review own video, and verdict must be valid
+        write(&mut ws, &set_verdict("approved", "")).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        write(&mut ws2, &set_verdict("meh", "")).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Viewers see the aggregate, owner gets notified
+        write(&mut ws2, &set_verdict("changes_requested", "Fix the color")).await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "review_verdicts");
+        assert_eq!(data["status"], "changes_requested");
+        assert_eq!(data["verdicts"][0]["note"], "Fix the color");
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "message");
+        assert!(data["message"].as_str().unwrap().contains("requested changes"));
+        assert_eq!(data["details"], "Fix the color");
+
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        open_video(&mut ws_admin, &vh).await;
+        write(&mut ws_admin, &set_verdict("approved", "")).await;
+        for w in [&mut ws_admin, &mut ws2] {
+            let (cmd, data) = expect_cmd_data(w).await;
+            assert_eq!(cmd, "review_verdicts");
+            assert_eq!(data["status"], "changes_requested", "Any change request should hold back approval");
+        }
+        expect_cmd_data(&mut ws).await;
+
+        // Replacing a verdict
+        write(&mut ws2, &set_verdict("approved", "")).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["status"], "approved");
+        assert_eq!(data["verdicts"].as_array().unwrap().len(), 2);
+        expect_cmd_data(&mut ws_admin).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert!(data["message"].as_str().unwrap().contains("approved"));
+
+        // Withdrawing
+        write(&mut ws_admin, &format!(r#"{{"cmd":"withdraw_review_verdict","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["verdicts"].as_array().unwrap().len(), 1);
+        expect_cmd_data(&mut ws2).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert!(data["message"].as_str().unwrap().contains("withdrew"));
+        write(&mut ws_admin, &format!(r#"{{"cmd":"withdraw_review_verdict","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "error");
+
+        write(&mut ws, &format!(r#"{{"cmd":"list_review_verdicts","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "review_verdicts");
+        assert_eq!(data["status"], "approved");
+        assert_eq!(data["verdicts"][0]["user_id"], "user.num2");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_comment_numbers()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let add_comment = format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Hi"}}}}"#);
+        open_video(&mut ws, &vh).await;
+        let n = ts.db.get_video_comments(&vh).unwrap().len() as i64;
+
+        write(&mut ws, &add_comment).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "new_comment");
+        assert_eq!(data["number"], n + 1);
+        write(&mut ws, &format!(r#"{{"cmd":"del_comment","data":{{"comment_id":{}}}}}"#, data["comment_id"])).await;
+        expect_cmd_data(&mut ws).await;
+        write(&mut ws, &add_comment).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["number"], n + 2, "Numbers of deleted comments shouldn't be reused");
+
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws2, &format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        expect_cmd_data(&mut ws2).await;
+        let mut numbers = vec![];
+        while let Some((_cmd, c)) = read_cmd_data(&mut ws2).await {
+            numbers.push(c["number"].as_i64().unwrap());
+        }
+        assert_eq!(numbers.iter().max(), Some(&(n + 2)));
+        assert_eq!(numbers.len() as i64, n + 1);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_video_tags()
+{
+    api_test! {[ws, ts]
+        // user.num1 owns videos 0, 2 and 4
+        let tag = |cmd: &str, vh: &str, tag: &str| format!(r#"{{"cmd":"{cmd}","data":{{"video_hash":"{vh}","tag":"{tag}"}}}}"#);
+        for (i, t) in [(0, "Show A"), (0, "Ep 1"), (2, "show a"), (2, "  Ep   2 ")] {
+            write(&mut ws, &tag("add_video_tag", &ts.videos[i].video_hash, t)).await;
+            let (cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(cmd, "video_tags");
+            let t = t.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+            assert!(data["tags"].as_array().unwrap().iter().any(|vt| vt.as_str().unwrap().to_lowercase() == t));
+        }
+        for msg in [tag("add_video_tag", &ts.videos[1].video_hash, "Mine"),   // Not owner
+                tag("add_video_tag", &ts.videos[0].video_hash, " "),
+                tag("del_video_tag", &ts.videos[0].video_hash, "Nope")] {
+            write(&mut ws, &msg).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(data["event_name"], "error");
+        }
+
+        // Filter and group
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{"tags":["SHOW A"],"group_by":"tag"}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "user_videos");
+        let videos = data["videos"].as_array().unwrap();
+        assert_eq!(videos.len(), 2);
+        assert_eq!(videos[0]["tags"], serde_json::json!(["Ep 1", "Show A"]));
+        let groups = data["groups"].as_array().unwrap();
+        assert_eq!(groups.iter().map(|g| g["tag"].as_str().unwrap()).collect::<Vec<_>>(), vec!["Ep 1", "Ep 2", "Show A"]);
+        assert_eq!(groups[2]["video_hashes"].as_array().unwrap().len(), 2);
+
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{"tags":["show a","ep 2"]}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["videos"].as_array().unwrap().len(), 1);
+        assert_eq!(data["videos"][0]["video_hash"], ts.videos[2].video_hash);
+
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{"group_by":"tag"}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        let groups = data["groups"].as_array().unwrap();
+        assert!(groups.last().unwrap()["tag"].is_null());
+        assert_eq!(groups.last().unwrap()["video_hashes"], serde_json::json!([ts.videos[4].video_hash]));
+
+        // Autocomplete only from own videos
+        ts.db.add_video_tag(&ts.videos[1].video_hash, "Secret", "user.num2").unwrap();
+        write(&mut ws, r#"{"cmd":"autocomplete_tags","data":{"prefix":"s"}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "tag_suggestions");
+        assert_eq!(data["tags"], serde_json::json!([{"tag": "Show A", "count": 2}]));
+        write(&mut ws, r#"{"cmd":"autocomplete_tags","data":{"prefix":"ep"}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["tags"].as_array().unwrap().len(), 2);
+
+        write(&mut ws, &tag("del_video_tag", &ts.videos[0].video_hash, "show a")).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["tags"], serde_json::json!(["Ep 1"]));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_custom_fields()
+{
+    api_test! {[ws, ts]
+        let dept = r#"{"cmd":"admin_set_custom_field","data":{"name":"dept","title":"Department","field_type":"select","options":["VFX","Sound"]}}"#;
+        write(&mut ws, dept).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error", "Only admin may define fields");
+
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        for msg in [dept,
+                r#"{"cmd":"admin_set_custom_field","data":{"name":"shot_code","title":"Shot code","field_type":"text"}}"#,
+                r#"{"cmd":"admin_set_custom_field","data":{"name":"frames","field_type":"number"}}"#] {
+            write(&mut ws_admin, msg).await;
+            let (cmd, _data) = expect_cmd_data(&mut ws_admin).await;
+            assert_eq!(cmd, "custom_fields");
+        }
+        for msg in [r#"{"cmd":"admin_set_custom_field","data":{"name":"bad name","field_type":"text"}}"#,
+                r#"{"cmd":"admin_set_custom_field","data":{"name":"x","field_type":"date"}}"#,
+                r#"{"cmd":"admin_set_custom_field","data":{"name":"x","field_type":"select","options":[]}}"#] {
+            write(&mut ws_admin, msg).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+            assert_eq!(data["event_name"], "error");
+        }
+
+        write(&mut ws, r#"{"cmd":"list_custom_fields","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "custom_fields");
+        let fields = data["fields"].as_array().unwrap();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0]["options"], serde_json::json!(["VFX", "Sound"]));
+        assert_eq!(fields[1]["title"], "frames");
+
+        // user.num1 owns videos 0, 2 and 4
+        let set = |vh: &str, vals: serde_json::Value| serde_json::json!({"cmd": "set_video_custom_fields", "data": {"video_hash": vh, "custom_fields": vals}}).to_string();
+        write(&mut ws, &set(&ts.videos[0].video_hash, serde_json::json!({"dept": "VFX", "shot_code": " SH010 ", "frames": 120}))).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "video_custom_fields");
+        assert_eq!(data["custom_fields"], serde_json::json!({"dept": "VFX", "shot_code": "SH010", "frames": 120}));
+        write(&mut ws, &set(&ts.videos[2].video_hash, serde_json::json!({"dept": "Sound", "frames": 48.5}))).await;
+        expect_cmd_data(&mut ws).await;
+
+        for msg in [set(&ts.videos[0].video_hash, serde_json::json!({"dept": "Catering"})),
+                set(&ts.videos[0].video_hash, serde_json::json!({"frames": "many", "dept": null})),
+                set(&ts.videos[0].video_hash, serde_json::json!({"nope": 1})),
+                set(&ts.videos[1].video_hash, serde_json::json!({"dept": "VFX"}))] {   // Not owner
+            write(&mut ws, &msg).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(data["event_name"], "error");
+        }
+
+        // Filter listing
+        for (conds, expected) in [(serde_json::json!({"dept": "VFX"}), vec![0]),
+                (serde_json::json!({"shot_code": "sh0"}), vec![0]),
+                (serde_json::json!({"frames": {"min": 40, "max": 100}}), vec![2]),
+                (serde_json::json!({"frames": 120}), vec![0]),
+                (serde_json::json!({"frames": {"min": 1}, "dept": "Sound"}), vec![2]),
+                (serde_json::json!({"unknown": "x"}), vec![])] {
+            write(&mut ws, &serde_json::json!({"cmd": "list_my_videos", "data": {"custom_fields": conds}}).to_string()).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            let got = data["videos"].as_array().unwrap().iter().map(|v| v["video_hash"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+            assert_eq!(got, expected.iter().map(|i| ts.videos[*i].video_hash.clone()).collect::<Vec<_>>(), "{}", conds);
+        }
+
+        // Clearing a value, and hiding values of deleted fields
+        write(&mut ws, &set(&ts.videos[0].video_hash, serde_json::json!({"shot_code": ""}))).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["custom_fields"], serde_json::json!({"dept": "VFX", "frames": 120}));
+        write(&mut ws_admin, r#"{"cmd":"admin_del_custom_field","data":{"name":"frames"}}"#).await;
+        expect_cmd_data(&mut ws_admin).await;
+        let (_cmd, data) = open_video(&mut ws, &ts.videos[0].video_hash).await;
+        assert_eq!(data["custom_fields"], serde_json::json!({"dept": "VFX"}));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_search_users()
+{
+    api_test! {[ws, ts]
+        ts.db.touch_user("jjarvinen", "Jussi Järvinen").unwrap();
+        write(&mut ws, r#"{"cmd":"search_users","data":{"query":"jarvinen"}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "user_suggestions");
+        assert_eq!(data["users"], serde_json::json!([{"user_id": "jjarvinen", "username": "Jussi Järvinen"}]));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_onboarding()
+{
+    let onboarding = crate::api_server::onboarding::Onboarding {
+        message: Some("Welcome aboard!".into()),
+        sample_videos: vec!["HASH3".into(), "NO_SUCH_VIDEO".into()],
+        links: vec![crate::api_server::onboarding::QuickLink { title: "Guide".into(), url: "https://example.com/guide".into() }],
+    };
+    api_test! {[ws, ts, None, Some(onboarding)]
+        // First login: welcome message, sample video linked, and onboarding content
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "message");
+        assert_eq!(data["message"], "Welcome aboard!");
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "onboarding");
+        assert_eq!(data["sample_videos"].as_array().unwrap().len(), 1);
+        assert_eq!(data["sample_videos"][0]["video_hash"], ts.videos[3].video_hash);
+        assert_eq!(data["links"][0]["url"], "https://example.com/guide");
+        expect_no_msg(&mut ws).await;
+        assert!(ts.db.get_user_messages("user.num1").unwrap().iter().any(|m| m.message == "Welcome aboard!"));
+
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert!(data["videos"].as_array().unwrap().iter().any(|v| v["video_hash"] == ts.videos[3].video_hash && v["linked"] == true));
+
+        // Only once
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num1").await;
+        expect_no_msg(&mut ws2).await;
+        expect_no_msg(&mut ws).await;
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_server_shutdown()
+{
+    api_test! {[ws, ts]
+        ts.terminate_flag.store(true, Relaxed);
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "server_shutdown");
+        assert!(data["message"].as_str().unwrap().contains("shutting down"));
+
+        // Results of jobs still finishing are relayed (saved in DB) until the pipeline closes the channel
+        ts.user_msg_tx.send(UserMessage {
+            msg: "Video transcoded.".into(),
+            user_id: Some("user.num1".into()),
+            details: None,
+            video_hash: None, topic: UserMessageTopic::Ok(), }).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert!(ts.db.get_user_messages("user.num1").unwrap().iter().any(|m| m.message == "Video transcoded." && !m.seen));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_reload_config()
+{
+    api_test! {[ws, ts]
+        write(&mut ws, r#"{"cmd":"admin_reload_config","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Test server has no config file to reload from
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"admin_reload_config","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "error");
+        assert!(data["message"].as_str().unwrap().contains("--config"));
+
+        // Reloaded quotas apply to requests right away
+        let changed = ts.config.apply(crate::config::ReloadableConfig {
+            quotas: crate::quota::Quotas { max_total_bytes: Some(5000), ..Default::default() },
+            ..ts.config.get() }).unwrap();
+        assert_eq!(changed, vec!["quotas"]);
+        write(&mut ws, r#"{"cmd":"get_my_usage","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["quotas"]["max_total_bytes"], 5000);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_reconcile()
+{
+    api_test! {[ws, ts]
+        write(&mut ws, r#"{"cmd":"admin_reconcile","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        // One video lost its files, an old dir has no video, and a deleted video left rows behind
+        for v in &ts.videos[..ts.videos.len()-1] {
+            std::fs::create_dir_all(ts.videos_dir.join(&v.video_hash)).unwrap();
+        }
+        let orphan = ts.videos_dir.join("deadbeef");
+        std::fs::create_dir_all(&orphan).unwrap();
+        std::fs::write(orphan.join("video.mp4"), "0123456789").unwrap();
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(3 * 24 * 3600);
+        std::fs::File::options().write(true).open(orphan.join("video.mp4")).unwrap().set_modified(old).unwrap();
+        std::fs::File::open(&orphan).unwrap().set_modified(old).unwrap();
+        ts.db.set_video_shots("deadbeef", &[models::VideoShotInsert { video_hash: "deadbeef".into(), start_time: 0.0, end_time: 1.0, thumb: None }]).unwrap();
+
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"admin_reconcile","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "admin_reconcile_report");
+        assert_eq!(data["orphan_files"][0]["path"], "videos/deadbeef");
+        assert_eq!(data["orphan_files"][0]["bytes"], 10);
+        assert_eq!(data["dangling_rows"]["video_shots"], 1);
+        assert_eq!(data["missing_files"][0]["video_hash"], ts.videos.last().unwrap().video_hash);
+        assert_eq!(data["missing_files"].as_array().unwrap().len(), 1);
+        assert_eq!(data["removed"], false);
+        assert!(orphan.exists());
+
+        // Removal leaves the video with missing files alone
+        write(&mut ws_admin, r#"{"cmd":"admin_reconcile","data":{"remove":true}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["freed_bytes"], 10);
+        assert!(!orphan.exists());
+        assert!(ts.db.get_video_shots("deadbeef").unwrap().is_empty());
+        assert!(ts.db.get_video(&ts.videos.last().unwrap().video_hash).is_ok());
+
+        write(&mut ws_admin, r#"{"cmd":"admin_reconcile","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["orphan_files"], serde_json::json!([]));
+        assert_eq!(data["dangling_rows"], serde_json::json!({}));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_upload_sweeper()
+{
+    api_test! {[_ws, ts]
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 24 * 3600);
+        let rejected_dir = ts.videos_dir.parent().unwrap().join("rejected");
+        for d in [ts.upload_dir.join("abandoned"), ts.upload_dir.join("recent"), rejected_dir.clone()] {
+            std::fs::create_dir_all(&d).unwrap();
+        }
+        for f in [ts.upload_dir.join("abandoned").join("a.mov"), ts.upload_dir.join("recent").join("b.mov"), rejected_dir.join("c.mov"), rejected_dir.join("d.mov")] {
+            std::fs::write(&f, "DATA").unwrap();
+        }
+        for p in [ts.upload_dir.join("abandoned").join("a.mov"), ts.upload_dir.join("abandoned"), rejected_dir.join("c.mov")] {
+            std::fs::File::open(&p).unwrap().set_modified(old).unwrap();
+        }
+
+        let (link, _intake) = crate::video_pipeline::PipelineLink::new(Default::default(), ts.storage.clone());
+        let server = ServerState::new(ts.db.clone(), &ts.opts, link, ts.config.clone(), ts.terminate_flag.clone());
+
+        // Nothing is removed when kept forever
+        assert_eq!(crate::api_server::upload_sweeper::sweep(&server, None, None), 0);
+        assert_eq!(crate::api_server::upload_sweeper::sweep(&server, Some(24), Some(1)), 2);
+        assert!(!ts.upload_dir.join("abandoned").exists());
+        assert!(ts.upload_dir.join("recent").join("b.mov").exists());
+        assert!(!rejected_dir.join("c.mov").exists());
+        assert!(rejected_dir.join("d.mov").exists());
+
+        let stats = server.sweep_stats.to_json();
+        assert_eq!(stats["runs"], 2);
+        assert_eq!(stats["stale_uploads"], 1);
+        assert_eq!(stats["rejected_files"], 1);
+        assert_eq!(stats["bytes_freed"], 8);
+        assert_eq!(stats["errors"], 0);
+
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"admin_queue_status","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["sweeper"]["runs"], 0);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_backup()
+{
+    api_test! {[ws, ts]
+        write(&mut ws, r#"{"cmd":"admin_backup","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"admin_backup","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "admin_backup");
+        assert_eq!(data["videos"], ts.videos.len());
+        assert!(data["files"].as_u64().unwrap() > 0);
+
+        let bundle = ts.videos_dir.parent().unwrap().join(data["dir"].as_str().unwrap());
+        let manifest = crate::backup::read_manifest(&bundle).unwrap();
+        assert_eq!(manifest.db_sha256, data["db_sha256"]);
+        assert!(crate::backup::verify_media(ts.videos_dir.parent().unwrap(), &manifest).is_ok());
+        let copy = crate::database::DB::connect_db_file(&bundle.join(crate::backup::DB_FILE)).unwrap();
+        assert_eq!(copy.get_video_comments(&ts.videos[0].video_hash).unwrap().len(), ts.db.get_video_comments(&ts.videos[0].video_hash).unwrap().len());
+
+        let events = ts.db.search_audit_events(&Default::default()).unwrap();
+        assert!(events.iter().any(|e| e.action == models::audit_action::BACKUP && e.user_id == "admin"));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_bulk_import_upload()
+{
+    api_test! {[_ws, ts]
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("old.mov"), "Old project").unwrap();
+        let (url, path) = (ts.url_base.clone(), dir.path().join("old.mov"));
+        let res = tokio::task::spawn_blocking(move || crate::bulk_import::http_upload(&url, &path, "user.num2")).await.unwrap();
+        assert_eq!(res, crate::bulk_import::UploadResult::Ok);
+        let f = ts.upload_res_rx.recv_timeout(std::time::Duration::from_secs(2)).unwrap();
+        assert_eq!(f.user_id, "user.num2");
+        assert_eq!(std::fs::read_to_string(&f.file_path).unwrap(), "Old project");
+
+        let (url, path) = (ts.url_base.clone(), dir.path().join("missing.mov"));
+        let res = tokio::task::spawn_blocking(move || crate::bulk_import::http_upload(&url, &path, "user.num2")).await.unwrap();
+        assert!(matches!(res, crate::bulk_import::UploadResult::Failed(_)));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_status_page()
+{
+    api_test! {[ws, ts]
+        let now = chrono::Utc::now().timestamp();
+        write(&mut ws, &format!(r#"{{"cmd":"admin_add_maintenance_window","data":{{"starts":{},"ends":{}}}}}"#, now, now+60)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, &format!(r#"{{"cmd":"admin_add_maintenance_window","data":{{"starts":{},"ends":{}}}}}"#, now, now-60)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "error");
+
+        write(&mut ws_admin, &format!(r#"{{"cmd":"admin_add_maintenance_window","data":{{"starts":{},"ends":{},"message":"Storage upgrade"}}}}"#, now-60, now+3600)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "admin_maintenance_windows");
+        assert_eq!(data["windows"][0]["created_by"], "admin");
+
+        // Public status, no login needed
+        let url = format!("http://127.0.0.1:{}/api/status", ts.port);
+        let resp = Client::new().get(&url).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert!(resp.headers()["cache-control"].to_str().unwrap().contains("max-age"));
+        let st: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(st["status"], "maintenance");
+        assert_eq!(st["message"], "Storage upgrade");
+        assert_eq!(st["maintenance"][0]["ends"], now+3600);
+        assert!(st["maintenance"][0].get("created_by").is_none());
+
+        // Rate limited per client
+        let mut codes = vec![];
+        for _ in 0..70 {
+            codes.push(Client::new().get(&url).header("x-forwarded-for", "192.0.2.1").send().await.unwrap().status());
+        }
+        assert_eq!(codes[0], reqwest::StatusCode::OK);
+        assert_eq!(codes.last(), Some(&reqwest::StatusCode::TOO_MANY_REQUESTS));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_feature_flags()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let join = format!(r#"{{"cmd":"join_collab","data":{{"collab_id":"c1","video_hash":"{}"}}}}"#, vh);
+        write(&mut ws, r#"{"cmd":"admin_set_feature","data":{"feature":"collab","enabled":false}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Off for everyone
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"admin_set_feature","data":{"feature":"collab","enabled":false}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "admin_features");
+        assert_eq!(data["overrides"][0]["target"], "*");
+        write(&mut ws, &join).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert!(data["message"].as_str().unwrap().contains("not enabled"));
+
+        // On for a team of the user
+        let team = ts.db.add_team("Pilot").unwrap();
+        ts.db.set_team_member(&models::TeamMember { team_id: team.id, user_id: "user.num1".into(), is_team_admin: false }).unwrap();
+        write(&mut ws_admin, &format!(r#"{{"cmd":"admin_set_feature","data":{{"feature":"collab","target":"team:{}","enabled":true}}}}"#, team.id)).await;
+        expect_cmd_data(&mut ws_admin).await;
+        write(&mut ws, &join).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "message");
+        assert_eq!(data["event_name"], "ok");
+
+        // User's own override wins over team's
+        write(&mut ws_admin, r#"{"cmd":"admin_set_feature","data":{"feature":"collab","target":"user:user.num1","enabled":false}}"#).await;
+        expect_cmd_data(&mut ws_admin).await;
+        write(&mut ws, r#"{"cmd":"collab_report","data":{"paused":true,"seek_time":1.0}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert!(data["message"].as_str().unwrap().contains("not enabled"));
+
+        // Bad feature or target
+        for bad in [r#"{"feature":"nosuch","enabled":true}"#, r#"{"feature":"collab","target":"team:999","enabled":true}"#, r#"{"feature":"collab","target":"group:x","enabled":true}"#] {
+            write(&mut ws_admin, &format!(r#"{{"cmd":"admin_set_feature","data":{}}}"#, bad)).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+            assert_eq!(data["event_name"], "error");
+        }
+
+        // Removing overrides
+        write(&mut ws_admin, r#"{"cmd":"admin_set_feature","data":{"feature":"collab","target":"*","enabled":null}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["overrides"].as_array().unwrap().len(), 2);
+
+        // Config rollout applies to features without overrides
+        ts.config.apply(crate::config::ReloadableConfig {
+            features: super::feature_flags::FeatureFlags::parse("transcripts=off").unwrap(),
+            ..ts.config.get() }).unwrap();
+        write(&mut ws, r#"{"cmd":"search_transcripts","data":{"query":"hello"}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert!(data["message"].as_str().unwrap().contains("not enabled"));
+        write(&mut ws_admin, r#"{"cmd":"admin_list_features","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["features"][1]["name"], "transcripts");
+        assert_eq!(data["features"][1]["config"], "off");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_ingest_url()
+{
+    api_test! {[ws, ts]
+        let vdir = ts.videos_dir.join(&ts.videos[0].video_hash);
+        let vurl = format!("{}/videos/{}", ts.url_base, ts.videos[0].video_hash);
+
+        // Bad scheme
+        write(&mut ws, r#"{"cmd":"ingest_url","data":{"url":"file:///etc/passwd"}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        // Server's own network can't be reached, not even the test server itself
+        std::fs::write(vdir.join("clip.mp4"), [1u8; 5000]).unwrap();
+        for url in [format!("{}/clip.mp4", vurl), "http://169.254.169.254/latest/meta-data/".into()] {
+            write(&mut ws, &format!(r#"{{"cmd":"ingest_url","data":{{"url":"{}"}}}}"#, url)).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(data["event_name"], "ok");
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(data["event_name"], "error");
+            assert!(data["details"].as_str().unwrap().contains("private network"), "{url}");
+        }
+        assert!(ts.upload_res_rx.is_empty());
+
+        // Concurrent downloads per user are limited
+        let (link, _intake) = crate::video_pipeline::PipelineLink::new(Default::default(), ts.storage.clone());
+        let server = ServerState::new(ts.db.clone(), &ts.opts, link, ts.config.clone(), ts.terminate_flag.clone());
+        let url = reqwest::Url::parse("http://127.0.0.1:1/clip.mp4").unwrap();
+        server.url_ingests.lock().unwrap().insert("user.num1".into(), crate::api_server::url_ingest::MAX_INGESTS_PER_USER);
+        assert!(crate::api_server::url_ingest::spawn_url_ingest(server.clone(), "user.num1".into(), url.clone(), None, Default::default()).is_err());
+        server.url_ingests.lock().unwrap().clear();
+        assert!(crate::api_server::url_ingest::spawn_url_ingest(server.clone(), "user.num1".into(), url, None, Default::default()).is_ok());
+        for _ in 0..50 {
+            if server.url_ingests.lock().unwrap().is_empty() { break; }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(server.url_ingests.lock().unwrap().is_empty(), "Finished download is no longer counted");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_search_events()
+{
+    api_test! {[ws, ts]
+        ts.db.add_audit_event(&models::AuditEventInsert {
+            user_id: "user.num1".into(), action: models::audit_action::DELETE_VIDEO.into(),
+            ref_video_hash: Some("HASH0".into()), details: "Title was test0.mp4".into() }).unwrap();
+
+        // Not for normal users
+        write(&mut ws, r#"{"cmd":"search_events","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws_admin, r#"{"cmd":"search_events","data":{"text":"test0","sources":["audit"],"format":"csv"}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(cmd, "event_search_results");
+        assert_eq!(data["events"].as_array().unwrap().len(), 1);
+        assert_eq!(data["events"][0]["kind"], models::audit_action::DELETE_VIDEO);
+        assert!(data["csv"].as_str().unwrap().contains("delete_video,HASH0"));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_ws_rate_limit()
+{
+    api_test! {[ws, ts]
+        ts.config.apply(crate::config::ReloadableConfig {
+            rate_limits: super::rate_limit::RateLimits::parse("echo=0.1:2").unwrap(),
+            ..ts.config.get() }).unwrap();
+
+        // Burst is allowed, then commands are dropped. Client is told once.
+        for _ in 0..4 { write(&mut ws, r#"{"cmd":"echo","data":"hello"}"#).await; }
+        assert_eq!(expect_msg(&mut ws).await, "Echo: hello");
+        assert_eq!(expect_msg(&mut ws).await, "Echo: hello");
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "error");
+        assert_eq!(data["message"], super::rate_limit::SLOW_DOWN_MSG);
+        expect_no_msg(&mut ws).await;
+
+        // Other commands are not limited
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{}}"#).await;
+        let (cmd, _data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "user_videos");
+
+        // Flooding disconnects
+        for _ in 0..60 { write(&mut ws, r#"{"cmd":"echo","data":"hello"}"#).await; }
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "error");
+        assert!(data["message"].as_str().unwrap().contains("bye"));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_collab_takeover()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+
+        // Room left behind by another instance that went down
+        ts.db.join_collab_room("c1", &vh, "user.num2", "User Num2").unwrap();
+        ts.db.set_collab_playback("c1", true, 42.0, None).unwrap();
+
+        write(&mut ws, &format!(r#"{{"cmd":"join_collab","data":{{"collab_id":"c1","video_hash":"{}"}}}}"#, ts.videos[1].video_hash)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert!(data["message"].as_str().unwrap().contains("Mismatching video"));
+
+        write(&mut ws, &format!(r#"{{"cmd":"join_collab","data":{{"collab_id":"c1","video_hash":"{}"}}}}"#, vh)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "message");
+        assert_eq!(data["event_name"], "ok");
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "collab_cmd");
+        assert_eq!(data["seek_time"], 42.0);
+        assert_eq!(data["paused"], true);
+        assert_eq!(data["from_user"], "User Num2");
+        assert_eq!(data["resumed"], true);
+        assert_eq!(ts.db.get_collab_participants("c1").unwrap().len(), 2);
+
+        // Reports are saved
+        write(&mut ws, r#"{"cmd":"collab_report","data":{"paused":false,"seek_time":50.0,"page":1}}"#).await;
+        let (cmd, _) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "collab_cmd");
+        let room = ts.db.get_collab_room("c1").unwrap();
+        assert_eq!((room.paused, room.seek_time, room.page, room.host.as_str()), (false, Some(50.0), Some(1), "user.num2"));
+
+        write(&mut ws, r#"{"cmd":"leave_collab","data":{}}"#).await;
+        expect_cmd_data(&mut ws).await;
+        assert_eq!(ts.db.get_collab_participants("c1").unwrap().len(), 1);
+
+        // Disconnecting from a running server leaves the room
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num3").await;
+        write(&mut ws2, &format!(r#"{{"cmd":"join_collab","data":{{"collab_id":"c2","video_hash":"{}"}}}}"#, vh)).await;
+        expect_cmd_data(&mut ws2).await;
+        assert!(ts.db.get_collab_room("c2").is_ok());
+        ws2.close(None).await.unwrap();
+        for _ in 0..20 {
+            if ts.db.get_collab_room("c2").is_err() { break; }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(matches!(ts.db.get_collab_room("c2"), Err(DBError::NotFound())));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_session_limits()
+{
+    api_test! {[ws, ts]
+        ts.config.apply(crate::config::ReloadableConfig {
+            sessions: super::session_limits::SessionLimits { max_user_connections: Some(1), idle_timeout: Some(std::time::Duration::from_millis(300)), ..Default::default() },
+            ..ts.config.get() }).unwrap();
+
+        // User already has a connection
+        let mut ws2 = connect_client_ws_raw(&ts.ws_url, "user.num1").await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "error");
+        assert_eq!(data["message"], super::session_limits::TOO_MANY_CONNECTIONS_MSG);
+        let mut ws3 = connect_client_ws(&ts.ws_url, "user.num2").await;
+
+        // Active client stays connected, a silent one that doesn't answer pings is closed
+        for _ in 0..8 {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            write(&mut ws3, r#"{"cmd":"echo","data":"hello"}"#).await;
+        }
+        let mut got_idle_msg = false;
+        while let Some(msg) = read(&mut ws).await {
+            if msg.contains(super::session_limits::IDLE_MSG) { got_idle_msg = true; break; }
+        }
+        assert!(got_idle_msg);
+        for _ in 0..8 {
+            assert_eq!(expect_msg(&mut ws3).await, "Echo: hello");
+        }
+
+        // Connection was freed
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        connect_client_ws(&ts.ws_url, "user.num1").await;
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_comment_mentions()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+
+        // Unknown users, and the author, aren't notified
+        write(&mut ws, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{}","comment":"@User.Num2 @nobody @user.num1 check this","timecode":"00:00:01:00"}}}}"#, vh)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "message");
+        assert_eq!(data["event_name"], "mention");
+        assert_eq!(data["ref_video_hash"], vh);
+        assert!(data["details"].as_str().unwrap().contains("check this"));
+        expect_no_msg(&mut ws).await;
+
+        let cid = ts.db.get_video_comments(&vh).unwrap().into_iter().max_by_key(|c| c.id).unwrap().id;
+        assert_eq!(ts.db.get_comment_mentions(cid).unwrap(), vec!["user.num1", "user.num2"]);
+
+        // Editing notifies only newly mentioned users
+        let mut ws_admin = connect_client_ws(&ts.ws_url, "admin").await;
+        write(&mut ws, &format!(r#"{{"cmd":"edit_comment","data":{{"comment_id":{},"comment":"@user.num2 @admin check this"}}}}"#, cid)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws_admin).await;
+        assert_eq!(data["event_name"], "mention");
+        assert_eq!(data["ref_comment_id"], cid);
+        expect_no_msg(&mut ws2).await;
+        assert_eq!(ts.db.get_comment_mentions(cid).unwrap(), vec!["admin", "user.num2"]);
+
+        ts.db.del_comment(cid).unwrap();
+        assert!(ts.db.get_comment_mentions(cid).unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_comment_reactions()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let cid = ts.comments.iter().find(|c| c.video_hash == vh).unwrap().id;
+        open_video(&mut ws, &vh).await;
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        open_video(&mut ws2, &vh).await;
+
+        // Reactions are broadcast to the video's viewers
+        let react = |emoji: &str, remove: bool| format!(r#"{{"cmd":"react_comment","data":{{"comment_id":{},"emoji":"{}","remove":{}}}}}"#, cid, emoji, remove);
+        write(&mut ws, &react("👍", false)).await;
+        for w in [&mut ws, &mut ws2] {
+            let (cmd, data) = expect_cmd_data(w).await;
+            assert_eq!(cmd, "comment_reactions");
+            assert_eq!(data["comment_id"], cid);
+            assert_eq!(data["reactions"], serde_json::json!([{"emoji": "👍", "count": 1, "users": ["user.num1"]}]));
+        }
+        write(&mut ws2, &react("👍", false)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["reactions"][0]["count"], 2);
+        expect_cmd_data(&mut ws2).await;
+
+        // Repeating or removing a missing reaction changes nothing
+        write(&mut ws, &react("👍", false)).await;
+        write(&mut ws, &react("🎉", true)).await;
+        expect_no_msg(&mut ws2).await;
+
+        // Text isn't a reaction
+        write(&mut ws, &react(":+1:", false)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "message");
+        assert_eq!(data["event_name"], "error");
+
+        // Counts are included in comment listings
+        write(&mut ws, &format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{}"}}}}"#, vh)).await;
+        let mut got = None;
+        while let Some((cmd, data)) = read_cmd_data(&mut ws).await {
+            if cmd == "new_comment" && data["comment_id"] == cid { got = Some(data); }
+        }
+        assert_eq!(got.unwrap()["reactions"], serde_json::json!([{"emoji": "👍", "count": 2, "users": ["user.num1", "user.num2"]}]));
+
+        write(&mut ws2, &react("👍", true)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["reactions"][0]["users"], serde_json::json!(["user.num1"]));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_comment_history()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let c = ts.comments.iter().find(|c| c.video_hash == vh && c.user_id == "user.num1").unwrap();
+        open_video(&mut ws, &vh).await;
+
+        write(&mut ws, &format!(r#"{{"cmd":"edit_comment","data":{{"comment_id":{},"comment":"Fixed typo"}}}}"#, c.id)).await;
+        let (cmd, _data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "del_comment");
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "new_comment");
+        assert_eq!(data["is_edited"], true);
+
+        write(&mut ws, &format!(r#"{{"cmd":"get_comment_history","data":{{"comment_id":{}}}}}"#, c.id)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "comment_history");
+        assert_eq!(data["comment"], "Fixed typo");
+        assert!(data["edited"].is_i64());
+        let revs = data["revisions"].as_array().unwrap();
+        assert_eq!(revs.len(), 1);
+        assert_eq!(revs[0]["comment"], c.comment);
+        assert_eq!(revs[0]["replaced_by"], "user.num1");
+
+        write(&mut ws, r#"{"cmd":"get_comment_history","data":{"comment_id":12345}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "message");
+        assert_eq!(data["event_name"], "error");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_comment_time_range()
+{
+    api_test! {[ws, ts]
+        let v = &ts.videos[1];
+        assert_eq!(v.duration, Some(100.0));
+        open_video(&mut ws, &v.video_hash).await;
+        let add = |tc: Option<&str>, tc_end: &str| {
+            let mut data = serde_json::json!({"video_hash": v.video_hash, "comment": "Range", "timecode_end": tc_end});
+            if let Some(tc) = tc { data["timecode"] = tc.into(); }
+            serde_json::json!({"cmd": "add_comment", "data": data}).to_string()
+        };
+
+        write(&mut ws, &add(Some("00:00:10.0"), "00:00:20.5")).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "new_comment");
+        assert_eq!((data["timecode"].as_str(), data["timecode_end"].as_str()), (Some("00:00:10.0"), Some("00:00:20.5")));
+
+        // Invalid ranges are refused
+        for (tc, tc_end) in [(Some("00:00:10.0"), "00:00:05.0"), (Some("00:00:10.0"), "00:02:00.0"), (None, "00:00:20.0"), (Some("00:00:10.0"), "later")] {
+            write(&mut ws, &add(tc, tc_end)).await;
+            let (cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!(cmd, "message");
+            assert_eq!(data["event_name"], "error");
+        }
+        assert_eq!(ts.db.get_video_comments(&v.video_hash).unwrap().iter().filter(|c| c.timecode_end.is_some()).count(), 1);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_comment_attachments()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();   // Owned by user.num1
+        let cid = ts.comments.iter().find(|c| c.video_hash == vh && c.user_id == "user.num1").unwrap().id;
+        open_video(&mut ws, &vh).await;
+        let attach = |filename: &str, data: &str| serde_json::json!({"cmd": "attach_comment_file",
+            "data": {"comment_id": cid, "filename": filename, "data": data}}).to_string();
+        let get = |user: &'static str, url: String| Client::new().get(url).header("X-Remote-User-Id", user).send();
+
+        write(&mut ws, &attach("ref frame.png", "data:image/png;base64,aGVsbG8=")).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "comment_attachments");
+        assert_eq!(data["comment_id"], cid);
+        let a = data["attachments"][0].clone();
+        assert_eq!((a["filename"].as_str(), a["mime_type"].as_str(), a["size"].as_i64()), (Some("ref frame.png"), Some("image/png"), Some(5)));
+        let url = a["url"].as_str().unwrap().to_string();
+        assert!(url.starts_with(&format!("{}/videos/{vh}/attachments/{}/", ts.url_base, a["id"])));
+
+        // Served as a download with the original name
+        let res = get("user.num2", url.clone()).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert!(res.headers()["content-disposition"].to_str().unwrap().contains("ref%20frame.png"));
+        assert_eq!(res.text().await.unwrap(), "hello");
+
+        // Comment listings include attachments
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws2, &format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{}"}}}}"#, vh)).await;
+        let mut got = None;
+        while let Some((cmd, data)) = read_cmd_data(&mut ws2).await {
+            if cmd == "new_comment" && data["comment_id"] == cid { got = Some(data); }
+        }
+        assert_eq!(got.unwrap()["attachments"][0]["url"], url);
+
+        // Refused: disallowed types, others' comments
+        write(&mut ws, &attach("logo.svg", "data:image/svg+xml;base64,PHN2Zy8+")).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["event_name"], "error");
+        write(&mut ws2, &attach("x.png", "data:image/png;base64,aGVsbG8=")).await;
+        assert_eq!(expect_cmd_data(&mut ws2).await.1["event_name"], "error");
+        assert_eq!(ts.db.get_comment_attachments(cid).unwrap().len(), 1);
+
+        // Internal comment's attachments are for the owner's side only
+        ts.db.set_comment_visibility(cid, models::comment_visibility::INTERNAL).unwrap();
+        assert_eq!(get("user.num2", url.clone()).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("user.num1", url.clone()).await.unwrap().status(), reqwest::StatusCode::OK);
+
+        // Removing deletes the file
+        write(&mut ws, &format!(r#"{{"cmd":"del_comment_attachment","data":{{"attachment_id":{}}}}}"#, a["id"])).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "comment_attachments");
+        assert_eq!(data["attachments"], serde_json::json!([]));
+        assert!(!ts.videos_dir.join(&vh).join("attachments").join(a["id"].to_string()).exists());
+        assert_eq!(get("user.num1", url).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_user_profiles()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let set = |data: serde_json::Value| serde_json::json!({"cmd": "set_user_profile", "data": data}).to_string();
+        let get = |url: &str| Client::new().get(url).header("X-Remote-User-Id", "user.num2").send();
+
+        // Defaults to the name from auth proxy
+        write(&mut ws, r#"{"cmd":"get_user_profile","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "user_profile");
+        assert_eq!((data["user_id"].as_str(), data["avatar_url"].is_null()), (Some("user.num1"), true));
+        let login_name = data["display_name"].clone();
+
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num1").await;
+        write(&mut ws, &set(serde_json::json!({"display_name": " Num One ", "timezone": "Europe/Helsinki", "avatar": "data:image/png;base64,aGVsbG8="}))).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "user_profile");
+        assert_eq!((data["display_name"].as_str(), data["timezone"].as_str()), (Some("Num One"), Some("Europe/Helsinki")));
+        assert_eq!(expect_cmd_data(&mut ws2).await.1, data, "All user's sessions get it");
+        let avatar_url = data["avatar_url"].as_str().unwrap().to_string();
+        assert!(avatar_url.starts_with(&format!("{}/avatars/", ts.url_base)) && avatar_url.ends_with(".png"));
+        let res = get(&avatar_url).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "hello");
+
+        // Refused: bad time zone, non-image avatar, long name
+        write(&mut ws, &set(serde_json::json!({"timezone": "Mars/Olympus Mons"}))).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["event_name"], "error");
+        write(&mut ws, &set(serde_json::json!({"avatar": "data:image/svg+xml;base64,PHN2Zy8+"}))).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["event_name"], "error");
+        write(&mut ws, &set(serde_json::json!({"display_name": "x".repeat(100)}))).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["event_name"], "error");
+        assert_eq!(ts.db.get_user_profile("user.num1").unwrap().display_name.as_deref(), Some("Num One"));
+        while read(&mut ws2).await.is_some() {}
+
+        // Comments and collab messages carry the profile
+        open_video(&mut ws, &vh).await;
+        write(&mut ws, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{}","comment":"hi"}}}}"#, vh)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "new_comment");
+        assert_eq!((data["author"]["display_name"].as_str(), data["author"]["avatar_url"].as_str()), (Some("Num One"), Some(avatar_url.as_str())));
+        while read(&mut ws).await.is_some() {}
+
+        write(&mut ws2, &format!(r#"{{"cmd":"join_collab","data":{{"collab_id":"c1","video_hash":"{}"}}}}"#, vh)).await;
+        let (_, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!((data["message"].as_str(), data["from_profile"]["user_id"].as_str()), (Some("'Num One' joined collab"), Some("user.num1")));
+        write(&mut ws2, r#"{"cmd":"collab_report","data":{"paused":true,"seek_time":1.5}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!((cmd.as_str(), data["from_profile"]["timezone"].as_str()), (Some("collab_cmd"), Some("Europe/Helsinki")));
+
+        // Clients without the capability don't get profiles
+        let mut ws3 = connect_client_ws_raw(&format!("{}?proto=1&caps=collab", ts.ws_url), "user.num1").await;
+        let (cmd, data) = expect_cmd_data(&mut ws3).await;
+        assert_eq!(cmd, "welcome");
+        assert!(data.get("profile").is_none());
+
+        // Clearing the avatar deletes the unused file
+        let avatar_file = avatar_url.rsplit('/').next().unwrap().to_string();
+        write(&mut ws, &set(serde_json::json!({"avatar": "", "display_name": ""}))).await;
+        let (_, data) = expect_cmd_data(&mut ws).await;
+        assert!(data["avatar_url"].is_null());
+        assert_eq!(data["display_name"], login_name);
+        assert!(!ts.videos_dir.parent().unwrap().join("avatars").join(avatar_file).exists());
+        assert_eq!(get(&avatar_url).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_user_prefs()
+{
+    api_test! {[ws, ts]
+        let set = |prefs: serde_json::Value| serde_json::json!({"cmd": "set_prefs", "data": {"prefs": prefs}}).to_string();
+        write(&mut ws, r#"{"cmd":"get_prefs","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "prefs");
+        assert_eq!(data["prefs"], serde_json::json!({}));
+
+        // Other browsers of the user get the changes
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num1").await;
+        let mut other_user = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws, &set(serde_json::json!({"playback.quality": "original", "ui.theme": "dark", "ui.columns": ["title", "added"]}))).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "prefs");
+        assert_eq!(data["prefs"], serde_json::json!({"playback.quality": "original", "ui.theme": "dark", "ui.columns": ["title", "added"]}));
+        assert_eq!(expect_cmd_data(&mut ws2).await.1, data);
+        assert!(read(&mut other_user).await.is_none());
+
+        // Invalid changes are refused as a whole
+        write(&mut ws, &set(serde_json::json!({"ui.theme": null, "playback.volume": 2}))).await;
+        let (_, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert!(data["message"].as_str().unwrap().contains("playback.volume"));
+        write(&mut ws, &set(serde_json::json!({"ui.notes": "x".repeat(crate::api_server::user_prefs::MAX_VALUE_BYTES)}))).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["event_name"], "error");
+        while read(&mut ws2).await.is_some() {}
+
+        // Null removes, and settings roam to new sessions
+        write(&mut ws, &set(serde_json::json!({"ui.theme": null}))).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["prefs"].as_object().unwrap().len(), 2);
+        let mut ws3 = connect_client_ws(&ts.ws_url, "user.num1").await;
+        write(&mut ws3, r#"{"cmd":"get_prefs","data":{}}"#).await;
+        assert_eq!(expect_cmd_data(&mut ws3).await.1["prefs"], serde_json::json!({"playback.quality": "original", "ui.columns": ["title", "added"]}));
+        write(&mut other_user, r#"{"cmd":"get_prefs","data":{}}"#).await;
+        assert_eq!(expect_cmd_data(&mut other_user).await.1["prefs"], serde_json::json!({}));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_message_locale()
+{
+    api_test! {[ws, ts]
+        // Language comes from client settings, English without a catalog for it
+        write(&mut ws, r#"{"cmd":"set_prefs","data":{"prefs":{"ui.language":"fi"}}}"#).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.0, "prefs");
+        let mut ws2 = connect_client_ws_raw(&ts.ws_url, "user.num1").await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "welcome");
+        assert_eq!((data["locale"].as_str(), data["locales"].clone()), (Some("fi"), serde_json::json!(["en"])));
+        write(&mut ws2, r#"{"cmd":"open_video","data":{"video_hash":"nonexistent"}}"#).await;
+        let (_, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+        assert_eq!(data["message"], "No such video.");
+
+        // Errors outside commands too, now as valid JSON even with quotes in them
+        write(&mut ws2, r#"{"no_cmd": "x"}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "error");
+        assert!(data["message"].as_str().unwrap().starts_with("Invalid message, bye"));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_batch_videos()
+{
+    api_test! {[ws, ts]
+        // user.num1 owns videos 0, 2 and 4
+        let own = [0, 2, 4].map(|i| ts.videos[i].video_hash.clone());
+        let batch = |vhs: &[&String], action: &str, extra: serde_json::Value| {
+            let mut data = serde_json::json!({"video_hashes": vhs, "action": action});
+            data.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            serde_json::json!({"cmd": "batch_videos", "data": data}).to_string()
+        };
+
+        write(&mut ws, &batch(&[&own[0], &own[1], &own[2], &own[0]], "add_tag", serde_json::json!({"tag": "Client A"}))).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "batch_result");
+        assert_eq!(data["done"], true);
+        assert_eq!(data["video_hashes"].as_array().unwrap().len(), 3);
+        assert_eq!(ts.db.get_video_tags(&own).unwrap().len(), 3);
+        expect_no_msg(&mut ws).await;
+
+        // Someone else's video in the batch: nothing is changed
+        let others = ts.videos[1].video_hash.clone();
+        write(&mut ws, &batch(&[&own[0], &others], "set_allow_download", serde_json::json!({"allow": false}))).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["done"], false);
+        assert_eq!(data["failed"], serde_json::json!([{"video_hash": others, "reason": "Video not owned by you"}]));
+        assert!(ts.db.get_video(&own[0]).unwrap().allow_download);
+
+        // Move to own folder
+        let f = ts.db.add_folder(&models::FolderInsert { user_id: "user.num1".into(), title: "Batch".into(), parent_id: None }).unwrap();
+        write(&mut ws, &batch(&[&own[0], &own[1]], "move_to_folder", serde_json::json!({"folder_id": f.id}))).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["done"], true);
+        assert_eq!(ts.db.get_video(&own[1]).unwrap().folder_id, Some(f.id));
+
+        // Delete: legal hold blocks the whole batch
+        for vh in &own {
+            std::fs::create_dir_all(ts.videos_dir.join(vh)).unwrap();
+        }
+        ts.db.set_video_legal_hold(&own[2], true).unwrap();
+        write(&mut ws, &batch(&[&own[1], &own[2]], "delete", serde_json::json!({}))).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!((data["done"].as_bool(), data["failed"][0]["reason"].as_str()), (Some(false), Some("Video is under legal hold")));
+        assert!(ts.db.get_video(&own[1]).unwrap().trashed.is_none());
+
+        // ...also when it's on the folder
+        ts.db.set_video_legal_hold(&own[2], false).unwrap();
+        ts.db.set_folder_legal_hold(f.id, true).unwrap();
+        write(&mut ws, &batch(&[&own[1], &own[2]], "delete", serde_json::json!({}))).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["failed"], serde_json::json!([{"video_hash": own[1], "reason": "Video is under legal hold"}]));
+
+        ts.db.set_folder_legal_hold(f.id, false).unwrap();
+        write(&mut ws, &batch(&[&own[1], &own[2]], "delete", serde_json::json!({}))).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["done"], true);
+        for vh in &own[1..] {
+            assert!(ts.db.get_video(vh).unwrap().trashed.is_some());
+            assert!(!ts.videos_dir.join(vh).exists());
+            assert!(ts.videos_dir.join("trash").join(vh).is_dir());
+        }
+
+        write(&mut ws, &batch(&[&own[0]], "explode", serde_json::json!({}))).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["event_name"], "error");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_paged_listings()
+{
+    api_test! {[ws, ts]
+        let hashes = |data: &serde_json::Value| data["videos"].as_array().unwrap().iter()
+            .map(|v| v["video_hash"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+
+        // Videos, sorted and paged
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{"sort":"duration","order":"desc","limit":2}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "user_videos");
+        assert_eq!(hashes(&data), vec![ts.videos[4].video_hash.clone(), ts.videos[2].video_hash.clone()]);
+        assert_eq!((data["page"]["total"].as_i64(), data["page"]["next_offset"].as_i64()), (Some(3), Some(2)));
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{"sort":"duration","order":"desc","offset":2,"limit":2}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(hashes(&data), vec![ts.videos[0].video_hash.clone()]);
+        assert!(data["page"]["next_offset"].is_null());
+
+        // Unpaged listing has no page info
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{}}"#).await;
+        assert!(expect_cmd_data(&mut ws).await.1.get("page").is_none());
+
+        for bad in [r#"{"sort":"size"}"#, r#"{"order":"up"}"#, r#"{"limit":0}"#, r#"{"offset":-1}"#] {
+            write(&mut ws, &format!(r#"{{"cmd":"list_my_videos","data":{}}}"#, bad)).await;
+            assert_eq!(expect_cmd_data(&mut ws).await.1["event_name"], "error");
+        }
+
+        // Comments: first ones with the video, then the rest
+        let vh = ts.videos[0].video_hash.clone();
+        let n_comments = ts.db.get_video_comments(&vh).unwrap().len() as i64;
+        write(&mut ws, &serde_json::json!({"cmd": "open_video", "data": {"video_hash": vh, "comments_limit": 2}}).to_string()).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "open_video");
+        assert_eq!(data["comment_count"].as_i64(), Some(n_comments));
+        for _ in 0..2 {
+            assert_eq!(expect_cmd_data(&mut ws).await.0, "new_comment");
+        }
+        expect_no_msg(&mut ws).await;
+
+        write(&mut ws, &serde_json::json!({"cmd": "list_comments", "data": {"video_hash": vh, "offset": 2, "limit": 100}}).to_string()).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "comments_page");
+        assert_eq!(data["video_hash"], vh);
+        assert_eq!(data["comments"].as_array().unwrap().len() as i64, n_comments - 2);
+        assert!(data["comments"][0]["comment_id"].is_i64());
+        assert_eq!((data["total"].as_i64(), data["next_offset"].is_null()), (Some(n_comments), true));
+
+        write(&mut ws, r#"{"cmd":"list_comments","data":{"video_hash":"nosuch"}}"#).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["event_name"], "error");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_unread_comments()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let n_unread = ts.comments.iter().filter(|c| c.video_hash == vh && c.user_id != "user.num1").count() as i64;
+        assert!(n_unread > 0);
+        let list_unread = |data: &serde_json::Value| data["videos"].as_array().unwrap().iter()
+            .find(|v| v["video_hash"] == vh).unwrap()["unread_count"].as_i64();
+
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{}}"#).await;
+        assert_eq!(list_unread(&expect_cmd_data(&mut ws).await.1), Some(n_unread));
+
+        // Others' comments are marked new in review view
+        write(&mut ws, &format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert!(data["seen_until"].is_null());
+        assert_eq!(data["unread_count"].as_i64(), Some(n_unread));
+        let mut marked = 0;
+        while let Some((cmd, c)) = read_cmd_data(&mut ws).await {
+            if cmd != "new_comment" { continue; }
+            assert_eq!(c["unread"].as_bool().unwrap_or(false), c["user_id"] != "user.num1");
+            if c["unread"] == true { marked += 1; }
+        }
+        assert_eq!(marked, n_unread);
+
+        // Read position must be a comment of the same video
+        let other = ts.comments.iter().find(|c| c.video_hash != vh).unwrap().id;
+        write(&mut ws, &format!(r#"{{"cmd":"mark_seen","data":{{"video_hash":"{vh}","comment_id":{other}}}}}"#)).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["event_name"], "error");
+
+        write(&mut ws, &format!(r#"{{"cmd":"mark_seen","data":{{"video_hash":"{vh}"}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "video_seen");
+        assert_eq!(data["unread_count"].as_i64(), Some(0));
+        assert!(data["seen_until"].is_i64());
+
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{}}"#).await;
+        assert_eq!(list_unread(&expect_cmd_data(&mut ws).await.1), Some(0));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_web_push()
+{
+    use std::io::{BufRead, BufReader, Read, Write};
+    use base64::Engine as _;
+    use crate::api_server::web_push;
+    let b64 = |b: &[u8]| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(b);
+
+    api_test! {[ws, ts]
+        write(&mut ws, r#"{"cmd":"get_push_settings","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "push_settings");
+        assert!(data["vapid_public_key"].as_str().is_some_and(|k| !k.is_empty()));
+        assert_eq!((data["subscriptions"].as_i64(), data["comments"].as_bool()), (Some(0), Some(false)));
+
+        // Fake push service, that says the subscription has expired
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/push/abc", listener.local_addr().unwrap());
+        let (ua_private, ua_public, auth) = web_push::browser_keys_for_test();
+        let sub = serde_json::json!({ "endpoint": endpoint, "keys": { "p256dh": b64(&ua_public), "auth": b64(&auth) } });
+        let push_service = tokio::task::spawn_blocking(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut headers = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() { break; }
+                headers.push(line.trim().to_lowercase());
+            }
+            let len = headers.iter().find_map(|h| h.strip_prefix("content-length:")).unwrap().trim().parse().unwrap();
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 410 Gone\r\nContent-Length: 0\r\n\r\n").unwrap();
+            (headers, web_push::decrypt_for_test(&body, ua_private, &ua_public, &auth))
+        });
+
+        write(&mut ws, &serde_json::json!({"cmd": "push_subscribe", "data": sub}).to_string()).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["subscriptions"].as_i64(), Some(1));
+        write(&mut ws, &serde_json::json!({"cmd": "push_subscribe", "data": {"endpoint": endpoint, "keys": {"p256dh": "AAAA", "auth": "AAAA"}}}).to_string()).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["event_name"], "error");
+        write(&mut ws, r#"{"cmd":"set_push_settings","data":{"comments":true}}"#).await;
+        let data = expect_cmd_data(&mut ws).await.1;
+        assert_eq!((data["comments"].as_bool(), data["mentions"].as_bool()), (Some(true), Some(false)));
+        write(&mut ws, r#"{"cmd":"set_push_settings","data":{"comments":"yes"}}"#).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["event_name"], "error");
+
+        // Comment on user's video is pushed, encrypted for the browser
+        let vh = ts.videos[0].video_hash.clone();
+        assert_eq!(ts.videos[0].added_by_userid.as_deref(), Some("user.num1"));
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws2, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Nice shot"}}}}"#)).await;
+        let (headers, payload) = tokio::time::timeout(std::time::Duration::from_secs(10), push_service).await.expect("No push").unwrap();
+        assert!(headers[0].starts_with("post /push/abc "));
+        assert!(headers.contains(&"content-encoding: aes128gcm".to_string()));
+        assert!(headers.iter().any(|h| h.starts_with("authorization: vapid t=")));
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!((payload["kind"].as_str(), payload["video_hash"].as_str(), payload["body"].as_str()), (Some("comments"), Some(vh.as_str()), Some("Nice shot")));
+
+        // Gone subscription is removed
+        for _ in 0..50 {
+            if ts.db.get_push_subscriptions("user.num1").unwrap().is_empty() { break; }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(ts.db.get_push_subscriptions("user.num1").unwrap().is_empty());
+
+        write(&mut ws, &serde_json::json!({"cmd": "push_unsubscribe", "data": {"endpoint": endpoint}}).to_string()).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.1["subscriptions"].as_i64(), Some(0));
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_chat_notify()
+{
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    api_test! {[ws, ts]
+        // Fake chat server, that takes two posts
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let hook_url = format!("http://{}/hooks/xyz", listener.local_addr().unwrap());
+        let chat_server = tokio::task::spawn_blocking(move || {
+            (0..2).map(|_| {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() { break; }
+                    if let Some(l) = line.to_lowercase().strip_prefix("content-length:") { len = l.trim().parse().unwrap(); }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }).collect::<Vec<_>>()
+        });
+        ts.config.apply(crate::config::ReloadableConfig {
+            chat: super::notifier::ChatNotifier::from_json(&format!(r#"{{"routes": [{{"url": "{hook_url}", "channel": "reviews"}}]}}"#)).unwrap(),
+            ..ts.config.get() }).unwrap();
+
+        let vh = ts.videos[0].video_hash.clone();
+        write(&mut ws, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Needs <more> cowbell"}}}}"#)).await;
+        ts.user_msg_tx.send(UserMessage {
+            msg: "Video transcoded.".into(),
+            user_id: Some("user.num1".into()),
+            details: None,
+            video_hash: Some(vh.clone()), topic: UserMessageTopic::VideoReady(), }).unwrap();
+
+        let mut posts = tokio::time::timeout(std::time::Duration::from_secs(10), chat_server).await.expect("No chat posts").unwrap();
+        posts.sort_by_key(|p| p["text"].as_str().unwrap().contains("ready for review"));
+        assert!(posts.iter().all(|p| p["channel"] == "reviews" && p["username"] == "Clapshot"));
+        let (comment, ready) = (posts[0]["text"].as_str().unwrap(), posts[1]["text"].as_str().unwrap());
+        assert!(comment.contains("commented on") && comment.ends_with("> Needs &lt;more&gt; cowbell"), "{comment}");
+        assert!(ready.contains(&format!("{}/?vid={vh}|Open in Clapshot>", ts.url_base)), "{ready}");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_organizer()
+{
+    use crate::api_server::organizer::{pb, spawn_test_organizer, Organizer};
+
+    api_test! {[ws, ts]
+        let (hidden, labeled) = (ts.videos[2].video_hash.clone(), ts.videos[0].video_hash.clone());
+        let embargoed = ts.videos[4].video_hash.clone();
+        let (hidden_cln, labeled_cln, embargoed_cln) = (hidden.clone(), labeled.clone(), embargoed.clone());
+        let url = spawn_test_organizer(move |method, req| {
+            let fields = pb::read(req).unwrap();
+            let mut res = pb::Writer::default();
+            match method {
+                "ListVideos" => {
+                    assert_eq!(fields[0].1.string().unwrap(), "user.num1");
+                    res.string(1, &hidden_cln);
+                    let mut label = pb::Writer::default();
+                    label.string(1, &labeled_cln);
+                    label.string(2, "Pending review");
+                    res.message(2, &label.0);
+                },
+                "CheckPermission" => {
+                    let (action, vh) = (fields[1].1.string().unwrap(), fields[2].1.string().unwrap());
+                    if action == "comment" && vh == labeled_cln {
+                        res.bool(1, true);
+                        res.string(2, "Shot is locked in ShotGrid");
+                    } else if action == "view" && vh == embargoed_cln {
+                        res.bool(1, true);
+                        res.string(2, "Embargoed");
+                    }
+                },
+                _ => return None,
+            }
+            Some(res.0)
+        }).await;
+        ts.config.apply(crate::config::ReloadableConfig {
+            organizer: Some(Organizer::new(&url).unwrap()),
+            ..ts.config.get() }).unwrap();
+
+        // Listing: hidden video left out, label added
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        let videos = data["videos"].as_array().unwrap();
+        assert!(!videos.is_empty());
+        assert!(videos.iter().all(|v| v["video_hash"] != hidden.as_str()));
+        let v = videos.iter().find(|v| v["video_hash"] == labeled.as_str()).unwrap();
+        assert_eq!(v["organizer_label"], "Pending review");
+
+        // Viewing allowed, commenting denied with organizer's reason
+        open_video(&mut ws, &labeled).await;
+        write(&mut ws, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{labeled}","comment":"Hmm"}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert_eq!(data["details"], "Shot is locked in ShotGrid");
+        assert!(ts.db.get_video_comments(&labeled).unwrap().iter().all(|c| c.comment != "Hmm"));
+
+        // View denial covers comments and exports, too
+        for cmd in [r#""list_comments","data":{"video_hash":"VH"}"#, r#""export_clip","data":{"video_hash":"VH","start":0,"end":1}"#,
+                r#""export_package","data":{"video_hash":"VH"}"#] {
+            write(&mut ws, &format!("{{\"cmd\":{}}}", cmd.replace("VH", &embargoed))).await;
+            let (_cmd, data) = expect_cmd_data(&mut ws).await;
+            assert_eq!((data["event_name"].as_str(), data["details"].as_str()), (Some("error"), Some("Embargoed")), "{cmd}");
+        }
+
+        // Unreachable organizer falls back to defaults
+        let port = portpicker::pick_unused_port().unwrap();
+        ts.config.apply(crate::config::ReloadableConfig {
+            organizer: Some(Organizer::new(&format!("http://127.0.0.1:{port}")).unwrap()),
+            ..ts.config.get() }).unwrap();
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{}}"#).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert!(data["videos"].as_array().unwrap().iter().any(|v| v["video_hash"] == hidden.as_str()));
+
+        // ...except access checks, which fail closed unless configured otherwise
+        let open = format!(r#"{{"cmd":"open_video","data":{{"video_hash":"{labeled}"}}}}"#);
+        write(&mut ws, &open).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!((data["event_name"].as_str(), data["details"].as_str()), (Some("error"), Some("Access check failed. Try again later.")));
+        ts.config.apply(crate::config::ReloadableConfig {
+            organizer: Some(Organizer::new(&format!("http://127.0.0.1:{port}")).unwrap().fail_open(true)),
+            ..ts.config.get() }).unwrap();
+        write(&mut ws, &open).await;
+        let (cmd, _data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "open_video");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_scheduled_review()
+{
+    use warp::Filter;
+    use super::review_schedule::{self, review_event};
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+
+        // Webhook gets the ICS invites, e.g. for emailing them
+        let hook_events = Arc::new(std::sync::Mutex::new(vec![]));
+        let hook_events_cln = hook_events.clone();
+        let hook = warp::post().and(warp::body::json()).map(move |ev: serde_json::Value| { hook_events_cln.lock().unwrap().push(ev); "" });
+        let (hook_addr, hook_server) = warp::serve(hook).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(hook_server);
+        ts.config.apply(crate::config::ReloadableConfig {
+            webhook: Some(super::webhook::Webhook::new(&format!("http://{hook_addr}/")).unwrap()), ..ts.config.get() }).unwrap();
+
+        let starts = chrono::Utc::now().timestamp() + 3600;
+        write(&mut ws, &format!(r#"{{"cmd":"schedule_review","data":{{"video_hash":"{vh}","starts":{starts},"invitees":["user.num2","nobody"]}}}}"#)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+        assert!(data["message"].as_str().unwrap().contains("nobody"));
+        write(&mut ws, &format!(r#"{{"cmd":"schedule_review","data":{{"video_hash":"{vh}","starts":{},"invitees":[]}}}}"#, starts - 7200)).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["event_name"], "error");
+
+        write(&mut ws, &format!(r#"{{"cmd":"schedule_review","data":{{"video_hash":"{vh}","starts":{starts},"duration_min":30,"title":"Dailies","invitees":["user.num2"]}}}}"#)).await;
+        let (cmd, session) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "review_session");
+        assert_eq!((session["title"].as_str(), session["duration_min"].as_i64(), session["invitees"].clone()), (Some("Dailies"), Some(30), serde_json::json!(["user.num2"])));
+        assert!(session["ics"].as_str().unwrap().contains("SUMMARY:Dailies"));
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "message");
+        assert_eq!(data["event_name"], review_event::SCHEDULED);
+        assert_eq!(data["details"], session["url"]);
+
+        write(&mut ws2, r#"{"cmd":"list_reviews","data":{}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "review_sessions");
+        assert_eq!(data["sessions"][0]["id"], session["id"]);
+
+        // Only host can cancel
+        let cancel = format!(r#"{{"cmd":"cancel_review","data":{{"id":{}}}}}"#, session["id"]);
+        write(&mut ws2, &cancel).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], "error");
+        write(&mut ws, &cancel).await;
+        let (_cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(data["sessions"], serde_json::json!([]));
+        let (_cmd, data) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(data["event_name"], review_event::CANCELLED);
+
+        for _ in 0..20 {
+            if hook_events.lock().unwrap().len() >= 2 { break; }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        let mut events = hook_events.lock().unwrap().clone();
+        events.sort_by_key(|e| e["event"].as_str().unwrap().to_string());
+        assert_eq!(events.iter().map(|e| e["event"].as_str().unwrap()).collect::<Vec<_>>(), vec![review_event::CANCELLED, review_event::SCHEDULED]);
+        assert_eq!(events[1]["data"]["invitees"][0]["user_id"], "user.num2");
+        assert!(events[1]["data"]["ics"].as_str().unwrap().contains("METHOD:PUBLISH"));
+        assert!(events[0]["data"]["ics"].as_str().unwrap().contains("METHOD:CANCEL"));
+
+        // Collab room is opened when the session starts
+        let s = ts.db.add_review_session(&models::ReviewSessionInsert {
+            collab_id: "sched1".into(), video_hash: vh.clone(), host: "user.num1".into(), host_name: "User Num1".into(),
+            title: "Now".into(), starts: chrono::Utc::now().naive_utc() - chrono::Duration::minutes(1), duration_min: 60,
+        }, &["user.num2".to_string()]).unwrap();
+        let (link, _intake) = crate::video_pipeline::PipelineLink::new(Default::default(), ts.storage.clone());
+        let server = ServerState::new(ts.db.clone(), &ts.opts, link, ts.config.clone(), ts.terminate_flag.clone());
+        assert_eq!(review_schedule::open_due_sessions(&server).unwrap(), 1);
+        assert_eq!(review_schedule::open_due_sessions(&server).unwrap(), 0);
+        assert!(ts.db.get_review_session(s.id).unwrap().opened.is_some());
+        assert_eq!(ts.db.get_collab_room("sched1").unwrap().host, "user.num1");
+        for uid in ["user.num1", "user.num2"] {
+            assert!(ts.db.get_user_messages(uid).unwrap().iter().any(|m| m.event_name == review_event::STARTED && m.details.contains("collab=sched1")));
+        }
+
+        // Joiners keep the scheduled host
+        write(&mut ws2, &format!(r#"{{"cmd":"join_collab","data":{{"collab_id":"sched1","video_hash":"{vh}"}}}}"#)).await;
+        expect_cmd_data(&mut ws2).await;
+        assert_eq!(ts.db.get_collab_room("sched1").unwrap().host, "user.num1");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_session_resume()
+{
+    api_test! {[ws, ts]
+        ts.config.apply(crate::config::ReloadableConfig {
+            sessions: super::session_limits::SessionLimits { resume_window: Some(std::time::Duration::from_secs(60)), ..Default::default() },
+            ..ts.config.get() }).unwrap();
+        let vh = ts.videos[0].video_hash.clone();
+        let seq_of = |msg: &str| serde_json::from_str::<serde_json::Value>(msg).unwrap()["seq"].as_u64();
+
+        // Session watching a video in a collab
+        let mut ws_a = connect_client_ws_raw(&ts.ws_url, "user.num1").await;
+        let (cmd, welcome) = expect_cmd_data(&mut ws_a).await;
+        assert_eq!(cmd, "welcome");
+        let token = welcome["resume_token"].as_str().unwrap().to_string();
+        let (_cmd, v) = open_video(&mut ws_a, &vh).await;
+        assert!(v["seq"].as_u64().unwrap() >= welcome["seq"].as_u64().unwrap());
+        write(&mut ws_a, &format!(r#"{{"cmd":"join_collab","data":{{"collab_id":"c9","video_hash":"{vh}"}}}}"#)).await;
+        let last_seq = seq_of(&expect_msg(&mut ws_a).await).expect("Collab events have seq");
+
+        // Connection drops, and events are missed meanwhile
+        ws_a.close(None).await.unwrap();
+        for _ in 0..20 {
+            if ts.db.get_collab_participants("c9").unwrap().is_empty() { break; }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        let mut ws2 = connect_client_ws(&ts.ws_url, "user.num2").await;
+        write(&mut ws2, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Missed this","timecode":"00:00:01:00"}}}}"#)).await;
+        while read(&mut ws2).await.is_some() {}
+
+        // Another user can't resume it
+        write(&mut ws2, &format!(r#"{{"cmd":"resume","data":{{"token":"{token}","last_seq":{last_seq}}}}}"#)).await;
+        let (cmd, _) = expect_cmd_data(&mut ws2).await;
+        assert_eq!(cmd, "resume_failed");
+
+        // Reconnect and resume: missed events are replayed, then `resumed`
+        let mut ws_b = connect_client_ws(&ts.ws_url, "user.num1").await;
+        write(&mut ws_b, &format!(r#"{{"cmd":"resume","data":{{"token":"{token}","last_seq":{last_seq}}}}}"#)).await;
+        let mut replayed = vec![];
+        loop {
+            let msg = expect_msg(&mut ws_b).await;
+            let p: serde_json::Value = serde_json::from_str(&msg).unwrap();
+            if p["cmd"] == "resumed" {
+                assert_eq!((p["data"]["video_hash"].as_str(), p["data"]["collab_id"].as_str()), (Some(vh.as_str()), Some("c9")));
+                assert_eq!(p["data"]["replayed"].as_u64(), Some(replayed.len() as u64));
+                break;
+            }
+            assert!(seq_of(&msg).unwrap() > last_seq);
+            replayed.push(p);
+        }
+        assert!(replayed.iter().any(|p| p["cmd"] == "new_comment" && p["data"]["comment"] == "Missed this"));
+        assert_eq!(ts.db.get_collab_participants("c9").unwrap().len(), 1);
+
+        // Video and collab are rejoined, new events come live
+        write(&mut ws2, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Live","timecode":"00:00:02:00"}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_b).await;
+        assert_eq!((cmd.as_str(), data["comment"].as_str()), (Some("new_comment"), Some("Live")));
+
+        // Tokens work once
+        let mut ws_c = connect_client_ws(&ts.ws_url, "user.num1").await;
+        write(&mut ws_c, &format!(r#"{{"cmd":"resume","data":{{"token":"{token}","last_seq":{last_seq}}}}}"#)).await;
+        let (cmd, data) = expect_cmd_data(&mut ws_c).await;
+        assert_eq!(cmd, "resume_failed");
+        assert!(data["reason"].as_str().unwrap().contains("expired"));
+        drop(ws);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_protocol_negotiation()
+{
+    api_test! {[ws, ts]
+        let vh = ts.videos[0].video_hash.clone();
+
+        // Client that doesn't negotiate gets everything
+        let mut ws_full = connect_client_ws_raw(&ts.ws_url, "user.num1").await;
+        let (_cmd, welcome) = expect_cmd_data(&mut ws_full).await;
+        assert_eq!(welcome["protocol"]["version"], super::protocol::PROTOCOL_VERSION);
+        assert_eq!(welcome["protocol"]["capabilities"].as_array().unwrap().len(), super::protocol::CAPABILITIES.len());
+
+        // Older client with fewer capabilities
+        let mut ws_old = connect_client_ws_raw(&format!("{}?proto=1&caps=reactions", ts.ws_url), "user.num1").await;
+        let (cmd, welcome) = expect_cmd_data(&mut ws_old).await;
+        assert_eq!(cmd, "welcome");
+        assert_eq!(welcome["protocol"]["capabilities"], serde_json::json!(["reactions"]));
+        assert!(welcome.get("resume_token").is_none() && welcome.get("seq").is_none());
+        open_video(&mut ws_old, &vh).await;
+
+        write(&mut ws, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Hello","timecode":"00:00:01:00"}}}}"#)).await;
+        let msg: serde_json::Value = serde_json::from_str(&expect_msg(&mut ws_old).await).unwrap();
+        assert_eq!(msg["cmd"], "new_comment");
+        assert_eq!(msg["data"]["comment"], "Hello");
+        assert!(msg["data"].get("reactions").is_some());
+        assert!(msg["data"].get("attachments").is_none() && msg.get("seq").is_none());
+
+        // Too old client is refused
+        let mut ws_ancient = connect_client_ws_raw(&format!("{}?proto=0", ts.ws_url), "user.num1").await;
+        let (cmd, data) = expect_cmd_data(&mut ws_ancient).await;
+        assert_eq!(cmd, "error");
+        assert!(data["message"].as_str().unwrap().contains("upgrade"));
+        drop(ws_full);
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_ws_compression()
+{
+    api_test! {[ws, ts]
+        use futures_util::StreamExt;
+        let vh = ts.videos[0].video_hash.clone();
+        for i in 0..10 {
+            let text = format!("Note {i}: {}", "the grade is too warm in this shot, ".repeat(40));
+            write(&mut ws, &serde_json::json!({"cmd": "add_comment", "data": {"video_hash": vh, "comment": text, "timecode": "00:00:01:00"}}).to_string()).await;
+        }
+        while read(&mut ws).await.is_some() {}
+
+        let mut ws_gz = connect_client_ws_raw(&format!("{}?compress=gzip", ts.ws_url), "user.num1").await;
+        let (cmd, welcome) = expect_cmd_data(&mut ws_gz).await;
+        assert_eq!(cmd, "welcome");
+        assert_eq!(welcome["protocol"]["compression"], "gzip");
+
+        // Large reply comes gzipped in a binary frame
+        let list_cmd = serde_json::json!({"cmd": "list_comments", "data": {"video_hash": vh, "limit": 100}}).to_string();
+        write(&mut ws_gz, &list_cmd).await;
+        let msg = tokio::time::timeout(std::time::Duration::from_secs(2), ws_gz.next()).await.unwrap().unwrap().unwrap();
+        let gz = match msg {
+            tokio_tungstenite::tungstenite::Message::Binary(b) => b,
+            other => panic!("Expected binary message, got {other:?}"),
+        };
+        let json = super::compression::gunzip(&gz);
+        let reply: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(reply["cmd"], "comments_page");
+        assert!(reply["data"]["comments"].as_array().unwrap().len() >= 10);
+        assert!(gz.len() * 3 < json.len());
+
+        // Small ones stay text, and clients that didn't ask get text
+        write(&mut ws_gz, r#"{"cmd":"echo","data":"hello"}"#).await;
+        assert_eq!(expect_msg(&mut ws_gz).await, "Echo: hello");
+        write(&mut ws, &list_cmd).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.0, "comments_page");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_msgpack_format()
+{
+    api_test! {[ws, ts]
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+        use super::msgpack;
+        let vh = ts.videos[0].video_hash.clone();
+
+        async fn next_msgpack(ws: &mut crate::api_server::test_utils::WsClient) -> serde_json::Value {
+            match tokio::time::timeout(std::time::Duration::from_secs(2), ws.next()).await.unwrap().unwrap().unwrap() {
+                WsMessage::Binary(b) => msgpack::decode(&b).unwrap(),
+                other => panic!("Expected binary message, got {other:?}"),
+            }
+        }
+
+        let mut ws_mp = connect_client_ws_raw(&format!("{}?format=msgpack", ts.ws_url), "user.num1").await;
+        let welcome = next_msgpack(&mut ws_mp).await;
+        assert_eq!(welcome["cmd"], "welcome");
+        assert_eq!(welcome["data"]["protocol"]["format"], "msgpack");
+
+        // Commands in MessagePack, replies too
+        let open = serde_json::json!({"cmd": "open_video", "data": {"video_hash": vh}});
+        ws_mp.send(WsMessage::Binary(msgpack::encode(&open))).await.unwrap();
+        let reply = next_msgpack(&mut ws_mp).await;
+        assert_eq!((reply["cmd"].as_str(), reply["data"]["video_hash"].as_str()), (Some("open_video"), Some(vh.as_str())));
+        while read(&mut ws_mp).await.is_some() {}
+
+        // Broadcasts from JSON clients arrive in MessagePack
+        write(&mut ws, &format!(r#"{{"cmd":"add_comment","data":{{"video_hash":"{vh}","comment":"Packed","timecode":"00:00:01:00"}}}}"#)).await;
+        let msg = next_msgpack(&mut ws_mp).await;
+        assert_eq!((msg["cmd"].as_str(), msg["data"]["comment"].as_str()), (Some("new_comment"), Some("Packed")));
+
+        // Bad MessagePack is an invalid message
+        ws_mp.send(WsMessage::Binary(vec![0xc1])).await.unwrap();
+        let (cmd, _) = expect_cmd_data(&mut ws_mp).await;
+        assert_eq!(cmd, "error");
+
+        // Unsupported format falls back to JSON
+        let mut ws_cbor = connect_client_ws_raw(&format!("{}?format=cbor", ts.ws_url), "user.num1").await;
+        let (cmd, welcome) = expect_cmd_data(&mut ws_cbor).await;
+        assert_eq!(cmd, "welcome");
+        assert_eq!(welcome["protocol"]["format"], "json");
+    }
+}
+
+#[tokio::test]
+#[traced_test]
+async fn test_api_video_list_deltas()
+{
+    api_test! {[ws, ts]
+        async fn expect_delta(ws: &mut crate::api_server::test_utils::WsClient, want: &str) -> serde_json::Value {
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(4);
+            while std::time::Instant::now() < deadline {
+                if let Some((cmd, data)) = read_cmd_data(ws).await {
+                    if cmd == want { return data; }
+                    assert!(!cmd.as_str().unwrap().starts_with("video_"), "Unexpected delta {cmd}: {data}");
+                }
+            }
+            panic!("No {want}");
+        }
+        let own = ts.videos.iter().filter(|v| v.added_by_userid.as_deref() == Some("user.num1")).collect::<Vec<_>>();
+        let others = ts.videos.iter().find(|v| v.added_by_userid.as_deref() == Some("user.num2")).unwrap();
+
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{"subscribe":true}}"#).await;
+        let (cmd, data) = expect_cmd_data(&mut ws).await;
+        assert_eq!(cmd, "user_videos");
+        assert_eq!(data["videos"].as_array().unwrap().len(), own.len());
+
+        // Listing without subscribing gets no deltas
+        let mut ws_plain = connect_client_ws(&ts.ws_url, "user.num1").await;
+        write(&mut ws_plain, r#"{"cmd":"list_my_videos","data":{}}"#).await;
+        assert_eq!(expect_cmd_data(&mut ws_plain).await.0, "user_videos");
+
+        // Changes by any writer come as deltas
+        write(&mut ws, &format!(r#"{{"cmd":"rename_video","data":{{"video_hash":"{}","new_name":"Renamed"}}}}"#, own[0].video_hash)).await;
+        let data = expect_delta(&mut ws, "video_updated").await;
+        assert_eq!((data["video"]["video_hash"].as_str(), data["video"]["title"].as_str()), (Some(own[0].video_hash.as_str()), Some("Renamed")));
+
+        ts.db.add_video_link("user.num1", &others.video_hash).unwrap();
+        let data = expect_delta(&mut ws, "video_added").await;
+        assert_eq!(data["video"]["video_hash"], others.video_hash);
+        assert_eq!(data["video"]["linked"], true);
+        assert!(data["index"].is_u64());
+
+        write(&mut ws, &format!(r#"{{"cmd":"del_video","data":{{"video_hash":"{}"}}}}"#, own[1].video_hash)).await;
+        let data = expect_delta(&mut ws, "video_removed").await;
+        assert_eq!(data["video_hash"], own[1].video_hash);
+
+        // Changes to other users' videos don't concern this list
+        ts.db.rename_video(&ts.videos.iter().filter(|v| v.added_by_userid.as_deref() == Some("user.num2")).nth(1).unwrap().video_hash, "Not mine").unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        while let Some((cmd, _)) = read_cmd_data(&mut ws).await {
+            assert!(!cmd.as_str().unwrap().starts_with("video_"));
+        }
+        while let Some((cmd, _)) = read_cmd_data(&mut ws_plain).await {
+            assert!(!cmd.as_str().unwrap().starts_with("video_"), "Not subscribed, got {cmd}");
+        }
+
+        // Listing again without `subscribe` ends deltas
+        write(&mut ws, r#"{"cmd":"list_my_videos","data":{}}"#).await;
+        assert_eq!(expect_cmd_data(&mut ws).await.0, "user_videos");
+        ts.db.rename_video(&own[0].video_hash, "Again").unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        while let Some((cmd, _)) = read_cmd_data(&mut ws).await {
+            assert!(!cmd.as_str().unwrap().starts_with("video_"));
+        }
     }
 }
@@ -0,0 +1,617 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::sync::atomic::Ordering::Relaxed;
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::database::{models, DB};
+use crate::database::error::DBError;
+use super::server_state::ServerState;
+
+// Bridge to production trackers: comments are pushed as notes on the ShotGrid or ftrack entity
+// (e.g. a Version) that the video is mapped to by a custom field, and replies to those notes can
+// be pulled back as replies to the comment. Configured with `--tracker-sync FILE` (JSON, reloadable):
+//
+//   {"kind": "shotgrid", "url": "https://studio.shotgrid.autodesk.com", "login": SCRIPT_NAME,
+//    "api_key": SCRIPT_KEY, "entity_field": "sg_version", "pull_replies": true}
+//
+// ShotGrid authenticates with OAuth client credentials (script name and key), ftrack with
+// API user and key. Pushes go through a queue in the DB (`tracker_outbox`), so comments made while
+// the tracker is down are retried with backoff. Edits and deletions aren't synced.
+
+/// How often queued comments are pushed
+const PUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often replies are pulled
+const PULL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Replies are pulled to notes pushed within this many days
+const PULL_DAYS: i64 = 30;
+
+/// Failed pushes are given up after this many attempts (about a day with backoff)
+const MAX_ATTEMPTS: i32 = 30;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackerKind {
+    ShotGrid,
+    Ftrack,
+}
+
+impl TrackerKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrackerKind::ShotGrid => "shotgrid",
+            TrackerKind::Ftrack => "ftrack",
+        }
+    }
+}
+
+/// Production tracker to sync comments with
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TrackerSync {
+    pub kind: TrackerKind,
+    /// Site URL, e.g. https://studio.shotgrid.autodesk.com
+    pub url: String,
+    /// ShotGrid script name, or ftrack API user
+    pub login: String,
+    pub api_key: String,
+    /// Custom field (see `DB::get_custom_fields`) that holds the tracker entity ID of a video
+    pub entity_field: String,
+    /// Tracker entity type. Default "Version" (ShotGrid) or "AssetVersion" (ftrack).
+    #[serde(default)]
+    pub entity_type: Option<String>,
+    /// Pull replies to pushed notes back as comments
+    #[serde(default)]
+    pub pull_replies: bool,
+    /// Push internal comments too
+    #[serde(default)]
+    pub include_internal: bool,
+}
+
+impl std::fmt::Debug for TrackerSync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // No API key in logs
+        f.debug_struct("TrackerSync").field("kind", &self.kind).field("url", &self.url)
+            .field("login", &self.login).field("entity_field", &self.entity_field).finish_non_exhaustive()
+    }
+}
+
+impl TrackerSync {
+    pub fn from_file(path: &Path) -> anyhow::Result<TrackerSync> {
+        let json = std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to read '{}': {}", path.display(), e))?;
+        TrackerSync::from_json(&json)
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<TrackerSync> {
+        let mut res: TrackerSync = serde_json::from_str(json)?;
+        let url = reqwest::Url::parse(&res.url).map_err(|e| anyhow!("Invalid tracker URL '{}': {}", res.url, e))?;
+        if !["http", "https"].contains(&url.scheme()) { bail!("Tracker URL must be http or https: '{}'", res.url); }
+        res.url = res.url.trim_end_matches('/').to_string();
+        for (name, val) in [("login", &res.login), ("api_key", &res.api_key), ("entity_field", &res.entity_field)] {
+            if val.trim().is_empty() { bail!("Missing '{name}'"); }
+        }
+        Ok(res)
+    }
+
+    pub fn entity_type(&self) -> &str {
+        match (&self.entity_type, self.kind) {
+            (Some(t), _) => t,
+            (None, TrackerKind::ShotGrid) => "Version",
+            (None, TrackerKind::Ftrack) => "AssetVersion",
+        }
+    }
+}
+
+/// Tracker entity ID of a video, from its custom field
+fn entity_id(db: &DB, cfg: &TrackerSync, vh: &str) -> Result<Option<String>, DBError> {
+    let vals = db.get_video_custom_fields(&[vh.to_string()])?.remove(vh).unwrap_or_default();
+    Ok(match vals.get(&cfg.entity_field) {
+        Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+        Some(serde_json::Value::String(s)) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        _ => None,
+    })
+}
+
+/// Queue a new comment for pushing, if its video is mapped to a tracker entity.
+///
+/// # Returns
+/// * `bool` - True if queued
+pub fn queue_comment(db: &DB, cfg: &TrackerSync, c: &models::Comment) -> Result<bool, DBError> {
+    if (c.is_internal() && !cfg.include_internal) || entity_id(db, cfg, &c.video_hash)?.is_none() {
+        return Ok(false);
+    }
+    db.add_tracker_push(c.id, &c.video_hash)?;
+    Ok(true)
+}
+
+/// Queue a new comment for pushing to the configured tracker, if any.
+pub fn enqueue(server: &ServerState, c: &models::Comment) {
+    if let Some(cfg) = server.config.tracker() {
+        if let Err(e) = queue_comment(&server.db, &cfg, c) {
+            tracing::error!(comment=c.id, details=%e, "Failed to queue comment for tracker sync.");
+        }
+    }
+}
+
+/// Reply to a note, in the tracker
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub id: String,
+    /// Author's login
+    pub author_id: String,
+    /// Author's name
+    pub author: String,
+    pub content: String,
+    pub created: Option<chrono::NaiveDateTime>,
+}
+
+/// Connection to a tracker, with cached credentials
+pub struct TrackerClient {
+    pub cfg: TrackerSync,
+    http: reqwest::blocking::Client,
+    /// ShotGrid access token, and when to renew it
+    token: Option<(String, Instant)>,
+    /// ftrack user ID of the API user
+    ftrack_user_id: Option<String>,
+}
+
+impl TrackerClient {
+    pub fn new(cfg: TrackerSync) -> anyhow::Result<TrackerClient> {
+        let http = reqwest::blocking::Client::builder().timeout(Duration::from_secs(30)).build()?;
+        Ok(TrackerClient { cfg, http, token: None, ftrack_user_id: None })
+    }
+
+    /// Create a note on an entity.
+    ///
+    /// # Returns
+    /// * `String` - ID of the note in the tracker
+    pub fn push_note(&mut self, entity_id: &str, subject: &str, content: &str) -> anyhow::Result<String> {
+        match self.cfg.kind {
+            TrackerKind::ShotGrid => self.sg_push_note(entity_id, subject, content),
+            TrackerKind::Ftrack => self.ftrack_push_note(entity_id, subject, content),
+        }
+    }
+
+    /// Get replies to a note, oldest first
+    pub fn fetch_replies(&mut self, note_id: &str) -> anyhow::Result<Vec<Reply>> {
+        match self.cfg.kind {
+            TrackerKind::ShotGrid => self.sg_fetch_replies(note_id),
+            TrackerKind::Ftrack => self.ftrack_fetch_replies(note_id),
+        }
+    }
+
+    // ShotGrid REST API ------------------------------------------------------
+
+    fn sg_token(&mut self) -> anyhow::Result<String> {
+        if let Some((token, renew_at)) = &self.token {
+            if Instant::now() < *renew_at { return Ok(token.clone()); }
+        }
+        let res: serde_json::Value = self.http.post(format!("{}/api/v1/auth/access_token", self.cfg.url))
+            .header("Accept", "application/json")
+            .form(&[("grant_type", "client_credentials"), ("client_id", &self.cfg.login), ("client_secret", &self.cfg.api_key)])
+            .send()?.error_for_status().context("ShotGrid authentication failed")?.json()?;
+        let token = res["access_token"].as_str().ok_or(anyhow!("No access token from ShotGrid"))?.to_string();
+        let ttl = res["expires_in"].as_u64().unwrap_or(600).saturating_sub(60);
+        self.token = Some((token.clone(), Instant::now() + Duration::from_secs(ttl)));
+        Ok(token)
+    }
+
+    fn sg_request(&mut self, req: reqwest::blocking::RequestBuilder) -> anyhow::Result<serde_json::Value> {
+        let res = req.bearer_auth(self.sg_token()?).header("Accept", "application/json").send()?;
+        if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+            self.token = None;  // Expired early? Get a new one next time.
+        }
+        if !res.status().is_success() {
+            let status = res.status();
+            bail!("ShotGrid replied {}: {}", status, res.text().unwrap_or_default());
+        }
+        Ok(res.json()?)
+    }
+
+    fn sg_push_note(&mut self, entity_id: &str, subject: &str, content: &str) -> anyhow::Result<String> {
+        let id = entity_id.parse::<i64>().map_err(|_| anyhow!("ShotGrid entity ID must be a number, not '{entity_id}'"))?;
+        let etype = self.cfg.entity_type().to_string();
+        // Notes must be in the entity's project
+        let ent = self.sg_request(self.http.get(format!("{}/api/v1/entity/{}/{id}?fields=project", self.cfg.url, sg_collection(&etype))))?;
+        let project = ent["data"]["relationships"]["project"]["data"].clone();
+        if !project.is_object() { bail!("ShotGrid {etype} {id} has no project"); }
+        let res = self.sg_request(self.http.post(format!("{}/api/v1/entity/notes", self.cfg.url)).json(&json!({
+            "subject": subject,
+            "content": content,
+            "project": { "type": project["type"], "id": project["id"] },
+            "note_links": [{ "type": etype, "id": id }],
+        })))?;
+        res["data"]["id"].as_i64().map(|id| id.to_string()).ok_or(anyhow!("No note ID from ShotGrid"))
+    }
+
+    fn sg_fetch_replies(&mut self, note_id: &str) -> anyhow::Result<Vec<Reply>> {
+        let note_id = note_id.parse::<i64>()?;
+        let body = json!({
+            "filters": [["entity", "is", { "type": "Note", "id": note_id }]],
+            "fields": ["content", "user", "created_at"],
+        });
+        let res = self.sg_request(self.http.post(format!("{}/api/v1/entity/replies/_search?sort=created_at", self.cfg.url))
+            .header("Content-Type", "application/vnd+shotgun.api3_array+json").body(body.to_string()))?;
+        Ok(res["data"].as_array().into_iter().flatten().filter_map(|r| {
+            let user = &r["relationships"]["user"]["data"];
+            Some(Reply {
+                id: r["id"].as_i64()?.to_string(),
+                author_id: format!("{}-{}", user["type"].as_str().unwrap_or("user"), user["id"]),
+                author: user["name"].as_str().unwrap_or("ShotGrid user").to_string(),
+                content: r["attributes"]["content"].as_str()?.to_string(),
+                created: r["attributes"]["created_at"].as_str().and_then(parse_time),
+            })
+        }).collect())
+    }
+
+    // ftrack API --------------------------------------------------------------
+
+    fn ftrack_call(&self, ops: serde_json::Value) -> anyhow::Result<Vec<serde_json::Value>> {
+        let res: serde_json::Value = self.http.post(format!("{}/api", self.cfg.url))
+            .header("ftrack-user", &self.cfg.login)
+            .header("ftrack-api-key", &self.cfg.api_key)
+            .json(&ops).send()?.error_for_status()?.json()?;
+        if let Some(exc) = res["exception"].as_str() {
+            bail!("ftrack {}: {}", exc, res["content"].as_str().unwrap_or_default());
+        }
+        res.as_array().cloned().ok_or(anyhow!("Unexpected reply from ftrack"))
+    }
+
+    fn ftrack_query(&self, expression: String) -> anyhow::Result<Vec<serde_json::Value>> {
+        let res = self.ftrack_call(json!([{ "action": "query", "expression": expression }]))?;
+        Ok(res.first().and_then(|r| r["data"].as_array()).cloned().unwrap_or_default())
+    }
+
+    fn ftrack_push_note(&mut self, entity_id: &str, subject: &str, content: &str) -> anyhow::Result<String> {
+        let user_id = match &self.ftrack_user_id {
+            Some(uid) => uid.clone(),
+            None => {
+                let users = self.ftrack_query(format!("select id from User where username is \"{}\"", ftrack_escape(&self.cfg.login)))?;
+                let uid = users.first().and_then(|u| u["id"].as_str()).ok_or(anyhow!("ftrack user '{}' not found", self.cfg.login))?.to_string();
+                self.ftrack_user_id = Some(uid.clone());
+                uid
+            }
+        };
+        let res = self.ftrack_call(json!([{
+            "action": "create",
+            "entity_type": "Note",
+            "entity_data": {
+                "content": format!("**{subject}**\n\n{content}"),
+                "parent_id": entity_id,
+                "parent_type": self.cfg.entity_type(),
+                "user_id": user_id,
+            },
+        }]))?;
+        res.first().and_then(|r| r["data"]["id"].as_str()).map(String::from).ok_or(anyhow!("No note ID from ftrack"))
+    }
+
+    fn ftrack_fetch_replies(&mut self, note_id: &str) -> anyhow::Result<Vec<Reply>> {
+        let replies = self.ftrack_query(format!(
+            "select id, content, date, author.username, author.first_name, author.last_name from Note where in_reply_to_id is \"{}\" order by date",
+            ftrack_escape(note_id)))?;
+        Ok(replies.iter().filter_map(|r| {
+            let a = &r["author"];
+            let username = a["username"].as_str().unwrap_or("ftrack user");
+            let name = format!("{} {}", a["first_name"].as_str().unwrap_or_default(), a["last_name"].as_str().unwrap_or_default());
+            Some(Reply {
+                id: r["id"].as_str()?.to_string(),
+                author_id: username.to_string(),
+                author: if name.trim().is_empty() { username.to_string() } else { name.trim().to_string() },
+                content: r["content"].as_str()?.to_string(),
+                created: r["date"]["value"].as_str().or(r["date"].as_str()).and_then(parse_time),
+            })
+        }).collect())
+    }
+}
+
+/// ShotGrid REST collection name for an entity type, e.g. "Version" -> "versions",
+/// "CustomEntity01" -> "custom_entity01s"
+fn sg_collection(entity_type: &str) -> String {
+    let mut res = String::new();
+    for (i, c) in entity_type.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 { res.push('_'); }
+        res.push(c.to_ascii_lowercase());
+    }
+    res + "s"
+}
+
+fn ftrack_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Parse a time from tracker (RFC 3339, or without offset for UTC)
+fn parse_time(s: &str) -> Option<chrono::NaiveDateTime> {
+    chrono::DateTime::parse_from_rfc3339(s).map(|t| t.naive_utc()).ok()
+        .or_else(|| chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f").ok())
+}
+
+/// Wait before next attempt, after `attempts` failed ones: 30 s, doubling up to an hour
+fn retry_delay(attempts: i32) -> Duration {
+    Duration::from_secs(30u64.saturating_mul(1 << attempts.clamp(0, 7)).min(3600))
+}
+
+/// Note subject and content for a comment
+fn note_text(v: &models::Video, c: &models::Comment, url_base: &str) -> (String, String) {
+    let title = v.title.as_deref().unwrap_or(&v.video_hash);
+    let at = c.timecode.as_ref().map(|tc| format!(" at {tc}")).unwrap_or_default();
+    let content = format!("{} commented{at}:\n{}\n\n{url_base}/?vid={}", c.username, c.comment, v.video_hash);
+    (format!("Clapshot: {title}"), content)
+}
+
+/// Push queued comments that are due.
+///
+/// # Returns
+/// Number of comments pushed
+pub fn push_queued(db: &DB, client: &mut TrackerClient, url_base: &str) -> Result<usize, DBError>
+{
+    let mut n_pushed = 0;
+    for item in db.get_tracker_pushes(true)? {
+        let c = match db.get_comment(item.comment_id) {
+            Ok(c) => c,
+            Err(DBError::NotFound()) => { db.del_tracker_push(item.id)?; continue; },  // Deleted meanwhile
+            Err(e) => return Err(e),
+        };
+        let res = (|| {
+            let v = db.get_video(&item.video_hash)?;
+            let eid = entity_id(db, &client.cfg, &v.video_hash)?.ok_or(anyhow!("Video is no longer mapped to a tracker entity"))?;
+            let (subject, content) = note_text(&v, &c, url_base);
+            client.push_note(&eid, &subject, &content)
+        })();
+        match res {
+            Ok(remote_id) => {
+                db.add_tracker_note(&models::TrackerNoteInsert {
+                    comment_id: c.id,
+                    video_hash: c.video_hash.clone(),
+                    tracker: client.cfg.kind.as_str().into(),
+                    remote_id,
+                    reply_to: None,
+                })?;
+                db.del_tracker_push(item.id)?;
+                n_pushed += 1;
+            },
+            Err(e) if item.attempts + 1 >= MAX_ATTEMPTS => {
+                tracing::error!(comment=c.id, details=%e, "Giving up pushing comment to tracker.");
+                db.del_tracker_push(item.id)?;
+            },
+            Err(e) => {
+                tracing::warn!(comment=c.id, attempt=item.attempts+1, details=%e, "Pushing comment to tracker failed. Will retry.");
+                let retry_at = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(retry_delay(item.attempts).as_secs() as i64);
+                db.set_tracker_push_failed(item.id, retry_at, &e.to_string())?;
+            },
+        }
+    }
+    Ok(n_pushed)
+}
+
+/// Pull new replies to pushed notes, as replies to their comments.
+///
+/// # Returns
+/// Number of replies added
+pub fn pull_replies(db: &DB, client: &mut TrackerClient) -> Result<usize, DBError>
+{
+    let tracker = client.cfg.kind.as_str();
+    let since = chrono::Utc::now().naive_utc() - chrono::Duration::days(PULL_DAYS);
+    let mut n_added = 0;
+    for note in db.get_pushed_tracker_notes(tracker, since)? {
+        let replies = match client.fetch_replies(&note.remote_id) {
+            Ok(r) => r,
+            Err(e) => {
+                tracing::warn!(note=note.remote_id, details=%e, "Failed to get note replies from tracker.");
+                continue;
+            }
+        };
+        for r in replies {
+            if db.get_tracker_note_by_remote_id(tracker, &r.id)?.is_some() { continue; }
+            let parent = db.get_comment(note.comment_id)?;
+            let created = r.created.unwrap_or_else(|| chrono::Utc::now().naive_utc());
+            let cid = db.add_imported_comment(&models::CommentInsert {
+                video_hash: note.video_hash.clone(),
+                parent_id: Some(parent.id),
+                user_id: format!("{tracker}:{}", r.author_id),
+                username: r.author.clone(),
+                comment: r.content.clone(),
+                timecode: parent.timecode.clone(),
+                timecode_end: None,
+                drawing: None,
+                page: parent.page,
+                region: None,
+                visibility: parent.visibility.clone(),
+            }, created, None)?;
+            db.add_tracker_note(&models::TrackerNoteInsert {
+                comment_id: cid,
+                video_hash: note.video_hash.clone(),
+                tracker: tracker.into(),
+                remote_id: r.id,
+                reply_to: Some(note.remote_id.clone()),
+            })?;
+            n_added += 1;
+        }
+    }
+    Ok(n_added)
+}
+
+/// Push queued comments and pull replies periodically, until server terminates
+pub fn run_tracker_loop(server: ServerState)
+{
+    let _span = tracing::info_span!("TRACKER_SYNC").entered();
+    let mut client: Option<TrackerClient> = None;
+    let (mut last_push, mut last_pull): (Option<Instant>, Option<Instant>) = (None, None);
+    while !server.terminate_flag.load(Relaxed) {
+        match server.config.tracker() {
+            None => { client = None; },
+            Some(cfg) => {
+                if client.as_ref().is_none_or(|c| c.cfg != cfg) {
+                    client = TrackerClient::new(cfg).map_err(|e| tracing::error!(details=%e, "Failed to create tracker client.")).ok();
+                }
+                if let Some(c) = client.as_mut() {
+                    if last_push.is_none_or(|t| t.elapsed() >= PUSH_INTERVAL) {
+                        last_push = Some(Instant::now());
+                        match push_queued(&server.db, c, &server.url_base) {
+                            Ok(n) if n > 0 => tracing::info!(n_pushed=n, "Comments pushed to tracker."),
+                            Ok(_) => {},
+                            Err(e) => tracing::error!(details=%e, "Failed to push comments to tracker."),
+                        }
+                    }
+                    if c.cfg.pull_replies && last_pull.is_none_or(|t| t.elapsed() >= PULL_INTERVAL) {
+                        last_pull = Some(Instant::now());
+                        match pull_replies(&server.db, c) {
+                            Ok(n) if n > 0 => tracing::info!(n_replies=n, "Replies pulled from tracker."),
+                            Ok(_) => {},
+                            Err(e) => tracing::error!(details=%e, "Failed to pull replies from tracker."),
+                        }
+                    }
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[cfg(test)]
+fn map_video_to_entity(db: &DB, vh: &str, field: &str, value: serde_json::Value) {
+    db.set_custom_field(&models::CustomField { name: field.into(), title: field.into(),
+        field_type: models::custom_field_type::TEXT.into(), options: None }).unwrap();
+    db.update_video_custom_fields(vh, &serde_json::from_value(json!({ field: value })).unwrap()).unwrap();
+}
+
+#[test]
+fn test_tracker_config()
+{
+    assert!(TrackerSync::from_json(r#"{"kind": "jira", "url": "https://x", "login": "a", "api_key": "b", "entity_field": "c"}"#).is_err());
+    assert!(TrackerSync::from_json(r#"{"kind": "ftrack", "url": "ftp://x", "login": "a", "api_key": "b", "entity_field": "c"}"#).is_err());
+    assert!(TrackerSync::from_json(r#"{"kind": "ftrack", "url": "https://x", "login": "a", "api_key": "", "entity_field": "c"}"#).is_err());
+    let cfg = TrackerSync::from_json(r#"{"kind": "shotgrid", "url": "https://sg.example/", "login": "clapshot", "api_key": "s3cret", "entity_field": "sg_version"}"#).unwrap();
+    assert_eq!((cfg.url.as_str(), cfg.entity_type(), cfg.pull_replies), ("https://sg.example", "Version", false));
+    assert!(!format!("{cfg:?}").contains("s3cret"));
+
+    assert_eq!(sg_collection("Version"), "versions");
+    assert_eq!(sg_collection("CustomEntity01"), "custom_entity01s");
+    assert_eq!(retry_delay(0), Duration::from_secs(30));
+    assert_eq!(retry_delay(2), Duration::from_secs(120));
+    assert_eq!(retry_delay(20), Duration::from_secs(3600));
+    assert_eq!(parse_time("2026-10-16T12:00:00Z"), parse_time("2026-10-16T12:00:00"));
+}
+
+#[tokio::test]
+async fn test_tracker_sync_ftrack()
+{
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    let (db, _data_dir, videos, comments) = crate::database::tests::make_test_db();
+    let vh = videos[0].video_hash.clone();
+    map_video_to_entity(&db, &vh, "ftrack_id", json!("av-123"));
+
+    // Fake ftrack: fails once, then creates notes and has one reply
+    let calls = Arc::new(Mutex::new(vec![]));
+    let calls_cln = calls.clone();
+    let api = warp::post().and(warp::path("api")).and(warp::header::<String>("ftrack-api-key")).and(warp::body::json())
+        .map(move |key: String, ops: serde_json::Value| {
+            assert_eq!(key, "k3y");
+            let op = ops[0].clone();
+            let mut calls = calls_cln.lock().unwrap();
+            calls.push(op.clone());
+            let res = match (op["action"].as_str().unwrap(), op["expression"].as_str().unwrap_or_default()) {
+                _ if calls.len() == 1 => json!({ "exception": "ServerError", "content": "Temporarily unavailable" }),
+                ("query", e) if e.contains("from User") => json!([{ "action": "query", "data": [{ "id": "user-1" }] }]),
+                ("create", _) => json!([{ "action": "create", "data": { "id": "note-1", "__entity_type__": "Note" } }]),
+                ("query", e) if e.contains("in_reply_to_id is \"note-1\"") => json!([{ "action": "query", "data": [{
+                    "id": "reply-1", "content": "Fixed in v4", "date": { "__type__": "datetime", "value": "2026-10-16T12:00:00" },
+                    "author": { "username": "jane", "first_name": "Jane", "last_name": "Doe" } }] }]),
+                _ => json!([{ "action": "query", "data": [] }]),
+            };
+            warp::reply::json(&res)
+        });
+    let (addr, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let cfg = TrackerSync::from_json(&format!(r#"{{"kind": "ftrack", "url": "http://{addr}", "login": "clapshot",
+        "api_key": "k3y", "entity_field": "ftrack_id", "pull_replies": true}}"#)).unwrap();
+
+    // Only comments on mapped videos are queued
+    assert!(queue_comment(&db, &cfg, &comments[0]).unwrap());
+    assert!(!queue_comment(&db, &cfg, comments.iter().find(|c| c.video_hash != vh).unwrap()).unwrap());
+
+    let db_cln = db.clone();
+    let (n_pushed, n_retried, n_replies) = tokio::task::spawn_blocking(move || {
+        let mut client = TrackerClient::new(cfg).unwrap();
+        let n_failed = push_queued(&db_cln, &mut client, "https://clap.example").unwrap();
+        let item = db_cln.get_tracker_pushes(false).unwrap().remove(0);
+        assert_eq!((n_failed, item.attempts), (0, 1));
+        assert!(item.last_error.unwrap().contains("Temporarily unavailable"));
+        assert!(db_cln.get_tracker_pushes(true).unwrap().is_empty(), "Not due before backoff");
+        assert_eq!(db_cln.retry_tracker_pushes_now().unwrap(), 1);
+        let n_pushed = push_queued(&db_cln, &mut client, "https://clap.example").unwrap();
+        let n_replies = pull_replies(&db_cln, &mut client).unwrap();
+        (n_pushed, db_cln.get_tracker_pushes(false).unwrap().len(), (n_replies, pull_replies(&db_cln, &mut client).unwrap()))
+    }).await.unwrap();
+    assert_eq!((n_pushed, n_retried, n_replies), (1, 0, (1, 0)));
+
+    let create = calls.lock().unwrap().iter().find(|op| op["action"] == "create").cloned().unwrap();
+    assert_eq!(create["entity_data"]["parent_id"], "av-123");
+    assert_eq!(create["entity_data"]["parent_type"], "AssetVersion");
+    assert_eq!(create["entity_data"]["user_id"], "user-1");
+    assert!(create["entity_data"]["content"].as_str().unwrap().contains(&comments[0].comment));
+
+    let reply = db.get_video_comments(&vh).unwrap().into_iter().find(|c| c.comment == "Fixed in v4").unwrap();
+    assert_eq!((reply.parent_id, reply.username.as_str(), reply.user_id.as_str()), (Some(comments[0].id), "Jane Doe", "ftrack:jane"));
+}
+
+#[tokio::test]
+async fn test_tracker_sync_shotgrid()
+{
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    let (db, _data_dir, videos, comments) = crate::database::tests::make_test_db();
+    map_video_to_entity(&db, &videos[0].video_hash, "sg_version", json!(6789));
+
+    let notes = Arc::new(Mutex::new(vec![]));
+    let notes_cln = notes.clone();
+    let auth = warp::post().and(warp::path!("api" / "v1" / "auth" / "access_token")).and(warp::body::form())
+        .map(|form: std::collections::HashMap<String, String>| {
+            assert_eq!((form["client_id"].as_str(), form["client_secret"].as_str()), ("clapshot", "k3y"));
+            warp::reply::json(&json!({ "access_token": "tok", "expires_in": 600 }))
+        });
+    let version = warp::get().and(warp::path!("api" / "v1" / "entity" / "versions" / i64)).and(warp::header::<String>("authorization"))
+        .map(|id: i64, auth: String| {
+            assert_eq!((id, auth.as_str()), (6789, "Bearer tok"));
+            warp::reply::json(&json!({ "data": { "id": id, "relationships": { "project": { "data": { "type": "Project", "id": 85 } } } } }))
+        });
+    let create = warp::post().and(warp::path!("api" / "v1" / "entity" / "notes")).and(warp::body::json())
+        .map(move |note: serde_json::Value| {
+            notes_cln.lock().unwrap().push(note);
+            warp::reply::json(&json!({ "data": { "id": 555, "type": "Note" } }))
+        });
+    let replies = warp::post().and(warp::path!("api" / "v1" / "entity" / "replies" / "_search")).and(warp::body::bytes())
+        .map(|body: bytes::Bytes| {
+            let q: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(q["filters"][0][2]["id"], 555);
+            warp::reply::json(&json!({ "data": [{ "id": 77, "attributes": { "content": "Agreed", "created_at": "2026-10-16T12:00:00Z" },
+                "relationships": { "user": { "data": { "type": "HumanUser", "id": 5, "name": "Sam Lee" } } } }] }))
+        });
+    let (addr, server) = warp::serve(auth.or(version).or(create).or(replies)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    let cfg = TrackerSync::from_json(&format!(r#"{{"kind": "shotgrid", "url": "http://{addr}", "login": "clapshot",
+        "api_key": "k3y", "entity_field": "sg_version"}}"#)).unwrap();
+
+    assert!(queue_comment(&db, &cfg, &comments[0]).unwrap());
+    let db_cln = db.clone();
+    let (n_pushed, n_replies) = tokio::task::spawn_blocking(move || {
+        let mut client = TrackerClient::new(cfg).unwrap();
+        (push_queued(&db_cln, &mut client, "https://clap.example").unwrap(), pull_replies(&db_cln, &mut client).unwrap())
+    }).await.unwrap();
+    assert_eq!((n_pushed, n_replies), (1, 1));
+
+    let note = notes.lock().unwrap()[0].clone();
+    assert_eq!(note["project"], json!({ "type": "Project", "id": 85 }));
+    assert_eq!(note["note_links"], json!([{ "type": "Version", "id": 6789 }]));
+    assert!(note["content"].as_str().unwrap().contains(&format!("https://clap.example/?vid={}", videos[0].video_hash)));
+    let reply = db.get_video_comments(&videos[0].video_hash).unwrap().into_iter().find(|c| c.comment == "Agreed").unwrap();
+    assert_eq!((reply.username.as_str(), reply.user_id.as_str()), ("Sam Lee", "shotgrid:HumanUser-5"));
+}
@@ -0,0 +1,168 @@
+use std::path::PathBuf;
+use anyhow::bail;
+
+use crate::database::{models, VideoBatchOp};
+use super::server_state::ServerState;
+
+/// Where files of a trashed video are kept (out of the served videos dir)
+pub fn trash_dir(server: &ServerState, vh: &str) -> PathBuf {
+    server.videos_dir.join("trash").join(vh)
+}
+
+/// Save DB row of a video next to its files, to help recovering it by hand
+fn backup_video_db_row(server: &ServerState, v: &models::Video) -> anyhow::Result<()> {
+    let backup_file = server.videos_dir.join(&v.video_hash).join("db_backup.json");
+    if backup_file.exists() {
+        std::fs::remove_file(&backup_file)?;
+    }
+    std::fs::write(&backup_file, serde_json::to_string_pretty(&v)?)?;
+    Ok(())
+}
+
+/// Move files of a video to `trash_dir` (not marking it trashed in DB).
+///
+/// # Returns
+/// * `true` - Files were moved
+/// * `false` - Video had no dir (added to `warnings`)
+fn move_files_to_trash(server: &ServerState, v: &models::Video, warnings: &mut Vec<String>) -> anyhow::Result<bool>
+{
+    let video_dir = server.videos_dir.join(&v.video_hash);
+    if !video_dir.is_dir() {
+        warnings.push("Video dir not found.".into());
+        return Ok(false);
+    }
+    if let Err(e) = backup_video_db_row(server, v) {
+        warnings.push(format!("DB row backup failed: {:?}.", e));
+    }
+    let dst = trash_dir(server, &v.video_hash);
+    if dst.exists() {
+        // Leftover from an earlier, failed purge
+        std::fs::remove_dir_all(&dst)?;
+    }
+    std::fs::create_dir_all(server.videos_dir.join("trash"))?;
+    std::fs::rename(&video_dir, &dst)?;
+    Ok(true)
+}
+
+/// Move a video to trash: mark it in DB and move its files to `trash_dir`.
+/// Comments etc. are kept until the video is purged.
+///
+/// # Returns
+/// Warnings about non-fatal problems (video was trashed anyway)
+pub fn move_to_trash(server: &ServerState, v: &models::Video) -> anyhow::Result<Vec<String>>
+{
+    let mut warnings = vec![];
+    move_files_to_trash(server, v, &mut warnings)?;
+    server.db.set_video_trashed(&v.video_hash, true)?;
+    Ok(warnings)
+}
+
+/// Move several videos to trash, all or none: if moving files of one fails, or marking
+/// them in DB (one transaction) does, files already moved are put back.
+///
+/// # Returns
+/// Warnings about non-fatal problems, prefixed with the video hash
+pub fn move_many_to_trash(server: &ServerState, vs: &[models::Video]) -> anyhow::Result<Vec<String>>
+{
+    let mut warnings = vec![];
+    let mut moved = vec![];
+    let res = (|| -> anyhow::Result<()> {
+        for v in vs {
+            let mut w = vec![];
+            if move_files_to_trash(server, v, &mut w)? {
+                moved.push(v.video_hash.clone());
+            }
+            warnings.extend(w.into_iter().map(|w| format!("{}: {}", v.video_hash, w)));
+        }
+        let vhs = vs.iter().map(|v| v.video_hash.clone()).collect::<Vec<_>>();
+        server.db.apply_video_batch(&vhs, &VideoBatchOp::Trash)?;
+        Ok(())
+    })();
+    if let Err(e) = res {
+        for vh in moved {
+            if let Err(e) = std::fs::rename(trash_dir(server, &vh), server.videos_dir.join(&vh)) {
+                tracing::error!(video=%vh, details=%e, "Failed to move video files back from trash.");
+            }
+        }
+        return Err(e);
+    }
+    Ok(warnings)
+}
+
+/// Restore a trashed video: move its files back and clear the trash mark
+pub fn restore_from_trash(server: &ServerState, vh: &str) -> anyhow::Result<()>
+{
+    let video_dir = server.videos_dir.join(vh);
+    if video_dir.exists() {
+        bail!("Video dir '{}' already exists", video_dir.display());
+    }
+    let src = trash_dir(server, vh);
+    if src.is_dir() {
+        std::fs::rename(&src, &video_dir)?;
+    }
+    server.db.set_video_trashed(vh, false)?;
+    Ok(())
+}
+
+/// Delete a video for good, trashed or not: DB rows (comments etc.) and files.
+/// Fails for videos under legal hold.
+pub fn purge_video(server: &ServerState, vh: &str) -> anyhow::Result<()>
+{
+    server.db.del_video_and_comments(vh)?;
+    for dir in [server.videos_dir.join(vh), trash_dir(server, vh)] {
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// Purge videos that have been in trash for `retention_days` or more.
+/// Videos under legal hold (also through their folder) are kept until it's lifted,
+/// and the skipped purge is audited.
+///
+/// # Returns
+/// Number of videos purged
+pub fn purge_expired(server: &ServerState, retention_days: u32) -> usize
+{
+    let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::days(retention_days as i64);
+    let trashed = match server.db.get_trashed_videos(None) {
+        Ok(v) => v,
+        Err(e) => { tracing::error!(details=%e, "Failed to get trashed videos."); return 0; }
+    };
+    let audit_system = |action: &str, v: &models::Video, details: String| {
+        let res = server.db.add_audit_event(&models::AuditEventInsert {
+            user_id: "system".into(),
+            action: action.into(),
+            ref_video_hash: Some(v.video_hash.clone()),
+            details,
+        });
+        if let Err(e) = res {
+            tracing::error!(details=%e, "Failed to audit purge.");
+        }
+    };
+    let mut n_purged = 0;
+    for v in trashed.iter().filter(|v| v.trashed.is_some_and(|t| t <= cutoff)) {
+        match server.db.is_video_held(&v.video_hash) {
+            Ok(false) => {},
+            Ok(true) => {
+                tracing::info!(video=%v.video_hash, "Not purging video from trash, it's under legal hold.");
+                audit_system(models::audit_action::DELETE_VIDEO_BLOCKED, v, "Trash retention expired, but video is under legal hold.".into());
+                continue;
+            },
+            Err(e) => {
+                tracing::error!(video=%v.video_hash, details=%e, "Failed to check legal hold.");
+                continue;
+            },
+        }
+        if let Err(e) = purge_video(server, &v.video_hash) {
+            tracing::error!(video=%v.video_hash, details=%e, "Failed to purge video from trash.");
+            continue;
+        }
+        tracing::info!(video=%v.video_hash, "Purged video from trash.");
+        n_purged += 1;
+        audit_system(models::audit_action::PURGE_VIDEO, v,
+            format!("In trash for {} days. Owner was {:?}, title was {:?}.", retention_days, v.added_by_userid, v.title));
+    }
+    n_purged
+}
@@ -0,0 +1,147 @@
+use std::path::Path;
+use anyhow::{anyhow, bail};
+use serde_json::json;
+
+use crate::database::{models, error::DBError};
+use crate::video_pipeline::{IncomingFile, LoudnormOpt, calc_content_hash};
+use super::server_state::ServerState;
+
+/// What to do with an upload that's identical to a video shared with the user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    /// Add the existing video to user's list, drop the upload
+    Link,
+    /// Store the upload as user's own copy
+    Keep,
+    /// Drop the upload
+    Discard,
+}
+
+impl std::str::FromStr for Resolution {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "link" => Ok(Resolution::Link),
+            "keep" => Ok(Resolution::Keep),
+            "discard" => Ok(Resolution::Discard),
+            _ => Err(format!("Invalid action '{}'. Use link, keep or discard.", s)),
+        }
+    }
+}
+
+/// Remove the upload dir of a pending upload. Refuses to touch anything outside upload dir.
+fn remove_upload(server: &ServerState, src_file: &Path) -> anyhow::Result<()> {
+    let dir = src_file.parent().ok_or(anyhow!("No parent dir"))?;
+    if !dir.starts_with(&server.upload_dir) || dir == server.upload_dir {
+        bail!("'{}' is not an upload dir", dir.display());
+    }
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// Pending upload as JSON for client, with title of the existing video
+pub fn pending_json(server: &ServerState, pu: &models::PendingUpload) -> anyhow::Result<serde_json::Value> {
+    let mut pj = pu.to_json()?;
+    pj["filename"] = json!(Path::new(&pu.src_file).file_name().map(|f| f.to_string_lossy()));
+    pj["duplicate_title"] = json!(server.db.get_video(&pu.duplicate_of).ok().and_then(|v| v.title));
+    Ok(pj)
+}
+
+/// Check a new upload against videos other users have shared with the user's teams.
+/// If there's an identical one, either link it right away (`IngestPolicy::auto_link_duplicates`)
+/// or hold the upload as pending and ask the user (`pending_upload` command to their sessions).
+///
+/// # Returns
+/// None if the file should be processed as usual, otherwise a JSON reply for the uploader
+pub fn check_upload(server: &ServerState, user_id: &str, file: &Path, loudnorm: LoudnormOpt) -> anyhow::Result<Option<serde_json::Value>>
+{
+    let hash = calc_content_hash(file)?;
+    let Some(existing) = server.db.get_shared_videos_by_content_hash(user_id, &hash)?.into_iter().next() else {
+        return Ok(None);
+    };
+    tracing::info!(user=%user_id, existing=%existing.video_hash, "Upload is identical to a video shared with user.");
+    if server.policy.auto_link_duplicates {
+        server.db.add_video_link(user_id, &existing.video_hash)?;
+        remove_upload(server, file)?;
+        return Ok(Some(json!({ "linked": existing.video_hash })));
+    }
+    let pu = server.db.add_pending_upload(&models::PendingUploadInsert {
+        user_id: user_id.into(),
+        src_file: file.to_string_lossy().into(),
+        duplicate_of: existing.video_hash.clone(),
+        loudnorm: Some(loudnorm.to_string()),
+    })?;
+    let pj = pending_json(server, &pu)?;
+    let msg = json!({ "cmd": "pending_upload", "data": pj });
+    server.send_to_all_user_sessions(user_id, &super::Message::text(msg.to_string()));
+    Ok(Some(json!({ "pending_upload": pj })))
+}
+
+/// Carry out user's choice for a pending upload
+///
+/// # Returns
+/// Message for user
+pub fn resolve(server: &ServerState, pu: &models::PendingUpload, action: Resolution) -> anyhow::Result<String>
+{
+    // Delete first, so the same upload can't be resolved twice
+    match server.db.del_pending_upload(pu.id) {
+        Err(DBError::NotFound()) => bail!("Upload has already been handled"),
+        res => res?,
+    }
+    let src = Path::new(&pu.src_file);
+    match action {
+        Resolution::Link => {
+            let v = server.db.get_video(&pu.duplicate_of).ok().filter(|v| v.trashed.is_none())
+                .ok_or(anyhow!("Existing video is gone. Upload it again to store a copy."))?;
+            server.db.add_video_link(&pu.user_id, &v.video_hash)?;
+            remove_upload(server, src)?;
+            Ok(format!("Linked to '{}'.", v.title.as_deref().unwrap_or(&v.video_hash)))
+        },
+        Resolution::Keep => {
+            let loudnorm = pu.loudnorm.as_deref().and_then(|s| s.parse().ok()).unwrap_or_default();
+            server.upload_tx.send(IncomingFile { file_path: src.into(), user_id: pu.user_id.clone(), loudnorm, ..Default::default() })?;
+            Ok("Storing a copy.".into())
+        },
+        Resolution::Discard => {
+            remove_upload(server, src)?;
+            Ok("Upload discarded.".into())
+        },
+    }
+}
+
+/// Store uploads that have waited longer than `IngestPolicy::dedup_window_hours` as copies
+///
+/// # Returns
+/// Number of uploads submitted
+pub fn expire_pending(server: &ServerState) -> usize
+{
+    let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::hours(server.policy.dedup_window_hours as i64);
+    let pending = match server.db.get_pending_uploads(None) {
+        Ok(p) => p,
+        Err(e) => { tracing::error!(details=%e, "Failed to get pending uploads."); return 0; }
+    };
+    let mut n = 0;
+    for pu in pending.iter().filter(|pu| pu.created <= cutoff) {
+        match resolve(server, pu, Resolution::Keep) {
+            Ok(_) => { n += 1; },
+            Err(e) => tracing::error!(id=pu.id, details=%e, "Failed to submit expired pending upload."),
+        }
+    }
+    if n > 0 {
+        tracing::info!(count=n, "Pending uploads expired. Stored as copies.");
+    }
+    n
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_parse_resolution() {
+    assert_eq!("link".parse::<Resolution>(), Ok(Resolution::Link));
+    assert_eq!("keep".parse::<Resolution>(), Ok(Resolution::Keep));
+    assert_eq!("discard".parse::<Resolution>(), Ok(Resolution::Discard));
+    assert!("copy".parse::<Resolution>().is_err());
+}
@@ -0,0 +1,327 @@
+use std::convert::Infallible;
+use std::path::PathBuf;
+use anyhow::bail;
+use futures_util::StreamExt;
+use serde_json::json;
+use tracing::Instrument;
+use warp::{Filter, Reply};
+use warp::http::{HeaderMap, StatusCode};
+
+use crate::database::{models, models::upload_session_status, error::DBError};
+use crate::video_pipeline::{IncomingFile, LoudnormOpt};
+use crate::storage::{StorageError, FULL_MSG};
+use super::parse_auth_headers;
+use super::server_state::ServerState;
+
+// Upload sessions let clients pause and resume a big upload, e.g. when a laptop goes to sleep
+// in the middle of it. Client creates a session (`create_upload_session`) with the filename
+// and size, and then PUTs the data in one or more requests to `/api/upload_session/<id>`,
+// with the offset it continues from in `X-Upload-Offset`. Server keeps the received data in
+// `<upload dir>/partial/<id>.part` and the offset in DB. The partial file is the truth: if a
+// request breaks off (or the server dies) mid-write, whatever got written is kept.
+//
+// Paused sessions refuse data until resumed. Resume replies with the offset to continue from.
+// User's sessions are listed in all their open sessions (`upload_sessions` command), also right
+// after login, so another device (or the same laptop after sleep) can offer to continue.
+// When all data has arrived, the file is processed like a regular upload.
+
+/// Unfinished sessions not touched for this long are removed (see `expire_stale`)
+pub const STALE_DAYS: i64 = 7;
+
+/// Partial file of an upload session
+pub fn partial_path(server: &ServerState, id: &str) -> PathBuf {
+    server.upload_dir.join("partial").join(format!("{id}.part"))
+}
+
+/// Bytes received for a session, i.e. size of the partial file
+fn bytes_on_disk(server: &ServerState, id: &str) -> u64 {
+    std::fs::metadata(partial_path(server, id)).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Create a new upload session. Quotas are checked against the declared size.
+///
+/// # Returns
+/// * `Ok(Err(msg))` - Refused, with a message for the user
+pub fn create(server: &ServerState, user_id: &str, filename: &str, total_size: u64, loudnorm: LoudnormOpt)
+    -> anyhow::Result<Result<models::UploadSession, String>>
+{
+    if !crate::video_pipeline::is_plain_file_name(filename) {
+        return Ok(Err("Filename must not be empty or contain a path.".into()));
+    }
+    if total_size == 0 {
+        return Ok(Err("Upload must not be empty.".into()));
+    }
+    let quotas = server.config.quotas();
+    if !quotas.is_unlimited() {
+        let usage = crate::quota::get_user_usage(&server.db, &server.videos_dir, user_id)?;
+        if let Err(msg) = quotas.check_new_job(&usage).and_then(|_| quotas.check_new_file(&usage, total_size)) {
+            return Ok(Err(msg));
+        }
+    }
+    let id = uuid::Uuid::new_v4().to_string();
+    let partial = partial_path(server, &id);
+    if let Some(dir) = partial.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::File::create(&partial)?;
+    let s = server.db.add_upload_session(&models::UploadSessionInsert {
+        id,
+        user_id: user_id.into(),
+        filename: filename.into(),
+        total_size: total_size as i64,
+        status: upload_session_status::ACTIVE.into(),
+        loudnorm: Some(loudnorm.to_string()),
+    })?;
+    tracing::info!(user=%user_id, id=%s.id, filename=%filename, size=total_size, "Upload session created.");
+    Ok(Ok(s))
+}
+
+/// Send user's unfinished upload sessions to all their open sessions (`upload_sessions` command)
+pub fn push_sessions(server: &ServerState, user_id: &str) -> anyhow::Result<()>
+{
+    let sessions = server.db.get_upload_sessions(Some(user_id))?.iter()
+        .map(|s| s.to_json()).collect::<Result<Vec<_>, _>>()?;
+    let msg = json!({ "cmd": "upload_sessions", "data": { "sessions": sessions } });
+    server.send_to_all_user_sessions(user_id, &super::Message::text(msg.to_string()));
+    Ok(())
+}
+
+/// Pause or resume an upload session. Offset is synced from the partial file.
+///
+/// # Returns
+/// Session as it is now
+pub fn set_paused(server: &ServerState, s: &models::UploadSession, paused: bool) -> anyhow::Result<models::UploadSession>
+{
+    let status = if paused { upload_session_status::PAUSED } else { upload_session_status::ACTIVE };
+    server.db.update_upload_session(&s.id, Some(status), Some(bytes_on_disk(server, &s.id) as i64))?;
+    tracing::info!(user=%s.user_id, id=%s.id, status, "Upload session status changed.");
+    Ok(server.db.get_upload_session(&s.id)?)
+}
+
+/// Cancel an upload session and remove its partial file
+pub fn cancel(server: &ServerState, s: &models::UploadSession) -> anyhow::Result<()>
+{
+    match server.db.del_upload_session(&s.id) {
+        Err(DBError::NotFound()) => bail!("Upload session has already ended"),
+        res => res?,
+    }
+    let partial = partial_path(server, &s.id);
+    if partial.exists() {
+        std::fs::remove_file(&partial)?;
+    }
+    Ok(())
+}
+
+/// Move a completed upload into its own upload dir and process it like a regular upload
+/// (including the duplicate check, see `upload_dedup`).
+///
+/// # Returns
+/// JSON reply for the uploader
+fn finish(server: &ServerState, s: &models::UploadSession, trace_id: String) -> anyhow::Result<serde_json::Value>
+{
+    // Delete first, so the same session can't be submitted twice
+    match server.db.del_upload_session(&s.id) {
+        Err(DBError::NotFound()) => bail!("Upload session has already ended"),
+        res => res?,
+    }
+    let dir = server.upload_dir.join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&dir)?;
+    let dst = dir.join(&s.filename);
+    std::fs::rename(partial_path(server, &s.id), &dst)?;
+    tracing::info!(user=%s.user_id, id=%s.id, "Upload session complete: '{:?}'", dst);
+
+    let loudnorm = s.loudnorm.as_deref().and_then(|l| l.parse().ok()).unwrap_or_default();
+    match super::upload_dedup::check_upload(server, &s.user_id, &dst, loudnorm) {
+        Ok(None) => {},
+        Ok(Some(reply)) => return Ok(reply),
+        Err(e) => tracing::error!(details=%e, "Duplicate check failed. Processing upload as usual."),
+    }
+    server.upload_tx.send(IncomingFile { file_path: dst, user_id: s.user_id.clone(), loudnorm, trace_id: Some(trace_id), ..Default::default() })?;
+    Ok(json!({}))
+}
+
+/// Remove unfinished upload sessions (and their data) not touched in `STALE_DAYS`
+///
+/// # Returns
+/// Number of sessions removed
+pub fn expire_stale(server: &ServerState) -> usize
+{
+    let cutoff = chrono::Utc::now().naive_utc() - chrono::Duration::days(STALE_DAYS);
+    let sessions = match server.db.get_upload_sessions(None) {
+        Ok(s) => s,
+        Err(e) => { tracing::error!(details=%e, "Failed to get upload sessions."); return 0; }
+    };
+    let mut n = 0;
+    for s in sessions.iter().filter(|s| s.updated <= cutoff) {
+        if server.upload_writers.lock().unwrap().contains(&s.id) {
+            continue;
+        }
+        match cancel(server, s) {
+            Ok(_) => { n += 1; },
+            Err(e) => tracing::error!(id=%s.id, details=%e, "Failed to remove stale upload session."),
+        }
+    }
+    if n > 0 {
+        tracing::info!(count=n, "Stale upload sessions removed.");
+    }
+    n
+}
+
+/// Marks an upload session as receiving data, for as long as it lives
+struct WriterGuard {
+    server: ServerState,
+    id: String,
+}
+
+impl WriterGuard {
+    /// # Returns
+    /// None if the session is already receiving data in another request
+    fn acquire(server: &ServerState, id: &str) -> Option<WriterGuard> {
+        match server.upload_writers.lock().unwrap().insert(id.to_string()) {
+            true => Some(WriterGuard { server: server.clone(), id: id.to_string() }),
+            false => None,
+        }
+    }
+}
+
+impl Drop for WriterGuard {
+    fn drop(&mut self) {
+        self.server.upload_writers.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Warp filter for upload session data: `PUT /api/upload_session/<id>`
+pub fn upload_session_filter(server: ServerState) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone
+{
+    warp::path!("api" / "upload_session" / String)
+        .and(warp::put())
+        .and(warp::any().map(move || server.clone()))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::stream())
+        .and_then(|id: String, server, hdrs: HeaderMap, body| {
+            let trace_id = crate::telemetry::trace_id_from_headers(&hdrs);
+            let span = tracing::info_span!("upload", trace_id=%trace_id, session=%id);
+            handle_session_data(server, id, hdrs, body, trace_id).instrument(span)
+        })
+}
+
+/// Append request body to an upload session's data, starting at offset `X-Upload-Offset`.
+/// Replies with JSON `{received, complete}` (and the duplicate check result, if any, on completion).
+/// On offset mismatch, replies 409 with the offset to continue from.
+async fn handle_session_data(
+    server: ServerState,
+    id: String,
+    hdrs: HeaderMap,
+    mut body: impl warp::Stream<Item = Result<impl bytes::Buf, warp::Error>> + Unpin,
+    trace_id: String)
+        -> Result<warp::reply::WithStatus<String>, Infallible>
+{
+    let reply = |msg: String, status: StatusCode| Ok(warp::reply::with_status(msg, status));
+    if server.terminate_flag.load(std::sync::atomic::Ordering::Relaxed) {
+        return reply(super::SHUTDOWN_MSG.into(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+    let (user_id, _) = parse_auth_headers(&hdrs);
+    if server.db.is_user_disabled(&user_id).unwrap_or(false) {
+        return reply("User is disabled".into(), StatusCode::FORBIDDEN);
+    }
+    let s = match server.db.get_upload_session(&id) {
+        Ok(s) if s.user_id == user_id => s,
+        _ => return reply("No such upload session".into(), StatusCode::NOT_FOUND),
+    };
+    if s.status == upload_session_status::PAUSED {
+        return reply("Upload session is paused".into(), StatusCode::CONFLICT);
+    }
+    let Some(_guard) = WriterGuard::acquire(&server, &id) else {
+        return reply("Upload session is already receiving data".into(), StatusCode::CONFLICT);
+    };
+
+    let on_disk = bytes_on_disk(&server, &id);
+    match hdrs.get("X-Upload-Offset").map(|v| v.to_str().unwrap_or_default().parse::<u64>()) {
+        Some(Ok(offset)) if offset == on_disk => {},
+        Some(Ok(_)) => return reply(json!({ "error": "Offset mismatch", "received": on_disk }).to_string(), StatusCode::CONFLICT),
+        _ => return reply("Missing or invalid X-Upload-Offset".into(), StatusCode::BAD_REQUEST),
+    }
+    // Out of space? Data is kept, and the upload can continue from this offset once there's room.
+    if server.storage.full().is_some() {
+        return reply(json!({ "error": FULL_MSG, "received": on_disk }).to_string(), StatusCode::INSUFFICIENT_STORAGE);
+    }
+
+    let partial = partial_path(&server, &id);
+    let mut f = match async_std::fs::OpenOptions::new().append(true).create(true).open(&partial).await {
+        Ok(f) => f,
+        Err(e) => {
+            tracing::error!(details=%e, "Failed to open partial upload file.");
+            if let Some(se) = StorageError::from_io(&e) {
+                server.report_storage_error(se);
+                return reply(json!({ "error": FULL_MSG, "received": on_disk }).to_string(), StatusCode::INSUFFICIENT_STORAGE);
+            }
+            return reply("Internal error: failed to open upload file".into(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    // Keep whatever arrives, even if the request breaks off
+    let mut received = on_disk;
+    let mut error = None;
+    while let Some(chunk) = body.next().await {
+        let data = match chunk {
+            Ok(mut buf) => buf.copy_to_bytes(buf.remaining()),
+            Err(e) => { error = Some((format!("Upload interrupted: {e}"), StatusCode::BAD_REQUEST)); break; }
+        };
+        if received + data.len() as u64 > s.total_size as u64 {
+            error = Some(("Data exceeds declared upload size".into(), StatusCode::BAD_REQUEST));
+            break;
+        }
+        if let Err(e) = futures_util::AsyncWriteExt::write_all(&mut f, &data).await {
+            tracing::error!(details=%e, "Failed to write partial upload file.");
+            error = Some(match StorageError::from_io(&e) {
+                Some(se) => { server.report_storage_error(se); (FULL_MSG.into(), StatusCode::INSUFFICIENT_STORAGE) },
+                None => ("Internal error: failed to write upload file".into(), StatusCode::INTERNAL_SERVER_ERROR),
+            });
+            break;
+        }
+        received += data.len() as u64;
+    }
+    if let Err(e) = futures_util::AsyncWriteExt::flush(&mut f).await {
+        tracing::warn!(details=%e, "Failed to flush partial upload file.");
+    }
+    drop(f);
+
+    // Sync offset from disk, in case a write was only partly done
+    let received = bytes_on_disk(&server, &id);
+    if let Err(e) = server.db.update_upload_session(&id, None, Some(received as i64)) {
+        tracing::error!(details=%e, "Failed to update upload session.");
+    }
+    if let Some((msg, status)) = error {
+        tracing::warn!(received, "{}", msg);
+        return reply(json!({ "error": msg, "received": received }).to_string(), status);
+    }
+    if received < s.total_size as u64 {
+        return reply(json!({ "received": received, "complete": false }).to_string(), StatusCode::OK);
+    }
+    // Pipeline too busy? Data is kept, and an empty PUT at the end offset finishes the upload later.
+    if server.upload_tx.is_full() {
+        return reply(json!({ "error": crate::video_pipeline::queues::BUSY_MSG, "received": received }).to_string(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let srv = server.clone();
+    let res = tokio::task::spawn_blocking(move || finish(&srv, &s, trace_id)).await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("Finishing upload panicked: {e}")));
+    if let Err(e) = push_sessions(&server, &user_id) {
+        tracing::warn!(details=%e, "Failed to send upload sessions to user.");
+    }
+    match res {
+        Ok(mut r) => {
+            r["received"] = json!(received);
+            r["complete"] = json!(true);
+            reply(r.to_string(), StatusCode::OK)
+        },
+        Err(e) => {
+            tracing::error!(details=%e, "Failed to finish upload session.");
+            if let Some(se) = StorageError::from_anyhow(&e) {
+                server.report_storage_error(se);
+                return reply(json!({ "error": FULL_MSG, "received": received }).to_string(), StatusCode::INSUFFICIENT_STORAGE);
+            }
+            reply(format!("Failed to finish upload: {e}"), StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::{Duration, SystemTime};
+
+use crate::database::DB;
+use crate::database::error::DBResult;
+use super::server_state::ServerState;
+use super::reconcile::last_modified;
+
+// Uploads that never make it to ingestion (clients that disconnect, crashes between upload and
+// pipeline, upload sessions whose DB row was removed) and originals of failed ingests (moved
+// under `rejected/` for inspection) would otherwise stay on disk forever. The sweeper runs with
+// retention (hourly) and deletes them once they're old enough:
+//
+//  - entries in upload dir that no unfinished job or pending upload refers to, and partial files
+//    of upload sessions that are gone, untouched for `--stale-upload-hours`,
+//  - entries in rejected dir older than `--rejected-retention` days.
+//
+// Removals are logged one by one, and counted in `SweepStats` (shown in `admin_queue_status`).
+
+/// Counters of what the sweeper has removed since server start, for metrics
+#[derive(Debug, Default)]
+pub struct SweepStats {
+    runs: AtomicU64,
+    stale_uploads: AtomicU64,
+    rejected_files: AtomicU64,
+    bytes_freed: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl SweepStats {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "runs": self.runs.load(Relaxed),
+            "stale_uploads": self.stale_uploads.load(Relaxed),
+            "rejected_files": self.rejected_files.load(Relaxed),
+            "bytes_freed": self.bytes_freed.load(Relaxed),
+            "errors": self.errors.load(Relaxed),
+        })
+    }
+}
+
+/// Files in upload dir that are still needed: sources of unfinished jobs and pending uploads
+pub fn upload_files_in_use(db: &DB) -> DBResult<Vec<PathBuf>>
+{
+    Ok(db.get_unfinished_jobs()?.into_iter().map(|j| j.src_file)
+        .chain(db.get_pending_uploads(None)?.into_iter().map(|p| p.src_file))
+        .map(PathBuf::from).collect())
+}
+
+/// Entries of a dir, sorted. Empty if it doesn't exist.
+pub(crate) fn dir_entries(dir: &Path) -> Vec<PathBuf>
+{
+    let mut res = std::fs::read_dir(dir).map(|rd| rd.flatten().map(|e| e.path()).collect::<Vec<_>>()).unwrap_or_default();
+    res.sort();
+    res
+}
+
+/// Upload dir entries nobody is going to process
+///
+/// # Arguments
+/// * `upload_dir` - Upload dir (upload dirs, and upload sessions' partial files under partial/)
+/// * `in_use` - Files still needed (see `upload_files_in_use`)
+/// * `session_ids` - IDs of existing upload sessions
+pub(crate) fn abandoned_uploads(upload_dir: &Path, in_use: &[PathBuf], session_ids: &HashSet<String>) -> Vec<(PathBuf, String)>
+{
+    let partial_dir = upload_dir.join("partial");
+    let mut res = vec![];
+    for p in dir_entries(upload_dir).into_iter().filter(|p| *p != partial_dir) {
+        if !in_use.iter().any(|f| f.starts_with(&p)) { res.push((p, "Upload is not being processed".to_string())); }
+    }
+    for p in dir_entries(&partial_dir) {
+        let id = p.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        if !session_ids.contains(&id) { res.push((p, "Upload session is gone".to_string())); }
+    }
+    res
+}
+
+/// Is file (or everything in a dir) untouched for at least `age`
+pub(crate) fn older_than(path: &Path, age: Duration, now: SystemTime) -> bool
+{
+    last_modified(path).is_some_and(|t| now.duration_since(t).unwrap_or_default() >= age)
+}
+
+/// Size of a file, or of everything in a dir
+pub(crate) fn disk_usage(path: &Path) -> u64
+{
+    match path.is_dir() {
+        true => crate::quota::dir_size(path).unwrap_or(0),
+        false => path.metadata().map(|m| m.len()).unwrap_or(0),
+    }
+}
+
+/// Delete a file or dir
+///
+/// # Returns
+/// Number of bytes freed
+pub(crate) fn remove(path: &Path) -> std::io::Result<u64>
+{
+    let bytes = disk_usage(path);
+    match path.is_dir() {
+        true => std::fs::remove_dir_all(path)?,
+        false => std::fs::remove_file(path)?,
+    };
+    Ok(bytes)
+}
+
+/// Delete stale uploads and old rejected files.
+///
+/// # Arguments
+/// * `server` - Server state
+/// * `upload_hours` - Age after which abandoned uploads are deleted, or None to keep them
+/// * `rejected_days` - Age after which rejected files are deleted, or None to keep them
+///
+/// # Returns
+/// Number of files (or dirs) deleted
+pub fn sweep(server: &ServerState, upload_hours: Option<u32>, rejected_days: Option<u32>) -> usize
+{
+    let stats = &server.sweep_stats;
+    stats.runs.fetch_add(1, Relaxed);
+    let now = SystemTime::now();
+    let mut n = 0;
+    let mut del = |path: &Path, reason: &str, counter: &AtomicU64| match remove(path) {
+        Ok(bytes) => {
+            tracing::info!(path=%path.display(), bytes, reason, "Removed stale file.");
+            counter.fetch_add(1, Relaxed);
+            stats.bytes_freed.fetch_add(bytes, Relaxed);
+            n += 1;
+        },
+        Err(e) => {
+            tracing::error!(path=%path.display(), details=%e, "Failed to remove stale file.");
+            stats.errors.fetch_add(1, Relaxed);
+        },
+    };
+
+    if let Some(hours) = upload_hours {
+        let lists = upload_files_in_use(&server.db).and_then(|in_use|
+            Ok((in_use, server.db.get_upload_sessions(None)?.into_iter().map(|s| s.id).collect::<HashSet<_>>())));
+        match lists {
+            Ok((in_use, session_ids)) => {
+                let max_age = Duration::from_secs(hours as u64 * 3600);
+                for (p, reason) in abandoned_uploads(&server.upload_dir, &in_use, &session_ids) {
+                    if older_than(&p, max_age, now) { del(&p, &reason, &stats.stale_uploads); }
+                }
+            },
+            Err(e) => {
+                tracing::error!(details=%e, "Failed to list uploads in use. Not sweeping upload dir.");
+                stats.errors.fetch_add(1, Relaxed);
+            },
+        }
+    }
+    if let Some(days) = rejected_days {
+        let rejected_dir = server.videos_dir.parent().unwrap_or(&server.videos_dir).join("rejected");
+        let max_age = Duration::from_secs(days as u64 * 24 * 3600);
+        for p in dir_entries(&rejected_dir) {
+            if older_than(&p, max_age, now) { del(&p, "Rejected file expired", &stats.rejected_files); }
+        }
+    }
+    if n > 0 {
+        tracing::info!(count=n, "Stale uploads and rejected files removed.");
+    }
+    n
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_abandoned_uploads()
+{
+    let dir = tempfile::tempdir().unwrap();
+    let upload_dir = dir.path().join("upload");
+    for d in ["in_use", "stale", "partial"] {
+        std::fs::create_dir_all(upload_dir.join(d)).unwrap();
+    }
+    for f in ["in_use/a.mov", "stale/b.mov", "partial/s1.part", "partial/s2.part"] {
+        std::fs::write(upload_dir.join(f), "DATA").unwrap();
+    }
+    let in_use = vec![upload_dir.join("in_use").join("a.mov")];
+    let sessions = HashSet::from(["s1".to_string()]);
+    let found = abandoned_uploads(&upload_dir, &in_use, &sessions).into_iter()
+        .map(|(p, _)| p.strip_prefix(&upload_dir).unwrap().to_string_lossy().to_string()).collect::<Vec<_>>();
+    assert_eq!(found, vec!["stale", "partial/s2.part"]);
+
+    let now = SystemTime::now();
+    assert!(!older_than(&upload_dir.join("stale"), Duration::from_secs(3600), now));
+    assert!(older_than(&upload_dir.join("stale"), Duration::from_secs(3600), now + Duration::from_secs(3601)));
+    assert!(!older_than(&upload_dir.join("missing"), Duration::ZERO, now));
+
+    assert_eq!(remove(&upload_dir.join("stale")).unwrap(), 4);
+    assert!(!upload_dir.join("stale").exists());
+}
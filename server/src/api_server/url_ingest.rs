@@ -0,0 +1,303 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::bail;
+
+use crate::database::models;
+use crate::video_pipeline::{IncomingFile, LoudnormOpt};
+use super::server_state::ServerState;
+
+/// Min interval between progress messages to user
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Give up if the server sends nothing for this long
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+const MAX_REDIRECTS: usize = 10;
+
+/// Max number of URL downloads a user can have running at once
+pub const MAX_INGESTS_PER_USER: usize = 3;
+
+/// Check that a URL is something we can download from (HTTP or HTTPS).
+pub fn parse_ingest_url(url: &str) -> anyhow::Result<reqwest::Url>
+{
+    let url = reqwest::Url::parse(url.trim())?;
+    match url.scheme() {
+        "http" | "https" => {},
+        other => bail!("Unsupported URL scheme '{}'. Only http and https are allowed.", other),
+    }
+    if url.host_str().unwrap_or("").is_empty() { bail!("URL has no host"); }
+    Ok(url)
+}
+
+/// Check if an address is on the public internet. Users' downloads must not reach the server's own
+/// network: loopback, private and link-local (e.g. cloud metadata at 169.254.169.254) addresses.
+fn is_public_ip(ip: IpAddr) -> bool
+{
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+            || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation()
+            || ip.octets()[0] == 0 || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)),  // 0.0.0.0/8, 100.64.0.0/10 (CGNAT)
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public_ip(IpAddr::V4(v4)),
+            None => !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || ip.is_unique_local() || ip.is_unicast_link_local()),
+        },
+    }
+}
+
+/// Host of a URL, if it's an IP address
+fn host_ip(url: &reqwest::Url) -> Option<IpAddr> {
+    url.host_str()?.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+/// Resolve host of a URL, refusing it if any of its addresses isn't public (see `is_public_ip`)
+fn resolve_public(url: &reqwest::Url) -> anyhow::Result<Vec<SocketAddr>>
+{
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs = match host_ip(url) {
+        Some(ip) => vec![SocketAddr::new(ip, port)],
+        None => (url.host_str().unwrap_or(""), port).to_socket_addrs()?.collect::<Vec<_>>(),
+    };
+    if addrs.is_empty() { bail!("Host not found"); }
+    if addrs.iter().any(|a| !is_public_ip(a.ip())) {
+        bail!("URL points to a local or private network address");
+    }
+    Ok(addrs)
+}
+
+/// Check if Content-Type looks like a media file.
+/// Generic binary types are accepted too, since many file sharing services don't know the real type.
+/// HTML pages (usually a login or "click to download" page instead of the file) are not.
+fn is_acceptable_content_type(content_type: &str) -> bool
+{
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    mime.starts_with("video/") || mime.starts_with("audio/") || matches!(mime.as_str(),
+        "" | "application/octet-stream" | "binary/octet-stream" | "application/mxf" | "application/x-matroska")
+}
+
+/// Pick a filename for the downloaded file, from Content-Disposition header or URL path.
+/// Result never contains path separators.
+fn filename_for(url: &reqwest::Url, content_disposition: Option<&str>) -> String
+{
+    let from_header = content_disposition.and_then(|cd| cd.split(';')
+        .map(|p| p.trim())
+        .find_map(|p| p.strip_prefix("filename="))
+        .map(|f| f.trim_matches('"').to_string()));
+    let from_url = url.path_segments().and_then(|mut s| s.next_back())
+        .and_then(|s| urlencoding::decode(s).ok().map(|s| s.to_string()));
+
+    let name = from_header.or(from_url).unwrap_or_default();
+    let name = Path::new(&name.replace('\\', "/")).file_name()
+        .map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    if name.is_empty() || name.starts_with('.') { "download".into() } else { name }
+}
+
+/// Download a file from URL into a new, unique subdirectory of `upload_dir`.
+/// Blocks until done, so call from a separate thread. Partial downloads are removed on error.
+///
+/// # Arguments
+/// * `url` - URL to download
+/// * `upload_dir` - Directory to download into
+/// * `max_bytes` - Max file size (e.g. from user quota), or None if unlimited
+/// * `public_only` - Refuse local and private network addresses, also on redirects (for URLs from users)
+/// * `progress` - Called periodically with (bytes downloaded, total bytes if known)
+///
+/// # Returns
+/// * Path to the downloaded file
+pub fn download_url<F>(url: &reqwest::Url, upload_dir: &Path, max_bytes: Option<u64>, public_only: bool, mut progress: F) -> anyhow::Result<PathBuf>
+    where F: FnMut(u64, Option<u64>)
+{
+    // Blocking client applies the timeout to each read, not the whole download
+    let mut builder = reqwest::blocking::Client::builder()
+        .connect_timeout(Duration::from_secs(30))
+        .timeout(READ_TIMEOUT);
+    if public_only {
+        // Connect to the checked addresses, so a second DNS lookup can't point elsewhere
+        let addrs = resolve_public(url)?;
+        if let (None, Some(domain)) = (host_ip(url), url.host_str()) {
+            builder = builder.resolve_to_addrs(domain, &addrs);
+        }
+        builder = builder.redirect(reqwest::redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("Too many redirects")
+            } else if let Err(e) = resolve_public(attempt.url()) {
+                attempt.error(e.to_string())
+            } else {
+                attempt.follow()
+            }
+        }));
+    }
+    let client = builder.build()?;
+    let mut resp = client.get(url.clone()).send()?.error_for_status()?;
+
+    let hdr = |name| resp.headers().get(name).and_then(|v| v.to_str().ok()).map(|s| s.to_string());
+    let content_type = hdr(reqwest::header::CONTENT_TYPE).unwrap_or_default();
+    if !is_acceptable_content_type(&content_type) {
+        bail!("URL doesn't point to a media file (Content-Type '{}')", content_type);
+    }
+    let total = resp.content_length();
+    if let (Some(total), Some(max)) = (total, max_bytes) {
+        if total > max { bail!("File is too large ({} bytes, max {} allowed)", total, max); }
+    }
+
+    let new_dir = upload_dir.join(uuid::Uuid::new_v4().to_string());
+    let dst = new_dir.join(filename_for(resp.url(), hdr(reqwest::header::CONTENT_DISPOSITION).as_deref()));
+    std::fs::create_dir_all(&new_dir)?;
+
+    let copy_res = (|| -> anyhow::Result<()> {
+        let mut f = std::fs::File::create(&dst)?;
+        let mut buf = vec![0u8; 256 * 1024];
+        let mut n_bytes = 0u64;
+        let mut last_report = Instant::now();
+        loop {
+            let n = resp.read(&mut buf)?;
+            if n == 0 { break; }
+            n_bytes += n as u64;
+            if max_bytes.map(|m| n_bytes > m).unwrap_or(false) {
+                bail!("File exceeds quota (max {} bytes allowed)", max_bytes.unwrap_or(0));
+            }
+            f.write_all(&buf[..n])?;
+            if last_report.elapsed() >= PROGRESS_INTERVAL {
+                progress(n_bytes, total);
+                last_report = Instant::now();
+            }
+        }
+        if n_bytes == 0 { bail!("Downloaded file is empty"); }
+        progress(n_bytes, total);
+        Ok(())
+    })();
+
+    if let Err(e) = copy_res {
+        if let Err(e) = std::fs::remove_dir_all(&new_dir) {
+            tracing::warn!(details=%e, "Failed to remove incomplete download dir.");
+        }
+        return Err(e);
+    }
+    Ok(dst)
+}
+
+/// Marks a URL download of a user as running, for as long as it lives
+struct IngestGuard {
+    server: ServerState,
+    user_id: String,
+}
+
+impl IngestGuard {
+    /// # Returns
+    /// None if user already has `MAX_INGESTS_PER_USER` downloads running
+    fn acquire(server: &ServerState, user_id: &str) -> Option<IngestGuard> {
+        let mut running = server.url_ingests.lock().unwrap();
+        let n = running.entry(user_id.to_string()).or_insert(0);
+        if *n >= MAX_INGESTS_PER_USER { return None; }
+        *n += 1;
+        Some(IngestGuard { server: server.clone(), user_id: user_id.to_string() })
+    }
+}
+
+impl Drop for IngestGuard {
+    fn drop(&mut self) {
+        let mut running = self.server.url_ingests.lock().unwrap();
+        if let Some(n) = running.get_mut(&self.user_id) {
+            *n -= 1;
+            if *n == 0 { running.remove(&self.user_id); }
+        }
+    }
+}
+
+/// Download a URL in a background thread, reporting progress to the user,
+/// and submit the result to the processing pipeline like a regular upload.
+///
+/// # Returns
+/// * `Err(msg)` - User has too many downloads running already
+pub fn spawn_url_ingest(server: ServerState, user_id: String, url: reqwest::Url, max_bytes: Option<u64>, loudnorm: LoudnormOpt) -> Result<(), String>
+{
+    let guard = IngestGuard::acquire(&server, &user_id)
+        .ok_or(format!("You have {} downloads running already. Wait for them to finish.", MAX_INGESTS_PER_USER))?;
+    let msg_user_id = user_id.clone();
+    let notify = move |server: &ServerState, event_name: &str, msg: String, details: String| {
+        let persist = event_name != "progress";
+        if let Err(e) = server.push_user_message(&models::MessageInsert {
+                event_name: event_name.into(),
+                user_id: msg_user_id.clone(),
+                message: msg,
+                details,
+                ..Default::default() }, persist) {
+            tracing::error!(details=%e, "Failed to send URL ingest message to user.");
+        }
+    };
+    std::thread::spawn(move || {
+        let _guard = guard;
+        let _span = tracing::info_span!("url_ingest", url=%url).entered();
+        let res = download_url(&url, &server.upload_dir, max_bytes, true, |n, total| {
+            let msg = match total {
+                Some(t) if t > 0 => format!("Downloading... {:.0}%", n as f64 * 100.0 / t as f64),
+                _ => format!("Downloading... {:.1} MB", n as f64 / (1024.0 * 1024.0)),
+            };
+            notify(&server, "progress", msg, String::new());
+        });
+        match res {
+            Err(e) => {
+                tracing::info!(details=%e, "URL ingest failed.");
+                notify(&server, "error", "Download failed".into(), format!("{url}: {e}"));
+            },
+            Ok(file_path) => {
+                tracing::info!(file=%file_path.display(), "URL downloaded.");
+                if let Err(e) = server.upload_tx.send(IncomingFile { file_path, user_id, loudnorm, ..Default::default() }) {
+                    tracing::error!(details=%e, "Failed to submit downloaded file for processing.");
+                    notify(&server, "error", "Download failed".into(), "Internal error: couldn't submit file for processing".into());
+                }
+            },
+        }
+    });
+    Ok(())
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_parse_ingest_url()
+{
+    assert!(parse_ingest_url(" https://example.com/a.mp4 ").is_ok());
+    assert!(parse_ingest_url("http://example.com/").is_ok());
+    assert!(parse_ingest_url("ftp://example.com/a.mp4").is_err());
+    assert!(parse_ingest_url("file:///etc/passwd").is_err());
+    assert!(parse_ingest_url("not a url").is_err());
+}
+
+#[test]
+fn test_url_ingest_public_only()
+{
+    for url in ["http://127.0.0.1/a.mp4", "http://localhost:8095/api/health", "http://169.254.169.254/latest/meta-data/",
+            "http://10.1.2.3/", "http://192.168.0.1/", "http://[::1]/", "http://[fd00::1]/", "http://[::ffff:127.0.0.1]/", "http://0.0.0.0/"] {
+        let url = reqwest::Url::parse(url).unwrap();
+        assert!(resolve_public(&url).is_err(), "{url}");
+    }
+    assert!(is_public_ip("93.184.216.34".parse().unwrap()));
+    assert!(is_public_ip("2606:2800:220:1::1".parse().unwrap()));
+    assert!(!is_public_ip("100.64.1.1".parse().unwrap()));
+}
+
+#[test]
+fn test_url_ingest_content_type()
+{
+    assert!(is_acceptable_content_type("video/mp4"));
+    assert!(is_acceptable_content_type("Video/QuickTime; charset=binary"));
+    assert!(is_acceptable_content_type("application/octet-stream"));
+    assert!(is_acceptable_content_type(""));
+    assert!(!is_acceptable_content_type("text/html; charset=utf-8"));
+    assert!(!is_acceptable_content_type("application/json"));
+}
+
+#[test]
+fn test_url_ingest_filename()
+{
+    let url = reqwest::Url::parse("https://example.com/files/My%20Clip.mov?dl=1").unwrap();
+    assert_eq!(filename_for(&url, None), "My Clip.mov");
+    assert_eq!(filename_for(&url, Some("attachment; filename=\"final cut.mp4\"")), "final cut.mp4");
+    assert_eq!(filename_for(&url, Some("attachment; filename=\"../../etc/passwd\"")), "passwd");
+    assert_eq!(filename_for(&url, Some("attachment; filename=\"..\\evil.mp4\"")), "evil.mp4");
+    let url = reqwest::Url::parse("https://example.com/").unwrap();
+    assert_eq!(filename_for(&url, None), "download");
+}
@@ -0,0 +1,96 @@
+use std::path::Path;
+use ring::{constant_time, hmac};
+
+/// Signs and verifies time-limited URLs for files in the videos dir, so they can be served
+/// by a CDN or web server that doesn't know about users.
+///
+/// Signed URL is `<url_base><path>?expires=<unix time>&sig=<hex HMAC-SHA256(key, "<expires>:<path>")>`,
+/// where `path` is the (URL decoded) path relative to URL base, e.g. `/videos/abc123/video.mp4`.
+#[derive(Clone)]
+pub struct UrlSigner {
+    key: hmac::Key,
+    ttl_secs: u64,
+}
+
+/// Current time as seconds since Unix epoch
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl UrlSigner {
+    /// # Arguments
+    /// * `key` - Secret shared with whatever serves the files. At least 16 bytes.
+    /// * `ttl_secs` - How long a signed URL stays valid
+    pub fn new(key: &[u8], ttl_secs: u64) -> Result<UrlSigner, String>
+    {
+        if key.len() < 16 { return Err("URL signing key must be at least 16 bytes".into()); }
+        if ttl_secs == 0 { return Err("Signed URL lifetime must be > 0".into()); }
+        Ok(UrlSigner { key: hmac::Key::new(hmac::HMAC_SHA256, key), ttl_secs })
+    }
+
+    /// Read the key from a file (surrounding whitespace is ignored), to keep it out of the command line
+    pub fn from_key_file(path: &Path, ttl_secs: u64) -> Result<UrlSigner, String>
+    {
+        let key = std::fs::read_to_string(path).map_err(|e| format!("Failed to read URL signing key '{}': {}", path.display(), e))?;
+        UrlSigner::new(key.trim().as_bytes(), ttl_secs)
+    }
+
+    /// Expiry time for URLs signed at `now`. Rounded up to a quarter of TTL, so the same URL is
+    /// handed out for a while and stays cacheable (by browser and CDN).
+    fn expiry(&self, now: u64) -> u64 {
+        let step = (self.ttl_secs / 4).max(1);
+        (now + self.ttl_secs).div_ceil(step) * step
+    }
+
+    fn signature(&self, path: &str, expires: u64) -> String {
+        hex::encode(hmac::sign(&self.key, format!("{expires}:{path}").as_bytes()))
+    }
+
+    /// Query string that makes a URL for `path` valid until expiry
+    pub fn sign(&self, path: &str, now: u64) -> String
+    {
+        let expires = self.expiry(now);
+        format!("expires={}&sig={}", expires, self.signature(path, expires))
+    }
+
+    /// Check the signature (from query string) of a request for `path`
+    pub fn verify(&self, path: &str, query: &str, now: u64) -> Result<(), &'static str>
+    {
+        let param = |name: &str| query.split('&').filter_map(|p| p.split_once('=')).find(|(k, _)| *k == name).map(|(_, v)| v);
+        let (Some(expires), Some(sig)) = (param("expires").and_then(|e| e.parse::<u64>().ok()), param("sig")) else {
+            return Err("Missing URL signature");
+        };
+        let expected = self.signature(path, expires);
+        if constant_time::verify_slices_are_equal(expected.as_bytes(), sig.as_bytes()).is_err() {
+            return Err("Invalid URL signature");
+        }
+        if expires < now {
+            return Err("Link expired");
+        }
+        Ok(())
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_url_signing()
+{
+    assert!(UrlSigner::new(b"short", 60).is_err());
+    let s = UrlSigner::new(b"0123456789abcdef", 3600).unwrap();
+    let now = 1_700_000_000;
+    let q = s.sign("/videos/abc/video.mp4", now);
+    assert_eq!(q, "expires=1700003700&sig=25acf2d929d671e300bdcdbfaa88d285c9c30d63fb1a461babfcb30a3ded4312");
+    assert_eq!(q, s.sign("/videos/abc/video.mp4", now + 10), "URL should stay the same for a while");
+    let expires = q.split('&').next().unwrap().trim_start_matches("expires=").parse::<u64>().unwrap();
+    assert!(expires >= now + 3600 && expires <= now + 3600 + 900);
+
+    assert_eq!(s.verify("/videos/abc/video.mp4", &q, now), Ok(()));
+    assert_eq!(s.verify("/videos/abc/video.mp4", &format!("x=1&{q}"), expires), Ok(()));
+    assert_eq!(s.verify("/videos/abc/video.mp4", &q, expires + 1), Err("Link expired"));
+    assert_eq!(s.verify("/videos/abd/video.mp4", &q, now), Err("Invalid URL signature"));
+    assert_eq!(s.verify("/videos/abc/video.mp4", &q.replace(&expires.to_string(), &(expires + 1000).to_string()), now), Err("Invalid URL signature"));
+    assert_eq!(s.verify("/videos/abc/video.mp4", "", now), Err("Missing URL signature"));
+    assert_eq!(UrlSigner::new(b"fedcba9876543210", 3600).unwrap().verify("/videos/abc/video.mp4", &q, now), Err("Invalid URL signature"));
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use serde_json::Value;
+
+use crate::database::models;
+
+// Clients keep their settings (playback quality, notification sounds, UI options) on the server,
+// so they follow the user from browser to browser. Settings are JSON values by key, e.g.
+// `playback.quality`. Clients may store keys of their own, but `KNOWN_PREFS` are checked for type,
+// so that all clients read them the same. Each user's settings are capped in count and size.
+//
+// `set_prefs` changes several at once (null removes one), and all of the user's sessions get
+// the result as `prefs`, so other open browsers update too.
+
+/// Max length of a key
+pub const MAX_KEY_LEN: usize = 64;
+
+/// Max size of a value, as JSON
+pub const MAX_VALUE_BYTES: usize = 4 * 1024;
+
+/// Max number of settings per user
+pub const MAX_KEYS: usize = 200;
+
+/// Max size of all of a user's values, as JSON
+pub const MAX_TOTAL_BYTES: usize = 64 * 1024;
+
+/// What a known setting's value must be
+pub enum PrefKind {
+    Bool,
+    /// Number between min and max (inclusive)
+    Number(f64, f64),
+    /// One of these strings
+    OneOf(&'static [&'static str]),
+    Text,
+}
+
+/// Settings that clients share, with their types
+pub const KNOWN_PREFS: &[(&str, PrefKind)] = &[
+    ("playback.quality", PrefKind::OneOf(&["auto", "transcoded", "original"])),
+    ("playback.autoplay", PrefKind::Bool),
+    ("playback.loop", PrefKind::Bool),
+    ("playback.volume", PrefKind::Number(0.0, 1.0)),
+    ("notifications.sound", PrefKind::Bool),
+    ("notifications.desktop", PrefKind::Bool),
+    ("ui.theme", PrefKind::OneOf(&["system", "light", "dark"])),
+    ("ui.language", PrefKind::Text),     // Also for messages from server, see `i18n`
+];
+
+/// Is `key` dot separated words of lowercase letters, digits and underscores (e.g. `ui.sidebar_width`)
+fn is_valid_key(key: &str) -> bool
+{
+    key.len() <= MAX_KEY_LEN && key.split('.').all(|part| !part.is_empty()
+        && part.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'))
+}
+
+/// Check a value against its key's type, if known
+fn check_value(key: &str, v: &Value) -> Result<(), String>
+{
+    let Some((_, kind)) = KNOWN_PREFS.iter().find(|(k, _)| *k == key) else { return Ok(()) };
+    let ok = match kind {
+        PrefKind::Bool => v.is_boolean(),
+        PrefKind::Number(min, max) => v.as_f64().is_some_and(|n| n >= *min && n <= *max),
+        PrefKind::OneOf(options) => v.as_str().is_some_and(|s| options.contains(&s)),
+        PrefKind::Text => v.is_string(),
+    };
+    match (ok, kind) {
+        (true, _) => Ok(()),
+        (false, PrefKind::Bool) => Err(format!("'{key}' must be true or false")),
+        (false, PrefKind::Number(min, max)) => Err(format!("'{key}' must be a number from {min} to {max}")),
+        (false, PrefKind::OneOf(options)) => Err(format!("'{key}' must be one of: {}", options.join(", "))),
+        (false, PrefKind::Text) => Err(format!("'{key}' must be text")),
+    }
+}
+
+/// Settings as a JSON object for clients. Values that fail to parse are left out.
+pub fn to_json(prefs: &[models::UserPref]) -> Value
+{
+    Value::Object(prefs.iter().filter_map(|p| Some((p.key.clone(), serde_json::from_str(&p.value).ok()?))).collect())
+}
+
+/// Validate changes to a user's settings, against the limits with their current ones.
+///
+/// # Arguments
+/// * `current` - User's settings now
+/// * `changes` - Keys and new values, null to remove
+///
+/// # Returns
+/// * `Ok` - Changes for `DB::set_user_prefs`
+/// * `Err` - Why they were refused, to tell the user
+pub fn validate(current: &[models::UserPref], changes: &serde_json::Map<String, Value>) -> Result<Vec<(String, Option<String>)>, String>
+{
+    let mut after = current.iter().map(|p| (p.key.as_str(), p.value.len())).collect::<BTreeMap<_, _>>();
+    let mut res = vec![];
+    for (key, v) in changes {
+        if !is_valid_key(key) {
+            return Err(format!("Invalid setting name '{}' (lowercase words separated by dots, max {} characters)", key, MAX_KEY_LEN));
+        }
+        if v.is_null() {
+            after.remove(key.as_str());
+            res.push((key.clone(), None));
+            continue;
+        }
+        check_value(key, v)?;
+        let json = v.to_string();
+        if json.len() > MAX_VALUE_BYTES {
+            return Err(format!("Value of '{}' is too big (max {} bytes)", key, MAX_VALUE_BYTES));
+        }
+        after.insert(key, json.len());
+        res.push((key.clone(), Some(json)));
+    }
+    if after.len() > MAX_KEYS {
+        return Err(format!("Too many settings (max {})", MAX_KEYS));
+    }
+    if after.values().sum::<usize>() > MAX_TOTAL_BYTES {
+        return Err(format!("Settings are too big (max {} kB in total)", MAX_TOTAL_BYTES / 1024));
+    }
+    Ok(res)
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_user_prefs_validation()
+{
+    use serde_json::json;
+    let changes = |v: Value| v.as_object().unwrap().clone();
+    let pref = |key: &str, value: &str| models::UserPref {
+        user_id: "u".into(), key: key.into(), value: value.into(), updated: chrono::Utc::now().naive_utc() };
+
+    let res = validate(&[], &changes(json!({"playback.quality": "original", "ui.sidebar_width": 320, "ui.panels": {"left": true}, "ui.theme": null}))).unwrap();
+    assert_eq!(res, vec![
+        ("playback.quality".into(), Some("\"original\"".into())),
+        ("ui.panels".into(), Some("{\"left\":true}".into())),
+        ("ui.sidebar_width".into(), Some("320".into())),
+        ("ui.theme".into(), None)]);
+
+    // Known settings are type checked
+    assert!(validate(&[], &changes(json!({"playback.quality": "8k"}))).unwrap_err().contains("one of"));
+    assert!(validate(&[], &changes(json!({"playback.volume": 1.5}))).is_err());
+    assert!(validate(&[], &changes(json!({"playback.autoplay": "yes"}))).is_err());
+    assert!(validate(&[], &changes(json!({"playback.volume": 0.25, "ui.language": "fi"}))).is_ok());
+
+    // Keys
+    for key in ["UI.theme", "ui..theme", ".ui", "ui theme", "ui/theme", ""] {
+        assert!(validate(&[], &changes(json!({key: 1}))).is_err(), "{key}");
+    }
+    assert!(validate(&[], &changes(json!({("x".repeat(MAX_KEY_LEN + 1)): 1}))).is_err());
+
+    // Size caps, counting current settings, minus removed ones
+    assert!(validate(&[], &changes(json!({"ui.notes": "x".repeat(MAX_VALUE_BYTES)}))).is_err());
+    let big = (0..MAX_TOTAL_BYTES / 4000).map(|i| pref(&format!("ui.k{i}"), &"1".repeat(4000))).collect::<Vec<_>>();
+    assert!(validate(&big, &changes(json!({"ui.more": "x".repeat(3000)}))).unwrap_err().contains("too big"));
+    assert!(validate(&big, &changes(json!({"ui.more": "x".repeat(3000), "ui.k0": null}))).is_ok());
+    let many = (0..MAX_KEYS).map(|i| pref(&format!("ui.k{i}"), "1")).collect::<Vec<_>>();
+    assert!(validate(&many, &changes(json!({"ui.more": 1}))).unwrap_err().contains("Too many"));
+    assert!(validate(&many, &changes(json!({"ui.k1": 2}))).is_ok(), "Replacing doesn't add");
+}
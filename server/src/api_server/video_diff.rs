@@ -0,0 +1,239 @@
+use serde_json::json;
+use crate::database::models;
+
+/// Technical metadata of a video that is relevant when comparing versions.
+/// Parsed from DB columns and the raw mediainfo JSON (`raw_metadata_all`).
+#[derive(Debug, Default, Clone, PartialEq)]
+struct TechMetadata {
+    resolution: Option<String>,
+    video_codec: Option<String>,
+    video_bitrate: Option<u64>,
+    fps: Option<String>,
+    duration: Option<f32>,
+    total_frames: Option<i32>,
+    audio_codec: Option<String>,
+    audio_channels: Option<String>,
+    audio_sample_rate: Option<String>,
+    loudness_lufs: Option<f32>,
+    dynamic_range: Option<String>,
+}
+
+impl TechMetadata {
+    fn from_video(v: &models::Video) -> TechMetadata
+    {
+        let json = v.raw_metadata_all.as_ref()
+            .and_then(|s| serde_json::from_str::<serde_json::Value>(s).ok())
+            .unwrap_or_default();
+        let tracks = json["media"]["track"].as_array().cloned().unwrap_or_default();
+        let track = |typ: &str| tracks.iter().find(|t| t["@type"] == typ).cloned().unwrap_or_default();
+        let (video, audio) = (track("Video"), track("Audio"));
+        let str_of = |t: &serde_json::Value, key: &str| t[key].as_str().map(|s| s.to_string());
+
+        TechMetadata {
+            resolution: match (str_of(&video, "Width"), str_of(&video, "Height")) {
+                (Some(w), Some(h)) => Some(format!("{}x{}", w, h)),
+                _ => None },
+            video_codec: str_of(&video, "Format"),
+            video_bitrate: str_of(&video, "BitRate").or(str_of(&video, "BitRate_Nominal")).and_then(|s| s.parse().ok()),
+            fps: v.fps.clone(),
+            duration: v.duration,
+            total_frames: v.total_frames,
+            audio_codec: str_of(&audio, "Format"),
+            audio_channels: str_of(&audio, "Channels"),
+            audio_sample_rate: str_of(&audio, "SamplingRate"),
+            loudness_lufs: v.loudness_lufs,
+            dynamic_range: v.raw_metadata_all.as_ref().map(|_| v.hdr_format.clone().unwrap_or("SDR".into())),
+        }
+    }
+}
+
+/// Compare technical metadata (resolution, codecs, duration, fps, loudness etc.)
+/// of two versions of a video.
+///
+/// # Arguments
+/// * `a` - Old version
+/// * `b` - New version
+///
+/// # Returns
+/// * JSON object with a list of compared `fields` (name, a, b, changed, delta),
+///   and a human readable `summary` of what changed from `a` to `b`.
+pub fn diff_video_metadata(a: &models::Video, b: &models::Video) -> serde_json::Value
+{
+    let (ma, mb) = (TechMetadata::from_video(a), TechMetadata::from_video(b));
+    let mut fields = vec![];
+    let mut summary = vec![];
+
+    fn field(name: &str, a: serde_json::Value, b: serde_json::Value, changed: bool, delta: Option<f64>) -> serde_json::Value {
+        json!({ "name": name, "a": a, "b": b, "changed": changed, "delta": delta })
+    }
+    fn more_or_less(delta: f64, more: &str, less: &str) -> String {
+        if delta > 0.0 { more.into() } else { less.into() }
+    }
+
+    // Strings that are simply equal or not
+    for (name, va, vb, label) in [
+        ("resolution", &ma.resolution, &mb.resolution, "resolution"),
+        ("video_codec", &ma.video_codec, &mb.video_codec, "video codec"),
+        ("audio_codec", &ma.audio_codec, &mb.audio_codec, "audio codec"),
+        ("audio_channels", &ma.audio_channels, &mb.audio_channels, "audio channels"),
+        ("audio_sample_rate", &ma.audio_sample_rate, &mb.audio_sample_rate, "audio sample rate"),
+        ("dynamic_range", &ma.dynamic_range, &mb.dynamic_range, "dynamic range"),
+    ] {
+        let changed = va != vb;
+        if changed {
+            summary.push(match vb {
+                Some(vb) => format!("{} now {}", label, vb),
+                None => format!("no {}", label),
+            });
+        }
+        fields.push(field(name, json!(va), json!(vb), changed, None));
+    }
+
+    // FPS is stored as a string ("30", "23.976"), so compare numerically when possible
+    let fps_num = |s: &Option<String>| s.as_ref().and_then(|s| s.parse::<f64>().ok());
+    let fps_delta = fps_num(&ma.fps).zip(fps_num(&mb.fps)).map(|(a, b)| b - a);
+    let fps_changed = match fps_delta {
+        Some(d) => d.abs() > 0.0005,
+        None => ma.fps != mb.fps,
+    };
+    if fps_changed {
+        if let Some(fps) = &mb.fps { summary.push(format!("now {} fps", fps)); }
+    }
+    fields.push(field("fps", json!(ma.fps), json!(mb.fps), fps_changed, fps_delta));
+
+    // Length. Prefer frame count for the summary, as it's exact.
+    let frames_delta = ma.total_frames.zip(mb.total_frames).map(|(a, b)| (b - a) as f64);
+    let frames_changed = frames_delta.map(|d| d != 0.0).unwrap_or(ma.total_frames != mb.total_frames);
+    let dur_delta = ma.duration.zip(mb.duration).map(|(a, b)| (b - a) as f64);
+    let dur_changed = dur_delta.map(|d| d.abs() > 0.001).unwrap_or(ma.duration != mb.duration);
+    if let (true, Some(d)) = (frames_changed, frames_delta) {
+        summary.push(format!("{} frame{} {}", d.abs(), if d.abs() == 1.0 {""} else {"s"}, more_or_less(d, "longer", "shorter")));
+    } else if let (true, Some(d)) = (dur_changed, dur_delta) {
+        summary.push(format!("{:.2} s {}", d.abs(), more_or_less(d, "longer", "shorter")));
+    }
+    fields.push(field("total_frames", json!(ma.total_frames), json!(mb.total_frames), frames_changed, frames_delta));
+    fields.push(field("duration", json!(ma.duration), json!(mb.duration), dur_changed, dur_delta));
+
+    let br_delta = ma.video_bitrate.zip(mb.video_bitrate).map(|(a, b)| b as f64 - a as f64);
+    let br_changed = ma.video_bitrate != mb.video_bitrate;
+    if br_changed {
+        if let Some(br) = mb.video_bitrate { summary.push(format!("video bitrate now {} kbps", br / 1000)); }
+    }
+    fields.push(field("video_bitrate", json!(ma.video_bitrate), json!(mb.video_bitrate), br_changed, br_delta));
+
+    // Loudness differences under 0.1 LU are inaudible and mostly measurement noise
+    let loud_delta = ma.loudness_lufs.zip(mb.loudness_lufs).map(|(a, b)| (b - a) as f64);
+    let loud_changed = loud_delta.map(|d| d.abs() >= 0.1).unwrap_or(ma.loudness_lufs != mb.loudness_lufs);
+    if let (true, Some(d)) = (loud_changed, loud_delta) {
+        summary.push(format!("{:.1} LU {}", d.abs(), more_or_less(d, "louder", "quieter")));
+    }
+    fields.push(field("loudness_lufs", json!(ma.loudness_lufs), json!(mb.loudness_lufs), loud_changed, loud_delta));
+
+    json!({
+        "video_hash_a": a.video_hash,
+        "video_hash_b": b.video_hash,
+        "fields": fields,
+        "summary": summary,
+    })
+}
+
+
+// Unit tests =====================================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mkvid(hash: &str, width: u32, fps: &str, frames: i32, loudness: Option<f32>) -> models::Video {
+        models::Video {
+            id: 1,
+            video_hash: hash.into(),
+            added_by_userid: None,
+            added_by_username: None,
+            added_time: chrono::NaiveDateTime::default(),
+            recompression_done: None,
+            thumb_sheet_dims: None,
+            orig_filename: None,
+            title: None,
+            total_frames: Some(frames),
+            duration: Some(frames as f32 / fps.parse::<f32>().unwrap()),
+            fps: Some(fps.into()),
+            raw_metadata_all: Some(format!(r#"{{ "media": {{ "track": [
+                {{ "@type": "Video", "Width": "{}", "Height": "1080", "Format": "AVC", "BitRate": "2000000" }},
+                {{ "@type": "Audio", "Format": "AAC", "Channels": "2", "SamplingRate": "48000" }} ] }} }}"#, width)),
+            silence_trim_start: None,
+            silence_trim_end: None,
+            loudness_lufs: loudness,
+            legal_hold: false,
+            content_hash: None,
+            loudnorm_target: None,
+            color_primaries: None,
+            color_transfer: None,
+            hdr_format: None,
+            vfr_fps_min: None,
+            vfr_fps_max: None,
+            vfr_fps_avg: None,
+            image_sequence: None,
+            audio_only: false,
+            still_kind: None,
+            page_count: None,
+            allow_download: true,
+            folder_id: None,
+            trashed: None,
+        }
+    }
+
+    fn changed_fields(diff: &serde_json::Value) -> Vec<String> {
+        diff["fields"].as_array().unwrap().iter()
+            .filter(|f| f["changed"].as_bool().unwrap())
+            .map(|f| f["name"].as_str().unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn test_diff_identical()
+    {
+        let a = mkvid("AAAA", 1920, "25", 250, Some(-23.0));
+        let diff = diff_video_metadata(&a, &a);
+        assert!(changed_fields(&diff).is_empty());
+        assert!(diff["summary"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diff_changes()
+    {
+        let a = mkvid("AAAA", 1920, "25", 250, Some(-23.0));
+        let b = mkvid("BBBB", 1440, "23.976", 248, Some(-20.5));
+        let diff = diff_video_metadata(&a, &b);
+
+        assert_eq!(diff["video_hash_a"], "AAAA");
+        assert_eq!(diff["video_hash_b"], "BBBB");
+        assert_eq!(changed_fields(&diff), vec!["resolution", "fps", "total_frames", "duration", "loudness_lufs"]);
+
+        let frames = diff["fields"].as_array().unwrap().iter().find(|f| f["name"] == "total_frames").unwrap();
+        assert_eq!(frames["delta"].as_f64(), Some(-2.0));
+
+        let summary = diff["summary"].as_array().unwrap().iter().map(|s| s.as_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(summary, vec!["resolution now 1440x1080", "now 23.976 fps", "2 frames shorter", "2.5 LU louder"]);
+    }
+
+    #[test]
+    fn test_diff_missing_metadata()
+    {
+        let a = mkvid("AAAA", 1920, "25", 250, None);
+        let mut b = a.clone();
+        b.raw_metadata_all = None;
+        let diff = diff_video_metadata(&a, &b);
+        assert!(changed_fields(&diff).contains(&"audio_codec".to_string()));
+        assert!(diff["summary"].as_array().unwrap().contains(&json!("no audio codec")));
+    }
+
+    #[test]
+    fn test_diff_dynamic_range()
+    {
+        let a = mkvid("AAAA", 1920, "25", 250, None);
+        let b = models::Video { hdr_format: Some(models::hdr_format::HLG.into()), ..a.clone() };
+        let diff = diff_video_metadata(&a, &b);
+        assert_eq!(changed_fields(&diff), vec!["dynamic_range"]);
+        assert_eq!(diff["summary"], json!(["dynamic range now HLG"]));
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+
+use super::{SendTo, WsSessionArgs};
+
+// Clients can keep their video list up to date without reloading all of it on every change.
+// After `list_my_videos` with `subscribe: true`, the session remembers the query and the videos
+// it listed. Changes that show in listings are journaled in the DB by triggers (`video_changes`),
+// so changes by the video pipeline, other users and other server instances are all seen.
+// About once a second, the session checks the journal. If any of its videos (or videos that could
+// join its list) have changed, it lists them again and sends the differences, in this order:
+//
+// - `video_removed` {video_hash} - No longer in the list
+// - `video_added` {video, index} - New in the list, at `index` of the new list
+// - `video_updated` {video} - Some of its fields changed
+// - `video_order` {video_hashes} - Order changed other than by the above (e.g. a video was
+//   renamed when sorting by title), all of the list in the new order
+//
+// For paged listings the deltas are about the page. Groups (`group_by`) and `page` aren't
+// updated, clients can work them out from the videos or list again.
+
+/// How often the journal is checked
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Journal entries older than this are deleted (see `retention`)
+pub const KEEP_CHANGES: Duration = Duration::from_secs(3600);
+
+/// Video listing a session gets deltas of
+pub struct VideoListSub {
+    /// `list_my_videos` data
+    query: Value,
+    /// Videos last sent to the client, in order
+    listed: Vec<Value>,
+    /// Latest journal entry already handled
+    last_change: i64,
+    last_check: Instant,
+}
+
+impl VideoListSub {
+    /// # Arguments
+    /// * `query` - `list_my_videos` data
+    /// * `videos` - Videos sent to the client
+    /// * `last_change` - Latest journal entry before listing them
+    pub fn new(query: Value, videos: &Value, last_change: i64) -> VideoListSub {
+        VideoListSub { query, listed: videos.as_array().cloned().unwrap_or_default(), last_change, last_check: Instant::now() }
+    }
+}
+
+/// Fields that change just by time passing (e.g. "5 minutes ago"), not worth an update
+const TIME_RELATIVE_FIELDS: &[&str] = &["added_time"];
+
+fn hash_of(v: &Value) -> &str {
+    v["video_hash"].as_str().unwrap_or_default()
+}
+
+/// Is a listed video the same as before, not counting time relative fields
+fn same_video(a: &Value, b: &Value) -> bool {
+    match (a.as_object(), b.as_object()) {
+        (Some(a), Some(b)) => {
+            let fields = |o: &'_ serde_json::Map<String, Value>| o.iter().filter(|(k, _)| !TIME_RELATIVE_FIELDS.contains(&k.as_str())).count();
+            fields(a) == fields(b) && a.iter().all(|(k, v)| TIME_RELATIVE_FIELDS.contains(&k.as_str()) || b.get(k) == Some(v))
+        },
+        _ => a == b,
+    }
+}
+
+/// Deltas (command and data) that turn `old` video list into `new`
+pub fn diff(old: &[Value], new: &[Value]) -> Vec<(&'static str, Value)> {
+    let old_by_hash = old.iter().map(|v| (hash_of(v), v)).collect::<HashMap<_, _>>();
+    let new_hashes = new.iter().map(hash_of).collect::<HashSet<_>>();
+    let mut res = vec![];
+    for v in old.iter().filter(|v| !new_hashes.contains(hash_of(v))) {
+        res.push(("video_removed", json!({ "video_hash": hash_of(v) })));
+    }
+    for (i, v) in new.iter().enumerate().filter(|(_, v)| !old_by_hash.contains_key(hash_of(v))) {
+        res.push(("video_added", json!({ "video": v, "index": i })));
+    }
+    for v in new.iter().filter(|v| old_by_hash.get(hash_of(v)).is_some_and(|o| !same_video(o, v))) {
+        res.push(("video_updated", json!({ "video": v })));
+    }
+    let kept_old = old.iter().map(hash_of).filter(|h| new_hashes.contains(h)).collect::<Vec<_>>();
+    let kept_new = new.iter().map(hash_of).filter(|h| old_by_hash.contains_key(h)).collect::<Vec<_>>();
+    if kept_old != kept_new {
+        res.push(("video_order", json!({ "video_hashes": new.iter().map(hash_of).collect::<Vec<_>>() })));
+    }
+    res
+}
+
+/// Send the client deltas of its subscribed video list, if it has changed.
+/// Checks at most every `POLL_INTERVAL`.
+pub async fn check(ses: &mut WsSessionArgs<'_>) -> anyhow::Result<()> {
+    let Some(sub) = &mut ses.video_list_sub else { return Ok(()); };
+    if sub.last_check.elapsed() < POLL_INTERVAL {
+        return Ok(());
+    }
+    sub.last_check = Instant::now();
+    let (latest, changed) = ses.server.db.get_video_changes(sub.last_change)?;
+    sub.last_change = latest;
+    let listed = changed.iter().any(|vh| sub.listed.iter().any(|v| hash_of(v) == vh));
+    if changed.is_empty() || !(listed || ses.server.db.any_user_video(ses.user_id, &changed)?) {
+        return Ok(());
+    }
+
+    let query = sub.query.clone();
+    let new = match super::ws_handers::user_videos_json(&query, ses).await? {
+        Ok(msg) => msg["videos"].as_array().cloned().unwrap_or_default(),
+        Err(msg) => {
+            tracing::warn!(details=msg, "Subscribed video list query failed. Unsubscribing.");
+            ses.video_list_sub = None;
+            return Ok(());
+        }
+    };
+    let Some(sub) = &mut ses.video_list_sub else { return Ok(()); };
+    let deltas = diff(&sub.listed, &new);
+    sub.listed = new;
+    for (cmd, data) in deltas {
+        ses.emit_cmd(cmd, &data, SendTo::CurSession())?;
+    }
+    Ok(())
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_video_list_diff()
+{
+    let v = |vh: &str, title: &str| json!({ "video_hash": vh, "title": title });
+    let old = vec![v("a", "A"), v("b", "B"), v("c", "C")];
+
+    assert!(diff(&old, &old).is_empty());
+    let aged = old.iter().map(|v| { let mut v = v.clone(); v["added_time"] = json!("1 minute ago"); v }).collect::<Vec<_>>();
+    assert!(diff(&old, &aged).is_empty(), "Relative times change by themselves");
+
+    // Removed, added, updated
+    let new = vec![v("a", "A"), v("x", "X"), v("c", "C2")];
+    let d = diff(&old, &new);
+    assert_eq!(d, vec![
+        ("video_removed", json!({ "video_hash": "b" })),
+        ("video_added", json!({ "video": v("x", "X"), "index": 1 })),
+        ("video_updated", json!({ "video": v("c", "C2") })),
+    ]);
+
+    // Reordered (e.g. renamed under title sort)
+    let new = vec![v("b", "B"), v("c", "C"), v("a", "Z")];
+    let d = diff(&old, &new);
+    assert_eq!(d.len(), 2);
+    assert_eq!(d[0].0, "video_updated");
+    assert_eq!(d[1], ("video_order", json!({ "video_hashes": ["b", "c", "a"] })));
+
+    // Adding to the front shifts others, but isn't a reorder
+    let new = vec![v("n", "N"), v("a", "A"), v("b", "B"), v("c", "C")];
+    assert_eq!(diff(&old, &new), vec![("video_added", json!({ "video": v("n", "N"), "index": 0 }))]);
+}
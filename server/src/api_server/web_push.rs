@@ -0,0 +1,320 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD as B64};
+use ring::{aead, agreement, hkdf, rand as ring_rand, signature};
+use ring::signature::KeyPair;
+use serde_json::json;
+
+use crate::database::{models, DB};
+use super::server_state::ServerState;
+use super::url_signing::unix_now;
+
+// Web Push (RFC 8030) shows desktop notifications even when no Clapshot tab is open. Browsers
+// subscribe (with the Push API) using the server's VAPID public key, and the client registers the
+// subscription with `push_subscribe`. Notifications are POSTed to the subscription's endpoint at the
+// browser vendor's push service, signed with the server's VAPID key (RFC 8292) and encrypted for
+// the browser (RFC 8291, "aes128gcm").
+//
+// Users opt in per event (`set_push_settings`): mentions, new comments on their own videos, and
+// processing results (uploads, exports). The VAPID key pair is generated on first use and kept
+// in data dir, so subscriptions stay valid over restarts. Delivery is best effort, like webhooks;
+// subscriptions the push service says are gone (404, 410) are deleted.
+
+/// Events users can opt in to (see `models::PushPrefs`)
+pub mod push_kind {
+    pub const MENTION: &str = "mentions";
+    pub const COMMENT: &str = "comments";
+    pub const PROCESSING: &str = "processing";
+    pub const ALL: &[&str] = &[MENTION, COMMENT, PROCESSING];
+}
+
+/// VAPID private key (PKCS#8), in data dir
+pub const KEY_FILE: &str = "vapid_key.pk8";
+
+/// How long push services keep undelivered notifications (seconds)
+const TTL: u32 = 24 * 3600;
+
+/// Notification body is cut to this many chars, to stay well under push services' 4 kB limit
+const MAX_BODY_CHARS: usize = 300;
+
+/// Server's VAPID identity
+pub struct WebPush {
+    key_file: PathBuf,
+    /// Contact for push services (server's URL)
+    subject: String,
+    key: Mutex<Option<Arc<signature::EcdsaKeyPair>>>,
+}
+
+impl WebPush {
+    pub fn new(data_dir: &Path, subject: &str) -> WebPush {
+        WebPush { key_file: data_dir.join(KEY_FILE), subject: subject.into(), key: Mutex::new(None) }
+    }
+
+    /// Key pair, read from key file (or generated and saved, if there's none yet)
+    fn key(&self) -> anyhow::Result<Arc<signature::EcdsaKeyPair>>
+    {
+        let mut key = self.key.lock().map_err(|_| anyhow!("VAPID key lock poisoned"))?;
+        if let Some(k) = &*key {
+            return Ok(k.clone());
+        }
+        let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = match std::fs::read(&self.key_file) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let doc = signature::EcdsaKeyPair::generate_pkcs8(alg, &ring_rand::SystemRandom::new())
+                    .map_err(|_| anyhow!("Failed to generate VAPID key"))?;
+                std::fs::write(&self.key_file, doc.as_ref()).context("Failed to save VAPID key")?;
+                tracing::info!(file=%self.key_file.display(), "Generated VAPID key for Web Push.");
+                doc.as_ref().to_vec()
+            },
+            Err(e) => bail!("Failed to read VAPID key: {e}"),
+        };
+        let k = Arc::new(signature::EcdsaKeyPair::from_pkcs8(alg, &pkcs8).map_err(|e| anyhow!("Invalid VAPID key: {e}"))?);
+        *key = Some(k.clone());
+        Ok(k)
+    }
+
+    /// Public key for browsers' `pushManager.subscribe()` (`applicationServerKey`), base64url
+    pub fn public_key(&self) -> anyhow::Result<String>
+    {
+        Ok(B64.encode(self.key()?.public_key().as_ref()))
+    }
+
+    /// Authorization header for a push service: a signed JWT for the endpoint's origin, and public key
+    pub fn vapid_auth(&self, endpoint: &reqwest::Url, now: u64) -> anyhow::Result<String>
+    {
+        let key = self.key()?;
+        let header = B64.encode(json!({ "typ": "JWT", "alg": "ES256" }).to_string());
+        let claims = B64.encode(json!({
+            "aud": endpoint.origin().ascii_serialization(),
+            "exp": now + 12 * 3600,
+            "sub": self.subject }).to_string());
+        let unsigned = format!("{header}.{claims}");
+        let sig = key.sign(&ring_rand::SystemRandom::new(), unsigned.as_bytes()).map_err(|_| anyhow!("Failed to sign VAPID token"))?;
+        Ok(format!("vapid t={unsigned}.{}, k={}", B64.encode(sig.as_ref()), B64.encode(key.public_key().as_ref())))
+    }
+}
+
+/// Decode base64url, with or without padding (browsers differ)
+fn b64_decode(s: &str) -> anyhow::Result<Vec<u8>>
+{
+    Ok(B64.decode(s.trim().trim_end_matches('='))?)
+}
+
+/// Check that subscription keys from a client are usable
+pub fn check_keys(p256dh: &str, auth: &str) -> anyhow::Result<()>
+{
+    match (b64_decode(p256dh)?.len(), b64_decode(auth)?.len()) {
+        (65, 16) => Ok(()),
+        _ => bail!("Invalid subscription keys"),
+    }
+}
+
+/// Output length for HKDF expand
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize { self.0 }
+}
+
+fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> anyhow::Result<Vec<u8>>
+{
+    let mut out = vec![0u8; len];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(ikm)
+        .expand(&[info], Len(len)).and_then(|okm| okm.fill(&mut out))
+        .map_err(|_| anyhow!("HKDF failed"))?;
+    Ok(out)
+}
+
+/// Content encryption key and nonce (RFC 8291 section 3.4 and RFC 8188 section 2.2)
+fn derive_key_nonce(ecdh_secret: &[u8], auth: &[u8], ua_public: &[u8], as_public: &[u8], salt: &[u8]) -> anyhow::Result<(Vec<u8>, Vec<u8>)>
+{
+    let key_info = [b"WebPush: info\0".as_slice(), ua_public, as_public].concat();
+    let ikm = hkdf(auth, ecdh_secret, &key_info, 32)?;
+    Ok((hkdf(salt, &ikm, b"Content-Encoding: aes128gcm\0", 16)?, hkdf(salt, &ikm, b"Content-Encoding: nonce\0", 12)?))
+}
+
+/// Encrypt a payload for a browser (single "aes128gcm" record)
+///
+/// # Arguments
+/// * `payload` - Plaintext
+/// * `p256dh` - Browser's public key (base64url)
+/// * `auth` - Browser's auth secret (base64url)
+pub fn encrypt(payload: &[u8], p256dh: &str, auth: &str) -> anyhow::Result<Vec<u8>>
+{
+    use ring::rand::SecureRandom;
+    let (ua_public, auth) = (b64_decode(p256dh)?, b64_decode(auth)?);
+    let rng = ring_rand::SystemRandom::new();
+    let as_private = agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng).map_err(|_| anyhow!("Key generation failed"))?;
+    let as_public = as_private.compute_public_key().map_err(|_| anyhow!("Key generation failed"))?.as_ref().to_vec();
+    let ecdh_secret = agreement::agree_ephemeral(as_private, &agreement::UnparsedPublicKey::new(&agreement::ECDH_P256, &ua_public),
+        anyhow!("Invalid subscription key"), |s| Ok(s.to_vec()))?;
+    let mut salt = [0u8; 16];
+    rng.fill(&mut salt).map_err(|_| anyhow!("RNG failed"))?;
+    let (cek, nonce) = derive_key_nonce(&ecdh_secret, &auth, &ua_public, &as_public, &salt)?;
+
+    // Payload, and delimiter of the last (only) record
+    let mut data = [payload, &[2u8]].concat();
+    let key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, &cek).map_err(|_| anyhow!("Invalid key"))?);
+    key.seal_in_place_append_tag(aead::Nonce::try_assume_unique_for_key(&nonce).map_err(|_| anyhow!("Invalid nonce"))?, aead::Aad::empty(), &mut data)
+        .map_err(|_| anyhow!("Encryption failed"))?;
+
+    // Header: salt, record size, key id (server's public key)
+    let rs = (data.len() as u32).max(4096);
+    Ok([salt.as_slice(), &rs.to_be_bytes(), &[as_public.len() as u8], &as_public, &data].concat())
+}
+
+/// Post an encrypted notification to a subscription's push service
+///
+/// # Returns
+/// * `Ok(true)` - Delivered (accepted by push service)
+/// * `Ok(false)` - Subscription is gone (expired or unsubscribed)
+fn post(wp: &WebPush, sub: &models::PushSubscription, payload: &[u8]) -> anyhow::Result<bool>
+{
+    let url = reqwest::Url::parse(&sub.endpoint)?;
+    let body = encrypt(payload, &sub.p256dh, &sub.auth)?;
+    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs(30)).build()?;
+    let res = client.post(url.clone())
+        .header("TTL", TTL.to_string())
+        .header("Content-Encoding", "aes128gcm")
+        .header("Content-Type", "application/octet-stream")
+        .header("Authorization", wp.vapid_auth(&url, unix_now())?)
+        .body(body).send()?;
+    match res.status().as_u16() {
+        404 | 410 => Ok(false),
+        _ => { res.error_for_status()?; Ok(true) },
+    }
+}
+
+/// Push a notification to user's subscribed browsers, in background, if user has opted in to the kind.
+///
+/// # Arguments
+/// * `server` - Server state
+/// * `user_id` - Recipient
+/// * `kind` - Event kind (see `push_kind`)
+/// * `title` - Notification title, in English (translated to user's language, see `i18n`)
+/// * `body` - Notification text (cut if long)
+/// * `video_hash` - Video the notification is about, if any, for opening it on click
+pub fn notify(server: &ServerState, user_id: &str, kind: &str, title: &str, body: &str, video_hash: Option<&str>)
+{
+    let subs = match wanted_subscriptions(&server.db, user_id, kind) {
+        Ok(subs) if subs.is_empty() => return,
+        Ok(subs) => subs,
+        Err(e) => { tracing::error!(user=user_id, details=%e, "Failed to get push subscriptions."); return; },
+    };
+    let body = match body.chars().count() > MAX_BODY_CHARS {
+        true => body.chars().take(MAX_BODY_CHARS - 1).collect::<String>() + "…",
+        false => body.to_string(),
+    };
+    let title = super::i18n::tr(server, user_id, title);
+    let payload = json!({ "kind": kind, "title": title, "body": body, "video_hash": video_hash, "url": server.url_base }).to_string();
+    let (wp, db, kind) = (server.web_push.clone(), server.db.clone(), kind.to_string());
+    std::thread::spawn(move || {
+        for sub in subs {
+            match post(&wp, &sub, payload.as_bytes()) {
+                Ok(true) => tracing::debug!(user=sub.user_id, kind, "Push notification sent."),
+                Ok(false) => {
+                    tracing::info!(user=sub.user_id, endpoint=sub.endpoint, "Push subscription is gone. Removing it.");
+                    if let Err(e) = db.del_push_subscription(&sub.endpoint, None) {
+                        tracing::error!(details=%e, "Failed to remove push subscription.");
+                    }
+                },
+                Err(e) => tracing::warn!(user=sub.user_id, kind, details=%e, "Push notification failed."),
+            }
+        }
+    });
+}
+
+/// User's subscriptions, if user wants this kind of events pushed
+fn wanted_subscriptions(db: &DB, user_id: &str, kind: &str) -> anyhow::Result<Vec<models::PushSubscription>>
+{
+    let prefs = db.get_push_prefs(user_id)?;
+    let wanted = match kind {
+        push_kind::MENTION => prefs.mentions,
+        push_kind::COMMENT => prefs.comments,
+        push_kind::PROCESSING => prefs.processing,
+        _ => bail!("Unknown push kind '{kind}'"),
+    };
+    Ok(if wanted { db.get_push_subscriptions(user_id)? } else { vec![] })
+}
+
+/// Push a new comment to the video's owner, unless owner wrote it or was mentioned in it
+/// (mentions are pushed separately).
+pub fn notify_new_comment(server: &ServerState, v: &models::Video, c: &models::Comment, mentioned: &[String])
+{
+    let owner = match &v.added_by_userid {
+        Some(owner) if *owner != c.user_id && !mentioned.contains(owner) => owner,
+        _ => return,
+    };
+    let title = format!("{} commented on '{}'", c.username, v.title.as_deref().unwrap_or(&v.video_hash));
+    notify(server, owner, push_kind::COMMENT, &title, &c.comment, Some(&v.video_hash));
+}
+
+
+// Unit tests =====================================================================================
+
+/// Decrypt like a browser would, for tests
+#[cfg(test)]
+pub(crate) fn decrypt_for_test(msg: &[u8], ua_private: agreement::EphemeralPrivateKey, ua_public: &[u8], auth: &[u8]) -> Vec<u8>
+{
+    let (salt, rest) = msg.split_at(16);
+    let id_len = rest[4] as usize;
+    let (as_public, data) = rest[5..].split_at(id_len);
+    let ecdh_secret = agreement::agree_ephemeral(ua_private, &agreement::UnparsedPublicKey::new(&agreement::ECDH_P256, as_public),
+        (), |s| Ok(s.to_vec())).unwrap();
+    let (cek, nonce) = derive_key_nonce(&ecdh_secret, auth, ua_public, as_public, salt).unwrap();
+    let key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, &cek).unwrap());
+    let mut data = data.to_vec();
+    let plain = key.open_in_place(aead::Nonce::try_assume_unique_for_key(&nonce).unwrap(), aead::Aad::empty(), &mut data).unwrap();
+    assert_eq!(plain.last(), Some(&2u8));
+    plain[..plain.len() - 1].to_vec()
+}
+
+/// New browser key pair and auth secret, for tests
+#[cfg(test)]
+pub(crate) fn browser_keys_for_test() -> (agreement::EphemeralPrivateKey, Vec<u8>, [u8; 16])
+{
+    let rng = ring_rand::SystemRandom::new();
+    let private = agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng).unwrap();
+    let public = private.compute_public_key().unwrap().as_ref().to_vec();
+    (private, public, *b"0123456789abcdef")
+}
+
+#[test]
+fn test_encrypt_payload()
+{
+    let (ua_private, ua_public, auth) = browser_keys_for_test();
+    let (p256dh, auth_b64) = (B64.encode(&ua_public), B64.encode(auth));
+    assert!(check_keys(&p256dh, &auth_b64).is_ok());
+    assert!(check_keys(&p256dh, "c2hvcnQ").is_err());
+
+    let msg = encrypt(b"Hello, browser!", &(p256dh.clone() + "="), &auth_b64).unwrap();
+    assert_eq!(u32::from_be_bytes(msg[16..20].try_into().unwrap()), 4096);
+    assert_eq!(msg[20], 65);
+    assert_eq!(decrypt_for_test(&msg, ua_private, &ua_public, &auth), b"Hello, browser!");
+    assert!(encrypt(b"x", "bm90IGEga2V5", &auth_b64).is_err());
+}
+
+#[test]
+fn test_vapid_auth()
+{
+    let dir = tempfile::tempdir().unwrap();
+    let wp = WebPush::new(dir.path(), "https://clapshot.example.com");
+    let pubkey = wp.public_key().unwrap();
+    assert!(dir.path().join(KEY_FILE).is_file());
+    assert_eq!(WebPush::new(dir.path(), "").public_key().unwrap(), pubkey, "Key should be reused");
+
+    let url = reqwest::Url::parse("https://push.example.net/send/abc?x=1").unwrap();
+    let auth = wp.vapid_auth(&url, 1000).unwrap();
+    let (token, k) = auth.strip_prefix("vapid t=").unwrap().split_once(", k=").unwrap();
+    assert_eq!(k, pubkey);
+    let parts = token.split('.').collect::<Vec<_>>();
+    let claims: serde_json::Value = serde_json::from_slice(&b64_decode(parts[1]).unwrap()).unwrap();
+    assert_eq!(claims, json!({ "aud": "https://push.example.net", "exp": 1000 + 12 * 3600, "sub": "https://clapshot.example.com" }));
+    signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, b64_decode(k).unwrap())
+        .verify(format!("{}.{}", parts[0], parts[1]).as_bytes(), &b64_decode(parts[2]).unwrap())
+        .expect("Bad VAPID signature");
+}
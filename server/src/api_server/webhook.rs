@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+/// Posts server events (e.g. review verdict changes) as JSON to an external URL,
+/// for chat bots, email gateways, CI pipelines etc.
+///
+/// Body is `{"event": <name>, "time": <unix time>, "data": {...}}`.
+/// Delivery is best effort: failures are logged, not retried.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Webhook {
+    url: reqwest::Url,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> anyhow::Result<Webhook> {
+        let url = reqwest::Url::parse(url.trim())?;
+        if !["http", "https"].contains(&url.scheme()) {
+            anyhow::bail!("Webhook URL must be http or https");
+        }
+        Ok(Webhook { url })
+    }
+
+    /// Post an event in background.
+    pub fn send(&self, event: &str, data: serde_json::Value) {
+        let body = serde_json::json!({ "event": event, "time": super::url_signing::unix_now(), "data": data });
+        let (url, event) = (self.url.clone(), event.to_string());
+        std::thread::spawn(move || {
+            if let Err(e) = post(&url, &body) {
+                tracing::warn!(event, details=%e, "Webhook failed.");
+            }
+        });
+    }
+}
+
+fn post(url: &reqwest::Url, body: &serde_json::Value) -> anyhow::Result<()> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    client.post(url.clone()).json(body).send()?.error_for_status()?;
+    Ok(())
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_webhook_post()
+{
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    assert!(Webhook::new("ftp://example.com/hook").is_err());
+    assert!(Webhook::new("not a url").is_err());
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let hook = Webhook::new(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+    hook.send("review_verdict", serde_json::json!({ "video_hash": "abc" }));
+
+    let (stream, _) = listener.accept().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut content_len = 0;
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    assert!(request_line.starts_with("POST /hook "));
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line.trim().is_empty() { break; }
+        if let Some(v) = line.to_lowercase().strip_prefix("content-length:") {
+            content_len = v.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; content_len];
+    reader.read_exact(&mut body).unwrap();
+    reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["event"], "review_verdict");
+    assert_eq!(body["data"]["video_hash"], "abc");
+    assert!(body["time"].as_u64().is_some());
+}
@@ -8,8 +8,6 @@ type WsMsg = warp::ws::Message;
 
 type Res<T> = anyhow::Result<T>;
 type MsgSender = tokio::sync::mpsc::UnboundedSender<WsMsg>;
-type SenderList = Vec<MsgSender>;
-type SenderListMap = Arc<RwLock<HashMap<String, SenderList>>>;
 
 use serde_json::json;
 use anyhow::{anyhow, bail, Context};
@@ -24,6 +22,9 @@ use crate::api_server::server_state::ServerState;
 use crate::database::error::DBError;
 use crate::database::{models, DB};
 use crate::database::schema::comments::drawing;
+use crate::video_pipeline::queues::BUSY_MSG;
+use super::organizer::organizer_action;
+use crossbeam_channel::TrySendError;
 
 
 // ---------------------------------------------------------------------
@@ -73,363 +74,3552 @@ macro_rules! send_user_ok(
     ($ses:expr, $topic:expr, $msg:expr) => { send_user_ok!($ses, $topic, $msg, String::new(), false); };
 );
 
+/// Record an action in the audit log, attributed to current user
+fn audit(ses: &WsSessionArgs<'_>, action: &str, video_hash: Option<&str>, details: String) -> Res<()> {
+    ses.server.db.add_audit_event(&models::AuditEventInsert {
+        user_id: ses.user_id.into(),
+        action: action.into(),
+        ref_video_hash: video_hash.map(|s| s.into()),
+        details,
+    })?;
+    Ok(())
+}
+
 // ---------------------------------------------------------------------
 // Command handlers
 // ---------------------------------------------------------------------
 
-/// Send user a list of all videos they have.
+/// Send user their own and linked videos.
+/// Optional `tags` (list) only lists videos that have all of them, and `group_by: "tag"`
+/// adds `groups`: video hashes per tag (and untagged ones with tag null).
+/// Optional `custom_fields` (field name -> value) only lists videos whose fields match
+/// all of them (see `custom_field_matches`).
+/// Optional `sort` ("added_time" (default), "title", "duration" or "comment_count") and
+/// `order` ("asc" (default) or "desc") order the list, and `offset` and `limit` page it.
+/// Paged replies have `page` (see `page_json`). Groups only cover the page.
+/// With `subscribe: true`, changes to the listing are pushed to the client as deltas until the
+/// next `list_my_videos` (see `video_list_sub`).
 pub async fn msg_list_my_videos(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
-    let videos = ses.server.db.get_all_user_videos(&ses.user_id)?;
-    let videos = videos.into_iter().map(|v| {
+    // Before listing, so changes made meanwhile aren't missed
+    let latest_change = match data["subscribe"].as_bool() {
+        Some(true) => Some(ses.server.db.get_latest_video_change()?),
+        _ => None,
+    };
+    let msg = match user_videos_json(data, ses).await? {
+        Ok(msg) => msg,
+        Err(msg) => { send_user_error!(ses, Topic::None, msg); return Ok(()); }
+    };
+    ses.video_list_sub = latest_change.map(|seq| super::video_list_sub::VideoListSub::new(data.clone(), &msg["videos"], seq));
+    ses.emit_cmd("user_videos", &msg, super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// User's video list as sent in `user_videos`, for `list_my_videos` query `data`.
+///
+/// # Returns
+/// * `Ok(Err(msg))` - Invalid query, with message for user
+pub(super) async fn user_videos_json(data: &serde_json::Value, ses: &WsSessionArgs<'_>) -> Res<Result<serde_json::Value, String>> {
+    use crate::database::VideoSort;
+    let (offset, limit) = match parse_page(data) {
+        Ok(p) => p,
+        Err(msg) => { return Ok(Err(msg)); }
+    };
+    let sort = match data["sort"].as_str().map(|s| (s, VideoSort::parse(s))) {
+        None => VideoSort::Added,
+        Some((_, Some(sort))) => sort,
+        Some((s, None)) => { return Ok(Err(format!("Invalid sort key: '{s}'"))); }
+    };
+    let desc = match data["order"].as_str() {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(o) => { return Ok(Err(format!("Invalid order: '{o}'"))); }
+    };
+    let wanted = data["tags"].as_array().into_iter().flatten()
+        .filter_map(|t| t.as_str().and_then(normalize_tag)).collect::<Vec<_>>();
+
+    // Custom fields and organizer are applied here, after the query, so page after them
+    let conds = data["custom_fields"].as_object();
+    let organizer = ses.server.config.organizer();
+    let filtered = conds.is_some() || organizer.is_some();
+    let (db_offset, db_limit) = if filtered { (0, None) } else { (offset, limit) };
+    let (mut page, mut total) = ses.server.db.get_user_videos_page(ses.user_id, &wanted, sort, desc, db_offset, db_limit)?;
+    let mut labels = HashMap::new();
+    if let Some(org) = organizer {
+        match org.list_videos(ses.user_id, &page).await {
+            Ok(res) => {
+                page.retain(|v| !res.hidden_video_hashes.contains(&v.video_hash));
+                labels = res.labels;
+            },
+            Err(e) => { tracing::warn!(details=%e, "Organizer failed to list videos. Listing them as is."); },
+        }
+    }
+    let linked = page.iter().map(|v| v.added_by_userid.as_deref() != Some(ses.user_id)).collect::<Vec<_>>();
+    let unread = unread_counts(ses, &page)?;
+    let mut videos = video_list_json(ses, page)?;
+    for (v, _) in videos.iter_mut().zip(linked).filter(|(_, linked)| *linked) {
+        v["linked"] = json!(true);
+    }
+    for (v, n) in videos.iter_mut().zip(unread) {
+        v["unread_count"] = json!(n);
+        if let Some(label) = v["video_hash"].as_str().and_then(|vh| labels.get(vh)) {
+            v["organizer_label"] = json!(label);
+        }
+    }
+    if let Some(conds) = conds {
+        let defs = ses.server.db.get_custom_fields()?;
+        videos.retain(|v| conds.iter().all(|(name, cond)|
+            defs.iter().find(|f| &f.name == name).is_some_and(|f| custom_field_matches(f, &v["custom_fields"][name], cond))));
+    }
+    if filtered {
+        total = videos.len() as i64;
+        videos = videos.into_iter().skip(offset as usize).take(limit.unwrap_or(i64::MAX) as usize).collect();
+    }
+
+    let mut msg = json!({
+        "username": ses.user_name,
+        "user_id": ses.user_id,
+        "videos": videos });
+    if limit.is_some() {
+        msg["page"] = page_json(offset, limit, videos.len(), total);
+    }
+    if data["group_by"].as_str() == Some("tag") {
+        let mut groups = std::collections::BTreeMap::<String, (String, Vec<&serde_json::Value>)>::new();
+        let mut untagged = vec![];
+        for v in &videos {
+            let tags = v["tags"].as_array().into_iter().flatten().filter_map(|t| t.as_str()).collect::<Vec<_>>();
+            if tags.is_empty() { untagged.push(&v["video_hash"]); }
+            for t in tags {
+                groups.entry(t.to_lowercase()).or_insert_with(|| (t.to_string(), vec![])).1.push(&v["video_hash"]);
+            }
+        }
+        let mut groups = groups.into_values().map(|(tag, vhs)| json!({ "tag": tag, "video_hashes": vhs })).collect::<Vec<_>>();
+        if !untagged.is_empty() {
+            groups.push(json!({ "tag": null, "video_hashes": untagged }));
+        }
+        msg["groups"] = json!(groups);
+    }
+    Ok(Ok(msg))
+}
+
+/// Number of comments user hasn't read (see `DB::get_unread_comment_counts`) for each video,
+/// leaving out internal ones from videos whose internal comments user may not see
+fn unread_counts(ses: &WsSessionArgs<'_>, videos: &[models::Video]) -> Res<Vec<i64>> {
+    let vhs = videos.iter().map(|v| v.video_hash.clone()).collect::<Vec<_>>();
+    let mut counts = ses.server.db.get_unread_comment_counts(ses.user_id, &vhs)?;
+    videos.iter().map(|v| Ok(match counts.remove(&v.video_hash) {
+            None => 0,
+            Some((n, n_internal)) if n_internal > 0 && !sees_internal_comments(ses, v)? => n - n_internal,
+            Some((n, _)) => n,
+        })).collect()
+}
+
+/// Max number of videos or comments per page in paged listings
+const MAX_PAGE_SIZE: i64 = 500;
+
+/// Paging of a listing from optional `offset` (default 0) and `limit` (default all) fields.
+///
+/// # Returns
+/// * `Ok((offset, limit))`
+/// * `Err(reason)` - Invalid values
+fn parse_page(data: &serde_json::Value) -> Result<(i64, Option<i64>), String> {
+    let offset = match &data["offset"] {
+        serde_json::Value::Null => 0,
+        o => o.as_i64().filter(|o| *o >= 0).ok_or(format!("Invalid offset: {o}"))?,
+    };
+    Ok((offset, parse_page_limit(&data["limit"])?))
+}
+
+/// Page size from an optional field, see `parse_page`
+fn parse_page_limit(limit: &serde_json::Value) -> Result<Option<i64>, String> {
+    match limit {
+        serde_json::Value::Null => Ok(None),
+        l => Ok(Some(l.as_i64().filter(|l| (1..=MAX_PAGE_SIZE).contains(l))
+            .ok_or(format!("Invalid limit: {l} (must be 1 - {MAX_PAGE_SIZE})"))?)),
+    }
+}
+
+/// Paging info for a listing reply. `next_offset` is null on the last page.
+fn page_json(offset: i64, limit: Option<i64>, n_items: usize, total: i64) -> serde_json::Value {
+    let next = offset + n_items as i64;
+    json!({
+        "offset": offset,
+        "limit": limit,
+        "total": total,
+        "next_offset": if n_items > 0 && next < total { Some(next) } else { None } })
+}
+
+/// Videos as JSON for a video listing, with thumbnail URLs, tags and custom fields
+fn video_list_json(ses: &WsSessionArgs<'_>, videos: Vec<models::Video>) -> Res<Vec<serde_json::Value>> {
+    let vhs = videos.iter().map(|v| v.video_hash.clone()).collect::<Vec<_>>();
+    let mut tags = HashMap::<String, Vec<String>>::new();
+    for t in ses.server.db.get_video_tags(&vhs)? {
+        tags.entry(t.video_hash).or_default().push(t.tag);
+    }
+    let mut custom_fields = video_custom_fields(ses, &vhs)?;
+    videos.into_iter().map(|v| {
             let mut fields = v.to_json()?;
+            fields["tags"] = json!(tags.remove(&v.video_hash).unwrap_or_default());
+            fields["custom_fields"] = json!(custom_fields.remove(&v.video_hash).unwrap_or_default());
             if let Some(sheet_dims) = v.thumb_sheet_dims {
                 let (sheet_w, sheet_h) = sheet_dims.split_once('x').ok_or(anyhow!("Invalid sheet dims"))?;
                 fields["thumb_sheet_cols"] = json!(sheet_w.parse::<u32>()?);
                 fields["thumb_sheet_rows"] = json!(sheet_h.parse::<u32>()?);
-                fields["thumb_url"] = json!(format!("{}/videos/{}/thumbs/thumb.webp", ses.server.url_base, &v.video_hash));
-                fields["thumb_sheet_url"] = json!(format!("{}/videos/{}/thumbs/sheet-{}.webp", ses.server.url_base, &v.video_hash, sheet_dims));
+                fields["thumb_url"] = json!(ses.server.asset_url(&v.video_hash, "thumbs/thumb.webp"));
+                fields["thumb_sheet_url"] = json!(ses.server.asset_url(&v.video_hash, &format!("thumbs/sheet-{}.webp", sheet_dims)));
             };
             Ok(fields)
-        }).collect::<Res<Vec<serde_json::Value>>>()?;
+        }).collect()
+}
+
+/// Remove another user's video from user's list (see `upload_dedup`)
+pub async fn msg_unlink_video(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let vh = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    match ses.server.db.del_video_link(ses.user_id, vh) {
+        Ok(()) => { send_user_ok!(ses, Topic::Video(vh), "Video removed from your list."); },
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(vh), "Video is not linked to your list."); },
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Send user their uploads that wait for a choice between linking an identical shared video and storing a copy.
+pub async fn msg_list_pending_uploads(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let uploads = ses.server.db.get_pending_uploads(Some(ses.user_id))?.iter()
+        .map(|pu| super::upload_dedup::pending_json(&ses.server, pu)).collect::<Res<Vec<_>>>()?;
+    ses.emit_cmd("pending_uploads", &json!({ "uploads": uploads }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Resolve a pending upload: `action` is "link" (list the existing video, drop upload),
+/// "keep" (store a copy) or "discard".
+pub async fn msg_resolve_pending_upload(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::upload_dedup;
+    let id = data["id"].as_i64().ok_or(anyhow!("id missing"))? as i32;
+    let action = match data["action"].as_str().unwrap_or_default().parse::<upload_dedup::Resolution>() {
+        Ok(a) => a,
+        Err(msg) => { send_user_error!(ses, Topic::None, msg); return Ok(()); }
+    };
+    let pu = match ses.server.db.get_pending_upload(id) {
+        Ok(pu) if pu.user_id == ses.user_id => pu,
+        Ok(_) | Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "No such pending upload."); return Ok(()); },
+        Err(e) => { bail!(e); }
+    };
+    let vh = pu.duplicate_of.clone();
+    match upload_dedup::resolve(&ses.server, &pu, action) {
+        Ok(msg) if action == upload_dedup::Resolution::Link => { send_user_ok!(ses, Topic::Video(&vh), msg); },
+        Ok(msg) => { send_user_ok!(ses, Topic::None, msg); },
+        Err(e) => { send_user_error!(ses, Topic::None, "Failed to handle upload.", e.to_string(), false); },
+    }
+    Ok(())
+}
+
+/// Start a resumable upload: `filename`, `size` (bytes) and optional `loudnorm`.
+/// Replies `upload_session` with the session id, for PUTting data (see `upload_sessions`).
+pub async fn msg_create_upload_session(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let filename = data["filename"].as_str().ok_or(anyhow!("filename missing"))?;
+    let size = data["size"].as_u64().ok_or(anyhow!("size missing"))?;
+    let loudnorm = match data["loudnorm"].as_str().map(|l| l.parse::<crate::video_pipeline::LoudnormOpt>()) {
+        None => Default::default(),
+        Some(Ok(l)) => l,
+        Some(Err(msg)) => { send_user_error!(ses, Topic::None, msg); return Ok(()); }
+    };
+    match super::upload_sessions::create(&ses.server, ses.user_id, filename, size, loudnorm)? {
+        Ok(us) => {
+            ses.emit_cmd("upload_session", &us.to_json()?, super::SendTo::CurSession())?;
+            super::upload_sessions::push_sessions(&ses.server, ses.user_id)?;
+        },
+        Err(msg) => { send_user_error!(ses, Topic::None, msg); },
+    }
+    Ok(())
+}
+
+/// Send user their unfinished upload sessions.
+pub async fn msg_list_upload_sessions(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let sessions = ses.server.db.get_upload_sessions(Some(ses.user_id))?.iter()
+        .map(|s| s.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("upload_sessions", &json!({ "sessions": sessions }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Get user's upload session by `id` from command data, or send an error to user
+fn get_own_upload_session(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<Option<models::UploadSession>> {
+    let id = data["id"].as_str().ok_or(anyhow!("id missing"))?;
+    match ses.server.db.get_upload_session(id) {
+        Ok(us) if us.user_id == ses.user_id => Ok(Some(us)),
+        Ok(_) | Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "No such upload session."); Ok(None) },
+        Err(e) => bail!(e),
+    }
+}
+
+/// Pause an upload session. Data is refused until resumed.
+pub async fn msg_pause_upload_session(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let Some(us) = get_own_upload_session(data, ses)? else { return Ok(()); };
+    super::upload_sessions::set_paused(&ses.server, &us, true)?;
+    super::upload_sessions::push_sessions(&ses.server, ses.user_id)?;
+    Ok(())
+}
 
-    ses.emit_cmd("user_videos", &json!({
-            "username": ses.user_name.clone(),
-            "user_id": ses.user_id.clone(),
-            "videos": videos }),
+/// Resume an upload session. Replies `upload_session`, with the offset to continue from in `received`.
+pub async fn msg_resume_upload_session(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let Some(us) = get_own_upload_session(data, ses)? else { return Ok(()); };
+    let us = super::upload_sessions::set_paused(&ses.server, &us, false)?;
+    ses.emit_cmd("upload_session", &us.to_json()?, super::SendTo::CurSession())?;
+    super::upload_sessions::push_sessions(&ses.server, ses.user_id)?;
+    Ok(())
+}
+
+/// Cancel an upload session, removing the data received so far.
+pub async fn msg_cancel_upload_session(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let Some(us) = get_own_upload_session(data, ses)? else { return Ok(()); };
+    match super::upload_sessions::cancel(&ses.server, &us) {
+        Ok(_) => { send_user_ok!(ses, Topic::None, format!("Upload of '{}' cancelled.", us.filename)); },
+        Err(e) => { send_user_error!(ses, Topic::None, "Failed to cancel upload.", e.to_string(), false); },
+    }
+    super::upload_sessions::push_sessions(&ses.server, ses.user_id)?;
+    Ok(())
+}
+
+/// Send user their current storage/processing usage and quotas.
+pub async fn msg_get_my_usage(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let usage = crate::quota::get_user_usage(&ses.server.db, &ses.server.videos_dir, ses.user_id)?;
+    ses.emit_cmd("user_usage", &json!({
+            "usage": usage,
+            "quotas": ses.server.config.quotas() }),
         super::SendTo::CurSession())?;
     Ok(())
 }
 
+/// Send user their push notification settings (`push_settings`): server's VAPID public key
+/// for subscribing, number of subscribed browsers, and which events are pushed.
+pub async fn msg_get_push_settings(_data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let mut msg = ses.server.db.get_push_prefs(ses.user_id)?.to_json()?;
+    msg["vapid_public_key"] = json!(ses.server.web_push.public_key()?);
+    msg["subscriptions"] = json!(ses.server.db.get_push_subscriptions(ses.user_id)?.len());
+    ses.emit_cmd("push_settings", &msg, super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Choose events to push: optional `mentions`, `comments` (on user's own videos) and `processing` booleans.
+pub async fn msg_set_push_settings(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let mut prefs = ses.server.db.get_push_prefs(ses.user_id)?;
+    for (kind, field) in super::web_push::push_kind::ALL.iter().zip([&mut prefs.mentions, &mut prefs.comments, &mut prefs.processing]) {
+        match &data[*kind] {
+            serde_json::Value::Null => {},
+            serde_json::Value::Bool(b) => *field = *b,
+            v => { send_user_error!(ses, Topic::None, format!("Invalid value for {kind}: {v}")); return Ok(()); }
+        }
+    }
+    ses.server.db.set_push_prefs(&prefs)?;
+    msg_get_push_settings(data, ses).await
+}
+
+/// Get a user's profile (`user_id`, default own). Replies with `user_profile`.
+pub async fn msg_get_user_profile(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let uid = data["user_id"].as_str().unwrap_or(ses.user_id);
+    let name = if uid == ses.user_id { ses.user_name } else { uid };
+    ses.emit_cmd("user_profile", &super::profiles::profile_json(&ses.server, uid, name), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Update own profile: optional `display_name`, `timezone` and `avatar` (image as data URI).
+/// Empty strings clear them. User's sessions get the new profile as `user_profile`.
+pub async fn msg_set_user_profile(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::profiles;
+    let mut p = ses.server.db.get_user_profile(ses.user_id)?;
+    let old_avatar = p.avatar.clone();
+    if let Some(name) = data["display_name"].as_str().map(str::trim) {
+        if name.chars().count() > profiles::MAX_NAME_LEN || name.chars().any(char::is_control) {
+            send_user_error!(ses, Topic::None, format!("Display name must be at most {} characters, on one line.", profiles::MAX_NAME_LEN));
+            return Ok(());
+        }
+        p.display_name = Some(name.to_string()).filter(|n| !n.is_empty());
+    }
+    if let Some(tz) = data["timezone"].as_str().map(str::trim) {
+        if !tz.is_empty() && !profiles::is_valid_timezone(tz) {
+            send_user_error!(ses, Topic::None, format!("Invalid time zone: '{}'", tz));
+            return Ok(());
+        }
+        p.timezone = Some(tz.to_string()).filter(|tz| !tz.is_empty());
+    }
+    match data["avatar"].as_str() {
+        None => {},
+        Some("") => { p.avatar = None; },
+        Some(uri) => match profiles::store_avatar(&ses.server, uri).await? {
+            Ok(file) => { p.avatar = Some(file); },
+            Err(msg) => { send_user_error!(ses, Topic::None, msg); return Ok(()); },
+        },
+    }
+    ses.server.db.set_user_profile(&p)?;
+    if let Some(file) = old_avatar.filter(|f| p.avatar.as_ref() != Some(f)) {
+        if let Err(e) = profiles::remove_unused_avatar(&ses.server, &file) {
+            tracing::warn!(details=%e, "Failed to delete old avatar.");
+        }
+    }
+    ses.emit_cmd("user_profile", &profiles::profile_json(&ses.server, ses.user_id, ses.user_name), super::SendTo::UserId(ses.user_id))?;
+    Ok(())
+}
+
+/// Get user's client settings (see `user_prefs`). Replies with `prefs`.
+pub async fn msg_get_prefs(_data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let prefs = super::user_prefs::to_json(&ses.server.db.get_user_prefs(ses.user_id)?);
+    ses.emit_cmd("prefs", &json!({ "prefs": prefs }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Change user's client settings: `prefs` object of keys and values (null removes). All or none
+/// are saved. User's sessions get the result as `prefs`.
+pub async fn msg_set_prefs(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let changes = data["prefs"].as_object().ok_or(anyhow!("prefs missing"))?;
+    let current = ses.server.db.get_user_prefs(ses.user_id)?;
+    match super::user_prefs::validate(&current, changes) {
+        Ok(changes) => ses.server.db.set_user_prefs(ses.user_id, &changes)?,
+        Err(msg) => { send_user_error!(ses, Topic::None, msg); return Ok(()); },
+    }
+    let prefs = super::user_prefs::to_json(&ses.server.db.get_user_prefs(ses.user_id)?);
+    ses.emit_cmd("prefs", &json!({ "prefs": prefs }), super::SendTo::UserId(ses.user_id))?;
+    Ok(())
+}
+
+/// Register browser's push subscription, as given by the Push API (`endpoint`, and `keys` with `p256dh` and `auth`)
+pub async fn msg_push_subscribe(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let endpoint = data["endpoint"].as_str().ok_or(anyhow!("endpoint missing"))?;
+    let (p256dh, auth) = (data["keys"]["p256dh"].as_str().unwrap_or_default(), data["keys"]["auth"].as_str().unwrap_or_default());
+    if !reqwest::Url::parse(endpoint).is_ok_and(|u| ["http", "https"].contains(&u.scheme())) {
+        send_user_error!(ses, Topic::None, "Invalid push endpoint URL.");
+        return Ok(());
+    }
+    if let Err(e) = super::web_push::check_keys(p256dh, auth) {
+        send_user_error!(ses, Topic::None, "Invalid push subscription.", e.to_string(), false);
+        return Ok(());
+    }
+    ses.server.db.add_push_subscription(&models::PushSubscriptionInsert {
+        user_id: ses.user_id.into(), endpoint: endpoint.into(), p256dh: p256dh.into(), auth: auth.into() })?;
+    msg_get_push_settings(data, ses).await
+}
+
+/// Remove browser's push subscription (by `endpoint`)
+pub async fn msg_push_unsubscribe(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let endpoint = data["endpoint"].as_str().ok_or(anyhow!("endpoint missing"))?;
+    match ses.server.db.del_push_subscription(endpoint, Some(ses.user_id)) {
+        Ok(()) | Err(DBError::NotFound()) => msg_get_push_settings(data, ses).await,
+        Err(e) => bail!(e),
+    }
+}
+
+/// Download a video from an HTTP(S) URL into upload dir and submit it for processing,
+/// like an upload. Download runs in background and reports progress as user messages.
+pub async fn msg_ingest_url(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::url_ingest;
+    let url = match url_ingest::parse_ingest_url(data["url"].as_str().ok_or(anyhow!("url missing"))?) {
+        Ok(url) => url,
+        Err(e) => {
+            send_user_error!(ses, Topic::None, format!("Invalid URL: {e}"));
+            return Ok(());
+        }
+    };
+    let loudnorm = match data["loudness_target"].as_str().map(|s| s.parse::<crate::video_pipeline::LoudnormOpt>()) {
+        None => Default::default(),
+        Some(Ok(l)) => l,
+        Some(Err(msg)) => {
+            send_user_error!(ses, Topic::None, msg);
+            return Ok(());
+        }
+    };
+    let mut max_bytes = None;
+    let quotas = ses.server.config.quotas();
+    if !quotas.is_unlimited() {
+        let usage = crate::quota::get_user_usage(&ses.server.db, &ses.server.videos_dir, ses.user_id)?;
+        if let Err(msg) = quotas.check_new_job(&usage).and(quotas.check_new_file(&usage, 0)) {
+            send_user_error!(ses, Topic::None, msg);
+            return Ok(());
+        }
+        max_bytes = quotas.max_new_file_bytes(&usage);
+    }
+    tracing::info!(url=%url, "Starting URL ingest.");
+    match url_ingest::spawn_url_ingest(ses.server.clone(), ses.user_id.to_string(), url, max_bytes, loudnorm) {
+        Ok(()) => { send_user_ok!(ses, Topic::None, "Download started."); },
+        Err(msg) => { send_user_error!(ses, Topic::None, msg); },
+    }
+    Ok(())
+}
+
+/// Concatenate videos into a new one, in given order. Runs in the background;
+/// result is processed like an upload, and remembers which videos it was made from.
+pub async fn msg_stitch_videos(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use crate::video_pipeline::stitcher::{StitchSource, MAX_SOURCES};
+    let hashes = data["video_hashes"].as_array().ok_or(anyhow!("video_hashes missing"))?
+        .iter().map(|h| h.as_str().map(String::from).ok_or(anyhow!("video_hashes must be strings")))
+        .collect::<Res<Vec<String>>>()?;
+    if hashes.len() < 2 || hashes.len() > MAX_SOURCES {
+        send_user_error!(ses, Topic::None, format!("Stitching needs 2 to {MAX_SOURCES} videos."));
+        return Ok(());
+    }
+    let mut sources = vec![];
+    for vh in &hashes {
+        let v = match ses.server.db.get_video(vh) {
+            Ok(v) => v,
+            Err(DBError::NotFound()) => {
+                send_user_error!(ses, Topic::Video(vh), "No such video.");
+                return Ok(());
+            },
+            Err(e) => bail!(e),
+        };
+        if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin {
+            send_user_error!(ses, Topic::Video(vh), "Can only stitch your own videos.");
+            return Ok(());
+        }
+        match StitchSource::from_video(&v, &ses.server.videos_dir) {
+            Ok(s) => sources.push(s),
+            Err(msg) => {
+                send_user_error!(ses, Topic::Video(vh), msg);
+                return Ok(());
+            }
+        }
+    }
+    let quotas = ses.server.config.quotas();
+    if !quotas.is_unlimited() {
+        let usage = crate::quota::get_user_usage(&ses.server.db, &ses.server.videos_dir, ses.user_id)?;
+        if let Err(msg) = quotas.check_new_job(&usage) {
+            send_user_error!(ses, Topic::None, msg);
+            return Ok(());
+        }
+    }
+    let title = data["title"].as_str().unwrap_or("").to_string();
+    tracing::info!(n_sources=sources.len(), "Starting video stitch.");
+    super::stitch::spawn_stitch(ses.server.clone(), ses.user_id.to_string(), sources, title);
+    send_user_ok!(ses, Topic::None, format!("Stitching {} videos...", hashes.len()));
+    Ok(())
+}
+
+/// Make a copy of a video with corrected frame rate (e.g. interpret 23.976 as 24, or retime 25 to 24).
+/// Runs in the background; result is a new video linked to the original, which is kept as is.
+pub async fn msg_conform_video(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use crate::video_pipeline::conform::{parse_fps, validate, ConformMode};
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let v = match ses.server.db.get_video(video_hash) {
+        Ok(v) => v,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(video_hash), "No such video.");
+            return Ok(());
+        },
+        Err(e) => bail!(e),
+    };
+    if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin {
+        send_user_error!(ses, Topic::Video(video_hash), "Can only conform your own videos.");
+        return Ok(());
+    }
+    fn parse_params(data: &serde_json::Value, v: &models::Video) -> Result<(f64, f64, ConformMode), String> {
+        // Source rate can be overridden, for files whose metadata is wrong
+        let src_fps = parse_fps(data["source_fps"].as_str().or(v.fps.as_deref()).ok_or("Video frame rate unknown")?)?;
+        let dst_fps = parse_fps(data["fps"].as_str().ok_or("fps missing")?)?;
+        let mode = data["mode"].as_str().unwrap_or("").parse::<ConformMode>()?;
+        validate(src_fps, dst_fps, mode)?;
+        Ok((src_fps, dst_fps, mode))
+    }
+    let (src_fps, dst_fps, mode) = match parse_params(data, &v) {
+        Ok(p) => p,
+        Err(msg) => {
+            send_user_error!(ses, Topic::Video(video_hash), msg);
+            return Ok(());
+        }
+    };
+    let quotas = ses.server.config.quotas();
+    if !quotas.is_unlimited() {
+        let usage = crate::quota::get_user_usage(&ses.server.db, &ses.server.videos_dir, ses.user_id)?;
+        if let Err(msg) = quotas.check_new_job(&usage) {
+            send_user_error!(ses, Topic::None, msg);
+            return Ok(());
+        }
+    }
+    tracing::info!(video=video_hash, src_fps, dst_fps, mode=%mode, "Starting frame rate conform.");
+    super::conform::spawn_conform(ses.server.clone(), ses.user_id.to_string(), v, src_fps, dst_fps, mode);
+    send_user_ok!(ses, Topic::Video(video_hash), format!("Conforming to {dst_fps} fps..."));
+    Ok(())
+}
+
+/// Start a multi-file upload batch. Client then uploads each file with
+/// `X-Batch-File-Id` header set to the ID of the corresponding batch file.
+/// Progress and completion of the whole batch are reported as user messages.
+pub async fn msg_create_upload_batch(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let title = data["title"].as_str().unwrap_or("");
+    let files = data["files"].as_array().ok_or(anyhow!("files missing"))?
+        .iter().map(|f| f.as_str().map(String::from).ok_or(anyhow!("files must be strings")))
+        .collect::<Res<Vec<String>>>()?;
+    if files.is_empty() {
+        send_user_error!(ses, Topic::None, "Upload batch has no files.");
+        return Ok(());
+    }
+    let (batch, _) = ses.server.db.add_upload_batch(ses.user_id, title, &files)?;
+    let summary = crate::upload_batch::get_batch_summary(&ses.server.db, batch.id)?;
+    ses.emit_cmd("upload_batch", &json!(summary), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Get batch with ID, if current user is allowed to see it
+fn get_own_upload_batch(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<Option<crate::upload_batch::BatchSummary>> {
+    let batch_id = data["batch_id"].as_i64().ok_or(anyhow!("batch_id missing"))? as i32;
+    match crate::upload_batch::get_batch_summary(&ses.server.db, batch_id) {
+        Ok(s) if s.batch.user_id == ses.user_id || ses.is_admin => Ok(Some(s)),
+        Ok(_) | Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such upload batch.");
+            Ok(None)
+        },
+        Err(e) => bail!(e),
+    }
+}
+
+/// Send user current state of an upload batch, with per-file status.
+pub async fn msg_get_upload_batch(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    if let Some(summary) = get_own_upload_batch(data, ses)? {
+        ses.emit_cmd("upload_batch", &json!(summary), super::SendTo::CurSession())?;
+    }
+    Ok(())
+}
+
+/// Cancel an upload batch. Files that haven't been uploaded yet are skipped,
+/// files already being processed are finished normally.
+pub async fn msg_cancel_upload_batch(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let batch_id = match get_own_upload_batch(data, ses)? {
+        Some(s) => s.batch.id,
+        None => return Ok(()),
+    };
+    ses.server.db.cancel_upload_batch(batch_id)?;
+    let just_finished = ses.server.db.try_finish_upload_batch(batch_id)?;
+    let summary = crate::upload_batch::get_batch_summary(&ses.server.db, batch_id)?;
+    if just_finished {
+        let (msg, details) = summary.completion_msg();
+        send_user_ok!(ses, Topic::None, msg, details, true);
+    } else {
+        send_user_ok!(ses, Topic::None, "Upload batch cancelled.");
+    }
+    ses.emit_cmd("upload_batch", &json!(summary), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// May user see internal comments on a video: owner, admins and members of teams it's shared with.
+/// Never share link guests.
+fn sees_internal_comments(ses: &WsSessionArgs<'_>, v: &models::Video) -> Res<bool> {
+    Ok(match ses.guest {
+        Some(_) => false,
+        None => ses.is_admin || v.added_by_userid.as_deref() == Some(ses.user_id) || ses.server.db.is_video_shared_with_user(v, ses.user_id)?,
+    })
+}
+
+/// Visibility for a new comment, from `visibility` field (default external).
+/// Replies to internal comments are always internal.
+///
+/// # Returns
+/// * `Ok(visibility)`
+/// * `Err(reason)` - Invalid, or user may not see internal comments
+fn new_comment_visibility(ses: &WsSessionArgs<'_>, v: &models::Video, data: &serde_json::Value, parent_id: Option<i32>) -> Res<Result<String, String>> {
+    use models::comment_visibility as cv;
+    let parent_internal = match parent_id {
+        Some(pid) => ses.server.db.get_comment(pid).map(|p| p.is_internal()).unwrap_or(false),
+        None => false,
+    };
+    let vis = match (data["visibility"].as_str(), parent_internal) {
+        (_, true) => cv::INTERNAL,
+        (Some(vis), _) if !cv::is_valid(vis) => return Ok(Err(format!("Invalid visibility: '{vis}'"))),
+        (Some(vis), _) => vis,
+        (None, _) => cv::EXTERNAL,
+    };
+    if vis == cv::INTERNAL && !sees_internal_comments(ses, v)? {
+        return Ok(Err("Only the owner's side can write internal comments".into()));
+    }
+    Ok(Ok(vis.into()))
+}
+
+/// Ask organizer (if any) whether user may do something to a video, and tell them if not.
+/// If the organizer fails, it's denied, unless organizer is configured to fail open.
+///
+/// # Arguments
+/// * `action` - See `organizer_action`
+async fn organizer_allows(ses: &mut WsSessionArgs<'_>, action: &str, video_hash: &str) -> Res<bool> {
+    let Some(org) = ses.server.config.organizer() else { return Ok(true); };
+    match org.check_permission(ses.user_id, action, video_hash).await {
+        Ok(None) => Ok(true),
+        Ok(Some(reason)) => {
+            send_user_error!(ses, Topic::Video(video_hash), format!("Not allowed to {action} this video."), reason, false);
+            Ok(false)
+        },
+        Err(e) if org.fail_open => {
+            tracing::warn!(details=%e, action, video=video_hash, "Organizer permission check failed. Allowing.");
+            Ok(true)
+        },
+        Err(e) => {
+            tracing::warn!(details=%e, action, video=video_hash, "Organizer permission check failed. Denying.");
+            send_user_error!(ses, Topic::Video(video_hash), format!("Not allowed to {action} this video."), "Access check failed. Try again later.", false);
+            Ok(false)
+        },
+    }
+}
+
 /// User opens a video.
-/// Send them the video info and all comments related to it.
+/// Send them the video info and all comments related to it, or with `comments_limit`,
+/// only the first ones (`comment_count` tells how many there are, see `list_comments`).
 /// Register the session as a viewer of the video (video_session_guard).
 pub async fn msg_open_video(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
     let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let comments_limit = match parse_page_limit(&data["comments_limit"]) {
+        Ok(limit) => limit,
+        Err(msg) => { send_user_error!(ses, Topic::Video(video_hash), msg); return Ok(()); }
+    };
     match ses.server.db.get_video(video_hash) {
         Err(DBError::NotFound()) => {
             send_user_error!(ses, Topic::Video(video_hash), "No such video.");
         }
         Err(e) => { bail!(e); }
+        Ok(v) if v.trashed.is_some() => {
+            send_user_error!(ses, Topic::Video(video_hash), "Video is in trash. Restore it to open.");
+        }
         Ok(v) => {
-            ses.video_session_guard = Some(ses.server.link_session_to_video(video_hash, ses.sender.clone()));
+            if !organizer_allows(ses, organizer_action::VIEW, video_hash).await? {
+                return Ok(());
+            }
+            let sees_internal = sees_internal_comments(ses, &v)?;
+            ses.video_session_guard = Some(ses.server.link_session_to_video(video_hash, ses.sender.clone(), sees_internal));
             let mut fields = v.to_json()?;
+            fields["sees_internal"] = json!(sees_internal);
+            fields["review_closed"] = json!(ses.server.db.get_closed_review(video_hash)?);
+            fields["review"] = review_verdicts_json(ses, video_hash)?;
+            fields["tags"] = json!(ses.server.db.get_video_tags(&[video_hash.to_string()])?.into_iter().map(|t| t.tag).collect::<Vec<_>>());
+            fields["custom_fields"] = json!(video_custom_fields(ses, &[video_hash.to_string()])?.remove(video_hash).unwrap_or_default());
+
+            // Use transcoded or orig video?
+            let file = match v.recompression_done {
+                Some(_) => Ok("video.mp4".to_string()),
+                None => match &v.orig_filename {
+                    Some(f) => Ok(format!("orig/{}", f)),
+                    None => Err(anyhow!("No video file"))
+                }}?;
+
+            fields["video_url"] = json!(ses.asset_url(&v.video_hash, &file));
+            fields["overlays"] = video_overlays(&ses.server, &v)?;
+            if ses.guest.is_none() && super::download::may_download(&ses.server.db, &v, ses.user_id, ses.is_admin) {
+                fields["download_urls"] = super::download::download_urls(&ses.server.url_base, &v);
+            }
+            fields["subtitles"] = json!(ses.server.db.get_video_subtitles(video_hash)?.iter()
+                .map(|s| subtitle_to_json(ses, s)).collect::<Res<Vec<_>>>()?);
+            fields["shots"] = json!(ses.server.db.get_video_shots(video_hash)?.iter().map(|s| json!({
+                "start": s.start_time,
+                "end": s.end_time,
+                "thumb_url": s.thumb.as_ref().map(|t| ses.asset_url(&s.video_hash, t)),
+            })).collect::<Vec<_>>());
+            fields["sources"] = json!(ses.server.db.get_video_sources(video_hash)?);
+            fields["derived"] = json!(ses.server.db.get_derived_videos(video_hash)?);
+            fields["derived_by"] = json!(ses.server.db.get_video_operation(video_hash)?);
+            fields["proxy_encoding"] = match ses.server.db.get_video_proxy_encoding(video_hash)? {
+                Some(enc) => serde_json::from_str(&enc)?,
+                None => serde_json::Value::Null,
+            };
+            if v.still_kind.is_some() {
+                // Stills are reviewed as rendered pages instead of playing video_url
+                fields["pages"] = json!((1..=v.page_count.unwrap_or(1) as u32).map(|p|
+                    ses.asset_url(&v.video_hash, &format!("pages/{}", crate::video_pipeline::stills::page_filename(p))))
+                    .collect::<Vec<_>>());
+            }
+            let (comments, n_comments) = ses.server.db.get_video_comments_page(video_hash, sees_internal, 0, comments_limit)?;
+            fields["comment_count"] = json!(n_comments);
+            fields["seq"] = json!(ses.server.resume.current_seq());
+
+            // Read position, for marking new comments (guests don't have one)
+            let seen_until = match ses.guest {
+                Some(_) => None,
+                None => Some(ses.server.db.get_video_seen(ses.user_id, video_hash)?),
+            };
+            if let Some(seen) = seen_until {
+                fields["seen_until"] = json!(seen.map(|t| t.timestamp()));
+                fields["unread_count"] = json!(unread_counts(ses, std::slice::from_ref(&v))?[0]);
+            }
+            ses.emit_cmd("open_video", &fields, super::SendTo::CurSession() )?;
+
+            for c in comments {
+                let cid = c.id;
+                let unread = seen_until.is_some_and(|seen| c.user_id != ses.user_id && seen.is_none_or(|s| c.created > s));
+                let res = match ses.comment_json(c).await {
+                    Ok(mut fields) => {
+                        if unread { fields["unread"] = json!(true); }
+                        ses.emit_cmd("new_comment", &fields, super::SendTo::CurSession()).map(|_| ())
+                    },
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    tracing::error!("Error sending comment: {}", e);
+                    send_user_error!(ses, Topic::Comment(cid), format!("Error sending comment #{cid}: {:?}", e));
+                }
+            }
+            for n in ses.server.db.get_user_notes(video_hash, ses.user_id)? {
+                ses.emit_cmd("new_note", &n.to_json()?, super::SendTo::CurSession())?;
+            }
+        }
+    }
+    ses.cur_video_hash = Some(video_hash.into());
+    Ok(())
+}
+
+/// Overlay presets for player, each with `enabled` set by video's folder defaults
+/// (or preset's own default, if no folder on the path sets them)
+fn video_overlays(server: &ServerState, v: &models::Video) -> Res<serde_json::Value> {
+    let folder_defaults = server.db.get_folder_overlay_defaults(v.folder_id)?;
+    Ok(json!(server.db.get_overlay_presets()?.into_iter().map(|p| {
+            let mut fields = p.to_json()?;
+            fields["enabled"] = json!(folder_defaults.as_ref().map_or(p.default_on, |d| d.contains(&p.name)));
+            Ok(fields)
+        }).collect::<Res<Vec<_>>>()?))
+}
+
+/// Subtitle info for client, with URL of the WebVTT file
+fn subtitle_to_json(ses: &WsSessionArgs<'_>, s: &models::Subtitle) -> Res<serde_json::Value> {
+    let mut fields = s.to_json()?;
+    fields["url"] = json!(ses.asset_url(&s.video_hash, &format!("subs/{}.vtt", s.id)));
+    Ok(fields)
+}
+
+/// Attach a subtitle file (.srt or .vtt) to a video. Converted to WebVTT for the player.
+pub async fn msg_add_subtitle(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use crate::video_pipeline::subtitles;
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let filename = data["filename"].as_str().ok_or(anyhow!("filename missing"))?;
+    let contents = data["contents"].as_str().ok_or(anyhow!("contents missing"))?;
+    match ses.server.db.get_video(video_hash) {
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(video_hash), "No such video."); },
+        Err(e) => { bail!(e); },
+        Ok(v) if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin => {
+            send_user_error!(ses, Topic::Video(video_hash), "Video not owned by you. Cannot add subtitles.");
+        },
+        Ok(_) => match subtitles::to_webvtt(contents, filename) {
+            Err(e) => { send_user_error!(ses, Topic::Video(video_hash), "Invalid subtitle file.", e.to_string(), false); },
+            Ok(vtt) => {
+                let track = subtitles::SubtitleTrack {
+                    language: data["language"].as_str().map(String::from).or_else(|| subtitles::language_from_filename(filename)),
+                    title: filename.into(),
+                    origin: models::subtitle_origin::UPLOAD,
+                    vtt,
+                };
+                let sub = subtitles::store_subtitle(&ses.server.db, &ses.server.videos_dir, video_hash, &track)?;
+                ses.emit_cmd("new_subtitle", &subtitle_to_json(ses, &sub)?, super::SendTo::VideoHash(video_hash))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove a subtitle track from a video.
+pub async fn msg_del_subtitle(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let id = data["id"].as_i64().ok_or(anyhow!("id missing"))? as i32;
+    let sub = match ses.server.db.get_subtitle(id) {
+        Ok(s) => s,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such subtitle.");
+            return Ok(());
+        },
+        Err(e) => bail!(e),
+    };
+    let v = ses.server.db.get_video(&sub.video_hash)?;
+    if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin {
+        send_user_error!(ses, Topic::Video(&sub.video_hash), "Video not owned by you. Cannot delete subtitles.");
+        return Ok(());
+    }
+    ses.server.db.del_subtitle(id)?;
+    let file = ses.server.videos_dir.join(&sub.video_hash).join("subs").join(format!("{id}.vtt"));
+    if let Err(e) = std::fs::remove_file(&file) {
+        tracing::warn!(details=%e, file=%file.display(), "Failed to remove subtitle file.");
+    }
+    ses.emit_cmd("del_subtitle", &json!({ "id": id, "video_hash": sub.video_hash }), super::SendTo::VideoHash(&sub.video_hash))?;
+    Ok(())
+}
+
+/// Move a video to trash (owner or admin). It's purged automatically after retention period,
+/// or right away by admin with `purge: true` (also from trash). Videos under legal hold can't be deleted.
+pub async fn msg_del_video(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let purge = data["purge"].as_bool().unwrap_or(false);
+    match ses.server.db.get_video(video_hash) {
+        Ok(v) => {
+            let details = format!("Added by {:?} ({:?}) on {}. Filename was {:?}.",
+                v.added_by_username, v.added_by_userid, v.added_time, v.orig_filename);
+            if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin {
+                send_user_error!(ses, Topic::Video(video_hash), "Video not owned by you. Cannot delete.");
+            } else if purge && !ses.is_admin {
+                send_user_error!(ses, Topic::Video(video_hash), "Only admin can purge videos. Trash is emptied automatically.");
+            } else if ses.server.db.is_video_held(video_hash)? {
+                audit(ses, models::audit_action::DELETE_VIDEO_BLOCKED, Some(video_hash), "Video is under legal hold.".into())?;
+                send_user_error!(ses, Topic::Video(video_hash), "Video is under legal hold. Cannot delete.");
+            } else if purge {
+                super::trash::purge_video(&ses.server, video_hash)?;
+                audit(ses, models::audit_action::PURGE_VIDEO, Some(video_hash), format!("Title was {:?}.", v.title))?;
+                send_user_ok!(ses, Topic::Video(video_hash), "Video purged.", details, true);
+            } else if v.trashed.is_some() {
+                send_user_error!(ses, Topic::Video(video_hash), "Video is already in trash.");
+            } else {
+                let warnings = super::trash::move_to_trash(&ses.server, &v)?;
+                audit(ses, models::audit_action::DELETE_VIDEO, Some(video_hash), format!("Title was {:?}.", v.title))?;
+                let details = warnings.iter().fold(details, |d, w| format!("{} WARNING: {}", d, w));
+                send_user_ok!(ses, Topic::Video(video_hash),
+                    if warnings.is_empty() {"Video moved to trash."} else {"Video moved to trash, but cleanup had errors."},
+                    details, true);
+            }
+        }
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(video_hash), "No such video. Cannot delete.");
+        }
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Restore a video from trash (owner or admin).
+pub async fn msg_restore_video(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    match ses.server.db.get_video(video_hash) {
+        Ok(v) if Some(ses.user_id.to_string()) == v.added_by_userid || ses.is_admin => {
+            if v.trashed.is_none() {
+                send_user_error!(ses, Topic::Video(video_hash), "Video is not in trash.");
+            } else {
+                super::trash::restore_from_trash(&ses.server, video_hash)?;
+                audit(ses, models::audit_action::RESTORE_VIDEO, Some(video_hash), format!("Title is {:?}.", v.title))?;
+                send_user_ok!(ses, Topic::Video(video_hash), "Video restored.", format!("Title: {:?}", v.title), true);
+            }
+        },
+        Ok(_) | Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(video_hash), "No such video.");
+        },
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Send user their videos in trash, oldest first. Admin gets everyone's with `all: true`.
+pub async fn msg_list_trash(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let user_id = match data["all"].as_bool() == Some(true) && ses.is_admin {
+        true => None,
+        false => Some(ses.user_id),
+    };
+    let videos = ses.server.db.get_trashed_videos(user_id)?.iter()
+        .map(|v| v.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("trash", &json!({ "videos": videos }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+pub async fn msg_rename_video(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let new_name = data["new_name"].as_str().ok_or(anyhow!("new_name missing"))?;
+
+    match ses.server.db.get_video(video_hash) {
+        Ok(v) => {
+            if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin {
+                send_user_error!(ses, Topic::Video(video_hash), "Video not owned by you. Cannot rename.");
+            } else {
+                let new_name = new_name.trim();
+                if new_name.is_empty() || !new_name.chars().any(|c| c.is_alphanumeric()) {
+                    send_user_error!(ses, Topic::Video(video_hash), "Invalid video name (must have letters/numbers)");
+                    return Ok(());
+                }
+                if new_name.len() > 160 {
+                    send_user_error!(ses, Topic::Video(video_hash), "Video name too long (max 160)");
+                    return Ok(());
+                }
+                ses.server.db.rename_video(video_hash, new_name)?;
+                send_user_ok!(ses, Topic::Video(video_hash), "Video renamed.", 
+                    format!("New name: '{}'", new_name), true);
+            }
+        }
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(video_hash), "No such video. Cannot rename.");
+        }
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Compare technical metadata of two versions of a video.
+/// Send the user a structured diff (see `video_diff::diff_video_metadata`).
+pub async fn msg_diff_videos(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let hash_a = data["video_hash_a"].as_str().ok_or(anyhow!("video_hash_a missing"))?;
+    let hash_b = data["video_hash_b"].as_str().ok_or(anyhow!("video_hash_b missing"))?;
+
+    let mut videos = vec![];
+    for vh in [hash_a, hash_b] {
+        match ses.server.db.get_video(vh) {
+            Ok(v) => videos.push(v),
+            Err(DBError::NotFound()) => {
+                send_user_error!(ses, Topic::Video(vh), "No such video. Cannot compare.");
+                return Ok(());
+            }
+            Err(e) => { bail!(e); }
+        }
+    }
+    let diff = super::video_diff::diff_video_metadata(&videos[0], &videos[1]);
+    ses.emit_cmd("video_diff", &diff, super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Parse spatial anchor of a comment on a still page: `{x, y}` point or `{x, y, w, h}` rectangle,
+/// as fractions (0..1) of page width and height. Returns normalized JSON for storing.
+fn parse_comment_region(v: &serde_json::Value) -> Result<String, String> {
+    let num = |k: &str| v[k].as_f64().filter(|n| (0.0..=1.0).contains(n));
+    let (x, y) = num("x").zip(num("y")).ok_or("Region needs x and y between 0 and 1")?;
+    match (v.get("w"), v.get("h")) {
+        (None, None) => Ok(json!({ "x": x, "y": y }).to_string()),
+        _ => {
+            let (w, h) = num("w").zip(num("h")).ok_or("Region w and h must be between 0 and 1")?;
+            if x + w > 1.0 || y + h > 1.0 { return Err("Region extends outside the page".into()); }
+            Ok(json!({ "x": x, "y": y, "w": w, "h": h }).to_string())
+        }
+    }
+}
+
+/// Where a comment is anchored: (timecode, page, region)
+type CommentAnchor = (Option<String>, Option<i32>, Option<String>);
+
+/// Where a comment (or private note) is anchored: timecode for videos, page and optional region for stills.
+///
+/// # Returns
+/// * `(timecode, page, region)`
+/// * `Err((message, details))` - Invalid anchor, to tell the user. `failed_msg` is used as message for bad values.
+fn parse_comment_anchor(video: &models::Video, data: &serde_json::Value, failed_msg: &str) -> Result<CommentAnchor, (String, String)> {
+    let timecode = data["timecode"].as_str().map(String::from);
+    let has_spatial = !data["page"].is_null() || !data["region"].is_null();
+    match (&video.still_kind, has_spatial) {
+        (None, false) => Ok((timecode, None, None)),
+        (None, true) => Err(("Page and region are only for still images and documents. Use a timecode.".into(), String::new())),
+        (Some(_), _) if timecode.is_some() => Err(("Still images and documents have no timecodes. Use page and region.".into(), String::new())),
+        (Some(_), _) => {
+            let page = data["page"].as_i64().unwrap_or(1);
+            if page < 1 || page > video.page_count.unwrap_or(1) as i64 {
+                return Err((failed_msg.into(), format!("No such page: {page}")));
+            }
+            let region = match data["region"].is_null() {
+                true => None,
+                false => Some(parse_comment_region(&data["region"]).map_err(|e| (failed_msg.to_string(), e))?),
+            };
+            Ok((None, Some(page as i32), region))
+        }
+    }
+}
+
+/// End of the time range (in/out) a comment is about, from optional `timecode_end`.
+/// Both ends must be valid timecodes, in order, and within the video (if its duration is known).
+///
+/// # Returns
+/// * `Ok(Some(timecode_end))` - Range, or `Ok(None)` for a single point (or no timecode)
+/// * `Err(details)` - Invalid range, to tell the user
+fn parse_comment_range(video: &models::Video, timecode: Option<&str>, data: &serde_json::Value) -> Result<Option<String>, String> {
+    use crate::video_pipeline::review_package::parse_comment_timecode;
+    let Some(end_tc) = data["timecode_end"].as_str() else { return Ok(None); };
+    let start_tc = timecode.ok_or("Time range needs a start timecode")?;
+    let fps = video.fps.as_deref().and_then(|f| f.parse::<f32>().ok());
+    let start = parse_comment_timecode(start_tc, fps).ok_or(format!("Invalid timecode: '{start_tc}'"))?;
+    let end = parse_comment_timecode(end_tc, fps).ok_or(format!("Invalid timecode: '{end_tc}'"))?;
+    if end <= start {
+        return Err("Time range must end after it starts".into());
+    }
+    if let Some(dur) = video.duration.filter(|d| *d > 0.0) {
+        if end > dur {
+            return Err(format!("Time range ends after the video ({dur:.2} s)"));
+        }
+    }
+    Ok(Some(end_tc.to_string()))
+}
+
+/// Add a comment. Videos take a `timecode`. Stills (images, PDFs) take a `page` (default 1)
+/// and optional `region` (see `parse_comment_region`) instead.
+pub async fn msg_add_comment(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let vh = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+
+    let video = match ses.server.db.get_video(vh) {
+        Ok(v) => v,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(vh), "No such video. Cannot comment.");
+            return Ok(());
+        }
+        Err(e) => { bail!(e); }
+    };
+    if ses.server.db.get_closed_review(vh)?.is_some() {
+        send_user_error!(ses, Topic::Video(vh), "Review is closed. Cannot comment.", "Ask the owner to reopen it.", false);
+        return Ok(());
+    }
+    if !organizer_allows(ses, organizer_action::COMMENT, vh).await? {
+        return Ok(());
+    }
+
+    let (timecode, page, region) = match parse_comment_anchor(&video, data, "Failed to add comment.") {
+        Ok(anchor) => anchor,
+        Err((msg, details)) => {
+            send_user_error!(ses, Topic::Video(vh), msg, details, false);
+            return Ok(());
+        }
+    };
+    let timecode_end = match parse_comment_range(&video, timecode.as_deref(), data) {
+        Ok(end) => end,
+        Err(e) => {
+            send_user_error!(ses, Topic::Video(vh), "Failed to add comment.", e, false);
+            return Ok(());
+        }
+    };
+    let parent_id = data["parent_id"].as_i64().map(|x| x as i32);
+    let visibility = match new_comment_visibility(ses, &video, data, parent_id)? {
+        Ok(vis) => vis,
+        Err(e) => {
+            send_user_error!(ses, Topic::Video(vh), "Failed to add comment.", e, false);
+            return Ok(());
+        }
+    };
+
+    // Parse drawing data if present and write to file
+    let mut drwn = data["drawing"].as_str().map(|s| s.to_string());
+    if let Some(d) = drwn.clone() {
+        if d.starts_with("data:") {
+
+            // Convert data URI to bytes
+            let img_uri = DataUrl::process(&d).map_err(|e| anyhow!("Invalid drawing data URI"))?;
+            
+            if img_uri.mime_type().type_ != "image" || img_uri.mime_type().subtype != "webp" {
+                bail!("Invalid mimetype in drawing: {:?}", img_uri.mime_type())
+            }
+            let img_data = img_uri.decode_to_vec().map_err(|e| anyhow!("Failed to decode drawing data URI: {:?}", e))?;
+
+            // Make up a filename
+            fn sha256hex( data: &[u8] ) -> String {
+                let mut hasher = Sha256::new();
+                hasher.update(data);
+                let result = hasher.finalize();
+                hex::encode(result)
+            }
+            let short_csum = sha256hex(img_data.0.as_ref())[..16].to_string();
+            let fname = format!("{}.webp", short_csum);
+
+            // Write to file
+            let drawing_path = ses.server.videos_dir.join(vh).join("drawings").join(&fname);
+            std::fs::create_dir_all(drawing_path.parent().unwrap())
+                .map_err(|e| anyhow!("Failed to create drawings dir: {:?}", e))?;
+            async_std::fs::write(drawing_path, img_data.0).await.map_err(
+                |e| anyhow!("Failed to write drawing file: {:?}", e))?;
+            
+            // Replace data URI with filename
+            drwn = Some(fname);
+        }
+    };
+
+    let c = models::CommentInsert {
+        video_hash: vh.to_string(),
+        parent_id,
+        user_id: ses.user_id.into(),
+        username: ses.user_name.into(),
+        comment: data["comment"].as_str().ok_or(anyhow!("comment missing"))?.to_string(),
+        timecode,
+        timecode_end,
+        drawing: drwn,
+        page,
+        region,
+        visibility,
+    };
+    let new_id = ses.server.db.add_comment(&c)
+        .map_err(|e| anyhow!("Failed to add comment: {:?}", e))?;
+    let c = ses.server.db.get_comment(new_id)?;
+    let mentioned = super::mentions::update_mentions(&ses.server, &video, &c)?;
+    super::web_push::notify_new_comment(&ses.server, &video, &c, &mentioned);
+    super::notifier::notify(&ses.server, super::notifier::chat_event::COMMENT, &video, Some(&c));
+    super::tracker_sync::enqueue(&ses.server, &c);
+
+    // Send to all clients watching this video
+    ses.emit_new_comment(c, super::SendTo::VideoHash(vh)).await?;
+    Ok(())
+}
+
+/// Get user's own private note. Tells user if not found (others' notes are "not found", too).
+fn get_my_note(ses: &mut WsSessionArgs<'_>, note_id: i32) -> Res<Option<models::Note>> {
+    match ses.server.db.get_note(note_id) {
+        Ok(n) if n.user_id == ses.user_id => Ok(Some(n)),
+        Ok(_) | Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such note.");
+            Ok(None)
+        }
+        Err(e) => { bail!(e); }
+    }
+}
+
+/// Add a private note on a video, visible only to the user. Anchored like comments.
+pub async fn msg_add_note(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let vh = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let text = data["note"].as_str().ok_or(anyhow!("note missing"))?;
+    let video = match ses.server.db.get_video(vh) {
+        Ok(v) => v,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(vh), "No such video. Cannot add note.");
+            return Ok(());
+        }
+        Err(e) => { bail!(e); }
+    };
+    let (timecode, page, region) = match parse_comment_anchor(&video, data, "Failed to add note.") {
+        Ok(anchor) => anchor,
+        Err((msg, details)) => {
+            send_user_error!(ses, Topic::Video(vh), msg, details, false);
+            return Ok(());
+        }
+    };
+    let n = ses.server.db.add_note(&models::NoteInsert {
+        video_hash: vh.into(), user_id: ses.user_id.into(), note: text.into(), timecode, page, region })?;
+    ses.emit_cmd("new_note", &n.to_json()?, super::SendTo::UserId(ses.user_id))?;
+    Ok(())
+}
+
+/// Change text of user's private note.
+pub async fn msg_edit_note(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let note_id = data["note_id"].as_i64().ok_or(anyhow!("note_id missing"))? as i32;
+    let text = data["note"].as_str().ok_or(anyhow!("note missing"))?;
+    if get_my_note(ses, note_id)?.is_none() { return Ok(()); }
+    ses.server.db.edit_note(note_id, text)?;
+    ses.emit_cmd("del_note", &json!({ "note_id": note_id }), super::SendTo::UserId(ses.user_id))?;
+    ses.emit_cmd("new_note", &ses.server.db.get_note(note_id)?.to_json()?, super::SendTo::UserId(ses.user_id))?;
+    Ok(())
+}
+
+/// Delete user's private note.
+pub async fn msg_del_note(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let note_id = data["note_id"].as_i64().ok_or(anyhow!("note_id missing"))? as i32;
+    if get_my_note(ses, note_id)?.is_none() { return Ok(()); }
+    ses.server.db.del_note(note_id)?;
+    ses.emit_cmd("del_note", &json!({ "note_id": note_id }), super::SendTo::UserId(ses.user_id))?;
+    Ok(())
+}
+
+/// Publish user's private note as a comment (optional `visibility`). The note is removed.
+pub async fn msg_publish_note(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let note_id = data["note_id"].as_i64().ok_or(anyhow!("note_id missing"))? as i32;
+    let Some(n) = get_my_note(ses, note_id)? else { return Ok(()); };
+    let video = ses.server.db.get_video(&n.video_hash)?;
+    if ses.server.db.get_closed_review(&n.video_hash)?.is_some() {
+        send_user_error!(ses, Topic::Video(&n.video_hash), "Review is closed. Cannot publish note.", "Ask the owner to reopen it.", false);
+        return Ok(());
+    }
+    if !organizer_allows(ses, organizer_action::COMMENT, &n.video_hash).await? {
+        return Ok(());
+    }
+    let visibility = match new_comment_visibility(ses, &video, data, None)? {
+        Ok(vis) => vis,
+        Err(e) => {
+            send_user_error!(ses, Topic::Video(&n.video_hash), "Failed to publish note.", e, false);
+            return Ok(());
+        }
+    };
+    let comment_id = ses.server.db.publish_note(note_id, ses.user_name, &visibility)?;
+    ses.emit_cmd("del_note", &json!({ "note_id": note_id }), super::SendTo::UserId(ses.user_id))?;
+    let c = ses.server.db.get_comment(comment_id)?;
+    super::web_push::notify_new_comment(&ses.server, &video, &c, &[]);
+    super::notifier::notify(&ses.server, super::notifier::chat_event::COMMENT, &video, Some(&c));
+    super::tracker_sync::enqueue(&ses.server, &c);
+    ses.emit_new_comment(c, super::SendTo::VideoHash(&n.video_hash)).await?;
+    Ok(())
+}
+
+pub async fn msg_edit_comment(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let comment_id = data["comment_id"].as_i64().ok_or(anyhow!("comment_id missing"))? as i32;
+    let new_text = data["comment"].as_str().ok_or(anyhow!("comment missing"))?.to_string();
+
+    match ses.server.db.get_comment(comment_id) {
+        Ok(old) => {
+            let vh = old.video_hash;
+            if ses.user_id != old.user_id && !ses.is_admin {
+                send_user_error!(ses, Topic::Video(&vh), "Failed to edit comment.", "You can only edit your own comments", true);
+                return Ok(());
+            }
+            ses.server.db.edit_comment(comment_id, &new_text, ses.user_id)?;
+            ses.emit_cmd("del_comment", &json!({ "comment_id": comment_id }), super::SendTo::VideoHash(&vh))?;
+            let c = ses.server.db.get_comment(comment_id)?;
+            super::mentions::update_mentions(&ses.server, &ses.server.db.get_video(&vh)?, &c)?;
+            ses.emit_new_comment(c, super::SendTo::VideoHash(&vh)).await?;
+        }
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "Failed to edit comment.", "No such comment. Cannot edit.", true);
+        }
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Change visibility of a comment (author or admin). Viewers who may no longer see it get `del_comment`.
+pub async fn msg_set_comment_visibility(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use models::comment_visibility as cv;
+    let comment_id = data["comment_id"].as_i64().ok_or(anyhow!("comment_id missing"))? as i32;
+    let vis = data["visibility"].as_str().ok_or(anyhow!("visibility missing"))?;
+    if !cv::is_valid(vis) {
+        send_user_error!(ses, Topic::Comment(comment_id), "Failed to change comment visibility.", format!("Invalid visibility: '{vis}'"), false);
+        return Ok(());
+    }
+    let old = match ses.server.db.get_comment(comment_id) {
+        Ok(c) => c,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such comment.");
+            return Ok(());
+        }
+        Err(e) => { bail!(e); }
+    };
+    let v = ses.server.db.get_video(&old.video_hash)?;
+    if (ses.user_id != old.user_id && !ses.is_admin) || !sees_internal_comments(ses, &v)? {
+        send_user_error!(ses, Topic::Comment(comment_id), "Failed to change comment visibility.", "Only the comment's author on the owner's side can do that", false);
+        return Ok(());
+    }
+    ses.server.db.set_comment_visibility(comment_id, vis)?;
+    ses.emit_cmd("del_comment", &json!({ "comment_id": comment_id }), super::SendTo::VideoHash(&old.video_hash))?;
+    let c = ses.server.db.get_comment(comment_id)?;
+    ses.emit_new_comment(c, super::SendTo::VideoHash(&old.video_hash)).await?;
+    Ok(())
+}
+
+pub async fn msg_del_comment(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let comment_id = data["comment_id"].as_i64().ok_or(anyhow!("comment_id missing"))? as i32;
+
+    match ses.server.db.get_comment(comment_id) {
+        Ok(cmt) => {
+            let vh = cmt.video_hash;
+            if ses.user_id != cmt.user_id && !ses.is_admin {
+                send_user_error!(ses, Topic::Video(&vh), "Failed to delete comment.", "You can only delete your own comments", true);
+                return Ok(());
+            }
+            let all_comm = ses.server.db.get_video_comments(&vh)?;
+            if all_comm.iter().any(|c| c.parent_id == Some(comment_id)) {
+                send_user_error!(ses, Topic::Video(&vh), "Failed to delete comment.", "Comment has replies. Cannot delete.", true);
+                return Ok(());
+            }
+            for a in ses.server.db.get_comment_attachments(comment_id)? {
+                super::attachments::remove(&ses.server, &a)?;
+            }
+            ses.server.db.del_comment(comment_id)?;
+            ses.emit_cmd("del_comment", &json!({ "comment_id": comment_id }), super::SendTo::VideoHash(&vh))?;
+        }
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "Failed to delete comment.", "No such comment. Cannot delete.", true);
+        }
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Comment attachments changed, send the current list to viewers who may see the comment
+fn emit_comment_attachments(ses: &WsSessionArgs<'_>, c: &models::Comment) -> Res<()> {
+    let send_to = match c.is_internal() {
+        true => super::SendTo::VideoHashInternal(&c.video_hash),
+        false => super::SendTo::VideoHash(&c.video_hash),
+    };
+    let attachments = super::attachments::comment_json(&ses.server, c.id)?;
+    ses.emit_cmd("comment_attachments", &json!({ "comment_id": c.id, "attachments": attachments }), send_to)?;
+    Ok(())
+}
+
+/// Attach a file (`filename`, `data` as data URI) to user's own comment. See `attachments` for limits.
+pub async fn msg_attach_comment_file(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let comment_id = data["comment_id"].as_i64().ok_or(anyhow!("comment_id missing"))? as i32;
+    let filename = data["filename"].as_str().ok_or(anyhow!("filename missing"))?;
+    let file_data = data["data"].as_str().ok_or(anyhow!("data missing"))?;
+
+    let c = match ses.server.db.get_comment(comment_id) {
+        Ok(c) => c,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such comment.");
+            return Ok(());
+        }
+        Err(e) => { bail!(e); }
+    };
+    if ses.user_id != c.user_id && !ses.is_admin {
+        send_user_error!(ses, Topic::Comment(comment_id), "Failed to attach file.", "You can only attach files to your own comments", false);
+        return Ok(());
+    }
+    if ses.server.db.get_closed_review(&c.video_hash)?.is_some() {
+        send_user_error!(ses, Topic::Comment(comment_id), "Review is closed. Cannot attach files.", "Ask the owner to reopen it.", false);
+        return Ok(());
+    }
+    match super::attachments::store(&ses.server, &c, ses.user_id, filename, file_data).await? {
+        Ok(a) => {
+            tracing::info!(comment=comment_id, attachment=a.id, size=a.size, "File attached to comment.");
+            emit_comment_attachments(ses, &c)?;
+        }
+        Err(e) => {
+            send_user_error!(ses, Topic::Comment(comment_id), "Failed to attach file.", e, false);
+        }
+    }
+    Ok(())
+}
+
+/// Remove a file from a comment (comment's author, or admin)
+pub async fn msg_del_comment_attachment(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let attachment_id = data["attachment_id"].as_i64().ok_or(anyhow!("attachment_id missing"))? as i32;
+    let (a, c) = match ses.server.db.get_comment_attachment(attachment_id).and_then(|a| ses.server.db.get_comment(a.comment_id).map(|c| (a, c))) {
+        Ok(ac) => ac,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such attachment.");
+            return Ok(());
+        }
+        Err(e) => { bail!(e); }
+    };
+    if ses.user_id != c.user_id && !ses.is_admin {
+        send_user_error!(ses, Topic::Comment(c.id), "Failed to remove file.", "You can only remove files from your own comments", false);
+        return Ok(());
+    }
+    super::attachments::remove(&ses.server, &a)?;
+    emit_comment_attachments(ses, &c)?;
+    Ok(())
+}
+
+/// Send user the earlier texts of a comment (`comment_history`), to see what was changed and by whom.
+pub async fn msg_get_comment_history(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let comment_id = data["comment_id"].as_i64().ok_or(anyhow!("comment_id missing"))? as i32;
+    let c = match ses.server.db.get_comment(comment_id) {
+        Ok(c) => c,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such comment.");
+            return Ok(());
+        }
+        Err(e) => { bail!(e); }
+    };
+    if c.is_internal() && !sees_internal_comments(ses, &ses.server.db.get_video(&c.video_hash)?)? {
+        send_user_error!(ses, Topic::None, "No such comment.");
+        return Ok(());
+    }
+    let revisions = ses.server.db.get_comment_revisions(comment_id)?.iter().map(|r| r.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("comment_history", &json!({
+            "comment_id": comment_id,
+            "comment": c.comment,
+            "edited": c.edited.map(|t| t.timestamp()),
+            "revisions": revisions }),
+        super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Send user a page of a video's comments (`offset`, `limit`, see `parse_page`), in the order
+/// they were written, e.g. the rest after opening the video with `comments_limit`.
+pub async fn msg_list_comments(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let vh = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let (offset, limit) = match parse_page(data) {
+        Ok(p) => p,
+        Err(msg) => { send_user_error!(ses, Topic::Video(vh), msg); return Ok(()); }
+    };
+    let v = match ses.server.db.get_video(vh) {
+        Ok(v) if v.trashed.is_none() => v,
+        Ok(_) | Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(vh), "No such video."); return Ok(()); }
+        Err(e) => { bail!(e); }
+    };
+    if !organizer_allows(ses, organizer_action::VIEW, vh).await? {
+        return Ok(());
+    }
+    let (page, total) = ses.server.db.get_video_comments_page(vh, sees_internal_comments(ses, &v)?, offset, limit)?;
+    let n = page.len();
+    let mut comments = vec![];
+    for c in page {
+        comments.push(ses.comment_json(c).await?);
+    }
+    let mut msg = page_json(offset, limit, n, total);
+    msg["video_hash"] = json!(vh);
+    msg["comments"] = json!(comments);
+    ses.emit_cmd("comments_page", &msg, super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Record that user has read a video's comments, up to `comment_id` (default: all of them).
+/// User's sessions get the new read position and unread count (`video_seen`), to update
+/// video lists and new comment markers.
+pub async fn msg_mark_seen(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let vh = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let v = match ses.server.db.get_video(vh) {
+        Ok(v) => v,
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(vh), "No such video."); return Ok(()); }
+        Err(e) => { bail!(e); }
+    };
+    let until = match data["comment_id"].as_i64() {
+        None => chrono::Utc::now().naive_utc(),
+        Some(cid) => match ses.server.db.get_comment(cid as i32) {
+            Ok(c) if c.video_hash == vh => c.created,
+            Ok(_) | Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(vh), "No such comment."); return Ok(()); }
+            Err(e) => { bail!(e); }
+        },
+    };
+    let seen = ses.server.db.set_video_seen(ses.user_id, vh, until)?;
+    ses.emit_cmd("video_seen", &json!({
+            "video_hash": vh,
+            "seen_until": seen.timestamp(),
+            "unread_count": unread_counts(ses, std::slice::from_ref(&v))?[0] }),
+        super::SendTo::UserId(ses.user_id))?;
+    Ok(())
+}
+
+/// Add (or with `remove`, take back) user's emoji reaction to a comment.
+/// Viewers of the video get the comment's updated reactions (`comment_reactions`).
+pub async fn msg_react_comment(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use models::comment_reaction as cr;
+    let comment_id = data["comment_id"].as_i64().ok_or(anyhow!("comment_id missing"))? as i32;
+    let emoji = data["emoji"].as_str().ok_or(anyhow!("emoji missing"))?;
+    let remove = data["remove"].as_bool().unwrap_or(false);
+
+    let c = match ses.server.db.get_comment(comment_id) {
+        Ok(c) => c,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such comment.");
+            return Ok(());
+        }
+        Err(e) => { bail!(e); }
+    };
+    let v = ses.server.db.get_video(&c.video_hash)?;
+    if c.is_internal() && !sees_internal_comments(ses, &v)? {
+        send_user_error!(ses, Topic::None, "No such comment.");
+        return Ok(());
+    }
+    if !remove {
+        if !cr::is_valid(emoji) {
+            send_user_error!(ses, Topic::Comment(comment_id), "Failed to add reaction.", "Reaction must be a single emoji", false);
+            return Ok(());
+        }
+        if ses.server.db.get_closed_review(&c.video_hash)?.is_some() {
+            send_user_error!(ses, Topic::Comment(comment_id), "Review is closed. Cannot react.", "Ask the owner to reopen it.", false);
+            return Ok(());
+        }
+        let own = ses.server.db.get_comment_reactions(comment_id)?.into_iter().filter(|r| r.user_id == ses.user_id).count();
+        if own >= cr::MAX_PER_USER {
+            send_user_error!(ses, Topic::Comment(comment_id), "Failed to add reaction.", format!("At most {} reactions per comment", cr::MAX_PER_USER), false);
+            return Ok(());
+        }
+    }
+    let changed = match remove {
+        true => ses.server.db.del_comment_reaction(comment_id, ses.user_id, emoji)?,
+        false => ses.server.db.add_comment_reaction(comment_id, ses.user_id, emoji)?,
+    };
+    if changed {
+        let reactions = cr::summarize(&ses.server.db.get_comment_reactions(comment_id)?);
+        let send_to = match c.is_internal() {
+            true => super::SendTo::VideoHashInternal(&c.video_hash),
+            false => super::SendTo::VideoHash(&c.video_hash),
+        };
+        ses.emit_cmd("comment_reactions", &json!({ "comment_id": comment_id, "reactions": reactions }), send_to)?;
+    }
+    Ok(())
+}
+
+pub async fn msg_list_my_messages(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let msgs = ses.server.db.get_user_messages(ses.user_id)?;
+    let locale = super::i18n::user_locale(&ses.server, ses.user_id);
+    for m in msgs {
+        let mut fields = m.to_json()?;
+        fields["message"] = json!(ses.server.i18n.translate(&locale, &m.message));
+        ses.emit_cmd("message", &fields, super::SendTo::CurSession())?;
+        if !m.seen {
+            ses.server.db.set_message_seen(m.id, true)?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn msg_join_collab(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let collab_id = data["collab_id"].as_str().ok_or(anyhow!("collab_id missing"))?;
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+
+    if let Some(collab_id) = ses.cur_collab_id.clone() {
+        if ses.server.sender_is_collab_participant(collab_id.as_str(), ses.sender) {
+            tracing::debug!("{} is already in collab {}. Ignoring double join.", ses.user_name, collab_id);
+            return Ok(());
+        }
+    }
+
+    ses.collab_session_guard = None;
+    if let Some(old_id) = ses.cur_collab_id.take() {
+        super::collab_state::leave(&ses.server, &old_id, ses.user_id);
+    }
+
+    match ses.server.db.get_video(video_hash) {
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(video_hash), "No such video.");
+        }
+        Err(e) => { bail!(e); }
+        Ok(v) => {
+            // Persisted room first, it may have been created by another instance (see `collab_state`)
+            let joined = super::collab_state::join(&ses.server, collab_id, video_hash, ses.user_id, ses.user_name)
+                .and_then(|room| Ok((room, ses.server.link_session_to_collab(collab_id, video_hash, ses.sender.clone())?)));
+            match joined {
+                Ok((room, csg)) => {
+                    ses.collab_session_guard = Some(csg);
+                    ses.cur_collab_id = Some(collab_id.to_string());
+                    let profile = super::profiles::profile_json(&ses.server, ses.user_id, ses.user_name);
+                    ses.emit_cmd("message", &json!({"event_name": "ok", "message": format!("'{}' joined collab", profile["display_name"].as_str().unwrap_or(ses.user_name)),
+                        "from_profile": profile}), super::SendTo::CurCollab())?;
+                    if let Some(cmd) = super::collab_state::resume_cmd(&ses.server, &room) {
+                        ses.emit_cmd("collab_cmd", &cmd, super::SendTo::CurSession())?;
+                    }
+                }
+                Err(e) => {
+                    send_user_error!(ses, Topic::Video(video_hash), format!("Failed to join collab session: {}", e));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resume a disconnected session (see `session_resume`): `token` (from its `welcome`) and `last_seq`
+/// (highest `seq` client has seen). Rejoins the session's video and collab, and replays the events
+/// it missed, followed by `resumed`. Replies `resume_failed` if it can't, and client should reload.
+pub async fn msg_resume(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::session_resume as sr;
+    let token = data["token"].as_str().ok_or(anyhow!("token missing"))?;
+    let last_seq = data["last_seq"].as_u64().ok_or(anyhow!("last_seq missing"))?;
+    let now = std::time::Instant::now();
+
+    let failed = |ses: &mut WsSessionArgs<'_>, reason: &str| {
+        tracing::info!(reason, "Session resume failed.");
+        ses.emit_cmd("resume_failed", &json!({ "reason": reason }), super::SendTo::CurSession()).map(|_| ())
+    };
+    let old = match ses.server.config.sessions().resume_window {
+        Some(window) => ses.server.resume.take_session(token, ses.user_id, window, now),
+        None => { return failed(ses, "Resuming sessions is disabled."); },
+    };
+    let Some(old) = old else { return failed(ses, "No such session, or it expired."); };
+
+    // Subscribe first, then replay, so nothing falls in between (client skips duplicates by seq)
+    let mut keys = vec![sr::user_key(ses.user_id)];
+    if let Some(vh) = &old.video_hash {
+        match ses.server.db.get_video(vh) {
+            Ok(v) if v.trashed.is_none() => {
+                let sees_internal = sees_internal_comments(ses, &v)?;
+                ses.video_session_guard = Some(ses.server.link_session_to_video(vh, ses.sender.clone(), sees_internal));
+                ses.cur_video_hash = Some(vh.clone());
+                keys.push(sr::video_key(vh));
+                if sees_internal { keys.push(sr::video_internal_key(vh)); }
+            },
+            Ok(_) | Err(DBError::NotFound()) => { return failed(ses, "Video is gone."); },
+            Err(e) => { bail!(e); },
+        }
+    }
+    if let (Some(cid), Some(vh)) = (&old.collab_id, &old.video_hash) {
+        let room = super::collab_state::join(&ses.server, cid, vh, ses.user_id, ses.user_name)?;
+        ses.collab_session_guard = Some(ses.server.link_session_to_collab(&room.collab_id, vh, ses.sender.clone())?);
+        ses.cur_collab_id = Some(cid.clone());
+        keys.push(sr::collab_key(cid));
+    }
+    let Some(events) = ses.server.resume.events_since(&keys, last_seq) else {
+        ses.collab_session_guard = None;
+        ses.video_session_guard = None;
+        if let Some(cid) = ses.cur_collab_id.take() {
+            super::collab_state::leave(&ses.server, &cid, ses.user_id);
+        }
+        ses.cur_video_hash = None;
+        return failed(ses, "Missed too many events.");
+    };
+    tracing::info!(n_events=events.len(), video=?old.video_hash, collab=?old.collab_id, "Session resumed.");
+    let n_events = events.len();
+    for msg in events {
+        ses.sender.send(msg)?;
+    }
+    ses.emit_cmd("resumed", &json!({
+        "video_hash": old.video_hash, "collab_id": old.collab_id, "replayed": n_events, "seq": ses.server.resume.current_seq() }),
+        super::SendTo::CurSession())?;
+    Ok(())
+}
+
+pub async fn msg_leave_collab(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    if let Some(collab_id) = ses.cur_collab_id.clone() {
+        let profile = super::profiles::profile_json(&ses.server, ses.user_id, ses.user_name);
+        ses.emit_cmd("message", &json!({"event_name": "ok", "message": format!("'{}' left collab", profile["display_name"].as_str().unwrap_or(ses.user_name)),
+            "from_profile": profile}), super::SendTo::CurCollab())?;
+        ses.collab_session_guard = None;
+        ses.cur_collab_id = None;
+        super::collab_state::leave(&ses.server, &collab_id, ses.user_id);
+    }
+    Ok(())
+}
+
+pub async fn msg_collab_report(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    if ses.cur_collab_id.is_none() {
+        send_user_error!(ses, Topic::None, "Report rejected: no active collab session.");
+        return Ok(());
+    }
+    let paused = data["paused"].as_bool().ok_or(anyhow!("paused missing"))?;
+    let seek_time = data["seek_time"].as_f64().ok_or(anyhow!("seek_time missing"))?;
+    let img_url = data["drawing"].as_str();
+    let from_profile = super::profiles::profile_json(&ses.server, ses.user_id, ses.user_name);
+    let mut msg = if img_url.is_some() {
+        json!({ "paused": paused, "seek_time": seek_time, "drawing": img_url, "from_user": &ses.user_name, "from_profile": from_profile })
+    } else {
+        json!({ "paused": paused, "seek_time": seek_time, "from_user": &ses.user_name, "from_profile": from_profile })
+    };
+    // Page being viewed, when reviewing a still
+    let page = data["page"].as_i64().map(|p| p as i32);
+    if let Some(page) = page {
+        msg["page"] = json!(page);
+    }
+    if let Some(collab_id) = &ses.cur_collab_id {
+        super::collab_state::report(&ses.server, collab_id, paused, seek_time, page);
+    }
+    ses.emit_cmd("collab_cmd", &msg, super::SendTo::CurCollab()).map(|_| ())
+}
+
+/// Schedule a collab review of a video for later (see `review_schedule`): `starts` (unix time),
+/// optional `duration_min` (default 60), `title` (default video title) and `invitees` (user IDs).
+/// Replies with `review_session`.
+pub async fn msg_schedule_review(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::review_schedule::{self as rs, review_event};
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let v = match ses.server.db.get_video(video_hash) {
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(video_hash), "No such video."); return Ok(()); },
+        Err(e) => { bail!(e); },
+        Ok(v) => v,
+    };
+    let now = chrono::Utc::now().naive_utc();
+    let starts = match data["starts"].as_i64().and_then(|t| chrono::NaiveDateTime::from_timestamp_opt(t, 0)) {
+        Some(t) if t > now && t < now + chrono::Duration::days(rs::MAX_DAYS_AHEAD) => t,
+        _ => {
+            send_user_error!(ses, Topic::Video(video_hash), format!("Review must start in the future, within {} days.", rs::MAX_DAYS_AHEAD));
+            return Ok(());
+        }
+    };
+    let duration_min = match data["duration_min"].as_i64().unwrap_or(60) {
+        d if (1..=rs::MAX_DURATION_MIN as i64).contains(&d) => d as i32,
+        _ => {
+            send_user_error!(ses, Topic::Video(video_hash), format!("Review can last 1 to {} minutes.", rs::MAX_DURATION_MIN));
+            return Ok(());
+        }
+    };
+    let mut invitees: Vec<String> = Vec::new();
+    for uid in data["invitees"].as_array().into_iter().flatten().filter_map(|u| u.as_str()) {
+        if uid != ses.user_id && !invitees.iter().any(|i| i == uid) { invitees.push(uid.into()); }
+    }
+    if invitees.len() > rs::MAX_INVITEES {
+        send_user_error!(ses, Topic::Video(video_hash), format!("Too many invitees (max {}).", rs::MAX_INVITEES));
+        return Ok(());
+    }
+    let users = ses.server.db.get_users()?;
+    let unknown = invitees.iter().filter(|uid| !users.iter().any(|u| &u.user_id == *uid && !u.disabled)).cloned().collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        send_user_error!(ses, Topic::Video(video_hash), format!("Unknown users: {}", unknown.join(", ")));
+        return Ok(());
+    }
+    let title = match data["title"].as_str().map(str::trim).filter(|t| !t.is_empty()) {
+        Some(t) => t.chars().take(200).collect(),
+        None => format!("Review: {}", v.title.clone().unwrap_or(v.video_hash.clone())),
+    };
+    let session = ses.server.db.add_review_session(&models::ReviewSessionInsert {
+        collab_id: super::share_links::new_token(),
+        video_hash: video_hash.into(),
+        host: ses.user_id.into(),
+        host_name: ses.user_name.into(),
+        title,
+        starts,
+        duration_min,
+    }, &invitees)?;
+    tracing::info!(session=session.id, video=video_hash, n_invitees=invitees.len(), "Review session scheduled.");
+    rs::notify(&ses.server, &session, &invitees, review_event::SCHEDULED)?;
+    ses.emit_cmd("review_session", &rs::session_json(&ses.server, &session)?, super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Send user the review sessions they host or are invited to, that haven't ended.
+pub async fn msg_list_reviews(_data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::review_schedule as rs;
+    let now = chrono::Utc::now().naive_utc();
+    let since = now - chrono::Duration::minutes(rs::MAX_DURATION_MIN as i64);
+    let sessions = ses.server.db.get_user_review_sessions(ses.user_id, since)?.iter()
+        .filter(|s| s.ends() > now)
+        .map(|s| rs::session_json(&ses.server, s)).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("review_sessions", &json!({ "sessions": sessions }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Cancel a scheduled review session (host or admin). Invitees are told, and get an ICS cancellation.
+/// A collab that has already started goes on.
+pub async fn msg_cancel_review(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::review_schedule::{self as rs, review_event};
+    let id = data["id"].as_i64().ok_or(anyhow!("id missing"))? as i32;
+    let session = match ses.server.db.get_review_session(id) {
+        Ok(s) if s.host == ses.user_id || ses.is_admin => s,
+        Ok(_) | Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such review session, or not scheduled by you.");
+            return Ok(());
+        },
+        Err(e) => { bail!(e); },
+    };
+    let invitees = ses.server.db.get_review_invitees(id)?;
+    ses.server.db.del_review_session(id)?;
+    tracing::info!(session=id, "Review session cancelled.");
+    rs::notify(&ses.server, &session, &invitees, review_event::CANCELLED)?;
+    msg_list_reviews(data, ses).await
+}
+
+
+/// Admin sets or lifts legal hold on a video, or on a folder (`folder_id` instead of `video_hash`).
+/// Videos under legal hold, also through their folder or its parents, can't be deleted by anyone until the hold is lifted.
+pub async fn msg_set_legal_hold(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let hold = data["hold"].as_bool().ok_or(anyhow!("hold missing"))?;
+    let reason = data["reason"].as_str().unwrap_or("");
+    let action = if hold { models::audit_action::LEGAL_HOLD_SET } else { models::audit_action::LEGAL_HOLD_LIFTED };
+    let msg = if hold { "Legal hold set." } else { "Legal hold lifted." };
+
+    if let Some(folder_id) = data["folder_id"].as_i64() {
+        match ses.server.db.set_folder_legal_hold(folder_id as i32, hold) {
+            Ok(()) => {
+                audit(ses, action, None, format!("Folder {}. {}", folder_id, reason))?;
+                send_user_ok!(ses, Topic::None, msg, reason, true);
+            }
+            Err(DBError::NotFound()) => {
+                send_user_error!(ses, Topic::None, "No such folder.");
+            }
+            Err(e) => { bail!(e); }
+        }
+        return Ok(());
+    }
+
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash or folder_id missing"))?;
+    match ses.server.db.set_video_legal_hold(video_hash, hold) {
+        Ok(()) => {
+            audit(ses, action, Some(video_hash), reason.into())?;
+            send_user_ok!(ses, Topic::Video(video_hash), msg, reason, true);
+        }
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(video_hash), "No such video.");
+        }
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Owner (or admin) allows or denies others to download the original and transcoded files of a video.
+pub async fn msg_set_allow_download(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let allow = data["allow"].as_bool().ok_or(anyhow!("allow missing"))?;
+    match ses.server.db.get_video(video_hash) {
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(video_hash), "No such video."); },
+        Err(e) => { bail!(e); },
+        Ok(v) if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin => {
+            send_user_error!(ses, Topic::Video(video_hash), "Video not owned by you. Cannot change download permission.");
+        },
+        Ok(_) => {
+            ses.server.db.set_video_allow_download(video_hash, allow)?;
+            send_user_ok!(ses, Topic::Video(video_hash),
+                if allow { "Downloads allowed." } else { "Downloads denied." }, "", true);
+        }
+    }
+    Ok(())
+}
+
+/// Owner (or admin) closes or reopens the review round of a video.
+/// While closed, new comments are rejected but existing ones stay visible.
+pub async fn msg_set_review_closed(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let closed = data["closed"].as_bool().ok_or(anyhow!("closed missing"))?;
+    match ses.server.db.get_video(video_hash) {
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(video_hash), "No such video."); },
+        Err(e) => { bail!(e); },
+        Ok(v) if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin => {
+            send_user_error!(ses, Topic::Video(video_hash), "Video not owned by you. Cannot close or reopen review.");
+        },
+        Ok(_) => {
+            if closed {
+                ses.server.db.close_review(video_hash, ses.user_id)?;
+            } else if let Err(e) = ses.server.db.reopen_review(video_hash) {
+                if !matches!(e, DBError::NotFound()) { bail!(e); }    // Already open is fine
+            }
+            let action = if closed { models::audit_action::REVIEW_CLOSED } else { models::audit_action::REVIEW_REOPENED };
+            audit(ses, action, Some(video_hash), "".into())?;
+            ses.emit_cmd("review_status", &json!({ "video_hash": video_hash, "closed": closed, "by": ses.user_name }),
+                super::SendTo::VideoHash(video_hash))?;
+            send_user_ok!(ses, Topic::Video(video_hash),
+                if closed { "Review closed." } else { "Review reopened." }, "", true);
+        }
+    }
+    Ok(())
+}
+
+/// Reviewers' verdicts on a video, with the aggregate status (see `models::review_verdict`)
+fn review_verdicts_json(ses: &WsSessionArgs<'_>, vh: &str) -> Res<serde_json::Value> {
+    let verdicts = ses.server.db.get_review_verdicts(vh)?;
+    Ok(json!({
+        "video_hash": vh,
+        "status": models::review_verdict::aggregate(&verdicts),
+        "verdicts": verdicts.iter().map(|v| v.to_json()).collect::<Result<Vec<_>, _>>()?,
+    }))
+}
+
+/// Tell video's viewers, owner and webhook (if configured) that current user's verdict changed.
+/// `verdict` is None if it was withdrawn.
+fn emit_review_verdict_change(ses: &WsSessionArgs<'_>, v: &models::Video, prev_status: &str, verdict: Option<&models::ReviewVerdict>) -> Res<()> {
+    use models::review_verdict as rv;
+    let review = review_verdicts_json(ses, &v.video_hash)?;
+    ses.emit_cmd("review_verdicts", &review, super::SendTo::VideoHash(&v.video_hash))?;
+
+    let title = v.title.clone().unwrap_or(v.video_hash.clone());
+    let note = verdict.and_then(|vd| vd.note.clone());
+    if let Some(owner) = v.added_by_userid.as_deref().filter(|o| *o != ses.user_id) {
+        let message = match verdict {
+            Some(vd) if vd.verdict == rv::APPROVED => format!("{} approved '{}'", ses.user_name, title),
+            Some(_) => format!("{} requested changes to '{}'", ses.user_name, title),
+            None => format!("{} withdrew their verdict on '{}'", ses.user_name, title),
+        };
+        ses.server.push_user_message(&models::MessageInsert {
+            event_name: "info".into(),
+            user_id: owner.into(),
+            ref_video_hash: Some(v.video_hash.clone()),
+            message,
+            details: note.clone().unwrap_or_default(),
+            ..Default::default() }, true)?;
+    }
+    if let Some(hook) = ses.server.config.webhook() {
+        hook.send("review_verdict", json!({
+            "video_hash": v.video_hash, "title": title, "owner": v.added_by_userid,
+            "reviewer": ses.user_id, "reviewer_name": ses.user_name,
+            "verdict": verdict.map(|vd| &vd.verdict), "note": note,
+            "status": review["status"], "previous_status": prev_status }));
+    }
+    Ok(())
+}
+
+/// Reviewer approves a video or requests changes to it, with an optional note.
+/// Replaces their earlier verdict, if any.
+pub async fn msg_set_review_verdict(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let verdict = data["verdict"].as_str().ok_or(anyhow!("verdict missing"))?;
+    let note = data["note"].as_str().map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+    if !models::review_verdict::is_valid(verdict) {
+        send_user_error!(ses, Topic::Video(video_hash), "Invalid verdict.", format!("Use '{}' or '{}'.",
+            models::review_verdict::APPROVED, models::review_verdict::CHANGES_REQUESTED), false);
+        return Ok(());
+    }
+    let v = match ses.server.db.get_video(video_hash) {
+        Ok(v) => v,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(video_hash), "No such video.");
+            return Ok(());
+        },
+        Err(e) => { bail!(e); }
+    };
+    if v.added_by_userid.as_deref() == Some(ses.user_id) {
+        send_user_error!(ses, Topic::Video(video_hash), "You can't review your own video.");
+        return Ok(());
+    }
+    let prev_status = models::review_verdict::aggregate(&ses.server.db.get_review_verdicts(video_hash)?);
+    let new = ses.server.db.set_review_verdict(&models::ReviewVerdictInsert {
+        video_hash: video_hash.into(),
+        user_id: ses.user_id.into(),
+        username: ses.user_name.into(),
+        verdict: verdict.into(),
+        note,
+    })?;
+    emit_review_verdict_change(ses, &v, prev_status, Some(&new))
+}
+
+/// Reviewer withdraws their verdict on a video.
+pub async fn msg_withdraw_review_verdict(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let v = match ses.server.db.get_video(video_hash) {
+        Ok(v) => v,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(video_hash), "No such video.");
+            return Ok(());
+        },
+        Err(e) => { bail!(e); }
+    };
+    let prev_status = models::review_verdict::aggregate(&ses.server.db.get_review_verdicts(video_hash)?);
+    match ses.server.db.del_review_verdict(video_hash, ses.user_id) {
+        Ok(()) => emit_review_verdict_change(ses, &v, prev_status, None),
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(video_hash), "You have no verdict on this video.");
+            Ok(())
+        },
+        Err(e) => { bail!(e); }
+    }
+}
+
+pub async fn msg_list_review_verdicts(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    ses.emit_cmd("review_verdicts", &review_verdicts_json(ses, video_hash)?, super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Get a video for managing its share links: owner and admins only.
+/// Tells user why if not found or not allowed.
+fn get_video_for_sharing(ses: &mut WsSessionArgs<'_>, video_hash: &str) -> Res<Option<models::Video>> {
+    match ses.server.db.get_video(video_hash) {
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(video_hash), "No such video."); },
+        Err(e) => { bail!(e); },
+        Ok(v) if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin => {
+            send_user_error!(ses, Topic::Video(video_hash), "Video not owned by you. Cannot manage share links.");
+        },
+        Ok(v) => return Ok(Some(v)),
+    }
+    Ok(None)
+}
+
+/// Create a public link to a video, for viewing (and optionally commenting) without an account.
+/// Optional `password` and `expires_hours`. Replies with `share_link`.
+pub async fn msg_create_share_link(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::share_links;
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let password = data["password"].as_str().filter(|p| !p.is_empty());
+    let expires = match data["expires_hours"].as_u64() {
+        Some(0) => {
+            send_user_error!(ses, Topic::Video(video_hash), "Expiry must be at least one hour.");
+            return Ok(());
+        },
+        Some(h) => Some(chrono::Utc::now().naive_utc() + chrono::Duration::hours(h.min(24 * 365 * 10) as i64)),
+        None => None,
+    };
+    if get_video_for_sharing(ses, video_hash)?.is_none() { return Ok(()); }
+    let link = ses.server.db.add_share_link(&models::ShareLinkInsert {
+        token: share_links::new_token(),
+        video_hash: video_hash.into(),
+        created_by: ses.user_id.into(),
+        allow_comments: data["allow_comments"].as_bool().unwrap_or(false),
+        password_hash: password.map(share_links::hash_password),
+        expires,
+    })?;
+    audit(ses, models::audit_action::SHARE_LINK_CREATED, Some(video_hash),
+        format!("Link #{}: comments={}, password={}, expires={:?}.", link.id, link.allow_comments, password.is_some(), link.expires))?;
+    ses.emit_cmd("share_link", &share_links::link_json(&ses.server, &link)?, super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Send user the share links of a video.
+pub async fn msg_list_share_links(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    if get_video_for_sharing(ses, video_hash)?.is_none() { return Ok(()); }
+    let links = ses.server.db.get_share_links(video_hash)?.iter()
+        .map(|l| super::share_links::link_json(&ses.server, l)).collect::<Res<Vec<_>>>()?;
+    ses.emit_cmd("share_links", &json!({ "video_hash": video_hash, "links": links }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Revoke a share link. Guests currently on it are disconnected.
+pub async fn msg_revoke_share_link(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let id = data["id"].as_i64().ok_or(anyhow!("id missing"))? as i32;
+    let link = match ses.server.db.get_share_link(id) {
+        Ok(l) => l,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such share link.");
+            return Ok(());
+        },
+        Err(e) => { bail!(e); },
+    };
+    if get_video_for_sharing(ses, &link.video_hash)?.is_none() { return Ok(()); }
+    ses.server.db.del_share_link(id)?;
+    super::share_links::close_guest_sessions(&ses.server, &link)?;
+    audit(ses, models::audit_action::SHARE_LINK_REVOKED, Some(&link.video_hash), format!("Link #{}.", link.id))?;
+    send_user_ok!(ses, Topic::Video(&link.video_hash), "Share link revoked.");
+    Ok(())
+}
+
+/// Max length of a folder name
+const MAX_FOLDER_TITLE_LEN: usize = 160;
+
+/// Max length of a video tag
+const MAX_TAG_LEN: usize = 64;
+
+/// Max number of tags suggested by autocompletion
+const MAX_TAG_SUGGESTIONS: i64 = 20;
+
+/// Clean up a tag given by user: trim and collapse whitespace. None if empty or too long.
+fn normalize_tag(s: &str) -> Option<String> {
+    let t = s.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!t.is_empty() && t.chars().count() <= MAX_TAG_LEN).then_some(t)
+}
+
+/// Owner (or admin) adds (`add_video_tag`) or removes (`del_video_tag`) a tag of a video.
+/// Replies with the video's tags.
+async fn tag_video(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>, add: bool) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let Some(tag) = data["tag"].as_str().and_then(normalize_tag) else {
+        send_user_error!(ses, Topic::Video(video_hash), format!("Invalid tag (1-{} characters)", MAX_TAG_LEN));
+        return Ok(());
+    };
+    match ses.server.db.get_video(video_hash) {
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(video_hash), "No such video."); },
+        Err(e) => { bail!(e); },
+        Ok(v) if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin => {
+            send_user_error!(ses, Topic::Video(video_hash), "Video not owned by you. Cannot change tags.");
+        },
+        Ok(_) => {
+            if add {
+                ses.server.db.add_video_tag(video_hash, &tag, ses.user_id)?;
+            } else if let Err(e) = ses.server.db.del_video_tag(video_hash, &tag) {
+                if !matches!(e, DBError::NotFound()) { bail!(e); }
+                send_user_error!(ses, Topic::Video(video_hash), "Video has no such tag.");
+                return Ok(());
+            }
+            let tags = ses.server.db.get_video_tags(&[video_hash.to_string()])?.into_iter().map(|t| t.tag).collect::<Vec<_>>();
+            ses.emit_cmd("video_tags", &json!({ "video_hash": video_hash, "tags": tags }), super::SendTo::UserId(ses.user_id))?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn msg_add_video_tag(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    tag_video(data, ses, true).await
+}
+
+pub async fn msg_del_video_tag(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    tag_video(data, ses, false).await
+}
+
+/// Suggest tags that start with `prefix` (may be empty), most used first.
+/// Only tags on user's own and linked videos (admin: all videos) are suggested.
+pub async fn msg_autocomplete_tags(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let prefix = data["prefix"].as_str().unwrap_or("").trim_start();
+    let scope = if ses.is_admin { None } else {
+        Some(ses.server.db.get_all_user_videos(ses.user_id)?.into_iter()
+            .chain(ses.server.db.get_user_linked_videos(ses.user_id)?)
+            .map(|v| v.video_hash).collect::<Vec<_>>())
+    };
+    let tags = ses.server.db.search_video_tags(prefix, scope.as_deref(), MAX_TAG_SUGGESTIONS)?.into_iter()
+        .map(|(tag, count)| json!({ "tag": tag, "count": count })).collect::<Vec<_>>();
+    ses.emit_cmd("tag_suggestions", &json!({ "prefix": prefix, "tags": tags }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Max number of users suggested by `search_users`
+const MAX_USER_SUGGESTIONS: usize = 20;
+
+/// Look up users by name or ID (e.g. for mention autocompletion and sharing with teams).
+/// Case, diacritics and small typos are ignored, see `DB::search_users`.
+pub async fn msg_search_users(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let query = data["query"].as_str().unwrap_or("");
+    let users = ses.server.db.search_users(query, MAX_USER_SUGGESTIONS)?.into_iter()
+        .map(|u| json!({ "user_id": u.user_id, "username": u.username })).collect::<Vec<_>>();
+    ses.emit_cmd("user_suggestions", &json!({ "query": query, "users": users }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Max length of a custom field name
+const MAX_CUSTOM_FIELD_NAME_LEN: usize = 64;
+
+/// Custom field values of given videos, by video hash. Values of fields that are no longer defined are left out.
+fn video_custom_fields(ses: &WsSessionArgs<'_>, vhs: &[String]) -> Res<HashMap<String, models::CustomFieldValues>> {
+    let defined = ses.server.db.get_custom_fields()?.into_iter().map(|f| f.name).collect::<Vec<_>>();
+    let mut res = ses.server.db.get_video_custom_fields(vhs)?;
+    for vals in res.values_mut() {
+        vals.retain(|k, _| defined.contains(k));
+    }
+    Ok(res)
+}
+
+/// Check a listing filter condition against a custom field value.
+/// Text matches case-insensitively by substring, numbers by value or `{"min": .., "max": ..}`, and selects exactly.
+fn custom_field_matches(field: &models::CustomField, value: &serde_json::Value, cond: &serde_json::Value) -> bool {
+    match (field.field_type.as_str(), value, cond) {
+        (_, serde_json::Value::Null, _) => false,
+        (models::custom_field_type::TEXT, serde_json::Value::String(v), serde_json::Value::String(c)) =>
+            v.to_lowercase().contains(&c.to_lowercase()),
+        (models::custom_field_type::NUMBER, v, serde_json::Value::Object(range)) => v.as_f64().is_some_and(|n|
+            range.get("min").and_then(|m| m.as_f64()).is_none_or(|m| n >= m) &&
+            range.get("max").and_then(|m| m.as_f64()).is_none_or(|m| n <= m)),
+        (models::custom_field_type::NUMBER, v, c) => v.as_f64().is_some() && v.as_f64() == c.as_f64(),
+        (_, v, c) => v == c,
+    }
+}
+
+/// Send client all custom field definitions.
+pub async fn msg_list_custom_fields(_data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let fields = ses.server.db.get_custom_fields()?.iter()
+        .map(|f| f.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("custom_fields", &json!({ "fields": fields }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Admin adds or replaces a custom field.
+/// `field_type` is "text", "number" or "select" (which needs a list of `options`).
+pub async fn msg_admin_set_custom_field(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let name = data["name"].as_str().ok_or(anyhow!("name missing"))?.trim();
+    let field_type = data["field_type"].as_str().ok_or(anyhow!("field_type missing"))?;
+    if name.is_empty() || name.len() > MAX_CUSTOM_FIELD_NAME_LEN || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        send_user_error!(ses, Topic::None, format!("Invalid field name (1-{} letters, numbers, '_' and '-').", MAX_CUSTOM_FIELD_NAME_LEN));
+        return Ok(());
+    }
+    if !models::custom_field_type::is_valid(field_type) {
+        send_user_error!(ses, Topic::None, format!("Unknown field type '{}'.", field_type));
+        return Ok(());
+    }
+    let mut options = vec![];
+    if field_type == models::custom_field_type::SELECT {
+        for o in data["options"].as_array().into_iter().flatten() {
+            match o.as_str().map(str::trim) {
+                Some(o) if !o.is_empty() && !options.contains(&o) => options.push(o),
+                _ => { send_user_error!(ses, Topic::None, "Select options must be unique, non-empty strings."); return Ok(()); }
+            }
+        }
+        if options.is_empty() {
+            send_user_error!(ses, Topic::None, "Select field needs options.");
+            return Ok(());
+        }
+    }
+    ses.server.db.set_custom_field(&models::CustomField {
+        name: name.into(),
+        title: data["title"].as_str().filter(|t| !t.trim().is_empty()).unwrap_or(name).trim().into(),
+        field_type: field_type.into(),
+        options: (!options.is_empty()).then(|| json!(options).to_string()),
+    })?;
+    msg_list_custom_fields(data, ses).await
+}
+
+/// Admin deletes a custom field. Its values are hidden from videos.
+pub async fn msg_admin_del_custom_field(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let name = data["name"].as_str().ok_or(anyhow!("name missing"))?;
+    match ses.server.db.del_custom_field(name) {
+        Ok(()) => msg_list_custom_fields(data, ses).await?,
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "No such custom field."); },
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Owner (or admin) sets custom field values of a video. `custom_fields` maps field names to values,
+/// null clears a value and fields not mentioned are kept. Nothing is changed if any value is invalid.
+/// Replies with all the video's values.
+pub async fn msg_set_video_custom_fields(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let given = data["custom_fields"].as_object().ok_or(anyhow!("custom_fields missing"))?;
+    match ses.server.db.get_video(video_hash) {
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(video_hash), "No such video."); },
+        Err(e) => { bail!(e); },
+        Ok(v) if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin => {
+            send_user_error!(ses, Topic::Video(video_hash), "Video not owned by you. Cannot change its fields.");
+        },
+        Ok(_) => {
+            let defs = ses.server.db.get_custom_fields()?;
+            let mut changes = models::CustomFieldValues::new();
+            for (name, val) in given {
+                let checked = match defs.iter().find(|f| &f.name == name) {
+                    Some(f) => f.validate(val),
+                    None => Err(format!("No such custom field '{}'.", name)),
+                };
+                match checked {
+                    Ok(v) => { changes.insert(name.clone(), v); },
+                    Err(msg) => { send_user_error!(ses, Topic::Video(video_hash), msg); return Ok(()); }
+                }
+            }
+            ses.server.db.update_video_custom_fields(video_hash, &changes)?;
+            let vals = video_custom_fields(ses, &[video_hash.to_string()])?.remove(video_hash).unwrap_or_default();
+            ses.emit_cmd("video_custom_fields", &json!({ "video_hash": video_hash, "custom_fields": vals }), super::SendTo::UserId(ses.user_id))?;
+        }
+    }
+    Ok(())
+}
+
+/// Send user a list of their folders.
+pub async fn msg_list_folders(_data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let mut folders = vec![];
+    for f in ses.server.db.get_user_folders(ses.user_id)? {
+        let mut fj = f.to_json()?;
+        fj["syncs"] = ses.server.db.get_folder_syncs(Some(f.id))?.iter()
+            .map(|s| s.to_json()).collect::<Result<Vec<_>, _>>()?.into();
+        folders.push(fj);
+    }
+    ses.emit_cmd("user_folders", &json!({ "folders": folders }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Get a folder if current user owns it (or is admin). Sends an error to user if not.
+fn get_owned_folder(ses: &mut WsSessionArgs<'_>, folder_id: i32) -> Res<Option<models::Folder>> {
+    match ses.server.db.get_folder(folder_id) {
+        Ok(f) if f.user_id == ses.user_id || ses.is_admin => Ok(Some(f)),
+        Ok(_) | Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such folder.");
+            Ok(None)
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Create a folder, optionally inside another one. Replies with updated folder list.
+pub async fn msg_create_folder(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let title = data["title"].as_str().ok_or(anyhow!("title missing"))?.trim();
+    if title.is_empty() || title.len() > MAX_FOLDER_TITLE_LEN {
+        send_user_error!(ses, Topic::None, format!("Invalid folder name (1-{} characters)", MAX_FOLDER_TITLE_LEN));
+        return Ok(());
+    }
+    let parent_id = data["parent_id"].as_i64().map(|p| p as i32);
+    if let Some(p) = parent_id {
+        match get_owned_folder(ses, p)? {
+            Some(f) if f.user_id == ses.user_id => {},
+            Some(_) => { send_user_error!(ses, Topic::None, "Can't create folders inside another user's folder."); return Ok(()); },
+            None => return Ok(()),
+        }
+    }
+    ses.server.db.add_folder(&models::FolderInsert { user_id: ses.user_id.into(), title: title.into(), parent_id })?;
+    msg_list_folders(data, ses).await
+}
+
+/// Delete a folder. Its videos and subfolders are moved to the parent folder.
+pub async fn msg_del_folder(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let folder_id = data["folder_id"].as_i64().ok_or(anyhow!("folder_id missing"))? as i32;
+    if let Some(f) = get_owned_folder(ses, folder_id)? {
+        ses.server.db.del_folder(f.id)?;
+        send_user_ok!(ses, Topic::None, "Folder deleted.", format!("Folder: '{}'", f.title), false);
+    }
+    Ok(())
+}
+
+/// Move a video to a folder (`folder_id`), or to root if `folder_id` is null.
+/// Folder must belong to the video's owner.
+pub async fn msg_move_to_folder(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let folder_id = data["folder_id"].as_i64().map(|f| f as i32);
+    let v = match ses.server.db.get_video(video_hash) {
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(video_hash), "No such video."); return Ok(()); },
+        Err(e) => { bail!(e); },
+        Ok(v) if Some(ses.user_id.to_string()) != v.added_by_userid && !ses.is_admin => {
+            send_user_error!(ses, Topic::Video(video_hash), "Video not owned by you. Cannot move.");
+            return Ok(());
+        },
+        Ok(v) => v,
+    };
+    if let Some(fid) = folder_id {
+        match get_owned_folder(ses, fid)? {
+            Some(f) if Some(&f.user_id) == v.added_by_userid.as_ref() => {},
+            Some(_) => { send_user_error!(ses, Topic::Video(video_hash), "Folder not owned by the video's owner."); return Ok(()); },
+            None => return Ok(()),
+        }
+    }
+    ses.server.db.set_video_folder(video_hash, folder_id)?;
+    send_user_ok!(ses, Topic::Video(video_hash), "Video moved.");
+    Ok(())
+}
+
+/// Max videos in one `batch_videos` command
+const MAX_BATCH_VIDEOS: usize = 500;
+
+/// Apply one action to several videos (`video_hashes`) at once: `move_to_folder` (`folder_id`, null for root),
+/// `add_tag` / `del_tag` (`tag`), `set_allow_download` (`allow`) or `delete` (move to trash).
+/// All or nothing: every video is checked first, and if any can't be changed, none are.
+/// Replies with a single `batch_result`, listing videos that failed the checks and why.
+pub async fn msg_batch_videos(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use crate::database::VideoBatchOp as Op;
+    let action = data["action"].as_str().ok_or(anyhow!("action missing"))?;
+    let mut vhs = data["video_hashes"].as_array().ok_or(anyhow!("video_hashes missing"))?.iter()
+        .map(|v| v.as_str().map(String::from).ok_or(anyhow!("video_hashes must be strings")))
+        .collect::<Res<Vec<_>>>()?;
+    vhs.sort();
+    vhs.dedup();
+    if vhs.is_empty() || vhs.len() > MAX_BATCH_VIDEOS {
+        send_user_error!(ses, Topic::None, format!("Select 1-{} videos.", MAX_BATCH_VIDEOS));
+        return Ok(());
+    }
+    let op = match action {
+        "move_to_folder" => Op::MoveToFolder(data["folder_id"].as_i64().map(|f| f as i32)),
+        "add_tag" | "del_tag" => {
+            let Some(tag) = data["tag"].as_str().and_then(normalize_tag) else {
+                send_user_error!(ses, Topic::None, format!("Invalid tag (1-{} characters)", MAX_TAG_LEN));
+                return Ok(());
+            };
+            if action == "add_tag" { Op::AddTag { tag, user_id: ses.user_id.into() } } else { Op::DelTag(tag) }
+        },
+        "set_allow_download" => Op::SetAllowDownload(data["allow"].as_bool().ok_or(anyhow!("allow missing"))?),
+        "delete" => Op::Trash,
+        _ => {
+            send_user_error!(ses, Topic::None, format!("Unknown batch action: '{action}'"));
+            return Ok(());
+        }
+    };
+    let folder = match op {
+        Op::MoveToFolder(Some(fid)) => match get_owned_folder(ses, fid)? {
+            Some(f) => Some(f),
+            None => return Ok(()),
+        },
+        _ => None,
+    };
+
+    // Check all before changing any
+    let mut videos = vec![];
+    let mut failed = vec![];
+    for vh in &vhs {
+        let reason = match ses.server.db.get_video(vh) {
+            Err(DBError::NotFound()) => Some("No such video"),
+            Err(e) => { bail!(e); },
+            Ok(v) if Some(ses.user_id) != v.added_by_userid.as_deref() && !ses.is_admin => Some("Video not owned by you"),
+            Ok(v) if folder.as_ref().is_some_and(|f| Some(&f.user_id) != v.added_by_userid.as_ref()) => Some("Folder not owned by the video's owner"),
+            Ok(_) if op == Op::Trash && ses.server.db.is_video_held(vh)? => {
+                audit(ses, models::audit_action::DELETE_VIDEO_BLOCKED, Some(vh), "Video is under legal hold.".into())?;
+                Some("Video is under legal hold")
+            },
+            Ok(v) if op == Op::Trash && v.trashed.is_some() => Some("Video is already in trash"),
+            Ok(v) => { videos.push(v); None },
+        };
+        if let Some(reason) = reason {
+            failed.push(json!({ "video_hash": vh, "reason": reason }));
+        }
+    }
+    if !failed.is_empty() {
+        ses.emit_cmd("batch_result", &json!({ "action": action, "done": false, "video_hashes": [], "failed": failed }),
+            super::SendTo::CurSession())?;
+        return Ok(());
+    }
+
+    let warnings = match op {
+        Op::Trash => {
+            let warnings = super::trash::move_many_to_trash(&ses.server, &videos)?;
+            for v in &videos {
+                audit(ses, models::audit_action::DELETE_VIDEO, Some(&v.video_hash),
+                    format!("Title was {:?}. One of {} deleted in a batch.", v.title, videos.len()))?;
+            }
+            warnings
+        },
+        _ => {
+            ses.server.db.apply_video_batch(&vhs, &op)?;
+            vec![]
+        },
+    };
+    ses.emit_cmd("batch_result", &json!({ "action": action, "done": true, "video_hashes": vhs, "failed": [], "warnings": warnings }),
+        super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Set overlay presets shown by default for videos in a folder and its subfolders.
+/// `presets` is a list of preset names, or null to inherit from parent folder.
+pub async fn msg_set_folder_overlays(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let folder_id = data["folder_id"].as_i64().ok_or(anyhow!("folder_id missing"))? as i32;
+    let presets = match &data["presets"] {
+        serde_json::Value::Null => None,
+        p => Some(p.as_array().ok_or(anyhow!("presets must be a list or null"))?.iter()
+            .map(|n| n.as_str().map(String::from).ok_or(anyhow!("preset names must be strings")))
+            .collect::<Res<Vec<_>>>()?),
+    };
+    if let Some(names) = &presets {
+        let known = ses.server.db.get_overlay_presets()?.into_iter().map(|p| p.name).collect::<Vec<_>>();
+        if let Some(unknown) = names.iter().find(|n| !known.contains(n)) {
+            send_user_error!(ses, Topic::None, format!("Unknown overlay preset '{}'.", unknown));
+            return Ok(());
+        }
+    }
+    if let Some(f) = get_owned_folder(ses, folder_id)? {
+        ses.server.db.set_folder_overlay_defaults(f.id, presets.as_deref())?;
+        send_user_ok!(ses, Topic::None, "Folder overlay defaults set.", format!("Folder: '{}'", f.title), false);
+    }
+    Ok(())
+}
+
+/// Send client all overlay presets (framing guides) defined in this deployment.
+pub async fn msg_list_overlay_presets(_data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let presets = ses.server.db.get_overlay_presets()?.iter()
+        .map(|p| p.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("overlay_presets", &json!({ "presets": presets }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Set the transcode preset for videos uploaded into a folder (and its subfolders).
+/// `preset` = null inherits from parent folder (or the server default).
+pub async fn msg_set_folder_transcode_preset(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let folder_id = data["folder_id"].as_i64().ok_or(anyhow!("folder_id missing"))? as i32;
+    let preset = match &data["preset"] {
+        serde_json::Value::Null => None,
+        p => Some(p.as_str().ok_or(anyhow!("preset must be a string or null"))?),
+    };
+    if let Some(name) = preset {
+        if !ses.server.config.transcode_presets().contains(name) {
+            send_user_error!(ses, Topic::None, format!("Unknown transcode preset '{}'.", name));
+            return Ok(());
+        }
+    }
+    if let Some(f) = get_owned_folder(ses, folder_id)? {
+        ses.server.db.set_folder_transcode_preset(f.id, preset)?;
+        send_user_ok!(ses, Topic::None, "Folder transcode preset set.", format!("Folder: '{}'", f.title), false);
+    }
+    Ok(())
+}
+
+/// Send client the transcode presets of this deployment, and which one is the default.
+pub async fn msg_list_transcode_presets(_data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    ses.emit_cmd("transcode_presets", &ses.server.config.transcode_presets().to_json(), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Admin adds or replaces an overlay preset.
+/// `kind` is "safe_area" (`value` = inset from each edge, 0..0.5) or "aspect" (`value` = width/height ratio).
+pub async fn msg_set_overlay_preset(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let name = data["name"].as_str().ok_or(anyhow!("name missing"))?.trim();
+    let kind = data["kind"].as_str().ok_or(anyhow!("kind missing"))?;
+    let value = data["value"].as_f64().ok_or(anyhow!("value missing"))? as f32;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        send_user_error!(ses, Topic::None, "Invalid preset name (use letters, numbers, '_' and '-').");
+        return Ok(());
+    }
+    let valid = match kind {
+        models::overlay_kind::SAFE_AREA => value > 0.0 && value < 0.5,
+        models::overlay_kind::ASPECT => value > 0.0 && value <= 10.0,
+        _ => { send_user_error!(ses, Topic::None, format!("Unknown overlay kind '{}'.", kind)); return Ok(()); },
+    };
+    if !valid {
+        send_user_error!(ses, Topic::None, format!("Invalid value {} for overlay kind '{}'.", value, kind));
+        return Ok(());
+    }
+    ses.server.db.set_overlay_preset(&models::OverlayPreset {
+        name: name.into(),
+        title: data["title"].as_str().filter(|t| !t.trim().is_empty()).unwrap_or(name).trim().into(),
+        kind: kind.into(),
+        value,
+        default_on: data["default_on"].as_bool().unwrap_or(false),
+    })?;
+    msg_list_overlay_presets(data, ses).await
+}
+
+/// Admin deletes an overlay preset.
+pub async fn msg_del_overlay_preset(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let name = data["name"].as_str().ok_or(anyhow!("name missing"))?;
+    match ses.server.db.del_overlay_preset(name) {
+        Ok(()) => msg_list_overlay_presets(data, ses).await?,
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "No such overlay preset."); },
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Admin lists federation peers (other Clapshot instances folders can be synced with). Secrets are not sent.
+pub async fn msg_list_federation_peers(_data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let peers = ses.server.db.get_federation_peers()?.iter()
+        .map(|p| p.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("federation_peers", &json!({ "peers": peers }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Admin adds or updates a federation peer.
+/// `url` is the peer's URL base, `secret` (min 16 chars, same on both sides) signs requests both ways,
+/// and `self_name` is the name the peer knows this instance by. Empty `secret` keeps the old one.
+pub async fn msg_set_federation_peer(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let name = data["name"].as_str().ok_or(anyhow!("name missing"))?.trim();
+    let self_name = data["self_name"].as_str().ok_or(anyhow!("self_name missing"))?.trim();
+    let valid_name = |n: &str| !n.is_empty() && n.chars().all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c));
+    if !valid_name(name) || !valid_name(self_name) {
+        send_user_error!(ses, Topic::None, "Invalid peer name (use letters, numbers, '_', '-' and '.').");
+        return Ok(());
+    }
+    let url = match super::url_ingest::parse_ingest_url(data["url"].as_str().ok_or(anyhow!("url missing"))?) {
+        Ok(u) => u.as_str().trim_end_matches('/').to_string(),
+        Err(e) => { send_user_error!(ses, Topic::None, format!("Invalid peer URL: {}", e)); return Ok(()); }
+    };
+    let secret = match data["secret"].as_str().map(str::trim).filter(|s| !s.is_empty()) {
+        Some(s) if s.len() >= 16 => s.to_string(),
+        Some(_) => { send_user_error!(ses, Topic::None, "Peer secret must be at least 16 characters."); return Ok(()); },
+        None => match ses.server.db.get_federation_peer(name) {
+            Ok(p) => p.secret,
+            Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "Secret is required for a new peer."); return Ok(()); },
+            Err(e) => { bail!(e); }
+        },
+    };
+    ses.server.db.set_federation_peer(&models::FederationPeerInsert {
+        name: name.into(), url: url.clone(), secret, self_name: self_name.into() })?;
+    audit(ses, models::audit_action::FEDERATION_PEER_SET, None, format!("Peer '{}' at {}, known there as '{}'.", name, url, self_name))?;
+    msg_list_federation_peers(data, ses).await
+}
+
+/// Admin deletes a federation peer. Folders stop syncing with it, but received videos and comments are kept.
+pub async fn msg_del_federation_peer(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let name = data["name"].as_str().ok_or(anyhow!("name missing"))?;
+    match ses.server.db.del_federation_peer(name) {
+        Ok(()) => {
+            audit(ses, models::audit_action::FEDERATION_PEER_DELETED, None, format!("Peer '{}'.", name))?;
+            msg_list_federation_peers(data, ses).await?
+        },
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "No such federation peer."); },
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Admin syncs a folder with a folder on a federation peer (see `federation::federation_filter`).
+/// The peer's admin must sync the remote folder with this one, too.
+pub async fn msg_set_folder_sync(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let folder_id = data["folder_id"].as_i64().ok_or(anyhow!("folder_id missing"))? as i32;
+    let peer = data["peer"].as_str().ok_or(anyhow!("peer missing"))?;
+    let remote_folder_id = data["remote_folder_id"].as_i64().ok_or(anyhow!("remote_folder_id missing"))? as i32;
+    let Some(f) = get_owned_folder(ses, folder_id)? else { return Ok(()) };
+    match ses.server.db.get_federation_peer(peer) {
+        Ok(_) => {},
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "No such federation peer."); return Ok(()); },
+        Err(e) => { bail!(e); }
+    }
+    if ses.server.db.get_folder_sync(f.id, peer).is_ok() {
+        send_user_error!(ses, Topic::None, "Folder is already synced with this peer.");
+        return Ok(());
+    }
+    ses.server.db.add_folder_sync(&models::FolderSyncInsert { folder_id: f.id, peer: peer.into(), remote_folder_id })?;
+    audit(ses, models::audit_action::FOLDER_SYNC_ADDED, None, format!("Folder {} ('{}') with folder {} at '{}'.", f.id, f.title, remote_folder_id, peer))?;
+    msg_list_folders(data, ses).await
+}
+
+/// Admin stops syncing a folder with a peer. Videos and comments received so far are kept.
+pub async fn msg_del_folder_sync(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let folder_id = data["folder_id"].as_i64().ok_or(anyhow!("folder_id missing"))? as i32;
+    let peer = data["peer"].as_str().ok_or(anyhow!("peer missing"))?;
+    let Some(f) = get_owned_folder(ses, folder_id)? else { return Ok(()) };
+    match ses.server.db.del_folder_sync(f.id, peer) {
+        Ok(()) => {
+            audit(ses, models::audit_action::FOLDER_SYNC_REMOVED, None, format!("Folder {} ('{}') with '{}'.", f.id, f.title, peer))?;
+            msg_list_folders(data, ses).await?
+        },
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "Folder is not synced with this peer."); },
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+const MAX_TEAM_NAME_LEN: usize = 160;
+
+/// Get a team if current user is a member of it (or admin). Sends an error to user if not.
+///
+/// # Returns
+/// The team, and whether user can manage its members (team admin or admin)
+fn get_my_team(ses: &mut WsSessionArgs<'_>, team_id: i32) -> Res<Option<(models::Team, bool)>> {
+    let member = match ses.server.db.get_team_member(team_id, ses.user_id) {
+        Ok(m) => Some(m),
+        Err(DBError::NotFound()) => None,
+        Err(e) => bail!(e),
+    };
+    match ses.server.db.get_team(team_id) {
+        Ok(t) if member.is_some() || ses.is_admin => Ok(Some((t, ses.is_admin || member.is_some_and(|m| m.is_team_admin)))),
+        Ok(_) | Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::None, "No such team.");
+            Ok(None)
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Send user a list of their teams, with members. Admin gets all teams with `all: true`.
+pub async fn msg_list_teams(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let uid = match data["all"].as_bool() == Some(true) && ses.is_admin {
+        true => None,
+        false => Some(ses.user_id),
+    };
+    let mut teams = vec![];
+    for t in ses.server.db.get_teams(uid)? {
+        let members = ses.server.db.get_team_members(t.id)?;
+        let mut tj = t.to_json()?;
+        tj["can_manage"] = json!(ses.is_admin || members.iter().any(|m| m.user_id == ses.user_id && m.is_team_admin));
+        tj["members"] = members.iter().map(|m| m.to_json()).collect::<Result<Vec<_>, _>>()?.into();
+        teams.push(tj);
+    }
+    ses.emit_cmd("teams", &json!({ "teams": teams }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Admin creates a team, optionally with a first member (`admin_user_id`) as team admin.
+pub async fn msg_admin_create_team(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let name = data["name"].as_str().ok_or(anyhow!("name missing"))?.trim();
+    if name.is_empty() || name.len() > MAX_TEAM_NAME_LEN {
+        send_user_error!(ses, Topic::None, format!("Invalid team name (1-{} characters)", MAX_TEAM_NAME_LEN));
+        return Ok(());
+    }
+    if ses.server.db.get_teams(None)?.iter().any(|t| t.name == name) {
+        send_user_error!(ses, Topic::None, "Team name is taken.");
+        return Ok(());
+    }
+    let team = ses.server.db.add_team(name)?;
+    if let Some(uid) = data["admin_user_id"].as_str() {
+        ses.server.db.set_team_member(&models::TeamMember { team_id: team.id, user_id: uid.into(), is_team_admin: true })?;
+        audit(ses, models::audit_action::TEAM_MEMBER_SET, None, format!("Team {} ('{}'): '{}' as team admin.", team.id, team.name, uid))?;
+    }
+    send_user_ok!(ses, Topic::None, "Team created.", format!("Team '{}' (id {}).", team.name, team.id), false);
+    msg_list_teams(&json!({ "all": true }), ses).await
+}
+
+/// Admin deletes a team. Videos and folders shared with it are not affected.
+pub async fn msg_admin_del_team(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let team_id = data["team_id"].as_i64().ok_or(anyhow!("team_id missing"))? as i32;
+    match ses.server.db.del_team(team_id) {
+        Ok(()) => {
+            send_user_ok!(ses, Topic::None, "Team deleted.");
+            msg_list_teams(&json!({ "all": true }), ses).await?
+        },
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "No such team."); },
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Team admin adds a member to a team, or changes their team admin status (`is_team_admin`).
+pub async fn msg_set_team_member(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let team_id = data["team_id"].as_i64().ok_or(anyhow!("team_id missing"))? as i32;
+    let uid = data["user_id"].as_str().ok_or(anyhow!("user_id missing"))?.trim();
+    let is_team_admin = data["is_team_admin"].as_bool().unwrap_or(false);
+    let Some((team, can_manage)) = get_my_team(ses, team_id)? else { return Ok(()) };
+    if !can_manage {
+        send_user_error!(ses, Topic::None, "Only team admins can manage members.");
+        return Ok(());
+    }
+    if uid.is_empty() {
+        send_user_error!(ses, Topic::None, "User ID missing.");
+        return Ok(());
+    }
+    ses.server.db.set_team_member(&models::TeamMember { team_id, user_id: uid.into(), is_team_admin })?;
+    audit(ses, models::audit_action::TEAM_MEMBER_SET, None, format!("Team {} ('{}'): '{}', team admin: {}.", team.id, team.name, uid, is_team_admin))?;
+    msg_list_teams(data, ses).await
+}
+
+/// Team admin removes a member from a team. Members can also remove themselves (leave).
+pub async fn msg_del_team_member(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let team_id = data["team_id"].as_i64().ok_or(anyhow!("team_id missing"))? as i32;
+    let uid = data["user_id"].as_str().ok_or(anyhow!("user_id missing"))?;
+    let Some((team, can_manage)) = get_my_team(ses, team_id)? else { return Ok(()) };
+    if !can_manage && uid != ses.user_id {
+        send_user_error!(ses, Topic::None, "Only team admins can manage members.");
+        return Ok(());
+    }
+    match ses.server.db.del_team_member(team_id, uid) {
+        Ok(()) => {
+            audit(ses, models::audit_action::TEAM_MEMBER_REMOVED, None, format!("Team {} ('{}'): '{}'.", team.id, team.name, uid))?;
+            msg_list_teams(data, ses).await?
+        },
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "User is not a member of the team."); },
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Video or folder to share with a team, from `video_hash` or `folder_id` field
+enum ShareTarget<'a> {
+    Video(&'a str),
+    Folder(i32),
+}
+
+fn parse_share_target(data: &serde_json::Value) -> Res<ShareTarget<'_>> {
+    match (data["video_hash"].as_str(), data["folder_id"].as_i64()) {
+        (Some(vh), None) => Ok(ShareTarget::Video(vh)),
+        (None, Some(fid)) => Ok(ShareTarget::Folder(fid as i32)),
+        _ => bail!("Give either video_hash or folder_id"),
+    }
+}
+
+/// Share a video or folder (with subfolders) with a team in one go. Members can then list it
+/// with `list_team_videos`, and download the videos. User must own it and be a member of the team.
+pub async fn msg_share_with_team(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let team_id = data["team_id"].as_i64().ok_or(anyhow!("team_id missing"))? as i32;
+    let target = parse_share_target(data)?;
+    let Some((team, _)) = get_my_team(ses, team_id)? else { return Ok(()) };
+    let what = match target {
+        ShareTarget::Video(vh) => {
+            match ses.server.db.get_video(vh) {
+                Ok(v) if Some(ses.user_id.to_string()) == v.added_by_userid || ses.is_admin => {},
+                Ok(_) => { send_user_error!(ses, Topic::Video(vh), "Video not owned by you. Cannot share."); return Ok(()); },
+                Err(DBError::NotFound()) => { send_user_error!(ses, Topic::Video(vh), "No such video."); return Ok(()); },
+                Err(e) => { bail!(e); },
+            }
+            ses.server.db.add_team_video(&models::TeamVideo { team_id, video_hash: vh.into(), shared_by: ses.user_id.into() })?;
+            format!("Video {}", vh)
+        },
+        ShareTarget::Folder(fid) => {
+            let Some(f) = get_owned_folder(ses, fid)? else { return Ok(()) };
+            ses.server.db.add_team_folder(&models::TeamFolder { team_id, folder_id: f.id, shared_by: ses.user_id.into() })?;
+            format!("Folder {} ('{}')", f.id, f.title)
+        },
+    };
+    let vh = match target { ShareTarget::Video(vh) => Some(vh), ShareTarget::Folder(_) => None };
+    audit(ses, models::audit_action::TEAM_SHARE_ADDED, vh, format!("{} with team {} ('{}').", what, team.id, team.name))?;
+    send_user_ok!(ses, Topic::None, "Shared with team.", format!("{} shared with team '{}'.", what, team.name), false);
+    Ok(())
+}
+
+/// Stop sharing a video or folder with a team. Owner, team admins and admin can do this.
+pub async fn msg_unshare_from_team(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let team_id = data["team_id"].as_i64().ok_or(anyhow!("team_id missing"))? as i32;
+    let target = parse_share_target(data)?;
+    let Some((team, can_manage)) = get_my_team(ses, team_id)? else { return Ok(()) };
+    let owner = match target {
+        ShareTarget::Video(vh) => ses.server.db.get_video(vh).ok().and_then(|v| v.added_by_userid),
+        ShareTarget::Folder(fid) => ses.server.db.get_folder(fid).ok().map(|f| f.user_id),
+    };
+    if !can_manage && owner.as_deref() != Some(ses.user_id) {
+        send_user_error!(ses, Topic::None, "Only owner or team admins can stop sharing.");
+        return Ok(());
+    }
+    let (res, what, vh) = match target {
+        ShareTarget::Video(vh) => (ses.server.db.del_team_video(team_id, vh), format!("Video {}", vh), Some(vh)),
+        ShareTarget::Folder(fid) => (ses.server.db.del_team_folder(team_id, fid), format!("Folder {}", fid), None),
+    };
+    match res {
+        Ok(()) => {
+            audit(ses, models::audit_action::TEAM_SHARE_REMOVED, vh, format!("{} from team {} ('{}').", what, team.id, team.name))?;
+            send_user_ok!(ses, Topic::None, "No longer shared with team.", format!("{} unshared from team '{}'.", what, team.name), false);
+        },
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "Not shared with this team."); },
+        Err(e) => { bail!(e); }
+    }
+    Ok(())
+}
+
+/// Send a team member the videos and folders shared with the team.
+pub async fn msg_list_team_videos(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let team_id = data["team_id"].as_i64().ok_or(anyhow!("team_id missing"))? as i32;
+    let Some((team, _)) = get_my_team(ses, team_id)? else { return Ok(()) };
+    let (_, shared_folders) = ses.server.db.get_team_shares(team_id)?;
+    let mut folders = vec![];
+    for tf in shared_folders {
+        let mut fj = ses.server.db.get_folder(tf.folder_id)?.to_json()?;
+        fj["shared_by"] = json!(tf.shared_by);
+        folders.push(fj);
+    }
+    let videos = video_list_json(ses, ses.server.db.get_team_videos(team_id)?)?;
+    ses.emit_cmd("team_videos", &json!({ "team_id": team.id, "name": team.name, "folders": folders, "videos": videos }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Admin searches the audit log and user messages (event console).
+/// Filters: `user_id`, `kind` (audit action or message event name), `video_hash`,
+/// `since`/`until` (Unix timestamps), `text` (free-text), `limit`.
+/// `sources` selects tables (default: all). If `format` is "csv", a CSV export is included.
+pub async fn msg_search_events(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::event_console;
+    let filter = match event_console::parse_filter(data) {
+        Ok(f) => f,
+        Err(e) => {
+            send_user_error!(ses, Topic::None, "Invalid event search.", e.to_string(), false);
+            return Ok(());
+        }
+    };
+    let sources = match data["sources"].as_array() {
+        Some(arr) => arr.iter().filter_map(|s| s.as_str()).collect::<Vec<_>>(),
+        None => vec![event_console::SOURCE_AUDIT, event_console::SOURCE_MESSAGES],
+    };
+    let rows = match event_console::search_events(&ses.server.db, &filter, &sources) {
+        Ok(rows) => rows,
+        Err(e) => {
+            send_user_error!(ses, Topic::None, "Event search failed.", e.to_string(), false);
+            return Ok(());
+        }
+    };
+    let mut res = json!({ "events": rows });
+    if data["format"].as_str() == Some("csv") {
+        res["csv"] = json!(event_console::to_csv(&rows));
+    }
+    ses.emit_cmd("event_search_results", &res, super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Render a time range of a video into a short movie clip or animated GIF/WebP for sharing.
+/// Rendering is queued in the pipeline. Reply has the download URL, which becomes available
+/// when user gets a "Clip ready" message (with the same URL in details).
+/// Fields: `video_hash`, `start`, `end` (seconds), `format` ("mp4", "gif" or "webp", default "gif"), `width` (optional)
+pub async fn msg_export_clip(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use crate::video_pipeline::ExportRequest;
+    use crate::video_pipeline::clip_export::{self, ClipFormat, ClipRequest};
+    let vh = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let start = data["start"].as_f64().ok_or(anyhow!("start missing"))? as f32;
+    let end = data["end"].as_f64().ok_or(anyhow!("end missing"))? as f32;
+    let width = data["width"].as_u64().map(|w| w as u32).unwrap_or(clip_export::DEFAULT_CLIP_WIDTH);
+    let format = match data["format"].as_str().unwrap_or("gif").parse::<ClipFormat>() {
+        Ok(f) => f,
+        Err(e) => {
+            send_user_error!(ses, Topic::Video(vh), "Clip export failed.", e, false);
+            return Ok(());
+        }
+    };
+    let v = match ses.server.db.get_video(vh) {
+        Ok(v) => v,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(vh), "No such video.");
+            return Ok(());
+        }
+        Err(e) => { bail!(e); }
+    };
+    if !super::download::may_download(&ses.server.db, &v, ses.user_id, ses.is_admin) {
+        send_user_error!(ses, Topic::Video(vh), "Owner doesn't allow downloading this video. Cannot export clip.");
+        return Ok(());
+    }
+    if !organizer_allows(ses, organizer_action::VIEW, vh).await? {
+        return Ok(());
+    }
+
+    let problem = if v.still_kind.is_some() { Some("Still images and documents have no time ranges.".to_string()) }
+        else if !(start >= 0.0 && end > start) { Some("Invalid time range.".into()) }
+        else if v.duration.is_some_and(|d| start >= d) { Some("Start is past the end of the video.".into()) }
+        else if end - start > format.max_seconds() { Some(format!("Clip too long. Max length for {} is {} seconds.", format.ext(), format.max_seconds())) }
+        else if !(16..=clip_export::MAX_CLIP_WIDTH).contains(&width) { Some(format!("Width must be between 16 and {}.", clip_export::MAX_CLIP_WIDTH)) }
+        else { None };
+    if let Some(p) = problem {
+        send_user_error!(ses, Topic::Video(vh), "Clip export failed.", p, false);
+        return Ok(());
+    }
+    let end = v.duration.map_or(end, |d| end.min(d));
+    let src = match crate::video_pipeline::playable_file(&v, &ses.server.videos_dir) {
+        Ok(f) => f,
+        Err(e) => {
+            send_user_error!(ses, Topic::Video(vh), "Clip export failed.", e, false);
+            return Ok(());
+        }
+    };
+
+    let fname = format!("clip_{:.0}-{:.0}_{}.{}", start, end, &uuid::Uuid::new_v4().simple().to_string()[..8], format.ext());
+    let url = format!("{}/videos/{}/clips/{}", ses.server.url_base, vh, fname);
+    let req = ExportRequest::Clip(ClipRequest {
+        video_hash: vh.into(),
+        user_id: ses.user_id.into(),
+        src,
+        dst: ses.server.videos_dir.join(vh).join("clips").join(&fname),
+        url: url.clone(),
+        start, end, format, width,
+        job_id: None,
+    });
+    match ses.server.export_tx.try_send(req) {
+        Ok(_) => {},
+        Err(TrySendError::Full(_)) => { send_user_error!(ses, Topic::Video(vh), BUSY_MSG); return Ok(()); },
+        Err(e) => bail!("Failed to submit clip export: {}", e),
+    }
+    ses.emit_cmd("clip_export_queued", &json!({ "video_hash": vh, "url": url, "start": start, "end": end, "format": format.ext() }),
+        super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Export a review package (zip) of a video for external vendors: proxy (or the original, with
+/// `original`), comment report, captions, drawings, metadata and optionally a version with comments
+/// burned in (`burn_in`).
+/// Built in the background. User gets a message with download link when it's ready.
+pub async fn msg_export_package(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use crate::video_pipeline::{ExportRequest, review_package};
+    let vh = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
+    let burn_in = data["burn_in"].as_bool().unwrap_or(false);
+    let original = data["original"].as_bool().unwrap_or(false);
+    let v = match ses.server.db.get_video(vh) {
+        Ok(v) => v,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(vh), "No such video.");
+            return Ok(());
+        }
+        Err(e) => { bail!(e); }
+    };
+    if !super::download::may_download(&ses.server.db, &v, ses.user_id, ses.is_admin) {
+        send_user_error!(ses, Topic::Video(vh), "Owner doesn't allow downloading this video. Cannot export package.");
+        return Ok(());
+    }
+    if !organizer_allows(ses, organizer_action::VIEW, vh).await? {
+        return Ok(());
+    }
+    match review_package::make_request(&ses.server.db, &ses.server.videos_dir, &ses.server.url_base, &v, ses.user_id, burn_in, original) {
+        Err(e) => { send_user_error!(ses, Topic::Video(vh), "Review package export failed.", e, false); },
+        Ok(req) => {
+            let (url, burn_in) = (req.url.clone(), req.burn_in_src.is_some());
+            match ses.server.export_tx.try_send(ExportRequest::Package(req)) {
+                Ok(_) => {},
+                Err(TrySendError::Full(_)) => { send_user_error!(ses, Topic::Video(vh), BUSY_MSG); return Ok(()); },
+                Err(e) => bail!("Failed to submit review package export: {}", e),
+            }
+            ses.emit_cmd("package_export_queued", &json!({ "video_hash": vh, "url": url, "burn_in": burn_in }),
+                super::SendTo::CurSession())?;
+        }
+    }
+    Ok(())
+}
+
+/// Max number of hits returned by transcript search
+const MAX_TRANSCRIPT_HITS: i64 = 500;
+
+/// Format seconds as "HH:MM:SS" for jump-to links
+fn format_timecode(secs: f32) -> String {
+    let s = secs.max(0.0) as u64;
+    format!("{:02}:{:02}:{:02}", s / 3600, (s / 60) % 60, s % 60)
+}
+
+/// Videos a search covers: the one given in `video_hash` (any user can open a video by its hash),
+/// otherwise all of user's own videos. None (= all videos) for admin.
+fn search_scope(data: &serde_json::Value, ses: &WsSessionArgs<'_>) -> Res<Option<Vec<String>>> {
+    Ok(match data["video_hash"].as_str() {
+        Some(vh) => Some(vec![vh.to_string()]),
+        None if ses.is_admin => None,
+        None => Some(ses.server.db.get_all_user_videos(ses.user_id)?.into_iter().map(|v| v.video_hash).collect()),
+    })
+}
+
+/// Search subtitle/transcript tracks for spoken words.
+/// Searches a single video if `video_hash` is given (any user can open a video by its hash),
+/// otherwise all of user's own videos (admin: all videos). `language` limits search to tracks in that language.
+/// Each hit carries a timecode to jump to.
+pub async fn msg_search_transcripts(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let query = data["query"].as_str().ok_or(anyhow!("query missing"))?.trim();
+    if query.is_empty() {
+        send_user_error!(ses, Topic::None, "Empty transcript search.");
+        return Ok(());
+    }
+    let lang = data["language"].as_str().filter(|l| !l.is_empty());
+    let video_hashes = search_scope(data, ses)?;
+    let cues = ses.server.db.search_transcripts(query, video_hashes.as_deref(), lang, MAX_TRANSCRIPT_HITS)?;
+
+    let mut titles = HashMap::new();
+    let mut hits = Vec::new();
+    for c in cues {
+        let title = titles.entry(c.video_hash.clone())
+            .or_insert_with(|| ses.server.db.get_video(&c.video_hash).ok().and_then(|v| v.title))
+            .clone();
+        hits.push(json!({
+            "video_hash": c.video_hash,
+            "title": title,
+            "subtitle_id": c.subtitle_id,
+            "language": c.language,
+            "start": c.start_time,
+            "end": c.end_time,
+            "timecode": format_timecode(c.start_time),
+            "text": c.text,
+        }));
+    }
+    ses.emit_cmd("transcript_search_results", &json!({ "query": query, "hits": hits }), super::SendTo::CurSession())?;
+    Ok(())
+}
 
-            // Use transcoded or orig video?
-            let (file, uri) = match v.recompression_done {
-                Some(_) => Ok(("video.mp4".into(), "video.mp4".into())),
-                None => match &v.orig_filename {
-                    Some(f) => Ok((format!("orig/{}", f), format!("orig/{}", urlencoding::encode(f)))),
-                    None => Err(anyhow!("No video file"))
-                }}?;
+/// Max number of labels returned by label search / listing
+const MAX_LABEL_HITS: i64 = 5000;
 
-            fields["video_url"] = json!(format!("{}/videos/{}/{}", ses.server.url_base, &v.video_hash, uri));
-            ses.emit_cmd("open_video", &fields, super::SendTo::CurSession() )?;
+/// Search or list ML analysis labels (faces, objects, text on screen).
+/// Optional filters: `query` (substring of label), `kind`, `min_confidence` (0..1).
+/// Scope as in transcript search (`video_hash`, or user's own videos). Each hit carries a timecode to jump to.
+pub async fn msg_search_labels(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let query = data["query"].as_str().map(str::trim).filter(|q| !q.is_empty());
+    let kind = data["kind"].as_str().filter(|k| !k.is_empty()).map(str::to_lowercase);
+    let min_confidence = data["min_confidence"].as_f64().map(|c| c as f32);
+    let video_hashes = search_scope(data, ses)?;
+    let labels = ses.server.db.search_video_labels(query, video_hashes.as_deref(), kind.as_deref(), min_confidence, MAX_LABEL_HITS)?;
 
-            for c in ses.server.db.get_video_comments(video_hash)? {
-                let cid = c.id;
-                if let Err(e) = ses.emit_new_comment(c, super::SendTo::CurSession()).await {
-                    tracing::error!("Error sending comment: {}", e);
-                    send_user_error!(ses, Topic::Comment(cid), format!("Error sending comment #{cid}: {:?}", e));
-                }
-            }
-        }
+    let mut titles = HashMap::new();
+    let mut hits = Vec::new();
+    for l in labels {
+        let title = titles.entry(l.video_hash.clone())
+            .or_insert_with(|| ses.server.db.get_video(&l.video_hash).ok().and_then(|v| v.title))
+            .clone();
+        hits.push(json!({
+            "video_hash": l.video_hash,
+            "title": title,
+            "kind": l.kind,
+            "label": l.label,
+            "start": l.start_time,
+            "end": l.end_time,
+            "timecode": format_timecode(l.start_time),
+            "confidence": l.confidence,
+        }));
     }
-    ses.cur_video_hash = Some(video_hash.into());
+    ses.emit_cmd("label_search_results", &json!({ "query": query, "kind": kind, "hits": hits }), super::SendTo::CurSession())?;
     Ok(())
 }
 
-pub async fn msg_del_video(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
-    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
-    match ses.server.db.get_video(video_hash) {
-        Ok(v) => {
-            if Some(ses.user_id.to_string()) != v.added_by_userid && ses.user_id != "admin" {
-                send_user_error!(ses, Topic::Video(video_hash), "Video not owned by you. Cannot delete.");
-            } else {
-                ses.server.db.del_video_and_comments(video_hash)?;
-                let mut details = format!("Added by {:?} ({:?}) on {}. Filename was {:?}.",
-                    v.added_by_username, v.added_by_userid, v.added_time, v.orig_filename);
-
-                fn backup_video_db_row(server: &ServerState, v: &models::Video) -> Res<()> {
-                    let backup_file = server.videos_dir.join(v.video_hash.clone()).join("db_backup.json");
-                    if backup_file.exists() {
-                        std::fs::remove_file(&backup_file)?;
-                    }
-                    let json_str = serde_json::to_string_pretty(&v)?;
-                    std::fs::write(&backup_file, json_str)?;
-                    Ok(())
-                }
+/// Admin sets processing priority of a user's jobs (higher first, 0 = default).
+/// Affects already queued jobs, too.
+pub async fn msg_set_user_priority(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let uid = data["user_id"].as_str().ok_or(anyhow!("user_id missing"))?;
+    let priority = data["priority"].as_i64().ok_or(anyhow!("priority missing"))?;
+    let priority = i32::try_from(priority).context("priority out of range")?;
 
-                fn move_video_to_trash(server: &ServerState, video_hash: &str) -> Res<()>
-                {
-                    let video_dir = server.videos_dir.join(video_hash);
-                    let trash_dir = server.videos_dir.join("trash");
-                    if !trash_dir.exists() {
-                        std::fs::create_dir(&trash_dir)?;
-                    }
-                    let hash_and_datetime = format!("{}_{}", video_hash, chrono::Utc::now().format("%Y%m%d-%H%M%S"));
-                    let video_trash_dir = trash_dir.join(hash_and_datetime);
-                    std::fs::rename(&video_dir, &video_trash_dir)?;
-                    Ok(())
-                }
+    ses.server.db.set_user_priority(uid, priority)?;
+    send_user_ok!(ses, Topic::None, "User priority set.", format!("User '{}' now has processing priority {}.", uid, priority), false);
+    Ok(())
+}
 
-                let mut cleanup_errors = false;
-                if let Err(e) = backup_video_db_row(&ses.server, &v) {
-                    details.push_str(&format!(" WARNING: DB row backup failed: {:?}.", e));
-                    cleanup_errors = true;
+/// Send admin a list of users with non-default processing priority.
+pub async fn msg_list_user_priorities(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let prios = ses.server.db.get_user_priorities()?.into_iter()
+        .map(|p| p.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("user_priorities", &json!({ "priorities": prios }), super::SendTo::CurSession())?;
+    Ok(())
+}
 
-                }
-                if let Err(e) = move_video_to_trash(&ses.server, video_hash) {
-                    details.push_str(&format!(" WARNING: Move to trash failed: {:?}.", e));
-                    cleanup_errors = true;
-                }
-                
-                send_user_ok!(ses, Topic::Video(video_hash),
-                    if !cleanup_errors {"Video deleted."} else {"Video deleted, but cleanup had errors."},
-                    details, true);
-            }
-        }
-        Err(DBError::NotFound()) => {
-            send_user_error!(ses, Topic::Video(video_hash), "No such video. Cannot delete.");
+/// Admin lists all users (known from logins or video ownership) with their storage usage.
+pub async fn msg_admin_list_users(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let mut users = ses.server.db.get_users()?.into_iter()
+        .map(|u| u.to_json()).collect::<Result<Vec<_>, _>>()?;
+    for owner in ses.server.db.get_video_owners()? {
+        if !users.iter().any(|u| u["user_id"] == owner.as_str()) {
+            users.push(json!({ "user_id": owner, "username": owner, "is_admin": false, "disabled": false }));
         }
-        Err(e) => { bail!(e); }
     }
+    for u in users.iter_mut() {
+        let uid = u["user_id"].as_str().unwrap_or_default().to_string();
+        u["usage"] = serde_json::to_value(crate::quota::get_user_usage(&ses.server.db, &ses.server.videos_dir, &uid)?)?;
+    }
+    ses.emit_cmd("admin_users", &json!({ "users": users }), super::SendTo::CurSession())?;
     Ok(())
 }
 
-pub async fn msg_rename_video(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
-    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
-    let new_name = data["new_name"].as_str().ok_or(anyhow!("new_name missing"))?;
+/// Admin lists everyone's videos, or those of `user_id` if given. Includes trashed videos.
+/// (Deleting them works with `del_video`.)
+pub async fn msg_admin_list_videos(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let owner = data["user_id"].as_str();
+    let videos = ses.server.db.get_all_videos(owner)?.into_iter()
+        .map(|v| v.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("admin_videos", &json!({ "user_id": owner, "videos": videos }), super::SendTo::CurSession())?;
+    Ok(())
+}
 
-    match ses.server.db.get_video(video_hash) {
-        Ok(v) => {
-            if Some(ses.user_id.to_string()) != v.added_by_userid && ses.user_id != "admin" {
-                send_user_error!(ses, Topic::Video(video_hash), "Video not owned by you. Cannot rename.");
-            } else {
-                let new_name = new_name.trim();
-                if new_name.is_empty() || !new_name.chars().any(|c| c.is_alphanumeric()) {
-                    send_user_error!(ses, Topic::Video(video_hash), "Invalid video name (must have letters/numbers)");
-                    return Ok(());
-                }
-                if new_name.len() > 160 {
-                    send_user_error!(ses, Topic::Video(video_hash), "Video name too long (max 160)");
-                    return Ok(());
-                }
-                ses.server.db.rename_video(video_hash, new_name)?;
-                send_user_ok!(ses, Topic::Video(video_hash), "Video renamed.", 
-                    format!("New name: '{}'", new_name), true);
-            }
-        }
-        Err(DBError::NotFound()) => {
-            send_user_error!(ses, Topic::Video(video_hash), "No such video. Cannot rename.");
-        }
-        Err(e) => { bail!(e); }
+/// Admin disables/enables a user or grants/revokes admin rights (`disabled` and `is_admin`, both optional).
+/// Disabling closes the user's open sessions.
+pub async fn msg_admin_set_user(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let uid = data["user_id"].as_str().ok_or(anyhow!("user_id missing"))?;
+    let disable = data["disabled"].as_bool();
+    let admin = data["is_admin"].as_bool();
+    if uid == ses.user_id && (disable == Some(true) || admin == Some(false)) {
+        send_user_error!(ses, Topic::None, "Can't disable yourself or revoke your own admin rights.");
+        return Ok(());
     }
-    Ok(())
+    ses.server.db.set_user_flags(uid, admin, disable)?;
+    if disable == Some(true) {
+        ses.server.send_to_all_user_sessions(uid, &WsMsg::close());
+    }
+    let details = format!("User '{}': disabled={:?}, is_admin={:?}.", uid, disable, admin);
+    audit(ses, models::audit_action::USER_UPDATED, None, details.clone())?;
+    send_user_ok!(ses, Topic::None, "User updated.", details, false);
+    msg_admin_list_users(data, ses).await
 }
 
-pub async fn msg_add_comment(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+/// Admin gives a video to another user. It goes to the new owner's root folder.
+pub async fn msg_admin_reassign_video(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
     let vh = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
-
-    if let Err(DBError::NotFound())  = ses.server.db.get_video(&vh) {
-        send_user_error!(ses, Topic::Video(&vh), "No such video. Cannot comment.");
+    let uid = data["user_id"].as_str().ok_or(anyhow!("user_id missing"))?.trim();
+    if uid.is_empty() {
+        send_user_error!(ses, Topic::Video(vh), "New owner missing.");
         return Ok(());
     }
-
-    // Parse drawing data if present and write to file
-    let mut drwn = data["drawing"].as_str().map(|s| s.to_string());
-    if let Some(d) = drwn.clone() {
-        if d.starts_with("data:") {
-
-            // Convert data URI to bytes
-            let img_uri = DataUrl::process(&d).map_err(|e| anyhow!("Invalid drawing data URI"))?;
-            
-            if img_uri.mime_type().type_ != "image" || img_uri.mime_type().subtype != "webp" {
-                bail!("Invalid mimetype in drawing: {:?}", img_uri.mime_type())
-            }
-            let img_data = img_uri.decode_to_vec().map_err(|e| anyhow!("Failed to decode drawing data URI: {:?}", e))?;
-
-            // Make up a filename
-            fn sha256hex( data: &[u8] ) -> String {
-                let mut hasher = Sha256::new();
-                hasher.update(data);
-                let result = hasher.finalize();
-                hex::encode(result)
-            }
-            let short_csum = sha256hex(img_data.0.as_ref())[..16].to_string();
-            let fname = format!("{}.webp", short_csum);
-
-            // Write to file
-            let drawing_path = ses.server.videos_dir.join(&vh).join("drawings").join(&fname);
-            std::fs::create_dir_all(drawing_path.parent().unwrap())
-                .map_err(|e| anyhow!("Failed to create drawings dir: {:?}", e))?;
-            async_std::fs::write(drawing_path, img_data.0).await.map_err(
-                |e| anyhow!("Failed to write drawing file: {:?}", e))?;
-            
-            // Replace data URI with filename
-            drwn = Some(fname);
-        }
+    let old_owner = match ses.server.db.get_video(vh) {
+        Ok(v) => v.added_by_userid,
+        Err(DBError::NotFound()) => {
+            send_user_error!(ses, Topic::Video(vh), "No such video.");
+            return Ok(());
+        },
+        Err(e) => { bail!(e); }
     };
-
-    let c = models::CommentInsert {
-        video_hash: vh.to_string(),
-        parent_id: data["parent_id"].as_i64().map(|x| x as i32),
-        user_id: ses.user_id.into(),
-        username: ses.user_name.into(),
-        comment: data["comment"].as_str().ok_or(anyhow!("comment missing"))?.to_string(),
-        timecode: data["timecode"].as_str().map(String::from),
-        drawing: drwn,
+    let uname = match ses.server.db.get_user(uid) {
+        Ok(u) => u.username,
+        Err(DBError::NotFound()) => uid.to_string(),
+        Err(e) => { bail!(e); }
     };
-    let new_id = ses.server.db.add_comment(&c)
-        .map_err(|e| anyhow!("Failed to add comment: {:?}", e))?;
-    let c = ses.server.db.get_comment(new_id)?;
-
-    // Send to all clients watching this video
-    ses.emit_new_comment(c, super::SendTo::VideoHash(&vh)).await?;
+    ses.server.db.set_video_owner(vh, uid, &uname)?;
+    audit(ses, models::audit_action::REASSIGN_VIDEO, Some(vh), format!("From {:?} to '{}'.", old_owner, uid))?;
+    send_user_ok!(ses, Topic::Video(vh), "Video reassigned.", format!("Video is now owned by '{}'.", uid), false);
     Ok(())
 }
 
-pub async fn msg_edit_comment(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
-    let comment_id = data["comment_id"].as_i64().ok_or(anyhow!("comment_id missing"))? as i32;
-    let new_text = data["comment"].as_str().ok_or(anyhow!("comment missing"))?.to_string();
+/// Admin lists share link guests, with the video and number of comments of each.
+pub async fn msg_admin_list_guests(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let mut guests = vec![];
+    for g in ses.server.db.get_guests(None)? {
+        let mut gj = g.to_json()?;
+        gj["user_id"] = json!(g.user_id());
+        gj["video_hash"] = json!(ses.server.db.get_share_link(g.share_link_id).ok().map(|l| l.video_hash));
+        gj["n_comments"] = json!(ses.server.db.count_user_comments(&g.user_id())?);
+        guests.push(gj);
+    }
+    ses.emit_cmd("admin_guests", &json!({ "guests": guests }), super::SendTo::CurSession())?;
+    Ok(())
+}
 
-    match ses.server.db.get_comment(comment_id) {
-        Ok(old) => {
-            let vh = old.video_hash;
-            if ses.user_id != old.user_id && ses.user_id != "admin" {
-                send_user_error!(ses, Topic::Video(&vh), "Failed to edit comment.", "You can only edit your own comments", true);
-                return Ok(());
-            }
-            ses.server.db.edit_comment(comment_id, &new_text)?;
-            ses.emit_cmd("del_comment", &json!({ "comment_id": comment_id }), super::SendTo::VideoHash(&vh))?;
-            let c = ses.server.db.get_comment(comment_id)?;
-            ses.emit_new_comment(c, super::SendTo::VideoHash(&vh)).await?;
-        }
+/// Admin promotes a share link guest to a real account: guest's comments are moved to `user_id`.
+/// The guest identity can't be resumed after this.
+pub async fn msg_admin_promote_guest(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let gid = data["guest_id"].as_i64().ok_or(anyhow!("guest_id missing"))? as i32;
+    let uid = data["user_id"].as_str().ok_or(anyhow!("user_id missing"))?.trim();
+    if uid.is_empty() || uid.starts_with(models::GUEST_USER_PREFIX) {
+        send_user_error!(ses, Topic::None, "Invalid user ID.");
+        return Ok(());
+    }
+    let guest = match ses.server.db.get_guest(gid) {
+        Ok(g) => g,
         Err(DBError::NotFound()) => {
-            send_user_error!(ses, Topic::None, "Failed to edit comment.", "No such comment. Cannot edit.", true);
-        }
+            send_user_error!(ses, Topic::None, "No such guest.");
+            return Ok(());
+        },
         Err(e) => { bail!(e); }
+    };
+    let uname = match ses.server.db.get_user(uid) {
+        Ok(u) => u.username,
+        Err(DBError::NotFound()) => uid.to_string(),
+        Err(e) => { bail!(e); }
+    };
+    let n = ses.server.db.promote_guest(gid, uid, &uname)?;
+    ses.server.send_to_all_user_sessions(&guest.user_id(), &WsMsg::close());
+    audit(ses, models::audit_action::GUEST_PROMOTED, None, format!("Guest #{} ('{}') to '{}', {} comments.", gid, guest.name, uid, n))?;
+    send_user_ok!(ses, Topic::None, "Guest promoted.", format!("Moved {} comments to '{}'.", n, uid), false);
+    Ok(())
+}
+
+/// Admin views processing queue: unfinished jobs, their counts by stage and status, and pipeline queue depths.
+pub async fn msg_admin_queue_status(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let jobs = ses.server.db.get_unfinished_jobs()?;
+    let mut counts: HashMap<String, HashMap<String, usize>> = HashMap::new();
+    for j in &jobs {
+        *counts.entry(j.stage.clone()).or_default().entry(j.status.clone()).or_default() += 1;
     }
+    let jobs = jobs.into_iter().map(|j| j.to_json()).collect::<Result<Vec<_>, _>>()?;
+    let queues = ses.server.queues.to_json(ses.server.upload_tx.len());
+    ses.emit_cmd("admin_queue_status", &json!({ "jobs": jobs, "counts": counts, "queues": queues, "storage": ses.server.storage.to_json(),
+        "sweeper": ses.server.sweep_stats.to_json() }), super::SendTo::CurSession())?;
     Ok(())
 }
 
-pub async fn msg_del_comment(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
-    let comment_id = data["comment_id"].as_i64().ok_or(anyhow!("comment_id missing"))? as i32;
+/// Admin views daily statistics of finished jobs (kept after jobs themselves expire), for the last `days` days (default 30).
+pub async fn msg_admin_job_stats(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let days = data["days"].as_i64().unwrap_or(30).max(1);
+    let since = chrono::Utc::now().date_naive() - chrono::Duration::days(days - 1);
+    let stats = ses.server.db.get_job_stats(Some(since))?.into_iter()
+        .map(|s| s.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("admin_job_stats", &json!({ "days": days, "stats": stats }), super::SendTo::CurSession())?;
+    Ok(())
+}
 
-    match ses.server.db.get_comment(comment_id) {
-        Ok(cmt) => {
-            let vh = cmt.video_hash;
-            if ses.user_id != cmt.user_id && ses.user_id != "admin" {
-                send_user_error!(ses, Topic::Video(&vh), "Failed to delete comment.", "You can only delete your own comments", true);
-                return Ok(());
-            }
-            let all_comm = ses.server.db.get_video_comments(&vh)?;
-            if all_comm.iter().any(|c| c.parent_id == Some(comment_id)) {
-                send_user_error!(ses, Topic::Video(&vh), "Failed to delete comment.", "Comment has replies. Cannot delete.", true);
-                return Ok(());
-            }
-            ses.server.db.del_comment(comment_id)?;
-            ses.emit_cmd("del_comment", &json!({ "comment_id": comment_id }), super::SendTo::VideoHash(&vh))?;
-        }
+/// Admin views a job and its transcode attempts (settings tried, outcome and end of ffmpeg's stderr).
+pub async fn msg_admin_job_history(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let job_id = data["job_id"].as_i64().ok_or(anyhow!("job_id missing"))? as i32;
+    let job = match ses.server.db.get_job(job_id) {
+        Ok(j) => j,
         Err(DBError::NotFound()) => {
-            send_user_error!(ses, Topic::None, "Failed to delete comment.", "No such comment. Cannot delete.", true);
-        }
-        Err(e) => { bail!(e); }
+            send_user_error!(ses, Topic::None, "No such job.");
+            return Ok(());
+        },
+        Err(e) => bail!(e),
+    };
+    let attempts = ses.server.db.get_job_attempts(job_id)?.into_iter()
+        .map(|a| a.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("admin_job_history", &json!({ "job": job.to_json()?, "attempts": attempts }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Admin views pipeline throughput for the last `days` days (default 28) and a projection of when
+/// the current workers stop keeping up (see `capacity`). Optional `stage` (default transcode)
+/// and `target_utilization` (0-1, default 0.7).
+pub async fn msg_admin_capacity_report(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::capacity;
+    let days = data["days"].as_i64().unwrap_or(28).max(2);
+    let stage = data["stage"].as_str().unwrap_or(models::job_stage::TRANSCODE);
+    let target = data["target_utilization"].as_f64().unwrap_or(capacity::DEFAULT_TARGET_UTILIZATION);
+    if !(target > 0.0 && target <= 1.0) {
+        send_user_error!(ses, Topic::None, "Target utilization must be between 0 and 1.");
+        return Ok(());
     }
+    let today = chrono::Utc::now().date_naive();
+    let since = (today - chrono::Duration::days(days - 1)).and_hms_opt(0, 0, 0).unwrap_or_default();
+    let stats = ses.server.db.get_throughput_stats(stage, Some(since))?;
+    let mut report = capacity::capacity_report(&stats, ses.server.config.n_workers.load(std::sync::atomic::Ordering::Relaxed), target, today);
+    report["days"] = days.into();
+    report["stage"] = stage.into();
+    ses.emit_cmd("admin_capacity_report", &report, super::SendTo::CurSession())?;
     Ok(())
 }
 
-pub async fn msg_list_my_messages(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
-    let msgs = ses.server.db.get_user_messages(&ses.user_id)?;
-    for m in msgs {
-        ses.emit_cmd("message", &m.to_json()?, super::SendTo::CurSession())?;
-        if !m.seen {
-            ses.server.db.set_message_seen(m.id, true)?;
+/// Admin re-reads server config file and applies changed settings (log level, workers, quotas, webhook, features, rate limits)
+/// without a restart, like SIGHUP. Replies with names of the `changed` settings.
+pub async fn msg_admin_reload_config(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    match ses.server.config.reload() {
+        Ok(changed) => {
+            tracing::info!(changed=?changed, "Config reloaded by admin.");
+            ses.emit_cmd("admin_config_reloaded", &json!({ "changed": changed, "config": config_summary(&ses.server.config.get()) }), super::SendTo::CurSession())?;
+        },
+        Err(e) => {
+            send_user_error!(ses, Topic::None, format!("Config reload failed: {e}"));
         }
     }
     Ok(())
 }
 
-pub async fn msg_join_collab(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
-    let collab_id = data["collab_id"].as_str().ok_or(anyhow!("collab_id missing"))?;
-    let video_hash = data["video_hash"].as_str().ok_or(anyhow!("video_hash missing"))?;
-
-    if let Some(collab_id) = ses.cur_collab_id.clone() {
-        if ses.server.sender_is_collab_participant(collab_id.as_str(), &ses.sender) {
-            tracing::debug!("{} is already in collab {}. Ignoring double join.", ses.user_name, collab_id);
-            return Ok(());
-        }
+/// Admin cross-checks stored files against DB (see `reconcile`): orphaned files, dangling DB rows
+/// and videos with missing files. With `remove`, orphaned files and dangling rows are removed.
+pub async fn msg_admin_reconcile(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let remove = data["remove"].as_bool().unwrap_or(false);
+    let server = ses.server.clone();
+    let report = tokio::task::spawn_blocking(move || super::reconcile::reconcile(&server, remove)).await??;
+    if remove && !report.is_clean() {
+        audit(ses, models::audit_action::RECONCILE, None, report.summary())?;
     }
+    ses.emit_cmd("admin_reconcile_report", &serde_json::to_value(&report)?, super::SendTo::CurSession())?;
+    Ok(())
+}
 
-    ses.collab_session_guard = None;
-    ses.cur_collab_id = None;
+/// Admin makes a backup bundle of the database (see `backup`) under `<data dir>/backups/`.
+/// Media files are listed in the bundle's manifest, but not copied.
+pub async fn msg_admin_backup(_data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let data_dir = ses.server.videos_dir.parent().unwrap_or(&ses.server.videos_dir).to_path_buf();
+    let rel = std::path::Path::new("backups").join(chrono::Local::now().format("%Y%m%d-%H%M%S").to_string());
+    let (db, dst) = (ses.server.db.clone(), data_dir.join(&rel));
+    let manifest = tokio::task::spawn_blocking(move || crate::backup::create_backup(&db, &data_dir, &dst)).await??;
+    audit(ses, models::audit_action::BACKUP, None, format!("{}: {}", rel.display(), manifest.summary()))?;
+    ses.emit_cmd("admin_backup", &json!({ "dir": rel.to_string_lossy(), "videos": manifest.videos,
+        "files": manifest.files.len(), "bytes": manifest.total_bytes(), "db_sha256": manifest.db_sha256 }), super::SendTo::CurSession())?;
+    Ok(())
+}
 
-    match ses.server.db.get_video(video_hash) {
-        Err(DBError::NotFound()) => {
-            send_user_error!(ses, Topic::Video(video_hash), "No such video.");
-        }
+/// Reloadable settings for admin's view. Webhook URL is left out, it may contain a secret.
+fn config_summary(cfg: &crate::config::ReloadableConfig) -> serde_json::Value {
+    json!({ "debug": cfg.debug, "workers": cfg.n_workers, "quotas": cfg.quotas, "webhook": cfg.webhook.is_some(), "features": cfg.features.to_json(), "rate_limits": cfg.rate_limits.to_json(), "sessions": cfg.sessions.to_json() })
+}
+
+/// Max length of a maintenance window's public message
+const MAX_MAINTENANCE_MSG_LEN: usize = 500;
+
+/// Send admin current and upcoming maintenance windows.
+pub async fn msg_admin_list_maintenance_windows(_data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let windows = ses.server.db.get_maintenance_windows(chrono::Utc::now().naive_utc())?.iter()
+        .map(|w| w.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("admin_maintenance_windows", &json!({ "windows": windows }), super::SendTo::CurSession())?;
+    Ok(())
+}
+
+/// Admin schedules a maintenance window, shown on the public status page (see `status_page`).
+/// `starts` and `ends` are unix timestamps.
+pub async fn msg_admin_add_maintenance_window(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let to_time = |k: &str| data[k].as_i64().and_then(|t| chrono::NaiveDateTime::from_timestamp_opt(t, 0));
+    let (starts, ends) = match (to_time("starts"), to_time("ends")) {
+        (Some(s), Some(e)) if e > s => (s, e),
+        _ => { send_user_error!(ses, Topic::None, "Maintenance window needs valid 'starts' and 'ends' times, ending after it starts."); return Ok(()); }
+    };
+    let message = data["message"].as_str().unwrap_or_default().trim();
+    if message.chars().count() > MAX_MAINTENANCE_MSG_LEN {
+        send_user_error!(ses, Topic::None, format!("Message too long (max {} characters).", MAX_MAINTENANCE_MSG_LEN));
+        return Ok(());
+    }
+    let mw = ses.server.db.add_maintenance_window(&models::MaintenanceWindowInsert {
+        starts, ends, message: message.into(), created_by: ses.user_id.into() })?;
+    tracing::info!(id=mw.id, starts=%mw.starts, ends=%mw.ends, "Maintenance window added.");
+    msg_admin_list_maintenance_windows(data, ses).await
+}
+
+/// Admin deletes a maintenance window.
+pub async fn msg_admin_del_maintenance_window(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    let id = data["id"].as_i64().ok_or(anyhow!("id missing"))?;
+    match ses.server.db.del_maintenance_window(id as i32) {
+        Ok(()) => msg_admin_list_maintenance_windows(data, ses).await?,
+        Err(DBError::NotFound()) => { send_user_error!(ses, Topic::None, "No such maintenance window."); },
         Err(e) => { bail!(e); }
-        Ok(v) => {
-            match ses.server.link_session_to_collab(collab_id, video_hash, ses.sender.clone()) {
-                Ok(csg) => {
-                    ses.collab_session_guard = Some(csg);
-                    ses.cur_collab_id = Some(collab_id.to_string());
-                    ses.emit_cmd("message", &json!({"event_name": "ok", "message": format!("'{}' joined collab", ses.user_name)}), super::SendTo::CurCollab())?;
-                }
-                Err(e) => {
-                    send_user_error!(ses, Topic::Video(video_hash), format!("Failed to join collab session: {}", e));
-                }
-            }
-        }
     }
     Ok(())
 }
 
-pub async fn msg_leave_collab(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
-    if ses.cur_collab_id.is_some() {
-        ses.emit_cmd("message", &json!({"event_name": "ok", "message": format!("'{}' left collab", ses.user_name)}), super::SendTo::CurCollab())?;
-        ses.collab_session_guard = None;
-        ses.cur_collab_id = None;
-    }
+/// Send admin the features, their rollout by config and the commands they gate, and admin's overrides.
+pub async fn msg_admin_list_features(_data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::feature_flags::FEATURES;
+    let flags = ses.server.config.features();
+    let features = FEATURES.iter().map(|(name, cmds)| json!({ "name": name, "config": flags.rollout(name).to_string(), "commands": cmds }))
+        .collect::<Vec<_>>();
+    let overrides = ses.server.db.get_feature_overrides(None)?.iter()
+        .map(|o| o.to_json()).collect::<Result<Vec<_>, _>>()?;
+    ses.emit_cmd("admin_features", &json!({ "features": features, "overrides": overrides }), super::SendTo::CurSession())?;
     Ok(())
 }
 
-pub async fn msg_collab_report(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
-    if ses.cur_collab_id.is_none() {
-        send_user_error!(ses, Topic::None, "Report rejected: no active collab session.");
+/// Admin overrides a feature's config for `target`: "*" (everyone), "user:<user id>" or "team:<team id>".
+/// `enabled` null removes the override. Users get the change on their next login.
+pub async fn msg_admin_set_feature(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    use super::feature_flags;
+    let feature = data["feature"].as_str().ok_or(anyhow!("feature missing"))?;
+    let target = data["target"].as_str().unwrap_or(feature_flags::TARGET_ALL).trim();
+    if !feature_flags::feature_names().contains(&feature) {
+        send_user_error!(ses, Topic::None, format!("Unknown feature '{}'.", feature));
         return Ok(());
     }
-    let paused = data["paused"].as_bool().ok_or(anyhow!("paused missing"))?;
-    let seek_time = data["seek_time"].as_f64().ok_or(anyhow!("seek_time missing"))?;
-    let img_url = data["drawing"].as_str();
-    let msg = if img_url.is_some() {
-        json!({ "paused": paused, "seek_time": seek_time, "drawing": img_url, "from_user": &ses.user_name })
-    } else {
-        json!({ "paused": paused, "seek_time": seek_time, "from_user": &ses.user_name })
-    };
-    ses.emit_cmd("collab_cmd", &msg, super::SendTo::CurCollab()).map(|_| ())
+    if let Err(msg) = feature_flags::check_target(&ses.server, target)? {
+        send_user_error!(ses, Topic::None, msg);
+        return Ok(());
+    }
+    match data["enabled"].as_bool() {
+        Some(enabled) => ses.server.db.set_feature_override(&models::FeatureOverrideInsert {
+            feature: feature.into(), target: target.into(), enabled, set_by: ses.user_id.into() })?,
+        None => match ses.server.db.del_feature_override(feature, target) {
+            Ok(()) | Err(DBError::NotFound()) => {},
+            Err(e) => { bail!(e); }
+        }
+    }
+    tracing::info!(feature, target, enabled=?data["enabled"].as_bool(), "Feature override set.");
+    msg_admin_list_features(data, ses).await
 }
 
+/// Send admin the queue of comments waiting to be pushed to ShotGrid / ftrack (see `tracker_sync`).
+/// With `"retry": true`, failed pushes are retried right away.
+pub async fn msg_admin_tracker_queue(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    if data["retry"].as_bool().unwrap_or(false) {
+        let n = ses.server.db.retry_tracker_pushes_now()?;
+        tracing::info!(n_retried=n, "Tracker pushes scheduled for retry.");
+    }
+    let items = ses.server.db.get_tracker_pushes(false)?.iter()
+        .map(|i| i.to_json()).collect::<Result<Vec<_>, _>>()?;
+    let tracker = ses.server.config.tracker().map(|t| t.kind.as_str());
+    ses.emit_cmd("admin_tracker_queue", &json!({ "tracker": tracker, "items": items }), super::SendTo::CurSession())?;
+    Ok(())
+}
 
 pub async fn msg_logout(data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
     tracing::info!("logout: user={}", ses.user_id);
-    drop(ses.sender);
     Ok(())
 }
 
 
+/// Commands that only admins may use. Checked here, so the handlers don't.
+const ADMIN_COMMANDS: &[&str] = &["admin_list_users", "admin_list_videos", "admin_set_user", "admin_reassign_video", "admin_queue_status", "admin_job_stats", "admin_job_history", "admin_capacity_report",
+    "admin_create_team", "admin_del_team", "admin_list_guests", "admin_promote_guest", "admin_set_custom_field", "admin_del_custom_field", "admin_reload_config", "admin_reconcile", "admin_backup",
+    "admin_list_maintenance_windows", "admin_add_maintenance_window", "admin_del_maintenance_window", "admin_list_features", "admin_set_feature",
+    "admin_tracker_queue", "set_legal_hold", "set_overlay_preset", "del_overlay_preset", "list_federation_peers", "set_federation_peer", "del_federation_peer",
+    "set_folder_sync", "del_folder_sync", "search_events", "set_user_priority", "list_user_priorities"];
+
+/// Audit attempts to use admin commands that need to be on record
+fn audit_refused_admin_command(cmd: &str, data: &serde_json::Value, ses: &WsSessionArgs<'_>) -> Res<()> {
+    if cmd == "set_legal_hold" {
+        let target = data["folder_id"].as_i64().map(|f| format!(" on folder {}", f)).unwrap_or_default();
+        audit(ses, models::audit_action::LEGAL_HOLD_DENIED, data["video_hash"].as_str(), format!("Not admin. Requested hold={}{}.", data["hold"], target))?;
+    }
+    Ok(())
+}
+
+/// Check that a guest session may run a command: share link is still valid, command is allowed
+/// for guests and it's about the shared video. Closes the session if the link is gone.
+///
+/// # Returns
+/// * `true` - Go ahead
+/// * `false` - Refused, user was told why
+fn check_guest_command(cmd: &str, data: &serde_json::Value, ses: &mut WsSessionArgs<'_>, link: &models::ShareLink) -> Res<bool> {
+    use super::share_links;
+    if let Err(super::file_server::Denied(_, msg)) = share_links::get_valid_link(&ses.server, &link.token) {
+        send_user_error!(ses, Topic::None, msg);
+        ses.sender.send(WsMsg::close())?;
+        return Ok(false);
+    }
+    let refusal = if !share_links::GUEST_COMMANDS.contains(&cmd) {
+        Some(format!("Guests can't use '{}'.", cmd))
+    } else if cmd != "logout" && data["video_hash"].as_str() != Some(&link.video_hash) {
+        Some("Share link is for another video.".into())
+    } else if cmd == "add_comment" && !link.allow_comments {
+        Some("Share link doesn't allow commenting.".into())
+    } else {
+        None
+    };
+    match refusal {
+        Some(msg) => {
+            tracing::info!(user=%ses.user_id, cmd=%cmd, "Guest command refused.");
+            send_user_error!(ses, Topic::None, msg);
+            Ok(false)
+        },
+        None => Ok(true),
+    }
+}
+
 pub async fn msg_dispatch(cmd: &str, data: &serde_json::Value, ses: &mut WsSessionArgs<'_>) -> Res<()> {
+    if let Some(link) = ses.guest.clone() {
+        if !check_guest_command(cmd, data, ses, &link)? {
+            return Ok(());
+        }
+    }
+    if ADMIN_COMMANDS.contains(&cmd) && !ses.is_admin {
+        tracing::warn!(user=%ses.user_id, cmd=%cmd, "Non-admin tried an admin command.");
+        audit_refused_admin_command(cmd, data, ses)?;
+        send_user_error!(ses, Topic::None, format!("Only admin can use '{}'.", cmd));
+        return Ok(());
+    }
+    if let Some(feature) = super::feature_flags::feature_for_command(cmd) {
+        if !super::feature_flags::is_enabled(&ses.server, feature, ses.user_id)? {
+            tracing::info!(user=%ses.user_id, cmd=%cmd, feature, "Command of a disabled feature refused.");
+            send_user_error!(ses, Topic::None, format!("Feature '{}' is not enabled for you.", feature));
+            return Ok(());
+        }
+    }
     let res = match cmd {
         "list_my_videos" => msg_list_my_videos(data, ses).await,
+        "get_my_usage" => msg_get_my_usage(data, ses).await,
+        "get_push_settings" => msg_get_push_settings(data, ses).await,
+        "set_push_settings" => msg_set_push_settings(data, ses).await,
+        "push_subscribe" => msg_push_subscribe(data, ses).await,
+        "push_unsubscribe" => msg_push_unsubscribe(data, ses).await,
+        "get_user_profile" => msg_get_user_profile(data, ses).await,
+        "set_user_profile" => msg_set_user_profile(data, ses).await,
+        "get_prefs" => msg_get_prefs(data, ses).await,
+        "set_prefs" => msg_set_prefs(data, ses).await,
+        "unlink_video" => msg_unlink_video(data, ses).await,
+        "list_pending_uploads" => msg_list_pending_uploads(data, ses).await,
+        "resolve_pending_upload" => msg_resolve_pending_upload(data, ses).await,
+        "create_upload_session" => msg_create_upload_session(data, ses).await,
+        "list_upload_sessions" => msg_list_upload_sessions(data, ses).await,
+        "pause_upload_session" => msg_pause_upload_session(data, ses).await,
+        "resume_upload_session" => msg_resume_upload_session(data, ses).await,
+        "cancel_upload_session" => msg_cancel_upload_session(data, ses).await,
+        "ingest_url" => msg_ingest_url(data, ses).await,
+        "stitch_videos" => msg_stitch_videos(data, ses).await,
+        "conform_video" => msg_conform_video(data, ses).await,
+        "create_upload_batch" => msg_create_upload_batch(data, ses).await,
+        "get_upload_batch" => msg_get_upload_batch(data, ses).await,
+        "cancel_upload_batch" => msg_cancel_upload_batch(data, ses).await,
         "open_video" => msg_open_video(data, ses).await,
         "del_video" => msg_del_video(data, ses).await,
+        "restore_video" => msg_restore_video(data, ses).await,
+        "list_trash" => msg_list_trash(data, ses).await,
+        "add_subtitle" => msg_add_subtitle(data, ses).await,
+        "del_subtitle" => msg_del_subtitle(data, ses).await,
         "rename_video" => msg_rename_video(data, ses).await,
+        "diff_videos" => msg_diff_videos(data, ses).await,
         "add_comment" => msg_add_comment(data, ses).await,
         "edit_comment" => msg_edit_comment(data, ses).await,
         "del_comment" => msg_del_comment(data, ses).await,
+        "react_comment" => msg_react_comment(data, ses).await,
+        "get_comment_history" => msg_get_comment_history(data, ses).await,
+        "list_comments" => msg_list_comments(data, ses).await,
+        "mark_seen" => msg_mark_seen(data, ses).await,
+        "attach_comment_file" => msg_attach_comment_file(data, ses).await,
+        "del_comment_attachment" => msg_del_comment_attachment(data, ses).await,
+        "set_comment_visibility" => msg_set_comment_visibility(data, ses).await,
+        "add_note" => msg_add_note(data, ses).await,
+        "edit_note" => msg_edit_note(data, ses).await,
+        "del_note" => msg_del_note(data, ses).await,
+        "publish_note" => msg_publish_note(data, ses).await,
         "list_my_messages" => msg_list_my_messages(data, ses).await,
         "join_collab" => msg_join_collab(data, ses).await,
         "leave_collab" => msg_leave_collab(data, ses).await,
+        "resume" => msg_resume(data, ses).await,
         "collab_report" => msg_collab_report(data, ses).await,
+        "schedule_review" => msg_schedule_review(data, ses).await,
+        "list_reviews" => msg_list_reviews(data, ses).await,
+        "cancel_review" => msg_cancel_review(data, ses).await,
+        "set_legal_hold" => msg_set_legal_hold(data, ses).await,
+        "set_allow_download" => msg_set_allow_download(data, ses).await,
+        "set_review_closed" => msg_set_review_closed(data, ses).await,
+        "add_video_tag" => msg_add_video_tag(data, ses).await,
+        "del_video_tag" => msg_del_video_tag(data, ses).await,
+        "autocomplete_tags" => msg_autocomplete_tags(data, ses).await,
+        "search_users" => msg_search_users(data, ses).await,
+        "list_custom_fields" => msg_list_custom_fields(data, ses).await,
+        "set_video_custom_fields" => msg_set_video_custom_fields(data, ses).await,
+        "set_review_verdict" => msg_set_review_verdict(data, ses).await,
+        "withdraw_review_verdict" => msg_withdraw_review_verdict(data, ses).await,
+        "list_review_verdicts" => msg_list_review_verdicts(data, ses).await,
+        "create_share_link" => msg_create_share_link(data, ses).await,
+        "list_share_links" => msg_list_share_links(data, ses).await,
+        "revoke_share_link" => msg_revoke_share_link(data, ses).await,
+        "list_folders" => msg_list_folders(data, ses).await,
+        "create_folder" => msg_create_folder(data, ses).await,
+        "del_folder" => msg_del_folder(data, ses).await,
+        "move_to_folder" => msg_move_to_folder(data, ses).await,
+        "batch_videos" => msg_batch_videos(data, ses).await,
+        "set_folder_overlays" => msg_set_folder_overlays(data, ses).await,
+        "list_overlay_presets" => msg_list_overlay_presets(data, ses).await,
+        "set_folder_transcode_preset" => msg_set_folder_transcode_preset(data, ses).await,
+        "list_transcode_presets" => msg_list_transcode_presets(data, ses).await,
+        "set_overlay_preset" => msg_set_overlay_preset(data, ses).await,
+        "del_overlay_preset" => msg_del_overlay_preset(data, ses).await,
+        "list_federation_peers" => msg_list_federation_peers(data, ses).await,
+        "set_federation_peer" => msg_set_federation_peer(data, ses).await,
+        "del_federation_peer" => msg_del_federation_peer(data, ses).await,
+        "set_folder_sync" => msg_set_folder_sync(data, ses).await,
+        "del_folder_sync" => msg_del_folder_sync(data, ses).await,
+        "list_teams" => msg_list_teams(data, ses).await,
+        "set_team_member" => msg_set_team_member(data, ses).await,
+        "del_team_member" => msg_del_team_member(data, ses).await,
+        "share_with_team" => msg_share_with_team(data, ses).await,
+        "unshare_from_team" => msg_unshare_from_team(data, ses).await,
+        "list_team_videos" => msg_list_team_videos(data, ses).await,
+        "search_events" => msg_search_events(data, ses).await,
+        "search_transcripts" => msg_search_transcripts(data, ses).await,
+        "export_clip" => msg_export_clip(data, ses).await,
+        "export_package" => msg_export_package(data, ses).await,
+        "search_labels" => msg_search_labels(data, ses).await,
+        "set_user_priority" => msg_set_user_priority(data, ses).await,
+        "list_user_priorities" => msg_list_user_priorities(data, ses).await,
+        "admin_list_users" => msg_admin_list_users(data, ses).await,
+        "admin_list_videos" => msg_admin_list_videos(data, ses).await,
+        "admin_set_user" => msg_admin_set_user(data, ses).await,
+        "admin_reassign_video" => msg_admin_reassign_video(data, ses).await,
+        "admin_queue_status" => msg_admin_queue_status(data, ses).await,
+        "admin_job_stats" => msg_admin_job_stats(data, ses).await,
+        "admin_job_history" => msg_admin_job_history(data, ses).await,
+        "admin_capacity_report" => msg_admin_capacity_report(data, ses).await,
+        "admin_create_team" => msg_admin_create_team(data, ses).await,
+        "admin_del_team" => msg_admin_del_team(data, ses).await,
+        "admin_list_guests" => msg_admin_list_guests(data, ses).await,
+        "admin_promote_guest" => msg_admin_promote_guest(data, ses).await,
+        "admin_set_custom_field" => msg_admin_set_custom_field(data, ses).await,
+        "admin_del_custom_field" => msg_admin_del_custom_field(data, ses).await,
+        "admin_reload_config" => msg_admin_reload_config(data, ses).await,
+        "admin_reconcile" => msg_admin_reconcile(data, ses).await,
+        "admin_backup" => msg_admin_backup(data, ses).await,
+        "admin_list_maintenance_windows" => msg_admin_list_maintenance_windows(data, ses).await,
+        "admin_add_maintenance_window" => msg_admin_add_maintenance_window(data, ses).await,
+        "admin_del_maintenance_window" => msg_admin_del_maintenance_window(data, ses).await,
+        "admin_list_features" => msg_admin_list_features(data, ses).await,
+        "admin_set_feature" => msg_admin_set_feature(data, ses).await,
+        "admin_tracker_queue" => msg_admin_tracker_queue(data, ses).await,
         "logout" => msg_logout(data, ses).await,
         "echo" => {
             let answ = format!("Echo: {}", data.as_str().ok_or(anyhow!("data not found"))?);
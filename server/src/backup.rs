@@ -0,0 +1,246 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::database::DB;
+
+// A backup bundle is a directory with a consistent copy of the database (`clapshot.sqlite`,
+// made with SQLite's VACUUM INTO, so the server can keep running) and `manifest.json`, which
+// lists the media files the copied database refers to (video dirs, including trashed ones).
+//
+// Media files are not copied: they are large and don't change after processing, so operators
+// sync `videos/` with their usual tools (rsync, snapshots). Restoring puts the database copy
+// in place, and checks the media files in data dir against the manifest, to tell what's
+// still missing.
+//
+// Made with `clapshot-server backup` (or admin's `admin_backup`, into `<data dir>/backups/`),
+// and restored with `clapshot-server restore` while the server is stopped.
+
+pub const DB_FILE: &str = "clapshot.sqlite";
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Manifest format written by this version
+const FORMAT_VERSION: u32 = 1;
+
+/// A media file referenced by the backed up database
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MediaFile {
+    /// Path under data dir, e.g. "videos/abc123/video.mp4"
+    pub path: String,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub format: u32,
+    pub created: chrono::NaiveDateTime,
+    pub server_version: String,
+    /// Checksum of the database copy, to detect a damaged bundle
+    pub db_sha256: String,
+    pub videos: usize,
+    pub files: Vec<MediaFile>,
+}
+
+impl Manifest {
+    pub fn total_bytes(&self) -> u64 {
+        self.files.iter().map(|f| f.bytes).sum()
+    }
+
+    pub fn summary(&self) -> String {
+        format!("{} videos, {} media files ({} MB)", self.videos, self.files.len(), self.total_bytes() / (1024 * 1024))
+    }
+}
+
+/// Media files in data dir that don't match the manifest
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct MediaCheck {
+    pub missing: Vec<String>,
+    pub wrong_size: Vec<String>,
+}
+
+impl MediaCheck {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.wrong_size.is_empty()
+    }
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String>
+{
+    let mut f = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        match f.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Regular files under a dir, recursively, with paths relative to `base`.
+/// Symlinks are skipped (video dirs link to files that are listed anyway).
+fn list_files(base: &Path, dir: &Path, out: &mut Vec<MediaFile>) -> std::io::Result<()>
+{
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.path());
+    for e in entries {
+        let md = e.path().symlink_metadata()?;
+        if md.is_dir() {
+            list_files(base, &e.path(), out)?;
+        } else if md.is_file() {
+            let path = e.path().strip_prefix(base).unwrap_or(&e.path()).to_string_lossy().to_string();
+            out.push(MediaFile { path, bytes: md.len() });
+        }
+    }
+    Ok(())
+}
+
+/// Make a backup bundle.
+///
+/// # Arguments
+/// * `db` - Database to back up
+/// * `data_dir` - Server's data dir (for listing media files)
+/// * `dst` - Bundle dir to create. Must not exist, or be empty.
+pub fn create_backup(db: &DB, data_dir: &Path, dst: &Path) -> anyhow::Result<Manifest>
+{
+    if dst.read_dir().is_ok_and(|mut d| d.next().is_some()) {
+        bail!("Backup dir '{}' is not empty", dst.display());
+    }
+    std::fs::create_dir_all(dst).context("Failed to create backup dir")?;
+    let db_copy = dst.join(DB_FILE);
+    db.backup_to(&db_copy).context("Failed to copy database")?;
+
+    // List media of the copy, not of the live DB, so the manifest matches it
+    let videos = DB::connect_db_file(&db_copy)?.get_all_videos(None)?;
+    let mut files = vec![];
+    for v in &videos {
+        let rel = match v.trashed {
+            Some(_) => PathBuf::from("videos").join("trash").join(&v.video_hash),
+            None => PathBuf::from("videos").join(&v.video_hash),
+        };
+        if data_dir.join(&rel).is_dir() {
+            list_files(data_dir, &data_dir.join(&rel), &mut files)
+                .with_context(|| format!("Failed to list files in '{}'", rel.display()))?;
+        }
+    }
+    let manifest = Manifest {
+        format: FORMAT_VERSION,
+        created: chrono::Utc::now().naive_utc(),
+        server_version: env!("CARGO_PKG_VERSION").into(),
+        db_sha256: sha256_file(&db_copy)?,
+        videos: videos.len(),
+        files,
+    };
+    std::fs::write(dst.join(MANIFEST_FILE), serde_json::to_string_pretty(&manifest)?).context("Failed to write manifest")?;
+    Ok(manifest)
+}
+
+/// Read and sanity check the manifest of a backup bundle
+pub fn read_manifest(src: &Path) -> anyhow::Result<Manifest>
+{
+    let json = std::fs::read_to_string(src.join(MANIFEST_FILE))
+        .with_context(|| format!("No backup manifest in '{}'", src.display()))?;
+    let manifest: Manifest = serde_json::from_str(&json).context("Invalid backup manifest")?;
+    if manifest.format > FORMAT_VERSION {
+        bail!("Backup was made by a newer server (version {}). Upgrade before restoring.", manifest.server_version);
+    }
+    Ok(manifest)
+}
+
+/// Compare media files in data dir with a manifest
+pub fn verify_media(data_dir: &Path, manifest: &Manifest) -> MediaCheck
+{
+    let mut res = MediaCheck::default();
+    for f in &manifest.files {
+        match data_dir.join(&f.path).metadata() {
+            Err(_) => res.missing.push(f.path.clone()),
+            Ok(md) if md.len() != f.bytes => res.wrong_size.push(f.path.clone()),
+            Ok(_) => {},
+        }
+    }
+    res
+}
+
+/// Restore database from a backup bundle. Server must not be running.
+///
+/// # Arguments
+/// * `src` - Bundle dir
+/// * `data_dir` - Server's data dir
+/// * `force` - Replace an existing database (it's kept as `clapshot.sqlite.before-restore-<time>`)
+///
+/// # Returns
+/// Manifest of the bundle, and media files that still need to be restored
+pub fn restore_backup(src: &Path, data_dir: &Path, force: bool) -> anyhow::Result<(Manifest, MediaCheck)>
+{
+    let manifest = read_manifest(src)?;
+    let db_copy = src.join(DB_FILE);
+    if sha256_file(&db_copy).context("Failed to read database copy")? != manifest.db_sha256 {
+        bail!("Database copy in '{}' is damaged (checksum mismatch)", src.display());
+    }
+    let db_file = data_dir.join(DB_FILE);
+    if db_file.exists() {
+        if !force {
+            bail!("Database '{}' already exists. Use --force to replace it.", db_file.display());
+        }
+        let old = data_dir.join(format!("{}.before-restore-{}", DB_FILE, chrono::Local::now().format("%Y%m%d-%H%M%S")));
+        std::fs::rename(&db_file, &old).context("Failed to move old database aside")?;
+        tracing::warn!(file=%old.display(), "Moved old database aside.");
+    }
+    std::fs::create_dir_all(data_dir)?;
+    let tmp = data_dir.join(format!("{}.restoring", DB_FILE));
+    std::fs::copy(&db_copy, &tmp).context("Failed to copy database")?;
+    std::fs::rename(&tmp, &db_file)?;
+    Ok((manifest.clone(), verify_media(data_dir, &manifest)))
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_backup_and_restore()
+{
+    let dir = tempfile::tempdir().unwrap();
+    let (data_dir, bundle, data_dir2) = (dir.path().join("data"), dir.path().join("bundle"), dir.path().join("data2"));
+    std::fs::create_dir_all(data_dir.join("videos").join("HASH0").join("orig")).unwrap();
+    std::fs::write(data_dir.join("videos/HASH0/orig/a.mov"), "DATA").unwrap();
+    std::fs::write(data_dir.join("videos/HASH0/notes.txt"), "NOTES!").unwrap();
+    std::os::unix::fs::symlink("orig/a.mov", data_dir.join("videos/HASH0/video.mov")).unwrap();
+    std::fs::create_dir_all(data_dir.join("videos").join("unrelated")).unwrap();
+    std::fs::write(data_dir.join("videos/unrelated/x.mov"), "X").unwrap();
+
+    let db = DB::connect_db_file(&data_dir.join(DB_FILE)).unwrap();
+    db.run_migrations().unwrap();
+    db.add_video(&crate::database::models::VideoInsert {
+        video_hash: "HASH0".into(),
+        added_by_userid: Some("user.num1".into()),
+        ..Default::default()
+    }).unwrap();
+
+    let m = create_backup(&db, &data_dir, &bundle).unwrap();
+    assert_eq!(m.videos, 1);
+    assert_eq!(m.files, vec![
+        MediaFile { path: "videos/HASH0/notes.txt".into(), bytes: 6 },
+        MediaFile { path: "videos/HASH0/orig/a.mov".into(), bytes: 4 }]);
+    assert!(create_backup(&db, &data_dir, &bundle).is_err());
+
+    // Restore into an empty data dir: DB is there, but media is still missing
+    let (m2, check) = restore_backup(&bundle, &data_dir2, false).unwrap();
+    assert_eq!(m2.db_sha256, m.db_sha256);
+    assert_eq!(check.missing.len(), 2);
+    assert_eq!(DB::connect_db_file(&data_dir2.join(DB_FILE)).unwrap().get_video("HASH0").unwrap().added_by_userid, Some("user.num1".into()));
+
+    // Existing DB is only replaced with force
+    assert!(restore_backup(&bundle, &data_dir, false).is_err());
+    let (_, check) = restore_backup(&bundle, &data_dir, true).unwrap();
+    assert!(check.is_ok());
+    assert_eq!(std::fs::read_dir(&data_dir).unwrap().flatten().filter(|e| e.file_name().to_string_lossy().contains("before-restore")).count(), 1);
+
+    std::fs::write(data_dir.join("videos/HASH0/notes.txt"), "?").unwrap();
+    assert_eq!(verify_media(&data_dir, &m).wrong_size, vec!["videos/HASH0/notes.txt"]);
+
+    std::fs::write(bundle.join(DB_FILE), "garbage").unwrap();
+    assert!(restore_backup(&bundle, &data_dir2, true).is_err());
+}
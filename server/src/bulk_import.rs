@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+// Bulk import of an existing video library (e.g. a shared drive) with `clapshot-server import`.
+//
+// The directory tree is walked for media files, each file gets an owner (from a mapping file,
+// the first dir level, or a default user), and files are uploaded to the running server's
+// `/api/upload` one by one, so they go through the normal pipeline (dedup, quotas, transcoding).
+// When the pipeline is busy (HTTP 429), the import waits and retries.
+//
+// Every uploaded (or failed) file is appended to a journal in `<data dir>/imports/`, one per
+// imported dir. If the import is interrupted, running it again skips files already uploaded
+// (unless they have changed size) and retries failed ones.
+
+/// File extensions considered media (videos, audio, stills)
+const MEDIA_EXTENSIONS: &[&str] = &[
+    "mp4", "mov", "m4v", "mkv", "avi", "mxf", "webm", "wmv", "mpg", "mpeg", "mts", "m2ts", "ts", "flv", "3gp", "ogv",
+    "wav", "mp3", "aac", "m4a", "flac", "ogg", "opus", "aif", "aiff",
+    "png", "jpg", "jpeg", "tif", "tiff", "pdf"];
+
+/// Wait between retries when server is busy
+pub const BUSY_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// How many times to retry a file while server is busy (30 min) before giving up on it
+const MAX_BUSY_RETRIES: u32 = 180;
+
+/// Who owns imported files
+#[derive(Debug, Clone, Default)]
+pub struct OwnerMapping {
+    /// Path prefixes (relative to import root) and their owners. Longest match wins.
+    pub prefixes: Vec<(PathBuf, String)>,
+    /// Use first dir under import root as user ID (e.g. "alice/project/a.mov" -> alice)
+    pub from_dir: bool,
+    /// Owner of files not matched otherwise
+    pub default: Option<String>,
+}
+
+impl OwnerMapping {
+    /// Parse a mapping file: `<path prefix> = <user id>` lines, `#` starts a comment
+    pub fn parse_map(text: &str) -> anyhow::Result<Vec<(PathBuf, String)>>
+    {
+        let mut res = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() { continue; }
+            match line.rsplit_once('=') {
+                Some((prefix, user)) if !user.trim().is_empty() => {
+                    res.push((PathBuf::from(prefix.trim().trim_matches('/')), user.trim().to_string()));
+                },
+                _ => bail!("Line {}: expected '<path> = <user id>'", i + 1),
+            }
+        }
+        Ok(res)
+    }
+
+    /// Owner of a file, by its path relative to import root
+    pub fn owner_of(&self, rel: &Path) -> Option<String>
+    {
+        let mapped = self.prefixes.iter()
+            .filter(|(prefix, _)| rel.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.components().count())
+            .map(|(_, user)| user.clone());
+        let from_dir = || match self.from_dir && rel.components().count() > 1 {
+            true => rel.components().next().map(|c| c.as_os_str().to_string_lossy().to_string()),
+            false => None,
+        };
+        mapped.or_else(from_dir).or_else(|| self.default.clone())
+    }
+}
+
+/// Media files under a dir, relative to it, sorted. Hidden files and dirs are skipped.
+pub fn find_media(root: &Path) -> anyhow::Result<Vec<(PathBuf, u64)>>
+{
+    fn walk(root: &Path, dir: &Path, out: &mut Vec<(PathBuf, u64)>) -> std::io::Result<()> {
+        for e in std::fs::read_dir(dir)? {
+            let e = e?;
+            if e.file_name().to_string_lossy().starts_with('.') { continue; }
+            let md = e.metadata()?;
+            if md.is_dir() {
+                walk(root, &e.path(), out)?;
+            } else if md.is_file() && e.path().extension()
+                    .is_some_and(|x| MEDIA_EXTENSIONS.contains(&x.to_string_lossy().to_lowercase().as_str())) {
+                out.push((e.path().strip_prefix(root).unwrap_or(&e.path()).to_path_buf(), md.len()));
+            }
+        }
+        Ok(())
+    }
+    let mut res = vec![];
+    walk(root, root, &mut res).with_context(|| format!("Failed to read '{}'", root.display()))?;
+    res.sort();
+    Ok(res)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct JournalEntry {
+    path: String,
+    bytes: u64,
+    owner: String,
+    ok: bool,
+    details: String,
+}
+
+/// Record of files handled by earlier runs of an import, for resuming it
+pub struct Journal {
+    file: std::fs::File,
+    done: HashMap<String, JournalEntry>,
+}
+
+impl Journal {
+    /// Journal file of an import dir, under `<data dir>/imports/`
+    pub fn path_for(data_dir: &Path, import_root: &Path) -> PathBuf
+    {
+        use sha2::{Digest, Sha256};
+        let abs = import_root.canonicalize().unwrap_or(import_root.to_path_buf());
+        let hash = hex::encode(Sha256::digest(abs.to_string_lossy().as_bytes()));
+        data_dir.join("imports").join(format!("{}.jsonl", &hash[..16]))
+    }
+
+    /// Open (or create) a journal. Later entries of a file override earlier ones.
+    pub fn open(path: &Path) -> anyhow::Result<Journal>
+    {
+        if let Some(dir) = path.parent() { std::fs::create_dir_all(dir)?; }
+        let mut done = HashMap::new();
+        if let Ok(text) = std::fs::read_to_string(path) {
+            // Last line may be cut short by an interruption. Skip it.
+            for e in text.lines().filter_map(|l| serde_json::from_str::<JournalEntry>(l).ok()) {
+                done.insert(e.path.clone(), e);
+            }
+        }
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Failed to open import journal '{}'", path.display()))?;
+        Ok(Journal { file, done })
+    }
+
+    /// Was file uploaded already (and hasn't changed size since)
+    fn is_done(&self, rel: &str, bytes: u64) -> bool {
+        self.done.get(rel).is_some_and(|e| e.ok && e.bytes == bytes)
+    }
+
+    fn record(&mut self, e: JournalEntry) -> anyhow::Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(&e)?)?;
+        self.file.flush()?;
+        self.done.insert(e.path.clone(), e);
+        Ok(())
+    }
+}
+
+/// Result of uploading one file
+#[derive(Debug, Clone, PartialEq)]
+pub enum UploadResult {
+    Ok,
+    /// Server can't take it now, try again later
+    Busy(String),
+    /// File was refused
+    Failed(String),
+    /// Import can't continue (server down, storage full)
+    Fatal(String),
+}
+
+/// Upload a file to the server's `/api/upload` as a user
+///
+/// # Arguments
+/// * `server_url` - Server's address, e.g. "http://127.0.0.1:8095"
+/// * `path` - File to upload
+/// * `user_id` - Owner
+pub fn http_upload(server_url: &str, path: &Path, user_id: &str) -> UploadResult
+{
+    let form = match reqwest::blocking::multipart::Form::new().file("fileupload", path) {
+        Ok(f) => f,
+        Err(e) => return UploadResult::Failed(format!("Failed to read file: {e}")),
+    };
+    let res = reqwest::blocking::Client::builder().timeout(None).build()
+        .and_then(|c| c.post(format!("{server_url}/api/upload")).header("X-Remote-User-Id", user_id).multipart(form).send());
+    match res {
+        Err(e) => UploadResult::Fatal(format!("Upload to {server_url} failed: {e}")),
+        Ok(r) => {
+            let status = r.status();
+            let body = r.text().unwrap_or_default();
+            match status {
+                s if s.is_success() => UploadResult::Ok,
+                reqwest::StatusCode::TOO_MANY_REQUESTS => UploadResult::Busy(body),
+                reqwest::StatusCode::INSUFFICIENT_STORAGE | reqwest::StatusCode::SERVICE_UNAVAILABLE => UploadResult::Fatal(body),
+                s => UploadResult::Failed(format!("{s}: {body}")),
+            }
+        },
+    }
+}
+
+/// What an import did
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImportSummary {
+    pub found: usize,
+    pub uploaded: usize,
+    /// Uploaded by an earlier run
+    pub already_done: usize,
+    /// Files nobody owns (not uploaded)
+    pub no_owner: Vec<String>,
+    pub failed: Vec<(String, String)>,
+    /// Why the import stopped early, if it did
+    pub aborted: Option<String>,
+}
+
+impl ImportSummary {
+    pub fn print(&self) {
+        for p in &self.no_owner { println!("No owner: {p}"); }
+        for (p, reason) in &self.failed { println!("Failed: {p}: {reason}"); }
+        println!("\n{} media files found: {} uploaded, {} already imported earlier, {} without owner, {} failed.",
+            self.found, self.uploaded, self.already_done, self.no_owner.len(), self.failed.len());
+        if let Some(reason) = &self.aborted {
+            println!("Import interrupted: {reason}\nRun the same command again to resume.");
+        }
+    }
+}
+
+/// Import media files under a dir.
+///
+/// # Arguments
+/// * `root` - Dir to import
+/// * `owners` - Who owns which files
+/// * `journal` - Journal of earlier runs (updated as files are uploaded)
+/// * `dry_run` - Only print what would be uploaded, and as whom
+/// * `upload` - Uploads a file as a user (`http_upload` for real)
+/// * `retry_wait` - Wait between retries when server is busy (`BUSY_RETRY_INTERVAL`)
+pub fn run_import(root: &Path, owners: &OwnerMapping, journal: &mut Journal, dry_run: bool,
+    mut upload: impl FnMut(&Path, &str) -> UploadResult, retry_wait: Duration) -> anyhow::Result<ImportSummary>
+{
+    let files = find_media(root)?;
+    let mut sum = ImportSummary { found: files.len(), ..Default::default() };
+    for (rel, bytes) in files {
+        let rel_str = rel.to_string_lossy().to_string();
+        if journal.is_done(&rel_str, bytes) {
+            sum.already_done += 1;
+            continue;
+        }
+        let Some(owner) = owners.owner_of(&rel) else {
+            sum.no_owner.push(rel_str);
+            continue;
+        };
+        if dry_run {
+            println!("{rel_str} -> {owner}");
+            continue;
+        }
+        let mut retries = 0;
+        let res = loop {
+            match upload(&root.join(&rel), &owner) {
+                UploadResult::Busy(msg) if retries < MAX_BUSY_RETRIES => {
+                    tracing::info!(file=%rel_str, details=%msg, "Server busy. Waiting before retry.");
+                    std::thread::sleep(retry_wait);
+                    retries += 1;
+                },
+                other => break other,
+            }
+        };
+        let (ok, details) = match res {
+            UploadResult::Ok => { sum.uploaded += 1; (true, String::new()) },
+            UploadResult::Busy(msg) | UploadResult::Failed(msg) => { sum.failed.push((rel_str.clone(), msg.clone())); (false, msg) },
+            UploadResult::Fatal(msg) => { sum.aborted = Some(msg); break; },
+        };
+        println!("{} {rel_str} -> {owner}", if ok { "Uploaded" } else { "FAILED" });
+        journal.record(JournalEntry { path: rel_str, bytes, owner, ok, details })?;
+    }
+    Ok(sum)
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_owner_mapping()
+{
+    let prefixes = OwnerMapping::parse_map("# Team drive\nprojects = alice\nprojects/b/ = bob  # Bob's\n\n").unwrap();
+    assert_eq!(prefixes, vec![(PathBuf::from("projects"), "alice".into()), (PathBuf::from("projects/b"), "bob".into())]);
+    assert!(OwnerMapping::parse_map("projects alice").is_err());
+
+    let m = OwnerMapping { prefixes, from_dir: true, default: Some("admin".into()) };
+    assert_eq!(m.owner_of(Path::new("projects/a/x.mov")), Some("alice".into()));
+    assert_eq!(m.owner_of(Path::new("projects/b/x.mov")), Some("bob".into()));
+    assert_eq!(m.owner_of(Path::new("projects_old/x.mov")), Some("projects_old".into()));
+    assert_eq!(m.owner_of(Path::new("x.mov")), Some("admin".into()));
+    assert_eq!(OwnerMapping::default().owner_of(Path::new("x.mov")), None);
+}
+
+#[test]
+fn test_import_and_resume()
+{
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("drive");
+    for d in ["alice/p1", "bob", ".Trash"] {
+        std::fs::create_dir_all(root.join(d)).unwrap();
+    }
+    for f in ["alice/p1/a.mov", "alice/p1/b.MP4", "alice/p1/notes.docx", "bob/c.wav", "bob/d.png", ".Trash/e.mov", "orphan.mov"] {
+        std::fs::write(root.join(f), "DATA").unwrap();
+    }
+    let owners = OwnerMapping { from_dir: true, ..Default::default() };
+    let journal_path = Journal::path_for(&dir.path().join("data"), &root);
+    assert_eq!(journal_path, Journal::path_for(&dir.path().join("data"), &root.join("alice").join("..")));
+
+    // Server goes down at the third file, after being busy once
+    let mut calls = vec![];
+    let mut n = 0;
+    let flaky = |p: &Path, user: &str| {
+        n += 1;
+        calls.push((p.file_name().unwrap().to_string_lossy().to_string(), user.to_string()));
+        match n { 2 => UploadResult::Busy("busy".into()), 4 => UploadResult::Fatal("down".into()), _ => UploadResult::Ok }
+    };
+    let sum = run_import(&root, &owners, &mut Journal::open(&journal_path).unwrap(), false, flaky, Duration::ZERO).unwrap();
+    assert_eq!(sum.found, 5);
+    assert_eq!(sum.uploaded, 2);
+    assert_eq!(sum.aborted, Some("down".into()));
+    assert_eq!(calls, vec![("a.mov".into(), "alice".into()), ("b.MP4".into(), "alice".into()), ("b.MP4".into(), "alice".into()), ("c.wav".into(), "bob".into())]);
+
+    // Resume: uploaded files are skipped, refused ones are recorded as failed
+    let mut calls = vec![];
+    let refuse_png = |p: &Path, _: &str| {
+        calls.push(p.file_name().unwrap().to_string_lossy().to_string());
+        if p.extension().unwrap() == "png" { UploadResult::Failed("400: bad".into()) } else { UploadResult::Ok }
+    };
+    let sum = run_import(&root, &owners, &mut Journal::open(&journal_path).unwrap(), false, refuse_png, Duration::ZERO).unwrap();
+    assert_eq!(calls, vec!["c.wav", "d.png"]);
+    assert_eq!((sum.already_done, sum.uploaded, sum.aborted), (2, 1, None));
+    assert_eq!(sum.no_owner, vec!["orphan.mov"]);
+    assert_eq!(sum.failed, vec![("bob/d.png".into(), "400: bad".into())]);
+
+    // Failed and changed files are retried
+    std::fs::write(root.join("alice/p1/a.mov"), "NEW DATA").unwrap();
+    let mut calls = vec![];
+    let ok = |p: &Path, _: &str| { calls.push(p.file_name().unwrap().to_string_lossy().to_string()); UploadResult::Ok };
+    let sum = run_import(&root, &owners, &mut Journal::open(&journal_path).unwrap(), false, ok, Duration::ZERO).unwrap();
+    assert_eq!(calls, vec!["a.mov", "d.png"]);
+    assert_eq!(sum.already_done, 2);
+}
@@ -0,0 +1,292 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+use crate::quota::Quotas;
+use crate::api_server::webhook::Webhook;
+use crate::api_server::notifier::ChatNotifier;
+use crate::api_server::organizer::Organizer;
+use crate::api_server::tracker_sync::TrackerSync;
+use crate::api_server::feature_flags::FeatureFlags;
+use crate::api_server::rate_limit::RateLimits;
+use crate::api_server::session_limits::SessionLimits;
+use crate::video_pipeline::transcode_presets::TranscodePresets;
+use crate::video_pipeline::burn_in::BurnInPolicy;
+use crate::video_pipeline::{IngestPolicy, scan::Scanner, sandbox::Sandbox, poster::PosterScoring};
+use crate::api_server::{url_signing::UrlSigner, retention::Retention, onboarding::Onboarding};
+
+/// Read a config file and convert it to command line arguments.
+///
+/// Format is the same as `/etc/clapshot-server.conf`: an INI file with a `[general]`
+/// section of `key = value` lines, where keys are long option names without dashes.
+/// Value `true` gives a flag (e.g. `debug = true` -> `--debug`) and `false` omits it.
+/// Other sections and comment lines (`#` or `;`) are ignored.
+///
+/// # Arguments
+/// * `path` - Path to the config file
+///
+/// # Returns
+/// * Arguments like `["--port=8095", "--debug"]`
+pub fn read_config_args(path: &Path) -> anyhow::Result<Vec<String>>
+{
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read config file '{}': {}", path.display(), e))?;
+    let mut args = Vec::new();
+    let mut section = String::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_string();
+            continue;
+        }
+        let (key, value) = line.split_once(['=', ':'])
+            .ok_or_else(|| anyhow::anyhow!("{}:{}: expected 'key = value'", path.display(), n+1))?;
+        if section != "general" {
+            continue;
+        }
+        let (key, value) = (key.trim().to_lowercase(), value.trim());
+        match value.to_lowercase().as_str() {
+            "true" => args.push(format!("--{key}")),
+            "false" => {},
+            _ => args.push(format!("--{key}={value}")),
+        }
+    }
+    Ok(args)
+}
+
+/// Settings that can be changed without restarting the server
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReloadableConfig {
+    /// Debug logging
+    pub debug: bool,
+    /// Max number of workers per video processing stage
+    pub n_workers: usize,
+    pub quotas: Quotas,
+    /// Where to post events like review verdict changes, if anywhere
+    pub webhook: Option<Webhook>,
+    /// Slack / Mattermost webhooks to post review activity to
+    pub chat: ChatNotifier,
+    /// External service consulted on library events (gRPC)
+    pub organizer: Option<Organizer>,
+    /// ShotGrid / ftrack to sync comments with
+    pub tracker: Option<TrackerSync>,
+    /// Rollout of features, before admin's overrides
+    pub features: FeatureFlags,
+    /// Per-connection limits of Websocket commands
+    pub rate_limits: RateLimits,
+    /// Connections per user and idle timeout
+    pub sessions: SessionLimits,
+    /// Output recipes for transcoding, and which one is used by default
+    pub transcode_presets: TranscodePresets,
+    /// Overlays to burn into transcoded videos
+    pub burn_in: BurnInPolicy,
+}
+
+/// Settings that need a restart to change (command line or config file), for API server and video pipeline
+#[derive(Clone, Default)]
+pub struct StartupOptions {
+    pub data_dir: PathBuf,
+    /// Run database migrations, if needed
+    pub migrate: bool,
+    /// URL base without trailing slash
+    pub url_base: String,
+    pub url_signer: Option<UrlSigner>,
+    pub port: u16,
+    /// Serve the videos dir from API server (instead of a web server in front of it)
+    pub host_videos: bool,
+    pub retention: Retention,
+    pub ingest: IngestPolicy,
+    /// Seconds between scans of the incoming dir
+    pub poll_interval: f32,
+    /// Seconds before a file in incoming dir that wasn't picked up is tried again
+    pub resubmit_delay: f32,
+    /// Detect leading/trailing audio silence (see `metadata_reader`)
+    pub trim_silence: bool,
+    /// Frame rate of movies assembled from image sequences
+    pub sequence_fps: f64,
+    /// Command for ML analysis of new videos, if any
+    pub analyzer: Option<String>,
+    pub detect_scenes: bool,
+    /// Malware / content scanner for new files, if any
+    pub scanner: Option<Scanner>,
+    pub sandbox: Sandbox,
+    pub poster: PosterScoring,
+    pub onboarding: Option<Onboarding>,
+    /// How long to wait for jobs in progress on shutdown
+    pub shutdown_grace: Duration,
+    /// Max length of pipeline queues (see `video_pipeline::queues`)
+    pub queue_capacity: usize,
+}
+
+/// Re-reads the config (file) and returns the new reloadable settings
+pub type ConfigReader = Box<dyn Fn() -> anyhow::Result<ReloadableConfig> + Send + Sync>;
+
+/// Switches log level between debug (true) and normal
+pub type LogLevelSetter = Box<dyn Fn(bool) -> anyhow::Result<()> + Send + Sync>;
+
+/// Current values of the reloadable settings, shared by API server and video pipeline.
+///
+/// Reloaded on SIGHUP or admin's `reload_config` command. Quotas, webhooks (also chat), organizer, tracker sync, features, rate limits,
+/// session limits, transcode presets and burn-in settings take effect on next use, worker pools are resized when they next schedule work (processing
+/// in progress is not interrupted), and log level right away.
+pub struct LiveConfig {
+    cur: RwLock<ReloadableConfig>,
+    /// Read by worker pools (see `fair_queue::run_fair_pool`)
+    pub n_workers: Arc<AtomicUsize>,
+    reader: Option<ConfigReader>,
+    set_log_level: Option<LogLevelSetter>,
+}
+
+impl LiveConfig {
+    pub fn new(initial: ReloadableConfig, reader: Option<ConfigReader>, set_log_level: Option<LogLevelSetter>) -> Arc<LiveConfig> {
+        Arc::new(LiveConfig {
+            n_workers: Arc::new(AtomicUsize::new(initial.n_workers)),
+            cur: RwLock::new(initial),
+            reader,
+            set_log_level,
+        })
+    }
+
+    /// Config that can't be reloaded (no config file)
+    pub fn fixed(initial: ReloadableConfig) -> Arc<LiveConfig> {
+        LiveConfig::new(initial, None, None)
+    }
+
+    pub fn get(&self) -> ReloadableConfig {
+        self.cur.read().unwrap().clone()
+    }
+
+    pub fn quotas(&self) -> Quotas {
+        self.cur.read().unwrap().quotas
+    }
+
+    pub fn webhook(&self) -> Option<Webhook> {
+        self.cur.read().unwrap().webhook.clone()
+    }
+
+    pub fn chat_notifier(&self) -> ChatNotifier {
+        self.cur.read().unwrap().chat.clone()
+    }
+
+    pub fn organizer(&self) -> Option<Organizer> {
+        self.cur.read().unwrap().organizer.clone()
+    }
+
+    pub fn tracker(&self) -> Option<TrackerSync> {
+        self.cur.read().unwrap().tracker.clone()
+    }
+
+    pub fn features(&self) -> FeatureFlags {
+        self.cur.read().unwrap().features.clone()
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        self.cur.read().unwrap().rate_limits.clone()
+    }
+
+    pub fn sessions(&self) -> SessionLimits {
+        self.cur.read().unwrap().sessions
+    }
+
+    pub fn transcode_presets(&self) -> TranscodePresets {
+        self.cur.read().unwrap().transcode_presets.clone()
+    }
+
+    pub fn burn_in(&self) -> BurnInPolicy {
+        self.cur.read().unwrap().burn_in.clone()
+    }
+
+    /// Re-read the config and apply changed settings.
+    ///
+    /// # Returns
+    /// * Names of the settings that changed
+    pub fn reload(&self) -> anyhow::Result<Vec<&'static str>> {
+        let reader = self.reader.as_ref().ok_or_else(|| anyhow::anyhow!("No config file to reload (see --config)"))?;
+        let new = reader()?;
+        self.apply(new)
+    }
+
+    /// Apply new settings, and return names of the ones that changed.
+    pub fn apply(&self, new: ReloadableConfig) -> anyhow::Result<Vec<&'static str>> {
+        let mut cur = self.cur.write().unwrap();
+        let mut changed = Vec::new();
+        if new.debug != cur.debug {
+            if let Some(set_log_level) = &self.set_log_level {
+                set_log_level(new.debug)?;
+            }
+            changed.push("debug");
+        }
+        if new.n_workers != cur.n_workers {
+            self.n_workers.store(new.n_workers, Relaxed);
+            changed.push("workers");
+        }
+        if new.quotas != cur.quotas { changed.push("quotas"); }
+        if new.webhook != cur.webhook { changed.push("webhook"); }
+        if new.chat != cur.chat { changed.push("chat_notify"); }
+        if new.organizer != cur.organizer { changed.push("organizer"); }
+        if new.tracker != cur.tracker { changed.push("tracker_sync"); }
+        if new.features != cur.features { changed.push("features"); }
+        if new.rate_limits != cur.rate_limits { changed.push("rate_limits"); }
+        if new.sessions != cur.sessions { changed.push("sessions"); }
+        if new.transcode_presets != cur.transcode_presets { changed.push("transcode_presets"); }
+        if new.burn_in != cur.burn_in { changed.push("burn_in"); }
+        *cur = new;
+        Ok(changed)
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_read_config_args()
+{
+    let dir = assert_fs::TempDir::new().unwrap();
+    let path = dir.join("clapshot-server.conf");
+    std::fs::write(&path, "# comment\n[general]\nurl-base = http://127.0.0.1:8080\nWorkers: 4\n\ndebug = true\nhost-videos = false\n; other\n[other]\nport = 1\n").unwrap();
+    assert_eq!(read_config_args(&path).unwrap(), vec!["--url-base=http://127.0.0.1:8080", "--workers=4", "--debug"]);
+
+    std::fs::write(&path, "[general]\nno value here\n").unwrap();
+    assert!(read_config_args(&path).is_err());
+    assert!(read_config_args(&dir.join("missing.conf")).is_err());
+}
+
+#[test]
+fn test_live_config_reload()
+{
+    assert!(LiveConfig::fixed(ReloadableConfig::default()).reload().is_err());
+
+    let levels = Arc::new(RwLock::new(Vec::new()));
+    let levels_cln = levels.clone();
+    let cfg = LiveConfig::new(
+        ReloadableConfig { n_workers: 2, ..Default::default() },
+        Some(Box::new(|| Ok(ReloadableConfig {
+            debug: true,
+            n_workers: 8,
+            quotas: Quotas { max_file_size: Some(1000), ..Default::default() },
+            webhook: None,
+            chat: Default::default(),
+            organizer: None,
+            tracker: None,
+            features: FeatureFlags::parse("collab=off").unwrap(),
+            rate_limits: RateLimits::parse("add_comment=1:10").unwrap(),
+            sessions: SessionLimits { max_user_connections: Some(5), ..Default::default() },
+            transcode_presets: TranscodePresets::parse(Some(r#"{"hq": {"crf": 18}}"#), "hq").unwrap(),
+            burn_in: BurnInPolicy::parse("timecode", "none", None).unwrap(),
+        }))),
+        Some(Box::new(move |debug| { levels_cln.write().unwrap().push(debug); Ok(()) })));
+
+    assert_eq!(cfg.reload().unwrap(), vec!["debug", "workers", "quotas", "features", "rate_limits", "sessions", "transcode_presets", "burn_in"]);
+    assert_eq!(cfg.transcode_presets().resolve(None).crf, Some(18));
+    assert!(cfg.burn_in().resolve(None).timecode);
+    assert_eq!(cfg.n_workers.load(Relaxed), 8);
+    assert_eq!(cfg.quotas().max_file_size, Some(1000));
+    assert_eq!(*levels.read().unwrap(), vec![true]);
+
+    // No changes the second time
+    assert!(cfg.reload().unwrap().is_empty());
+}
@@ -37,6 +37,148 @@ fn to_db_res<U>(res: QueryResult<U>) -> DBResult<U> {
 }
 
 
+/// Filter for searching the audit log and user messages (admin event console).
+/// Unset fields don't filter anything.
+#[derive(Debug, Clone)]
+pub struct EventFilter {
+    pub user_id: Option<String>,
+    /// Audit log `action` or message `event_name`
+    pub kind: Option<String>,
+    pub video_hash: Option<String>,
+    pub since: Option<chrono::NaiveDateTime>,
+    pub until: Option<chrono::NaiveDateTime>,
+    /// Free-text search (substring, case-insensitive) over all text fields
+    pub text: Option<String>,
+    pub limit: i64,
+}
+
+impl Default for EventFilter {
+    fn default() -> Self {
+        EventFilter { user_id: None, kind: None, video_hash: None, since: None, until: None, text: None, limit: 1000 }
+    }
+}
+
+/// Change applied to many videos at once, see `DB::apply_video_batch`
+#[derive(Debug, Clone, PartialEq)]
+pub enum VideoBatchOp {
+    /// Move to a folder, or to root with None
+    MoveToFolder(Option<i32>),
+    AddTag { tag: String, user_id: String },
+    DelTag(String),
+    SetAllowDownload(bool),
+    /// Mark as trashed (files are moved by `api_server::trash`)
+    Trash,
+}
+
+/// Sort key for video listings, see `DB::get_user_videos_page`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoSort {
+    Added,
+    /// Title, ignoring case
+    Title,
+    Duration,
+    CommentCount,
+}
+
+impl VideoSort {
+    /// Parse a sort key as clients send it
+    pub fn parse(s: &str) -> Option<VideoSort> {
+        match s {
+            "added_time" => Some(VideoSort::Added),
+            "title" => Some(VideoSort::Title),
+            "duration" => Some(VideoSort::Duration),
+            "comment_count" => Some(VideoSort::CommentCount),
+            _ => None,
+        }
+    }
+}
+
+diesel::sql_function! {
+    /// SQL LOWER(), for sorting text ignoring case
+    fn lower(x: diesel::sql_types::Nullable<diesel::sql_types::Text>) -> diesel::sql_types::Nullable<diesel::sql_types::Text>;
+}
+
+/// Make a LIKE pattern for substring search, escaping wildcards with '\\'
+fn like_pattern(s: &str) -> String {
+    format!("%{}%", s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+}
+
+/// Shortest query that's also matched by spelling similarity (see `DB::search_users`)
+const MIN_FUZZY_QUERY_LEN: usize = 3;
+
+/// Share of query's trigrams a name must have to count as similarly spelled
+const MIN_TRIGRAM_SIMILARITY: f32 = 0.6;
+
+/// Trigrams of the words in a text, padded like in PostgreSQL's pg_trgm ("ab" -> "  a", " ab", "ab ")
+fn trigrams(s: &str) -> std::collections::HashSet<[char; 3]> {
+    s.split_whitespace().flat_map(|w| {
+        let padded = [' ', ' '].into_iter().chain(w.chars()).chain([' ']).collect::<Vec<_>>();
+        padded.windows(3).map(|t| [t[0], t[1], t[2]]).collect::<Vec<_>>()
+    }).collect()
+}
+
+/// Give out the next number for a comment on a video (see `models::Comment::number`).
+/// Call in the same transaction that inserts the comment.
+fn next_comment_number(conn: &mut SqliteConnection, vh: &str) -> QueryResult<i32> {
+    use schema::comment_numbers::dsl::*;
+    diesel::insert_into(comment_numbers)
+        .values((video_hash.eq(vh), last_number.eq(1)))
+        .on_conflict(video_hash).do_update().set(last_number.eq(last_number + 1))
+        .returning(last_number).get_result(conn)
+}
+
+/// Save current text of a comment as a revision, before replacing it with `new_text`.
+/// Nothing is saved if the text doesn't change.
+fn save_comment_revision(conn: &mut SqliteConnection, cid: i32, new_text: &str, by: Option<&str>) -> QueryResult<()> {
+    use schema::comments::dsl as c;
+    use schema::comment_revisions::dsl::*;
+    let (old_text, old_created, old_edited) = c::comments.filter(c::id.eq(cid))
+        .select((c::comment, c::created, c::edited)).first::<(String, chrono::NaiveDateTime, Option<chrono::NaiveDateTime>)>(conn)?;
+    if old_text != new_text {
+        diesel::insert_into(comment_revisions)
+            .values((comment_id.eq(cid), comment.eq(old_text), written.eq(old_edited.unwrap_or(old_created)), replaced_by.eq(by)))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Check if a video is under legal hold, either by itself or through its folder or any parent folder.
+/// Returns false for missing videos.
+fn video_is_held(conn: &mut SqliteConnection, vh: &str) -> QueryResult<bool> {
+    use schema::videos::dsl as v;
+    use schema::folders::dsl as f;
+    let Some((held, mut cur)) = v::videos.filter(v::video_hash.eq(vh)).select((v::legal_hold, v::folder_id))
+        .first::<(bool, Option<i32>)>(conn).optional()? else { return Ok(false) };
+    if held { return Ok(true); }
+    let mut depth = 0;
+    while let Some(fid) = cur {
+        let Some((parent, held)) = f::folders.filter(f::id.eq(fid)).select((f::parent_id, f::legal_hold))
+            .first::<(Option<i32>, bool)>(conn).optional()? else { break };
+        if held { return Ok(true); }
+        depth += 1;
+        if depth > 100 { break; }  // Guard against loops in broken data
+        cur = parent;
+    }
+    Ok(false)
+}
+
+/// IDs of folders under legal hold, by themselves or through any parent folder
+fn held_folder_ids(conn: &mut SqliteConnection) -> QueryResult<Vec<i32>> {
+    use schema::folders::dsl::*;
+    let all = folders.select((id, parent_id, legal_hold)).load::<(i32, Option<i32>, bool)>(conn)?;
+    let mut held: Vec<i32> = all.iter().filter(|f| f.2).map(|f| f.0).collect();
+    let mut i = 0;
+    while i < held.len() {
+        let parent = held[i];
+        for (fid, _, _) in all.iter().filter(|f| f.1 == Some(parent)) {
+            if !held.contains(fid) { held.push(*fid); }
+        }
+        i += 1;
+    }
+    Ok(held)
+}
+
+
 pub struct DB {
     pool: Pool,
     broken_for_test: AtomicBool,
@@ -48,7 +190,7 @@ impl DB {
     pub fn connect_db_url( db_url: &str ) -> DBResult<DB> {
         let manager = ConnectionManager::<SqliteConnection>::new(db_url);
         let pool = Pool::builder().max_size(1).build(manager).context("Failed to build DB pool")?;
-        Ok(DB { pool: pool, broken_for_test: AtomicBool::new(false) })
+        Ok(DB { pool, broken_for_test: AtomicBool::new(false) })
     }
 
     /// Connect to SQLite database with a file path
@@ -81,6 +223,31 @@ impl DB {
         let mut conn = self.conn()?;
         let migr = conn.run_pending_migrations(MIGRATIONS).map_err(|e| anyhow!("Failed to apply migrations: {:?}", e))?;
         for m in migr { tracing::info!("Applied DB migration: {}", m); }
+        drop(conn);
+        self.fill_user_search_keys()
+    }
+
+    /// Compute missing user search keys (they can't be made in SQL migrations)
+    fn fill_user_search_keys(&self) -> EmptyDBResult
+    {
+        use schema::users::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            for (uid, uname) in users.filter(search_key.eq("")).select((user_id, username)).load::<(String, String)>(conn)? {
+                diesel::update(users.filter(user_id.eq(&uid))).set(search_key.eq(models::user_search_key(&uid, &uname))).execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Write a consistent copy of the database into a new file (SQLite `VACUUM INTO`).
+    /// Safe to do while the server is running.
+    ///
+    /// # Arguments
+    /// * `dst` - File to write. Must not exist.
+    pub fn backup_to(&self, dst: &Path) -> EmptyDBResult
+    {
+        let dst = dst.to_str().ok_or(anyhow!("Invalid backup file path"))?;
+        diesel::sql_query(format!("VACUUM INTO '{}'", dst.replace('\'', "''"))).execute(&mut self.conn()?)?;
         Ok(())
     }
 
@@ -110,13 +277,35 @@ impl DB {
     /// 
     /// # Arguments
     /// * `vh` - Hash (unique identifier) of the video
-    pub fn set_video_recompressed(&self, vh: &str) -> EmptyDBResult
+    /// * `encoding` - How the transcoded video was encoded (JSON), if known
+    pub fn set_video_recompressed(&self, vh: &str, encoding: Option<&str>) -> EmptyDBResult
     {
         use schema::videos::dsl::*;
-        diesel::update(videos.filter(video_hash.eq(vh)))
-            .set(recompression_done.eq(Local::now().naive_local()))
-            .execute(&mut self.conn()?)?;
-        Ok(())
+        use schema::proxy_encodings::dsl as pe;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            diesel::update(videos.filter(video_hash.eq(vh)))
+                .set(recompression_done.eq(Local::now().naive_local()))
+                .execute(conn)?;
+            match encoding {
+                Some(enc) => { diesel::replace_into(pe::proxy_encodings).values((pe::video_hash.eq(vh), pe::encoding.eq(enc))).execute(conn)?; },
+                None => { diesel::delete(pe::proxy_encodings.filter(pe::video_hash.eq(vh))).execute(conn)?; },
+            }
+            Ok(())
+        })
+    }
+
+    /// Get how the transcoded video was encoded.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash (unique identifier) of the video
+    ///
+    /// # Returns
+    /// * `Option<String>` - Encoding as JSON, or None if not transcoded (or transcoded before this was recorded)
+    pub fn get_video_proxy_encoding(&self, vh: &str) -> DBResult<Option<String>>
+    {
+        use schema::proxy_encodings::dsl::*;
+        Ok(proxy_encodings.filter(video_hash.eq(vh)).select(encoding).first::<String>(&mut self.conn()?).optional()?)
     }
 
     /// Set thumbnail sheet dimensions for a video.
@@ -150,6 +339,7 @@ impl DB {
     }
 
     /// Delete a video and all its comments from the database.
+    /// Refuses to delete videos that are under legal hold (also through their folder).
     ///     
     /// # Arguments
     /// * `vh` - Hash (unique identifier) of the video
@@ -157,19 +347,230 @@ impl DB {
     /// # Returns
     /// * `EmptyResult`
     /// * `Err(NotFound)` - Video not found
+    /// * `Err(Other)` - Video is under legal hold
     pub fn del_video_and_comments(&self, vh: &str) -> EmptyDBResult
     {
         use schema::videos::dsl as sv;
         use schema::comments::dsl as sc;
         let conn = &mut self.conn()?;
-        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        conn.transaction::<_, DBError, _>(|conn| {
+            if video_is_held(conn, vh)? {
+                return Err(anyhow!("Video is under legal hold").into());
+            }
             diesel::delete(sv::videos.filter(sv::video_hash.eq(vh))).execute(conn)?;
             diesel::delete(sc::comments.filter(sc::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::subtitles::table.filter(schema::subtitles::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::transcript_cues::table.filter(schema::transcript_cues::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::video_labels::table.filter(schema::video_labels::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::video_shots::table.filter(schema::video_shots::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::video_sources::table.filter(schema::video_sources::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::video_imports::table.filter(schema::video_imports::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::proxy_encodings::table.filter(schema::proxy_encodings::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::team_videos::table.filter(schema::team_videos::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::video_links::table.filter(schema::video_links::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::video_views::table.filter(schema::video_views::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::share_links::table.filter(schema::share_links::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::notes::table.filter(schema::notes::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::closed_reviews::table.filter(schema::closed_reviews::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::review_verdicts::table.filter(schema::review_verdicts::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::comment_numbers::table.filter(schema::comment_numbers::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::video_tags::table.filter(schema::video_tags::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::video_custom_fields::table.filter(schema::video_custom_fields::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::tracker_notes::table.filter(schema::tracker_notes::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::tracker_outbox::table.filter(schema::tracker_outbox::video_hash.eq(vh))).execute(conn)?;
+            diesel::delete(schema::review_invitees::table.filter(schema::review_invitees::session_id.eq_any(
+                schema::review_sessions::table.filter(schema::review_sessions::video_hash.eq(vh)).select(schema::review_sessions::id)))).execute(conn)?;
+            diesel::delete(schema::review_sessions::table.filter(schema::review_sessions::video_hash.eq(vh))).execute(conn)?;
             Ok(())
         })?;
         Ok(())
     }
 
+    /// Find (and delete) rows of videos that are no longer in the videos table, in the tables
+    /// `del_video_and_comments` cleans up. Audit log, messages and jobs are history, and are left alone.
+    ///
+    /// # Arguments
+    /// * `dry_run` - Only count the rows
+    ///
+    /// # Returns
+    /// * `Vec<(&str, usize)>` - Table name and number of rows, for tables that had any
+    pub fn del_dangling_video_rows(&self, dry_run: bool) -> DBResult<Vec<(&'static str, usize)>>
+    {
+        macro_rules! dangling {
+            ($conn:ident, $($t:ident),*) => { vec![$({
+                let q = schema::$t::table.filter(schema::$t::video_hash.ne_all(schema::videos::table.select(schema::videos::video_hash)));
+                (stringify!($t), match dry_run {
+                    true => q.count().get_result::<i64>($conn)? as usize,
+                    false => diesel::delete(q).execute($conn)?,
+                })
+            }),*] };
+        }
+        let res = self.conn()?.transaction::<_, DBError, _>(|conn| {
+            Ok(dangling!(conn, comments, subtitles, transcript_cues, video_labels, video_shots, video_sources, video_imports,
+                proxy_encodings, team_videos, video_links, video_views, share_links, notes, closed_reviews, review_verdicts, comment_numbers,
+                video_tags, video_custom_fields, tracker_notes, tracker_outbox, review_sessions))
+        })?;
+        Ok(res.into_iter().filter(|(_, n)| *n > 0).collect())
+    }
+
+    /// Move a video to trash, or restore it. Trashed videos are left out of user's video lists.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash (unique identifier) of the video
+    /// * `trash` - True to mark as trashed (now), false to restore
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Video not found
+    pub fn set_video_trashed(&self, vh: &str, trash: bool) -> EmptyDBResult
+    {
+        use schema::videos::dsl::*;
+        let q = videos.filter(video_hash.eq(vh));
+        let res = match trash {
+            true => diesel::update(q).set(trashed.eq(diesel::dsl::now)).execute(&mut self.conn()?)?,
+            false => diesel::update(q).set(trashed.eq(None::<chrono::NaiveDateTime>)).execute(&mut self.conn()?)?,
+        };
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Apply the same change to several videos in one transaction, so that either all of them change or none.
+    ///
+    /// # Arguments
+    /// * `vhs` - Hashes of the videos, without duplicates
+    /// * `op` - Change to make
+    ///
+    /// # Returns
+    /// * `Err(NotFound)` - Some video doesn't exist (nothing was changed)
+    pub fn apply_video_batch(&self, vhs: &[String], op: &VideoBatchOp) -> EmptyDBResult
+    {
+        use schema::videos::dsl::*;
+        use schema::video_tags::dsl as t;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            let q = videos.filter(video_hash.eq_any(vhs));
+            let n = match op {
+                VideoBatchOp::MoveToFolder(fid) => diesel::update(q).set(folder_id.eq(fid)).execute(conn)?,
+                VideoBatchOp::SetAllowDownload(allow) => diesel::update(q).set(allow_download.eq(allow)).execute(conn)?,
+                VideoBatchOp::Trash => diesel::update(q).set(trashed.eq(diesel::dsl::now)).execute(conn)?,
+                VideoBatchOp::AddTag { tag, user_id } => {
+                    // Keep the case the tag already has on other videos, as `add_video_tag` does
+                    let existing = t::video_tags.filter(t::tag.eq(tag)).select(t::tag).first::<String>(conn).optional()?;
+                    for vh in vhs {
+                        diesel::insert_or_ignore_into(t::video_tags)
+                            .values((t::video_hash.eq(vh), t::tag.eq(existing.as_deref().unwrap_or(tag)), t::added_by.eq(user_id)))
+                            .execute(conn)?;
+                    }
+                    q.count().get_result::<i64>(conn)? as usize
+                },
+                VideoBatchOp::DelTag(tag) => {
+                    diesel::delete(t::video_tags.filter(t::video_hash.eq_any(vhs)).filter(t::tag.eq(tag))).execute(conn)?;
+                    q.count().get_result::<i64>(conn)? as usize
+                },
+            };
+            if n != vhs.len() { return Err(DBError::NotFound()); }
+            Ok(())
+        })
+    }
+
+    /// Get trashed videos, oldest first.
+    ///
+    /// # Arguments
+    /// * `user_id` - Owner of the videos, or None for everyone's
+    pub fn get_trashed_videos(&self, user_id: Option<&str>) -> DBResult<Vec<models::Video>>
+    {
+        use schema::videos::dsl::*;
+        let mut q = videos.filter(trashed.is_not_null()).order(trashed.asc()).into_boxed();
+        if let Some(u) = user_id { q = q.filter(added_by_userid.eq(u)); }
+        Ok(q.load::<models::Video>(&mut self.conn()?)?)
+    }
+
+    /// Set or lift legal hold on a video. Videos under legal hold can't be deleted.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash (unique identifier) of the video
+    /// * `hold` - True to set, false to lift
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Video not found
+    pub fn set_video_legal_hold(&self, vh: &str, hold: bool) -> EmptyDBResult
+    {
+        use schema::videos::dsl::*;
+        let res = diesel::update(videos.filter(video_hash.eq(vh)))
+            .set(legal_hold.eq(hold))
+            .execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Check if a video is under legal hold, by itself or through its folder or any parent folder.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash (unique identifier) of the video
+    ///
+    /// # Returns
+    /// * `bool` - True if held (false for missing videos)
+    pub fn is_video_held(&self, vh: &str) -> DBResult<bool>
+    {
+        Ok(video_is_held(&mut *self.conn()?, vh)?)
+    }
+
+    /// Allow or deny others than owner to download the files of a video.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash (unique identifier) of the video
+    /// * `allow` - True to allow, false to deny
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Video not found
+    pub fn set_video_allow_download(&self, vh: &str, allow: bool) -> EmptyDBResult
+    {
+        use schema::videos::dsl::*;
+        let res = diesel::update(videos.filter(video_hash.eq(vh)))
+            .set(allow_download.eq(allow))
+            .execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Close the review round of a video (see `models::ClosedReview`).
+    ///
+    /// # Arguments
+    /// * `vh` - Hash (unique identifier) of the video
+    /// * `uid` - User ID of who closed it
+    pub fn close_review(&self, vh: &str, uid: &str) -> EmptyDBResult
+    {
+        use schema::closed_reviews::dsl::*;
+        diesel::replace_into(closed_reviews)
+            .values((video_hash.eq(vh), closed_by.eq(uid), closed.eq(chrono::Utc::now().naive_utc())))
+            .execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Reopen a closed review round of a video.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Review was not closed
+    pub fn reopen_review(&self, vh: &str) -> EmptyDBResult
+    {
+        use schema::closed_reviews::dsl::*;
+        let res = diesel::delete(closed_reviews.filter(video_hash.eq(vh))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Get closed review round of a video.
+    ///
+    /// # Returns
+    /// * `Option<models::ClosedReview>` - None if review is open
+    pub fn get_closed_review(&self, vh: &str) -> DBResult<Option<models::ClosedReview>>
+    {
+        use schema::closed_reviews::dsl::*;
+        Ok(closed_reviews.filter(video_hash.eq(vh)).first::<models::ClosedReview>(&mut self.conn()?).optional()?)
+    }
+
     /// Rename a video (title).
     /// 
     /// # Arguments
@@ -189,7 +590,7 @@ impl DB {
         Ok(())
     }
     
-    /// Get all videos for a user.
+    /// Get all videos for a user, except trashed ones.
     /// 
     /// # Arguments
     /// * `user_id` - User ID
@@ -200,10 +601,79 @@ impl DB {
     {
         use models::*;
         use schema::videos::dsl::*;
-        to_db_res(videos.filter(added_by_userid.eq(user_id)).load::<Video>(&mut self.conn()?))
+        to_db_res(videos.filter(added_by_userid.eq(user_id)).filter(trashed.is_null()).load::<Video>(&mut self.conn()?))
+    }
+
+    /// Get a page of user's videos, own and linked ones (see `add_video_link`), except trashed ones.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    /// * `with_tags` - Only videos that have all of these tags (in any case)
+    /// * `sort` - Sort key. Ties are in upload order.
+    /// * `desc` - Sort in descending order
+    /// * `offset` - Number of videos to skip
+    /// * `limit` - Max number of videos to return, None for all
+    ///
+    /// # Returns
+    /// * `(Vec<models::Video>, i64)` - Videos on the page, and total number of matching videos
+    pub fn get_user_videos_page(&self, uid: &str, with_tags: &[String], sort: VideoSort, desc: bool, offset: i64, limit: Option<i64>) -> DBResult<(Vec<models::Video>, i64)>
+    {
+        use schema::videos::dsl::*;
+        use schema::video_links::dsl as vl;
+        use schema::video_tags::dsl as vt;
+        use schema::comments::dsl as c;
+        let conn = &mut self.conn()?;
+        let matching = || {
+            let mut q = videos
+                .filter(trashed.is_null())
+                .filter(added_by_userid.eq(uid).assume_not_null()
+                    .or(video_hash.eq_any(vl::video_links.filter(vl::user_id.eq(uid)).select(vl::video_hash))))
+                .into_boxed();
+            for t in with_tags {
+                q = q.filter(video_hash.eq_any(vt::video_tags.filter(vt::tag.eq(t.clone())).select(vt::video_hash)));
+            }
+            q
+        };
+        let total = matching().count().get_result::<i64>(conn)?;
+
+        let n_comments = c::comments.filter(c::video_hash.eq(video_hash)).count().single_value();
+        let mut q = match (sort, desc) {
+            (VideoSort::Added, false) => matching().order(added_time.asc()),
+            (VideoSort::Added, true) => matching().order(added_time.desc()),
+            (VideoSort::Title, false) => matching().order(lower(title).asc()),
+            (VideoSort::Title, true) => matching().order(lower(title).desc()),
+            (VideoSort::Duration, false) => matching().order(duration.asc()),
+            (VideoSort::Duration, true) => matching().order(duration.desc()),
+            (VideoSort::CommentCount, false) => matching().order(n_comments.asc()),
+            (VideoSort::CommentCount, true) => matching().order(n_comments.desc()),
+        }.then_order_by(id.asc()).offset(offset);
+        if let Some(limit) = limit {
+            q = q.limit(limit);
+        }
+        Ok((q.load::<models::Video>(conn)?, total))
+    }
+
+    /// Get user's (non-trashed) videos that have given content hash (i.e. are byte-identical).
+    ///
+    /// # Arguments
+    /// * `user_id` - User ID
+    /// * `hash` - Content hash of the original file (see `video_pipeline::calc_content_hash`)
+    ///
+    /// # Returns
+    /// * `Vec<models::Video>` - List of Video objects, oldest first
+    pub fn get_user_videos_by_content_hash(&self, user_id: &str, hash: &str) -> DBResult<Vec<models::Video>>
+    {
+        use models::*;
+        use schema::videos::dsl::*;
+        to_db_res(videos
+            .filter(added_by_userid.eq(user_id))
+            .filter(content_hash.eq(hash))
+            .filter(trashed.is_null())
+            .order_by(id.asc())
+            .load::<Video>(&mut self.conn()?))
     }
 
-    /// Get all videos that don't have thumbnails yet.
+    /// Get all videos that don't have thumbnails yet (except trashed ones).
     /// 
     /// # Returns
     /// * `Vec<models::Video>` - List of Video objects
@@ -211,7 +681,7 @@ impl DB {
     {
         use models::*;
         use schema::videos::dsl::*;
-        to_db_res(videos.filter(thumb_sheet_dims.is_null()).load::<Video>(&mut self.conn()?))
+        to_db_res(videos.filter(thumb_sheet_dims.is_null()).filter(trashed.is_null()).load::<Video>(&mut self.conn()?))
     }
 
     /// Add a new comment on a video.
@@ -224,9 +694,32 @@ impl DB {
     pub fn add_comment(&self, cmt: &models::CommentInsert) -> DBResult<i32>
     {
         use schema::comments::dsl::*;
-        let res = diesel::insert_into(comments)
-            .values(cmt).returning(id).get_result(&mut self.conn()?)?;
-        Ok(res)
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            let n = next_comment_number(conn, &cmt.video_hash)?;
+            Ok(diesel::insert_into(comments).values((cmt, number.eq(n))).returning(id).get_result(conn)?)
+        })
+    }
+
+    /// Add a comment imported from elsewhere, keeping its original creation and edit times.
+    ///
+    /// # Arguments
+    /// * `cmt` - Comment object
+    /// * `created_at` - Original creation time
+    /// * `edited_at` - Original edit time, if edited
+    ///
+    /// # Returns
+    /// * `i32` - ID of the new comment
+    pub fn add_imported_comment(&self, cmt: &models::CommentInsert, created_at: chrono::NaiveDateTime, edited_at: Option<chrono::NaiveDateTime>) -> DBResult<i32>
+    {
+        use schema::comments::dsl::*;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            let n = next_comment_number(conn, &cmt.video_hash)?;
+            let new_id = diesel::insert_into(comments).values((cmt, number.eq(n))).returning(id).get_result(conn)?;
+            diesel::update(comments.filter(id.eq(new_id))).set((created.eq(created_at), edited.eq(edited_at))).execute(conn)?;
+            Ok(new_id)
+        })
     }
 
     /// Get a comment from the database.
@@ -258,109 +751,2990 @@ impl DB {
         Ok(comments.filter(video_hash.eq(vh)).load::<Comment>(&mut self.conn()?)?)
     }
 
-    /// Delete a comment from the database.
-    /// 
+    /// Get a page of a video's comments, in the order they were written.
+    ///
     /// # Arguments
-    /// * `comment_id` - ID of the comment
-    /// 
+    /// * `vh` - Hash (unique identifier) of the video
+    /// * `include_internal` - Include internal comments (see `Comment::is_internal`)
+    /// * `offset` - Number of comments to skip
+    /// * `limit` - Max number of comments to return, None for all
+    ///
     /// # Returns
-    /// * `Res<bool>` - True if comment was deleted, false if it was not found
-    pub fn del_comment(&self, comment_id: i32 ) -> DBResult<bool>
+    /// * `(Vec<models::Comment>, i64)` - Comments on the page, and total number of comments
+    pub fn get_video_comments_page(&self, vh: &str, include_internal: bool, offset: i64, limit: Option<i64>) -> DBResult<(Vec<models::Comment>, i64)>
     {
         use schema::comments::dsl::*;
-        let res = diesel::delete(comments.filter(id.eq(comment_id))).execute(&mut self.conn()?)?;
-        Ok(res > 0)
+        let conn = &mut self.conn()?;
+        let matching = || {
+            let mut q = comments.filter(video_hash.eq(vh)).into_boxed();
+            if !include_internal {
+                q = q.filter(visibility.ne(models::comment_visibility::INTERNAL));
+            }
+            q
+        };
+        let total = matching().count().get_result::<i64>(conn)?;
+        let mut q = matching().order(id.asc()).offset(offset);
+        if let Some(limit) = limit {
+            q = q.limit(limit);
+        }
+        Ok((q.load::<models::Comment>(conn)?, total))
     }
 
-    /// Edit a comment (change text).
+    /// Delete a comment from the database.
     /// 
     /// # Arguments
     /// * `comment_id` - ID of the comment
-    /// * `new_comment` - New text of the comment
     /// 
     /// # Returns
-    /// * `Res<bool>` - True if comment was edited, false if it was not found
-    pub fn edit_comment(&self, comment_id: i32, new_comment: &str) -> DBResult<bool>
+    /// * `Res<bool>` - True if comment was deleted, false if it was not found
+    pub fn del_comment(&self, comment_id: i32 ) -> DBResult<bool>
     {
         use schema::comments::dsl::*;
-        let res = diesel::update(comments.filter(id.eq(comment_id)))
-            .set((comment.eq(new_comment), edited.eq(diesel::dsl::now))).execute(&mut self.conn()?)?;
+        use schema::comment_mentions::dsl as m;
+        use schema::comment_reactions::dsl as r;
+        use schema::comment_revisions::dsl as rev;
+        use schema::comment_attachments::dsl as att;
+        let conn = &mut self.conn()?;
+        diesel::delete(att::comment_attachments.filter(att::comment_id.eq(comment_id))).execute(conn)?;
+        diesel::delete(rev::comment_revisions.filter(rev::comment_id.eq(comment_id))).execute(conn)?;
+        diesel::delete(m::comment_mentions.filter(m::comment_id.eq(comment_id))).execute(conn)?;
+        diesel::delete(r::comment_reactions.filter(r::comment_id.eq(comment_id))).execute(conn)?;
+        diesel::delete(schema::tracker_notes::table.filter(schema::tracker_notes::comment_id.eq(comment_id))).execute(conn)?;
+        diesel::delete(schema::tracker_outbox::table.filter(schema::tracker_outbox::comment_id.eq(comment_id))).execute(conn)?;
+        let res = diesel::delete(comments.filter(id.eq(comment_id))).execute(conn)?;
         Ok(res > 0)
     }
 
-    /// Add a new message to the database.
-    /// 
-    /// # Arguments
-    /// * `msg` - Message object
-    /// 
-    /// # Returns
-    /// * `models::Message` - Message object, with ID and timestamp set
-    pub fn add_message(&self, msg: &models::MessageInsert) -> DBResult<models::Message>
+    /// Record a file attached to a comment.
+    pub fn add_comment_attachment(&self, a: &models::CommentAttachmentInsert) -> DBResult<models::CommentAttachment>
     {
-        use schema::messages::dsl::*;
-        assert!(msg.event_name != "progress", "Must not add progress messages to database");
-        let res = diesel::insert_into(messages)
-            .values(msg).get_result(&mut self.conn()?)?;
-        Ok(res)
+        use schema::comment_attachments::dsl::*;
+        Ok(diesel::insert_into(comment_attachments).values(a).get_result(&mut self.conn()?)?)
     }
 
-    /// Get a message from the database.
-    /// 
-    /// # Arguments
-    /// * `msg_id` - ID of the message
-    /// 
+    /// Get a comment attachment by ID.
+    ///
     /// # Returns
-    /// * `models::Message` - Message object
-    /// * `Err(NotFound)` - Message not found
-    pub fn get_message(&self, msg_id: i32) -> DBResult<models::Message>
+    /// * `Err(NotFound)` - No such attachment
+    pub fn get_comment_attachment(&self, aid: i32) -> DBResult<models::CommentAttachment>
     {
-        use models::*;
-        use schema::messages::dsl::*;
-        to_db_res(messages.filter(id.eq(msg_id)).first::<Message>(&mut self.conn()?))
+        use schema::comment_attachments::dsl::*;
+        to_db_res(comment_attachments.filter(id.eq(aid)).first::<models::CommentAttachment>(&mut self.conn()?))
     }
 
-    /// Get all messages for a user.
-    /// 
-    /// # Arguments
-    /// * `uid` - User ID
-    /// 
-    /// # Returns
-    /// * `Vec<models::Message>` - List of Message objects
-    pub fn get_user_messages(&self, uid: &str) -> DBResult<Vec<models::Message>>
+    /// Get files attached to a comment, oldest first.
+    pub fn get_comment_attachments(&self, cid: i32) -> DBResult<Vec<models::CommentAttachment>>
     {
-        use models::*;
-        use schema::messages::dsl::*;
-        Ok(messages.filter(user_id.eq(uid)).load::<Message>(&mut self.conn()?)?)
+        use schema::comment_attachments::dsl::*;
+        Ok(comment_attachments.filter(comment_id.eq(cid)).order(id.asc())
+            .load::<models::CommentAttachment>(&mut self.conn()?)?)
     }
 
-    /// Set the seen status of a message.
-    /// 
-    /// # Arguments
-    /// * `msg_id` - ID of the message
-    /// * `new_status` - New status
-    /// 
+    /// Delete a comment attachment record (not the file).
+    ///
     /// # Returns
-    /// * `Res<bool>` - True if message was found and updated, false if it was not found
-    pub fn set_message_seen(&self, msg_id: i32, new_status: bool) -> DBResult<bool>
+    /// * `bool` - False if there was no such attachment
+    pub fn del_comment_attachment(&self, aid: i32) -> DBResult<bool>
     {
-        use schema::messages::dsl::*;
-        let res = diesel::update(messages.filter(id.eq(msg_id)))
-            .set(seen.eq(new_status)).execute(&mut self.conn()?)?;
-        Ok(res > 0)
+        use schema::comment_attachments::dsl::*;
+        Ok(diesel::delete(comment_attachments.filter(id.eq(aid))).execute(&mut self.conn()?)? > 0)
     }
 
-    /// Delete a message from the database.
-    /// 
-    /// # Arguments
-    /// * `msg_id` - ID of the message
-    /// 
+    /// Add a user's emoji reaction to a comment.
+    ///
     /// # Returns
-    /// * `Res<bool>` - True if message was deleted, false if it was not found
-    pub fn del_message(&self, msg_id: i32) -> DBResult<bool>
+    /// * `bool` - False if user had already reacted with the emoji
+    pub fn add_comment_reaction(&self, cid: i32, uid: &str, reaction: &str) -> DBResult<bool>
     {
-        use schema::messages::dsl::*;
-        let res = diesel::delete(messages.filter(id.eq(msg_id))).execute(&mut self.conn()?)?;
+        use schema::comment_reactions::dsl::*;
+        let res = diesel::insert_into(comment_reactions).values((comment_id.eq(cid), user_id.eq(uid), emoji.eq(reaction)))
+            .on_conflict_do_nothing().execute(&mut self.conn()?)?;
         Ok(res > 0)
     }
 
+    /// Remove a user's emoji reaction from a comment.
+    ///
+    /// # Returns
+    /// * `bool` - False if user hadn't reacted with the emoji
+    pub fn del_comment_reaction(&self, cid: i32, uid: &str, reaction: &str) -> DBResult<bool>
+    {
+        use schema::comment_reactions::dsl::*;
+        let res = diesel::delete(comment_reactions.filter(comment_id.eq(cid)).filter(user_id.eq(uid)).filter(emoji.eq(reaction)))
+            .execute(&mut self.conn()?)?;
+        Ok(res > 0)
+    }
+
+    /// Get reactions to a comment, oldest first.
+    pub fn get_comment_reactions(&self, cid: i32) -> DBResult<Vec<models::CommentReaction>>
+    {
+        use schema::comment_reactions::dsl::*;
+        Ok(comment_reactions.filter(comment_id.eq(cid)).order((created.asc(), user_id.asc()))
+            .load::<models::CommentReaction>(&mut self.conn()?)?)
+    }
+
+    /// Get users mentioned in a comment.
+    ///
+    /// # Returns
+    /// * `Vec<String>` - User IDs, sorted
+    pub fn get_comment_mentions(&self, cid: i32) -> DBResult<Vec<String>>
+    {
+        use schema::comment_mentions::dsl::*;
+        Ok(comment_mentions.filter(comment_id.eq(cid)).order(user_id.asc()).select(user_id).load::<String>(&mut self.conn()?)?)
+    }
+
+    /// Replace users mentioned in a comment.
+    ///
+    /// # Arguments
+    /// * `cid` - Comment ID
+    /// * `uids` - User IDs of the mentioned users
+    pub fn set_comment_mentions(&self, cid: i32, uids: &[String]) -> EmptyDBResult
+    {
+        use schema::comment_mentions::dsl::*;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            diesel::delete(comment_mentions.filter(comment_id.eq(cid))).execute(conn)?;
+            for uid in uids {
+                diesel::insert_into(comment_mentions).values((comment_id.eq(cid), user_id.eq(uid)))
+                    .on_conflict_do_nothing().execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Edit a comment (change text). The old text is kept as a revision.
+    /// 
+    /// # Arguments
+    /// * `comment_id` - ID of the comment
+    /// * `new_comment` - New text of the comment
+    /// * `by_user` - User ID of the editor
+    /// 
+    /// # Returns
+    /// * `Res<bool>` - True if comment was edited, false if it was not found
+    pub fn edit_comment(&self, comment_id: i32, new_comment: &str, by_user: &str) -> DBResult<bool>
+    {
+        use schema::comments::dsl::*;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            match save_comment_revision(conn, comment_id, new_comment, Some(by_user)) {
+                Err(diesel::result::Error::NotFound) => return Ok(false),
+                res => res?,
+            }
+            let res = diesel::update(comments.filter(id.eq(comment_id)))
+                .set((comment.eq(new_comment), edited.eq(diesel::dsl::now))).execute(conn)?;
+            Ok(res > 0)
+        })
+    }
+
+    /// Get earlier texts of a comment, oldest first.
+    pub fn get_comment_revisions(&self, cid: i32) -> DBResult<Vec<models::CommentRevision>>
+    {
+        use schema::comment_revisions::dsl::*;
+        Ok(comment_revisions.filter(comment_id.eq(cid)).order(id.asc())
+            .load::<models::CommentRevision>(&mut self.conn()?)?)
+    }
+
+    /// Add a private note on a video.
+    pub fn add_note(&self, n: &models::NoteInsert) -> DBResult<models::Note>
+    {
+        use schema::notes::dsl::*;
+        Ok(diesel::insert_into(notes).values(n).get_result(&mut self.conn()?)?)
+    }
+
+    /// Set a reviewer's verdict on a video, replacing their earlier one.
+    ///
+    /// # Returns
+    /// * `models::ReviewVerdict` - New verdict
+    pub fn set_review_verdict(&self, v: &models::ReviewVerdictInsert) -> DBResult<models::ReviewVerdict>
+    {
+        use schema::review_verdicts::dsl::*;
+        Ok(diesel::replace_into(review_verdicts).values(v).get_result(&mut self.conn()?)?)
+    }
+
+    /// Get reviewers' verdicts on a video, oldest first.
+    pub fn get_review_verdicts(&self, vh: &str) -> DBResult<Vec<models::ReviewVerdict>>
+    {
+        use schema::review_verdicts::dsl::*;
+        Ok(review_verdicts.filter(video_hash.eq(vh)).order(id.asc()).load::<models::ReviewVerdict>(&mut self.conn()?)?)
+    }
+
+    /// Withdraw a reviewer's verdict on a video.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - User has no verdict on the video
+    pub fn del_review_verdict(&self, vh: &str, uid: &str) -> EmptyDBResult
+    {
+        use schema::review_verdicts::dsl::*;
+        let res = diesel::delete(review_verdicts.filter(video_hash.eq(vh)).filter(user_id.eq(uid))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Get a private note.
+    ///
+    /// # Returns
+    /// * `models::Note`
+    /// * `Err(NotFound)` - No such note
+    pub fn get_note(&self, nid: i32) -> DBResult<models::Note>
+    {
+        use schema::notes::dsl::*;
+        to_db_res(notes.filter(id.eq(nid)).first::<models::Note>(&mut self.conn()?))
+    }
+
+    /// Get user's private notes on a video, oldest first.
+    pub fn get_user_notes(&self, vh: &str, uid: &str) -> DBResult<Vec<models::Note>>
+    {
+        use schema::notes::dsl::*;
+        Ok(notes.filter(video_hash.eq(vh)).filter(user_id.eq(uid)).order(id.asc()).load::<models::Note>(&mut self.conn()?)?)
+    }
+
+    /// Change text of a private note.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - No such note
+    pub fn edit_note(&self, nid: i32, new_text: &str) -> EmptyDBResult
+    {
+        use schema::notes::dsl::*;
+        let res = diesel::update(notes.filter(id.eq(nid)))
+            .set((note.eq(new_text), edited.eq(diesel::dsl::now))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Delete a private note.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - No such note
+    pub fn del_note(&self, nid: i32) -> EmptyDBResult
+    {
+        use schema::notes::dsl::*;
+        let res = diesel::delete(notes.filter(id.eq(nid))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Turn a private note into a public comment (by the note's author), in one transaction.
+    ///
+    /// # Arguments
+    /// * `nid` - ID of the note
+    /// * `username` - Name to show on the comment
+    /// * `vis` - Visibility of the comment (see `models::comment_visibility`)
+    ///
+    /// # Returns
+    /// * `i32` - ID of the new comment
+    /// * `Err(NotFound)` - No such note
+    pub fn publish_note(&self, nid: i32, username: &str, vis: &str) -> DBResult<i32>
+    {
+        use schema::notes::dsl as sn;
+        use schema::comments::dsl as sc;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            let n = to_db_res(sn::notes.filter(sn::id.eq(nid)).first::<models::Note>(conn))?;
+            let cmt = models::CommentInsert {
+                video_hash: n.video_hash, parent_id: None, user_id: n.user_id, username: username.into(),
+                comment: n.note, timecode: n.timecode, timecode_end: None, drawing: None, page: n.page, region: n.region, visibility: vis.into() };
+            let n = next_comment_number(conn, &cmt.video_hash)?;
+            let new_id = diesel::insert_into(sc::comments).values((&cmt, sc::number.eq(n))).returning(sc::id).get_result(conn)?;
+            diesel::delete(sn::notes.filter(sn::id.eq(nid))).execute(conn)?;
+            Ok(new_id)
+        })
+    }
+
+    /// Change visibility of a comment (see `models::comment_visibility`).
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - No such comment
+    pub fn set_comment_visibility(&self, comment_id: i32, vis: &str) -> EmptyDBResult
+    {
+        use schema::comments::dsl::*;
+        let res = diesel::update(comments.filter(id.eq(comment_id))).set(visibility.eq(vis)).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Replace text of a comment with a version edited elsewhere (e.g. on a federation peer).
+    ///
+    /// # Arguments
+    /// * `comment_id` - ID of the comment
+    /// * `new_comment` - New text of the comment
+    /// * `edited_at` - When it was edited
+    ///
+    /// # Returns
+    /// * `Res<bool>` - True if comment was updated, false if it was not found
+    pub fn set_comment_text(&self, comment_id: i32, new_comment: &str, edited_at: chrono::NaiveDateTime) -> DBResult<bool>
+    {
+        use schema::comments::dsl::*;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            match save_comment_revision(conn, comment_id, new_comment, None) {
+                Err(diesel::result::Error::NotFound) => return Ok(false),
+                res => res?,
+            }
+            let res = diesel::update(comments.filter(id.eq(comment_id)))
+                .set((comment.eq(new_comment), edited.eq(edited_at))).execute(conn)?;
+            Ok(res > 0)
+        })
+    }
+
+    /// Add a new message to the database.
+    /// 
+    /// # Arguments
+    /// * `msg` - Message object
+    /// 
+    /// # Returns
+    /// * `models::Message` - Message object, with ID and timestamp set
+    pub fn add_message(&self, msg: &models::MessageInsert) -> DBResult<models::Message>
+    {
+        use schema::messages::dsl::*;
+        assert!(msg.event_name != "progress", "Must not add progress messages to database");
+        let res = diesel::insert_into(messages)
+            .values(msg).get_result(&mut self.conn()?)?;
+        Ok(res)
+    }
+
+    /// Get a message from the database.
+    /// 
+    /// # Arguments
+    /// * `msg_id` - ID of the message
+    /// 
+    /// # Returns
+    /// * `models::Message` - Message object
+    /// * `Err(NotFound)` - Message not found
+    pub fn get_message(&self, msg_id: i32) -> DBResult<models::Message>
+    {
+        use models::*;
+        use schema::messages::dsl::*;
+        to_db_res(messages.filter(id.eq(msg_id)).first::<Message>(&mut self.conn()?))
+    }
+
+    /// Get all messages for a user.
+    /// 
+    /// # Arguments
+    /// * `uid` - User ID
+    /// 
+    /// # Returns
+    /// * `Vec<models::Message>` - List of Message objects
+    pub fn get_user_messages(&self, uid: &str) -> DBResult<Vec<models::Message>>
+    {
+        use models::*;
+        use schema::messages::dsl::*;
+        Ok(messages.filter(user_id.eq(uid)).load::<Message>(&mut self.conn()?)?)
+    }
+
+    /// Set the seen status of a message.
+    /// 
+    /// # Arguments
+    /// * `msg_id` - ID of the message
+    /// * `new_status` - New status
+    /// 
+    /// # Returns
+    /// * `Res<bool>` - True if message was found and updated, false if it was not found
+    pub fn set_message_seen(&self, msg_id: i32, new_status: bool) -> DBResult<bool>
+    {
+        use schema::messages::dsl::*;
+        let res = diesel::update(messages.filter(id.eq(msg_id)))
+            .set(seen.eq(new_status)).execute(&mut self.conn()?)?;
+        Ok(res > 0)
+    }
+
+    /// Delete a message from the database.
+    /// 
+    /// # Arguments
+    /// * `msg_id` - ID of the message
+    /// 
+    /// # Returns
+    /// * `Res<bool>` - True if message was deleted, false if it was not found
+    pub fn del_message(&self, msg_id: i32) -> DBResult<bool>
+    {
+        use schema::messages::dsl::*;
+        let res = diesel::delete(messages.filter(id.eq(msg_id))).execute(&mut self.conn()?)?;
+        Ok(res > 0)
+    }
+
+    /// Delete messages created before given time.
+    ///
+    /// # Returns
+    /// * `usize` - Number of messages deleted
+    pub fn del_messages_before(&self, cutoff: chrono::NaiveDateTime) -> DBResult<usize>
+    {
+        use schema::messages::dsl::*;
+        Ok(diesel::delete(messages.filter(created.lt(cutoff))).execute(&mut self.conn()?)?)
+    }
+
+    /// Add a new pipeline job to the database.
+    ///
+    /// # Arguments
+    /// * `job` - Job object
+    ///
+    /// # Returns
+    /// * `i32` - ID of the new job
+    pub fn add_job(&self, job: &models::JobInsert) -> DBResult<i32>
+    {
+        use schema::jobs::dsl::*;
+        let res = diesel::insert_into(jobs)
+            .values(job).returning(id).get_result(&mut self.conn()?)?;
+        Ok(res)
+    }
+
+    /// Get a job from the database.
+    ///
+    /// # Arguments
+    /// * `job_id` - ID of the job
+    ///
+    /// # Returns
+    /// * `models::Job` - Job object
+    /// * `Err(NotFound)` - Job not found
+    pub fn get_job(&self, job_id: i32) -> DBResult<models::Job>
+    {
+        use models::*;
+        use schema::jobs::dsl::*;
+        to_db_res(jobs.filter(id.eq(job_id)).first::<Job>(&mut self.conn()?))
+    }
+
+    /// Set the status (and details) of a job.
+    ///
+    /// # Arguments
+    /// * `job_id` - ID of the job
+    /// * `new_status` - New status (see `models::job_status`)
+    /// * `new_details` - Free-form details, e.g. error message
+    ///
+    /// # Returns
+    /// * `Res<bool>` - True if job was found and updated, false if it was not found
+    pub fn set_job_status(&self, job_id: i32, new_status: &str, new_details: &str) -> DBResult<bool>
+    {
+        use schema::jobs::dsl::*;
+        let res = diesel::update(jobs.filter(id.eq(job_id)))
+            .set((status.eq(new_status), details.eq(new_details), updated.eq(diesel::dsl::now)))
+            .execute(&mut self.conn()?)?;
+        Ok(res > 0)
+    }
+
+    /// Record an attempt at a job (see `models::JobAttempt`).
+    pub fn add_job_attempt(&self, a: &models::JobAttemptInsert) -> EmptyDBResult
+    {
+        use schema::job_attempts::dsl::*;
+        diesel::insert_into(job_attempts).values(a).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Get the attempts at a job.
+    ///
+    /// # Returns
+    /// * `Vec<models::JobAttempt>` - First attempt first
+    pub fn get_job_attempts(&self, jid: i32) -> DBResult<Vec<models::JobAttempt>>
+    {
+        use schema::job_attempts::dsl::*;
+        Ok(job_attempts.filter(job_id.eq(jid)).order(id.asc()).load::<models::JobAttempt>(&mut self.conn()?)?)
+    }
+
+    /// Get all jobs that are pending or running.
+    ///
+    /// # Returns
+    /// * `Vec<models::Job>` - List of Job objects, oldest first
+    pub fn get_unfinished_jobs(&self) -> DBResult<Vec<models::Job>>
+    {
+        use models::*;
+        use schema::jobs::dsl::*;
+        Ok(jobs.filter(status.eq_any([job_status::PENDING, job_status::RUNNING]))
+            .order(id.asc()).load::<Job>(&mut self.conn()?)?)
+    }
+
+    /// Get most recently finished successful jobs of a stage.
+    ///
+    /// # Arguments
+    /// * `job_stage` - Stage name (see `models::job_stage`)
+    /// * `limit` - Max number of jobs to return
+    ///
+    /// # Returns
+    /// * `Vec<models::Job>` - List of Job objects, newest first
+    pub fn get_recent_done_jobs(&self, job_stage: &str, limit: i64) -> DBResult<Vec<models::Job>>
+    {
+        use models::*;
+        use schema::jobs::dsl::*;
+        Ok(jobs.filter(stage.eq(job_stage)).filter(status.eq(job_status::DONE))
+            .order(id.desc()).limit(limit).load::<Job>(&mut self.conn()?)?)
+    }
+
+    /// Get IDs of unfinished jobs for given stage and source file.
+    ///
+    /// # Arguments
+    /// * `job_stage` - Stage name (see `models::job_stage`)
+    /// * `src` - Source file path
+    ///
+    /// # Returns
+    /// * `Vec<i32>` - List of job IDs
+    pub fn get_unfinished_job_ids_for_src(&self, job_stage: &str, src: &str) -> DBResult<Vec<i32>>
+    {
+        use models::*;
+        use schema::jobs::dsl::*;
+        Ok(jobs.filter(stage.eq(job_stage)).filter(src_file.eq(src))
+            .filter(status.eq_any([job_status::PENDING, job_status::RUNNING]))
+            .select(id).load::<i32>(&mut self.conn()?)?)
+    }
+
+    /// Count pending and running jobs of a user.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    ///
+    /// # Returns
+    /// * `i64` - Number of unfinished jobs
+    pub fn count_unfinished_user_jobs(&self, uid: &str) -> DBResult<i64>
+    {
+        use models::*;
+        use schema::jobs::dsl::*;
+        Ok(jobs.filter(user_id.eq(uid))
+            .filter(status.eq_any([job_status::PENDING, job_status::RUNNING]))
+            .count().get_result(&mut self.conn()?)?)
+    }
+
+    /// Delete jobs that finished before given time, adding them to daily job statistics first.
+    ///
+    /// # Returns
+    /// * `usize` - Number of jobs deleted
+    pub fn aggregate_and_del_jobs_before(&self, cutoff: chrono::NaiveDateTime) -> DBResult<usize>
+    {
+        use models::*;
+        use schema::jobs::dsl::*;
+        use schema::job_stats::dsl as js;
+        use schema::job_attempts::dsl as ja;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            let old = jobs.filter(status.eq_any([job_status::DONE, job_status::FAILED]))
+                .filter(updated.lt(cutoff)).load::<Job>(conn)?;
+            let mut totals: std::collections::BTreeMap<(chrono::NaiveDate, &str, &str), (i32, i64)> = Default::default();
+            for j in &old {
+                let t = totals.entry((j.updated.date(), &j.stage, &j.status)).or_default();
+                t.0 += 1;
+                t.1 += (j.updated - j.created).num_seconds().max(0);
+            }
+            for ((d, stg, sts), (n, secs)) in totals {
+                diesel::insert_into(js::job_stats)
+                    .values(&JobStat { day: d, stage: stg.into(), status: sts.into(), n_jobs: n, total_secs: secs })
+                    .on_conflict((js::day, js::stage, js::status)).do_update()
+                    .set((js::n_jobs.eq(js::n_jobs + n), js::total_secs.eq(js::total_secs + secs)))
+                    .execute(conn)?;
+            }
+            let ids = old.iter().map(|j| j.id).collect::<Vec<_>>();
+            for chunk in ids.chunks(500) {
+                diesel::delete(ja::job_attempts.filter(ja::job_id.eq_any(chunk))).execute(conn)?;
+                diesel::delete(jobs.filter(id.eq_any(chunk))).execute(conn)?;
+            }
+            Ok(old.len())
+        })
+    }
+
+    /// Get daily statistics of finished jobs.
+    ///
+    /// # Arguments
+    /// * `since` - First day to include, or None for all
+    ///
+    /// # Returns
+    /// * `Vec<models::JobStat>` - Oldest day first
+    pub fn get_job_stats(&self, since: Option<chrono::NaiveDate>) -> DBResult<Vec<models::JobStat>>
+    {
+        use schema::job_stats::dsl::*;
+        let mut q = job_stats.order((day.asc(), stage.asc(), status.asc())).into_boxed();
+        if let Some(d) = since { q = q.filter(day.ge(d)); }
+        Ok(q.load::<models::JobStat>(&mut self.conn()?)?)
+    }
+
+    /// Add a finished job to hourly throughput statistics.
+    ///
+    /// # Arguments
+    /// * `finished` - When the job finished. Truncated to the hour.
+    /// * `job_stage` - Stage name (see `models::job_stage`)
+    /// * `video` - Duration of the processed video, in seconds
+    /// * `work` - Time spent processing, in seconds
+    /// * `wait` - Time spent waiting in queue, in seconds
+    pub fn add_throughput(&self, finished: chrono::NaiveDateTime, job_stage: &str, video: f64, work: f64, wait: f64) -> EmptyDBResult
+    {
+        use schema::throughput_stats::dsl::*;
+        use diesel::upsert::excluded;
+        use chrono::Timelike;
+        let h = finished.date().and_hms_opt(finished.hour(), 0, 0).unwrap_or(finished);
+        diesel::insert_into(throughput_stats)
+            .values(&models::ThroughputStat { hour: h, stage: job_stage.into(), n_jobs: 1, video_secs: video, work_secs: work, wait_secs: wait })
+            .on_conflict((hour, stage)).do_update()
+            .set((n_jobs.eq(n_jobs + 1), video_secs.eq(video_secs + excluded(video_secs)),
+                work_secs.eq(work_secs + excluded(work_secs)), wait_secs.eq(wait_secs + excluded(wait_secs))))
+            .execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Get hourly throughput statistics.
+    ///
+    /// # Arguments
+    /// * `job_stage` - Stage name (see `models::job_stage`)
+    /// * `since` - First hour to include, or None for all
+    ///
+    /// # Returns
+    /// * `Vec<models::ThroughputStat>` - Oldest hour first
+    pub fn get_throughput_stats(&self, job_stage: &str, since: Option<chrono::NaiveDateTime>) -> DBResult<Vec<models::ThroughputStat>>
+    {
+        use schema::throughput_stats::dsl::*;
+        let mut q = throughput_stats.filter(stage.eq(job_stage)).order(hour.asc()).into_boxed();
+        if let Some(t) = since { q = q.filter(hour.ge(t)); }
+        Ok(q.load::<models::ThroughputStat>(&mut self.conn()?)?)
+    }
+
+    /// Get processing priority of a user.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    ///
+    /// # Returns
+    /// * `i32` - Priority (0 if not set)
+    pub fn get_user_priority(&self, uid: &str) -> DBResult<i32>
+    {
+        use schema::user_priorities::dsl::*;
+        Ok(user_priorities.filter(user_id.eq(uid)).select(priority)
+            .first::<i32>(&mut self.conn()?).optional()?.unwrap_or(0))
+    }
+
+    /// Set processing priority of a user. Priority 0 (the default) removes the setting.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    /// * `new_priority` - New priority. Higher is processed first.
+    pub fn set_user_priority(&self, uid: &str, new_priority: i32) -> EmptyDBResult
+    {
+        use schema::user_priorities::dsl::*;
+        let conn = &mut self.conn()?;
+        if new_priority == 0 {
+            diesel::delete(user_priorities.filter(user_id.eq(uid))).execute(conn)?;
+        } else {
+            diesel::replace_into(user_priorities)
+                .values(&models::UserPriority { user_id: uid.to_string(), priority: new_priority })
+                .execute(conn)?;
+        }
+        Ok(())
+    }
+
+    /// Get all users that have a non-default processing priority.
+    ///
+    /// # Returns
+    /// * `Vec<models::UserPriority>` - List of UserPriority objects, highest priority first
+    pub fn get_user_priorities(&self) -> DBResult<Vec<models::UserPriority>>
+    {
+        use models::*;
+        use schema::user_priorities::dsl::*;
+        Ok(user_priorities.order((priority.desc(), user_id.asc())).load::<UserPriority>(&mut self.conn()?)?)
+    }
+
+    /// Record that a user connected: add them, or update their name and last seen time.
+    ///
+    /// # Returns
+    /// * `models::User` - User with admin's settings (admin flag, disabled)
+    pub fn touch_user(&self, uid: &str, uname: &str) -> DBResult<models::User>
+    {
+        use schema::users::dsl::*;
+        let conn = &mut self.conn()?;
+        diesel::insert_into(users).values(&models::UserInsert::new(uid, uname))
+            .on_conflict(user_id).do_update().set((username.eq(uname), search_key.eq(models::user_search_key(uid, uname)))).execute(conn)?;
+        diesel::update(users.filter(user_id.eq(uid))).set(last_seen.eq(diesel::dsl::now)).execute(conn)?;
+        Ok(users.filter(user_id.eq(uid)).first::<models::User>(conn)?)
+    }
+
+    /// Mark that user has got the first login onboarding. Only the first call for a user succeeds.
+    ///
+    /// # Returns
+    /// * `bool` - True if user wasn't onboarded before (i.e. this is their first login)
+    pub fn mark_user_onboarded(&self, uid: &str) -> DBResult<bool>
+    {
+        use schema::users::dsl::*;
+        let res = diesel::update(users.filter(user_id.eq(uid)).filter(onboarded.is_null()))
+            .set(onboarded.eq(diesel::dsl::now)).execute(&mut self.conn()?)?;
+        Ok(res > 0)
+    }
+
+    /// Get a user.
+    ///
+    /// # Returns
+    /// * `models::User`
+    /// * `Err(NotFound)` - User has never connected (and admin hasn't changed their settings)
+    pub fn get_user(&self, uid: &str) -> DBResult<models::User>
+    {
+        use schema::users::dsl::*;
+        to_db_res(users.filter(user_id.eq(uid)).first::<models::User>(&mut self.conn()?))
+    }
+
+    /// Get all known users.
+    pub fn get_users(&self) -> DBResult<Vec<models::User>>
+    {
+        use schema::users::dsl::*;
+        Ok(users.order(user_id.asc()).load::<models::User>(&mut self.conn()?)?)
+    }
+
+    /// Find users by name or ID, ignoring case and diacritics ("jarvinen" finds "Järvinen").
+    /// Substring matches come first (those at the start of a word before others), then
+    /// similarly spelled ones (by trigrams, for typos). Disabled users are left out.
+    ///
+    /// # Arguments
+    /// * `query` - Text to look for
+    /// * `limit` - Max number of users
+    pub fn search_users(&self, query: &str, limit: usize) -> DBResult<Vec<models::User>>
+    {
+        use schema::users::dsl::*;
+        let q = models::normalize_for_search(query);
+        if q.is_empty() { return Ok(vec![]); }
+        let conn = &mut self.conn()?;
+        let mut found = users.filter(disabled.eq(false)).filter(search_key.like(like_pattern(&q)).escape('\\'))
+            .load::<models::User>(conn)?;
+        let at_word_start = |k: &str| k.starts_with(&q) || k.contains(&format!(" {}", q));
+        found.sort_by_cached_key(|u| (!at_word_start(&u.search_key), u.username.to_lowercase()));
+        found.truncate(limit);
+
+        if found.len() < limit && q.chars().count() >= MIN_FUZZY_QUERY_LEN {
+            let q_trigrams = trigrams(&q);
+            let mut similar = users.filter(disabled.eq(false)).load::<models::User>(conn)?.into_iter()
+                .filter(|u| !found.iter().any(|f| f.user_id == u.user_id))
+                .map(|u| (q_trigrams.intersection(&trigrams(&u.search_key)).count() as f32 / q_trigrams.len() as f32, u))
+                .filter(|(score, _)| *score >= MIN_TRIGRAM_SIMILARITY)
+                .collect::<Vec<_>>();
+            similar.sort_by(|(a, ua), (b, ub)| b.total_cmp(a).then_with(|| ua.username.to_lowercase().cmp(&ub.username.to_lowercase())));
+            found.extend(similar.into_iter().map(|(_, u)| u).take(limit - found.len()));
+        }
+        Ok(found)
+    }
+
+    /// Check if a user is an admin. Unknown users are not.
+    pub fn is_user_admin(&self, uid: &str) -> DBResult<bool>
+    {
+        use schema::users::dsl::*;
+        Ok(users.filter(user_id.eq(uid)).select(is_admin).first::<bool>(&mut self.conn()?).optional()?.unwrap_or(false))
+    }
+
+    /// Check if a user has been disabled by admin. Unknown users are not.
+    pub fn is_user_disabled(&self, uid: &str) -> DBResult<bool>
+    {
+        use schema::users::dsl::*;
+        Ok(users.filter(user_id.eq(uid)).select(disabled).first::<bool>(&mut self.conn()?).optional()?.unwrap_or(false))
+    }
+
+    /// Change admin's settings for a user. Adds the user if they haven't connected yet.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    /// * `admin` - New value of admin flag, or None to keep it
+    /// * `disable` - New value of disabled flag, or None to keep it
+    pub fn set_user_flags(&self, uid: &str, admin: Option<bool>, disable: Option<bool>) -> EmptyDBResult
+    {
+        use schema::users::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            diesel::insert_or_ignore_into(users).values(&models::UserInsert::new(uid, uid)).execute(conn)?;
+            if let Some(a) = admin { diesel::update(users.filter(user_id.eq(uid))).set(is_admin.eq(a)).execute(conn)?; }
+            if let Some(d) = disable { diesel::update(users.filter(user_id.eq(uid))).set(disabled.eq(d)).execute(conn)?; }
+            Ok(())
+        })
+    }
+
+    /// Get IDs of all users that own videos.
+    pub fn get_video_owners(&self) -> DBResult<Vec<String>>
+    {
+        use schema::videos::dsl::*;
+        Ok(videos.filter(added_by_userid.is_not_null()).select(added_by_userid.assume_not_null()).distinct()
+            .order(added_by_userid.asc()).load::<String>(&mut self.conn()?)?)
+    }
+
+    /// Get all videos (including trashed ones), optionally of one user only.
+    ///
+    /// # Arguments
+    /// * `owner` - User ID, or None for everyone's
+    pub fn get_all_videos(&self, owner: Option<&str>) -> DBResult<Vec<models::Video>>
+    {
+        use schema::videos::dsl::*;
+        let mut q = videos.order(id.asc()).into_boxed();
+        if let Some(o) = owner { q = q.filter(added_by_userid.eq(o)); }
+        Ok(q.load::<models::Video>(&mut self.conn()?)?)
+    }
+
+    /// Give a video to another user. It's moved to their root folder.
+    ///
+    /// # Arguments
+    /// * `vh` - Video hash
+    /// * `uid` - New owner's user ID
+    /// * `uname` - New owner's username
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Video not found
+    pub fn set_video_owner(&self, vh: &str, uid: &str, uname: &str) -> EmptyDBResult
+    {
+        use schema::videos::dsl::*;
+        let res = diesel::update(videos.filter(video_hash.eq(vh)))
+            .set((added_by_userid.eq(uid), added_by_username.eq(uname), folder_id.eq(None::<i32>)))
+            .execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Add a new team.
+    ///
+    /// # Returns
+    /// * `models::Team` - The new team
+    /// * `Err` - Name is taken
+    pub fn add_team(&self, team_name: &str) -> DBResult<models::Team>
+    {
+        use schema::teams::dsl::*;
+        Ok(diesel::insert_into(teams).values(&models::TeamInsert { name: team_name.into() })
+            .get_result::<models::Team>(&mut self.conn()?)?)
+    }
+
+    /// Get a team.
+    ///
+    /// # Returns
+    /// * `models::Team`
+    /// * `Err(NotFound)` - Team not found
+    pub fn get_team(&self, tid: i32) -> DBResult<models::Team>
+    {
+        use schema::teams::dsl::*;
+        to_db_res(teams.filter(id.eq(tid)).first::<models::Team>(&mut self.conn()?))
+    }
+
+    /// Get all teams, or those a user is a member of.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID, or None for all teams
+    pub fn get_teams(&self, uid: Option<&str>) -> DBResult<Vec<models::Team>>
+    {
+        use schema::teams::dsl::*;
+        use schema::team_members::dsl as tm;
+        let mut q = teams.order(name.asc()).into_boxed();
+        if let Some(u) = uid {
+            q = q.filter(id.eq_any(tm::team_members.filter(tm::user_id.eq(u)).select(tm::team_id)));
+        }
+        Ok(q.load::<models::Team>(&mut self.conn()?)?)
+    }
+
+    /// Delete a team, its memberships, shares and feature flag overrides. Shared videos and folders are not affected.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Team not found
+    pub fn del_team(&self, tid: i32) -> EmptyDBResult
+    {
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            if diesel::delete(schema::teams::table.filter(schema::teams::id.eq(tid))).execute(conn)? == 0 {
+                return Err(DBError::NotFound());
+            }
+            diesel::delete(schema::team_members::table.filter(schema::team_members::team_id.eq(tid))).execute(conn)?;
+            diesel::delete(schema::team_videos::table.filter(schema::team_videos::team_id.eq(tid))).execute(conn)?;
+            diesel::delete(schema::team_folders::table.filter(schema::team_folders::team_id.eq(tid))).execute(conn)?;
+            diesel::delete(schema::feature_overrides::table.filter(schema::feature_overrides::target.eq(format!("team:{tid}")))).execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Get members of a team.
+    pub fn get_team_members(&self, tid: i32) -> DBResult<Vec<models::TeamMember>>
+    {
+        use schema::team_members::dsl::*;
+        Ok(team_members.filter(team_id.eq(tid)).order(user_id.asc()).load::<models::TeamMember>(&mut self.conn()?)?)
+    }
+
+    /// Get a user's membership in a team.
+    ///
+    /// # Returns
+    /// * `models::TeamMember`
+    /// * `Err(NotFound)` - User is not a member
+    pub fn get_team_member(&self, tid: i32, uid: &str) -> DBResult<models::TeamMember>
+    {
+        use schema::team_members::dsl::*;
+        to_db_res(team_members.filter(team_id.eq(tid)).filter(user_id.eq(uid)).first::<models::TeamMember>(&mut self.conn()?))
+    }
+
+    /// Add a member to a team, or change their team admin status.
+    pub fn set_team_member(&self, m: &models::TeamMember) -> EmptyDBResult
+    {
+        use schema::team_members::dsl::*;
+        diesel::replace_into(team_members).values(m).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Remove a member from a team.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - User was not a member
+    pub fn del_team_member(&self, tid: i32, uid: &str) -> EmptyDBResult
+    {
+        use schema::team_members::dsl::*;
+        let res = diesel::delete(team_members.filter(team_id.eq(tid)).filter(user_id.eq(uid))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Share a video with a team. Sharing again is a no-op.
+    pub fn add_team_video(&self, tv: &models::TeamVideo) -> EmptyDBResult
+    {
+        use schema::team_videos::dsl::*;
+        diesel::insert_or_ignore_into(team_videos).values(tv).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Stop sharing a video with a team.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Video was not shared with the team
+    pub fn del_team_video(&self, tid: i32, vh: &str) -> EmptyDBResult
+    {
+        use schema::team_videos::dsl::*;
+        let res = diesel::delete(team_videos.filter(team_id.eq(tid)).filter(video_hash.eq(vh))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Share a folder (and its subfolders) with a team. Sharing again is a no-op.
+    pub fn add_team_folder(&self, tf: &models::TeamFolder) -> EmptyDBResult
+    {
+        use schema::team_folders::dsl::*;
+        diesel::insert_or_ignore_into(team_folders).values(tf).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Stop sharing a folder with a team.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Folder was not shared with the team
+    pub fn del_team_folder(&self, tid: i32, fid: i32) -> EmptyDBResult
+    {
+        use schema::team_folders::dsl::*;
+        let res = diesel::delete(team_folders.filter(team_id.eq(tid)).filter(folder_id.eq(fid))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Get what's been shared with a team.
+    ///
+    /// # Returns
+    /// * `(Vec<models::TeamVideo>, Vec<models::TeamFolder>)` - Directly shared videos, and shared folders
+    pub fn get_team_shares(&self, tid: i32) -> DBResult<(Vec<models::TeamVideo>, Vec<models::TeamFolder>)>
+    {
+        let conn = &mut self.conn()?;
+        let tv = schema::team_videos::table.filter(schema::team_videos::team_id.eq(tid)).load::<models::TeamVideo>(conn)?;
+        let tf = schema::team_folders::table.filter(schema::team_folders::team_id.eq(tid)).load::<models::TeamFolder>(conn)?;
+        Ok((tv, tf))
+    }
+
+    /// Get all videos shared with a team: directly shared ones, and those in shared folders
+    /// and their subfolders. Trashed videos are left out.
+    pub fn get_team_videos(&self, tid: i32) -> DBResult<Vec<models::Video>>
+    {
+        use schema::videos::dsl::*;
+        use schema::folders::dsl as sf;
+        let (tv, tf) = self.get_team_shares(tid)?;
+        let conn = &mut self.conn()?;
+        let mut fids = tf.iter().map(|f| f.folder_id).collect::<Vec<_>>();
+        let mut i = 0;
+        while i < fids.len() {
+            for sub in sf::folders.filter(sf::parent_id.eq(fids[i])).select(sf::id).load::<i32>(conn)? {
+                if !fids.contains(&sub) { fids.push(sub); }
+            }
+            i += 1;
+        }
+        let hashes = tv.into_iter().map(|v| v.video_hash).collect::<Vec<_>>();
+        Ok(videos.filter(video_hash.eq_any(hashes).or(folder_id.eq_any(fids).assume_not_null()))
+            .filter(trashed.is_null()).order(id.asc()).load::<models::Video>(conn)?)
+    }
+
+    /// Check if a video has been shared with any team the user is a member of,
+    /// directly or in a (parent) folder.
+    pub fn is_video_shared_with_user(&self, v: &models::Video, uid: &str) -> DBResult<bool>
+    {
+        use schema::team_members::dsl as tm;
+        use schema::team_videos::dsl as tv;
+        use schema::team_folders::dsl as tf;
+        use schema::folders::dsl as sf;
+        let conn = &mut self.conn()?;
+        let tids = tm::team_members.filter(tm::user_id.eq(uid)).select(tm::team_id).load::<i32>(conn)?;
+        if tids.is_empty() {
+            return Ok(false);
+        }
+        if tv::team_videos.filter(tv::video_hash.eq(&v.video_hash)).filter(tv::team_id.eq_any(&tids))
+                .count().get_result::<i64>(conn)? > 0 {
+            return Ok(true);
+        }
+        let mut fid = v.folder_id;
+        let mut seen = vec![];
+        while let Some(f) = fid.filter(|f| !seen.contains(f)) {
+            if tf::team_folders.filter(tf::folder_id.eq(f)).filter(tf::team_id.eq_any(&tids))
+                    .count().get_result::<i64>(conn)? > 0 {
+                return Ok(true);
+            }
+            seen.push(f);
+            fid = sf::folders.filter(sf::id.eq(f)).select(sf::parent_id).first::<Option<i32>>(conn).optional()?.flatten();
+        }
+        Ok(false)
+    }
+
+    /// Find other users' videos that are byte-identical to a file and shared with the user's teams.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    /// * `hash` - Content hash of the file (see `video_pipeline::calc_content_hash`)
+    pub fn get_shared_videos_by_content_hash(&self, uid: &str, hash: &str) -> DBResult<Vec<models::Video>>
+    {
+        use schema::videos::dsl::*;
+        let candidates = videos.filter(content_hash.eq(hash)).filter(trashed.is_null())
+            .filter(added_by_userid.ne(uid).or(added_by_userid.is_null()))
+            .order(id.asc()).load::<models::Video>(&mut self.conn()?)?;
+        let mut res = vec![];
+        for v in candidates {
+            if self.is_video_shared_with_user(&v, uid)? { res.push(v); }
+        }
+        Ok(res)
+    }
+
+    /// Add an upload that waits for user's choice (link or copy).
+    pub fn add_pending_upload(&self, pu: &models::PendingUploadInsert) -> DBResult<models::PendingUpload>
+    {
+        use schema::pending_uploads::dsl::*;
+        Ok(diesel::insert_into(pending_uploads).values(pu).get_result(&mut self.conn()?)?)
+    }
+
+    /// Get a pending upload.
+    ///
+    /// # Returns
+    /// * `models::PendingUpload`
+    /// * `Err(NotFound)` - Not found (already resolved or expired)
+    pub fn get_pending_upload(&self, pid: i32) -> DBResult<models::PendingUpload>
+    {
+        use schema::pending_uploads::dsl::*;
+        to_db_res(pending_uploads.filter(id.eq(pid)).first::<models::PendingUpload>(&mut self.conn()?))
+    }
+
+    /// Get pending uploads of a user, or everyone's.
+    ///
+    /// # Returns
+    /// * `Vec<models::PendingUpload>` - Oldest first
+    pub fn get_pending_uploads(&self, uid: Option<&str>) -> DBResult<Vec<models::PendingUpload>>
+    {
+        use schema::pending_uploads::dsl::*;
+        let mut q = pending_uploads.order(id.asc()).into_boxed();
+        if let Some(u) = uid { q = q.filter(user_id.eq(u)); }
+        Ok(q.load::<models::PendingUpload>(&mut self.conn()?)?)
+    }
+
+    /// Delete a pending upload (from DB only, not the file).
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Not found (resolved already)
+    pub fn del_pending_upload(&self, pid: i32) -> EmptyDBResult
+    {
+        use schema::pending_uploads::dsl::*;
+        let res = diesel::delete(pending_uploads.filter(id.eq(pid))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// List another user's video among user's own. Linking again is a no-op.
+    pub fn add_video_link(&self, uid: &str, vh: &str) -> EmptyDBResult
+    {
+        use schema::video_links::dsl::*;
+        diesel::insert_or_ignore_into(video_links).values((user_id.eq(uid), video_hash.eq(vh))).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Tag a video. Tagging again (in any case) is a no-op. If other videos have the tag already,
+    /// their spelling (case) is used, to keep it the same everywhere.
+    ///
+    /// # Returns
+    /// * `bool` - True if the tag was added, false if video had it already
+    pub fn add_video_tag(&self, vh: &str, t: &str, uid: &str) -> DBResult<bool>
+    {
+        use schema::video_tags::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            let existing = video_tags.filter(tag.eq(t)).select(tag).first::<String>(conn).optional()?;
+            let res = diesel::insert_or_ignore_into(video_tags)
+                .values((video_hash.eq(vh), tag.eq(existing.as_deref().unwrap_or(t)), added_by.eq(uid)))
+                .execute(conn)?;
+            Ok(res > 0)
+        })
+    }
+
+    /// Remove a tag (in any case) from a video.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Video didn't have the tag
+    pub fn del_video_tag(&self, vh: &str, t: &str) -> EmptyDBResult
+    {
+        use schema::video_tags::dsl::*;
+        let res = diesel::delete(video_tags.filter(video_hash.eq(vh)).filter(tag.eq(t))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Get tags of given videos, ordered by video and tag.
+    pub fn get_video_tags(&self, vhs: &[String]) -> DBResult<Vec<models::VideoTag>>
+    {
+        use schema::video_tags::dsl::*;
+        Ok(video_tags.filter(video_hash.eq_any(vhs)).order((video_hash.asc(), tag.asc())).load::<models::VideoTag>(&mut self.conn()?)?)
+    }
+
+    /// Find tags that start with given text, for autocompletion.
+    ///
+    /// # Arguments
+    /// * `prefix` - Start of the tag (case-insensitive)
+    /// * `vhs` - Only tags of these videos, or None for all
+    /// * `limit` - Max number of tags
+    ///
+    /// # Returns
+    /// * `Vec<(String, i64)>` - Tags and number of videos with them, most used first
+    pub fn search_video_tags(&self, prefix: &str, vhs: Option<&[String]>, limit: i64) -> DBResult<Vec<(String, i64)>>
+    {
+        use schema::video_tags::dsl::*;
+        let pattern = format!("{}%", prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        let mut q = video_tags.filter(tag.like(pattern).escape('\\'))
+            .group_by(tag).select((tag, diesel::dsl::count_star()))
+            .order((diesel::dsl::count_star().desc(), tag.asc())).limit(limit)
+            .into_boxed();
+        if let Some(vhs) = vhs { q = q.filter(video_hash.eq_any(vhs)); }
+        Ok(q.load::<(String, i64)>(&mut self.conn()?)?)
+    }
+
+    /// Remove a linked video from user's list.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Video wasn't linked
+    pub fn del_video_link(&self, uid: &str, vh: &str) -> EmptyDBResult
+    {
+        use schema::video_links::dsl::*;
+        let res = diesel::delete(video_links.filter(user_id.eq(uid)).filter(video_hash.eq(vh))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Get videos linked to user's list (see `add_video_link`), except trashed ones.
+    pub fn get_user_linked_videos(&self, uid: &str) -> DBResult<Vec<models::Video>>
+    {
+        use schema::videos::dsl::*;
+        use schema::video_links::dsl as vl;
+        Ok(videos.filter(video_hash.eq_any(vl::video_links.filter(vl::user_id.eq(uid)).select(vl::video_hash)))
+            .filter(trashed.is_null()).order(id.asc()).load::<models::Video>(&mut self.conn()?)?)
+    }
+
+    /// Record how far a user has read a video's comments. Never moves backwards, so
+    /// positions reported out of order (e.g. from several tabs) don't make comments unread again.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    /// * `vh` - Hash of the video
+    /// * `until` - Comments created at or before this are read
+    ///
+    /// # Returns
+    /// * `NaiveDateTime` - Read position after the update
+    pub fn set_video_seen(&self, uid: &str, vh: &str, until: chrono::NaiveDateTime) -> DBResult<chrono::NaiveDateTime>
+    {
+        use schema::video_views::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            let old = video_views.filter(user_id.eq(uid)).filter(video_hash.eq(vh)).select(seen_until).first::<chrono::NaiveDateTime>(conn).optional()?;
+            let new = old.map_or(until, |old| old.max(until));
+            diesel::replace_into(video_views).values((user_id.eq(uid), video_hash.eq(vh), seen_until.eq(new))).execute(conn)?;
+            Ok(new)
+        })
+    }
+
+    /// Get how far a user has read a video's comments.
+    ///
+    /// # Returns
+    /// * `Option<NaiveDateTime>` - Read position, or None if user hasn't read any
+    pub fn get_video_seen(&self, uid: &str, vh: &str) -> DBResult<Option<chrono::NaiveDateTime>>
+    {
+        use schema::video_views::dsl::*;
+        Ok(video_views.filter(user_id.eq(uid)).filter(video_hash.eq(vh)).select(seen_until)
+            .first::<chrono::NaiveDateTime>(&mut self.conn()?).optional()?)
+    }
+
+    /// Count comments a user hasn't read yet: those by other users, created after user's
+    /// read position (see `set_video_seen`). All of them, if user hasn't read any.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    /// * `vhs` - Hashes of the videos
+    ///
+    /// # Returns
+    /// * `HashMap<String, (i64, i64)>` - Video hash -> (unread comments, how many of them are internal).
+    ///   Videos without unread comments are left out.
+    pub fn get_unread_comment_counts(&self, uid: &str, vhs: &[String]) -> DBResult<std::collections::HashMap<String, (i64, i64)>>
+    {
+        use schema::comments::dsl as c;
+        use schema::video_views::dsl as vv;
+        let conn = &mut self.conn()?;
+        let seen = vv::video_views.filter(vv::user_id.eq(uid)).filter(vv::video_hash.eq_any(vhs))
+            .select((vv::video_hash, vv::seen_until)).load::<(String, chrono::NaiveDateTime)>(conn)?
+            .into_iter().collect::<std::collections::HashMap<_, _>>();
+        let mut res = std::collections::HashMap::<String, (i64, i64)>::new();
+        for (vh, created, vis) in c::comments.filter(c::video_hash.eq_any(vhs)).filter(c::user_id.ne(uid))
+            .select((c::video_hash, c::created, c::visibility)).load::<(String, chrono::NaiveDateTime, String)>(conn)?
+        {
+            if seen.get(&vh).is_none_or(|s| created > *s) {
+                let cnt = res.entry(vh).or_default();
+                cnt.0 += 1;
+                if vis == models::comment_visibility::INTERNAL { cnt.1 += 1; }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Add a public share link for a video.
+    pub fn add_share_link(&self, link: &models::ShareLinkInsert) -> DBResult<models::ShareLink>
+    {
+        use schema::share_links::dsl::*;
+        Ok(diesel::insert_into(share_links).values(link).get_result(&mut self.conn()?)?)
+    }
+
+    /// Get a share link by its ID.
+    ///
+    /// # Returns
+    /// * `models::ShareLink`
+    /// * `Err(NotFound)` - No such link (or revoked)
+    pub fn get_share_link(&self, lid: i32) -> DBResult<models::ShareLink>
+    {
+        use schema::share_links::dsl::*;
+        to_db_res(share_links.filter(id.eq(lid)).first::<models::ShareLink>(&mut self.conn()?))
+    }
+
+    /// Get a share link by its token. Expiry is not checked here.
+    ///
+    /// # Returns
+    /// * `models::ShareLink`
+    /// * `Err(NotFound)` - No such link (or revoked)
+    pub fn get_share_link_by_token(&self, tok: &str) -> DBResult<models::ShareLink>
+    {
+        use schema::share_links::dsl::*;
+        to_db_res(share_links.filter(token.eq(tok)).first::<models::ShareLink>(&mut self.conn()?))
+    }
+
+    /// Get share links of a video, oldest first.
+    pub fn get_share_links(&self, vh: &str) -> DBResult<Vec<models::ShareLink>>
+    {
+        use schema::share_links::dsl::*;
+        Ok(share_links.filter(video_hash.eq(vh)).order(id.asc()).load::<models::ShareLink>(&mut self.conn()?)?)
+    }
+
+    /// Delete (revoke) a share link.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - No such link
+    pub fn del_share_link(&self, lid: i32) -> EmptyDBResult
+    {
+        use schema::share_links::dsl::*;
+        let res = diesel::delete(share_links.filter(id.eq(lid))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Delete share links that expired before given time.
+    ///
+    /// # Returns
+    /// * `usize` - Number of links deleted
+    pub fn del_share_links_expired_before(&self, t: chrono::NaiveDateTime) -> DBResult<usize>
+    {
+        use schema::share_links::dsl::*;
+        Ok(diesel::delete(share_links.filter(expires.lt(t))).execute(&mut self.conn()?)?)
+    }
+
+    /// Add a share link guest identity.
+    pub fn add_guest(&self, g: &models::GuestInsert) -> DBResult<models::Guest>
+    {
+        use schema::guests::dsl::*;
+        Ok(diesel::insert_into(guests).values(g).get_result(&mut self.conn()?)?)
+    }
+
+    /// Get a guest identity.
+    ///
+    /// # Returns
+    /// * `models::Guest`
+    /// * `Err(NotFound)` - No such guest
+    pub fn get_guest(&self, gid: i32) -> DBResult<models::Guest>
+    {
+        use schema::guests::dsl::*;
+        to_db_res(guests.filter(id.eq(gid)).first::<models::Guest>(&mut self.conn()?))
+    }
+
+    /// Get a guest identity by its secret key.
+    ///
+    /// # Returns
+    /// * `models::Guest`
+    /// * `Err(NotFound)` - No such guest
+    pub fn get_guest_by_key(&self, k: &str) -> DBResult<models::Guest>
+    {
+        use schema::guests::dsl::*;
+        to_db_res(guests.filter(key.eq(k)).first::<models::Guest>(&mut self.conn()?))
+    }
+
+    /// Get guests of a share link, or all of them. Oldest first.
+    pub fn get_guests(&self, link_id: Option<i32>) -> DBResult<Vec<models::Guest>>
+    {
+        use schema::guests::dsl::*;
+        let mut q = guests.order(id.asc()).into_boxed();
+        if let Some(l) = link_id { q = q.filter(share_link_id.eq(l)); }
+        Ok(q.load::<models::Guest>(&mut self.conn()?)?)
+    }
+
+    /// Change display name of a guest.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - No such guest
+    pub fn set_guest_name(&self, gid: i32, new_name: &str) -> EmptyDBResult
+    {
+        use schema::guests::dsl::*;
+        let res = diesel::update(guests.filter(id.eq(gid))).set(name.eq(new_name)).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Count comments written by a user.
+    pub fn count_user_comments(&self, uid: &str) -> DBResult<i64>
+    {
+        use schema::comments::dsl::*;
+        Ok(comments.filter(user_id.eq(uid)).count().get_result(&mut self.conn()?)?)
+    }
+
+    /// Promote a guest to a real user: move their comments to the user and mark the guest promoted.
+    ///
+    /// # Arguments
+    /// * `gid` - ID of the guest
+    /// * `uid` - User ID to move comments to
+    /// * `uname` - Name of the user, for the comments
+    ///
+    /// # Returns
+    /// * `usize` - Number of comments moved
+    /// * `Err(NotFound)` - No such guest
+    pub fn promote_guest(&self, gid: i32, uid: &str, uname: &str) -> DBResult<usize>
+    {
+        use schema::guests::dsl as sg;
+        use schema::comments::dsl as sc;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            let g = to_db_res(sg::guests.filter(sg::id.eq(gid)).first::<models::Guest>(conn))?;
+            let n = diesel::update(sc::comments.filter(sc::user_id.eq(g.user_id())))
+                .set((sc::user_id.eq(uid), sc::username.eq(uname))).execute(conn)?;
+            diesel::update(sg::guests.filter(sg::id.eq(gid))).set(sg::promoted_to.eq(uid)).execute(conn)?;
+            Ok(n)
+        })
+    }
+
+    /// Record an event in the audit log.
+    ///
+    /// # Arguments
+    /// * `event` - AuditEventInsert object
+    ///
+    /// # Returns
+    /// * `i32` - ID of the new audit log entry
+    pub fn add_audit_event(&self, event: &models::AuditEventInsert) -> DBResult<i32>
+    {
+        use schema::audit_log::dsl::*;
+        let res = diesel::insert_into(audit_log)
+            .values(event).returning(id).get_result(&mut self.conn()?)?;
+        Ok(res)
+    }
+
+    /// Get audit log entries related to a video.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash (unique identifier) of the video
+    ///
+    /// # Returns
+    /// * `Vec<models::AuditEvent>` - List of AuditEvent objects, oldest first
+    pub fn get_video_audit_events(&self, vh: &str) -> DBResult<Vec<models::AuditEvent>>
+    {
+        use models::*;
+        use schema::audit_log::dsl::*;
+        Ok(audit_log.filter(ref_video_hash.eq(vh)).order(id.asc()).load::<AuditEvent>(&mut self.conn()?)?)
+    }
+
+    /// Delete audit log entries created before given time.
+    /// Entries about videos under legal hold (also through their folder) are kept.
+    ///
+    /// # Returns
+    /// * `usize` - Number of entries deleted
+    pub fn del_audit_events_before(&self, cutoff: chrono::NaiveDateTime) -> DBResult<usize>
+    {
+        use schema::audit_log::dsl::*;
+        use schema::videos::dsl as v;
+        let conn = &mut self.conn()?;
+        let held_folders = held_folder_ids(conn)?;
+        let held = v::videos.filter(v::legal_hold.eq(true).or(v::folder_id.assume_not_null().eq_any(held_folders))).select(v::video_hash);
+        Ok(diesel::delete(audit_log.filter(created.lt(cutoff))
+                .filter(ref_video_hash.is_null().or(ref_video_hash.assume_not_null().ne_all(held))))
+            .execute(conn)?)
+    }
+
+    /// Search audit log entries.
+    ///
+    /// # Arguments
+    /// * `f` - Search filter
+    ///
+    /// # Returns
+    /// * `Vec<models::AuditEvent>` - Matching entries, newest first, max `f.limit`
+    pub fn search_audit_events(&self, f: &EventFilter) -> DBResult<Vec<models::AuditEvent>>
+    {
+        use models::*;
+        use schema::audit_log::dsl::*;
+        let mut q = audit_log.into_boxed();
+        if let Some(v) = &f.user_id { q = q.filter(user_id.eq(v)); }
+        if let Some(v) = &f.kind { q = q.filter(action.eq(v)); }
+        if let Some(v) = &f.video_hash { q = q.filter(ref_video_hash.eq(v)); }
+        if let Some(v) = f.since { q = q.filter(created.ge(v)); }
+        if let Some(v) = f.until { q = q.filter(created.lt(v)); }
+        if let Some(v) = &f.text {
+            let pat = like_pattern(v);
+            q = q.filter(user_id.like(pat.clone()).escape('\\')
+                .or(action.like(pat.clone()).escape('\\'))
+                .or(details.like(pat.clone()).escape('\\'))
+                .or(ref_video_hash.assume_not_null().like(pat.clone()).escape('\\')));
+        }
+        Ok(q.order(id.desc()).limit(f.limit).load::<AuditEvent>(&mut self.conn()?)?)
+    }
+
+    /// Search user messages (event log) of all users.
+    ///
+    /// # Arguments
+    /// * `f` - Search filter
+    ///
+    /// # Returns
+    /// * `Vec<models::Message>` - Matching messages, newest first, max `f.limit`
+    pub fn search_messages(&self, f: &EventFilter) -> DBResult<Vec<models::Message>>
+    {
+        use models::*;
+        use schema::messages::dsl::*;
+        let mut q = messages.into_boxed();
+        if let Some(v) = &f.user_id { q = q.filter(user_id.eq(v)); }
+        if let Some(v) = &f.kind { q = q.filter(event_name.eq(v)); }
+        if let Some(v) = &f.video_hash { q = q.filter(ref_video_hash.eq(v)); }
+        if let Some(v) = f.since { q = q.filter(created.ge(v)); }
+        if let Some(v) = f.until { q = q.filter(created.lt(v)); }
+        if let Some(v) = &f.text {
+            let pat = like_pattern(v);
+            q = q.filter(user_id.like(pat.clone()).escape('\\')
+                .or(event_name.like(pat.clone()).escape('\\'))
+                .or(message.like(pat.clone()).escape('\\'))
+                .or(details.like(pat.clone()).escape('\\'))
+                .or(ref_video_hash.assume_not_null().like(pat.clone()).escape('\\')));
+        }
+        Ok(q.order(id.desc()).limit(f.limit).load::<Message>(&mut self.conn()?)?)
+    }
+
+    /// Create a new folder.
+    ///
+    /// # Arguments
+    /// * `folder` - Folder to create
+    ///
+    /// # Returns
+    /// * `models::Folder` - Created folder
+    pub fn add_folder(&self, folder: &models::FolderInsert) -> DBResult<models::Folder>
+    {
+        use schema::folders::dsl::*;
+        Ok(diesel::insert_into(folders).values(folder).get_result::<models::Folder>(&mut self.conn()?)?)
+    }
+
+    /// Get a folder.
+    ///
+    /// # Arguments
+    /// * `fid` - ID of the folder
+    ///
+    /// # Returns
+    /// * `models::Folder`
+    /// * `Err(NotFound)` - Folder not found
+    pub fn get_folder(&self, fid: i32) -> DBResult<models::Folder>
+    {
+        use models::*;
+        use schema::folders::dsl::*;
+        to_db_res(folders.filter(id.eq(fid)).first::<Folder>(&mut self.conn()?))
+    }
+
+    /// Get all folders of a user.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    ///
+    /// # Returns
+    /// * `Vec<models::Folder>` - Folders, oldest first
+    pub fn get_user_folders(&self, uid: &str) -> DBResult<Vec<models::Folder>>
+    {
+        use models::*;
+        use schema::folders::dsl::*;
+        Ok(folders.filter(user_id.eq(uid)).order(id.asc()).load::<Folder>(&mut self.conn()?)?)
+    }
+
+    /// Delete a folder. Its videos and subfolders are moved to the parent folder (or root).
+    ///
+    /// # Arguments
+    /// * `fid` - ID of the folder
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Folder not found
+    pub fn del_folder(&self, fid: i32) -> EmptyDBResult
+    {
+        use schema::folders::dsl as sf;
+        use schema::videos::dsl as sv;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            let parent = to_db_res(sf::folders.filter(sf::id.eq(fid)).select(sf::parent_id).first::<Option<i32>>(conn))?;
+            diesel::update(sf::folders.filter(sf::parent_id.eq(fid))).set(sf::parent_id.eq(parent)).execute(conn)?;
+            diesel::update(sv::videos.filter(sv::folder_id.eq(fid))).set(sv::folder_id.eq(parent)).execute(conn)?;
+            diesel::delete(sf::folders.filter(sf::id.eq(fid))).execute(conn)?;
+            diesel::delete(schema::folder_syncs::table.filter(schema::folder_syncs::folder_id.eq(fid))).execute(conn)?;
+            diesel::delete(schema::team_folders::table.filter(schema::team_folders::folder_id.eq(fid))).execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Get videos in a folder (not in its subfolders), except trashed ones.
+    ///
+    /// # Arguments
+    /// * `fid` - Folder ID
+    pub fn get_folder_videos(&self, fid: i32) -> DBResult<Vec<models::Video>>
+    {
+        use schema::videos::dsl::*;
+        Ok(videos.filter(folder_id.eq(fid)).filter(trashed.is_null()).order(id.asc()).load::<models::Video>(&mut self.conn()?)?)
+    }
+
+    /// Move a video to a folder.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash (unique identifier) of the video
+    /// * `fid` - ID of the folder, or None for root
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Video not found
+    pub fn set_video_folder(&self, vh: &str, fid: Option<i32>) -> EmptyDBResult
+    {
+        use schema::videos::dsl::*;
+        let res = diesel::update(videos.filter(video_hash.eq(vh)))
+            .set(folder_id.eq(fid))
+            .execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Set default overlay presets of a folder (see `models::Folder::overlay_defaults`).
+    ///
+    /// # Arguments
+    /// * `fid` - ID of the folder
+    /// * `presets` - Preset names, or None to inherit from parent folder
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Folder not found
+    pub fn set_folder_overlay_defaults(&self, fid: i32, presets: Option<&[String]>) -> EmptyDBResult
+    {
+        use schema::folders::dsl::*;
+        let res = diesel::update(folders.filter(id.eq(fid)))
+            .set(overlay_defaults.eq(presets.map(|p| p.join(","))))
+            .execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Set or lift legal hold on a folder. It applies to all videos in the folder and its subfolders.
+    ///
+    /// # Arguments
+    /// * `fid` - ID of the folder
+    /// * `hold` - True to set, false to lift
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Folder not found
+    pub fn set_folder_legal_hold(&self, fid: i32, hold: bool) -> EmptyDBResult
+    {
+        use schema::folders::dsl::*;
+        let res = diesel::update(folders.filter(id.eq(fid)))
+            .set(legal_hold.eq(hold))
+            .execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Get a folder and its parents.
+    ///
+    /// # Arguments
+    /// * `fid` - ID of the folder, or None for root
+    ///
+    /// # Returns
+    /// * `Vec<i32>` - Folder IDs, from the folder up to its top level parent (empty for root)
+    pub fn get_folder_path(&self, fid: Option<i32>) -> DBResult<Vec<i32>>
+    {
+        use schema::folders::dsl::*;
+        let conn = &mut self.conn()?;
+        let mut res = vec![];
+        let mut cur = fid;
+        while let Some(f) = cur {
+            let Some(parent) = folders.filter(id.eq(f)).select(parent_id).first::<Option<i32>>(conn).optional()? else { break };
+            if res.contains(&f) { break; }  // Guard against loops in broken data
+            res.push(f);
+            cur = parent;
+        }
+        Ok(res)
+    }
+
+    /// Resolve default overlay presets for videos in a folder, inheriting from parent folders.
+    ///
+    /// # Arguments
+    /// * `fid` - ID of the folder, or None for root
+    ///
+    /// # Returns
+    /// * `Option<Vec<String>>` - Preset names, or None if no folder on the path sets them
+    pub fn get_folder_overlay_defaults(&self, fid: Option<i32>) -> DBResult<Option<Vec<String>>>
+    {
+        use schema::folders::dsl::*;
+        let conn = &mut self.conn()?;
+        let mut cur = fid;
+        let mut depth = 0;
+        while let Some(f) = cur {
+            let Some((parent, defaults)) = folders.filter(id.eq(f)).select((parent_id, overlay_defaults))
+                .first::<(Option<i32>, Option<String>)>(conn).optional()? else { break };
+            if let Some(d) = defaults {
+                return Ok(Some(d.split(',').filter(|s| !s.is_empty()).map(String::from).collect()));
+            }
+            depth += 1;
+            if depth > 100 { break; }  // Guard against loops in broken data
+            cur = parent;
+        }
+        Ok(None)
+    }
+
+    /// Set transcode preset of a folder (see `models::Folder::transcode_preset`).
+    ///
+    /// # Arguments
+    /// * `fid` - ID of the folder
+    /// * `preset` - Preset name, or None to inherit from parent folder
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Folder not found
+    pub fn set_folder_transcode_preset(&self, fid: i32, preset: Option<&str>) -> EmptyDBResult
+    {
+        use schema::folders::dsl::*;
+        let res = diesel::update(folders.filter(id.eq(fid)))
+            .set(transcode_preset.eq(preset))
+            .execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Resolve transcode preset for videos in a folder, inheriting from parent folders.
+    ///
+    /// # Arguments
+    /// * `fid` - ID of the folder, or None for root
+    ///
+    /// # Returns
+    /// * `Option<String>` - Preset name, or None if no folder on the path sets one
+    pub fn get_folder_transcode_preset(&self, fid: Option<i32>) -> DBResult<Option<String>>
+    {
+        use schema::folders::dsl::*;
+        let conn = &mut self.conn()?;
+        let mut cur = fid;
+        let mut depth = 0;
+        while let Some(f) = cur {
+            let Some((parent, preset)) = folders.filter(id.eq(f)).select((parent_id, transcode_preset))
+                .first::<(Option<i32>, Option<String>)>(conn).optional()? else { break };
+            if preset.is_some() {
+                return Ok(preset);
+            }
+            depth += 1;
+            if depth > 100 { break; }  // Guard against loops in broken data
+            cur = parent;
+        }
+        Ok(None)
+    }
+
+    /// Get all overlay presets.
+    ///
+    /// # Returns
+    /// * `Vec<models::OverlayPreset>` - Presets, ordered by kind and name
+    pub fn get_overlay_presets(&self) -> DBResult<Vec<models::OverlayPreset>>
+    {
+        use models::*;
+        use schema::overlay_presets::dsl::*;
+        Ok(overlay_presets.order((kind.asc(), name.asc())).load::<OverlayPreset>(&mut self.conn()?)?)
+    }
+
+    /// Add or replace an overlay preset.
+    ///
+    /// # Arguments
+    /// * `preset` - Preset to store (identified by name)
+    pub fn set_overlay_preset(&self, preset: &models::OverlayPreset) -> EmptyDBResult
+    {
+        use schema::overlay_presets::dsl::*;
+        diesel::replace_into(overlay_presets).values(preset).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Delete an overlay preset.
+    ///
+    /// # Arguments
+    /// * `preset_name` - Name of the preset
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Preset not found
+    pub fn del_overlay_preset(&self, preset_name: &str) -> EmptyDBResult
+    {
+        use schema::overlay_presets::dsl::*;
+        let res = diesel::delete(overlay_presets.filter(name.eq(preset_name))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Get all custom field definitions.
+    ///
+    /// # Returns
+    /// * `Vec<models::CustomField>` - Fields, ordered by name
+    pub fn get_custom_fields(&self) -> DBResult<Vec<models::CustomField>>
+    {
+        use schema::custom_fields::dsl::*;
+        Ok(custom_fields.order(name.asc()).load::<models::CustomField>(&mut self.conn()?)?)
+    }
+
+    /// Add or replace a custom field definition. Existing values of the field are kept as is.
+    ///
+    /// # Arguments
+    /// * `field` - Field to store (identified by name)
+    pub fn set_custom_field(&self, field: &models::CustomField) -> EmptyDBResult
+    {
+        use schema::custom_fields::dsl::*;
+        diesel::replace_into(custom_fields).values(field).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Delete a custom field definition. Values stay in videos, but are no longer shown.
+    ///
+    /// # Arguments
+    /// * `field_name` - Name of the field
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Field not found
+    pub fn del_custom_field(&self, field_name: &str) -> EmptyDBResult
+    {
+        use schema::custom_fields::dsl::*;
+        let res = diesel::delete(custom_fields.filter(name.eq(field_name))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Get custom field values of given videos.
+    ///
+    /// # Returns
+    /// * `HashMap<String, CustomFieldValues>` - Field values (name -> value) by video hash. Videos without any are left out.
+    pub fn get_video_custom_fields(&self, vhs: &[String]) -> DBResult<std::collections::HashMap<String, models::CustomFieldValues>>
+    {
+        use schema::video_custom_fields::dsl::*;
+        video_custom_fields.filter(video_hash.eq_any(vhs)).load::<(String, String)>(&mut self.conn()?)?
+            .into_iter().map(|(vh, vals)| Ok((vh, serde_json::from_str(&vals).map_err(anyhow::Error::from)?)))
+            .collect()
+    }
+
+    /// Change custom field values of a video. Fields not mentioned are kept.
+    ///
+    /// # Arguments
+    /// * `vh` - Video hash
+    /// * `changes` - New values by field name. Null removes the value.
+    ///
+    /// # Returns
+    /// * `CustomFieldValues` - All field values of the video after the change
+    pub fn update_video_custom_fields(&self, vh: &str, changes: &models::CustomFieldValues) -> DBResult<models::CustomFieldValues>
+    {
+        use schema::video_custom_fields::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            let mut vals = match video_custom_fields.filter(video_hash.eq(vh)).select(field_values).first::<String>(conn).optional()? {
+                Some(v) => serde_json::from_str::<models::CustomFieldValues>(&v).map_err(anyhow::Error::from)?,
+                None => models::CustomFieldValues::new(),
+            };
+            for (k, v) in changes {
+                if v.is_null() { vals.remove(k); } else { vals.insert(k.clone(), v.clone()); }
+            }
+            if vals.is_empty() {
+                diesel::delete(video_custom_fields.filter(video_hash.eq(vh))).execute(conn)?;
+            } else {
+                let json = serde_json::to_string(&vals).map_err(anyhow::Error::from)?;
+                diesel::replace_into(video_custom_fields).values((video_hash.eq(vh), field_values.eq(json))).execute(conn)?;
+            }
+            Ok(vals)
+        })
+    }
+
+    /// Add a maintenance window.
+    pub fn add_maintenance_window(&self, mw: &models::MaintenanceWindowInsert) -> DBResult<models::MaintenanceWindow>
+    {
+        use schema::maintenance_windows::dsl::*;
+        Ok(diesel::insert_into(maintenance_windows).values(mw).get_result(&mut self.conn()?)?)
+    }
+
+    /// Get maintenance windows that end after given time (i.e. current and upcoming ones), earliest first.
+    pub fn get_maintenance_windows(&self, ending_after: chrono::NaiveDateTime) -> DBResult<Vec<models::MaintenanceWindow>>
+    {
+        use schema::maintenance_windows::dsl::*;
+        Ok(maintenance_windows.filter(ends.gt(ending_after)).order(starts.asc())
+            .load::<models::MaintenanceWindow>(&mut self.conn()?)?)
+    }
+
+    /// Delete a maintenance window.
+    ///
+    /// # Returns
+    /// * `Err(NotFound)` - No such window
+    pub fn del_maintenance_window(&self, mw_id: i32) -> EmptyDBResult
+    {
+        use schema::maintenance_windows::dsl::*;
+        let res = diesel::delete(maintenance_windows.filter(id.eq(mw_id))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Set (add or replace) a feature flag override.
+    pub fn set_feature_override(&self, fo: &models::FeatureOverrideInsert) -> EmptyDBResult
+    {
+        use schema::feature_overrides::dsl::*;
+        diesel::replace_into(feature_overrides).values(fo).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Delete a feature flag override.
+    ///
+    /// # Returns
+    /// * `Err(NotFound)` - No such override
+    pub fn del_feature_override(&self, feat: &str, tgt: &str) -> EmptyDBResult
+    {
+        use schema::feature_overrides::dsl::*;
+        let res = diesel::delete(feature_overrides.filter(feature.eq(feat)).filter(target.eq(tgt))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Get feature flag overrides, ordered by feature and target.
+    ///
+    /// # Arguments
+    /// * `targets` - Only overrides for these targets (e.g. `["*", "user:alice", "team:3"]`), or None for all
+    pub fn get_feature_overrides(&self, targets: Option<&[String]>) -> DBResult<Vec<models::FeatureOverride>>
+    {
+        use schema::feature_overrides::dsl::*;
+        let mut q = feature_overrides.order((feature.asc(), target.asc())).into_boxed();
+        if let Some(t) = targets {
+            q = q.filter(target.eq_any(t));
+        }
+        Ok(q.load::<models::FeatureOverride>(&mut self.conn()?)?)
+    }
+
+    /// Add a browser's push subscription. A subscription with the same endpoint (e.g. after
+    /// the browser renewed its keys, or another user logged in on it) is replaced.
+    pub fn add_push_subscription(&self, ps: &models::PushSubscriptionInsert) -> DBResult<models::PushSubscription>
+    {
+        use schema::push_subscriptions::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            diesel::delete(push_subscriptions.filter(endpoint.eq(&ps.endpoint))).execute(conn)?;
+            Ok(diesel::insert_into(push_subscriptions).values(ps).get_result(conn)?)
+        })
+    }
+
+    /// Delete a push subscription.
+    ///
+    /// # Arguments
+    /// * `ep` - Endpoint URL of the subscription
+    /// * `uid` - Only if it's this user's, or None for anyone's (e.g. when push service says it's gone)
+    ///
+    /// # Returns
+    /// * `Err(NotFound)` - No such subscription
+    pub fn del_push_subscription(&self, ep: &str, uid: Option<&str>) -> EmptyDBResult
+    {
+        use schema::push_subscriptions::dsl::*;
+        let mut q = diesel::delete(push_subscriptions).filter(endpoint.eq(ep)).into_boxed();
+        if let Some(uid) = uid {
+            q = q.filter(user_id.eq(uid));
+        }
+        if q.execute(&mut self.conn()?)? == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Get user's push subscriptions, oldest first.
+    pub fn get_push_subscriptions(&self, uid: &str) -> DBResult<Vec<models::PushSubscription>>
+    {
+        use schema::push_subscriptions::dsl::*;
+        Ok(push_subscriptions.filter(user_id.eq(uid)).order(id.asc()).load::<models::PushSubscription>(&mut self.conn()?)?)
+    }
+
+    /// Get events user wants pushed. All off if user hasn't set them.
+    pub fn get_push_prefs(&self, uid: &str) -> DBResult<models::PushPrefs>
+    {
+        use schema::push_prefs::dsl::*;
+        Ok(push_prefs.filter(user_id.eq(uid)).first::<models::PushPrefs>(&mut self.conn()?).optional()?
+            .unwrap_or(models::PushPrefs { user_id: uid.into(), ..Default::default() }))
+    }
+
+    /// Set (add or replace) events user wants pushed.
+    pub fn set_push_prefs(&self, prefs: &models::PushPrefs) -> EmptyDBResult
+    {
+        use schema::push_prefs::dsl::*;
+        diesel::replace_into(push_prefs).values(prefs).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Get user's profile. Empty if user hasn't set one.
+    pub fn get_user_profile(&self, uid: &str) -> DBResult<models::UserProfile>
+    {
+        use schema::user_profiles::dsl::*;
+        Ok(user_profiles.filter(user_id.eq(uid)).first::<models::UserProfile>(&mut self.conn()?).optional()?
+            .unwrap_or(models::UserProfile { user_id: uid.into(), ..Default::default() }))
+    }
+
+    /// Get profiles of given users. Users without one are left out.
+    pub fn get_user_profiles(&self, uids: &[String]) -> DBResult<Vec<models::UserProfile>>
+    {
+        use schema::user_profiles::dsl::*;
+        Ok(user_profiles.filter(user_id.eq_any(uids)).load::<models::UserProfile>(&mut self.conn()?)?)
+    }
+
+    /// Set (add or replace) user's profile.
+    pub fn set_user_profile(&self, profile: &models::UserProfile) -> EmptyDBResult
+    {
+        use schema::user_profiles::dsl::*;
+        diesel::replace_into(user_profiles).values(profile).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Is an avatar file used by any profile
+    pub fn is_avatar_in_use(&self, file: &str) -> DBResult<bool>
+    {
+        use schema::user_profiles::dsl::*;
+        Ok(user_profiles.filter(avatar.eq(file)).count().get_result::<i64>(&mut self.conn()?)? > 0)
+    }
+
+    /// Get user's client settings, by key.
+    pub fn get_user_prefs(&self, uid: &str) -> DBResult<Vec<models::UserPref>>
+    {
+        use schema::user_prefs::dsl::*;
+        Ok(user_prefs.filter(user_id.eq(uid)).order(key.asc()).load::<models::UserPref>(&mut self.conn()?)?)
+    }
+
+    /// Get one of user's client settings (JSON), if set.
+    pub fn get_user_pref(&self, uid: &str, k: &str) -> DBResult<Option<String>>
+    {
+        use schema::user_prefs::dsl::*;
+        Ok(user_prefs.filter(user_id.eq(uid)).filter(key.eq(k)).select(value).first::<String>(&mut self.conn()?).optional()?)
+    }
+
+    /// Set and remove user's client settings, all or none.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    /// * `changes` - Keys and their new values (JSON), None to remove
+    pub fn set_user_prefs(&self, uid: &str, changes: &[(String, Option<String>)]) -> EmptyDBResult
+    {
+        use schema::user_prefs::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            for (k, v) in changes {
+                match v {
+                    Some(v) => { diesel::replace_into(user_prefs).values((user_id.eq(uid), key.eq(k), value.eq(v), updated.eq(diesel::dsl::now))).execute(conn)?; },
+                    None => { diesel::delete(user_prefs.filter(user_id.eq(uid)).filter(key.eq(k))).execute(conn)?; },
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Queue a comment for pushing to the production tracker. Already queued ones are left as is.
+    ///
+    /// # Arguments
+    /// * `cid` - Comment ID
+    /// * `vh` - Video hash of the comment
+    pub fn add_tracker_push(&self, cid: i32, vh: &str) -> EmptyDBResult
+    {
+        use schema::tracker_outbox::dsl::*;
+        diesel::insert_into(tracker_outbox).values((comment_id.eq(cid), video_hash.eq(vh)))
+            .on_conflict_do_nothing().execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Get comments queued for pushing to the production tracker, oldest first.
+    ///
+    /// # Arguments
+    /// * `due_only` - Only those whose next attempt is due
+    pub fn get_tracker_pushes(&self, due_only: bool) -> DBResult<Vec<models::TrackerOutboxItem>>
+    {
+        use schema::tracker_outbox::dsl::*;
+        let mut q = tracker_outbox.order(id.asc()).into_boxed();
+        if due_only {
+            q = q.filter(next_attempt.le(diesel::dsl::now));
+        }
+        Ok(q.load::<models::TrackerOutboxItem>(&mut self.conn()?)?)
+    }
+
+    /// Record a failed push attempt.
+    ///
+    /// # Arguments
+    /// * `item_id` - ID of the queue item
+    /// * `retry_at` - When to try again
+    /// * `error` - What went wrong
+    pub fn set_tracker_push_failed(&self, item_id: i32, retry_at: chrono::NaiveDateTime, error: &str) -> EmptyDBResult
+    {
+        use schema::tracker_outbox::dsl::*;
+        let res = diesel::update(tracker_outbox.filter(id.eq(item_id)))
+            .set((attempts.eq(attempts + 1), next_attempt.eq(retry_at), last_error.eq(error)))
+            .execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Remove a comment from the push queue (pushed, or given up on).
+    pub fn del_tracker_push(&self, item_id: i32) -> EmptyDBResult
+    {
+        use schema::tracker_outbox::dsl::*;
+        diesel::delete(tracker_outbox.filter(id.eq(item_id))).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Make all queued pushes due now.
+    ///
+    /// # Returns
+    /// * `usize` - Number of queued comments
+    pub fn retry_tracker_pushes_now(&self) -> DBResult<usize>
+    {
+        use schema::tracker_outbox::dsl::*;
+        Ok(diesel::update(tracker_outbox).set(next_attempt.eq(diesel::dsl::now)).execute(&mut self.conn()?)?)
+    }
+
+    /// Record a comment pushed to the production tracker, or a reply pulled from it.
+    pub fn add_tracker_note(&self, note: &models::TrackerNoteInsert) -> EmptyDBResult
+    {
+        use schema::tracker_notes::dsl::*;
+        diesel::insert_into(tracker_notes).values(note).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Get notes pushed to a tracker (not pulled replies), for polling replies to them.
+    ///
+    /// # Arguments
+    /// * `tracker_kind` - Tracker kind, e.g. "shotgrid"
+    /// * `since` - Only notes pushed after this
+    pub fn get_pushed_tracker_notes(&self, tracker_kind: &str, since: chrono::NaiveDateTime) -> DBResult<Vec<models::TrackerNote>>
+    {
+        use schema::tracker_notes::dsl::*;
+        Ok(tracker_notes.filter(tracker.eq(tracker_kind)).filter(reply_to.is_null()).filter(created.ge(since))
+            .order(created.asc()).load::<models::TrackerNote>(&mut self.conn()?)?)
+    }
+
+    /// Find a note (or reply) by its ID in the tracker.
+    pub fn get_tracker_note_by_remote_id(&self, tracker_kind: &str, rid: &str) -> DBResult<Option<models::TrackerNote>>
+    {
+        use schema::tracker_notes::dsl::*;
+        Ok(tracker_notes.filter(tracker.eq(tracker_kind)).filter(remote_id.eq(rid))
+            .first::<models::TrackerNote>(&mut self.conn()?).optional()?)
+    }
+
+    /// Schedule a review session.
+    ///
+    /// # Arguments
+    /// * `rs` - Session to add
+    /// * `invitees` - User IDs of invited users
+    pub fn add_review_session(&self, rs: &models::ReviewSessionInsert, invitees: &[String]) -> DBResult<models::ReviewSession>
+    {
+        use schema::review_invitees::dsl as i;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            let res: models::ReviewSession = diesel::insert_into(schema::review_sessions::table).values(rs).get_result(conn)?;
+            for uid in invitees {
+                diesel::insert_into(i::review_invitees).values((i::session_id.eq(res.id), i::user_id.eq(uid)))
+                    .on_conflict_do_nothing().execute(conn)?;
+            }
+            Ok(res)
+        })
+    }
+
+    /// Get a review session.
+    ///
+    /// # Returns
+    /// * `Err(NotFound)` - No such session
+    pub fn get_review_session(&self, sid: i32) -> DBResult<models::ReviewSession>
+    {
+        use schema::review_sessions::dsl::*;
+        to_db_res(review_sessions.filter(id.eq(sid)).first::<models::ReviewSession>(&mut self.conn()?))
+    }
+
+    /// Get user IDs invited to a review session.
+    pub fn get_review_invitees(&self, sid: i32) -> DBResult<Vec<String>>
+    {
+        use schema::review_invitees::dsl::*;
+        Ok(review_invitees.filter(session_id.eq(sid)).select(user_id).order(user_id.asc()).load::<String>(&mut self.conn()?)?)
+    }
+
+    /// Get review sessions a user hosts or is invited to, by start time.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    /// * `since` - Only sessions starting after this
+    pub fn get_user_review_sessions(&self, uid: &str, since: chrono::NaiveDateTime) -> DBResult<Vec<models::ReviewSession>>
+    {
+        use schema::review_sessions::dsl::*;
+        use schema::review_invitees::dsl as i;
+        let invited = i::review_invitees.filter(i::user_id.eq(uid)).select(i::session_id);
+        Ok(review_sessions.filter(host.eq(uid).or(id.eq_any(invited))).filter(starts.ge(since))
+            .order((starts.asc(), id.asc())).load::<models::ReviewSession>(&mut self.conn()?)?)
+    }
+
+    /// Get review sessions that have started but whose collab room hasn't been opened yet.
+    pub fn get_due_review_sessions(&self) -> DBResult<Vec<models::ReviewSession>>
+    {
+        use schema::review_sessions::dsl::*;
+        Ok(review_sessions.filter(opened.is_null()).filter(starts.le(diesel::dsl::now))
+            .order(starts.asc()).load::<models::ReviewSession>(&mut self.conn()?)?)
+    }
+
+    /// Mark a review session's collab room opened.
+    pub fn set_review_session_opened(&self, sid: i32) -> EmptyDBResult
+    {
+        use schema::review_sessions::dsl::*;
+        let res = diesel::update(review_sessions.filter(id.eq(sid))).set(opened.eq(diesel::dsl::now)).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Delete a review session and its invitations.
+    pub fn del_review_session(&self, sid: i32) -> EmptyDBResult
+    {
+        use schema::review_invitees::dsl as i;
+        use schema::review_sessions::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            diesel::delete(i::review_invitees.filter(i::session_id.eq(sid))).execute(conn)?;
+            if diesel::delete(review_sessions.filter(id.eq(sid))).execute(conn)? == 0 {
+                return Err(DBError::NotFound());
+            }
+            Ok(())
+        })
+    }
+
+    /// Delete review sessions that started before given time, and invitations to sessions that no longer exist.
+    ///
+    /// # Returns
+    /// * `usize` - Number of sessions deleted
+    pub fn del_past_review_sessions(&self, before: chrono::NaiveDateTime) -> DBResult<usize>
+    {
+        use schema::review_invitees::dsl as i;
+        use schema::review_sessions::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            let n = diesel::delete(review_sessions.filter(starts.lt(before))).execute(conn)?;
+            diesel::delete(i::review_invitees.filter(i::session_id.ne_all(review_sessions.select(id)))).execute(conn)?;
+            Ok(n)
+        })
+    }
+
+    /// Create a collab room ahead of its participants, e.g. for a scheduled review.
+    /// An existing room is left as is.
+    pub fn open_collab_room(&self, cid: &str, vh: &str, host_uid: &str) -> EmptyDBResult
+    {
+        use schema::collab_rooms::dsl::*;
+        diesel::insert_into(collab_rooms)
+            .values(&models::CollabRoomInsert { collab_id: cid.into(), video_hash: vh.into(), host: host_uid.into() })
+            .on_conflict_do_nothing().execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Get sequence number of the latest change in the video change journal (filled by triggers,
+    /// see `api_server::video_list_sub`), 0 if there are none.
+    pub fn get_latest_video_change(&self) -> DBResult<i64>
+    {
+        use schema::video_changes::dsl::*;
+        Ok(video_changes.select(diesel::dsl::max(seq)).first::<Option<i64>>(&mut self.conn()?)?.unwrap_or(0))
+    }
+
+    /// Get videos that have changed (in ways that show in video listings) after given change.
+    ///
+    /// # Arguments
+    /// * `since` - Sequence number of the last change already handled
+    ///
+    /// # Returns
+    /// * `(i64, Vec<String>)` - Sequence number of the latest change, and hashes of the changed videos
+    pub fn get_video_changes(&self, since: i64) -> DBResult<(i64, Vec<String>)>
+    {
+        use schema::video_changes::dsl::*;
+        let changes = video_changes.filter(seq.gt(since)).order(seq.asc())
+            .select((seq, video_hash)).load::<(i64, String)>(&mut self.conn()?)?;
+        let latest = changes.last().map(|(s, _)| *s).unwrap_or(since);
+        let mut vhs = changes.into_iter().map(|(_, vh)| vh).collect::<Vec<_>>();
+        vhs.sort();
+        vhs.dedup();
+        Ok((latest, vhs))
+    }
+
+    /// Delete video change journal entries older than given time.
+    ///
+    /// # Returns
+    /// * `usize` - Number of entries deleted
+    pub fn del_video_changes_before(&self, before: chrono::NaiveDateTime) -> DBResult<usize>
+    {
+        use schema::video_changes::dsl::*;
+        Ok(diesel::delete(video_changes.filter(created.lt(before))).execute(&mut self.conn()?)?)
+    }
+
+    /// Check if any of the given videos are user's own or linked to them (trashed or not),
+    /// i.e. could be in their video list.
+    pub fn any_user_video(&self, uid: &str, vhs: &[String]) -> DBResult<bool>
+    {
+        use schema::videos::dsl::*;
+        use schema::video_links::dsl as vl;
+        let conn = &mut self.conn()?;
+        if vl::video_links.filter(vl::user_id.eq(uid)).filter(vl::video_hash.eq_any(vhs)).count().get_result::<i64>(conn)? > 0 {
+            return Ok(true);
+        }
+        Ok(videos.filter(added_by_userid.eq(uid)).filter(video_hash.eq_any(vhs)).count().get_result::<i64>(conn)? > 0)
+    }
+
+    /// Add a new resumable upload session.
+    pub fn add_upload_session(&self, us: &models::UploadSessionInsert) -> DBResult<models::UploadSession>
+    {
+        use schema::upload_sessions::dsl::*;
+        Ok(diesel::insert_into(upload_sessions).values(us).get_result(&mut self.conn()?)?)
+    }
+
+    /// Get an upload session.
+    ///
+    /// # Returns
+    /// * `Err(NotFound)` - No such session (finished, cancelled or expired)
+    pub fn get_upload_session(&self, sid: &str) -> DBResult<models::UploadSession>
+    {
+        use schema::upload_sessions::dsl::*;
+        to_db_res(upload_sessions.filter(id.eq(sid)).first::<models::UploadSession>(&mut self.conn()?))
+    }
+
+    /// Get unfinished upload sessions of a user, or everyone's.
+    ///
+    /// # Returns
+    /// * `Vec<models::UploadSession>` - Oldest first
+    pub fn get_upload_sessions(&self, uid: Option<&str>) -> DBResult<Vec<models::UploadSession>>
+    {
+        use schema::upload_sessions::dsl::*;
+        let mut q = upload_sessions.order(created.asc()).into_boxed();
+        if let Some(u) = uid { q = q.filter(user_id.eq(u)); }
+        Ok(q.load::<models::UploadSession>(&mut self.conn()?)?)
+    }
+
+    /// Update status and/or received byte count of an upload session. Also bumps `updated`.
+    ///
+    /// # Returns
+    /// * `Err(NotFound)` - No such session
+    pub fn update_upload_session(&self, sid: &str, new_status: Option<&str>, new_received: Option<i64>) -> EmptyDBResult
+    {
+        use schema::upload_sessions::dsl::*;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            let res = diesel::update(upload_sessions.filter(id.eq(sid)))
+                .set(updated.eq(diesel::dsl::now)).execute(conn)?;
+            if res == 0 { return Err(DBError::NotFound()); }
+            if let Some(s) = new_status {
+                diesel::update(upload_sessions.filter(id.eq(sid))).set(status.eq(s)).execute(conn)?;
+            }
+            if let Some(r) = new_received {
+                diesel::update(upload_sessions.filter(id.eq(sid))).set(received.eq(r)).execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Delete an upload session (from DB only, not the partial file).
+    ///
+    /// # Returns
+    /// * `Err(NotFound)` - No such session
+    pub fn del_upload_session(&self, sid: &str) -> EmptyDBResult
+    {
+        use schema::upload_sessions::dsl::*;
+        let res = diesel::delete(upload_sessions.filter(id.eq(sid))).execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Add a participant to a collab room. Creates the room, with the user as host, if it doesn't exist.
+    /// If the room exists for another video, the user is not added.
+    ///
+    /// # Arguments
+    /// * `cid` - Collab ID
+    /// * `vh` - Video hash the collab is for (only used when creating the room)
+    /// * `uid` - User ID
+    /// * `uname` - User name, for showing who the host is
+    ///
+    /// # Returns
+    /// * `models::CollabRoom` - The room (check its `video_hash`)
+    pub fn join_collab_room(&self, cid: &str, vh: &str, uid: &str, uname: &str) -> DBResult<models::CollabRoom>
+    {
+        use schema::collab_rooms::dsl as r;
+        use schema::collab_participants::dsl as p;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            diesel::insert_into(r::collab_rooms)
+                .values(&models::CollabRoomInsert { collab_id: cid.into(), video_hash: vh.into(), host: uid.into() })
+                .on_conflict_do_nothing().execute(conn)?;
+            let room = r::collab_rooms.filter(r::collab_id.eq(cid)).first::<models::CollabRoom>(conn)?;
+            if room.video_hash == vh {
+                diesel::update(r::collab_rooms.filter(r::collab_id.eq(cid))).set(r::updated.eq(diesel::dsl::now)).execute(conn)?;
+                diesel::insert_into(p::collab_participants)
+                    .values(&models::CollabParticipant { collab_id: cid.into(), user_id: uid.into(), user_name: uname.into() })
+                    .on_conflict((p::collab_id, p::user_id)).do_update().set(p::user_name.eq(uname)).execute(conn)?;
+            }
+            Ok(room)
+        })
+    }
+
+    /// Remove a participant from a collab room. The room is deleted when the last one leaves.
+    pub fn leave_collab_room(&self, cid: &str, uid: &str) -> EmptyDBResult
+    {
+        use schema::collab_rooms::dsl as r;
+        use schema::collab_participants::dsl as p;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            diesel::delete(p::collab_participants.filter(p::collab_id.eq(cid)).filter(p::user_id.eq(uid))).execute(conn)?;
+            let n_left: i64 = p::collab_participants.filter(p::collab_id.eq(cid)).count().get_result(conn)?;
+            if n_left == 0 {
+                diesel::delete(r::collab_rooms.filter(r::collab_id.eq(cid))).execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Get a collab room.
+    ///
+    /// # Returns
+    /// * `Err(NotFound)` - No such room (never created, or everyone left)
+    pub fn get_collab_room(&self, cid: &str) -> DBResult<models::CollabRoom>
+    {
+        use schema::collab_rooms::dsl::*;
+        to_db_res(collab_rooms.filter(collab_id.eq(cid)).first::<models::CollabRoom>(&mut self.conn()?))
+    }
+
+    /// Get participants of a collab room, in user ID order.
+    pub fn get_collab_participants(&self, cid: &str) -> DBResult<Vec<models::CollabParticipant>>
+    {
+        use schema::collab_participants::dsl::*;
+        Ok(collab_participants.filter(collab_id.eq(cid)).order(user_id.asc()).load::<models::CollabParticipant>(&mut self.conn()?)?)
+    }
+
+    /// Save playback state of a collab room. Also bumps `updated`.
+    ///
+    /// # Returns
+    /// * `Err(NotFound)` - No such room
+    pub fn set_collab_playback(&self, cid: &str, is_paused: bool, time: f64, page_num: Option<i32>) -> EmptyDBResult
+    {
+        use schema::collab_rooms::dsl::*;
+        let res = diesel::update(collab_rooms.filter(collab_id.eq(cid)))
+            .set((paused.eq(is_paused), seek_time.eq(time), page.eq(page_num), updated.eq(diesel::dsl::now)))
+            .execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Delete collab rooms (and their participants) that haven't been joined or reported to since given time.
+    ///
+    /// # Returns
+    /// * `usize` - Number of rooms deleted
+    pub fn del_stale_collab_rooms(&self, before: chrono::NaiveDateTime) -> DBResult<usize>
+    {
+        use schema::collab_rooms::dsl as r;
+        use schema::collab_participants::dsl as p;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            let stale = r::collab_rooms.filter(r::updated.lt(before)).select(r::collab_id).load::<String>(conn)?;
+            diesel::delete(p::collab_participants.filter(p::collab_id.eq_any(&stale))).execute(conn)?;
+            Ok(diesel::delete(r::collab_rooms.filter(r::collab_id.eq_any(&stale))).execute(conn)?)
+        })
+    }
+
+    /// Create a new upload batch, with a pending file entry for each filename.
+    ///
+    /// # Arguments
+    /// * `uid` - User ID
+    /// * `batch_title` - Title of the batch (e.g. folder name)
+    /// * `filenames` - Names of the files that will be uploaded
+    ///
+    /// # Returns
+    /// * `(models::UploadBatch, Vec<models::UploadBatchFile>)` - New batch and its files
+    pub fn add_upload_batch(&self, uid: &str, batch_title: &str, filenames: &[String]) -> DBResult<(models::UploadBatch, Vec<models::UploadBatchFile>)>
+    {
+        use models::*;
+        use schema::upload_batches::dsl as sb;
+        use schema::upload_batch_files::dsl as sf;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            let batch = diesel::insert_into(sb::upload_batches)
+                .values(&UploadBatchInsert { user_id: uid.into(), title: batch_title.into() })
+                .get_result::<UploadBatch>(conn)?;
+            let rows = filenames.iter().map(|f| UploadBatchFileInsert {
+                    batch_id: batch.id, filename: f.clone(), status: batch_file_status::PENDING.into() })
+                .collect::<Vec<_>>();
+            diesel::insert_into(sf::upload_batch_files).values(&rows).execute(conn)?;
+            let files = sf::upload_batch_files.filter(sf::batch_id.eq(batch.id))
+                .order(sf::id.asc()).load::<UploadBatchFile>(conn)?;
+            Ok((batch, files))
+        })
+    }
+
+    /// Get an upload batch.
+    ///
+    /// # Arguments
+    /// * `bid` - ID of the batch
+    ///
+    /// # Returns
+    /// * `models::UploadBatch`
+    /// * `Err(NotFound)` - Batch not found
+    pub fn get_upload_batch(&self, bid: i32) -> DBResult<models::UploadBatch>
+    {
+        use models::*;
+        use schema::upload_batches::dsl::*;
+        to_db_res(upload_batches.filter(id.eq(bid)).first::<UploadBatch>(&mut self.conn()?))
+    }
+
+    /// Get files of an upload batch.
+    ///
+    /// # Arguments
+    /// * `bid` - ID of the batch
+    ///
+    /// # Returns
+    /// * `Vec<models::UploadBatchFile>` - Files, in the order they were added
+    pub fn get_upload_batch_files(&self, bid: i32) -> DBResult<Vec<models::UploadBatchFile>>
+    {
+        use models::*;
+        use schema::upload_batch_files::dsl::*;
+        Ok(upload_batch_files.filter(batch_id.eq(bid)).order(id.asc()).load::<UploadBatchFile>(&mut self.conn()?)?)
+    }
+
+    /// Get a single file entry of an upload batch.
+    ///
+    /// # Arguments
+    /// * `file_id` - ID of the batch file
+    ///
+    /// # Returns
+    /// * `models::UploadBatchFile`
+    /// * `Err(NotFound)` - File not found
+    pub fn get_upload_batch_file(&self, file_id: i32) -> DBResult<models::UploadBatchFile>
+    {
+        use models::*;
+        use schema::upload_batch_files::dsl::*;
+        to_db_res(upload_batch_files.filter(id.eq(file_id)).first::<UploadBatchFile>(&mut self.conn()?))
+    }
+
+    /// Find the batch file entry that is being processed from given source file.
+    ///
+    /// # Arguments
+    /// * `src` - Path of the uploaded file
+    ///
+    /// # Returns
+    /// * `models::UploadBatchFile`
+    /// * `Err(NotFound)` - File is not part of any batch (or already finished)
+    pub fn get_processing_batch_file_by_src(&self, src: &str) -> DBResult<models::UploadBatchFile>
+    {
+        use models::*;
+        use schema::upload_batch_files::dsl::*;
+        to_db_res(upload_batch_files
+            .filter(src_file.eq(src))
+            .filter(status.eq(batch_file_status::PROCESSING))
+            .first::<UploadBatchFile>(&mut self.conn()?))
+    }
+
+    /// Update status of a batch file.
+    ///
+    /// # Arguments
+    /// * `file_id` - ID of the batch file
+    /// * `new_status` - New status (see `models::batch_file_status`)
+    /// * `src` - Path of the uploaded file, if known
+    /// * `vh` - Hash of the resulting video, if known
+    /// * `new_details` - Free-form details, e.g. error message
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - File not found
+    pub fn set_upload_batch_file_status(&self, file_id: i32, new_status: &str, src: Option<&str>, vh: Option<&str>, new_details: &str) -> EmptyDBResult
+    {
+        use schema::upload_batch_files::dsl::*;
+        let res = diesel::update(upload_batch_files.filter(id.eq(file_id)))
+            .set(&models::UploadBatchFileUpdate {
+                status: new_status.into(),
+                src_file: src.map(|s| s.into()),
+                video_hash: vh.map(|s| s.into()),
+                details: new_details.into() })
+            .execute(&mut self.conn()?)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Cancel an upload batch. Files that haven't been uploaded yet are marked cancelled.
+    /// Files already being processed are not affected.
+    ///
+    /// # Arguments
+    /// * `bid` - ID of the batch
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Batch not found
+    pub fn cancel_upload_batch(&self, bid: i32) -> EmptyDBResult
+    {
+        use models::*;
+        use schema::upload_batches::dsl as sb;
+        use schema::upload_batch_files::dsl as sf;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            let res = diesel::update(sb::upload_batches.filter(sb::id.eq(bid)))
+                .set(sb::cancelled.eq(true)).execute(conn)?;
+            if res == 0 { return Err(DBError::NotFound()); }
+            diesel::update(sf::upload_batch_files
+                    .filter(sf::batch_id.eq(bid))
+                    .filter(sf::status.eq(batch_file_status::PENDING)))
+                .set(sf::status.eq(batch_file_status::CANCELLED))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Mark upload batch finished, if all its files are in a final state
+    /// (done, failed or cancelled) and it wasn't marked already.
+    /// Use the return value to send a completion notification exactly once.
+    ///
+    /// # Arguments
+    /// * `bid` - ID of the batch
+    ///
+    /// # Returns
+    /// * `bool` - True if the batch was marked finished by this call
+    pub fn try_finish_upload_batch(&self, bid: i32) -> DBResult<bool>
+    {
+        use models::*;
+        use schema::upload_batches::dsl as sb;
+        use schema::upload_batch_files::dsl as sf;
+        let conn = &mut self.conn()?;
+        conn.transaction::<_, DBError, _>(|conn| {
+            let n_unfinished: i64 = sf::upload_batch_files
+                .filter(sf::batch_id.eq(bid))
+                .filter(sf::status.eq_any([batch_file_status::PENDING, batch_file_status::PROCESSING]))
+                .count().get_result(conn)?;
+            if n_unfinished > 0 { return Ok(false); }
+            let res = diesel::update(sb::upload_batches.filter(sb::id.eq(bid)).filter(sb::finished.is_null()))
+                .set(sb::finished.eq(diesel::dsl::now)).execute(conn)?;
+            Ok(res > 0)
+        })
+    }
+
+    /// Add a subtitle track to a video. Caller is responsible for storing the actual file.
+    ///
+    /// # Arguments
+    /// * `sub` - Subtitle to add
+    ///
+    /// # Returns
+    /// * `models::Subtitle` - New subtitle (with ID)
+    pub fn add_subtitle(&self, sub: &models::SubtitleInsert) -> DBResult<models::Subtitle>
+    {
+        use schema::subtitles::dsl::*;
+        Ok(diesel::insert_into(subtitles).values(sub).get_result(&mut self.conn()?)?)
+    }
+
+    /// Get a subtitle track by ID.
+    ///
+    /// # Arguments
+    /// * `sid` - ID of the subtitle
+    ///
+    /// # Returns
+    /// * `models::Subtitle`
+    /// * `Err(NotFound)` - Subtitle not found
+    pub fn get_subtitle(&self, sid: i32) -> DBResult<models::Subtitle>
+    {
+        use models::*;
+        use schema::subtitles::dsl::*;
+        to_db_res(subtitles.filter(id.eq(sid)).first::<Subtitle>(&mut self.conn()?))
+    }
+
+    /// Get all subtitle tracks of a video, in the order they were added.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash of the video
+    ///
+    /// # Returns
+    /// * `Vec<models::Subtitle>`
+    pub fn get_video_subtitles(&self, vh: &str) -> DBResult<Vec<models::Subtitle>>
+    {
+        use models::*;
+        use schema::subtitles::dsl::*;
+        Ok(subtitles.filter(video_hash.eq(vh)).order(id.asc()).load::<Subtitle>(&mut self.conn()?)?)
+    }
+
+    /// Delete a subtitle track.
+    ///
+    /// # Arguments
+    /// * `sid` - ID of the subtitle
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Subtitle not found
+    pub fn del_subtitle(&self, sid: i32) -> EmptyDBResult
+    {
+        use schema::subtitles::dsl::*;
+        let conn = &mut self.conn()?;
+        diesel::delete(schema::transcript_cues::table.filter(schema::transcript_cues::subtitle_id.eq(sid))).execute(conn)?;
+        let res = diesel::delete(subtitles.filter(id.eq(sid))).execute(conn)?;
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Replace ML analysis labels of a video.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash of the video
+    /// * `labels` - New labels (replace all old ones)
+    pub fn set_video_labels(&self, vh: &str, labels: &[models::VideoLabelInsert]) -> EmptyDBResult
+    {
+        use schema::video_labels::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            diesel::delete(video_labels.filter(video_hash.eq(vh))).execute(conn)?;
+            diesel::insert_into(video_labels).values(labels).execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Replace shots (from scene change detection) of a video.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash of the video
+    /// * `shots` - New shots (replace all old ones)
+    pub fn set_video_shots(&self, vh: &str, shots: &[models::VideoShotInsert]) -> EmptyDBResult
+    {
+        use schema::video_shots::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            diesel::delete(video_shots.filter(video_hash.eq(vh))).execute(conn)?;
+            diesel::insert_into(video_shots).values(shots).execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Get shots of a video.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash of the video
+    ///
+    /// # Returns
+    /// * `Vec<models::VideoShot>` - Shots in time order (empty if scenes weren't detected)
+    pub fn get_video_shots(&self, vh: &str) -> DBResult<Vec<models::VideoShot>>
+    {
+        use schema::video_shots::dsl::*;
+        Ok(video_shots.filter(video_hash.eq(vh)).order((start_time.asc(), id.asc())).load::<models::VideoShot>(&mut self.conn()?)?)
+    }
+
+    /// Search or list ML analysis labels.
+    ///
+    /// # Arguments
+    /// * `query` - Text to search for in labels (substring, case-insensitive), or None for all
+    /// * `video_hashes` - Only these videos, or None for all
+    /// * `lbl_kind` - Only labels of this kind, or None for all
+    /// * `min_confidence` - Skip labels with lower confidence (labels without confidence are kept)
+    /// * `limit` - Max number of results
+    ///
+    /// # Returns
+    /// * `Vec<models::VideoLabel>` - Matching labels, ordered by video and time
+    pub fn search_video_labels(&self, query: Option<&str>, video_hashes: Option<&[String]>, lbl_kind: Option<&str>, min_confidence: Option<f32>, limit: i64) -> DBResult<Vec<models::VideoLabel>>
+    {
+        use models::*;
+        use schema::video_labels::dsl::*;
+        let mut q = video_labels.into_boxed();
+        if let Some(s) = query { q = q.filter(label.like(like_pattern(s)).escape('\\')); }
+        if let Some(vhs) = video_hashes { q = q.filter(video_hash.eq_any(vhs)); }
+        if let Some(k) = lbl_kind { q = q.filter(kind.eq(k)); }
+        if let Some(c) = min_confidence { q = q.filter(confidence.ge(c).or(confidence.is_null())); }
+        Ok(q.order((video_hash.asc(), start_time.asc(), id.asc())).limit(limit).load::<VideoLabel>(&mut self.conn()?)?)
+    }
+
+    /// Add cues of a subtitle track to the transcript search index.
+    ///
+    /// # Arguments
+    /// * `cues` - Cues to add
+    ///
+    /// # Returns
+    /// * `usize` - Number of cues added
+    pub fn add_transcript_cues(&self, cues: &[models::TranscriptCueInsert]) -> DBResult<usize>
+    {
+        use schema::transcript_cues::dsl::*;
+        Ok(diesel::insert_into(transcript_cues).values(cues).execute(&mut self.conn()?)?)
+    }
+
+    /// Search subtitle tracks for spoken words (substring, case-insensitive).
+    ///
+    /// # Arguments
+    /// * `query` - Text to search for
+    /// * `video_hashes` - Only search these videos, or None for all
+    /// * `lang` - Only search tracks in this language (or its variants, e.g. "en" matches "en-US"), or None for all
+    /// * `limit` - Max number of results
+    ///
+    /// # Returns
+    /// * `Vec<models::TranscriptCue>` - Matching cues, ordered by video and time
+    pub fn search_transcripts(&self, query: &str, video_hashes: Option<&[String]>, lang: Option<&str>, limit: i64) -> DBResult<Vec<models::TranscriptCue>>
+    {
+        use models::*;
+        use schema::transcript_cues::dsl::*;
+        let mut q = transcript_cues.filter(text.like(like_pattern(query)).escape('\\')).into_boxed();
+        if let Some(vhs) = video_hashes { q = q.filter(video_hash.eq_any(vhs)); }
+        if let Some(l) = lang {
+            q = q.filter(language.eq(l).or(language.like(format!("{}-%", l.replace('%', "")))));
+        }
+        Ok(q.order((video_hash.asc(), start_time.asc())).limit(limit).load::<TranscriptCue>(&mut self.conn()?)?)
+    }
+
+    /// Record the source videos a derived video was made from.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash of the derived video
+    /// * `sources` - Hashes of the source videos, in order
+    /// * `op` - Human readable description of how the video was derived
+    pub fn add_video_sources(&self, vh: &str, sources: &[String], op: &str) -> EmptyDBResult
+    {
+        use schema::video_sources::dsl::*;
+        let rows = sources.iter().enumerate().map(|(i, s)| models::VideoSourceInsert {
+                video_hash: vh.into(), source_video_hash: s.clone(), position: i as i32, operation: op.into() })
+            .collect::<Vec<_>>();
+        diesel::insert_into(video_sources).values(&rows).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Get hashes of the videos a derived video was made from, in order.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash of the derived video
+    ///
+    /// # Returns
+    /// * `Vec<String>` - Source video hashes. Empty if the video is not derived.
+    pub fn get_video_sources(&self, vh: &str) -> DBResult<Vec<String>>
+    {
+        use schema::video_sources::dsl::*;
+        Ok(video_sources.filter(video_hash.eq(vh)).order(position.asc()).select(source_video_hash).load::<String>(&mut self.conn()?)?)
+    }
+
+    /// Get description of how a derived video was made from its sources.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash of the derived video
+    ///
+    /// # Returns
+    /// * `Option<String>` - Operation, or None if the video is not derived
+    pub fn get_video_operation(&self, vh: &str) -> DBResult<Option<String>>
+    {
+        use schema::video_sources::dsl::*;
+        Ok(video_sources.filter(video_hash.eq(vh)).order(position.asc()).select(operation)
+            .first::<String>(&mut self.conn()?).optional()?)
+    }
+
+    /// Get hashes of the videos derived from given video.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash of the source video
+    pub fn get_derived_videos(&self, vh: &str) -> DBResult<Vec<String>>
+    {
+        use schema::video_sources::dsl::*;
+        Ok(video_sources.filter(source_video_hash.eq(vh)).select(video_hash).distinct().load::<String>(&mut self.conn()?)?)
+    }
+
+    /// Record where an imported video came from.
+    ///
+    /// # Arguments
+    /// * `imp` - Import record
+    pub fn add_video_import(&self, imp: &models::VideoImportInsert) -> EmptyDBResult
+    {
+        use schema::video_imports::dsl::*;
+        diesel::insert_into(video_imports).values(imp).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Get import record of a video.
+    ///
+    /// # Arguments
+    /// * `vh` - Hash of the (local) video
+    ///
+    /// # Returns
+    /// * `models::VideoImport`
+    /// * `Err(NotFound)` - Video was not imported
+    pub fn get_video_import(&self, vh: &str) -> DBResult<models::VideoImport>
+    {
+        use schema::video_imports::dsl::*;
+        to_db_res(video_imports.filter(video_hash.eq(vh)).first::<models::VideoImport>(&mut self.conn()?))
+    }
+
+    /// Find local copies of a video imported from another instance.
+    ///
+    /// # Arguments
+    /// * `from` - Origin instance
+    /// * `origin_vh` - Hash of the video on the origin instance
+    ///
+    /// # Returns
+    /// * `Vec<String>` - Hashes of the local videos, most recently imported first
+    pub fn get_imported_copies(&self, from: &str, origin_vh: &str) -> DBResult<Vec<String>>
+    {
+        use schema::video_imports::dsl::*;
+        Ok(video_imports.filter(origin.eq(from)).filter(origin_video_hash.eq(origin_vh))
+            .order(created.desc()).select(video_hash).load::<String>(&mut self.conn()?)?)
+    }
+
+    /// Get all federation peers.
+    pub fn get_federation_peers(&self) -> DBResult<Vec<models::FederationPeer>>
+    {
+        use schema::federation_peers::dsl::*;
+        Ok(federation_peers.order(name.asc()).load::<models::FederationPeer>(&mut self.conn()?)?)
+    }
+
+    /// Get a federation peer by name.
+    ///
+    /// # Returns
+    /// * `models::FederationPeer`
+    /// * `Err(NotFound)` - No such peer
+    pub fn get_federation_peer(&self, peer_name: &str) -> DBResult<models::FederationPeer>
+    {
+        use schema::federation_peers::dsl::*;
+        to_db_res(federation_peers.filter(name.eq(peer_name)).first::<models::FederationPeer>(&mut self.conn()?))
+    }
+
+    /// Add or update a federation peer.
+    pub fn set_federation_peer(&self, peer: &models::FederationPeerInsert) -> EmptyDBResult
+    {
+        use schema::federation_peers::dsl::*;
+        diesel::insert_into(federation_peers).values(peer)
+            .on_conflict(name).do_update().set(peer).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Delete a federation peer and its folder syncs. Received objects are kept.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - No such peer
+    pub fn del_federation_peer(&self, peer_name: &str) -> EmptyDBResult
+    {
+        use schema::federation_peers::dsl::*;
+        self.conn()?.transaction::<_, DBError, _>(|conn| {
+            diesel::delete(schema::folder_syncs::table.filter(schema::folder_syncs::peer.eq(peer_name))).execute(conn)?;
+            match diesel::delete(federation_peers.filter(name.eq(peer_name))).execute(conn)? {
+                0 => Err(DBError::NotFound()),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    /// Sync a folder with a folder on a federation peer.
+    ///
+    /// # Returns
+    /// * `models::FolderSync` - The new sync
+    pub fn add_folder_sync(&self, sync: &models::FolderSyncInsert) -> DBResult<models::FolderSync>
+    {
+        use schema::folder_syncs::dsl::*;
+        Ok(diesel::insert_into(folder_syncs).values(sync).get_result(&mut self.conn()?)?)
+    }
+
+    /// Get syncs of a folder, or all of them.
+    ///
+    /// # Arguments
+    /// * `fid` - Folder ID, or None for all syncs
+    pub fn get_folder_syncs(&self, fid: Option<i32>) -> DBResult<Vec<models::FolderSync>>
+    {
+        use schema::folder_syncs::dsl::*;
+        let mut q = folder_syncs.order(id.asc()).into_boxed();
+        if let Some(fid) = fid { q = q.filter(folder_id.eq(fid)); }
+        Ok(q.load::<models::FolderSync>(&mut self.conn()?)?)
+    }
+
+    /// Get sync of a folder with given peer.
+    ///
+    /// # Returns
+    /// * `models::FolderSync`
+    /// * `Err(NotFound)` - Folder is not synced with the peer
+    pub fn get_folder_sync(&self, fid: i32, peer_name: &str) -> DBResult<models::FolderSync>
+    {
+        use schema::folder_syncs::dsl::*;
+        to_db_res(folder_syncs.filter(folder_id.eq(fid)).filter(peer.eq(peer_name)).first::<models::FolderSync>(&mut self.conn()?))
+    }
+
+    /// Stop syncing a folder with a peer.
+    ///
+    /// # Returns
+    /// * `EmptyResult`
+    /// * `Err(NotFound)` - Folder is not synced with the peer
+    pub fn del_folder_sync(&self, fid: i32, peer_name: &str) -> EmptyDBResult
+    {
+        use schema::folder_syncs::dsl::*;
+        match diesel::delete(folder_syncs.filter(folder_id.eq(fid)).filter(peer.eq(peer_name))).execute(&mut self.conn()?)? {
+            0 => Err(DBError::NotFound()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record result of a sync run.
+    ///
+    /// # Arguments
+    /// * `sync_id` - Folder sync ID
+    /// * `new_cursor` - Peer's time to continue from next time, or None to keep the old one (on error)
+    /// * `error` - Error message, or None on success
+    pub fn set_folder_sync_result(&self, sync_id: i32, new_cursor: Option<i64>, error: Option<&str>) -> EmptyDBResult
+    {
+        use schema::folder_syncs::dsl::*;
+        let conn = &mut self.conn()?;
+        let q = folder_syncs.filter(id.eq(sync_id));
+        let res = match new_cursor {
+            Some(c) => diesel::update(q).set((cursor.eq(c), last_sync.eq(diesel::dsl::now), last_error.eq(error))).execute(conn)?,
+            None => diesel::update(q).set((last_sync.eq(diesel::dsl::now), last_error.eq(error))).execute(conn)?,
+        };
+        if res == 0 { return Err(DBError::NotFound()); }
+        Ok(())
+    }
+
+    /// Record a local copy of an object received from a federation peer.
+    pub fn add_federated_object(&self, obj: &models::FederatedObjectInsert) -> EmptyDBResult
+    {
+        use schema::federated_objects::dsl::*;
+        diesel::insert_into(federated_objects).values(obj).execute(&mut self.conn()?)?;
+        Ok(())
+    }
+
+    /// Get local ID of an object received from a peer.
+    ///
+    /// # Arguments
+    /// * `peer_name` - Federation peer
+    /// * `obj_kind` - See `models::federated_kind`
+    /// * `remote` - ID of the object at the peer
+    ///
+    /// # Returns
+    /// * `Option<String>` - Local ID, or None if not received from the peer
+    pub fn get_federated_local_id(&self, peer_name: &str, obj_kind: &str, remote: &str) -> DBResult<Option<String>>
+    {
+        use schema::federated_objects::dsl::*;
+        Ok(federated_objects.filter(peer.eq(peer_name)).filter(kind.eq(obj_kind)).filter(remote_id.eq(remote))
+            .select(local_id).first::<String>(&mut self.conn()?).optional()?)
+    }
+
+    /// Get peer's ID of a local object, if it was received from the peer.
+    ///
+    /// # Arguments
+    /// * `peer_name` - Federation peer
+    /// * `obj_kind` - See `models::federated_kind`
+    /// * `local` - Local ID of the object
+    ///
+    /// # Returns
+    /// * `Option<String>` - ID at the peer, or None if not received from the peer
+    pub fn get_federated_remote_id(&self, peer_name: &str, obj_kind: &str, local: &str) -> DBResult<Option<String>>
+    {
+        use schema::federated_objects::dsl::*;
+        Ok(federated_objects.filter(peer.eq(peer_name)).filter(kind.eq(obj_kind)).filter(local_id.eq(local))
+            .select(remote_id).first::<String>(&mut self.conn()?).optional()?)
+    }
+
+    /// Get all objects of a kind received from a peer.
+    pub fn get_federated_objects(&self, peer_name: &str, obj_kind: &str) -> DBResult<Vec<models::FederatedObject>>
+    {
+        use schema::federated_objects::dsl::*;
+        Ok(federated_objects.filter(peer.eq(peer_name)).filter(kind.eq(obj_kind)).load::<models::FederatedObject>(&mut self.conn()?)?)
+    }
+
+    /// Mark a received video as placed in its folder.
+    pub fn clear_federated_pending_folder(&self, peer_name: &str, remote: &str) -> EmptyDBResult
+    {
+        use schema::federated_objects::dsl::*;
+        diesel::update(federated_objects.filter(peer.eq(peer_name)).filter(kind.eq(models::federated_kind::VIDEO)).filter(remote_id.eq(remote)))
+            .set(pending_folder_id.eq(None::<i32>)).execute(&mut self.conn()?)?;
+        Ok(())
+    }
 }
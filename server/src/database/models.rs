@@ -26,9 +26,39 @@ pub struct Video {
     pub duration: Option<f32>,
     pub fps: Option<String>,
     pub raw_metadata_all: Option<String>,
+    pub silence_trim_start: Option<f32>,
+    pub silence_trim_end: Option<f32>,
+    pub loudness_lufs: Option<f32>,
+    pub legal_hold: bool,
+    pub content_hash: Option<String>,
+    /// Integrated loudness (LUFS) the transcoded audio was normalized to, if any
+    pub loudnorm_target: Option<f32>,
+    pub color_primaries: Option<String>,
+    pub color_transfer: Option<String>,
+    /// HDR format of the original (see `hdr_format`), None for SDR
+    pub hdr_format: Option<String>,
+    /// Frame rate range of a variable frame rate (VFR) original. `fps` is then the constant rate it was converted to.
+    pub vfr_fps_min: Option<f32>,
+    pub vfr_fps_max: Option<f32>,
+    pub vfr_fps_avg: Option<f32>,
+    /// Image sequence the video was assembled from (JSON: pattern, frame range, fps), if any
+    pub image_sequence: Option<String>,
+    /// Original has no video track. Player gets a waveform video rendered from it.
+    pub audio_only: bool,
+    /// Still image or document (see `still_kind`) reviewed page by page instead of played. None for videos.
+    pub still_kind: Option<String>,
+    /// Number of rendered pages of a still (1 for images)
+    pub page_count: Option<i32>,
+    /// Owner allows others to download the original and transcoded files (see `api_server::download`)
+    pub allow_download: bool,
+    /// Folder the video is in, None for user's root
+    pub folder_id: Option<i32>,
+    /// When the video was moved to trash (see `api_server::trash`), None if not deleted
+    #[serde(with = "ts_seconds_option")]
+    pub trashed: Option<chrono::NaiveDateTime>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Insertable)]
+#[derive(Serialize, Deserialize, Debug, Default, Insertable)]
 #[diesel(table_name = videos)]
 pub struct VideoInsert {
     pub video_hash: String,
@@ -42,6 +72,21 @@ pub struct VideoInsert {
     pub duration: Option<f32>,
     pub fps: Option<String>,
     pub raw_metadata_all: Option<String>,
+    pub silence_trim_start: Option<f32>,
+    pub silence_trim_end: Option<f32>,
+    pub loudness_lufs: Option<f32>,
+    pub content_hash: Option<String>,
+    pub loudnorm_target: Option<f32>,
+    pub color_primaries: Option<String>,
+    pub color_transfer: Option<String>,
+    pub hdr_format: Option<String>,
+    pub vfr_fps_min: Option<f32>,
+    pub vfr_fps_max: Option<f32>,
+    pub vfr_fps_avg: Option<f32>,
+    pub image_sequence: Option<String>,
+    pub audio_only: bool,
+    pub still_kind: Option<String>,
+    pub page_count: Option<i32>,
 }
 
 // -------------------------------------------------------
@@ -64,6 +109,19 @@ pub struct Comment {
     pub comment: String,
     pub timecode: Option<String>,
     pub drawing: Option<String>,
+    /// Page (1-based) of a still the comment is about
+    pub page: Option<i32>,
+    /// Spatial anchor on a still page (JSON: x, y and optional w, h as fractions of page size)
+    pub region: Option<String>,
+    /// Who may see the comment, see `comment_visibility`
+    pub visibility: String,
+    /// Sequential number within the video ("#12"), for referring to it in meetings.
+    /// Kept across edits and not reused after deletion.
+    #[serde(default)]
+    pub number: i32,
+    /// End of the time range (in/out) the comment is about, if not a single point
+    #[serde(default)]
+    pub timecode_end: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Insertable)]
@@ -77,6 +135,279 @@ pub struct CommentInsert {
     pub comment: String,
     pub timecode: Option<String>,
     pub drawing: Option<String>,
+    pub page: Option<i32>,
+    pub region: Option<String>,
+    pub visibility: String,
+    #[serde(default)]
+    pub timecode_end: Option<String>,
+}
+
+impl Comment {
+    pub fn is_internal(&self) -> bool { self.visibility == comment_visibility::INTERNAL }
+    /// Text has been changed since the comment was written (see `CommentRevision`)
+    pub fn is_edited(&self) -> bool { self.edited.is_some() }
+    /// Written by a share link guest (see `Guest`)
+    pub fn is_guest(&self) -> bool { self.user_id.starts_with(GUEST_USER_PREFIX) }
+}
+
+/// Visibility of a comment (see `comments.visibility`)
+pub mod comment_visibility {
+    /// Only for the owner's side: owner, teams the video is shared with, and admins.
+    /// Never shown to share link guests or synced to federation peers.
+    pub const INTERNAL: &str = "internal";
+    /// Everyone who can view the video
+    pub const EXTERNAL: &str = "external";
+
+    pub fn is_valid(v: &str) -> bool {
+        [INTERNAL, EXTERNAL].contains(&v)
+    }
+}
+
+/// File attached to a comment (see `api_server::attachments`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = comment_attachments)]
+pub struct CommentAttachment {
+    pub id: i32,
+    pub comment_id: i32,
+    pub video_hash: String,
+    pub user_id: String,
+    /// Name the file was uploaded with
+    pub filename: String,
+    pub mime_type: String,
+    pub size: i32,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Clone)]
+#[diesel(table_name = comment_attachments)]
+pub struct CommentAttachmentInsert {
+    pub comment_id: i32,
+    pub video_hash: String,
+    pub user_id: String,
+    pub filename: String,
+    pub mime_type: String,
+    pub size: i32,
+}
+
+/// Earlier text of an edited comment
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = comment_revisions)]
+pub struct CommentRevision {
+    pub id: i32,
+    pub comment_id: i32,
+    pub comment: String,
+
+    /// When this text was written (comment created, or previous edit)
+    #[serde(with = "ts_seconds")]
+    pub written: chrono::NaiveDateTime,
+
+    /// When it was replaced by the next revision
+    #[serde(with = "ts_seconds")]
+    pub replaced: chrono::NaiveDateTime,
+
+    /// User who replaced it, None if edited elsewhere (federation peer)
+    pub replaced_by: Option<String>,
+}
+
+/// Emoji reaction of a user to a comment
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = comment_reactions, primary_key(comment_id, user_id, emoji))]
+pub struct CommentReaction {
+    pub comment_id: i32,
+    pub user_id: String,
+    pub emoji: String,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+pub mod comment_reaction {
+    use super::CommentReaction;
+
+    /// Max different emojis a user can react to one comment with
+    pub const MAX_PER_USER: usize = 10;
+
+    /// A single emoji (possibly a ZWJ sequence or a flag), not text
+    pub fn is_valid(emoji: &str) -> bool {
+        let n_chars = emoji.chars().count();
+        (1..=16).contains(&n_chars) && !emoji.chars().any(|c| c.is_ascii() || c.is_whitespace() || c.is_control())
+    }
+
+    /// Reactions of a comment counted per emoji, in order of first reaction:
+    /// `[{"emoji": "👍", "count": 2, "users": [user ids]}, ...]`
+    pub fn summarize(reactions: &[CommentReaction]) -> serde_json::Value {
+        let mut res: Vec<(&str, Vec<&str>)> = Vec::new();
+        for r in reactions {
+            match res.iter_mut().find(|(e, _)| *e == r.emoji) {
+                Some((_, users)) => users.push(&r.user_id),
+                None => res.push((&r.emoji, vec![&r.user_id])),
+            }
+        }
+        res.into_iter().map(|(emoji, users)| serde_json::json!({ "emoji": emoji, "count": users.len(), "users": users }))
+            .collect::<Vec<_>>().into()
+    }
+}
+
+// -------------------------------------------------------
+
+/// Private note on a video, only visible to its author. Anchored like a comment,
+/// and can be published as one.
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = notes)]
+pub struct Note {
+    pub id: i32,
+    pub video_hash: String,
+    pub user_id: String,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+
+    #[serde(with = "ts_seconds_option")]
+    pub edited: Option<chrono::NaiveDateTime>,
+
+    pub note: String,
+    pub timecode: Option<String>,
+    pub page: Option<i32>,
+    pub region: Option<String>,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = notes)]
+pub struct NoteInsert {
+    pub video_hash: String,
+    pub user_id: String,
+    pub note: String,
+    pub timecode: Option<String>,
+    pub page: Option<i32>,
+    pub region: Option<String>,
+}
+
+// -------------------------------------------------------
+
+/// Free-form tag on a video. Tags are case-insensitive (but keep the case they were added with).
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Clone)]
+#[diesel(table_name = video_tags)]
+pub struct VideoTag {
+    pub video_hash: String,
+    pub tag: String,
+    pub added_by: String,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+// -------------------------------------------------------
+
+/// Metadata field defined by admin (e.g. "Shot code", "Department"), filled per video.
+/// Values are stored per video as a JSON object, see `DB::update_video_custom_fields`.
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Insertable, Clone, PartialEq)]
+#[diesel(table_name = custom_fields, primary_key(name))]
+pub struct CustomField {
+    pub name: String,
+    pub title: String,
+    /// See `custom_field_type`
+    pub field_type: String,
+    /// JSON list of allowed values, for `select` fields
+    pub options: Option<String>,
+}
+
+/// Types of custom fields
+pub mod custom_field_type {
+    pub const TEXT: &str = "text";
+    pub const NUMBER: &str = "number";
+    pub const SELECT: &str = "select";
+
+    pub fn is_valid(t: &str) -> bool {
+        [TEXT, NUMBER, SELECT].contains(&t)
+    }
+}
+
+/// Values of custom fields of a video (field name -> value)
+pub type CustomFieldValues = serde_json::Map<String, serde_json::Value>;
+
+/// Max length of a text field value
+pub const MAX_CUSTOM_FIELD_TEXT_LEN: usize = 1000;
+
+impl CustomField {
+    /// Allowed values of a `select` field (empty for other types)
+    pub fn select_options(&self) -> Vec<String> {
+        self.options.as_deref().and_then(|o| serde_json::from_str(o).ok()).unwrap_or_default()
+    }
+
+    /// Check a value given for this field, and normalize it.
+    ///
+    /// # Returns
+    /// * `Ok(value)` - Value to store. Null (or empty text) clears the field.
+    /// * `Err(msg)` - Value doesn't match field type
+    pub fn validate(&self, v: &serde_json::Value) -> Result<serde_json::Value, String> {
+        use serde_json::Value;
+        if v.is_null() { return Ok(Value::Null); }
+        match self.field_type.as_str() {
+            custom_field_type::TEXT => match v.as_str().map(str::trim) {
+                Some("") => Ok(Value::Null),
+                Some(t) if t.chars().count() <= MAX_CUSTOM_FIELD_TEXT_LEN => Ok(Value::from(t)),
+                Some(_) => Err(format!("'{}' is too long (max {} characters).", self.title, MAX_CUSTOM_FIELD_TEXT_LEN)),
+                None => Err(format!("'{}' must be text.", self.title)),
+            },
+            custom_field_type::NUMBER => match v.as_f64() {
+                Some(n) if n.is_finite() => Ok(v.clone()),
+                _ => Err(format!("'{}' must be a number.", self.title)),
+            },
+            custom_field_type::SELECT => match v.as_str() {
+                Some(o) if self.select_options().iter().any(|x| x == o) => Ok(Value::from(o)),
+                _ => Err(format!("'{}' must be one of: {}.", self.title, self.select_options().join(", "))),
+            },
+            t => Err(format!("Field '{}' has unknown type '{}'.", self.name, t)),
+        }
+    }
+}
+
+// -------------------------------------------------------
+
+/// Verdict of a reviewer on a video
+pub mod review_verdict {
+    pub const APPROVED: &str = "approved";
+    pub const CHANGES_REQUESTED: &str = "changes_requested";
+    /// Aggregate status of a video with no verdicts yet (see `aggregate`)
+    pub const PENDING: &str = "pending";
+
+    pub fn is_valid(v: &str) -> bool {
+        [APPROVED, CHANGES_REQUESTED].contains(&v)
+    }
+
+    /// Overall status of a video: changes requested if anyone requested them,
+    /// approved if everyone who gave a verdict approved, pending if no one did.
+    pub fn aggregate(verdicts: &[super::ReviewVerdict]) -> &'static str {
+        if verdicts.is_empty() { PENDING }
+        else if verdicts.iter().any(|v| v.verdict == CHANGES_REQUESTED) { CHANGES_REQUESTED }
+        else { APPROVED }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = review_verdicts)]
+pub struct ReviewVerdict {
+    pub id: i32,
+    pub video_hash: String,
+    pub user_id: String,
+    pub username: String,
+    pub verdict: String,
+    pub note: Option<String>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = review_verdicts)]
+pub struct ReviewVerdictInsert {
+    pub video_hash: String,
+    pub user_id: String,
+    pub username: String,
+    pub verdict: String,
+    pub note: Option<String>,
 }
 
 // -------------------------------------------------------
@@ -109,14 +440,1068 @@ pub struct MessageInsert {
     pub details: String,
 }
 
+// -------------------------------------------------------
+
+/// Processing stages that are persisted as jobs (see `jobs` table)
+pub mod job_stage {
+    pub const METADATA: &str = "metadata";
+    pub const TRANSCODE: &str = "transcode";
+    pub const THUMBNAIL: &str = "thumbnail";
+    /// Stream copy of a browser-compatible source into MP4 (see `video_pipeline::bypass`)
+    pub const REMUX: &str = "remux";
+    /// Clip / animated GIF export of a time range
+    pub const EXPORT: &str = "export";
+    /// Review package (zip for external handoff)
+    pub const PACKAGE: &str = "package";
+    /// ML analysis (labels for faces, objects, text on screen)
+    pub const ANALYSIS: &str = "analysis";
+    /// Scene change detection (shot boundaries and thumbnails)
+    pub const SCENES: &str = "scenes";
+}
+
+/// Job lifecycle: pending -> running (handed to a worker pool) -> done | failed
+pub mod job_status {
+    pub const PENDING: &str = "pending";
+    pub const RUNNING: &str = "running";
+    pub const DONE: &str = "done";
+    pub const FAILED: &str = "failed";
+}
+
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+pub struct Job {
+    pub id: i32,
+    pub stage: String,
+    pub status: String,
+    pub user_id: String,
+    pub video_hash: Option<String>,
+    pub src_file: String,
+    pub dst: Option<String>,
+    pub video_bitrate: Option<i32>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+
+    #[serde(with = "ts_seconds")]
+    pub updated: chrono::NaiveDateTime,
+
+    pub details: String,
+    /// Loudness normalization setting (see `video_pipeline::LoudnormOpt`)
+    pub loudnorm: Option<String>,
+    /// Overlays to burn in, as JSON (see `video_pipeline::burn_in::BurnIn`)
+    pub burn_in: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = jobs)]
+pub struct JobInsert {
+    pub stage: String,
+    pub status: String,
+    pub user_id: String,
+    pub video_hash: Option<String>,
+    pub src_file: String,
+    pub dst: Option<String>,
+    pub video_bitrate: Option<i32>,
+    pub loudnorm: Option<String>,
+    pub burn_in: Option<String>,
+}
+
+/// One try at a transcode job, with ffmpeg's error output (tail). Failed attempts are
+/// retried with safer settings, see `video_compressor::TRANSCODE_ATTEMPTS`.
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = job_attempts)]
+pub struct JobAttempt {
+    pub id: i32,
+    pub job_id: i32,
+    /// 1 for the first try
+    pub attempt: i32,
+    /// Description of the settings used
+    pub settings: String,
+    pub success: bool,
+    pub stderr: String,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = job_attempts)]
+pub struct JobAttemptInsert {
+    pub job_id: i32,
+    pub attempt: i32,
+    pub settings: String,
+    pub success: bool,
+    pub stderr: String,
+}
+
+// -------------------------------------------------------
+
+/// Actions recorded in the audit log (see `audit_log` table)
+pub mod audit_action {
+    pub const DELETE_VIDEO: &str = "delete_video";
+    pub const DELETE_VIDEO_BLOCKED: &str = "delete_video_blocked";
+    pub const RESTORE_VIDEO: &str = "restore_video";
+    pub const PURGE_VIDEO: &str = "purge_video";
+    pub const REASSIGN_VIDEO: &str = "reassign_video";
+    pub const USER_UPDATED: &str = "user_updated";
+    pub const GUEST_PROMOTED: &str = "guest_promoted";
+    pub const LEGAL_HOLD_SET: &str = "legal_hold_set";
+    pub const LEGAL_HOLD_LIFTED: &str = "legal_hold_lifted";
+    pub const LEGAL_HOLD_DENIED: &str = "legal_hold_denied";
+    pub const REVIEW_CLOSED: &str = "review_closed";
+    pub const REVIEW_REOPENED: &str = "review_reopened";
+    pub const FEDERATION_PEER_SET: &str = "federation_peer_set";
+    pub const FEDERATION_PEER_DELETED: &str = "federation_peer_deleted";
+    pub const FOLDER_SYNC_ADDED: &str = "folder_sync_added";
+    pub const FOLDER_SYNC_REMOVED: &str = "folder_sync_removed";
+    pub const TEAM_MEMBER_SET: &str = "team_member_set";
+    pub const TEAM_MEMBER_REMOVED: &str = "team_member_removed";
+    pub const TEAM_SHARE_ADDED: &str = "team_share_added";
+    pub const TEAM_SHARE_REMOVED: &str = "team_share_removed";
+    pub const SHARE_LINK_CREATED: &str = "share_link_created";
+    /// Orphaned files and dangling DB rows removed (see `api_server::reconcile`)
+    pub const RECONCILE: &str = "reconcile";
+    pub const BACKUP: &str = "backup";
+    pub const SHARE_LINK_REVOKED: &str = "share_link_revoked";
+    pub const UPLOAD_QUARANTINED: &str = "upload_quarantined";
+}
+
+/// Number and total duration of finished jobs per day, stage and status
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Insertable, Clone, PartialEq)]
+#[diesel(table_name = job_stats)]
+pub struct JobStat {
+    pub day: chrono::NaiveDate,
+    pub stage: String,
+    pub status: String,
+    pub n_jobs: i32,
+    /// Sum of job durations (created to last update), in seconds
+    pub total_secs: i64,
+}
+
+/// Pipeline throughput per hour and stage, summed over jobs finished during the hour
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Insertable, Clone, PartialEq)]
+#[diesel(table_name = throughput_stats)]
+pub struct ThroughputStat {
+    #[serde(with = "ts_seconds")]
+    pub hour: chrono::NaiveDateTime,
+    pub stage: String,
+    pub n_jobs: i32,
+    /// Duration of processed videos, in seconds
+    pub video_secs: f64,
+    /// Time workers spent processing, in seconds
+    pub work_secs: f64,
+    /// Time jobs waited in queue before processing, in seconds
+    pub wait_secs: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = audit_log)]
+pub struct AuditEvent {
+    pub id: i32,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+
+    pub user_id: String,
+    pub action: String,
+    pub ref_video_hash: Option<String>,
+    pub details: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = audit_log)]
+pub struct AuditEventInsert {
+    pub user_id: String,
+    pub action: String,
+    pub ref_video_hash: Option<String>,
+    pub details: String,
+}
+
+// -------------------------------------------------------
+
+/// Processing priority of a user's jobs. Higher values are processed first.
+/// Users without a row have priority 0.
+#[derive(Serialize, Deserialize, Debug, Default, Queryable, Selectable, Identifiable, Insertable, Clone)]
+#[diesel(table_name = user_priorities)]
+#[diesel(primary_key(user_id))]
+pub struct UserPriority {
+    pub user_id: String,
+    pub priority: i32,
+}
+
+/// User seen by the server (from reverse proxy's auth headers), with admin's settings for them
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = users, primary_key(user_id))]
+pub struct User {
+    pub user_id: String,
+    pub username: String,
+    /// Can use admin commands and act on other users' videos
+    pub is_admin: bool,
+    /// Can't connect or upload
+    pub disabled: bool,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+
+    #[serde(with = "ts_seconds_option")]
+    pub last_seen: Option<chrono::NaiveDateTime>,
+
+    /// Name and ID normalized for lookup, see `user_search_key`
+    #[serde(skip)]
+    pub search_key: String,
+
+    /// When user got the first login onboarding (see `api_server::onboarding`)
+    #[serde(with = "ts_seconds_option")]
+    pub onboarded: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = users)]
+pub struct UserInsert {
+    pub user_id: String,
+    pub username: String,
+    pub search_key: String,
+}
+
+impl UserInsert {
+    pub fn new(uid: &str, uname: &str) -> UserInsert {
+        UserInsert { user_id: uid.into(), username: uname.into(), search_key: user_search_key(uid, uname) }
+    }
+}
+
+/// Normalize text for spelling-insensitive matching: lowercase, without diacritics
+/// ("Järvinen" -> "jarvinen") and with whitespace collapsed.
+pub fn normalize_for_search(s: &str) -> String {
+    use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
+    let folded = s.nfkd().filter(|c| !is_combining_mark(*c)).flat_map(char::to_lowercase)
+        .map(|c| match c {
+            // Letters that don't decompose into base letter + mark
+            'ø' => "o".into(), 'æ' => "ae".into(), 'œ' => "oe".into(), 'ß' => "ss".into(),
+            'đ' | 'ð' => "d".into(), 'ł' => "l".into(), 'þ' => "th".into(), 'ı' => "i".into(),
+            c => c.to_string(),
+        }).collect::<String>();
+    folded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Search key of a user: their name and ID, normalized (see `normalize_for_search`)
+pub fn user_search_key(uid: &str, uname: &str) -> String {
+    normalize_for_search(&format!("{} {}", uname, uid))
+}
+
+/// Group of users that videos and folders can be shared with
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = teams)]
+pub struct Team {
+    pub id: i32,
+    pub name: String,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = teams)]
+pub struct TeamInsert {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Insertable, Clone, PartialEq)]
+#[diesel(table_name = team_members)]
+pub struct TeamMember {
+    pub team_id: i32,
+    pub user_id: String,
+    /// Can add and remove members
+    pub is_team_admin: bool,
+}
+
+/// Video shared with a team
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Insertable, Clone)]
+#[diesel(table_name = team_videos)]
+pub struct TeamVideo {
+    pub team_id: i32,
+    pub video_hash: String,
+    pub shared_by: String,
+}
+
+/// Folder (and its subfolders) shared with a team
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Insertable, Clone)]
+#[diesel(table_name = team_folders)]
+pub struct TeamFolder {
+    pub team_id: i32,
+    pub folder_id: i32,
+    pub shared_by: String,
+}
+
+/// Upload that's identical to a video the user can see (e.g. shared with their team),
+/// waiting for user to either link the existing video or store a copy
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = pending_uploads)]
+pub struct PendingUpload {
+    pub id: i32,
+    pub user_id: String,
+    pub src_file: String,
+    /// Hash of the existing video
+    pub duplicate_of: String,
+    /// Loudness normalization requested on upload (see `video_pipeline::LoudnormOpt`)
+    pub loudnorm: Option<String>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = pending_uploads)]
+pub struct PendingUploadInsert {
+    pub user_id: String,
+    pub src_file: String,
+    pub duplicate_of: String,
+    pub loudnorm: Option<String>,
+}
+
+// -------------------------------------------------------
+
+/// Public link to a video, for guests without an account (see `api_server::share_links`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = share_links)]
+pub struct ShareLink {
+    pub id: i32,
+    pub token: String,
+    pub video_hash: String,
+    pub created_by: String,
+    /// Guests may comment (under a name of their choice)
+    pub allow_comments: bool,
+    #[serde(skip_serializing)]
+    pub password_hash: Option<String>,
+
+    #[serde(with = "ts_seconds_option")]
+    pub expires: Option<chrono::NaiveDateTime>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = share_links)]
+pub struct ShareLinkInsert {
+    pub token: String,
+    pub video_hash: String,
+    pub created_by: String,
+    pub allow_comments: bool,
+    pub password_hash: Option<String>,
+    pub expires: Option<chrono::NaiveDateTime>,
+}
+
+/// User ID prefix of share link guests, followed by `Guest::id`
+pub const GUEST_USER_PREFIX: &str = "guest:";
+
+/// Identity of a share link guest, with the display name they gave
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = guests)]
+pub struct Guest {
+    pub id: i32,
+    pub share_link_id: i32,
+    pub name: String,
+    /// Secret for resuming the identity on reconnect
+    #[serde(skip_serializing)]
+    pub key: String,
+    /// User ID that an admin moved guest's comments to
+    pub promoted_to: Option<String>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+impl Guest {
+    pub fn user_id(&self) -> String { format!("{}{}", GUEST_USER_PREFIX, self.id) }
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = guests)]
+pub struct GuestInsert {
+    pub share_link_id: i32,
+    pub name: String,
+    pub key: String,
+}
+
+// -------------------------------------------------------
+
+/// Closed review round of a video. New comments are rejected until it's reopened (row deleted).
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Insertable, Clone)]
+#[diesel(table_name = closed_reviews, primary_key(video_hash))]
+pub struct ClosedReview {
+    pub video_hash: String,
+    pub closed_by: String,
+
+    #[serde(with = "ts_seconds")]
+    pub closed: chrono::NaiveDateTime,
+}
+
+// -------------------------------------------------------
+
+/// Status of a file in an upload batch (see `upload_batch_files` table)
+pub mod batch_file_status {
+    /// Waiting for upload
+    pub const PENDING: &str = "pending";
+    /// Uploaded, being processed
+    pub const PROCESSING: &str = "processing";
+    pub const DONE: &str = "done";
+    pub const FAILED: &str = "failed";
+    /// Batch was cancelled before this file was uploaded
+    pub const CANCELLED: &str = "cancelled";
+
+    pub fn is_final(status: &str) -> bool {
+        [DONE, FAILED, CANCELLED].contains(&status)
+    }
+}
+
+/// High dynamic range format of a video
+pub mod hdr_format {
+    /// HDR10 and other PQ (SMPTE ST 2084) based formats
+    pub const PQ: &str = "PQ";
+    /// Hybrid Log-Gamma (ARIB STD-B67)
+    pub const HLG: &str = "HLG";
+    pub const DOLBY_VISION: &str = "Dolby Vision";
+}
+
+/// Kind of a still (non-video) review item
+pub mod still_kind {
+    /// Single image (PNG, JPEG, TIFF)
+    pub const IMAGE: &str = "image";
+    /// Multi-page PDF document
+    pub const PDF: &str = "pdf";
+}
+
+/// Where a subtitle track came from
+pub mod subtitle_origin {
+    /// Sidecar file uploaded with the video, or attached to it later
+    pub const UPLOAD: &str = "upload";
+    /// Extracted from a subtitle track inside the video file
+    pub const EMBEDDED: &str = "embedded";
+}
+
+/// Subtitle (caption) track of a video. Stored as WebVTT in `<video dir>/subs/<id>.vtt`
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = subtitles)]
+pub struct Subtitle {
+    pub id: i32,
+    pub video_hash: String,
+    pub language: Option<String>,
+    pub title: String,
+    pub origin: String,
+
+    #[serde(with = "ts_seconds")]
+    pub added: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = subtitles)]
+pub struct SubtitleInsert {
+    pub video_hash: String,
+    pub language: Option<String>,
+    pub title: String,
+    pub origin: String,
+}
+
+/// One cue (timed line) of a subtitle track, indexed for spoken-word search
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = transcript_cues)]
+pub struct TranscriptCue {
+    pub id: i32,
+    pub subtitle_id: i32,
+    pub video_hash: String,
+    pub language: Option<String>,
+    /// Seconds from start of the video
+    pub start_time: f32,
+    pub end_time: f32,
+    pub text: String,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = transcript_cues)]
+pub struct TranscriptCueInsert {
+    pub subtitle_id: i32,
+    pub video_hash: String,
+    pub language: Option<String>,
+    pub start_time: f32,
+    pub end_time: f32,
+    pub text: String,
+}
+
+/// Time-ranged label from ML analysis (face, object, text on screen...)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = video_labels)]
+pub struct VideoLabel {
+    pub id: i32,
+    pub video_hash: String,
+    /// What was detected, see `label_kind`. Analyzers may report other kinds, too.
+    pub kind: String,
+    pub label: String,
+    /// Seconds from start of the video
+    pub start_time: f32,
+    pub end_time: f32,
+    /// 0..1, if the analyzer reports it
+    pub confidence: Option<f32>,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[diesel(table_name = video_labels)]
+pub struct VideoLabelInsert {
+    pub video_hash: String,
+    pub kind: String,
+    pub label: String,
+    pub start_time: f32,
+    pub end_time: f32,
+    pub confidence: Option<f32>,
+}
+
+/// Shot of a video, between two scene changes (see `video_pipeline::scenes`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = video_shots)]
+pub struct VideoShot {
+    pub id: i32,
+    pub video_hash: String,
+    /// Seconds from start of the video
+    pub start_time: f32,
+    pub end_time: f32,
+    /// Thumbnail of the shot's first frame, path in video's dir
+    pub thumb: Option<String>,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[diesel(table_name = video_shots)]
+pub struct VideoShotInsert {
+    pub video_hash: String,
+    pub start_time: f32,
+    pub end_time: f32,
+    pub thumb: Option<String>,
+}
+
+/// Common kinds of ML analysis labels
+pub mod label_kind {
+    pub const FACE: &str = "face";
+    pub const OBJECT: &str = "object";
+    /// Text on screen (OCR)
+    pub const TEXT: &str = "text";
+}
+
+/// Provenance of a derived video (e.g. stitched from several videos): one row per source, in order
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = video_sources)]
+pub struct VideoSource {
+    pub id: i32,
+    pub video_hash: String,
+    pub source_video_hash: String,
+    pub position: i32,
+    /// What was done to the sources, e.g. "Stitched from 3 videos"
+    pub operation: String,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = video_sources)]
+pub struct VideoSourceInsert {
+    pub video_hash: String,
+    pub source_video_hash: String,
+    pub position: i32,
+    /// What was done to the sources, e.g. "Stitched from 3 videos"
+    pub operation: String,
+}
+
+/// Video imported from a review package exported by another instance (see `api_server::package_import`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = video_imports, primary_key(video_hash))]
+pub struct VideoImport {
+    pub video_hash: String,
+    /// Instance the package came from (its URL base)
+    pub origin: String,
+    /// Hash of the video on the origin instance
+    pub origin_video_hash: String,
+    pub imported_by: String,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = video_imports)]
+pub struct VideoImportInsert {
+    pub video_hash: String,
+    pub origin: String,
+    pub origin_video_hash: String,
+    pub imported_by: String,
+}
+
+/// User's folder for organizing videos. Folders can be nested.
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = folders)]
+pub struct Folder {
+    pub id: i32,
+    pub user_id: String,
+    pub title: String,
+    pub parent_id: Option<i32>,
+    /// Comma separated names of overlay presets shown by default for videos in this folder
+    /// (and subfolders). None inherits from parent folder, empty string shows none.
+    pub overlay_defaults: Option<String>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+    /// Transcode preset for videos uploaded into this folder (and subfolders). None inherits from parent folder.
+    pub transcode_preset: Option<String>,
+    /// Videos in this folder (and subfolders) are under legal hold, too
+    pub legal_hold: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = folders)]
+pub struct FolderInsert {
+    pub user_id: String,
+    pub title: String,
+    pub parent_id: Option<i32>,
+}
+
+/// Another Clapshot instance that folders can be synced with (see `api_server::federation`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = federation_peers, primary_key(name))]
+pub struct FederationPeer {
+    pub name: String,
+    /// URL base of the peer's API server
+    pub url: String,
+    /// Shared secret for signing requests in both directions
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// Name this instance is known by at the peer
+    pub self_name: String,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, AsChangeset, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = federation_peers)]
+pub struct FederationPeerInsert {
+    pub name: String,
+    pub url: String,
+    pub secret: String,
+    pub self_name: String,
+}
+
+/// Folder synced with a folder on a federation peer
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = folder_syncs)]
+pub struct FolderSync {
+    pub id: i32,
+    pub folder_id: i32,
+    pub peer: String,
+    pub remote_folder_id: i32,
+    /// Peer's time (Unix seconds) up to which changes have been pulled
+    pub cursor: i64,
+
+    #[serde(with = "ts_seconds_option")]
+    pub last_sync: Option<chrono::NaiveDateTime>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = folder_syncs)]
+pub struct FolderSyncInsert {
+    pub folder_id: i32,
+    pub peer: String,
+    pub remote_folder_id: i32,
+}
+
+/// Local copy of a video or comment received from a federation peer. Kept after the local
+/// copy is deleted, so it isn't received again.
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Clone)]
+#[diesel(table_name = federated_objects)]
+pub struct FederatedObject {
+    pub peer: String,
+    /// See `federated_kind`
+    pub kind: String,
+    /// ID at the peer (video hash or comment ID)
+    pub remote_id: String,
+    pub local_id: String,
+    /// Folder to put a video in once it's ingested
+    pub pending_folder_id: Option<i32>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(table_name = federated_objects)]
+pub struct FederatedObjectInsert {
+    pub peer: String,
+    pub kind: String,
+    pub remote_id: String,
+    pub local_id: String,
+    pub pending_folder_id: Option<i32>,
+}
+
+/// Kinds of federated objects
+pub mod federated_kind {
+    pub const VIDEO: &str = "video";
+    pub const COMMENT: &str = "comment";
+}
+
+/// Framing guide the player draws over video (safe area, aspect ratio crop).
+/// Defined per deployment by admin.
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Insertable, AsChangeset, Clone, PartialEq)]
+#[diesel(table_name = overlay_presets, primary_key(name))]
+pub struct OverlayPreset {
+    pub name: String,
+    pub title: String,
+    /// See `overlay_kind`
+    pub kind: String,
+    /// `safe_area`: inset from each edge, as fraction of frame size. `aspect`: width/height ratio of centered crop.
+    pub value: f32,
+    /// Shown by default, unless video's folder overrides it
+    pub default_on: bool,
+}
+
+/// Kinds of overlay presets
+pub mod overlay_kind {
+    pub const SAFE_AREA: &str = "safe_area";
+    pub const ASPECT: &str = "aspect";
+}
+
+/// Group of files uploaded together (e.g. a folder), tracked as one unit
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = upload_batches)]
+pub struct UploadBatch {
+    pub id: i32,
+    pub user_id: String,
+    pub title: String,
+    pub cancelled: bool,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+
+    #[serde(with = "ts_seconds_option")]
+    pub finished: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = upload_batches)]
+pub struct UploadBatchInsert {
+    pub user_id: String,
+    pub title: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Associations, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(belongs_to(UploadBatch, foreign_key = batch_id))]
+#[diesel(table_name = upload_batch_files)]
+pub struct UploadBatchFile {
+    pub id: i32,
+    pub batch_id: i32,
+    pub filename: String,
+    pub status: String,
+    /// Path of the uploaded file, for matching processing results to the batch
+    #[serde(skip)]
+    pub src_file: Option<String>,
+    pub video_hash: Option<String>,
+    pub details: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = upload_batch_files)]
+pub struct UploadBatchFileInsert {
+    pub batch_id: i32,
+    pub filename: String,
+    pub status: String,
+}
+
+/// Status update for a batch file. `None` fields are left unchanged.
+#[derive(Debug, Default, AsChangeset)]
+#[diesel(table_name = upload_batch_files)]
+pub struct UploadBatchFileUpdate {
+    pub status: String,
+    pub src_file: Option<String>,
+    pub video_hash: Option<String>,
+    pub details: String,
+}
+
+/// Scheduled maintenance, announced on public status page (see `api_server::status_page`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = maintenance_windows)]
+pub struct MaintenanceWindow {
+    pub id: i32,
+
+    #[serde(with = "ts_seconds")]
+    pub starts: chrono::NaiveDateTime,
+
+    #[serde(with = "ts_seconds")]
+    pub ends: chrono::NaiveDateTime,
+
+    /// Public notice, e.g. "Storage upgrade, uploads paused"
+    pub message: String,
+    pub created_by: String,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = maintenance_windows)]
+pub struct MaintenanceWindowInsert {
+    pub starts: chrono::NaiveDateTime,
+    pub ends: chrono::NaiveDateTime,
+    pub message: String,
+    pub created_by: String,
+}
+
+/// Admin's feature flag setting for everyone, a user or a team (see `api_server::feature_flags`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = feature_overrides, primary_key(feature, target))]
+pub struct FeatureOverride {
+    pub feature: String,
+    /// "*", "user:<user id>" or "team:<team id>"
+    pub target: String,
+    pub enabled: bool,
+    pub set_by: String,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = feature_overrides)]
+pub struct FeatureOverrideInsert {
+    pub feature: String,
+    pub target: String,
+    pub enabled: bool,
+    pub set_by: String,
+}
+
+/// Browser's Web Push subscription (see `api_server::web_push`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = push_subscriptions)]
+pub struct PushSubscription {
+    pub id: i32,
+    pub user_id: String,
+    /// Push service URL that delivers to the browser
+    pub endpoint: String,
+    /// Browser's public key (P-256, base64url), for encrypting payloads
+    pub p256dh: String,
+    /// Browser's auth secret (base64url)
+    pub auth: String,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = push_subscriptions)]
+pub struct PushSubscriptionInsert {
+    pub user_id: String,
+    pub endpoint: String,
+    pub p256dh: String,
+    pub auth: String,
+}
+
+/// Events a user wants as push notifications (all off by default)
+#[derive(Serialize, Deserialize, Debug, Default, Queryable, Selectable, Identifiable, Insertable, Clone, PartialEq)]
+#[diesel(table_name = push_prefs, primary_key(user_id))]
+pub struct PushPrefs {
+    pub user_id: String,
+    /// User is mentioned in a comment
+    pub mentions: bool,
+    /// New comments on user's own videos
+    pub comments: bool,
+    /// User's uploads and exports are done (or failed)
+    pub processing: bool,
+}
+
+/// User's own display name, avatar and time zone (see `api_server::profiles`)
+#[derive(Serialize, Deserialize, Debug, Default, Queryable, Selectable, Identifiable, Insertable, Clone, PartialEq)]
+#[diesel(table_name = user_profiles, primary_key(user_id))]
+pub struct UserProfile {
+    pub user_id: String,
+    /// Shown instead of the name from the auth proxy
+    pub display_name: Option<String>,
+    /// File name in the avatars dir
+    pub avatar: Option<String>,
+    /// IANA time zone name (e.g. "Europe/Helsinki") or UTC offset ("+02:00")
+    pub timezone: Option<String>,
+}
+
+/// User's client setting (see `api_server::user_prefs`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = user_prefs, primary_key(user_id, key))]
+pub struct UserPref {
+    pub user_id: String,
+    pub key: String,
+    /// JSON
+    pub value: String,
+
+    #[serde(with = "ts_seconds")]
+    pub updated: chrono::NaiveDateTime,
+}
+
+/// Comment pushed to a production tracker as a note, or a note reply pulled from it
+/// (see `api_server::tracker_sync`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = tracker_notes, primary_key(comment_id))]
+pub struct TrackerNote {
+    pub comment_id: i32,
+    pub video_hash: String,
+    /// Tracker kind, e.g. "shotgrid"
+    pub tracker: String,
+    /// ID of the note (or reply) in the tracker
+    pub remote_id: String,
+    /// For pulled replies, ID of the note they reply to
+    pub reply_to: Option<String>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = tracker_notes)]
+pub struct TrackerNoteInsert {
+    pub comment_id: i32,
+    pub video_hash: String,
+    pub tracker: String,
+    pub remote_id: String,
+    pub reply_to: Option<String>,
+}
+
+/// Comment waiting to be pushed to the production tracker
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = tracker_outbox)]
+pub struct TrackerOutboxItem {
+    pub id: i32,
+    pub comment_id: i32,
+    pub video_hash: String,
+    /// Failed attempts so far
+    pub attempts: i32,
+
+    #[serde(with = "ts_seconds")]
+    pub next_attempt: chrono::NaiveDateTime,
+    pub last_error: Option<String>,
+}
+
+/// Collab session scheduled for a later time (see `api_server::review_schedule`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = review_sessions)]
+pub struct ReviewSession {
+    pub id: i32,
+    pub collab_id: String,
+    pub video_hash: String,
+    /// User ID of the scheduler, host of the collab
+    pub host: String,
+    pub host_name: String,
+    pub title: String,
+
+    #[serde(with = "ts_seconds")]
+    pub starts: chrono::NaiveDateTime,
+    pub duration_min: i32,
+    /// When the collab room was opened, None until the session starts
+    #[serde(with = "ts_seconds_option")]
+    pub opened: Option<chrono::NaiveDateTime>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+}
+
+impl ReviewSession {
+    pub fn ends(&self) -> chrono::NaiveDateTime { self.starts + chrono::Duration::minutes(self.duration_min as i64) }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = review_sessions)]
+pub struct ReviewSessionInsert {
+    pub collab_id: String,
+    pub video_hash: String,
+    pub host: String,
+    pub host_name: String,
+    pub title: String,
+    pub starts: chrono::NaiveDateTime,
+    pub duration_min: i32,
+}
+
+/// Upload that can be paused and resumed (see `api_server::upload_sessions`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = upload_sessions)]
+pub struct UploadSession {
+    pub id: String,
+    pub user_id: String,
+    pub filename: String,
+    pub total_size: i64,
+    /// Bytes received so far, i.e. offset to continue from
+    pub received: i64,
+    /// See `upload_session_status`
+    pub status: String,
+    /// Loudness normalization requested on upload (see `video_pipeline::LoudnormOpt`)
+    pub loudnorm: Option<String>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+
+    #[serde(with = "ts_seconds")]
+    pub updated: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = upload_sessions)]
+pub struct UploadSessionInsert {
+    pub id: String,
+    pub user_id: String,
+    pub filename: String,
+    pub total_size: i64,
+    pub status: String,
+    pub loudnorm: Option<String>,
+}
+
+pub mod upload_session_status {
+    /// Accepting data
+    pub const ACTIVE: &str = "active";
+    /// Paused by user. Data is refused until resumed.
+    pub const PAUSED: &str = "paused";
+}
+
+/// Live collab session, persisted for failover (see `api_server::collab_state`)
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Identifiable, Clone)]
+#[diesel(table_name = collab_rooms, primary_key(collab_id))]
+pub struct CollabRoom {
+    pub collab_id: String,
+    pub video_hash: String,
+    /// User ID of the participant that created the collab
+    pub host: String,
+    pub paused: bool,
+    /// Last reported playback position, None until someone reports one
+    pub seek_time: Option<f64>,
+    /// Page being viewed, when reviewing a still
+    pub page: Option<i32>,
+
+    #[serde(with = "ts_seconds")]
+    pub created: chrono::NaiveDateTime,
+
+    #[serde(with = "ts_seconds")]
+    pub updated: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Insertable, Clone)]
+#[diesel(table_name = collab_rooms)]
+pub struct CollabRoomInsert {
+    pub collab_id: String,
+    pub video_hash: String,
+    pub host: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Queryable, Selectable, Insertable, Identifiable, Clone, PartialEq)]
+#[diesel(table_name = collab_participants, primary_key(collab_id, user_id))]
+pub struct CollabParticipant {
+    pub collab_id: String,
+    pub user_id: String,
+    pub user_name: String,
+}
+
 pub fn humanize_utc_timestamp(timestamp: &chrono::NaiveDateTime) -> String {
     let added_time: chrono::DateTime<chrono::Utc> = chrono::Utc.from_utc_datetime(timestamp);
-    let time_ago_str = timeago::Formatter::new().convert_chrono(added_time, chrono::Local::now());
-    time_ago_str
+    
+    timeago::Formatter::new().convert_chrono(added_time, chrono::Local::now())
 } 
 
 pub fn to_json<T: serde::Serialize>(t: &T) -> Result<serde_json::Value, serde_json::Error> {
-    serde_json::to_value(&t)
+    serde_json::to_value(t)
 }
 
 impl Video {
@@ -139,4 +1524,48 @@ impl Message { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Er
     })
 }}
 
+impl AuditEvent { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl UserPriority { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl Subtitle { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl Folder { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl Note { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl Guest { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl ShareLink { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl PendingUpload { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl Team { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl TeamMember { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl User { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl FederationPeer { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl FolderSync { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl OverlayPreset { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl UploadBatch { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl UploadBatchFile { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl CustomField {
+    pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> {
+        to_json(&self).map(|mut v| {
+            v["options"] = serde_json::json!(self.select_options());
+            v
+        })
+    }
+}
+impl VideoTag { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl ReviewVerdict { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl ClosedReview { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl ThroughputStat { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl JobStat { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl Job { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl JobAttempt { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl MaintenanceWindow { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl UploadSession { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl CollabRoom { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl CommentAttachment { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl CommentRevision { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl CommentReaction { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl CollabParticipant { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl ReviewSession { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl TrackerOutboxItem { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl PushPrefs { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl UserProfile { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+impl FeatureOverride { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
+
 impl MessageInsert { pub fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> { to_json(&self) } }
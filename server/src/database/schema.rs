@@ -15,6 +15,25 @@ diesel::table! {
         duration -> Nullable<Float>,
         fps -> Nullable<Text>,
         raw_metadata_all -> Nullable<Text>,
+        silence_trim_start -> Nullable<Float>,
+        silence_trim_end -> Nullable<Float>,
+        loudness_lufs -> Nullable<Float>,
+        legal_hold -> Bool,
+        content_hash -> Nullable<Text>,
+        loudnorm_target -> Nullable<Float>,
+        color_primaries -> Nullable<Text>,
+        color_transfer -> Nullable<Text>,
+        hdr_format -> Nullable<Text>,
+        vfr_fps_min -> Nullable<Float>,
+        vfr_fps_max -> Nullable<Float>,
+        vfr_fps_avg -> Nullable<Float>,
+        image_sequence -> Nullable<Text>,
+        audio_only -> Bool,
+        still_kind -> Nullable<Text>,
+        page_count -> Nullable<Integer>,
+        allow_download -> Bool,
+        folder_id -> Nullable<Integer>,
+        trashed -> Nullable<Timestamp>,
     }
 }
 
@@ -30,6 +49,29 @@ diesel::table! {
         comment -> Text,
         timecode -> Nullable<Text>,
         drawing -> Nullable<Text>,
+        page -> Nullable<Integer>,
+        region -> Nullable<Text>,
+        visibility -> Text,
+        number -> Integer,
+        timecode_end -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    comment_numbers (video_hash) {
+        video_hash -> Text,
+        last_number -> Integer,
+    }
+}
+
+diesel::table! {
+    maintenance_windows (id) {
+        id -> Integer,
+        starts -> Timestamp,
+        ends -> Timestamp,
+        message -> Text,
+        created_by -> Text,
+        created -> Timestamp,
     }
 }
 
@@ -47,10 +89,617 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    jobs (id) {
+        id -> Integer,
+        stage -> Text,
+        status -> Text,
+        user_id -> Text,
+        video_hash -> Nullable<Text>,
+        src_file -> Text,
+        dst -> Nullable<Text>,
+        video_bitrate -> Nullable<Integer>,
+        created -> Timestamp,
+        updated -> Timestamp,
+        details -> Text,
+        loudnorm -> Nullable<Text>,
+        burn_in -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    job_attempts (id) {
+        id -> Integer,
+        job_id -> Integer,
+        attempt -> Integer,
+        settings -> Text,
+        success -> Bool,
+        stderr -> Text,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    audit_log (id) {
+        id -> Integer,
+        created -> Timestamp,
+        user_id -> Text,
+        action -> Text,
+        ref_video_hash -> Nullable<Text>,
+        details -> Text,
+    }
+}
+
+diesel::table! {
+    closed_reviews (video_hash) {
+        video_hash -> Text,
+        closed_by -> Text,
+        closed -> Timestamp,
+    }
+}
+
+diesel::table! {
+    custom_fields (name) {
+        name -> Text,
+        title -> Text,
+        field_type -> Text,
+        options -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    guests (id) {
+        id -> Integer,
+        share_link_id -> Integer,
+        name -> Text,
+        key -> Text,
+        promoted_to -> Nullable<Text>,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    job_stats (day, stage, status) {
+        day -> Date,
+        stage -> Text,
+        status -> Text,
+        n_jobs -> Integer,
+        total_secs -> BigInt,
+    }
+}
+
+diesel::table! {
+    throughput_stats (hour, stage) {
+        hour -> Timestamp,
+        stage -> Text,
+        n_jobs -> Integer,
+        video_secs -> Double,
+        work_secs -> Double,
+        wait_secs -> Double,
+    }
+}
+
+diesel::table! {
+    user_priorities (user_id) {
+        user_id -> Text,
+        priority -> Integer,
+    }
+}
+
+diesel::table! {
+    users (user_id) {
+        user_id -> Text,
+        username -> Text,
+        is_admin -> Bool,
+        disabled -> Bool,
+        created -> Timestamp,
+        last_seen -> Nullable<Timestamp>,
+        search_key -> Text,
+        onboarded -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    comment_revisions (id) {
+        id -> Integer,
+        comment_id -> Integer,
+        comment -> Text,
+        written -> Timestamp,
+        replaced -> Timestamp,
+        replaced_by -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    comment_reactions (comment_id, user_id, emoji) {
+        comment_id -> Integer,
+        user_id -> Text,
+        emoji -> Text,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    comment_attachments (id) {
+        id -> Integer,
+        comment_id -> Integer,
+        video_hash -> Text,
+        user_id -> Text,
+        filename -> Text,
+        mime_type -> Text,
+        size -> Integer,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    comment_mentions (comment_id, user_id) {
+        comment_id -> Integer,
+        user_id -> Text,
+    }
+}
+
+diesel::table! {
+    collab_rooms (collab_id) {
+        collab_id -> Text,
+        video_hash -> Text,
+        host -> Text,
+        paused -> Bool,
+        seek_time -> Nullable<Double>,
+        page -> Nullable<Integer>,
+        created -> Timestamp,
+        updated -> Timestamp,
+    }
+}
+
+diesel::table! {
+    collab_participants (collab_id, user_id) {
+        collab_id -> Text,
+        user_id -> Text,
+        user_name -> Text,
+    }
+}
+
+diesel::table! {
+    upload_sessions (id) {
+        id -> Text,
+        user_id -> Text,
+        filename -> Text,
+        total_size -> BigInt,
+        received -> BigInt,
+        status -> Text,
+        loudnorm -> Nullable<Text>,
+        created -> Timestamp,
+        updated -> Timestamp,
+    }
+}
+
+diesel::table! {
+    upload_batches (id) {
+        id -> Integer,
+        user_id -> Text,
+        title -> Text,
+        cancelled -> Bool,
+        created -> Timestamp,
+        finished -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    push_subscriptions (id) {
+        id -> Integer,
+        user_id -> Text,
+        endpoint -> Text,
+        p256dh -> Text,
+        auth -> Text,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    push_prefs (user_id) {
+        user_id -> Text,
+        mentions -> Bool,
+        comments -> Bool,
+        processing -> Bool,
+    }
+}
+
+diesel::table! {
+    tracker_notes (comment_id) {
+        comment_id -> Integer,
+        video_hash -> Text,
+        tracker -> Text,
+        remote_id -> Text,
+        reply_to -> Nullable<Text>,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    tracker_outbox (id) {
+        id -> Integer,
+        comment_id -> Integer,
+        video_hash -> Text,
+        attempts -> Integer,
+        next_attempt -> Timestamp,
+        last_error -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    review_sessions (id) {
+        id -> Integer,
+        collab_id -> Text,
+        video_hash -> Text,
+        host -> Text,
+        host_name -> Text,
+        title -> Text,
+        starts -> Timestamp,
+        duration_min -> Integer,
+        opened -> Nullable<Timestamp>,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    review_invitees (session_id, user_id) {
+        session_id -> Integer,
+        user_id -> Text,
+    }
+}
+
+diesel::table! {
+    user_profiles (user_id) {
+        user_id -> Text,
+        display_name -> Nullable<Text>,
+        avatar -> Nullable<Text>,
+        timezone -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    user_prefs (user_id, key) {
+        user_id -> Text,
+        key -> Text,
+        value -> Text,
+        updated -> Timestamp,
+    }
+}
+
+diesel::table! {
+    video_changes (seq) {
+        seq -> BigInt,
+        video_hash -> Text,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    feature_overrides (feature, target) {
+        feature -> Text,
+        target -> Text,
+        enabled -> Bool,
+        set_by -> Text,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    federated_objects (peer, kind, remote_id) {
+        peer -> Text,
+        kind -> Text,
+        remote_id -> Text,
+        local_id -> Text,
+        pending_folder_id -> Nullable<Integer>,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    federation_peers (name) {
+        name -> Text,
+        url -> Text,
+        secret -> Text,
+        self_name -> Text,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    folder_syncs (id) {
+        id -> Integer,
+        folder_id -> Integer,
+        peer -> Text,
+        remote_folder_id -> Integer,
+        cursor -> BigInt,
+        last_sync -> Nullable<Timestamp>,
+        last_error -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    folders (id) {
+        id -> Integer,
+        user_id -> Text,
+        title -> Text,
+        parent_id -> Nullable<Integer>,
+        overlay_defaults -> Nullable<Text>,
+        created -> Timestamp,
+        transcode_preset -> Nullable<Text>,
+        legal_hold -> Bool,
+    }
+}
+
+diesel::table! {
+    notes (id) {
+        id -> Integer,
+        video_hash -> Text,
+        user_id -> Text,
+        created -> Timestamp,
+        edited -> Nullable<Timestamp>,
+        note -> Text,
+        timecode -> Nullable<Text>,
+        page -> Nullable<Integer>,
+        region -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    overlay_presets (name) {
+        name -> Text,
+        title -> Text,
+        kind -> Text,
+        value -> Float,
+        default_on -> Bool,
+    }
+}
+
+diesel::table! {
+    pending_uploads (id) {
+        id -> Integer,
+        user_id -> Text,
+        src_file -> Text,
+        duplicate_of -> Text,
+        loudnorm -> Nullable<Text>,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    video_custom_fields (video_hash) {
+        video_hash -> Text,
+        field_values -> Text,
+    }
+}
+
+diesel::table! {
+    video_tags (video_hash, tag) {
+        video_hash -> Text,
+        tag -> Text,
+        added_by -> Text,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    video_links (user_id, video_hash) {
+        user_id -> Text,
+        video_hash -> Text,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    review_verdicts (id) {
+        id -> Integer,
+        video_hash -> Text,
+        user_id -> Text,
+        username -> Text,
+        verdict -> Text,
+        note -> Nullable<Text>,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    share_links (id) {
+        id -> Integer,
+        token -> Text,
+        video_hash -> Text,
+        created_by -> Text,
+        allow_comments -> Bool,
+        password_hash -> Nullable<Text>,
+        expires -> Nullable<Timestamp>,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    subtitles (id) {
+        id -> Integer,
+        video_hash -> Text,
+        language -> Nullable<Text>,
+        title -> Text,
+        origin -> Text,
+        added -> Timestamp,
+    }
+}
+
+diesel::table! {
+    teams (id) {
+        id -> Integer,
+        name -> Text,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    team_members (team_id, user_id) {
+        team_id -> Integer,
+        user_id -> Text,
+        is_team_admin -> Bool,
+    }
+}
+
+diesel::table! {
+    team_videos (team_id, video_hash) {
+        team_id -> Integer,
+        video_hash -> Text,
+        shared_by -> Text,
+    }
+}
+
+diesel::table! {
+    team_folders (team_id, folder_id) {
+        team_id -> Integer,
+        folder_id -> Integer,
+        shared_by -> Text,
+    }
+}
+
+diesel::table! {
+    transcript_cues (id) {
+        id -> Integer,
+        subtitle_id -> Integer,
+        video_hash -> Text,
+        language -> Nullable<Text>,
+        start_time -> Float,
+        end_time -> Float,
+        text -> Text,
+    }
+}
+
+diesel::table! {
+    upload_batch_files (id) {
+        id -> Integer,
+        batch_id -> Integer,
+        filename -> Text,
+        status -> Text,
+        src_file -> Nullable<Text>,
+        video_hash -> Nullable<Text>,
+        details -> Text,
+    }
+}
+
+diesel::table! {
+    video_views (user_id, video_hash) {
+        user_id -> Text,
+        video_hash -> Text,
+        seen_until -> Timestamp,
+    }
+}
+
+diesel::table! {
+    video_shots (id) {
+        id -> Integer,
+        video_hash -> Text,
+        start_time -> Float,
+        end_time -> Float,
+        thumb -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    video_labels (id) {
+        id -> Integer,
+        video_hash -> Text,
+        kind -> Text,
+        label -> Text,
+        start_time -> Float,
+        end_time -> Float,
+        confidence -> Nullable<Float>,
+    }
+}
+
+diesel::table! {
+    video_sources (id) {
+        id -> Integer,
+        video_hash -> Text,
+        source_video_hash -> Text,
+        position -> Integer,
+        operation -> Text,
+    }
+}
+
+diesel::table! {
+    video_imports (video_hash) {
+        video_hash -> Text,
+        origin -> Text,
+        origin_video_hash -> Text,
+        imported_by -> Text,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    proxy_encodings (video_hash) {
+        video_hash -> Text,
+        encoding -> Text,
+        created -> Timestamp,
+    }
+}
+
 diesel::joinable!(messages -> comments (ref_comment_id));
+diesel::joinable!(upload_batch_files -> upload_batches (batch_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    audit_log,
     comments,
+    feature_overrides,
+    push_subscriptions,
+    push_prefs,
+    tracker_notes,
+    tracker_outbox,
+    review_sessions,
+    review_invitees,
+    video_changes,
+    user_profiles,
+    user_prefs,
+    federated_objects,
+    federation_peers,
+    folder_syncs,
+    folders,
+    closed_reviews,
+    collab_participants,
+    collab_rooms,
+    comment_attachments,
+    comment_mentions,
+    comment_numbers,
+    comment_reactions,
+    comment_revisions,
+    custom_fields,
+    guests,
+    jobs,
+    job_attempts,
+    job_stats,
+    maintenance_windows,
     messages,
+    notes,
+    overlay_presets,
+    pending_uploads,
+    proxy_encodings,
+    review_verdicts,
+    share_links,
+    subtitles,
+    team_folders,
+    team_members,
+    team_videos,
+    teams,
+    throughput_stats,
+    transcript_cues,
+    upload_batch_files,
+    upload_batches,
+    upload_sessions,
+    user_priorities,
+    users,
+    video_imports,
+    video_custom_fields,
+    video_labels,
+    video_shots,
+    video_tags,
+    video_links,
+    video_sources,
+    video_views,
     videos,
 );
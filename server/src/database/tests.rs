@@ -28,7 +28,7 @@ pub fn make_test_db() -> (std::sync::Arc<DB>, assert_fs::TempDir, Vec<models::Vi
     db.run_migrations().unwrap();
 
     // Make some videos
-    let hashes = vec!["HASH0", "11111", "22222", "HASH3", "HASH4"];
+    let hashes = ["HASH0", "11111", "22222", "HASH3", "HASH4"];
     let mkvid = |i: usize| {
         let v = models::VideoInsert {
             video_hash: hashes[i].to_string(),
@@ -42,6 +42,21 @@ pub fn make_test_db() -> (std::sync::Arc<DB>, assert_fs::TempDir, Vec<models::Vi
             duration: Some((i * 100) as f32),
             fps: Some(format!("{}", i * i)),
             raw_metadata_all: Some(format!("{{all: {{video: {}}}}}", i)),
+            silence_trim_start: None,
+            silence_trim_end: None,
+            loudness_lufs: None,
+            content_hash: Some(format!("content{}", i % 3)),
+            loudnorm_target: None,
+            color_primaries: None,
+            color_transfer: None,
+            hdr_format: None,
+            vfr_fps_min: None,
+            vfr_fps_max: None,
+            vfr_fps_avg: None,
+            image_sequence: None,
+            audio_only: false,
+            still_kind: None,
+            page_count: None,
         };
         db.add_video(&v).unwrap();
         db.get_video(&v.video_hash).unwrap()
@@ -54,16 +69,20 @@ pub fn make_test_db() -> (std::sync::Arc<DB>, assert_fs::TempDir, Vec<models::Vi
             video_hash: vh.to_string(),
             parent_id,
             timecode: None,
+            timecode_end: None,
             user_id: format!("user.num{}", 1 + i % 2),
             username: format!("User Number{}", 1 + i % 2),
             comment: format!("Comment {}", i),
             drawing: Some(format!("drawing_{}.webp", i)),
+            page: None,
+            region: None,
+            visibility: models::comment_visibility::EXTERNAL.into(),
         };
         let id = db.add_comment(&c).unwrap();
         let c = db.get_comment(id).unwrap();
         let dp = data_dir.join("videos").join(vh).join("drawings");
         std::fs::create_dir_all(&dp).unwrap();
-        std::fs::write(dp.join(&c.drawing.clone().unwrap()), "IMAGE_DATA").unwrap();
+        std::fs::write(dp.join(c.drawing.clone().unwrap()), "IMAGE_DATA").unwrap();
         c
     };
     let mut comments = (0..5)
@@ -79,10 +98,14 @@ pub fn make_test_db() -> (std::sync::Arc<DB>, assert_fs::TempDir, Vec<models::Vi
         video_hash: videos[0].video_hash.clone(),
         parent_id: None,
         timecode: None,
+        timecode_end: None,
         user_id: "user.num1".to_string(),
         username: "User Number1".to_string(),
         comment: "Comment_with_empty_drawing".to_string(),
         drawing: Some("".into()),
+        page: None,
+        region: None,
+        visibility: models::comment_visibility::EXTERNAL.into(),
     };
     db.add_comment(&c).unwrap();
 
@@ -97,8 +120,8 @@ fn test_fixture_state() -> anyhow::Result<()>
     let (db, _data_dir, vid, com) = make_test_db();
 
     // First 5 comments have no parent, last 2 have parent_id=1
-    for i in 0..5 { assert!(com[i].parent_id.is_none()); }
-    for i in 5..5 + 2 { assert_eq!(com[i].parent_id, Some(com[0].id)); }
+    for c in &com[0..5] { assert!(c.parent_id.is_none()); }
+    for c in &com[5..5 + 2] { assert_eq!(c.parent_id, Some(com[0].id)); }
 
     // Video #0 has 3 comments, video #1 has 2, video #2 has 1
     assert_eq!(com[0].video_hash, com[3].video_hash);
@@ -163,7 +186,11 @@ fn test_comment_delete() -> anyhow::Result<()> {
         username: "name".to_string(),
         comment: "re-add".to_string(),
         timecode: None,
+        timecode_end: None,
         drawing: None,
+        page: None,
+        region: None,
+        visibility: models::comment_visibility::EXTERNAL.into(),
     };
     let new_id = db.add_comment(&c)?;
     assert_ne!(new_id, com[6].id, "Comment ID was re-used after deletion. This would mix up comment threads in the UI.");
@@ -177,14 +204,14 @@ fn test_rename_video() -> anyhow::Result<()> {
 
     // Rename video #1
     let new_name = "New name";
-    db.rename_video(&"11111".to_string(), new_name)?;
+    db.rename_video("11111", new_name)?;
 
     // Check that video #1 has new name
-    let v = db.get_video(&"11111".to_string())?;
+    let v = db.get_video("11111")?;
     assert_eq!(v.title, Some(new_name.into()));
 
     // Check that video #2 still has old name
-    let v = db.get_video(&"22222".to_string())?;
+    let v = db.get_video("22222")?;
     assert_ne!(v.title, Some(new_name.into()));
 
     Ok(())
@@ -228,10 +255,10 @@ fn test_user_messages() -> anyhow::Result<()> {
     ];
 
     let mut new_msgs = vec![];
-    for i in 0..msgs.len() {
-        let new_msg = db.add_message(&msgs[i])?;
-        assert_eq!(new_msg.user_id, msgs[i].user_id);
-        assert_eq!(new_msg.message, msgs[i].message);
+    for m in msgs.iter() {
+        let new_msg = db.add_message(m)?;
+        assert_eq!(new_msg.user_id, m.user_id);
+        assert_eq!(new_msg.message, m.message);
         assert_eq!(db.get_message(new_msg.id)?.to_json()?, new_msg.to_json()?);
         assert!(!db.get_message(new_msg.id)?.seen);
         new_msgs.push(new_msg);
@@ -253,3 +280,1453 @@ fn test_user_messages() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+#[traced_test]
+fn test_pipeline_jobs() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+
+    let mkjob = |src: &str| models::JobInsert {
+        stage: models::job_stage::METADATA.into(),
+        status: models::job_status::PENDING.into(),
+        user_id: "user.num1".into(),
+        src_file: src.into(),
+        ..Default::default()
+    };
+    let j1 = db.add_job(&mkjob("/tmp/a.mov"))?;
+    let j2 = db.add_job(&mkjob("/tmp/b.mov"))?;
+    assert_eq!(db.get_job(j1)?.status, models::job_status::PENDING);
+    assert_eq!(db.get_unfinished_jobs()?.len(), 2);
+    assert_eq!(db.get_unfinished_job_ids_for_src(models::job_stage::METADATA, "/tmp/b.mov")?, vec![j2]);
+
+    // Finish one, fail the other
+    assert!(db.set_job_status(j1, models::job_status::DONE, "")?);
+    assert!(db.set_job_status(j2, models::job_status::FAILED, "oops")?);
+    assert_eq!(db.get_job(j2)?.details, "oops");
+    assert!(db.get_unfinished_jobs()?.is_empty());
+    assert!(!db.set_job_status(j2 + 100, models::job_status::DONE, "")?);
+    assert!(matches!(db.get_job(j2 + 100).unwrap_err(), DBError::NotFound()));
+    Ok(())
+}
+
+#[test]
+fn test_job_attempts() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+    let future = chrono::Utc::now().naive_utc() + chrono::Duration::days(1);
+
+    let j = db.add_job(&models::JobInsert { stage: models::job_stage::TRANSCODE.into(), status: models::job_status::FAILED.into(), user_id: "user.num1".into(), ..Default::default() })?;
+    for (n, success) in [(1, false), (2, true)] {
+        db.add_job_attempt(&models::JobAttemptInsert { job_id: j, attempt: n, settings: format!("try {n}"), success, stderr: "...".into() })?;
+    }
+    let attempts = db.get_job_attempts(j)?;
+    assert_eq!(attempts.iter().map(|a| (a.attempt, a.success)).collect::<Vec<_>>(), vec![(1, false), (2, true)]);
+    assert_eq!(attempts[0].settings, "try 1");
+    assert!(db.get_job_attempts(j + 100)?.is_empty());
+
+    // Purged with the job
+    assert_eq!(db.aggregate_and_del_jobs_before(future)?, 1);
+    assert!(db.get_job_attempts(j)?.is_empty());
+    Ok(())
+}
+
+#[test]
+#[traced_test]
+fn test_user_priorities() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+
+    assert_eq!(db.get_user_priority("user.num1")?, 0);
+    db.set_user_priority("user.num1", 5)?;
+    db.set_user_priority("user.num2", 10)?;
+    db.set_user_priority("user.num1", 7)?;
+    assert_eq!(db.get_user_priority("user.num1")?, 7);
+
+    let prios = db.get_user_priorities()?;
+    assert_eq!(prios.iter().map(|p| p.user_id.as_str()).collect::<Vec<_>>(), vec!["user.num2", "user.num1"]);
+
+    // Setting back to default removes the row
+    db.set_user_priority("user.num2", 0)?;
+    assert_eq!(db.get_user_priority("user.num2")?, 0);
+    assert_eq!(db.get_user_priorities()?.len(), 1);
+    Ok(())
+}
+
+#[test]
+#[traced_test]
+fn test_videos_by_content_hash() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+
+    // Same content for different users doesn't count
+    let hashes = |user: &str, content: &str| db.get_user_videos_by_content_hash(user, content)
+        .unwrap().into_iter().map(|v| v.video_hash).collect::<Vec<_>>();
+    assert_eq!(hashes("user.num1", "content0"), vec![vid[0].video_hash.clone()]);
+    assert_eq!(hashes("user.num2", "content0"), vec![vid[3].video_hash.clone()]);
+    assert_eq!(hashes("user.num1", "content1"), vec![vid[4].video_hash.clone()]);
+    assert!(hashes("user.num1", "no-such-content").is_empty());
+    Ok(())
+}
+
+#[test]
+#[traced_test]
+fn test_legal_hold_blocks_delete() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+
+    db.set_video_legal_hold(&vid[1].video_hash, true)?;
+    assert!(db.del_video_and_comments(&vid[1].video_hash).is_err());
+    assert!(db.get_video(&vid[1].video_hash)?.legal_hold);
+    assert_eq!(db.get_video_comments(&vid[1].video_hash)?.len(), 2);
+
+    db.set_video_legal_hold(&vid[1].video_hash, false)?;
+    db.del_video_and_comments(&vid[1].video_hash)?;
+    assert!(matches!(db.get_video(&vid[1].video_hash).unwrap_err(), DBError::NotFound()));
+    assert!(matches!(db.set_video_legal_hold("non-existent", true).unwrap_err(), DBError::NotFound()));
+    Ok(())
+}
+
+#[test]
+#[traced_test]
+fn test_folder_legal_hold() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let vh = &vid[0].video_hash;
+    let mkfolder = |title: &str, parent_id: Option<i32>| db.add_folder(&models::FolderInsert {
+        user_id: "user.num1".into(), title: title.into(), parent_id }).unwrap();
+    let top = mkfolder("Case", None);
+    let sub = mkfolder("Evidence", Some(top.id));
+    db.set_video_folder(vh, Some(sub.id))?;
+    db.add_audit_event(&models::AuditEventInsert { user_id: "admin".into(), action: "test".into(), ref_video_hash: Some(vh.clone()), details: "".into() })?;
+    assert!(!db.is_video_held(vh)?);
+
+    // Hold on a parent folder applies to videos in subfolders
+    db.set_folder_legal_hold(top.id, true)?;
+    assert!(db.get_folder(top.id)?.legal_hold);
+    assert!(db.is_video_held(vh)?);
+    assert!(!db.is_video_held(&vid[1].video_hash)?);
+    assert!(db.del_video_and_comments(vh).is_err());
+    db.del_audit_events_before(chrono::Utc::now().naive_utc() + chrono::Duration::days(1))?;
+    assert_eq!(db.get_video_audit_events(vh)?.len(), 1);
+
+    db.set_folder_legal_hold(top.id, false)?;
+    assert!(!db.is_video_held(vh)?);
+    db.del_video_and_comments(vh)?;
+    assert!(!db.is_video_held(vh)?);
+    assert!(matches!(db.set_folder_legal_hold(12345, true), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+#[traced_test]
+fn test_search_events() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+
+    for (uid, act, vh, details) in [
+        ("admin", models::audit_action::LEGAL_HOLD_SET, "HASH0", "Case 50%"),
+        ("user.num1", models::audit_action::DELETE_VIDEO, "HASH3", "Title was test3"),
+        ("user.num2", models::audit_action::DELETE_VIDEO, "11111", "Title was test1"),
+    ] {
+        db.add_audit_event(&models::AuditEventInsert {
+            user_id: uid.into(), action: act.into(), ref_video_hash: Some(vh.into()), details: details.into() })?;
+    }
+    db.add_message(&models::MessageInsert {
+        user_id: "user.num1".into(), event_name: "error".into(), message: "Upload failed".into(),
+        details: "garbage.mp4".into(), ..Default::default() })?;
+
+    assert_eq!(db.search_audit_events(&EventFilter::default())?.len(), 3);
+    assert_eq!(db.search_audit_events(&EventFilter { kind: Some("delete_video".into()), ..Default::default() })?.len(), 2);
+    assert_eq!(db.search_audit_events(&EventFilter { user_id: Some("user.num1".into()), ..Default::default() })?[0].ref_video_hash, Some("HASH3".into()));
+    assert_eq!(db.search_audit_events(&EventFilter { text: Some("TEST1".into()), ..Default::default() })?.len(), 1);
+    assert_eq!(db.search_audit_events(&EventFilter { text: Some("hash3".into()), ..Default::default() })?.len(), 1);
+    assert_eq!(db.search_audit_events(&EventFilter { limit: 1, ..Default::default() })?[0].user_id, "user.num2");
+
+    // LIKE wildcards are matched literally
+    assert_eq!(db.search_audit_events(&EventFilter { text: Some("0%".into()), ..Default::default() })?.len(), 1);
+    assert_eq!(db.search_audit_events(&EventFilter { text: Some("n_m".into()), ..Default::default() })?.len(), 0);
+
+    let future = chrono::Utc::now().naive_utc() + chrono::Duration::hours(1);
+    assert!(db.search_audit_events(&EventFilter { since: Some(future), ..Default::default() })?.is_empty());
+
+    assert_eq!(db.search_messages(&EventFilter { text: Some("garbage".into()), ..Default::default() })?.len(), 1);
+    assert!(db.search_messages(&EventFilter { kind: Some("ok".into()), ..Default::default() })?.is_empty());
+    Ok(())
+}
+
+#[test]
+#[traced_test]
+fn test_upload_batch_lifecycle() -> anyhow::Result<()> {
+    use models::batch_file_status as bfs;
+    let (db, _data_dir, _vid, _com) = make_test_db();
+
+    let (batch, files) = db.add_upload_batch("user.num1", "Day 1", &["a.mp4".into(), "b.mp4".into(), "c.mp4".into()])?;
+    assert_eq!(files.len(), 3);
+    assert!(files.iter().all(|f| f.status == bfs::PENDING && f.batch_id == batch.id));
+    assert!(!db.try_finish_upload_batch(batch.id)?);
+
+    db.set_upload_batch_file_status(files[0].id, bfs::PROCESSING, Some("/upload/x/a.mp4"), None, "")?;
+    assert_eq!(db.get_processing_batch_file_by_src("/upload/x/a.mp4")?.id, files[0].id);
+    assert!(matches!(db.get_processing_batch_file_by_src("/upload/x/b.mp4").unwrap_err(), DBError::NotFound()));
+
+    // Cancel skips pending files, but not the one being processed
+    db.cancel_upload_batch(batch.id)?;
+    assert!(db.get_upload_batch(batch.id)?.cancelled);
+    let files = db.get_upload_batch_files(batch.id)?;
+    assert_eq!(files.iter().filter(|f| f.status == bfs::CANCELLED).count(), 2);
+    assert!(!db.try_finish_upload_batch(batch.id)?);
+
+    db.set_upload_batch_file_status(files[0].id, bfs::DONE, None, Some("HASH0"), "")?;
+    assert_eq!(db.get_upload_batch_file(files[0].id)?.video_hash, Some("HASH0".into()));
+    assert_eq!(db.get_upload_batch_file(files[0].id)?.src_file, Some("/upload/x/a.mp4".into()));
+    assert!(db.try_finish_upload_batch(batch.id)?);
+    assert!(!db.try_finish_upload_batch(batch.id)?);  // Only once
+    assert!(db.get_upload_batch(batch.id)?.finished.is_some());
+
+    assert!(matches!(db.cancel_upload_batch(9999).unwrap_err(), DBError::NotFound()));
+    Ok(())
+}
+
+#[test]
+#[traced_test]
+fn test_subtitles() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+
+    let sub = db.add_subtitle(&models::SubtitleInsert {
+        video_hash: vid[0].video_hash.clone(), language: Some("en".into()), title: "English".into(),
+        origin: models::subtitle_origin::EMBEDDED.into() })?;
+    db.add_subtitle(&models::SubtitleInsert {
+        video_hash: vid[0].video_hash.clone(), title: "notes.srt".into(),
+        origin: models::subtitle_origin::UPLOAD.into(), ..Default::default() })?;
+    assert_eq!(db.get_subtitle(sub.id)?.language, Some("en".into()));
+    assert_eq!(db.get_video_subtitles(&vid[0].video_hash)?.len(), 2);
+    assert!(db.get_video_subtitles(&vid[1].video_hash)?.is_empty());
+
+    db.del_subtitle(sub.id)?;
+    assert!(matches!(db.del_subtitle(sub.id).unwrap_err(), DBError::NotFound()));
+    assert_eq!(db.get_video_subtitles(&vid[0].video_hash)?.len(), 1);
+
+    // Deleted with the video
+    db.del_video_and_comments(&vid[0].video_hash)?;
+    assert!(db.get_video_subtitles(&vid[0].video_hash)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_transcript_search() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let (a, b) = (&vid[0].video_hash, &vid[1].video_hash);
+    let mut cues = vec![];
+    for (vh, lang, t, text) in [(a, "en", 252.0, "Is this the final render?"), (a, "fi", 252.0, "Onko tämä lopullinen?"),
+                                (b, "en-US", 10.0, "FINAL RENDER looks good"), (b, "en", 5.0, "100% done")] {
+        let sub = db.add_subtitle(&models::SubtitleInsert {
+            video_hash: vh.clone(), language: Some(lang.into()), title: lang.into(),
+            origin: models::subtitle_origin::UPLOAD.into() })?;
+        cues.push(models::TranscriptCueInsert { subtitle_id: sub.id, video_hash: vh.clone(), language: Some(lang.into()),
+            start_time: t, end_time: t + 2.0, text: text.into() });
+    }
+    assert_eq!(db.add_transcript_cues(&cues)?, 4);
+
+    let hits = db.search_transcripts("final render", None, None, 100)?;
+    assert_eq!(hits.len(), 2);
+    assert_eq!(db.search_transcripts("final render", Some(std::slice::from_ref(a)), None, 100)?[0].start_time, 252.0);
+    assert_eq!(db.search_transcripts("final render", None, Some("en"), 100)?.len(), 2);
+    assert_eq!(db.search_transcripts("lopullinen", None, Some("en"), 100)?.len(), 0);
+    assert_eq!(db.search_transcripts("lopullinen", None, Some("fi"), 100)?.len(), 1);
+    assert_eq!(db.search_transcripts("0%", None, None, 100)?.len(), 1);
+    assert_eq!(db.search_transcripts("%", None, None, 100)?.len(), 1);
+    assert_eq!(db.search_transcripts("final", None, None, 1)?.len(), 1);
+
+    // Deleting a track or video drops its cues from index
+    let hit_a = hits.iter().find(|c| &c.video_hash == a).unwrap();
+    db.del_subtitle(hit_a.subtitle_id)?;
+    assert_eq!(db.search_transcripts("final render", None, None, 100)?[0].video_hash, *b);
+    db.del_video_and_comments(b)?;
+    assert!(db.search_transcripts("render", None, None, 100)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_video_labels() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let (a, b) = (&vid[0].video_hash, &vid[1].video_hash);
+    let lbl = |vh: &str, kind: &str, label: &str, t: f32, c: Option<f32>| models::VideoLabelInsert {
+        video_hash: vh.into(), kind: kind.into(), label: label.into(), start_time: t, end_time: t + 1.0, confidence: c };
+
+    db.set_video_labels(a, &[lbl(a, "face", "Alice", 5.0, Some(0.9)), lbl(a, "object", "car", 1.0, Some(0.4))])?;
+    db.set_video_labels(b, &[lbl(b, "text", "CAR WASH", 2.0, None)])?;
+    assert_eq!(db.search_video_labels(None, None, None, None, 100)?.len(), 3);
+
+    // Filters
+    let own = db.search_video_labels(None, Some(std::slice::from_ref(a)), None, None, 100)?;
+    assert_eq!(own.iter().map(|l| l.label.as_str()).collect::<Vec<_>>(), vec!["car", "Alice"]);
+    assert_eq!(db.search_video_labels(Some("car"), None, None, None, 100)?.len(), 2);
+    assert_eq!(db.search_video_labels(Some("car"), None, Some("object"), None, 100)?.len(), 1);
+    assert_eq!(db.search_video_labels(Some("car"), None, None, Some(0.5), 100)?[0].label, "CAR WASH");
+    assert_eq!(db.search_video_labels(None, None, None, None, 1)?.len(), 1);
+
+    // Re-analysis replaces old labels
+    db.set_video_labels(a, &[lbl(a, "face", "Bob", 0.0, None)])?;
+    assert_eq!(db.search_video_labels(None, Some(std::slice::from_ref(a)), None, None, 100)?[0].label, "Bob");
+    assert!(db.search_video_labels(Some("alice"), None, None, None, 100)?.is_empty());
+
+    db.del_video_and_comments(b)?;
+    assert_eq!(db.search_video_labels(None, None, None, None, 100)?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_video_shots() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let (a, b) = (&vid[0].video_hash, &vid[1].video_hash);
+    let shot = |vh: &str, s: f32, e: f32| models::VideoShotInsert {
+        video_hash: vh.into(), start_time: s, end_time: e, thumb: Some(format!("shots/{s}.webp")) };
+
+    assert!(db.get_video_shots(a)?.is_empty());
+    db.set_video_shots(a, &[shot(a, 4.0, 10.0), shot(a, 0.0, 4.0)])?;
+    db.set_video_shots(b, &[shot(b, 0.0, 2.5)])?;
+    let shots = db.get_video_shots(a)?;
+    assert_eq!(shots.iter().map(|s| (s.start_time, s.end_time)).collect::<Vec<_>>(), vec![(0.0, 4.0), (4.0, 10.0)]);
+    assert_eq!(shots[1].thumb.as_deref(), Some("shots/4.webp"));
+
+    // Re-detection replaces old shots
+    db.set_video_shots(a, &[shot(a, 0.0, 10.0)])?;
+    assert_eq!(db.get_video_shots(a)?.len(), 1);
+
+    db.del_video_and_comments(b)?;
+    assert!(db.get_video_shots(b)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_video_seen_and_unread() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let vhs = vid.iter().map(|v| v.video_hash.clone()).collect::<Vec<_>>();
+    let a = &vhs[0];
+    let by_others = |vh: &str, uid: &str| db.get_video_comments(vh).unwrap().iter().filter(|c| c.user_id != uid).count() as i64;
+
+    // Nothing read yet: all comments by others are unread
+    assert_eq!(db.get_video_seen("user.num1", a)?, None);
+    let unread = db.get_unread_comment_counts("user.num1", &vhs)?;
+    assert_eq!(unread.get(a), Some(&(by_others(a, "user.num1"), 0)));
+    assert_eq!(unread.values().map(|(n, _)| n).sum::<i64>(), vhs.iter().map(|vh| by_others(vh, "user.num1")).sum::<i64>());
+
+    // Read up to the last comment, and a report of an earlier position doesn't undo it
+    let last = db.get_video_comments(a)?.iter().map(|c| c.created).max().unwrap();
+    assert_eq!(db.set_video_seen("user.num1", a, last)?, last);
+    assert_eq!(db.set_video_seen("user.num1", a, last - chrono::Duration::hours(1))?, last);
+    assert_eq!(db.get_video_seen("user.num1", a)?, Some(last));
+    assert!(!db.get_unread_comment_counts("user.num1", &vhs)?.contains_key(a));
+    assert_eq!(db.get_unread_comment_counts("user.num2", std::slice::from_ref(a))?.get(a), Some(&(by_others(a, "user.num2"), 0)));
+
+    db.del_video_and_comments(a)?;
+    assert_eq!(db.get_video_seen("user.num1", a)?, None);
+    Ok(())
+}
+
+#[test]
+fn test_push_subscriptions_and_prefs() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+    let sub = |uid: &str, ep: &str| models::PushSubscriptionInsert {
+        user_id: uid.into(), endpoint: ep.into(), p256dh: "KEY".into(), auth: "AUTH".into() };
+    db.add_push_subscription(&sub("user.num1", "https://push.example/a"))?;
+    db.add_push_subscription(&sub("user.num1", "https://push.example/b"))?;
+    assert_eq!(db.get_push_subscriptions("user.num1")?.len(), 2);
+
+    // Same browser, other user: subscription moves
+    db.add_push_subscription(&sub("user.num2", "https://push.example/a"))?;
+    assert_eq!(db.get_push_subscriptions("user.num1")?.iter().map(|s| s.endpoint.as_str()).collect::<Vec<_>>(), vec!["https://push.example/b"]);
+    assert!(matches!(db.del_push_subscription("https://push.example/a", Some("user.num1")), Err(DBError::NotFound())));
+    db.del_push_subscription("https://push.example/a", None)?;
+    assert!(db.get_push_subscriptions("user.num2")?.is_empty());
+
+    assert_eq!(db.get_push_prefs("user.num1")?, models::PushPrefs { user_id: "user.num1".into(), ..Default::default() });
+    db.set_push_prefs(&models::PushPrefs { user_id: "user.num1".into(), mentions: true, ..Default::default() })?;
+    assert!(db.get_push_prefs("user.num1")?.mentions);
+    assert!(!db.get_push_prefs("user.num2")?.mentions);
+    Ok(())
+}
+
+#[test]
+fn test_user_profiles() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+    assert_eq!(db.get_user_profile("user.num1")?, models::UserProfile { user_id: "user.num1".into(), ..Default::default() });
+    let p = models::UserProfile { user_id: "user.num1".into(), display_name: Some("Num One".into()), avatar: Some("ab12.png".into()), timezone: Some("Europe/Helsinki".into()) };
+    db.set_user_profile(&p)?;
+    assert_eq!(db.get_user_profile("user.num1")?, p);
+    assert!(db.is_avatar_in_use("ab12.png")?);
+
+    // Replaced as a whole
+    db.set_user_profile(&models::UserProfile { avatar: None, ..p.clone() })?;
+    assert!(!db.is_avatar_in_use("ab12.png")?);
+    assert_eq!(db.get_user_profiles(&["user.num1".into(), "user.num2".into()])?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_user_prefs() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+    assert!(db.get_user_prefs("user.num1")?.is_empty());
+    db.set_user_prefs("user.num1", &[("ui.theme".into(), Some("\"dark\"".into())), ("playback.volume".into(), Some("0.5".into()))])?;
+    db.set_user_prefs("user.num2", &[("ui.theme".into(), Some("\"light\"".into()))])?;
+    db.set_user_prefs("user.num1", &[("ui.theme".into(), None), ("playback.volume".into(), Some("1".into()))])?;
+    let prefs = db.get_user_prefs("user.num1")?;
+    assert_eq!(prefs.iter().map(|p| (p.key.as_str(), p.value.as_str())).collect::<Vec<_>>(), vec![("playback.volume", "1")]);
+    assert_eq!(db.get_user_prefs("user.num2")?[0].value, "\"light\"");
+    assert_eq!(db.get_user_pref("user.num2", "ui.theme")?.as_deref(), Some("\"light\""));
+    assert_eq!(db.get_user_pref("user.num1", "ui.theme")?, None);
+    Ok(())
+}
+
+#[test]
+fn test_dangling_video_rows() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let shot = |vh: &str| models::VideoShotInsert { video_hash: vh.into(), start_time: 0.0, end_time: 1.0, thumb: None };
+    db.set_video_shots(&vid[0].video_hash, &[shot(&vid[0].video_hash)])?;
+    db.set_video_shots("gone", &[shot("gone"), shot("gone")])?;
+
+    // Dry run only counts
+    let found = db.del_dangling_video_rows(true)?;
+    assert_eq!(found, vec![("video_shots", 2)]);
+    assert_eq!(db.get_video_shots("gone")?.len(), 2);
+
+    assert_eq!(db.del_dangling_video_rows(false)?, found);
+    assert!(db.get_video_shots("gone")?.is_empty());
+    assert_eq!(db.get_video_shots(&vid[0].video_hash)?.len(), 1);
+    assert!(db.del_dangling_video_rows(true)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_folders_and_overlay_defaults() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let vh = &vid[0].video_hash;
+    let mkfolder = |title: &str, parent_id: Option<i32>| db.add_folder(&models::FolderInsert {
+        user_id: "user.num1".into(), title: title.into(), parent_id }).unwrap();
+    let top = mkfolder("Project", None);
+    let sub = mkfolder("Dailies", Some(top.id));
+    assert_eq!(db.get_user_folders("user.num1")?.len(), 2);
+    assert!(db.get_user_folders("user.num2")?.is_empty());
+
+    db.set_video_folder(vh, Some(sub.id))?;
+    assert_eq!(db.get_video(vh)?.folder_id, Some(sub.id));
+    assert_eq!(db.get_folder_path(Some(sub.id))?, vec![sub.id, top.id]);
+    assert!(db.get_folder_path(None)?.is_empty());
+    assert!(matches!(db.set_video_folder("nonexisting", None), Err(DBError::NotFound())));
+
+    // Overlay defaults are inherited from parent folders, and can be set to none
+    assert_eq!(db.get_folder_overlay_defaults(Some(sub.id))?, None);
+    db.set_folder_overlay_defaults(top.id, Some(&["title_safe".into(), "social_9x16".into()]))?;
+    assert_eq!(db.get_folder_overlay_defaults(Some(sub.id))?, Some(vec!["title_safe".into(), "social_9x16".into()]));
+    db.set_folder_overlay_defaults(sub.id, Some(&[]))?;
+    assert_eq!(db.get_folder_overlay_defaults(Some(sub.id))?, Some(vec![]));
+    db.set_folder_overlay_defaults(sub.id, None)?;
+    assert_eq!(db.get_folder_overlay_defaults(Some(sub.id))?.unwrap().len(), 2);
+    assert_eq!(db.get_folder_overlay_defaults(None)?, None);
+
+    // So are transcode presets
+    assert_eq!(db.get_folder_transcode_preset(Some(sub.id))?, None);
+    db.set_folder_transcode_preset(top.id, Some("archive"))?;
+    assert_eq!(db.get_folder_transcode_preset(Some(sub.id))?, Some("archive".into()));
+    db.set_folder_transcode_preset(sub.id, Some("nvenc"))?;
+    assert_eq!(db.get_folder_transcode_preset(Some(sub.id))?, Some("nvenc".into()));
+    db.set_folder_transcode_preset(sub.id, None)?;
+    assert_eq!(db.get_folder_transcode_preset(Some(sub.id))?, Some("archive".into()));
+    assert_eq!(db.get_folder_transcode_preset(None)?, None);
+    assert!(matches!(db.set_folder_transcode_preset(12345, None), Err(DBError::NotFound())));
+
+    // Deleting a folder moves its contents to parent
+    db.del_folder(sub.id)?;
+    assert_eq!(db.get_video(vh)?.folder_id, Some(top.id));
+    assert!(matches!(db.get_folder(sub.id), Err(DBError::NotFound())));
+    assert!(matches!(db.del_folder(sub.id), Err(DBError::NotFound())));
+
+    // Presets: seeded by migration, editable
+    let presets = db.get_overlay_presets()?;
+    assert!(presets.iter().any(|p| p.name == "title_safe" && p.default_on));
+    let custom = models::OverlayPreset { name: "cinemascope".into(), title: "2.39:1".into(),
+        kind: models::overlay_kind::ASPECT.into(), value: 2.39, default_on: false };
+    db.set_overlay_preset(&custom)?;
+    db.set_overlay_preset(&models::OverlayPreset { default_on: true, ..custom.clone() })?;
+    assert!(db.get_overlay_presets()?.iter().any(|p| p.name == "cinemascope" && p.default_on));
+    assert_eq!(db.get_overlay_presets()?.len(), presets.len() + 1);
+    db.del_overlay_preset("cinemascope")?;
+    assert!(matches!(db.del_overlay_preset("cinemascope"), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+fn test_proxy_encoding() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let vh = &vid[0].video_hash;
+    assert_eq!(db.get_video_proxy_encoding(vh)?, None);
+    db.set_video_recompressed(vh, Some(r#"{"rate_control":"two_pass"}"#))?;
+    assert!(db.get_video(vh)?.recompression_done.is_some());
+    assert_eq!(db.get_video_proxy_encoding(vh)?.as_deref(), Some(r#"{"rate_control":"two_pass"}"#));
+    db.set_video_recompressed(vh, Some(r#"{"rate_control":"crf"}"#))?;
+    assert_eq!(db.get_video_proxy_encoding(vh)?.as_deref(), Some(r#"{"rate_control":"crf"}"#));
+    db.set_video_recompressed(vh, None)?;
+    assert_eq!(db.get_video_proxy_encoding(vh)?, None);
+
+    db.set_video_recompressed(vh, Some("{}"))?;
+    db.del_video_and_comments(vh)?;
+    assert_eq!(db.get_video_proxy_encoding(vh)?, None);
+    Ok(())
+}
+
+#[test]
+fn test_video_sources() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let (a, b, c) = (&vid[0].video_hash, &vid[1].video_hash, &vid[2].video_hash);
+
+    // c was stitched from b and a (in that order)
+    db.add_video_sources(c, &[b.clone(), a.clone()], "Stitched from 2 videos")?;
+    assert_eq!(db.get_video_sources(c)?, vec![b.clone(), a.clone()]);
+    assert_eq!(db.get_video_operation(c)?, Some("Stitched from 2 videos".into()));
+    assert_eq!(db.get_video_operation(a)?, None);
+    assert!(db.get_video_sources(a)?.is_empty());
+    assert_eq!(db.get_derived_videos(a)?, vec![c.clone()]);
+    assert_eq!(db.get_derived_videos(b)?, vec![c.clone()]);
+
+    // Deleting derived video removes the links
+    db.del_video_and_comments(c)?;
+    assert!(db.get_video_sources(c)?.is_empty());
+    assert!(db.get_derived_videos(a)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_video_imports() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, com) = make_test_db();
+    let (a, b) = (&vid[0].video_hash, &vid[1].video_hash);
+    let imp = |vh: &str| models::VideoImportInsert {
+        video_hash: vh.into(), origin: "https://a.com".into(), origin_video_hash: "remote1".into(), imported_by: "user.num1".into() };
+    db.add_video_import(&imp(a))?;
+    assert_eq!(db.get_video_import(a)?.origin_video_hash, "remote1");
+    assert!(matches!(db.get_video_import(b), Err(DBError::NotFound())));
+    assert_eq!(db.get_imported_copies("https://a.com", "remote1")?, vec![a.clone()]);
+    assert!(db.get_imported_copies("https://b.com", "remote1")?.is_empty());
+
+    // Imported comments keep their original timestamps
+    let created = chrono::NaiveDate::from_ymd_opt(2020, 1, 2).unwrap().and_hms_opt(3, 4, 5).unwrap();
+    let id = db.add_imported_comment(&models::CommentInsert {
+        video_hash: a.clone(), parent_id: None, user_id: "alice@a.com".into(), username: "Alice".into(),
+        comment: "Imported".into(), timecode: None, timecode_end: None, drawing: None, page: None, region: None,
+        visibility: models::comment_visibility::EXTERNAL.into() }, created, Some(created))?;
+    let c = db.get_comment(id)?;
+    assert_eq!((c.created, c.edited), (created, Some(created)));
+    assert!(id > com.iter().map(|c| c.id).max().unwrap());
+
+    db.del_video_and_comments(a)?;
+    assert!(db.get_imported_copies("https://a.com", "remote1")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_federation() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, com) = make_test_db();
+    let vh = &vid[0].video_hash;
+    let peer = |url: &str| models::FederationPeerInsert {
+        name: "studio-b".into(), url: url.into(), secret: "0123456789abcdef".into(), self_name: "studio-a".into() };
+    db.set_federation_peer(&peer("https://b.com"))?;
+    db.set_federation_peer(&peer("https://b2.com"))?;
+    assert_eq!(db.get_federation_peers()?.len(), 1);
+    assert_eq!(db.get_federation_peer("studio-b")?.url, "https://b2.com");
+    assert!(matches!(db.get_federation_peer("studio-c"), Err(DBError::NotFound())));
+
+    // Folder syncs
+    let folder = db.add_folder(&models::FolderInsert { user_id: "user.num1".into(), title: "Shared".into(), parent_id: None })?;
+    db.set_video_folder(vh, Some(folder.id))?;
+    assert_eq!(db.get_folder_videos(folder.id)?.len(), 1);
+    let sync = db.add_folder_sync(&models::FolderSyncInsert { folder_id: folder.id, peer: "studio-b".into(), remote_folder_id: 7 })?;
+    assert_eq!((sync.cursor, sync.last_sync), (0, None));
+    assert!(db.add_folder_sync(&models::FolderSyncInsert { folder_id: folder.id, peer: "studio-b".into(), remote_folder_id: 8 }).is_err());
+    db.set_folder_sync_result(sync.id, Some(1234), None)?;
+    db.set_folder_sync_result(sync.id, None, Some("Peer unreachable"))?;
+    let s = db.get_folder_sync(folder.id, "studio-b")?;
+    assert_eq!((s.cursor, s.last_error.as_deref()), (1234, Some("Peer unreachable")));
+    assert!(s.last_sync.is_some());
+    assert_eq!(db.get_folder_syncs(None)?.len(), 1);
+    assert!(db.get_folder_syncs(Some(folder.id + 1))?.is_empty());
+
+    // Object mappings
+    let obj = |kind: &str, remote: &str, local: &str, pending| models::FederatedObjectInsert {
+        peer: "studio-b".into(), kind: kind.into(), remote_id: remote.into(), local_id: local.into(), pending_folder_id: pending };
+    db.add_federated_object(&obj(models::federated_kind::VIDEO, "remote_vh", vh, Some(folder.id)))?;
+    db.add_federated_object(&obj(models::federated_kind::COMMENT, "5", &com[0].id.to_string(), None))?;
+    assert!(db.add_federated_object(&obj(models::federated_kind::COMMENT, "5", "999", None)).is_err());
+    assert_eq!(db.get_federated_local_id("studio-b", models::federated_kind::VIDEO, "remote_vh")?.as_ref(), Some(vh));
+    assert_eq!(db.get_federated_local_id("studio-b", models::federated_kind::COMMENT, "remote_vh")?, None);
+    assert_eq!(db.get_federated_remote_id("studio-b", models::federated_kind::COMMENT, &com[0].id.to_string())?.as_deref(), Some("5"));
+    db.clear_federated_pending_folder("studio-b", "remote_vh")?;
+    assert_eq!(db.get_federated_objects("studio-b", models::federated_kind::VIDEO)?[0].pending_folder_id, None);
+
+    // Edits from peers keep their timestamp
+    let edited = chrono::NaiveDate::from_ymd_opt(2030, 1, 2).unwrap().and_hms_opt(3, 4, 5).unwrap();
+    assert!(db.set_comment_text(com[0].id, "Edited at peer", edited)?);
+    assert_eq!(db.get_comment(com[0].id)?.edited, Some(edited));
+
+    // Deleting a peer or folder removes syncs, but not received objects
+    db.del_federation_peer("studio-b")?;
+    assert!(db.get_folder_syncs(None)?.is_empty());
+    assert!(matches!(db.del_federation_peer("studio-b"), Err(DBError::NotFound())));
+    assert_eq!(db.get_federated_objects("studio-b", models::federated_kind::COMMENT)?.len(), 1);
+    db.set_federation_peer(&peer("https://b.com"))?;
+    db.add_folder_sync(&models::FolderSyncInsert { folder_id: folder.id, peer: "studio-b".into(), remote_folder_id: 7 })?;
+    db.del_folder(folder.id)?;
+    assert!(db.get_folder_syncs(None)?.is_empty());
+    assert!(matches!(db.del_folder_sync(folder.id, "studio-b"), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+fn test_video_trash() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let vh = &vid[0].video_hash;
+    let n_videos = db.get_all_user_videos("user.num1")?.len();
+    db.set_video_trashed(vh, true)?;
+    assert!(db.get_video(vh)?.trashed.is_some());
+    assert_eq!(db.get_all_user_videos("user.num1")?.len(), n_videos - 1);
+    assert!(db.get_user_videos_by_content_hash("user.num1", vid[0].content_hash.as_deref().unwrap())?.iter().all(|v| &v.video_hash != vh));
+    assert_eq!(db.get_trashed_videos(Some("user.num1"))?.len(), 1);
+    assert!(db.get_trashed_videos(Some("user.num2"))?.is_empty());
+    assert_eq!(db.get_trashed_videos(None)?.len(), 1);
+
+    db.set_video_trashed(vh, false)?;
+    assert!(db.get_video(vh)?.trashed.is_none());
+    assert_eq!(db.get_all_user_videos("user.num1")?.len(), n_videos);
+    assert!(db.get_trashed_videos(None)?.is_empty());
+    assert!(matches!(db.set_video_trashed("nonexisting", true), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+fn test_users() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+
+    // Admin is created by migration
+    assert!(db.is_user_admin("admin")?);
+    assert!(!db.is_user_admin("user.num1")?);
+    assert!(matches!(db.get_user("user.num1"), Err(DBError::NotFound())));
+
+    let u = db.touch_user("user.num1", "User Num1")?;
+    assert!(!u.is_admin && !u.disabled && u.last_seen.is_some());
+    assert_eq!(db.touch_user("user.num1", "Renamed")?.username, "Renamed");
+    assert_eq!(db.get_users()?.len(), 2);
+
+    // Flags can be set before user has logged in
+    db.set_user_flags("user.num2", None, Some(true))?;
+    assert!(db.is_user_disabled("user.num2")?);
+    assert_eq!(db.get_user("user.num2")?.username, "user.num2");
+    db.set_user_flags("user.num2", Some(true), Some(false))?;
+    let u = db.touch_user("user.num2", "User Num2")?;
+    assert!(u.is_admin && !u.disabled);
+
+    assert_eq!(db.get_video_owners()?, vec!["user.num1", "user.num2"]);
+    assert_eq!(db.get_all_videos(None)?.len(), vid.len());
+    db.set_video_trashed(&vid[0].video_hash, true)?;
+    assert_eq!(db.get_all_videos(Some("user.num1"))?.len(), 3);
+
+    db.set_video_owner(&vid[0].video_hash, "user.num2", "User Num2")?;
+    assert_eq!(db.get_video(&vid[0].video_hash)?.added_by_username.as_deref(), Some("User Num2"));
+    assert_eq!(db.get_all_videos(Some("user.num1"))?.len(), 2);
+    assert!(matches!(db.set_video_owner("nonexisting", "user.num2", "x"), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+fn test_retention() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let future = chrono::Utc::now().naive_utc() + chrono::Duration::days(1);
+    let past = chrono::Utc::now().naive_utc() - chrono::Duration::days(1);
+
+    // Audit events of held videos are kept
+    db.set_video_legal_hold(&vid[0].video_hash, true)?;
+    for vh in [Some(vid[0].video_hash.clone()), Some(vid[1].video_hash.clone()), None] {
+        db.add_audit_event(&models::AuditEventInsert { user_id: "admin".into(), action: "test".into(), ref_video_hash: vh, details: "".into() })?;
+    }
+    assert_eq!(db.del_audit_events_before(past)?, 0);
+    assert_eq!(db.del_audit_events_before(future)?, 2);
+    let left = db.search_audit_events(&Default::default())?;
+    assert_eq!(left.len(), 1);
+    assert_eq!(left[0].ref_video_hash.as_deref(), Some(vid[0].video_hash.as_str()));
+
+    db.add_message(&models::MessageInsert { user_id: "user.num1".into(), event_name: "ok".into(), ..Default::default() })?;
+    assert_eq!(db.del_messages_before(past)?, 0);
+    assert!(db.del_messages_before(future)? > 0);
+    assert!(db.get_user_messages("user.num1")?.is_empty());
+
+    // Finished jobs are summed into stats, unfinished ones kept
+    let job = |sts: &str| models::JobInsert { stage: models::job_stage::TRANSCODE.into(), status: sts.into(), user_id: "user.num1".into(), ..Default::default() };
+    for sts in [models::job_status::DONE, models::job_status::DONE, models::job_status::FAILED, models::job_status::PENDING] {
+        db.add_job(&job(sts))?;
+    }
+    assert_eq!(db.aggregate_and_del_jobs_before(past)?, 0);
+    assert_eq!(db.aggregate_and_del_jobs_before(future)?, 3);
+    assert_eq!(db.get_unfinished_jobs()?.len(), 1);
+    db.add_job(&job(models::job_status::DONE))?;
+    assert_eq!(db.aggregate_and_del_jobs_before(future)?, 1);
+    let stats = db.get_job_stats(None)?;
+    assert_eq!(stats.len(), 2);
+    assert_eq!((stats[0].status.as_str(), stats[0].n_jobs), (models::job_status::DONE, 3));
+    assert_eq!((stats[1].status.as_str(), stats[1].n_jobs), (models::job_status::FAILED, 1));
+    assert!(db.get_job_stats(Some(future.date() + chrono::Duration::days(1)))?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_throughput_stats() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+    let t = |h: u32, m: u32| chrono::NaiveDate::from_ymd_opt(2026, 3, 1).unwrap().and_hms_opt(h, m, 0).unwrap();
+    db.add_throughput(t(10, 5), models::job_stage::TRANSCODE, 60.0, 30.0, 5.0)?;
+    db.add_throughput(t(10, 55), models::job_stage::TRANSCODE, 120.0, 40.0, 15.0)?;
+    db.add_throughput(t(11, 0), models::job_stage::TRANSCODE, 10.0, 5.0, 0.0)?;
+    db.add_throughput(t(10, 30), models::job_stage::THUMBNAIL, 60.0, 2.0, 0.0)?;
+
+    let stats = db.get_throughput_stats(models::job_stage::TRANSCODE, None)?;
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0], models::ThroughputStat { hour: t(10, 0), stage: models::job_stage::TRANSCODE.into(), n_jobs: 2, video_secs: 180.0, work_secs: 70.0, wait_secs: 20.0 });
+    assert_eq!(stats[1].n_jobs, 1);
+    assert_eq!(db.get_throughput_stats(models::job_stage::TRANSCODE, Some(t(10, 30)))?.len(), 1);
+    assert_eq!(db.get_throughput_stats(models::job_stage::THUMBNAIL, None)?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_collab_rooms() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let room = db.join_collab_room("c1", &vid[0].video_hash, "user.num1", "User Num1")?;
+    assert_eq!(room.host, "user.num1");
+    assert!(room.seek_time.is_none());
+
+    // Second joiner doesn't become host, and can't rebind the video
+    assert_eq!(db.join_collab_room("c1", &vid[0].video_hash, "user.num2", "User Num2")?.host, "user.num1");
+    assert_eq!(db.join_collab_room("c1", &vid[1].video_hash, "user.num3", "User Num3")?.video_hash, vid[0].video_hash);
+    let uids = |db: &DB| db.get_collab_participants("c1").unwrap().into_iter().map(|p| p.user_id).collect::<Vec<_>>();
+    assert_eq!(uids(&db), vec!["user.num1", "user.num2"]);
+
+    db.set_collab_playback("c1", false, 12.5, Some(2))?;
+    let room = db.get_collab_room("c1")?;
+    assert_eq!((room.paused, room.seek_time, room.page), (false, Some(12.5), Some(2)));
+    assert!(matches!(db.set_collab_playback("nosuch", true, 0.0, None), Err(DBError::NotFound())));
+
+    // Deleted when the last one leaves
+    db.leave_collab_room("c1", "user.num1")?;
+    assert_eq!(uids(&db), vec!["user.num2"]);
+    db.leave_collab_room("c1", "user.num2")?;
+    assert!(matches!(db.get_collab_room("c1"), Err(DBError::NotFound())));
+
+    // Abandoned rooms
+    db.join_collab_room("c2", &vid[0].video_hash, "user.num1", "User Num1")?;
+    assert_eq!(db.del_stale_collab_rooms(chrono::Utc::now().naive_utc() - chrono::Duration::hours(1))?, 0);
+    assert_eq!(db.del_stale_collab_rooms(chrono::Utc::now().naive_utc() + chrono::Duration::hours(1))?, 1);
+    assert!(db.get_collab_participants("c2")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_teams() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let team = db.add_team("Editors")?;
+    assert!(db.add_team("Editors").is_err());
+    let other = db.add_team("Sound")?;
+    db.set_team_member(&models::TeamMember { team_id: team.id, user_id: "user.num1".into(), is_team_admin: true })?;
+    db.set_team_member(&models::TeamMember { team_id: team.id, user_id: "user.num2".into(), is_team_admin: false })?;
+    db.set_team_member(&models::TeamMember { team_id: team.id, user_id: "user.num2".into(), is_team_admin: true })?;
+    assert!(db.get_team_member(team.id, "user.num2")?.is_team_admin);
+    assert_eq!(db.get_team_members(team.id)?.len(), 2);
+    assert_eq!(db.get_teams(None)?.len(), 2);
+    assert_eq!(db.get_teams(Some("user.num2"))?.iter().map(|t| t.id).collect::<Vec<_>>(), vec![team.id]);
+
+    // Share a video directly, and another one in a subfolder of a shared folder
+    let v1 = db.get_video(&vid[1].video_hash)?;
+    assert!(!db.is_video_shared_with_user(&v1, "user.num1")?);
+    db.add_team_video(&models::TeamVideo { team_id: team.id, video_hash: v1.video_hash.clone(), shared_by: "user.num2".into() })?;
+    db.add_team_video(&models::TeamVideo { team_id: team.id, video_hash: v1.video_hash.clone(), shared_by: "user.num2".into() })?;
+    assert!(db.is_video_shared_with_user(&v1, "user.num1")?);
+    assert!(!db.is_video_shared_with_user(&v1, "user.num3")?);
+
+    let parent = db.add_folder(&models::FolderInsert { user_id: "user.num1".into(), title: "Parent".into(), parent_id: None })?;
+    let child = db.add_folder(&models::FolderInsert { user_id: "user.num1".into(), title: "Child".into(), parent_id: Some(parent.id) })?;
+    db.set_video_folder(&vid[0].video_hash, Some(child.id))?;
+    let v0 = db.get_video(&vid[0].video_hash)?;
+    assert!(!db.is_video_shared_with_user(&v0, "user.num2")?);
+    db.add_team_folder(&models::TeamFolder { team_id: team.id, folder_id: parent.id, shared_by: "user.num1".into() })?;
+    assert!(db.is_video_shared_with_user(&v0, "user.num2")?);
+    let shared = db.get_team_videos(team.id)?.into_iter().map(|v| v.video_hash).collect::<Vec<_>>();
+    assert_eq!(shared, vec![v0.video_hash.clone(), v1.video_hash.clone()]);
+    assert!(db.get_team_videos(other.id)?.is_empty());
+
+    // Unsharing and leaving
+    db.del_team_folder(team.id, parent.id)?;
+    assert!(matches!(db.del_team_folder(team.id, parent.id), Err(DBError::NotFound())));
+    assert!(!db.is_video_shared_with_user(&v0, "user.num2")?);
+    db.del_team_member(team.id, "user.num1")?;
+    assert!(!db.is_video_shared_with_user(&v1, "user.num1")?);
+    assert!(matches!(db.del_team_member(team.id, "user.num1"), Err(DBError::NotFound())));
+
+    // Deleting a team or video removes shares
+    db.del_video_and_comments(&v1.video_hash)?;
+    assert!(db.get_team_shares(team.id)?.0.is_empty());
+    db.add_team_video(&models::TeamVideo { team_id: team.id, video_hash: v0.video_hash.clone(), shared_by: "user.num1".into() })?;
+    db.del_team(team.id)?;
+    assert!(matches!(db.get_team(team.id), Err(DBError::NotFound())));
+    assert!(db.get_team_members(team.id)?.is_empty());
+    assert!(db.get_team_shares(team.id)?.0.is_empty());
+    assert!(matches!(db.del_team(team.id), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+fn test_upload_dedup() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let v1 = &vid[1];   // Owned by user.num2
+    let hash = v1.content_hash.clone().unwrap();
+    assert!(db.get_shared_videos_by_content_hash("user.num1", &hash)?.is_empty());
+    let team = db.add_team("Editors")?;
+    db.set_team_member(&models::TeamMember { team_id: team.id, user_id: "user.num1".into(), is_team_admin: false })?;
+    db.add_team_video(&models::TeamVideo { team_id: team.id, video_hash: v1.video_hash.clone(), shared_by: "user.num2".into() })?;
+    let found = db.get_shared_videos_by_content_hash("user.num1", &hash)?;
+    assert_eq!(found.iter().map(|v| v.video_hash.as_str()).collect::<Vec<_>>(), vec![v1.video_hash.as_str()]);
+    assert!(db.get_shared_videos_by_content_hash("user.num2", &hash)?.is_empty());
+
+    let pu = db.add_pending_upload(&models::PendingUploadInsert {
+        user_id: "user.num1".into(), src_file: "/tmp/x/a.mp4".into(), duplicate_of: v1.video_hash.clone(), loudnorm: None })?;
+    assert_eq!(db.get_pending_upload(pu.id)?.duplicate_of, v1.video_hash);
+    assert_eq!(db.get_pending_uploads(Some("user.num1"))?.len(), 1);
+    assert!(db.get_pending_uploads(Some("user.num2"))?.is_empty());
+    db.del_pending_upload(pu.id)?;
+    assert!(matches!(db.del_pending_upload(pu.id), Err(DBError::NotFound())));
+
+    db.add_video_link("user.num1", &v1.video_hash)?;
+    db.add_video_link("user.num1", &v1.video_hash)?;
+    assert_eq!(db.get_user_linked_videos("user.num1")?.len(), 1);
+    db.set_video_trashed(&v1.video_hash, true)?;
+    assert!(db.get_user_linked_videos("user.num1")?.is_empty());
+    db.del_video_and_comments(&v1.video_hash)?;
+    assert!(matches!(db.del_video_link("user.num1", &v1.video_hash), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+fn test_share_links() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let now = chrono::Utc::now().naive_utc();
+    let mk = |tok: &str, expires: Option<chrono::NaiveDateTime>| models::ShareLinkInsert {
+        token: tok.into(), video_hash: vid[0].video_hash.clone(), created_by: "user.num1".into(), expires, ..Default::default() };
+    let l1 = db.add_share_link(&mk("tok1", None))?;
+    let l2 = db.add_share_link(&mk("tok2", Some(now - chrono::Duration::hours(1))))?;
+    db.add_share_link(&mk("tok3", Some(now + chrono::Duration::hours(1))))?;
+    assert!(db.add_share_link(&mk("tok1", None)).is_err(), "Tokens should be unique");
+
+    assert_eq!(db.get_share_link_by_token("tok2")?.id, l2.id);
+    assert_eq!(db.get_share_link(l1.id)?.token, "tok1");
+    assert!(matches!(db.get_share_link_by_token("nope"), Err(DBError::NotFound())));
+    assert_eq!(db.get_share_links(&vid[0].video_hash)?.len(), 3);
+    assert!(db.get_share_links(&vid[1].video_hash)?.is_empty());
+
+    assert_eq!(db.del_share_links_expired_before(now)?, 1);
+    assert!(matches!(db.get_share_link(l2.id), Err(DBError::NotFound())));
+    db.del_share_link(l1.id)?;
+    assert!(matches!(db.del_share_link(l1.id), Err(DBError::NotFound())));
+
+    db.del_video_and_comments(&vid[0].video_hash)?;
+    assert!(db.get_share_links(&vid[0].video_hash)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_notes() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, com) = make_test_db();
+    let vh = &vid[0].video_hash;
+    let mk = |uid: &str, text: &str| models::NoteInsert { video_hash: vh.clone(), user_id: uid.into(), note: text.into(), timecode: Some("00:00:02:00".into()), ..Default::default() };
+    let n1 = db.add_note(&mk("user.num1", "first"))?;
+    let n2 = db.add_note(&mk("user.num1", "second"))?;
+    db.add_note(&mk("user.num2", "other's"))?;
+    assert_eq!(db.get_user_notes(vh, "user.num1")?.iter().map(|n| n.id).collect::<Vec<_>>(), vec![n1.id, n2.id]);
+    assert!(db.get_user_notes(&vid[1].video_hash, "user.num1")?.is_empty());
+
+    db.edit_note(n1.id, "first, edited")?;
+    let n = db.get_note(n1.id)?;
+    assert_eq!(n.note, "first, edited");
+    assert!(n.edited.is_some());
+
+    let n_comments = db.get_video_comments(vh)?.len();
+    let cid = db.publish_note(n1.id, "User Num1", models::comment_visibility::INTERNAL)?;
+    let c = db.get_comment(cid)?;
+    assert_eq!((c.comment.as_str(), c.user_id.as_str(), c.username.as_str()), ("first, edited", "user.num1", "User Num1"));
+    assert_eq!(c.timecode.as_deref(), Some("00:00:02:00"));
+    assert!(c.is_internal());
+    assert!(c.id > com.iter().map(|c| c.id).max().unwrap());
+    assert_eq!(db.get_video_comments(vh)?.len(), n_comments + 1);
+    assert!(matches!(db.get_note(n1.id), Err(DBError::NotFound())));
+    assert!(matches!(db.publish_note(n1.id, "x", models::comment_visibility::EXTERNAL), Err(DBError::NotFound())));
+
+    db.del_note(n2.id)?;
+    assert!(matches!(db.del_note(n2.id), Err(DBError::NotFound())));
+    assert!(matches!(db.edit_note(n2.id, "x"), Err(DBError::NotFound())));
+
+    db.del_video_and_comments(vh)?;
+    assert!(db.get_user_notes(vh, "user.num2")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_comment_visibility() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, com) = make_test_db();
+    assert!(com.iter().all(|c| !c.is_internal()), "Comments should be external by default");
+    db.set_comment_visibility(com[0].id, models::comment_visibility::INTERNAL)?;
+    assert!(db.get_comment(com[0].id)?.is_internal());
+    assert!(!db.get_comment(com[1].id)?.is_internal());
+    db.set_comment_visibility(com[0].id, models::comment_visibility::EXTERNAL)?;
+    assert!(!db.get_comment(com[0].id)?.is_internal());
+    assert!(matches!(db.set_comment_visibility(9999, models::comment_visibility::INTERNAL), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+fn test_guests() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let link = db.add_share_link(&models::ShareLinkInsert { token: "tok".into(), video_hash: vid[0].video_hash.clone(), created_by: "user.num1".into(), ..Default::default() })?;
+    let mk = |name: &str, k: &str| models::GuestInsert { share_link_id: link.id, name: name.into(), key: k.into() };
+    let g1 = db.add_guest(&mk("Carol", "key1"))?;
+    let g2 = db.add_guest(&mk("Dave", "key2"))?;
+    assert!(db.add_guest(&mk("Eve", "key1")).is_err(), "Keys should be unique");
+    assert_eq!(g1.user_id(), format!("guest:{}", g1.id));
+    assert_eq!(db.get_guest_by_key("key2")?.id, g2.id);
+    assert!(matches!(db.get_guest_by_key("nope"), Err(DBError::NotFound())));
+    assert_eq!(db.get_guests(Some(link.id))?.len(), 2);
+    assert!(db.get_guests(Some(link.id + 1))?.is_empty());
+    assert_eq!(db.get_guests(None)?.len(), 2);
+
+    db.set_guest_name(g1.id, "Carol B")?;
+    assert_eq!(db.get_guest(g1.id)?.name, "Carol B");
+    assert!(matches!(db.set_guest_name(9999, "x"), Err(DBError::NotFound())));
+
+    let mk_comment = |uid: String| models::CommentInsert { video_hash: vid[0].video_hash.clone(), user_id: uid, username: "Carol".into(),
+        comment: "Hi".into(), parent_id: None, timecode: None, timecode_end: None, drawing: None, page: None, region: None, visibility: models::comment_visibility::EXTERNAL.into() };
+    let c1 = db.add_comment(&mk_comment(g1.user_id()))?;
+    let c2 = db.add_comment(&mk_comment(g2.user_id()))?;
+    assert!(db.get_comment(c1)?.is_guest());
+    assert_eq!(db.count_user_comments(&g1.user_id())?, 1);
+
+    assert_eq!(db.promote_guest(g1.id, "user.num2", "User Num2")?, 1);
+    let c = db.get_comment(c1)?;
+    assert_eq!((c.user_id.as_str(), c.username.as_str()), ("user.num2", "User Num2"));
+    assert!(!c.is_guest());
+    assert!(db.get_comment(c2)?.is_guest());
+    assert_eq!(db.get_guest(g1.id)?.promoted_to.as_deref(), Some("user.num2"));
+    assert!(matches!(db.promote_guest(9999, "user.num2", "x"), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+fn test_closed_reviews() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let vh = &vid[0].video_hash;
+    assert!(db.get_closed_review(vh)?.is_none());
+    db.close_review(vh, "user.num1")?;
+    db.close_review(vh, "admin")?;
+    assert_eq!(db.get_closed_review(vh)?.unwrap().closed_by, "admin");
+    assert!(db.get_closed_review(&vid[1].video_hash)?.is_none());
+
+    db.reopen_review(vh)?;
+    assert!(db.get_closed_review(vh)?.is_none());
+    assert!(matches!(db.reopen_review(vh), Err(DBError::NotFound())));
+
+    db.close_review(vh, "user.num1")?;
+    db.del_video_and_comments(vh)?;
+    assert!(db.get_closed_review(vh)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn test_review_verdicts() -> anyhow::Result<()> {
+    use models::review_verdict as rv;
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let vh = &vid[0].video_hash;
+    let mk = |uid: &str, verdict: &str| models::ReviewVerdictInsert {
+        video_hash: vh.clone(), user_id: uid.into(), username: uid.into(), verdict: verdict.into(), note: None };
+    assert_eq!(rv::aggregate(&db.get_review_verdicts(vh)?), rv::PENDING);
+
+    db.set_review_verdict(&mk("user.num2", rv::APPROVED))?;
+    assert_eq!(rv::aggregate(&db.get_review_verdicts(vh)?), rv::APPROVED);
+    db.set_review_verdict(&mk("admin", rv::CHANGES_REQUESTED))?;
+    assert_eq!(rv::aggregate(&db.get_review_verdicts(vh)?), rv::CHANGES_REQUESTED);
+    db.set_review_verdict(&models::ReviewVerdictInsert { note: Some("ok now".into()), ..mk("admin", rv::APPROVED) })?;
+    let verdicts = db.get_review_verdicts(vh)?;
+    assert_eq!(verdicts.len(), 2, "New verdict should replace reviewer's old one");
+    assert_eq!(verdicts[1].note.as_deref(), Some("ok now"));
+    assert_eq!(rv::aggregate(&verdicts), rv::APPROVED);
+    assert!(db.get_review_verdicts(&vid[1].video_hash)?.is_empty());
+
+    db.del_review_verdict(vh, "admin")?;
+    assert!(matches!(db.del_review_verdict(vh, "admin"), Err(DBError::NotFound())));
+    assert_eq!(db.get_review_verdicts(vh)?.len(), 1);
+
+    db.del_video_and_comments(vh)?;
+    assert!(db.get_review_verdicts(vh)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_comment_numbers() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, com) = make_test_db();
+    let vh = &vid[0].video_hash;
+    let numbers = |vh: &str| db.get_video_comments(vh).unwrap().iter().map(|c| c.number).collect::<Vec<_>>();
+    let n = numbers(vh).len() as i32;
+    assert_eq!(numbers(vh), (1..=n).collect::<Vec<_>>(), "Comments should be numbered per video");
+    assert_eq!(numbers(&vid[1].video_hash), vec![1, 2]);
+
+    // Numbers stay on edit and aren't reused after delete
+    let last = db.get_video_comments(vh)?.into_iter().last().unwrap();
+    db.edit_comment(com[0].id, "edited", "user.num1")?;
+    assert_eq!(db.get_comment(com[0].id)?.number, com[0].number);
+    db.del_comment(last.id)?;
+    let mk = |text: &str| models::CommentInsert { video_hash: vh.clone(), parent_id: None, user_id: "user.num1".into(), username: "User Num1".into(),
+        comment: text.into(), timecode: None, timecode_end: None, drawing: None, page: None, region: None, visibility: models::comment_visibility::EXTERNAL.into() };
+    assert_eq!(db.get_comment(db.add_comment(&mk("new"))?)?.number, n + 1);
+    let imported = db.add_imported_comment(&mk("imported"), chrono::NaiveDateTime::default(), None)?;
+    assert_eq!(db.get_comment(imported)?.number, n + 2);
+    let note = db.add_note(&models::NoteInsert { video_hash: vh.clone(), user_id: "user.num1".into(), note: "note".into(), ..Default::default() })?;
+    assert_eq!(db.get_comment(db.publish_note(note.id, "User Num1", models::comment_visibility::EXTERNAL)?)?.number, n + 3);
+
+    db.del_video_and_comments(vh)?;
+    assert_eq!(db.get_comment(db.add_comment(&mk("again"))?)?.number, 1);
+    Ok(())
+}
+
+#[test]
+fn test_video_tags() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let (vh0, vh1) = (vid[0].video_hash.clone(), vid[1].video_hash.clone());
+    assert!(db.add_video_tag(&vh0, "Show A", "user.num1")?);
+    assert!(!db.add_video_tag(&vh0, "show a", "user.num1")?, "Tags should be case-insensitive");
+    db.add_video_tag(&vh0, "Episode 1", "user.num1")?;
+    db.add_video_tag(&vh1, "SHOW A", "user.num2")?;
+    db.add_video_tag(&vh1, "Shots_50%", "user.num2")?;
+    assert_eq!(db.get_video_tags(std::slice::from_ref(&vh1))?[1].tag, "Show A", "Existing spelling should be used");
+
+    let tags = db.get_video_tags(std::slice::from_ref(&vh0))?;
+    assert_eq!(tags.iter().map(|t| t.tag.as_str()).collect::<Vec<_>>(), vec!["Episode 1", "Show A"]);
+    assert_eq!(db.get_video_tags(&[vh0.clone(), vh1.clone()])?.len(), 4);
+
+    assert_eq!(db.search_video_tags("sh", None, 10)?, vec![("Show A".to_string(), 2), ("Shots_50%".to_string(), 1)]);
+    assert_eq!(db.search_video_tags("", Some(std::slice::from_ref(&vh0)), 10)?.len(), 2);
+    assert_eq!(db.search_video_tags("shots_5", None, 10)?.len(), 1);
+    assert!(db.search_video_tags("shots%", None, 10)?.is_empty(), "Wildcards should be escaped");
+    assert_eq!(db.search_video_tags("", None, 1)?.len(), 1);
+
+    db.del_video_tag(&vh0, "SHOW A")?;
+    assert!(matches!(db.del_video_tag(&vh0, "Show A"), Err(DBError::NotFound())));
+    db.del_video_and_comments(&vh1)?;
+    assert!(db.get_video_tags(&[vh1])?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_custom_fields() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let dept = models::CustomField { name: "dept".into(), title: "Department".into(),
+        field_type: models::custom_field_type::SELECT.into(), options: Some(r#"["VFX","Sound"]"#.into()) };
+    db.set_custom_field(&dept)?;
+    db.set_custom_field(&models::CustomField { name: "frames".into(), title: "Frames".into(), field_type: "number".into(), options: None })?;
+    db.set_custom_field(&models::CustomField { title: "Dept.".into(), ..dept.clone() })?;
+    let fields = db.get_custom_fields()?;
+    assert_eq!(fields.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), vec!["dept", "frames"]);
+    assert_eq!(fields[0].title, "Dept.");
+    assert_eq!(fields[0].select_options(), vec!["VFX", "Sound"]);
+
+    assert_eq!(dept.validate(&serde_json::json!("VFX")), Ok(serde_json::json!("VFX")));
+    assert!(dept.validate(&serde_json::json!("vfx")).is_err());
+    assert!(fields[1].validate(&serde_json::json!("12")).is_err());
+    assert_eq!(fields[1].validate(&serde_json::json!(null)), Ok(serde_json::Value::Null));
+
+    let (vh0, vh1) = (vid[0].video_hash.clone(), vid[1].video_hash.clone());
+    let vals = |v: serde_json::Value| v.as_object().unwrap().clone();
+    db.update_video_custom_fields(&vh0, &vals(serde_json::json!({"dept": "VFX", "frames": 10})))?;
+    let res = db.update_video_custom_fields(&vh0, &vals(serde_json::json!({"frames": null, "dept": "Sound"})))?;
+    assert_eq!(serde_json::Value::Object(res), serde_json::json!({"dept": "Sound"}));
+    db.update_video_custom_fields(&vh1, &vals(serde_json::json!({"frames": 5})))?;
+
+    let all = db.get_video_custom_fields(&[vh0.clone(), vh1.clone(), vid[2].video_hash.clone()])?;
+    assert_eq!(all.len(), 2);
+    assert_eq!(all[&vh1]["frames"], 5);
+
+    db.update_video_custom_fields(&vh1, &vals(serde_json::json!({"frames": null})))?;
+    assert!(db.get_video_custom_fields(std::slice::from_ref(&vh1))?.is_empty());
+    db.del_video_and_comments(&vh0)?;
+    assert!(db.get_video_custom_fields(&[vh0])?.is_empty());
+
+    db.del_custom_field("dept")?;
+    assert!(matches!(db.del_custom_field("dept"), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+fn test_search_users() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+    assert_eq!(models::normalize_for_search("  Järvinen  Øystein Straße "), "jarvinen oystein strasse");
+    db.touch_user("jjarvinen", "Jussi Järvinen")?;
+    db.touch_user("ajarvis", "Anna Jarvis")?;
+    db.touch_user("bvarvinen", "Björn Varvinen")?;
+    db.touch_user("zoe", "Zoë Ström")?;
+    db.set_user_flags("old.jarvinen", None, Some(true))?;
+
+    let names = |q: &str, limit: usize| -> anyhow::Result<Vec<String>> {
+        Ok(db.search_users(q, limit)?.into_iter().map(|u| u.user_id).collect())
+    };
+    assert_eq!(names("jarvinen", 10)?, vec!["jjarvinen", "bvarvinen"], "Diacritics ignored, similar after exact, disabled left out");
+    assert_eq!(names("JÄRV", 10)?, vec!["ajarvis", "jjarvinen"]);
+    assert_eq!(names("arvi", 10)?, vec!["ajarvis", "bvarvinen", "jjarvinen"]);
+    assert_eq!(names("jarvnen", 10)?, vec!["jjarvinen"], "Typo should still match");
+    assert_eq!(names("zoe strom", 10)?, vec!["zoe"]);
+    assert_eq!(names("jarvinen", 1)?, vec!["jjarvinen"]);
+    assert!(names(" ", 10)?.is_empty());
+    assert!(names("50%", 10)?.is_empty());
+
+    // Renames update the key
+    db.touch_user("zoe", "Zoe Smith")?;
+    assert!(names("strom", 10)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_user_onboarded() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+    assert!(!db.mark_user_onboarded("nobody")?, "Unknown users can't be onboarded");
+    db.touch_user("new.user", "New User")?;
+    assert!(db.get_user("new.user")?.onboarded.is_none());
+    assert!(db.mark_user_onboarded("new.user")?);
+    assert!(!db.mark_user_onboarded("new.user")?, "Only first login should count");
+    assert!(db.get_user("new.user")?.onboarded.is_some());
+    Ok(())
+}
+
+#[test]
+fn test_maintenance_windows() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+    let now = chrono::Utc::now().naive_utc();
+    let hours = |h: i64| now + chrono::Duration::hours(h);
+    let past = db.add_maintenance_window(&models::MaintenanceWindowInsert { starts: hours(-3), ends: hours(-2), message: "old".into(), created_by: "admin".into() })?;
+    let later = db.add_maintenance_window(&models::MaintenanceWindowInsert { starts: hours(24), ends: hours(25), message: "later".into(), created_by: "admin".into() })?;
+    let cur = db.add_maintenance_window(&models::MaintenanceWindowInsert { starts: hours(-1), ends: hours(1), message: "".into(), created_by: "admin".into() })?;
+
+    let ids = |w: Vec<models::MaintenanceWindow>| w.into_iter().map(|w| w.id).collect::<Vec<_>>();
+    assert_eq!(ids(db.get_maintenance_windows(now)?), vec![cur.id, later.id]);
+    assert_eq!(ids(db.get_maintenance_windows(hours(-10))?), vec![past.id, cur.id, later.id]);
+
+    db.del_maintenance_window(cur.id)?;
+    assert!(matches!(db.del_maintenance_window(cur.id), Err(DBError::NotFound())));
+    assert_eq!(ids(db.get_maintenance_windows(now)?), vec![later.id]);
+    Ok(())
+}
+
+#[test]
+fn test_feature_overrides() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+    let team = db.add_team("Pilot")?;
+    let set = |feature: &str, target: &str, enabled: bool| db.set_feature_override(&models::FeatureOverrideInsert {
+        feature: feature.into(), target: target.into(), enabled, set_by: "admin".into() });
+    set("collab", "*", false)?;
+    set("collab", "*", true)?;
+    set("collab", &format!("team:{}", team.id), false)?;
+    set("stitch", "user:alice", true)?;
+
+    assert_eq!(db.get_feature_overrides(None)?.len(), 3);
+    assert!(db.get_feature_overrides(None)?[0].enabled);
+    let mine = db.get_feature_overrides(Some(&["*".to_string(), "user:alice".to_string()]))?;
+    assert_eq!(mine.iter().map(|o| o.feature.as_str()).collect::<Vec<_>>(), vec!["collab", "stitch"]);
+
+    db.del_feature_override("stitch", "user:alice")?;
+    assert!(matches!(db.del_feature_override("stitch", "user:alice"), Err(DBError::NotFound())));
+
+    // Deleting a team drops its overrides
+    db.del_team(team.id)?;
+    assert_eq!(db.get_feature_overrides(None)?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_upload_sessions() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, _com) = make_test_db();
+    let add = |id: &str, user: &str| db.add_upload_session(&models::UploadSessionInsert {
+        id: id.into(), user_id: user.into(), filename: "big.mov".into(), total_size: 1000,
+        status: models::upload_session_status::ACTIVE.into(), loudnorm: None });
+    let s = add("s1", "alice")?;
+    assert_eq!(s.received, 0);
+    add("s2", "bob")?;
+    assert_eq!(db.get_upload_sessions(Some("alice"))?.len(), 1);
+    assert_eq!(db.get_upload_sessions(None)?.len(), 2);
+
+    db.update_upload_session("s1", Some(models::upload_session_status::PAUSED), Some(400))?;
+    let s = db.get_upload_session("s1")?;
+    assert_eq!((s.status.as_str(), s.received), (models::upload_session_status::PAUSED, 400));
+    db.update_upload_session("s1", None, Some(500))?;
+    assert_eq!(db.get_upload_session("s1")?.status, models::upload_session_status::PAUSED);
+    assert!(matches!(db.update_upload_session("nosuch", None, Some(1)), Err(DBError::NotFound())));
+
+    db.del_upload_session("s1")?;
+    assert!(matches!(db.get_upload_session("s1"), Err(DBError::NotFound())));
+    assert!(matches!(db.del_upload_session("s1"), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+fn test_comment_reactions() -> anyhow::Result<()> {
+    use models::comment_reaction as cr;
+    let (db, _data_dir, _vid, com) = make_test_db();
+    let cid = com[0].id;
+    assert!(db.add_comment_reaction(cid, "alice", "👍")?);
+    assert!(!db.add_comment_reaction(cid, "alice", "👍")?);
+    assert!(db.add_comment_reaction(cid, "bob", "🎉")?);
+    assert!(db.add_comment_reaction(cid, "bob", "👍")?);
+    assert!(db.add_comment_reaction(com[1].id, "bob", "👍")?);
+
+    let sum = cr::summarize(&db.get_comment_reactions(cid)?);
+    assert_eq!(sum, serde_json::json!([
+        {"emoji": "👍", "count": 2, "users": ["alice", "bob"]},
+        {"emoji": "🎉", "count": 1, "users": ["bob"]}]));
+
+    assert!(db.del_comment_reaction(cid, "alice", "👍")?);
+    assert!(!db.del_comment_reaction(cid, "alice", "👍")?);
+    assert_eq!(db.get_comment_reactions(cid)?.len(), 2);
+
+    // Deleting a comment drops its reactions only
+    db.del_comment(cid)?;
+    assert!(db.get_comment_reactions(cid)?.is_empty());
+    assert_eq!(db.get_comment_reactions(com[1].id)?.len(), 1);
+
+    assert!(cr::is_valid("👍") && cr::is_valid("👍🏽") && cr::is_valid("🇫🇮") && cr::is_valid("👨‍👩‍👧"));
+    assert!(!cr::is_valid("") && !cr::is_valid(":+1:") && !cr::is_valid("👍 👍") && !cr::is_valid("a👍"));
+    Ok(())
+}
+
+#[test]
+fn test_comment_revisions() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, com) = make_test_db();
+    let cid = com[0].id;
+    assert!(db.get_comment_revisions(cid)?.is_empty());
+    assert!(db.edit_comment(cid, "second", "user.num1")?);
+    assert!(db.edit_comment(cid, "second", "user.num1")?);   // same text, no new revision
+    let peer_time = chrono::NaiveDate::from_ymd_opt(2030, 1, 2).unwrap().and_hms_opt(3, 4, 5).unwrap();
+    assert!(db.set_comment_text(cid, "third", peer_time)?);
+    assert!(!db.edit_comment(-1, "nope", "user.num1")?);
+
+    let revs = db.get_comment_revisions(cid)?;
+    assert_eq!(revs.iter().map(|r| (r.comment.as_str(), r.replaced_by.as_deref())).collect::<Vec<_>>(),
+        vec![(com[0].comment.as_str(), Some("user.num1")), ("second", None)]);
+    assert_eq!(revs[0].written, com[0].created);
+    assert!(db.get_comment(cid)?.is_edited());
+
+    db.del_comment(cid)?;
+    assert!(db.get_comment_revisions(cid)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_comment_attachments() -> anyhow::Result<()> {
+    let (db, _data_dir, _vid, com) = make_test_db();
+    let add = |cid: i32, name: &str| db.add_comment_attachment(&models::CommentAttachmentInsert {
+        comment_id: cid, video_hash: com[0].video_hash.clone(), user_id: "user.num1".into(),
+        filename: name.into(), mime_type: "image/png".into(), size: 5 });
+    let a1 = add(com[0].id, "a.png")?;
+    let a2 = add(com[0].id, "b.png")?;
+    add(com[1].id, "c.png")?;
+    assert_eq!(db.get_comment_attachments(com[0].id)?.iter().map(|a| a.id).collect::<Vec<_>>(), vec![a1.id, a2.id]);
+    assert_eq!(db.get_comment_attachment(a2.id)?.filename, "b.png");
+
+    assert!(db.del_comment_attachment(a1.id)?);
+    assert!(!db.del_comment_attachment(a1.id)?);
+    assert!(matches!(db.get_comment_attachment(a1.id), Err(DBError::NotFound())));
+
+    // Deleting a comment drops its attachment records
+    db.del_comment(com[0].id)?;
+    assert!(db.get_comment_attachments(com[0].id)?.is_empty());
+    assert_eq!(db.get_comment_attachments(com[1].id)?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_apply_video_batch() -> anyhow::Result<()> {
+    use crate::database::VideoBatchOp as Op;
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let vhs = vec![vid[0].video_hash.clone(), vid[2].video_hash.clone()];
+    let tags = |vh: &str| db.get_video_tags(&[vh.to_string()]).unwrap().into_iter().map(|t| t.tag).collect::<Vec<_>>();
+
+    db.add_video_tag(&vid[4].video_hash, "Client A", "user.num1")?;
+    db.apply_video_batch(&vhs, &Op::AddTag { tag: "client a".into(), user_id: "user.num1".into() })?;
+    assert_eq!(tags(&vhs[0]), vec!["Client A"]);
+    assert_eq!(tags(&vhs[1]), vec!["Client A"]);
+
+    let f = db.add_folder(&models::FolderInsert { user_id: "user.num1".into(), title: "Batch".into(), parent_id: None })?;
+    db.apply_video_batch(&vhs, &Op::MoveToFolder(Some(f.id)))?;
+    db.apply_video_batch(&vhs, &Op::SetAllowDownload(false))?;
+    db.apply_video_batch(&vhs, &Op::Trash)?;
+    for vh in &vhs {
+        let v = db.get_video(vh)?;
+        assert_eq!((v.folder_id, v.allow_download, v.trashed.is_some()), (Some(f.id), false, true));
+    }
+
+    // Missing video rolls back the whole batch
+    let with_missing = vec![vhs[0].clone(), "nosuch".into()];
+    assert!(matches!(db.apply_video_batch(&with_missing, &Op::DelTag("Client A".into())), Err(DBError::NotFound())));
+    assert!(matches!(db.apply_video_batch(&with_missing, &Op::MoveToFolder(None)), Err(DBError::NotFound())));
+    assert_eq!(tags(&vhs[0]), vec!["Client A"]);
+    assert_eq!(db.get_video(&vhs[0])?.folder_id, Some(f.id));
+
+    db.apply_video_batch(&vhs, &Op::DelTag("CLIENT A".into()))?;
+    assert!(tags(&vhs[0]).is_empty() && tags(&vhs[1]).is_empty());
+    assert_eq!(tags(&vid[4].video_hash), vec!["Client A"]);
+    Ok(())
+}
+
+#[test]
+fn test_user_videos_page() -> anyhow::Result<()> {
+    use crate::database::VideoSort;
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let page = |tags: &[String], sort, desc, offset, limit| {
+        let (vids, total) = db.get_user_videos_page("user.num1", tags, sort, desc, offset, limit).unwrap();
+        (vids.into_iter().map(|v| v.video_hash).collect::<Vec<_>>(), total)
+    };
+    let hashes = |idx: &[usize]| idx.iter().map(|i| vid[*i].video_hash.clone()).collect::<Vec<_>>();
+
+    // Own videos and linked ones
+    db.add_video_link("user.num1", &vid[1].video_hash)?;
+    assert_eq!(page(&[], VideoSort::Added, false, 0, None), (hashes(&[0, 1, 2, 4]), 4));
+    assert_eq!(page(&[], VideoSort::Duration, true, 0, None), (hashes(&[4, 2, 1, 0]), 4));
+    assert_eq!(page(&[], VideoSort::CommentCount, true, 0, None), (hashes(&[0, 1, 2, 4]), 4));
+    db.rename_video(&vid[2].video_hash, "beta")?;
+    db.rename_video(&vid[4].video_hash, "Zeta")?;
+    assert_eq!(page(&[], VideoSort::Title, false, 0, None), (hashes(&[2, 0, 1, 4]), 4));
+
+    // Paging
+    assert_eq!(page(&[], VideoSort::Duration, false, 1, Some(2)), (hashes(&[1, 2]), 4));
+    assert_eq!(page(&[], VideoSort::Duration, false, 3, Some(2)), (hashes(&[4]), 4));
+    assert_eq!(page(&[], VideoSort::Duration, false, 10, Some(2)), (vec![], 4));
+
+    // Tag filter, trash
+    db.add_video_tag(&vid[0].video_hash, "Client A", "user.num1")?;
+    db.add_video_tag(&vid[1].video_hash, "Client A", "user.num2")?;
+    db.add_video_tag(&vid[1].video_hash, "Final", "user.num2")?;
+    assert_eq!(page(&["client a".into()], VideoSort::Duration, true, 0, Some(1)), (hashes(&[1]), 2));
+    assert_eq!(page(&["client a".into(), "final".into()], VideoSort::Added, false, 0, None), (hashes(&[1]), 1));
+    db.set_video_trashed(&vid[0].video_hash, true)?;
+    assert_eq!(page(&[], VideoSort::Added, false, 0, None), (hashes(&[1, 2, 4]), 3));
+    Ok(())
+}
+
+#[test]
+fn test_video_comments_page() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let vh = &vid[0].video_hash;
+    db.add_comment(&models::CommentInsert {
+        video_hash: vh.clone(),
+        parent_id: None,
+        timecode: None,
+        timecode_end: None,
+        user_id: "user.num1".into(),
+        username: "User Number1".into(),
+        comment: "Internal".into(),
+        drawing: None,
+        page: None,
+        region: None,
+        visibility: models::comment_visibility::INTERNAL.into(),
+    })?;
+    let all = db.get_video_comments_page(vh, true, 0, None)?.0.into_iter().map(|c| c.id).collect::<Vec<_>>();
+    assert_eq!(all.len(), 6);
+    assert!(all.windows(2).all(|w| w[0] < w[1]));
+
+    let (page, total) = db.get_video_comments_page(vh, true, 1, Some(2))?;
+    assert_eq!((page.iter().map(|c| c.id).collect::<Vec<_>>(), total), (all[1..3].to_vec(), 6));
+    let (page, total) = db.get_video_comments_page(vh, false, 0, None)?;
+    assert_eq!((page.len(), total), (5, 5));
+    assert!(page.iter().all(|c| !c.is_internal()));
+    Ok(())
+}
+
+#[test]
+fn test_tracker_sync_tables() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, com) = make_test_db();
+    let c = &com[0];
+    db.add_tracker_push(c.id, &c.video_hash)?;
+    db.add_tracker_push(c.id, &c.video_hash)?;  // Already queued
+    let item = db.get_tracker_pushes(true)?.remove(0);
+    assert_eq!((item.comment_id, item.attempts), (c.id, 0));
+
+    let later = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(5);
+    db.set_tracker_push_failed(item.id, later, "Timeout")?;
+    assert!(db.get_tracker_pushes(true)?.is_empty());
+    assert_eq!(db.get_tracker_pushes(false)?[0].last_error.as_deref(), Some("Timeout"));
+    assert_eq!(db.retry_tracker_pushes_now()?, 1);
+    db.del_tracker_push(db.get_tracker_pushes(true)?[0].id)?;
+    assert!(db.get_tracker_pushes(false)?.is_empty());
+
+    let note = |cid: i32, rid: &str, reply_to: Option<&str>| models::TrackerNoteInsert {
+        comment_id: cid, video_hash: c.video_hash.clone(), tracker: "ftrack".into(), remote_id: rid.into(), reply_to: reply_to.map(String::from) };
+    db.add_tracker_note(&note(c.id, "n1", None))?;
+    db.add_tracker_note(&note(com[1].id, "n2", Some("n1")))?;
+    let since = chrono::Utc::now().naive_utc() - chrono::Duration::days(1);
+    assert_eq!(db.get_pushed_tracker_notes("ftrack", since)?.iter().map(|n| n.remote_id.as_str()).collect::<Vec<_>>(), vec!["n1"]);
+    assert!(db.get_pushed_tracker_notes("shotgrid", since)?.is_empty());
+    assert_eq!(db.get_tracker_note_by_remote_id("ftrack", "n2")?.map(|n| n.comment_id), Some(com[1].id));
+
+    // Queue and notes go with the video
+    db.add_tracker_push(c.id, &c.video_hash)?;
+    db.del_video_and_comments(&vid[0].video_hash)?;
+    assert!(db.get_tracker_pushes(false)?.is_empty());
+    assert!(db.get_tracker_note_by_remote_id("ftrack", "n1")?.is_none());
+    Ok(())
+}
+
+#[test]
+fn test_review_sessions() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let now = chrono::Utc::now().naive_utc();
+    let add = |cid: &str, vh: &str, starts: chrono::NaiveDateTime| db.add_review_session(&models::ReviewSessionInsert {
+        collab_id: cid.into(), video_hash: vh.into(), host: "user.num1".into(), host_name: "User Num1".into(),
+        title: cid.into(), starts, duration_min: 60 }, &["user.num2".into(), "user.num2".into()]);
+    let old = add("old", &vid[0].video_hash, now - chrono::Duration::days(3))?;
+    let soon = add("soon", &vid[0].video_hash, now + chrono::Duration::hours(1))?;
+    let other = add("other", &vid[1].video_hash, now + chrono::Duration::hours(2))?;
+    assert_eq!(db.get_review_invitees(soon.id)?, vec!["user.num2"]);
+
+    let since = now - chrono::Duration::days(1);
+    assert_eq!(db.get_user_review_sessions("user.num2", since)?.iter().map(|s| s.id).collect::<Vec<_>>(), vec![soon.id, other.id]);
+    assert!(db.get_user_review_sessions("user.num3", since)?.is_empty());
+    assert_eq!(db.get_due_review_sessions()?.iter().map(|s| s.id).collect::<Vec<_>>(), vec![old.id]);
+
+    assert_eq!(db.del_past_review_sessions(since)?, 1);
+    assert!(db.get_review_invitees(old.id)?.is_empty());
+    db.del_video_and_comments(&vid[0].video_hash)?;
+    assert!(matches!(db.get_review_session(soon.id), Err(DBError::NotFound())));
+    assert!(db.get_review_invitees(soon.id)?.is_empty());
+    db.del_review_session(other.id)?;
+    assert!(matches!(db.del_review_session(other.id), Err(DBError::NotFound())));
+    Ok(())
+}
+
+#[test]
+fn test_video_changes() -> anyhow::Result<()> {
+    let (db, _data_dir, vid, _com) = make_test_db();
+    let (vh0, vh1) = (vid[0].video_hash.clone(), vid[1].video_hash.clone());  // Of user.num1 and user.num2
+    let start = db.get_latest_video_change()?;
+    assert!(start > 0, "Test data was journaled");
+    assert_eq!(db.get_video_changes(start)?, (start, vec![]));
+
+    // Changes that show in listings are journaled by triggers
+    db.rename_video(&vh0, "Renamed")?;
+    db.add_video_tag(&vh1, "hero", "user.num2")?;
+    let (latest, changed) = db.get_video_changes(start)?;
+    assert_eq!((latest, changed), (start + 2, { let mut v = vec![vh0.clone(), vh1.clone()]; v.sort(); v }));
+    db.set_video_seen("user.num1", &vh0, chrono::Utc::now().naive_utc())?;
+    db.del_video_and_comments(&vh1)?;
+    let (latest2, changed) = db.get_video_changes(latest)?;
+    assert!(latest2 > latest);
+    assert_eq!(changed.len(), 2);
+
+    // Videos that could be in a user's list
+    assert!(db.any_user_video("user.num1", std::slice::from_ref(&vh0))?);
+    assert!(!db.any_user_video("user.num1", &[vid[3].video_hash.clone()])?);
+    db.add_video_link("user.num1", &vid[3].video_hash)?;
+    assert!(db.any_user_video("user.num1", &[vid[3].video_hash.clone()])?);
+
+    assert_eq!(db.del_video_changes_before(chrono::Utc::now().naive_utc() - chrono::Duration::hours(1))?, 0);
+    assert!(db.del_video_changes_before(chrono::Utc::now().naive_utc() + chrono::Duration::hours(1))? > 0);
+    assert_eq!(db.get_latest_video_change()?, 0);
+    Ok(())
+}
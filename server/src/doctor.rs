@@ -0,0 +1,248 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::database::DB;
+use crate::quota::Quotas;
+
+/// Oldest external tool versions known to work
+const MIN_FFMPEG_VERSION: (u32, u32) = (4, 0);
+const MIN_MEDIAINFO_VERSION: (u32, u32) = (18, 0);
+
+/// Warn if free disk space in data dir is below this
+pub(crate) const MIN_FREE_DISK_BYTES: u64 = 5 * 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status { Ok, Warn, Fail }
+
+/// Result of a single diagnostic check
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub msg: String,
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &str, msg: impl Into<String>) -> Check {
+        Check { name: name.into(), status: Status::Ok, msg: msg.into(), fix: None }
+    }
+    fn warn(name: &str, msg: impl Into<String>, fix: impl Into<String>) -> Check {
+        Check { name: name.into(), status: Status::Warn, msg: msg.into(), fix: Some(fix.into()) }
+    }
+    fn fail(name: &str, msg: impl Into<String>, fix: impl Into<String>) -> Check {
+        Check { name: name.into(), status: Status::Fail, msg: msg.into(), fix: Some(fix.into()) }
+    }
+}
+
+/// Server configuration to check
+#[derive(Debug, Clone)]
+pub struct DoctorConfig {
+    pub data_dir: PathBuf,
+    pub url_base: Option<String>,
+    pub target_bitrate: u32,
+    pub quotas: Quotas,
+}
+
+
+/// Parse (major, minor) version from `ffmpeg -version` output.
+/// First line looks like "ffmpeg version 6.0-6ubuntu1 Copyright (c) ..." or "ffmpeg version n5.1.2 ...".
+fn parse_ffmpeg_version(output: &str) -> Option<(u32, u32)>
+{
+    let ver = output.lines().next()?.split_whitespace()
+        .skip_while(|w| *w != "version").nth(1)?;
+    parse_major_minor(ver.trim_start_matches('n'))
+}
+
+/// Parse (major, minor) version from `mediainfo --Version` output.
+/// Looks like "MediaInfo Command line,\nMediaInfoLib - v21.09".
+fn parse_mediainfo_version(output: &str) -> Option<(u32, u32)>
+{
+    let ver = output.lines().find(|l| l.contains("MediaInfoLib"))?
+        .split_whitespace().last()?;
+    parse_major_minor(ver.trim_start_matches('v'))
+}
+
+fn parse_major_minor(ver: &str) -> Option<(u32, u32)>
+{
+    let mut parts = ver.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+    Some((major, minor))
+}
+
+fn check_tool(name: &str, args: &[&str], parse: fn(&str) -> Option<(u32, u32)>, min: (u32, u32)) -> Check
+{
+    let install_fix = format!("Install {name} (e.g. `apt install {name}`) and make sure it's in PATH.");
+    match Command::new(name).args(args).output() {
+        Err(e) => Check::fail(name, format!("Could not run {name}: {e}"), install_fix),
+        Ok(out) => {
+            let text = String::from_utf8_lossy(&out.stdout).to_string() + &String::from_utf8_lossy(&out.stderr);
+            match parse(&text) {
+                None => Check::warn(name, format!("Found {name}, but couldn't parse its version."),
+                    format!("Make sure {name} is at least version {}.{}.", min.0, min.1)),
+                Some(v) if v < min => Check::fail(name, format!("{name} version {}.{} is too old.", v.0, v.1),
+                    format!("Upgrade {name} to at least version {}.{}.", min.0, min.1)),
+                Some(v) => Check::ok(name, format!("{name} version {}.{}", v.0, v.1)),
+            }
+        }
+    }
+}
+
+/// Check that a directory exists and is writable. Doesn't create anything except a temporary probe file.
+fn check_dir_writable(dir: &Path) -> Check
+{
+    let name = format!("dir {}", dir.display());
+    if !dir.is_dir() {
+        return Check::warn(&name, "Doesn't exist.",
+            format!("Create '{}' and give the server user write access to it.", dir.display()));
+    }
+    let probe = dir.join(format!(".clapshot-doctor-{}", uuid::Uuid::new_v4()));
+    match std::fs::write(&probe, "test") {
+        Ok(()) => {
+            std::fs::remove_file(&probe).ok();
+            Check::ok(&name, "Writable")
+        },
+        Err(e) => Check::fail(&name, format!("Not writable: {e}"),
+            format!("Run e.g. `chown -R <server user> '{}'`.", dir.display())),
+    }
+}
+
+fn check_db(data_dir: &Path) -> Check
+{
+    let db_file = data_dir.join("clapshot.sqlite");
+    if !db_file.exists() {
+        return Check::warn("database", "Database file doesn't exist yet.", "It will be created on first start.");
+    }
+    match DB::connect_db_file(&db_file).and_then(|db| db.migrations_needed()) {
+        Err(e) => Check::fail("database", format!("Can't open database: {e}"),
+            format!("Check that '{}' is a valid SQLite file and readable.", db_file.display())),
+        Ok(true) => Check::fail("database", "Database schema is out of date.",
+            "Make a backup of the database and run `clapshot-server --migrate`."),
+        Ok(false) => Check::ok("database", "Schema is up to date."),
+    }
+}
+
+/// Get free disk space (bytes) on the filesystem containing `dir`, using `df`
+pub(crate) fn free_disk_bytes(dir: &Path) -> Result<u64, String>
+{
+    let out = Command::new("df").arg("-Pk").arg(dir).output().map_err(|e| e.to_string())?;
+    let text = String::from_utf8_lossy(&out.stdout);
+    // Filesystem 1024-blocks Used Available Capacity Mounted-on
+    let avail_kb = text.lines().nth(1).and_then(|l| l.split_whitespace().nth(3))
+        .ok_or("Unexpected df output")?;
+    avail_kb.parse::<u64>().map(|kb| kb * 1024).map_err(|e| e.to_string())
+}
+
+fn check_disk(data_dir: &Path) -> Check
+{
+    match free_disk_bytes(data_dir) {
+        Err(e) => Check::warn("disk space", format!("Couldn't check free space: {e}"), "Check free space manually."),
+        Ok(free) if free < MIN_FREE_DISK_BYTES => Check::warn("disk space",
+            format!("Only {:.1} GB free in data dir.", free as f64 / 1e9),
+            "Free up space or move data dir to a bigger disk. Transcoding needs room for temporary files."),
+        Ok(free) => Check::ok("disk space", format!("{:.1} GB free", free as f64 / 1e9)),
+    }
+}
+
+/// Check configuration values for mistakes and contradictions
+fn check_config(cfg: &DoctorConfig) -> Vec<Check>
+{
+    let mut res = vec![];
+    match &cfg.url_base {
+        None => {},
+        Some(u) if !(u.starts_with("http://") || u.starts_with("https://")) => res.push(Check::fail("url-base",
+            format!("'{u}' is not an HTTP(S) URL."), "Use a full URL, e.g. --url-base=https://example.com/clapshot/")),
+        Some(u) => res.push(Check::ok("url-base", u.clone())),
+    }
+    if cfg.target_bitrate < 500_000 {
+        res.push(Check::warn("bitrate", format!("Target bitrate {} bps is very low.", cfg.target_bitrate),
+            "Bitrate is given in Mbps, e.g. --bitrate=2.5"));
+    }
+    let q = &cfg.quotas;
+    if let (Some(file), Some(total)) = (q.max_file_size, q.max_total_bytes) {
+        if file > total {
+            res.push(Check::warn("quotas", "Max file size is larger than total storage quota.",
+                "Lower --max-file-size or raise --quota-total."));
+        }
+    }
+    if res.iter().all(|c| c.status == Status::Ok) {
+        res.push(Check::ok("config", "No inconsistencies found."));
+    }
+    res
+}
+
+/// Run all diagnostic checks.
+pub fn run_checks(cfg: &DoctorConfig) -> Vec<Check>
+{
+    let mut res = vec![
+        check_tool("ffmpeg", &["-version"], parse_ffmpeg_version, MIN_FFMPEG_VERSION),
+        check_tool("mediainfo", &["--Version"], parse_mediainfo_version, MIN_MEDIAINFO_VERSION),
+    ];
+    res.push(check_dir_writable(&cfg.data_dir));
+    res.extend(["incoming", "videos", "upload", "rejected"].iter()
+        .map(|d| check_dir_writable(&cfg.data_dir.join(d))));
+    res.push(check_db(&cfg.data_dir));
+    res.push(check_disk(&cfg.data_dir));
+    res.extend(check_config(cfg));
+    res
+}
+
+/// Print check results in human readable form.
+/// Returns true if there were no failures.
+pub fn print_report(checks: &[Check]) -> bool
+{
+    for c in checks {
+        let tag = match c.status { Status::Ok => "[ OK ]", Status::Warn => "[WARN]", Status::Fail => "[FAIL]" };
+        println!("{tag} {}: {}", c.name, c.msg);
+        if let Some(fix) = &c.fix {
+            println!("       -> {fix}");
+        }
+    }
+    let n_fail = checks.iter().filter(|c| c.status == Status::Fail).count();
+    let n_warn = checks.iter().filter(|c| c.status == Status::Warn).count();
+    println!("\n{} checks, {} failed, {} warnings.", checks.len(), n_fail, n_warn);
+    n_fail == 0
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_parse_tool_versions()
+{
+    assert_eq!(parse_ffmpeg_version("ffmpeg version 6.0-6ubuntu1 Copyright (c) 2000-2023"), Some((6, 0)));
+    assert_eq!(parse_ffmpeg_version("ffmpeg version n5.1.2 Copyright"), Some((5, 1)));
+    assert_eq!(parse_ffmpeg_version("ffmpeg version 4.4\nbuilt with gcc"), Some((4, 4)));
+    assert_eq!(parse_ffmpeg_version("ffmpeg version N-109447-g1a5d3b4 Copyright"), None);
+    assert_eq!(parse_mediainfo_version("MediaInfo Command line,\nMediaInfoLib - v21.09\n"), Some((21, 9)));
+    assert_eq!(parse_mediainfo_version("garbage"), None);
+}
+
+#[test]
+fn test_check_config()
+{
+    let cfg = DoctorConfig {
+        data_dir: PathBuf::from("/nonexistent"),
+        url_base: Some("example.com".into()),
+        target_bitrate: 2_500_000,
+        quotas: Quotas { max_total_bytes: Some(100), max_file_size: Some(200), max_concurrent_jobs: None },
+    };
+    let res = check_config(&cfg);
+    assert!(res.iter().any(|c| c.name == "url-base" && c.status == Status::Fail));
+    assert!(res.iter().any(|c| c.name == "quotas" && c.status == Status::Warn));
+
+    let cfg = DoctorConfig { url_base: Some("https://example.com".into()), quotas: Quotas::default(), ..cfg };
+    assert!(check_config(&cfg).iter().all(|c| c.status == Status::Ok));
+}
+
+#[test]
+fn test_check_dirs_and_db()
+{
+    let data_dir = assert_fs::TempDir::new().unwrap();
+    assert_eq!(check_dir_writable(&data_dir.join("videos")).status, Status::Warn);
+    std::fs::create_dir(data_dir.join("videos")).unwrap();
+    assert_eq!(check_dir_writable(&data_dir.join("videos")).status, Status::Ok);
+    assert_eq!(std::fs::read_dir(data_dir.join("videos")).unwrap().count(), 0);
+    assert_eq!(check_db(data_dir.path()).status, Status::Warn);
+}
@@ -1,22 +1,21 @@
 pub mod video_pipeline;
 pub mod api_server;
 pub mod database;
+pub mod quota;
+pub mod config;
+pub mod doctor;
+pub mod upload_batch;
+pub mod telemetry;
+pub mod storage;
+pub mod backup;
+pub mod bulk_import;
 pub mod tests;
 
-pub fn run_clapshot(
-    data_dir: std::path::PathBuf,
-    migrate: bool,
-    url_base: String,
-    port: u16,
-    n_workers: usize,
-    target_bitrate: u32,
-    poll_interval: f32,
-    resubmit_delay: f32)
-        -> anyhow::Result<()>
+pub fn run_clapshot(mut opts: config::StartupOptions, config: std::sync::Arc<config::LiveConfig>) -> anyhow::Result<()>
 {
     use std::thread;    
     use std::sync::atomic::{AtomicBool, Ordering};
-    use signal_hook::consts::TERM_SIGNALS;
+    use signal_hook::consts::{TERM_SIGNALS, SIGHUP};
     use signal_hook::flag;
     use std::sync::Arc;
     use anyhow::bail;
@@ -30,13 +29,16 @@ pub fn run_clapshot(
         flag::register_conditional_shutdown(*sig, 1, Arc::clone(&terminate_flag))?;
         flag::register(*sig, Arc::clone(&terminate_flag))?;
     }
+    // SIGHUP reloads config
+    let reload_flag = Arc::new(AtomicBool::new(false));
+    flag::register(SIGHUP, Arc::clone(&reload_flag))?;
 
     // Create directories
     for d in &["videos", "incoming", "videos"] {
-        std::fs::create_dir_all(&data_dir.join(d))?;
+        std::fs::create_dir_all(opts.data_dir.join(d))?;
     }
 
-    let db_file = data_dir.join("clapshot.sqlite");
+    let db_file = opts.data_dir.join("clapshot.sqlite");
     let was_missing = !db_file.exists();
     if was_missing {
         eprintln!("Database file not found, running migrations to create it.");
@@ -44,7 +46,7 @@ pub fn run_clapshot(
     let db = Arc::new(database::DB::connect_db_file(&db_file).unwrap());
 
     // Check & apply database migrations
-    if  (migrate || was_missing) && db.migrations_needed()? {
+    if  (opts.migrate || was_missing) && db.migrations_needed()? {
         match db.run_migrations() {
             Ok(_) => {
                 assert!(!db.migrations_needed()?);
@@ -63,31 +65,28 @@ pub fn run_clapshot(
         }
     }
 
+    // Check once which sandbox actually works here, for both API server and pipeline
+    opts.sandbox = opts.sandbox.probe();
+
     // Run API server
     let tf = Arc::clone(&terminate_flag);
     let (user_msg_tx, user_msg_rx) = unbounded::<api_server::UserMessage>();
-    let (upload_tx, upload_rx) = unbounded::<video_pipeline::IncomingFile>();
-    let api_thread = { 
+    let queues = Arc::new(video_pipeline::queues::QueueDepths::new(opts.queue_capacity));
+    let storage = Arc::new(storage::StorageStatus::new(&opts.data_dir));
+    let (pipeline_link, pipeline_intake) = video_pipeline::PipelineLink::new(queues, storage);
+    let api_thread = {
         let db = db.clone();
-        let data_dir = data_dir.clone();
-        thread::spawn(move || {
-            api_server::run_forever(
-                    db,
-                    data_dir.join("videos"),
-                    data_dir.join("upload"),
-                    user_msg_rx, 
-                    upload_tx, 
-                    tf.clone(), 
-                    url_base.to_string(),
-                    port) 
-            })};
+        let opts = opts.clone();
+        let config = config.clone();
+        thread::spawn(move || { api_server::run_forever(db, opts, pipeline_link, user_msg_rx, config, tf) })
+    };
 
     // Run video processing pipeline
     let tf = Arc::clone(&terminate_flag);
     let vpp_thread = {
             let db = db.clone();
-            thread::spawn(move || { video_pipeline::run_forever(
-                db, tf.clone(), data_dir, user_msg_tx, poll_interval, resubmit_delay, target_bitrate, upload_rx, n_workers)})
+            let config = config.clone();
+            thread::spawn(move || { video_pipeline::run_forever(db, tf, opts, pipeline_intake, user_msg_tx, config) })
         };
 
     // Loop forever, abort on SIGINT/SIGTERM or if child threads die
@@ -96,9 +95,15 @@ pub fn run_clapshot(
         if vpp_thread.is_finished() {
             terminate_flag.store(true, Ordering::Relaxed);
         }
+        if reload_flag.swap(false, Ordering::Relaxed) {
+            match config.reload() {
+                Ok(changed) => tracing::info!(changed=?changed, "Got SIGHUP. Config reloaded."),
+                Err(e) => tracing::error!(details=%e, "Got SIGHUP, but config reload failed. Keeping old settings."),
+            }
+        }
     }
 
-    tracing::warn!("Got kill signal. Cleaning up.");
+    tracing::warn!("Got kill signal. Finishing jobs in progress and cleaning up.");
     vpp_thread.join().unwrap();
     api_thread.join().unwrap();
     Ok(())
@@ -2,12 +2,21 @@ use tracing::subscriber::set_global_default;
 use tracing_appender::{non_blocking};
 use tracing_subscriber::{fmt, EnvFilter};
 use tracing_subscriber::fmt::time::OffsetTime;
+use tracing_subscriber::layer::SubscriberExt;
+use clapshot_server::config::LogLevelSetter;
+use clapshot_server::telemetry::{otlp_layer, OtlpGuard};
 
+fn level_filter(debug: bool) -> &'static str {
+    if debug {"debug,clapshot_server=debug"} else {"info,clapshot_server=info"}
+}
 
-pub fn setup_logging(time_offset: time::UtcOffset, debug: bool, log_file: &str, json_log: bool)
-     -> anyhow::Result<non_blocking::WorkerGuard>
+/// Setup logging, and trace export if `otlp_endpoint` is given (see `telemetry`).
+/// Returns guards that flush logs and traces when dropped, and a function
+/// to switch between debug and normal log level at runtime (unless set by RUST_LOG).
+pub fn setup_logging(time_offset: time::UtcOffset, debug: bool, log_file: &str, json_log: bool, otlp_endpoint: Option<&str>)
+     -> anyhow::Result<(non_blocking::WorkerGuard, Option<OtlpGuard>, LogLevelSetter)>
 {
-    let log_to_stdout = log_file == "" || log_file == "-";
+    let log_to_stdout = log_file.is_empty() || log_file == "-";
     let (log_writer, guard) = if log_to_stdout {
             non_blocking(std::io::stdout())
         } else {
@@ -15,8 +24,9 @@ pub fn setup_logging(time_offset: time::UtcOffset, debug: bool, log_file: &str,
             non_blocking(f)
         };
 
-    if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var("RUST_LOG", if debug {"debug,clapshot_server=debug"} else {"info,clapshot_server=info"});
+    let level_from_env = std::env::var_os("RUST_LOG").is_some();
+    if !level_from_env {
+        std::env::set_var("RUST_LOG", level_filter(debug));
     };
 
     let minute_offset = time_offset.whole_minutes() % 60;
@@ -32,6 +42,14 @@ pub fn setup_logging(time_offset: time::UtcOffset, debug: bool, log_file: &str,
 
     let timer = OffsetTime::new(time_offset, time_format);
 
+    let (otlp, otlp_guard) = match otlp_endpoint {
+        Some(endpoint) => {
+            let (layer, guard) = otlp_layer(endpoint).map_err(|e| anyhow::anyhow!("Invalid --otlp-endpoint: {e}"))?;
+            (Some(layer), Some(guard))
+        },
+        None => (None, None),
+    };
+
     let log_sbsc = fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .with_timer(timer)
@@ -42,11 +60,26 @@ pub fn setup_logging(time_offset: time::UtcOffset, debug: bool, log_file: &str,
         .with_writer(log_writer)
         .with_ansi(log_to_stdout);
 
-    if json_log {
-        set_global_default(log_sbsc.json().finish())
+    // Handles of the two formats are of different types, so wrap them in a closure
+    macro_rules! install_reloadable {
+        ($builder:expr) => {{
+            let builder = $builder.with_filter_reloading();
+            let handle = builder.reload_handle();
+            set_global_default(builder.finish().with(otlp)).expect("tracing::subscriber::set_global_default failed");
+            Box::new(move |debug: bool| {
+                if level_from_env {
+                    tracing::warn!("Log level is set by RUST_LOG. Not changing it.");
+                    return Ok(());
+                }
+                handle.reload(EnvFilter::new(level_filter(debug))).map_err(anyhow::Error::from)
+            }) as LogLevelSetter
+        }};
+    }
+    let set_log_level = if json_log {
+        install_reloadable!(log_sbsc.json())
     } else {
-        set_global_default(log_sbsc.finish())
-    }.expect("tracing::subscriber::set_global_default failed");
+        install_reloadable!(log_sbsc)
+    };
 
-    Ok(guard)
+    Ok((guard, otlp_guard, set_log_level))
 }
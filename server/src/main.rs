@@ -3,7 +3,7 @@ use std::path::PathBuf;
 use anyhow::bail;
 mod log;
 
-const USAGE: &'static str = r#"
+const USAGE: &str = r#"
 Clapshot server - backend of a video annotation tool
 
 Monitors <path>/incoming for new videos, processes them, and stores them in <path>/videos.
@@ -13,26 +13,206 @@ Use a proxy server to serve files in /videos and to secure the API with HTTPS/WS
 Usage:
   clapshot-server [options] (--url-base=URL) (--data-dir=PATH)
   clapshot-server [options] [--mute TOPIC]... (--url-base=URL) (--data-dir=PATH)
+  clapshot-server [options] --config=FILE
+  clapshot-server doctor [options] [--url-base=URL] (--data-dir=PATH)
+  clapshot-server backup [options] [--url-base=URL] (--data-dir=PATH) <dir>
+  clapshot-server restore [options] [--force] [--url-base=URL] (--data-dir=PATH) <dir>
+  clapshot-server import [options] [--owner=USER] [--owner-map=FILE] [--owner-from-dir] [--dry-run] [--url-base=URL] (--data-dir=PATH) <dir>
   clapshot-server (-h | --help)
 
+Commands:
+ doctor               Check external tools, directories, database, free disk
+                      and configuration, and print suggested fixes. Exits with
+                      a non-zero status if any check fails.
+ backup <dir>         Write a consistent copy of the database and a manifest of
+                      the media files it refers to into <dir>. Safe to run while
+                      the server is running. Media files (videos/) are not copied:
+                      back them up separately.
+ restore <dir>        Restore the database from a backup made with "backup", and
+                      list media files that are missing from data dir. Stop the
+                      server first. With --force, an existing database is replaced
+                      (it's renamed, not deleted).
+ import <dir>         Upload all media files under <dir> (e.g. a shared drive) to
+                      the server running on this host (at --host and --port), to
+                      be processed like any upload. Files are owned by the user
+                      mapped to their path in --owner-map FILE ("<path> = <user>"
+                      lines, paths relative to <dir>), or with --owner-from-dir,
+                      by the user named like the first dir under <dir>, or else
+                      by --owner USER. If interrupted, run again to resume: files
+                      already uploaded are skipped. With --dry-run, just lists
+                      files and their owners.
+
 Required:
  --url-base=URL       Base URL of the API server, e.g. https://example.com/clapshot/.
                       This depends on your proxy server configuration.
  --data-dir=PATH      Directory for database, /incoming, /videos and /rejected
 
 Options:
+ --config=FILE          Read options from FILE, an INI file with a [general] section of
+                        "option = value" lines (e.g. "workers = 4", "debug = true"),
+                        like /etc/clapshot-server.conf. Command line options override
+                        it. On SIGHUP (or admin's "admin_reload_config"), the file is
+                        re-read and changes to debug, workers, quotas, webhook,
+                        chat-notify, organizer (also organizer-fail-open), tracker-sync, features, ws-rate-limits,
+                        max-user-connections, ws-idle-timeout, ws-resume-window,
+                        transcode presets and burn-in settings are applied without
+                        restart.
+                        Other changes need a restart.
  -p PORT --port=PORT    Port to listen on [default: 8095]
  -H HOST --host=HOST    Host to listen on [default: 0.0.0.0]
- --host-videos          Host the /videos directory
-                        (For debugging. Use Nginx or Apache with auth in production.)
+ --host-videos          Serve the /videos directory from this server, with range
+                        requests, caching headers and access checks per request
+                        (user from X-Remote-User-Id, as for the API). Without this,
+                        configure Nginx or Apache to serve it.
+ --url-signing-key FILE  Sign URLs of video files (playback, thumbnails, subtitles)
+                        with the secret in FILE, so a CDN or web server that can't
+                        check users can serve them: it verifies "sig" =
+                        hex(HMAC-SHA256(secret, "<expires>:<path>")) and that
+                        "expires" (Unix time) hasn't passed. With --host-videos,
+                        this server checks them, and rejects unsigned anonymous requests.
+ --signed-url-ttl SEC   How long signed URLs stay valid, in seconds [default: 3600]
+ --webhook URL          POST events (e.g. review verdict changes) to URL as JSON:
+                        {"event": NAME, "time": UNIX_TIME, "data": {...}}
+ --chat-notify FILE     Post ready videos and new comments to Slack or Mattermost
+                        incoming webhooks, as configured in FILE (JSON):
+                        {"routes": [{"url": URL, "format": "slack"|"mattermost",
+                        "channel": NAME, "folders": [ID, ...], "events":
+                        ["video_ready", "comment"]}, ...]}. Routes with "folders"
+                        get videos in those folders (and subfolders), others the rest.
+ --organizer URL        Consult an external organizer service (gRPC over plaintext
+                        HTTP/2, e.g. http://127.0.0.1:50051) on new videos, video
+                        listings and access checks, for production tracking
+                        integrations. Interface: organizer.proto (in docs).
+ --organizer-fail-open  Allow viewing and commenting when the organizer's access
+                        check fails or times out. By default it's denied.
+ --tracker-sync FILE    Push new comments as notes to ShotGrid or ftrack, on the entity
+                        whose ID is in a video's custom field, as configured in FILE
+                        (JSON): {"kind": "shotgrid"|"ftrack", "url": URL, "login":
+                        SCRIPT_NAME_OR_API_USER, "api_key": KEY, "entity_field": FIELD,
+                        "entity_type": TYPE, "pull_replies": BOOL, "include_internal":
+                        BOOL}. Failed pushes are retried with backoff.
+ --features LIST        Roll out features gradually: comma separated "name=on|off|N%",
+                        e.g. "collab=on, transcripts=25%". Percentage picks users
+                        by a stable hash. Unlisted features are on. Admins can
+                        override these per user, team or for everyone.
+                        Known features: collab, transcripts, video_diff, stitch,
+                        url_ingest.
+ --ws-rate-limits LIST  Limit Websocket commands per connection: comma separated
+                        "cmd=RATE:BURST" (commands per second, max burst), "*" for
+                        all unlisted commands, or "off". Commands over the limit are
+                        dropped, and clients that keep flooding are disconnected.
+                        [default: add_comment=1:10, collab_report=10:50, *=20:100]
+ --max-user-connections N  Max open Websocket connections (e.g. browser tabs) per
+                        user. More are refused. (0 = unlimited) [default: 20]
+ --ws-idle-timeout SEC  Ping Websocket sessions the client hasn't sent anything to
+                        in SEC seconds, and close them if there's no answer in 30 s
+                        (0 = never) [default: 300]
+ --ws-resume-window SEC  Let clients that lose their connection resume the session
+                        within SEC seconds, replaying the events they missed
+                        (0 = disabled) [default: 60]
+ --onboarding FILE      Welcome content for new users' first login, as JSON:
+                        {"message": TEXT, "sample_videos": [VIDEO_HASH, ...],
+                         "links": [{"title": TEXT, "url": URL}, ...]}
+ --trash-retention DAYS  Days to keep deleted videos in trash before purging them
+                        for good (0 = keep forever) [default: 30]
+ --audit-retention DAYS  Days to keep audit log entries (0 = keep forever). Entries
+                        about videos under legal hold are always kept. [default: 0]
+ --message-retention DAYS  Days to keep user messages (notifications, processing
+                        progress and errors) (0 = keep forever) [default: 365]
+ --job-retention DAYS   Days to keep finished processing jobs. Older ones are
+                        summed into daily job statistics, then deleted
+                        (0 = keep forever) [default: 90]
+ --stale-upload-hours HOURS  Hours to keep uploads that aren't being processed
+                        (abandoned or interrupted) before deleting them
+                        (0 = keep forever) [default: 48]
+ --rejected-retention DAYS  Days to keep originals of videos that failed to
+                        ingest (in "rejected" dir) (0 = keep forever) [default: 30]
+ --reconcile MODE       Daily check of stored files against the database: "report"
+                        tells admins about orphaned files, dangling database rows
+                        and videos with missing files, "remove" also removes the
+                        orphaned files and rows, "off" skips it. [default: report]
  -P SEC --poll SEC      Polling interval for incoming folder [default: 3.0]
  -m TOPIC --mute TOPIC    Mute logging for a topic (can be repeated). Sets level to WARNING.
                         See logs logs for available topics.
  -l FILE --log FILE     Log to file instead of stdout
- -j --json              Log in JSON format
+ -j --json              Log in JSON format. Lines include their spans, with the
+                        trace_id of the request or video being processed.
+ --otlp-endpoint URL    Export traces of requests and video processing (from upload
+                        to ready) to an OpenTelemetry collector, e.g. Jaeger or
+                        Grafana Tempo, as OTLP/HTTP JSON to URL/v1/traces
+                        (e.g. http://localhost:4318).
  -w N --workers N       Max number of workers for video processing [default: 0]
                         (0 = number of CPU cores)
  -b VBR --bitrate VBR   Target (max) bitrate for transcoding, in Mbps [default: 2.5]
+ --transcode-presets FILE  Named transcoding recipes, as JSON: {NAME: {"video_codec":
+                        ENCODER, "speed": PRESET, "crf": N, "max_bitrate": BPS,
+                        "two_pass": BOOL, "max_size": PIXELS,
+                        "audio_codec": ENCODER, "audio_bitrate": BPS,
+                        "audio_channels": N, "extra_args": [ARG, ...]}, ...}.
+                        Fields left out are as in the built-in "default" preset
+                        (libx264 "faster" at --bitrate, max 1920 px, 128 kbps AAC stereo).
+                        Without "crf", video is encoded at --bitrate, in two passes
+                        if "two_pass" is set. "max_bitrate" caps peaks.
+ --transcode-preset NAME  Preset to transcode with. Folder owners can pick another
+                        one for videos uploaded into a folder. [default: default]
+ --burn-in LIST         Overlays to burn into transcoded videos, unless the upload
+                        picks others: comma separated list of "timecode" (running
+                        SMPTE), "filename", "watermark" and "username", or "none".
+                        [default: none]
+ --burn-in-required LIST  Overlays burned in regardless of what the upload picks,
+                        e.g. "watermark,username" for leak tracing. [default: none]
+ --watermark FILE       Watermark image (PNG) for the "watermark" overlay.
+ --trim-silence         Detect leading/trailing silence in audio and offer
+                        non-destructive trim points to the player.
+                        Also measures loudness (EBU R128).
+ --loudness-target LUFS  Normalize audio of transcoded videos to this integrated
+                        loudness (EBU R128, e.g. -23), "on" for -23, or "off".
+                        Uploads can override this. [default: off]
+ --cfr-fps FPS          Frame rate to convert variable frame rate (VFR) videos to,
+                        e.g. screen recordings. "auto" uses the average frame
+                        rate, rounded. [default: auto]
+ --sequence-fps FPS     Frame rate for image sequences (e.g. shot_####.exr),
+                        unless given on upload. [default: 24]
+ --analyzer CMD         ML analysis command for tagging faces, objects and text on
+                        screen (e.g. an ONNX runtime script, or a client for an
+                        analysis service). Called as "CMD <video file>" for each
+                        new video, must print labels as JSON. Runs outside the sandbox.
+ --detect-scenes        Detect scene changes in new videos, and store their shots
+                        (with thumbnails) for next/previous shot navigation.
+ --scanner SPEC         Scan new files for malware or against content policies before
+                        processing them: "clamd:SOCKET" (ClamAV daemon's Unix socket;
+                        raise its StreamMaxLength to fit your videos) or a command,
+                        called as "CMD <file>", that exits with 0 if the file is
+                        clean, 1 if it's rejected (reason on stdout) and anything
+                        else if the scan failed. Rejected files are moved to
+                        <data-dir>/quarantine, and ones that failed the scan to
+                        <data-dir>/rejected. Runs outside the sandbox.
+ --poster-scoring SPEC  How to pick the poster thumbnail: comma separated key=value
+                        of candidates (frames to compare, spread over the video),
+                        min_luma / max_luma (0-255, darker or brighter frames are
+                        skipped), min_contrast (flatter frames are skipped) and
+                        contrast_weight (vs. sharpness), or "off" to use one of the
+                        first frames. [default: candidates=8, min_luma=20, max_luma=235, min_contrast=10, contrast_weight=0.5]
+ --dedup-window HOURS   How long an upload identical to a video shared with the
+                        user (via a team) waits for them to choose between linking
+                        the existing video and storing a copy. After that, it's
+                        stored as a copy. [default: 24]
+ --auto-link-duplicates  Link such uploads to the existing video without asking.
+ --quota-total GB       Max total storage per user, in GB (0 = unlimited) [default: 0]
+ --max-file-size MB     Max size of a single video file, in MB (0 = unlimited) [default: 0]
+ --max-user-jobs N      Max concurrent processing jobs per user (0 = unlimited) [default: 0]
+ --sandbox MODE         Sandbox for ffmpeg/mediainfo, which parse untrusted uploads:
+                        off, rlimit (memory/CPU limits), bwrap or firejail
+                        (limits + read-only filesystem except output dir, no network).
+                        Falls back to a weaker mode if unavailable. [default: off]
+ --sandbox-mem MB       Max memory per tool process in sandbox, in MB (0 = unlimited) [default: 4096]
+ --sandbox-cpu SEC      Max CPU time per tool process in sandbox, in seconds (0 = unlimited) [default: 0]
+ --shutdown-grace SEC   On SIGTERM/SIGINT, how long to wait for video processing in
+                        progress to finish. Unfinished jobs are resumed on next
+                        start. A second signal exits immediately. [default: 60]
+ --queue-capacity N     Max items waiting in each video pipeline queue. When the
+                        pipeline falls behind and the queues fill up, new uploads
+                        are refused (HTTP 429) until there's room. [default: 100]
  --migrate              Migrate database to latest version. Make a backup first.
 
  -d --debug             Enable debug logging
@@ -41,25 +221,23 @@ Options:
 
 fn main() -> anyhow::Result<()>
 {
-    let argv = std::env::args;
-    //let argv = || vec!["clapshot-server", "--bitrate", "8", "--migrate", "--debug", "--url-base", "http://127.0.0.1:8095", "--data-dir", "DEV_DATADIR/"];
+    let argv = std::env::args().collect::<Vec<_>>();
+    //let argv = vec!["clapshot-server", "--bitrate", "8", "--migrate", "--debug", "--url-base", "http://127.0.0.1:8095", "--data-dir", "DEV_DATADIR/"].into_iter().map(String::from).collect::<Vec<_>>();
 
-    let args = Docopt::new(USAGE)
-        .and_then(|d| d.argv(argv().into_iter()).parse())
-        .unwrap_or_else(|e| e.exit());
+    let args = parse_args(&argv).unwrap_or_else(|e| e.exit());
+    if args.get_str("--data-dir").is_empty() || (args.get_str("--url-base").is_empty() && !["doctor", "backup", "restore", "import"].iter().any(|c| args.get_bool(c))) {
+        bail!("--url-base and --data-dir are required (on command line or in --config file)");
+    }
 
     let port_str = args.get_str("--port");
     let port = port_str.parse::<u16>().unwrap();
+    let host_videos = args.get_bool("--host-videos");
 
-    let debug: bool = args.get_bool("--debug");
     let data_dir = PathBuf::from(args.get_str("--data-dir"));
 
     let log_file = args.get_str("--log").to_string();
     let json_log = args.get_bool("--json");
 
-    let mut n_workers = args.get_str("--workers").parse::<usize>().unwrap_or(0);
-    if n_workers == 0 { n_workers = num_cpus::get(); }
-
     let bitrate_mbps = args.get_str("--bitrate").parse::<f32>().unwrap_or(2.5);
     if bitrate_mbps < 0.1 { bail!("Bitrate must be >= 0.1"); }
     let target_bitrate = (bitrate_mbps * 1_000_000.0) as u32;
@@ -68,17 +246,282 @@ fn main() -> anyhow::Result<()>
         .strip_suffix("/").unwrap_or("").to_string(); // strip trailing slash, if any
 
     let migrate = args.get_bool("--migrate");
+    let trim_silence = args.get_bool("--trim-silence");
+    let loudness_target = args.get_str("--loudness-target")
+        .parse::<clapshot_server::video_pipeline::LoudnormOpt>().map_err(|e| anyhow::anyhow!(e))?
+        .resolve(None);
+    let cfr_fps = match args.get_str("--cfr-fps") {
+        "auto" => None,
+        s => match s.parse::<f32>() {
+            Ok(fps) if fps > 0.0 && fps <= 240.0 => Some(fps),
+            _ => bail!("Invalid value for --cfr-fps: '{}'", s),
+        }
+    };
+
+    let sequence_fps = clapshot_server::video_pipeline::conform::parse_fps(args.get_str("--sequence-fps"))
+        .map_err(|e| anyhow::anyhow!("--sequence-fps: {e}"))?;
+
+    let analyzer = Some(args.get_str("--analyzer").trim().to_string()).filter(|s| !s.is_empty());
+    let detect_scenes = args.get_bool("--detect-scenes");
+    let scanner = match args.get_str("--scanner").trim() {
+        "" => None,
+        s => Some(s.parse::<clapshot_server::video_pipeline::scan::Scanner>().map_err(|e| anyhow::anyhow!("--scanner: {e}"))?),
+    };
+
+    let auto_link_duplicates = args.get_bool("--auto-link-duplicates");
+    let dedup_window_hours = args.get_str("--dedup-window").parse::<u32>()
+        .map_err(|_| anyhow::anyhow!("Invalid value for --dedup-window"))?;
+
+    let reloadable = parse_reloadable(&args)?;
+
+    if args.get_bool("doctor") {
+        let cfg = clapshot_server::doctor::DoctorConfig {
+            data_dir,
+            url_base: Some(args.get_str("--url-base").to_string()).filter(|s| !s.is_empty()),
+            target_bitrate,
+            quotas: reloadable.quotas,
+        };
+        let all_ok = clapshot_server::doctor::print_report(&clapshot_server::doctor::run_checks(&cfg));
+        std::process::exit(if all_ok { 0 } else { 1 });
+    }
+
+    if args.get_bool("backup") {
+        let db_file = data_dir.join(clapshot_server::backup::DB_FILE);
+        if !db_file.exists() { bail!("No database in '{}'", data_dir.display()); }
+        let db = clapshot_server::database::DB::connect_db_file(&db_file)?;
+        let dst = PathBuf::from(args.get_str("<dir>"));
+        let manifest = clapshot_server::backup::create_backup(&db, &data_dir, &dst)?;
+        println!("Backup written to '{}': {}.", dst.display(), manifest.summary());
+        println!("Media files are listed in the manifest but not copied. Back up '{}' separately.", data_dir.join("videos").display());
+        return Ok(());
+    }
+
+    if args.get_bool("restore") {
+        let src = PathBuf::from(args.get_str("<dir>"));
+        let (manifest, check) = clapshot_server::backup::restore_backup(&src, &data_dir, args.get_bool("--force"))?;
+        println!("Database restored from backup of {} ({}).", manifest.created.format("%Y-%m-%d %H:%M:%S UTC"), manifest.summary());
+        if check.is_ok() {
+            println!("All media files are in place.");
+            return Ok(());
+        }
+        for f in &check.missing { println!("Missing: {f}"); }
+        for f in &check.wrong_size { println!("Size differs: {f}"); }
+        println!("\n{} media files missing and {} differing. Restore them into '{}'.", check.missing.len(), check.wrong_size.len(), data_dir.display());
+        std::process::exit(1);
+    }
+
+    if args.get_bool("import") {
+        use clapshot_server::bulk_import::*;
+        let root = PathBuf::from(args.get_str("<dir>"));
+        let owners = OwnerMapping {
+            prefixes: match args.get_str("--owner-map") {
+                "" => vec![],
+                f => OwnerMapping::parse_map(&std::fs::read_to_string(f)?).map_err(|e| anyhow::anyhow!("Invalid --owner-map file: {e}"))?,
+            },
+            from_dir: args.get_bool("--owner-from-dir"),
+            default: Some(args.get_str("--owner").to_string()).filter(|s| !s.is_empty()),
+        };
+        if owners.prefixes.is_empty() && !owners.from_dir && owners.default.is_none() {
+            bail!("Give file owners with --owner-map, --owner-from-dir or --owner");
+        }
+        let host = match args.get_str("--host") { "0.0.0.0" => "127.0.0.1", h => h };
+        let server_url = format!("http://{host}:{port}");
+        let mut journal = Journal::open(&Journal::path_for(&data_dir, &root))?;
+        let summary = run_import(&root, &owners, &mut journal, args.get_bool("--dry-run"),
+            |path, user| http_upload(&server_url, path, user), BUSY_RETRY_INTERVAL)?;
+        summary.print();
+        std::process::exit(if summary.aborted.is_none() && summary.failed.is_empty() { 0 } else { 1 });
+    }
+
+    let sandbox = {
+        let parse_limit = |opt: &str| -> anyhow::Result<Option<u64>> {
+            let v = args.get_str(opt).parse::<u64>().map_err(|_| anyhow::anyhow!("Invalid value for {}", opt))?;
+            Ok(if v > 0 { Some(v) } else { None })
+        };
+        clapshot_server::video_pipeline::sandbox::Sandbox {
+            mode: args.get_str("--sandbox").parse().map_err(|e: String| anyhow::anyhow!(e))?,
+            max_mem_bytes: parse_limit("--sandbox-mem")?.map(|mb| mb * 1024 * 1024),
+            max_cpu_secs: parse_limit("--sandbox-cpu")?,
+        }
+    };
+
+    let url_signer = match args.get_str("--url-signing-key") {
+        "" => None,
+        key_file => {
+            let ttl = args.get_str("--signed-url-ttl").parse::<u64>().map_err(|_| anyhow::anyhow!("Invalid value for --signed-url-ttl"))?;
+            Some(clapshot_server::api_server::url_signing::UrlSigner::from_key_file(std::path::Path::new(key_file), ttl)
+                .map_err(|e| anyhow::anyhow!(e))?)
+        }
+    };
+
+    let onboarding = match args.get_str("--onboarding") {
+        "" => None,
+        file => Some(clapshot_server::api_server::onboarding::Onboarding::from_file(std::path::Path::new(file))
+            .map_err(|e| anyhow::anyhow!("Invalid --onboarding file: {e}"))?),
+    };
+
+    let retention = {
+        use clapshot_server::api_server::retention::Retention;
+        let parse_days = |opt: &str| Retention::parse_days(args.get_str(opt)).map_err(|e| anyhow::anyhow!("{}: {}", opt, e));
+        Retention {
+            trash_days: parse_days("--trash-retention")?,
+            audit_days: parse_days("--audit-retention")?,
+            message_days: parse_days("--message-retention")?,
+            job_days: parse_days("--job-retention")?,
+            stale_upload_hours: Retention::parse_days(args.get_str("--stale-upload-hours"))
+                .map_err(|_| anyhow::anyhow!("Invalid value for --stale-upload-hours"))?,
+            rejected_days: parse_days("--rejected-retention")?,
+            reconcile: args.get_str("--reconcile").parse()
+                .map_err(|e| anyhow::anyhow!("Invalid value for --reconcile: {e}"))?,
+        }
+    };
+
+    let shutdown_grace = args.get_str("--shutdown-grace").parse::<u64>()
+        .map(std::time::Duration::from_secs)
+        .map_err(|_| anyhow::anyhow!("Invalid value for --shutdown-grace"))?;
+
+    let poster = args.get_str("--poster-scoring").parse::<clapshot_server::video_pipeline::poster::PosterScoring>()
+        .map_err(|e| anyhow::anyhow!("Invalid value for --poster-scoring: {}", e))?;
+
+    let queue_capacity = args.get_str("--queue-capacity").parse::<usize>()
+        .ok().filter(|c| *c > 0)
+        .ok_or_else(|| anyhow::anyhow!("Invalid value for --queue-capacity"))?;
 
     let poll_interval = args.get_str("--poll").parse::<f32>().unwrap_or(3.0);
     let resubmit_delay = poll_interval * 5.0;
 
     // Setup logging
     let time_offset = time::UtcOffset::current_local_offset().expect("should get local offset");
-    let _log_guard = log::setup_logging(
+    let otlp_endpoint = Some(args.get_str("--otlp-endpoint").trim()).filter(|s| !s.is_empty());
+    let (_log_guard, _otlp_guard, set_log_level) = log::setup_logging(
         time_offset,
-        debug,
+        reloadable.debug,
         &log_file,
-        json_log);
+        json_log,
+        otlp_endpoint)?;
+
+    // Settings that can be changed at runtime, re-read from --config file (if any)
+    let config_reader: Option<clapshot_server::config::ConfigReader> = match args.get_str("--config") {
+        "" => None,
+        _ => Some(Box::new(move || {
+            let args = parse_args(&argv).map_err(|e| anyhow::anyhow!("{e}"))?;
+            parse_reloadable(&args)
+        })),
+    };
+    let config = clapshot_server::config::LiveConfig::new(reloadable, config_reader, Some(set_log_level));
+
+    let opts = clapshot_server::config::StartupOptions {
+        data_dir, migrate, url_base, url_signer, port, host_videos, retention,
+        ingest: clapshot_server::video_pipeline::IngestPolicy { target_bitrate, loudness_target, cfr_fps, auto_link_duplicates, dedup_window_hours },
+        poll_interval, resubmit_delay, trim_silence, sequence_fps, analyzer, detect_scenes, scanner,
+        sandbox, poster, onboarding, shutdown_grace, queue_capacity,
+    };
+    clapshot_server::run_clapshot(opts, config)
+}
+
+/// Parse arguments. With `--config FILE`, options are read from the file first
+/// (see `config::read_config_args`), and overridden by the ones on command line.
+fn parse_args(argv: &[String]) -> Result<docopt::ArgvMap, docopt::Error>
+{
+    let args = Docopt::new(USAGE).and_then(|d| d.argv(argv).parse())?;
+    let config_file = args.get_str("--config");
+    if config_file.is_empty() {
+        return Ok(args);
+    }
+    let file_args = clapshot_server::config::read_config_args(std::path::Path::new(config_file))
+        .map_err(|e| docopt::Error::Argv(e.to_string()))?;
+
+    let opt_name = |a: &str| a.split('=').next().unwrap_or_default().to_string();
+    let cli_opts = argv.iter().skip(1).filter(|a| a.starts_with("--")).map(|a| opt_name(a)).collect::<Vec<_>>();
+    let merged = argv.iter().take(1).cloned()
+        .chain(file_args.into_iter().filter(|a| !cli_opts.contains(&opt_name(a))))
+        .chain(argv.iter().skip(1).cloned())
+        .collect::<Vec<_>>();
+    Docopt::new(USAGE).and_then(|d| d.argv(merged).parse())
+}
+
+/// Parse settings that can be reloaded at runtime
+fn parse_reloadable(args: &docopt::ArgvMap) -> anyhow::Result<clapshot_server::config::ReloadableConfig>
+{
+    let mut n_workers = args.get_str("--workers").parse::<usize>().unwrap_or(0);
+    if n_workers == 0 { n_workers = num_cpus::get(); }
+
+    let quotas = {
+        let parse_limit = |opt: &str, unit: f64| -> anyhow::Result<Option<u64>> {
+            let v = args.get_str(opt).parse::<f64>().map_err(|_| anyhow::anyhow!("Invalid value for {}", opt))?;
+            if v < 0.0 { bail!("{} must be >= 0", opt); }
+            Ok(if v > 0.0 { Some((v * unit) as u64) } else { None })
+        };
+        clapshot_server::quota::Quotas {
+            max_total_bytes: parse_limit("--quota-total", 1024.0 * 1024.0 * 1024.0)?,
+            max_file_size: parse_limit("--max-file-size", 1024.0 * 1024.0)?,
+            max_concurrent_jobs: parse_limit("--max-user-jobs", 1.0)?.map(|n| n as u32),
+        }
+    };
+
+    let webhook = match args.get_str("--webhook") {
+        "" => None,
+        url => Some(clapshot_server::api_server::webhook::Webhook::new(url)
+            .map_err(|e| anyhow::anyhow!("Invalid value for --webhook: {e}"))?),
+    };
+
+    let chat = match args.get_str("--chat-notify") {
+        "" => Default::default(),
+        file => clapshot_server::api_server::notifier::ChatNotifier::from_file(std::path::Path::new(file))
+            .map_err(|e| anyhow::anyhow!("Invalid value for --chat-notify: {e}"))?,
+    };
+
+    let organizer = match args.get_str("--organizer") {
+        "" => None,
+        url => Some(clapshot_server::api_server::organizer::Organizer::new(url)
+            .map_err(|e| anyhow::anyhow!("Invalid value for --organizer: {e}"))?
+            .fail_open(args.get_bool("--organizer-fail-open"))),
+    };
+
+    let tracker = match args.get_str("--tracker-sync") {
+        "" => None,
+        file => Some(clapshot_server::api_server::tracker_sync::TrackerSync::from_file(std::path::Path::new(file))
+            .map_err(|e| anyhow::anyhow!("Invalid value for --tracker-sync: {e}"))?),
+    };
+
+    let features = clapshot_server::api_server::feature_flags::FeatureFlags::parse(args.get_str("--features"))
+        .map_err(|e| anyhow::anyhow!("Invalid value for --features: {e}"))?;
+
+    let rate_limits = clapshot_server::api_server::rate_limit::RateLimits::parse(args.get_str("--ws-rate-limits"))
+        .map_err(|e| anyhow::anyhow!("Invalid value for --ws-rate-limits: {e}"))?;
+
+    let sessions = {
+        let parse_num = |opt: &str| args.get_str(opt).parse::<u64>().map_err(|_| anyhow::anyhow!("Invalid value for {}", opt));
+        clapshot_server::api_server::session_limits::SessionLimits {
+            max_user_connections: Some(parse_num("--max-user-connections")? as usize).filter(|n| *n > 0),
+            idle_timeout: Some(parse_num("--ws-idle-timeout")?).filter(|s| *s > 0).map(std::time::Duration::from_secs),
+            resume_window: Some(parse_num("--ws-resume-window")?).filter(|s| *s > 0).map(std::time::Duration::from_secs),
+        }
+    };
+
+    let transcode_presets = {
+        let file = Some(args.get_str("--transcode-presets")).filter(|f| !f.is_empty()).map(std::path::Path::new);
+        clapshot_server::video_pipeline::transcode_presets::TranscodePresets::from_file(file, args.get_str("--transcode-preset"))
+            .map_err(|e| anyhow::anyhow!("Invalid transcode presets: {e}"))?
+    };
+
+    let burn_in = clapshot_server::video_pipeline::burn_in::BurnInPolicy::parse(
+            args.get_str("--burn-in"), args.get_str("--burn-in-required"),
+            Some(args.get_str("--watermark")).filter(|f| !f.is_empty()).map(std::path::Path::new))
+        .map_err(|e| anyhow::anyhow!("Invalid burn-in settings: {e}"))?;
 
-    clapshot_server::run_clapshot(data_dir, migrate, url_base, port, n_workers, target_bitrate, poll_interval, resubmit_delay)
+    Ok(clapshot_server::config::ReloadableConfig {
+        debug: args.get_bool("--debug"),
+        n_workers,
+        quotas,
+        webhook,
+        chat,
+        organizer,
+        tracker,
+        features,
+        rate_limits,
+        sessions,
+        transcode_presets,
+        burn_in,
+    })
 }
@@ -0,0 +1,153 @@
+use std::path::Path;
+use serde::Serialize;
+
+use crate::database::DB;
+
+/// Per-user limits. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Quotas {
+    pub max_total_bytes: Option<u64>,
+    pub max_file_size: Option<u64>,
+    pub max_concurrent_jobs: Option<u32>,
+}
+
+/// Current resource usage of a user
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UserUsage {
+    pub used_bytes: u64,
+    /// Part of `used_bytes` taken by videos in trash (until purged, they can be restored)
+    pub trash_bytes: u64,
+    pub n_videos: usize,
+    pub active_jobs: i64,
+}
+
+/// Calculate total size of files in a directory, recursively.
+/// Symlinks are not followed (they'd count transcoded files twice).
+pub fn dir_size(dir: &Path) -> std::io::Result<u64>
+{
+    let mut total = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let md = entry.path().symlink_metadata()?;
+        if md.is_dir() {
+            total += dir_size(&entry.path())?;
+        } else if md.is_file() {
+            total += md.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Get current storage and processing usage of a user.
+/// Trashed videos count toward storage, as their files are kept until purged.
+///
+/// # Arguments
+/// * `db` - Database
+/// * `videos_dir` - Path to the videos directory
+/// * `user_id` - User ID
+pub fn get_user_usage(db: &DB, videos_dir: &Path, user_id: &str) -> anyhow::Result<UserUsage>
+{
+    let videos = db.get_all_user_videos(user_id)?;
+    let mut used_bytes = 0;
+    for v in videos.iter() {
+        let dir = videos_dir.join(&v.video_hash);
+        if dir.is_dir() {
+            used_bytes += dir_size(&dir)?;
+        }
+    }
+    let mut trash_bytes = 0;
+    for v in db.get_trashed_videos(Some(user_id))? {
+        let dir = videos_dir.join("trash").join(&v.video_hash);   // See `trash::trash_dir`
+        if dir.is_dir() {
+            trash_bytes += dir_size(&dir)?;
+        }
+    }
+    Ok(UserUsage {
+        used_bytes: used_bytes + trash_bytes,
+        trash_bytes,
+        n_videos: videos.len(),
+        active_jobs: db.count_unfinished_user_jobs(user_id)?,
+    })
+}
+
+fn fmt_mb(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+impl Quotas {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_total_bytes.is_none() && self.max_file_size.is_none() && self.max_concurrent_jobs.is_none()
+    }
+
+    /// Max number of bytes the user can still add as a single file, or None if unlimited.
+    pub fn max_new_file_bytes(&self, usage: &UserUsage) -> Option<u64> {
+        let remaining = self.max_total_bytes.map(|m| m.saturating_sub(usage.used_bytes));
+        match (self.max_file_size, remaining) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Check if user can add a new file of given size.
+    /// Returns a user readable explanation if not.
+    pub fn check_new_file(&self, usage: &UserUsage, file_size: u64) -> Result<(), String> {
+        if let Some(max) = self.max_file_size {
+            if file_size > max {
+                return Err(format!("File is too large ({}). Max file size is {}.", fmt_mb(file_size), fmt_mb(max)));
+            }
+        }
+        if let Some(max) = self.max_total_bytes {
+            if usage.used_bytes + file_size > max {
+                return Err(format!("Storage quota exceeded. Using {} of {}, new file is {}.",
+                    fmt_mb(usage.used_bytes), fmt_mb(max), fmt_mb(file_size)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if user can start a new processing job.
+    /// Returns a user readable explanation if not.
+    pub fn check_new_job(&self, usage: &UserUsage) -> Result<(), String> {
+        if let Some(max) = self.max_concurrent_jobs {
+            if usage.active_jobs >= max as i64 {
+                return Err(format!("Too many videos processing ({} of max {}). Try again when they're done.", usage.active_jobs, max));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_quota_checks()
+{
+    let q = Quotas { max_total_bytes: Some(1000), max_file_size: Some(300), max_concurrent_jobs: Some(2) };
+    let usage = UserUsage { used_bytes: 800, trash_bytes: 0, n_videos: 3, active_jobs: 1 };
+
+    assert!(q.check_new_file(&usage, 200).is_ok());
+    assert!(q.check_new_file(&usage, 250).unwrap_err().contains("quota"));
+    assert!(q.check_new_file(&UserUsage::default(), 301).unwrap_err().contains("too large"));
+    assert_eq!(q.max_new_file_bytes(&usage), Some(200));
+    assert_eq!(q.max_new_file_bytes(&UserUsage::default()), Some(300));
+
+    assert!(q.check_new_job(&usage).is_ok());
+    assert!(q.check_new_job(&UserUsage { active_jobs: 2, ..usage }).is_err());
+
+    let unlimited = Quotas::default();
+    assert!(unlimited.is_unlimited());
+    assert_eq!(unlimited.max_new_file_bytes(&usage), None);
+    assert!(unlimited.check_new_file(&usage, u64::MAX / 2).is_ok());
+}
+
+#[test]
+fn test_dir_size()
+{
+    let dir = assert_fs::TempDir::new().unwrap();
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("a"), [0u8; 100]).unwrap();
+    std::fs::write(dir.join("sub").join("b"), [0u8; 50]).unwrap();
+    std::os::unix::fs::symlink(dir.join("a"), dir.join("link")).unwrap();
+    assert_eq!(dir_size(dir.path()).unwrap(), 150);
+}
@@ -0,0 +1,162 @@
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::database::DB;
+
+// When the disk (or user's filesystem quota) fills up during an upload, ingestion, transcode or
+// thumbnail write, the error is recognized as a `StorageError` and `StorageStatus` is marked full.
+// While it's full, the video pipeline stops taking new files from its intake queue, uploads are
+// refused with HTTP 507, and admins get a notification. The pipeline checks free space
+// periodically, and resumes (telling admins again) once there's `RESUME_FREE_BYTES` free.
+
+/// Free space needed in data dir to resume after running out
+pub const RESUME_FREE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// How often (seconds) to check free space while full
+pub const CHECK_INTERVAL_SECS: u64 = 30;
+
+/// Message for clients whose uploads are refused while storage is full
+pub const FULL_MSG: &str = "Server is out of storage space. Uploads are paused until space is freed.";
+
+/// Notifications to admins when storage fills up and when processing resumes
+pub const ADMIN_FULL_MSG: &str = "Server is out of storage space. Uploads and processing of new videos are paused until space is freed.";
+pub const ADMIN_RESUMED_MSG: &str = "Storage space freed. Uploads and processing of new videos resumed.";
+
+const ENOSPC: i32 = 28;
+const EDQUOT: i32 = 122;
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageError {
+    #[error("No space left on device")]
+    NoSpace,
+    #[error("Disk quota exceeded")]
+    QuotaExceeded,
+}
+
+impl StorageError {
+    pub fn from_io(e: &std::io::Error) -> Option<StorageError> {
+        match e.raw_os_error() {
+            Some(ENOSPC) => Some(StorageError::NoSpace),
+            Some(EDQUOT) => Some(StorageError::QuotaExceeded),
+            _ => None,
+        }
+    }
+
+    /// Find an I/O error about storage in an error chain
+    pub fn from_anyhow(e: &anyhow::Error) -> Option<StorageError> {
+        e.chain().filter_map(|c| c.downcast_ref::<std::io::Error>()).find_map(StorageError::from_io)
+    }
+
+    /// Recognize the error from an error message, or output of an external tool (e.g. ffmpeg)
+    pub fn from_message(msg: &str) -> Option<StorageError> {
+        if msg.contains("No space left on device") {
+            Some(StorageError::NoSpace)
+        } else if msg.contains("Disk quota exceeded") {
+            Some(StorageError::QuotaExceeded)
+        } else {
+            None
+        }
+    }
+}
+
+/// Is storage full, shared by API server and video pipeline
+#[derive(Debug)]
+pub struct StorageStatus {
+    data_dir: PathBuf,
+    full: RwLock<Option<StorageError>>,
+}
+
+impl StorageStatus {
+    pub fn new(data_dir: &Path) -> StorageStatus {
+        StorageStatus { data_dir: data_dir.to_path_buf(), full: RwLock::new(None) }
+    }
+
+    /// Error that filled storage, if it's (still) full
+    pub fn full(&self) -> Option<StorageError> {
+        *self.full.read().unwrap()
+    }
+
+    /// Mark storage full.
+    ///
+    /// # Returns
+    /// * `true` - It wasn't already, admins should be told
+    pub fn report(&self, err: StorageError) -> bool {
+        let mut full = self.full.write().unwrap();
+        let was_full = full.is_some();
+        *full = Some(err);
+        if !was_full {
+            tracing::error!(details=%err, "Out of storage space. Pausing uploads and processing of new files.");
+        }
+        !was_full
+    }
+
+    /// If full, check if there's enough free space to resume now.
+    ///
+    /// # Arguments
+    /// * `free_bytes` - Gets free space of a dir
+    ///
+    /// # Returns
+    /// * `true` - Resumed, admins should be told
+    pub fn try_resume(&self, free_bytes: impl Fn(&Path) -> Result<u64, String>) -> bool {
+        if self.full().is_none() {
+            return false;
+        }
+        match free_bytes(&self.data_dir) {
+            Ok(free) if free >= RESUME_FREE_BYTES => {
+                tracing::info!(free_bytes=free, "Storage space freed. Resuming.");
+                *self.full.write().unwrap() = None;
+                true
+            },
+            Ok(_) => false,
+            Err(e) => { tracing::warn!(details=%e, "Failed to check free disk space."); false },
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({ "full": self.full().is_some(), "error": self.full().map(|e| e.to_string()) })
+    }
+}
+
+/// User IDs of admins, to notify about storage running out
+pub fn admin_ids(db: &DB) -> Vec<String> {
+    match db.get_users() {
+        Ok(users) => users.into_iter().filter(|u| u.is_admin && !u.disabled).map(|u| u.user_id).collect(),
+        Err(e) => { tracing::error!(details=%e, "Failed to get admins from DB."); vec![] },
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_storage_error()
+{
+    let e = std::io::Error::from_raw_os_error(ENOSPC);
+    assert_eq!(StorageError::from_io(&e), Some(StorageError::NoSpace));
+    assert_eq!(StorageError::from_io(&std::io::Error::from_raw_os_error(EDQUOT)), Some(StorageError::QuotaExceeded));
+    assert_eq!(StorageError::from_io(&std::io::Error::from_raw_os_error(2)), None);
+
+    let ae = anyhow::Error::from(e).context("Failed to move file");
+    assert_eq!(StorageError::from_anyhow(&ae), Some(StorageError::NoSpace));
+    assert_eq!(StorageError::from_anyhow(&anyhow::anyhow!("other")), None);
+
+    assert_eq!(StorageError::from_message("[mp4 @ 0x1] Error writing trailer: No space left on device"), Some(StorageError::NoSpace));
+    assert_eq!(StorageError::from_message("av_interleaved_write_frame(): Disk quota exceeded"), Some(StorageError::QuotaExceeded));
+    assert_eq!(StorageError::from_message("Invalid data found when processing input"), None);
+}
+
+#[test]
+fn test_storage_status()
+{
+    let st = StorageStatus::new(Path::new("/data"));
+    assert!(!st.try_resume(|_| Ok(0)));
+    assert!(st.report(StorageError::NoSpace));
+    assert!(!st.report(StorageError::NoSpace));
+    assert_eq!(st.full(), Some(StorageError::NoSpace));
+    assert_eq!(st.to_json()["full"], true);
+
+    assert!(!st.try_resume(|_| Ok(RESUME_FREE_BYTES - 1)));
+    assert!(!st.try_resume(|_| Err("df failed".into())));
+    assert!(st.try_resume(|dir| { assert_eq!(dir, Path::new("/data")); Ok(RESUME_FREE_BYTES) }));
+    assert_eq!(st.full(), None);
+}
@@ -0,0 +1,343 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crossbeam_channel::{select, Receiver, Sender};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+// Traces of requests and of videos' journey through the pipeline, exported to an
+// OpenTelemetry collector (Jaeger, Grafana Tempo/Alloy etc.) with `--otlp-endpoint`.
+//
+// A span with a `trace_id` field starts a trace, and spans and log events inside it belong
+// to it. The ID is created for each websocket command and HTTP upload (or taken from the
+// client's W3C `traceparent` header), and carried through the pipeline stages in
+// `IncomingFile`, `Metadata` and `CmprInput`, so an upload, its metadata reading, ingestion,
+// transcoding and thumbnailing show up as one trace. Spans outside traces (e.g. the long
+// lived per-thread ones) are not exported.
+//
+// Spans are posted as OTLP/HTTP JSON to `<endpoint>/v1/traces` in batches, best effort:
+// if the collector is down, spans are dropped.
+
+/// Span field that starts a trace (32 hex digits)
+pub const TRACE_ID_FIELD: &str = "trace_id";
+
+const SERVICE_NAME: &str = "clapshot-server";
+
+/// Max spans waiting for export. More are dropped.
+const MAX_QUEUED_SPANS: usize = 10_000;
+const MAX_BATCH: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Max log events kept per span
+const MAX_SPAN_EVENTS: usize = 128;
+
+/// Make a new, random trace ID
+pub fn new_trace_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// Trace ID from a W3C `traceparent` header, if the client sent one, otherwise a new one.
+pub fn trace_id_from_headers(hdrs: &warp::http::HeaderMap) -> String {
+    hdrs.get("traceparent").and_then(|v| v.to_str().ok())
+        .and_then(parse_traceparent)
+        .unwrap_or_else(new_trace_id)
+}
+
+/// Parse trace ID from `traceparent` ("00-<trace id>-<parent id>-<flags>")
+fn parse_traceparent(tp: &str) -> Option<String> {
+    match tp.trim().split('-').collect::<Vec<_>>().as_slice() {
+        [_version, trace_id, _parent, _flags] if trace_id.len() == 32
+            && trace_id.chars().all(|c| c.is_ascii_hexdigit())
+            && trace_id.chars().any(|c| c != '0') => Some(trace_id.to_lowercase()),
+        _ => None,
+    }
+}
+
+/// A span of a trace. Kept in span's extensions while open, and sent to exporter when closed.
+#[derive(Debug, Clone)]
+pub struct SpanRecord {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub name: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(String, String)>,
+    pub events: Vec<SpanEvent>,
+    /// An error was logged in the span
+    pub error: bool,
+}
+
+/// Log event in a span
+#[derive(Debug, Clone)]
+pub struct SpanEvent {
+    pub time: SystemTime,
+    pub level: Level,
+    pub message: String,
+    pub attributes: Vec<(String, String)>,
+}
+
+/// Collects span and event fields as strings
+#[derive(Default)]
+struct FieldVisitor(Vec<(String, String)>);
+
+impl FieldVisitor {
+    fn take(&mut self, name: &str) -> Option<String> {
+        let pos = self.0.iter().position(|(k, _)| k == name)?;
+        Some(self.0.remove(pos).1)
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name().into(), value.into()));
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.push((field.name().into(), format!("{value:?}")));
+    }
+}
+
+/// Tracing layer that records spans of traces (see module docs) and passes them to exporter
+pub struct OtlpLayer {
+    tx: Sender<SpanRecord>,
+}
+
+impl<S> Layer<S> for OtlpLayer
+    where S: Subscriber + for<'a> LookupSpan<'a>
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut fields = FieldVisitor::default();
+        attrs.record(&mut fields);
+        let parent = span.parent().and_then(|p| p.extensions().get::<SpanRecord>()
+            .map(|r| (r.trace_id.clone(), r.span_id.clone())));
+        let (trace_id, parent_span_id) = match (fields.take(TRACE_ID_FIELD), parent) {
+            (Some(t), Some((pt, ps))) if t == pt => (t, Some(ps)),
+            (Some(t), _) => (t, None),
+            (None, Some((pt, ps))) => (pt, Some(ps)),
+            (None, None) => return,
+        };
+        let now = SystemTime::now();
+        span.extensions_mut().insert(SpanRecord {
+            trace_id,
+            span_id: format!("{:016x}", rand::random::<u64>().max(1)),
+            parent_span_id,
+            name: span.name().into(),
+            start: now,
+            end: now,
+            attributes: fields.0,
+            events: vec![],
+            error: false,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut ext = span.extensions_mut();
+        if let Some(rec) = ext.get_mut::<SpanRecord>() {
+            let mut fields = FieldVisitor::default();
+            values.record(&mut fields);
+            rec.attributes.extend(fields.0);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else { return };
+        let mut ext = span.extensions_mut();
+        let Some(rec) = ext.get_mut::<SpanRecord>() else { return };
+        let level = *event.metadata().level();
+        rec.error |= level == Level::ERROR;
+        if rec.events.len() < MAX_SPAN_EVENTS {
+            let mut fields = FieldVisitor::default();
+            event.record(&mut fields);
+            rec.events.push(SpanEvent {
+                time: SystemTime::now(),
+                level,
+                message: fields.take("message").unwrap_or_default(),
+                attributes: fields.0,
+            });
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let rec = span.extensions_mut().remove::<SpanRecord>();
+        if let Some(mut rec) = rec {
+            rec.end = SystemTime::now();
+            self.tx.try_send(rec).ok();     // Queue full => drop it
+        }
+    }
+}
+
+/// Flushes queued spans when dropped (on exit)
+pub struct OtlpGuard {
+    flush_tx: Sender<()>,
+    done_rx: Receiver<()>,
+}
+
+impl Drop for OtlpGuard {
+    fn drop(&mut self) {
+        if self.flush_tx.send(()).is_ok() {
+            self.done_rx.recv_timeout(Duration::from_secs(5)).ok();
+        }
+    }
+}
+
+/// Make a layer that exports traces to an OTLP/HTTP collector, and start the exporter thread.
+///
+/// # Arguments
+/// * `endpoint` - Collector's base URL, e.g. `http://localhost:4318` (`/v1/traces` is appended)
+pub fn otlp_layer(endpoint: &str) -> anyhow::Result<(OtlpLayer, OtlpGuard)>
+{
+    let url = traces_url(endpoint)?;
+    let (tx, rx) = crossbeam_channel::bounded(MAX_QUEUED_SPANS);
+    let (flush_tx, flush_rx) = crossbeam_channel::bounded(1);
+    let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+    std::thread::spawn(move || run_exporter(url, rx, flush_rx, done_tx));
+    Ok((OtlpLayer { tx }, OtlpGuard { flush_tx, done_rx }))
+}
+
+fn traces_url(endpoint: &str) -> anyhow::Result<reqwest::Url> {
+    let endpoint = endpoint.trim();
+    let url = if endpoint.ends_with("/v1/traces") {
+        reqwest::Url::parse(endpoint)?
+    } else {
+        reqwest::Url::parse(&format!("{}/", endpoint.trim_end_matches('/')))?.join("v1/traces")?
+    };
+    if !["http", "https"].contains(&url.scheme()) {
+        anyhow::bail!("OTLP endpoint must be http or https");
+    }
+    Ok(url)
+}
+
+/// Post spans in batches every `EXPORT_INTERVAL` (or when a batch is full), until flushed on exit.
+fn run_exporter(url: reqwest::Url, rx: Receiver<SpanRecord>, flush_rx: Receiver<()>, done_tx: Sender<()>)
+{
+    let client = match reqwest::blocking::Client::builder().timeout(Duration::from_secs(10)).build() {
+        Ok(c) => c,
+        Err(e) => { tracing::error!(details=%e, "Failed to create OTLP client. Traces won't be exported."); return; }
+    };
+    let mut batch = Vec::new();
+    let mut failing = false;
+    let mut post = |batch: &mut Vec<SpanRecord>| {
+        if batch.is_empty() { return; }
+        let res = client.post(url.clone()).json(&to_otlp_json(batch)).send().and_then(|r| r.error_for_status());
+        match (res, failing) {
+            (Err(e), false) => { tracing::warn!(details=%e, "OTLP trace export failed. Dropping spans until collector is back."); failing = true; },
+            (Ok(_), true) => { tracing::info!("OTLP trace export works again."); failing = false; },
+            _ => {},
+        }
+        batch.clear();
+    };
+    loop {
+        select! {
+            recv(rx) -> rec => match rec {
+                Ok(rec) => {
+                    batch.push(rec);
+                    if batch.len() >= MAX_BATCH { post(&mut batch); }
+                },
+                Err(_) => { post(&mut batch); return; }
+            },
+            recv(flush_rx) -> _ => {
+                batch.extend(rx.try_iter());
+                post(&mut batch);
+                done_tx.send(()).ok();
+                return;
+            },
+            default(EXPORT_INTERVAL) => { post(&mut batch); },
+        }
+    }
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn otlp_attributes(attrs: &[(String, String)]) -> serde_json::Value {
+    attrs.iter().map(|(k, v)| serde_json::json!({ "key": k, "value": { "stringValue": v } })).collect()
+}
+
+/// Make an OTLP/HTTP JSON `ExportTraceServiceRequest` of spans
+pub fn to_otlp_json(spans: &[SpanRecord]) -> serde_json::Value
+{
+    let spans = spans.iter().map(|s| {
+        let mut span = serde_json::json!({
+            "traceId": s.trace_id,
+            "spanId": s.span_id,
+            "name": s.name,
+            "kind": 1,  // internal
+            "startTimeUnixNano": unix_nanos(s.start),
+            "endTimeUnixNano": unix_nanos(s.end),
+            "attributes": otlp_attributes(&s.attributes),
+            "events": s.events.iter().map(|e| {
+                let mut attrs = e.attributes.clone();
+                attrs.push(("level".into(), e.level.to_string()));
+                serde_json::json!({ "timeUnixNano": unix_nanos(e.time), "name": e.message, "attributes": otlp_attributes(&attrs) })
+            }).collect::<Vec<_>>(),
+            "status": { "code": if s.error { 2 } else { 0 } },
+        });
+        if let Some(p) = &s.parent_span_id {
+            span["parentSpanId"] = serde_json::json!(p);
+        }
+        span
+    }).collect::<Vec<_>>();
+
+    serde_json::json!({ "resourceSpans": [{
+        "resource": { "attributes": otlp_attributes(&[("service.name".into(), SERVICE_NAME.into())]) },
+        "scopeSpans": [{ "scope": { "name": SERVICE_NAME }, "spans": spans }],
+    }]})
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_traceparent()
+{
+    assert_eq!(parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").as_deref(), Some("4bf92f3577b34da6a3ce929d0e0e4736"));
+    assert_eq!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+    assert_eq!(parse_traceparent("garbage"), None);
+    assert_eq!(new_trace_id().len(), 32);
+
+    assert_eq!(traces_url("http://localhost:4318").unwrap().as_str(), "http://localhost:4318/v1/traces");
+    assert_eq!(traces_url("http://collector/otlp/").unwrap().as_str(), "http://collector/otlp/v1/traces");
+    assert_eq!(traces_url("http://collector/v1/traces").unwrap().as_str(), "http://collector/v1/traces");
+    assert!(traces_url("ftp://collector").is_err());
+}
+
+#[test]
+fn test_otlp_layer_records_traces()
+{
+    use tracing_subscriber::layer::SubscriberExt;
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let subscriber = tracing_subscriber::registry().with(OtlpLayer { tx });
+    tracing::subscriber::with_default(subscriber, || {
+        let _outer = tracing::info_span!("PIPELINE").entered();   // Not in a trace
+        tracing::info!("Not exported.");
+        {
+            let _root = tracing::info_span!("INGEST_VIDEO", trace_id="abc", vh="v1").entered();
+            let _child = tracing::info_span!("compress", job_id=3).entered();
+            tracing::error!(details="boom", "Transcode failed.");
+        }
+        let _other = tracing::info_span!("cmd", trace_id="def").entered();
+    });
+    let spans = rx.try_iter().collect::<Vec<_>>();
+    assert_eq!(spans.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), vec!["compress", "INGEST_VIDEO", "cmd"]);
+    let (child, root) = (&spans[0], &spans[1]);
+    assert_eq!(child.trace_id, "abc");
+    assert_eq!(child.parent_span_id.as_ref(), Some(&root.span_id));
+    assert_eq!(root.parent_span_id, None);
+    assert_eq!(root.attributes, vec![("vh".to_string(), "v1".to_string())]);
+    assert!(child.error && !root.error);
+    assert_eq!(child.events[0].message, "Transcode failed.");
+    assert_eq!(spans[2].trace_id, "def");
+
+    let json = to_otlp_json(&spans);
+    let out = &json["resourceSpans"][0]["scopeSpans"][0]["spans"];
+    assert_eq!(out[0]["parentSpanId"], root.span_id.as_str());
+    assert_eq!(out[0]["status"]["code"], 2);
+    assert_eq!(out[1]["attributes"][0]["value"]["stringValue"], "v1");
+    assert!(out[1].get("parentSpanId").is_none());
+}
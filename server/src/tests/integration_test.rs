@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 #![allow(unused_imports)]
+#![allow(clippy::module_inception)]
 
 #[cfg(test)]
 mod integration_test
@@ -8,6 +9,7 @@ mod integration_test
     use std::{error, any};
     use std::{path::PathBuf, str::FromStr};
     use std::{thread, time::Duration};
+    use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize}};
 
     use assert_fs::prelude::PathCopy;
     use futures::Future;
@@ -40,13 +42,14 @@ mod integration_test
         let (arg_sender, arg_recvr) = unbounded::<IncomingFile>();
         let (res_sender, res_recvr) = unbounded::<metadata_reader::MetadataResult>();
         let th = thread::spawn(move || {
-                metadata_reader::run_forever(arg_recvr, res_sender, 4);
+                metadata_reader::run_forever(arg_recvr, res_sender, Arc::new(AtomicUsize::new(4)), Arc::new(AtomicUsize::new(0)), false, None, None, Default::default(), Arc::new(AtomicBool::new(false)), |_| 0);
             });
 
         // Send request to metadata reader
         let args = IncomingFile {
             file_path: PathBuf::from_str(data_dir.join("NASA_Red_Lettuce_excerpt.mov").to_str().unwrap())?,
             user_id: "nobody".to_string(),
+            ..Default::default()
         };
         arg_sender.send(args.clone())?;
 
@@ -86,12 +89,27 @@ mod integration_test
                     let data_dir = $data_dir.path().to_path_buf();
                     let url_base = url_base.clone();
                     thread::spawn(move || {
-                        crate::run_clapshot(data_dir, true, url_base, port, 4, target_bitrate, poll_interval, poll_interval*5.0).unwrap()
+                        let opts = crate::config::StartupOptions {
+                            data_dir, migrate: true, url_base, port, host_videos: true,
+                            ingest: crate::video_pipeline::IngestPolicy { target_bitrate, ..Default::default() },
+                            poll_interval, resubmit_delay: poll_interval*5.0, sequence_fps: 24.0,
+                            shutdown_grace: Duration::from_secs(5), queue_capacity: crate::video_pipeline::queues::DEFAULT_CAPACITY,
+                            ..Default::default()
+                        };
+                        crate::run_clapshot(opts, crate::config::LiveConfig::fixed(crate::config::ReloadableConfig { n_workers: 4, ..Default::default() })).unwrap()
                     })};
-                thread::sleep(Duration::from_secs_f32(0.25));
-
-                let resp = reqwest::blocking::get(&format!("{}/api/health", &url_base)).unwrap();
-                assert_eq!(resp.status(), 200);
+                // Wait for server to start
+                let deadline = std::time::Instant::now() + Duration::from_secs(10);
+                loop {
+                    match reqwest::blocking::get(&format!("{}/api/health", &url_base)) {
+                        Ok(resp) => { assert_eq!(resp.status(), 200); break; },
+                        Err(e) if std::time::Instant::now() < deadline && !th.is_finished() => {
+                            tracing::debug!(details=%e, "Server not up yet.");
+                            thread::sleep(Duration::from_millis(50));
+                        },
+                        Err(e) => panic!("Server didn't start: {e}"),
+                    }
+                }
         
                 tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
                     // Connect client
@@ -109,7 +127,7 @@ mod integration_test
     #[traced_test]
     fn test_video_ingest_no_transcode() -> anyhow::Result<()>
     {
-        cs_main_test! {[ws, data_dir, incoming_dir, 2500_000]
+        cs_main_test! {[ws, data_dir, incoming_dir, 2_500_000]
             // Copy test file to incoming dir
             let mp4_file = "60fps-example.mp4";
             data_dir.copy_from("src/tests/assets/", &[mp4_file]).unwrap();
@@ -188,7 +206,7 @@ mod integration_test
             // Check that it's being transcoded
             assert!(data["details"].as_str().unwrap().to_ascii_lowercase().contains("ranscod"));
             let vh = data["ref_video_hash"].as_str().unwrap();
-            assert!(vh.len() > 0);
+            assert!(!vh.is_empty());
 
             // Wait until transcoding is done
             let mut transcode_complete = false;
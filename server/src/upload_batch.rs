@@ -0,0 +1,88 @@
+use std::path::Path;
+use serde::Serialize;
+
+use crate::database::{DB, models};
+use crate::database::error::DBError;
+use crate::database::models::batch_file_status;
+
+/// Current state of an upload batch, with per-file status and aggregate counts
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchSummary {
+    pub batch: models::UploadBatch,
+    pub files: Vec<models::UploadBatchFile>,
+    pub n_total: usize,
+    pub n_pending: usize,
+    pub n_processing: usize,
+    pub n_done: usize,
+    pub n_failed: usize,
+    pub n_cancelled: usize,
+}
+
+/// Get current state of an upload batch.
+///
+/// # Arguments
+/// * `db` - Database
+/// * `batch_id` - ID of the batch
+pub fn get_batch_summary(db: &DB, batch_id: i32) -> Result<BatchSummary, DBError>
+{
+    let batch = db.get_upload_batch(batch_id)?;
+    let files = db.get_upload_batch_files(batch_id)?;
+    let count = |s: &str| files.iter().filter(|f| f.status == s).count();
+    Ok(BatchSummary {
+        n_total: files.len(),
+        n_pending: count(batch_file_status::PENDING),
+        n_processing: count(batch_file_status::PROCESSING),
+        n_done: count(batch_file_status::DONE),
+        n_failed: count(batch_file_status::FAILED),
+        n_cancelled: count(batch_file_status::CANCELLED),
+        batch,
+        files,
+    })
+}
+
+impl BatchSummary {
+    fn title(&self) -> String {
+        if self.batch.title.is_empty() { format!("#{}", self.batch.id) } else { format!("'{}'", self.batch.title) }
+    }
+
+    /// Aggregate progress as a user readable message
+    pub fn progress_msg(&self) -> String {
+        format!("Upload batch {}: {} of {} files processed", self.title(),
+            self.n_done + self.n_failed + self.n_cancelled, self.n_total)
+    }
+
+    /// Completion notification (message, details). Details is a JSON object
+    /// with the batch ID and hashes of all resulting videos, for linking to them.
+    pub fn completion_msg(&self) -> (String, String) {
+        let mut msg = format!("Upload batch {} done: {} video{} added", self.title(), self.n_done, if self.n_done == 1 {""} else {"s"});
+        if self.n_failed > 0 { msg += &format!(", {} failed", self.n_failed); }
+        if self.n_cancelled > 0 { msg += &format!(", {} cancelled", self.n_cancelled); }
+        let video_hashes = self.files.iter().filter_map(|f| f.video_hash.clone()).collect::<Vec<_>>();
+        (msg, serde_json::json!({ "batch_id": self.batch.id, "video_hashes": video_hashes }).to_string())
+    }
+}
+
+/// Record processing result of an uploaded file, if it belongs to an upload batch.
+///
+/// # Arguments
+/// * `db` - Database
+/// * `src_file` - Path of the uploaded file, as submitted to the pipeline
+/// * `res` - Hash of the resulting video, or error message
+///
+/// # Returns
+/// * `None` if the file is not part of a batch
+/// * `Some((summary, just_finished))` otherwise. `just_finished` is true (only once) when the whole batch is done.
+pub fn record_processing_result(db: &DB, src_file: &Path, res: Result<&str, &str>) -> Result<Option<(BatchSummary, bool)>, DBError>
+{
+    let f = match db.get_processing_batch_file_by_src(&src_file.to_string_lossy()) {
+        Ok(f) => f,
+        Err(DBError::NotFound()) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    match res {
+        Ok(vh) => db.set_upload_batch_file_status(f.id, batch_file_status::DONE, None, Some(vh), "")?,
+        Err(msg) => db.set_upload_batch_file_status(f.id, batch_file_status::FAILED, None, None, msg)?,
+    }
+    let just_finished = db.try_finish_upload_batch(f.batch_id)?;
+    Ok(Some((get_batch_summary(db, f.batch_id)?, just_finished)))
+}
@@ -0,0 +1,127 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed}};
+use crossbeam_channel::{Sender, Receiver};
+
+use super::fair_queue;
+use crate::database::models;
+
+/// Max number of labels stored per video. Rest are dropped (lowest confidence first).
+pub const MAX_LABELS_PER_VIDEO: usize = 10_000;
+/// Max length of a label's kind and text
+const MAX_LABEL_LEN: usize = 200;
+
+/// Request to analyze a video with the external ML backend
+#[derive(Debug, Clone)]
+pub struct AnalysisRequest {
+    pub video_hash: String,
+    pub user_id: String,
+    pub src: PathBuf,
+    pub job_id: Option<i32>,
+}
+
+/// Result of an analysis: labels, or error message
+#[derive(Debug, Clone)]
+pub struct AnalysisResult {
+    pub req: AnalysisRequest,
+    pub labels: Result<Vec<models::VideoLabelInsert>, String>,
+}
+
+/// Parse analyzer output. Accepts a JSON array of labels, `{"labels": [...]}`, or one label object per line.
+/// A label looks like `{"kind": "face", "label": "Alice", "start": 1.5, "end": 4.0, "confidence": 0.93}`.
+/// `end` defaults to `start`. Invalid entries are skipped with a warning.
+pub fn parse_labels(output: &str, video_hash: &str) -> Result<Vec<models::VideoLabelInsert>, String>
+{
+    let entries = match serde_json::from_str::<serde_json::Value>(output.trim()) {
+        Ok(serde_json::Value::Array(a)) => a,
+        Ok(v) if v["labels"].is_array() => v["labels"].as_array().cloned().unwrap_or_default(),
+        Ok(v) if v.is_object() => vec![v],
+        _ => output.lines().filter(|l| !l.trim().is_empty())
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).map_err(|e| format!("Invalid analyzer output: {e}")))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+
+    let mut labels = entries.iter().filter_map(|e| {
+        let kind = e["kind"].as_str()?.trim().to_lowercase();
+        let label = e["label"].as_str()?.trim().to_string();
+        let start = e["start"].as_f64()? as f32;
+        let end = e["end"].as_f64().map(|t| t as f32).unwrap_or(start);
+        let confidence = e["confidence"].as_f64().map(|c| c as f32);
+        let valid = !kind.is_empty() && !label.is_empty() && kind.len() <= MAX_LABEL_LEN && label.len() <= MAX_LABEL_LEN
+            && start.is_finite() && start >= 0.0 && end >= start
+            && confidence.is_none_or(|c| (0.0..=1.0).contains(&c));
+        if !valid { tracing::warn!(entry=%e, "Skipping invalid analysis label."); }
+        valid.then(|| models::VideoLabelInsert { video_hash: video_hash.into(), kind, label, start_time: start, end_time: end, confidence })
+    }).collect::<Vec<_>>();
+
+    if labels.len() > MAX_LABELS_PER_VIDEO {
+        tracing::warn!(n_labels=labels.len(), "Too many analysis labels. Keeping the most confident ones.");
+        labels.sort_by(|a, b| b.confidence.unwrap_or(1.0).total_cmp(&a.confidence.unwrap_or(1.0)));
+        labels.truncate(MAX_LABELS_PER_VIDEO);
+        labels.sort_by(|a, b| a.start_time.total_cmp(&b.start_time));
+    }
+    Ok(labels)
+}
+
+/// Run the analyzer command (`<cmd> [args] <file>`) and return its stdout.
+/// Runs outside the sandbox: analyzers are configured by the admin, and may need network access
+/// (to call an analysis service) or GPU devices.
+fn run_analyzer(cmd: &str, file: &Path) -> Result<String, String>
+{
+    let mut argv = cmd.split_whitespace();
+    let prog = argv.next().ok_or("Analyzer command is empty")?;
+    let mut c = std::process::Command::new(prog);
+    c.args(argv).arg(file);
+    tracing::info!(file=%file.display(), "Calling analyzer");
+    tracing::debug!("Exec: {:?}", c);
+    match c.output() {
+        Ok(out) if out.status.success() => Ok(String::from_utf8_lossy(&out.stdout).to_string()),
+        Ok(out) => Err(format!("Analyzer exited with error: {}", String::from_utf8_lossy(&out.stderr).lines().last().unwrap_or(""))),
+        Err(e) => Err(format!("Failed to execute analyzer '{}': {}", prog, e)),
+    }
+}
+
+/// Listen to analysis requests and run them on the analyzer in a thread pool.
+/// Requests are scheduled fairly between users (see `fair_queue::run_fair_pool`).
+///
+/// # Arguments
+/// * `inq` - Channel to receive requests
+/// * `outq` - Channel to send results
+/// * `n_workers` - Number of worker threads
+/// * `depth` - Number of items waiting, for metrics
+/// * `cmd` - Analyzer command (see `run_analyzer`)
+/// * `priority_of` - Function that returns current processing priority of a user
+pub fn run_forever<P>(inq: Receiver<AnalysisRequest>, outq: Sender<AnalysisResult>, n_workers: Arc<AtomicUsize>, depth: Arc<AtomicUsize>, cmd: String, terminate_flag: Arc<AtomicBool>, priority_of: P)
+    where P: Fn(&str) -> i32
+{
+    let _span = tracing::info_span!("ANALYSIS").entered();
+    tracing::info!(n_workers = n_workers.load(Relaxed), cmd = %cmd, "Starting.");
+    fair_queue::run_fair_pool(inq, &n_workers, &depth, |r: &AnalysisRequest| r.user_id.clone(), priority_of, &terminate_flag, move |req: AnalysisRequest| {
+        let _span = tracing::info_span!("analyze", video=%req.video_hash, user=%req.user_id).entered();
+        let labels = run_analyzer(&cmd, &req.src).and_then(|out| parse_labels(&out, &req.video_hash));
+        outq.send(AnalysisResult { req, labels }).is_ok()
+    });
+    tracing::info!("Exiting.");
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_parse_analysis_labels()
+{
+    let lbl = |kind: &str, label: &str, s: f32, e: f32, c: Option<f32>| models::VideoLabelInsert {
+        video_hash: "vh".into(), kind: kind.into(), label: label.into(), start_time: s, end_time: e, confidence: c };
+
+    let arr = r#"[{"kind":"Face","label":"Alice","start":1.5,"end":4,"confidence":0.9}, {"kind":"object","label":"car","start":2}]"#;
+    assert_eq!(parse_labels(arr, "vh").unwrap(), vec![lbl("face", "Alice", 1.5, 4.0, Some(0.9)), lbl("object", "car", 2.0, 2.0, None)]);
+
+    let obj = r#"{"labels": [{"kind":"text","label":"FINAL","start":0,"end":1}]}"#;
+    assert_eq!(parse_labels(obj, "vh").unwrap(), vec![lbl("text", "FINAL", 0.0, 1.0, None)]);
+
+    // JSON lines, with invalid entries skipped
+    let lines = "{\"kind\":\"face\",\"label\":\"Bob\",\"start\":3,\"end\":2}\n\n{\"kind\":\"face\",\"label\":\"Bob\",\"start\":3,\"end\":5,\"confidence\":1.5}\n{\"kind\":\"face\",\"label\":\"Bob\",\"start\":3,\"end\":5}\n";
+    assert_eq!(parse_labels(lines, "vh").unwrap(), vec![lbl("face", "Bob", 3.0, 5.0, None)]);
+
+    assert!(parse_labels("not json", "vh").is_err());
+    assert!(parse_labels("", "vh").unwrap().is_empty());
+}
@@ -0,0 +1,98 @@
+use std::ffi::OsString;
+use std::path::Path;
+
+use super::sandbox::Sandbox;
+
+/// What to do with the existing audio when muxing in a new audio mix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AudioMuxMode {
+    /// Drop old audio tracks
+    #[default]
+    Replace,
+    /// Keep old audio tracks after the new one
+    Add,
+}
+
+impl std::str::FromStr for AudioMuxMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "replace" => Ok(AudioMuxMode::Replace),
+            "add" | "augment" => Ok(AudioMuxMode::Add),
+            _ => Err(format!("Unknown audio mode '{}'. Use replace or add.", s)),
+        }
+    }
+}
+
+/// Build ffmpeg arguments (after "ffmpeg") for muxing `audio` into `video`.
+/// Video stream is copied as is. The new mix becomes the first (default) audio track.
+/// Output container is Matroska, so that any video codec can be copied.
+fn ffmpeg_args(video: &Path, audio: &Path, mode: AudioMuxMode, duration: Option<f32>, track_title: &str, dst: &Path) -> Vec<OsString>
+{
+    let mut args: Vec<OsString> = vec!["-nostats".into(), "-y".into(),
+        "-i".into(), video.as_os_str().into(), "-i".into(), audio.as_os_str().into()];
+    args.extend(["-map", "0:v:0", "-map", "1:a:0"].map(OsString::from));
+    if mode == AudioMuxMode::Add {
+        args.extend(["-map", "0:a?"].map(OsString::from));
+    }
+    args.extend(["-c:v", "copy", "-c:a", "aac", "-b:a", "256k",
+        "-disposition:a:0", "default"].map(OsString::from));
+    args.extend(["-metadata:s:a:0".into(), format!("title={track_title}").into()]);
+    // Don't let a longer audio file extend the video
+    if let Some(d) = duration.filter(|d| *d > 0.0) {
+        args.extend(["-t".into(), format!("{d:.3}").into()]);
+    }
+    args.push(dst.as_os_str().into());
+    args
+}
+
+/// Mux a new audio mix into a video without re-encoding the video. Blocks until done.
+///
+/// # Arguments
+/// * `video` - Video file to take video (and in Add mode, old audio) from
+/// * `audio` - Audio file with the new mix. First audio stream is used.
+/// * `mode` - Replace or keep old audio
+/// * `duration` - Video duration in seconds, if known. Output is cut to it.
+/// * `dst` - Output file (.mkv). Its directory must exist.
+/// * `sandbox` - Sandbox to run ffmpeg in
+pub fn mux_audio(video: &Path, audio: &Path, mode: AudioMuxMode, duration: Option<f32>, dst: &Path, sandbox: &Sandbox) -> Result<(), String>
+{
+    let out_dir = dst.parent().ok_or("Invalid destination")?;
+    let track_title = audio.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let cmd = &mut sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[out_dir]);
+    cmd.args(ffmpeg_args(video, audio, mode, duration, &track_title, dst));
+    tracing::info!(video=%video.display(), audio=%audio.display(), mode=?mode, "Calling ffmpeg to mux new audio");
+    tracing::debug!("Exec: {:?}", cmd);
+    match cmd.output() {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            tracing::error!(stderr=%stderr, "ffmpeg audio mux failed");
+            Err(format!("FFMPEG exited with error: {}", stderr.lines().last().unwrap_or("")))
+        },
+        Err(e) => Err(format!("Failed to execute ffmpeg: {}", e)),
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_audio_mux_ffmpeg_args()
+{
+    let args = |mode, duration| ffmpeg_args(Path::new("/v/abc/video.mp4"), Path::new("/up/x/mix2.wav"), mode, duration, "mix2.wav", Path::new("/up/x/out.mkv"))
+        .iter().map(|a| a.to_string_lossy().to_string()).collect::<Vec<_>>().join(" ");
+
+    let a = args(AudioMuxMode::Replace, Some(12.5));
+    assert!(a.contains("-map 0:v:0 -map 1:a:0 -c:v copy"));
+    assert!(a.contains("-metadata:s:a:0 title=mix2.wav"));
+    assert!(a.ends_with("-t 12.500 /up/x/out.mkv"));
+
+    let a = args(AudioMuxMode::Add, None);
+    assert!(a.contains("-map 0:v:0 -map 1:a:0 -map 0:a? -c:v copy"));
+    assert!(!a.contains("-t "));
+
+    assert_eq!("augment".parse::<AudioMuxMode>(), Ok(AudioMuxMode::Add));
+    assert_eq!("".parse::<AudioMuxMode>(), Ok(AudioMuxMode::Replace));
+    assert!("mix".parse::<AudioMuxMode>().is_err());
+}
@@ -0,0 +1,194 @@
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+// Overlays burned into transcoded videos: running SMPTE timecode, source file name, and for leak
+// tracing, the deployment's watermark image and the uploader's name. Uploads pick them with the
+// `X-Burn-In` header (e.g. "timecode,filename"), or get the deployment's default (`--burn-in`).
+// `--burn-in-required` ones are added regardless, so users can't opt out of them.
+//
+// Burning in forces transcoding (sources that could be served as is or remuxed are transcoded too),
+// and is kept on safer retries. Stills and the waveform video of audio-only files are left as is.
+
+/// Which overlays to burn in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BurnInFlags {
+    pub timecode: bool,
+    pub filename: bool,
+    pub watermark: bool,
+    pub username: bool,
+}
+
+impl BurnInFlags {
+    pub fn is_empty(&self) -> bool {
+        *self == BurnInFlags::default()
+    }
+
+    pub fn union(self, other: BurnInFlags) -> BurnInFlags {
+        BurnInFlags {
+            timecode: self.timecode || other.timecode,
+            filename: self.filename || other.filename,
+            watermark: self.watermark || other.watermark,
+            username: self.username || other.username,
+        }
+    }
+}
+
+impl std::str::FromStr for BurnInFlags {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut res = BurnInFlags::default();
+        for name in s.split(',').map(|n| n.trim().to_lowercase()).filter(|n| !n.is_empty()) {
+            match name.as_str() {
+                "none" => {},
+                "timecode" => res.timecode = true,
+                "filename" => res.filename = true,
+                "watermark" => res.watermark = true,
+                "username" => res.username = true,
+                _ => return Err(format!("Unknown burn-in overlay '{}'. Use timecode, filename, watermark, username or none.", name)),
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl std::fmt::Display for BurnInFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = [(self.timecode, "timecode"), (self.filename, "filename"), (self.watermark, "watermark"), (self.username, "username")]
+            .into_iter().filter(|(on, _)| *on).map(|(_, n)| n).collect::<Vec<_>>();
+        write!(f, "{}", if names.is_empty() { "none".to_string() } else { names.join(",") })
+    }
+}
+
+/// Burn-in settings of the deployment
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BurnInPolicy {
+    /// Overlays for uploads that don't pick any
+    pub default: BurnInFlags,
+    /// Overlays added to every upload
+    pub required: BurnInFlags,
+    /// Watermark image (PNG with transparency), burned in bottom right at its own size
+    pub watermark: Option<PathBuf>,
+}
+
+impl BurnInPolicy {
+    /// Parse settings from config options (see `--burn-in`, `--burn-in-required` and `--watermark`)
+    pub fn parse(default: &str, required: &str, watermark: Option<&Path>) -> Result<BurnInPolicy, String>
+    {
+        let res = BurnInPolicy { default: default.parse()?, required: required.parse()?, watermark: watermark.map(PathBuf::from) };
+        match &res.watermark {
+            Some(f) if !f.is_file() => Err(format!("Watermark image '{}' not found", f.display())),
+            None if res.default.watermark || res.required.watermark => Err("Watermark burn-in needs a watermark image".into()),
+            _ => Ok(res),
+        }
+    }
+
+    /// Overlays for an upload: what it asked for (or default), plus required ones.
+    /// Watermark is left out if there's no image for it (only uploads can ask for it then).
+    pub fn resolve(&self, requested: Option<BurnInFlags>) -> BurnInFlags
+    {
+        let mut res = requested.unwrap_or(self.default).union(self.required);
+        res.watermark &= self.watermark.is_some();
+        res
+    }
+}
+
+/// Overlays for one transcode, with the texts and image to burn in
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BurnIn {
+    /// Frame rate of the output, for running timecode (from 00:00:00:00). None = no timecode.
+    pub timecode_fps: Option<f32>,
+    pub filename: Option<String>,
+    pub username: Option<String>,
+    pub watermark: Option<PathBuf>,
+}
+
+impl BurnIn {
+    pub fn new(flags: BurnInFlags, policy: &BurnInPolicy, fps: f32, filename: &str, username: &str) -> BurnIn
+    {
+        BurnIn {
+            timecode_fps: flags.timecode.then_some(fps).filter(|f| *f > 0.0),
+            filename: flags.filename.then(|| filename.to_string()),
+            username: flags.username.then(|| username.to_string()),
+            watermark: policy.watermark.clone().filter(|_| flags.watermark),
+        }
+    }
+
+    pub fn flags(&self) -> BurnInFlags {
+        BurnInFlags {
+            timecode: self.timecode_fps.is_some(),
+            filename: self.filename.is_some(),
+            watermark: self.watermark.is_some(),
+            username: self.username.is_some(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flags().is_empty()
+    }
+
+    /// Add the overlays to a "-vf" filter chain. They go last, so their size and position is relative to the output.
+    pub fn apply(&self, video_filter: &str) -> String
+    {
+        const BOX: &str = "fontsize=28:box=1:boxborderw=6";
+        let mut chain = video_filter.to_string();
+        if let Some(fps) = self.timecode_fps {
+            chain += &format!(",drawtext=timecode='00\\:00\\:00\\:00':rate={fps}:x=w-tw-20:y=20:{BOX}:fontcolor=white:boxcolor=black@0.6");
+        }
+        if let Some(name) = &self.filename {
+            chain += &format!(",drawtext=expansion=none:text='{}':x=20:y=20:{BOX}:fontcolor=white:boxcolor=black@0.6", escape_filter_value(name));
+        }
+        // Leak tracing marks are see-through, so they don't get in the way of review
+        if let Some(name) = &self.username {
+            chain += &format!(",drawtext=expansion=none:text='{}':x=20:y=h-th-20:{BOX}:fontcolor=white@0.5:boxcolor=black@0.3", escape_filter_value(name));
+        }
+        match &self.watermark {
+            None => chain,
+            Some(img) => format!("movie='{}',format=rgba,colorchannelmixer=aa=0.5[wm];[in]{chain}[base];[base][wm]overlay=W-w-20:H-h-20[out]",
+                escape_filter_value(&img.to_string_lossy())),
+        }
+    }
+}
+
+/// Escape a value (file path, text) for use as a single-quoted option value in an ffmpeg filter graph.
+/// Quotes protect it from filter graph parsing, but the filter's option parser still unescapes it.
+pub fn escape_filter_value(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(':', "\\:").replace('\'', "'\\\\\\''")
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_burn_in()
+{
+    let flags: BurnInFlags = " Timecode,username ".parse().unwrap();
+    assert_eq!(flags, BurnInFlags { timecode: true, username: true, ..Default::default() });
+    assert_eq!(flags.to_string(), "timecode,username");
+    assert_eq!("none".parse::<BurnInFlags>().unwrap().to_string(), "none");
+    assert!("".parse::<BurnInFlags>().unwrap().is_empty());
+    assert!("timecode,logo".parse::<BurnInFlags>().is_err());
+
+    // Required overlays can't be opted out of, and watermark needs an image
+    let wm_dir = tempfile::tempdir().unwrap();
+    let wm = wm_dir.path().join("wm.png");
+    assert!(BurnInPolicy::parse("none", "watermark", None).is_err());
+    assert!(BurnInPolicy::parse("none", "watermark", Some(&wm)).is_err());
+    std::fs::write(&wm, b"png").unwrap();
+    let policy = BurnInPolicy::parse("timecode", "watermark", Some(&wm)).unwrap();
+    assert_eq!(policy.resolve(None).to_string(), "timecode,watermark");
+    assert_eq!(policy.resolve(Some(BurnInFlags::default())).to_string(), "watermark");
+    assert_eq!(BurnInPolicy::default().resolve(Some("watermark,filename".parse().unwrap())).to_string(), "filename");
+
+    let all = "timecode,filename,watermark,username".parse().unwrap();
+    let burn_in = BurnIn::new(all, &policy, 25.0, "it's: take 1.mov", "Alice");
+    assert_eq!(burn_in.flags(), all);
+    let vf = burn_in.apply("scale=1920:-8");
+    assert!(vf.starts_with(&format!("movie='{}',format=rgba", wm.display())));
+    assert!(vf.contains("[in]scale=1920:-8,drawtext=timecode='00\\:00\\:00\\:00':rate=25:"));
+    assert!(vf.contains(r"text='it'\\\''s\: take 1.mov'"));
+    assert!(vf.contains("text='Alice'") && vf.ends_with("[base];[base][wm]overlay=W-w-20:H-h-20[out]"));
+
+    let tc_only = BurnIn::new("timecode".parse().unwrap(), &BurnInPolicy::default(), 29.97, "a.mov", "Alice");
+    assert_eq!(tc_only.apply("x"), "x,drawtext=timecode='00\\:00\\:00\\:00':rate=29.97:x=w-tw-20:y=20:fontsize=28:box=1:boxborderw=6:fontcolor=white:boxcolor=black@0.6");
+    assert!(BurnIn::new(BurnInFlags::default(), &policy, 25.0, "a.mov", "Alice").is_empty());
+}
@@ -0,0 +1,97 @@
+use super::metadata_reader::Metadata;
+
+// Sources that browsers can already play (H.264 video, AAC audio, in MP4, within bitrate limits)
+// don't need re-encoding, which for long pre-encoded deliveries saves hours of CPU. They're either
+// served as is, or if only the container is off (MKV, MOV, MP4 with its index at the end), their
+// streams are copied into a new MP4 ("remuxed") in seconds. If remuxing fails, the video is
+// transcoded after all.
+//
+// `RULES` are checked in order, and the first one that objects decides. Rules that call for
+// re-encoding come before ones that only call for remuxing.
+
+/// What to do to a source to make it playable in browsers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conversion {
+    /// Copy streams into an MP4 without re-encoding
+    Remux,
+    /// Re-encode
+    Transcode,
+}
+
+/// Max bitrate to serve a source at, compared to the transcode target
+const BITRATE_TOLERANCE: f32 = 1.2;
+
+/// A rule gets the source's metadata and target bitrate, and tells what's needed (and why), if anything
+type Rule = fn(&Metadata, u32) -> Option<(Conversion, String)>;
+
+const RULES: &[Rule] = &[
+    |md, _| (!md.orig_codec.eq_ignore_ascii_case("avc") && !md.orig_codec.eq_ignore_ascii_case("h264"))
+        .then(|| (Conversion::Transcode, format!("codec '{}' isn't playable in all browsers", md.orig_codec))),
+    |md, _| md.pixel_format.as_ref().filter(|pf| pf.as_str() != "yuv420p")
+        .map(|pf| (Conversion::Transcode, format!("pixel format '{pf}' isn't playable in all browsers"))),
+    |md, target| (md.bitrate as f32 > BITRATE_TOLERANCE * target as f32)
+        .then(|| (Conversion::Transcode, format!("bitrate is too high: old {} > new {}", md.bitrate, target_bitrate(md.bitrate, target)))),
+    |md, _| md.audio_codec.as_ref().filter(|c| !c.eq_ignore_ascii_case("aac"))
+        .map(|c| (Conversion::Transcode, format!("audio codec '{c}' isn't playable in all browsers"))),
+    |md, _| {
+        let ext = md.src_file.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+        (md.container != "MPEG-4" || !["mp4", "m4v"].contains(&ext.as_str()))
+            .then(|| (Conversion::Remux, format!("container '{}' isn't playable in all browsers", if md.container.is_empty() { &ext } else { &md.container })))
+    },
+    |md, _| (md.faststart == Some(false))
+        .then(|| (Conversion::Remux, "index is at the end of the file, so playback couldn't start before it's all downloaded".into())),
+];
+
+/// Bitrate to transcode a source to: target, but no more than the source, nor less than half of it
+pub fn target_bitrate(src_bitrate: u32, target_max_bitrate: u32) -> u32
+{
+    std::cmp::max(src_bitrate / 2, std::cmp::min(src_bitrate, target_max_bitrate))
+}
+
+/// Check if a source can be played in browsers as is, and if not, what it takes.
+///
+/// # Returns
+/// * `None` - Serve as is
+/// * `Some((conversion, reason))` - Convert first
+pub fn check(md: &Metadata, target_max_bitrate: u32) -> Option<(Conversion, String)>
+{
+    RULES.iter().find_map(|rule| rule(md, target_max_bitrate))
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_bypass_rules()
+{
+    let web_ready = Metadata {
+        src_file: "delivery.mp4".into(),
+        orig_codec: "AVC".into(),
+        bitrate: 2_000_000,
+        container: "MPEG-4".into(),
+        audio_codec: Some("AAC".into()),
+        pixel_format: Some("yuv420p".into()),
+        faststart: Some(true),
+        ..Default::default()
+    };
+    let conv = |md: Metadata| check(&md, 2_500_000).map(|(c, _)| c);
+    assert_eq!(check(&web_ready, 2_500_000), None);
+    assert_eq!(conv(Metadata { audio_codec: None, pixel_format: None, faststart: None, ..web_ready.clone() }), None);
+
+    assert_eq!(conv(Metadata { container: "Matroska".into(), src_file: "a.mkv".into(), ..web_ready.clone() }), Some(Conversion::Remux));
+    assert_eq!(conv(Metadata { container: "QuickTime".into(), src_file: "a.mov".into(), ..web_ready.clone() }), Some(Conversion::Remux));
+    assert_eq!(conv(Metadata { faststart: Some(false), ..web_ready.clone() }), Some(Conversion::Remux));
+
+    assert_eq!(conv(Metadata { orig_codec: "HEVC".into(), ..web_ready.clone() }), Some(Conversion::Transcode));
+    assert_eq!(conv(Metadata { pixel_format: Some("yuv422p10".into()), ..web_ready.clone() }), Some(Conversion::Transcode));
+    assert_eq!(conv(Metadata { audio_codec: Some("PCM".into()), ..web_ready.clone() }), Some(Conversion::Transcode));
+    assert_eq!(conv(Metadata { bitrate: 3_000_000, ..web_ready.clone() }), None);
+    let (c, reason) = check(&Metadata { bitrate: 8_000_000, ..web_ready.clone() }, 2_500_000).unwrap();
+    assert_eq!((c, reason.as_str()), (Conversion::Transcode, "bitrate is too high: old 8000000 > new 4000000"));
+
+    // Re-encoding wins over remuxing
+    assert_eq!(conv(Metadata { orig_codec: "ProRes".into(), container: "QuickTime".into(), ..web_ready }), Some(Conversion::Transcode));
+
+    assert_eq!(target_bitrate(8_000_000, 2_500_000), 4_000_000);
+    assert_eq!(target_bitrate(2_000_000, 2_500_000), 2_000_000);
+}
@@ -0,0 +1,156 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed}};
+use crossbeam_channel::{Sender, Receiver};
+
+use super::fair_queue;
+use super::sandbox::Sandbox;
+
+/// Max length (seconds) of an exported movie clip
+pub const MAX_CLIP_SECONDS: f32 = 300.0;
+/// Max length (seconds) of an exported animation (GIF/WebP). These get big fast.
+pub const MAX_ANIM_SECONDS: f32 = 30.0;
+/// Default and max width (pixels) of exported clips. Height follows aspect ratio.
+pub const DEFAULT_CLIP_WIDTH: u32 = 480;
+pub const MAX_CLIP_WIDTH: u32 = 1920;
+/// Frame rate of exported animations
+const ANIM_FPS: u32 = 12;
+
+/// Output format of an exported clip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipFormat { Mp4, Gif, Webp }
+
+impl ClipFormat {
+    pub fn ext(&self) -> &'static str {
+        match self { ClipFormat::Mp4 => "mp4", ClipFormat::Gif => "gif", ClipFormat::Webp => "webp" }
+    }
+
+    /// Max clip length for this format
+    pub fn max_seconds(&self) -> f32 {
+        match self { ClipFormat::Mp4 => MAX_CLIP_SECONDS, _ => MAX_ANIM_SECONDS }
+    }
+}
+
+impl std::str::FromStr for ClipFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mp4" => Ok(ClipFormat::Mp4),
+            "gif" => Ok(ClipFormat::Gif),
+            "webp" => Ok(ClipFormat::Webp),
+            _ => Err(format!("Unknown clip format '{s}'. Use mp4, gif or webp.")),
+        }
+    }
+}
+
+/// Request to render a time range of a video into a shareable file
+#[derive(Debug, Clone)]
+pub struct ClipRequest {
+    pub video_hash: String,
+    pub user_id: String,
+    /// Playable file of the video
+    pub src: PathBuf,
+    /// Where to write the clip (under the video's clips/ dir)
+    pub dst: PathBuf,
+    /// Download URL of `dst`, for notifying the user
+    pub url: String,
+    pub start: f32,
+    pub end: f32,
+    pub format: ClipFormat,
+    pub width: u32,
+    pub job_id: Option<i32>,
+}
+
+/// Result of a clip export. `error` is None on success.
+#[derive(Debug, Clone)]
+pub struct ClipResult {
+    pub req: ClipRequest,
+    pub error: Option<String>,
+}
+
+/// Build ffmpeg arguments for rendering a clip
+fn ffmpeg_args(req: &ClipRequest) -> Vec<OsString>
+{
+    let mut args: Vec<OsString> = ["-y", "-nostats", "-ss", &req.start.to_string(), "-t", &(req.end - req.start).to_string(), "-i"]
+        .iter().map(OsString::from).collect();
+    args.push(req.src.clone().into());
+    let scale = format!("scale={}:-2:flags=lanczos", req.width);
+    let rest = match req.format {
+        ClipFormat::Mp4 => vec!["-vf".into(), scale, "-c:v".into(), "libx264".into(), "-preset".into(), "fast".into(), "-crf".into(), "23".into(),
+            "-pix_fmt".into(), "yuv420p".into(), "-c:a".into(), "aac".into(), "-movflags".into(), "+faststart".into()],
+        // Two-pass palette for decent looking GIFs
+        ClipFormat::Gif => vec!["-filter_complex".into(), format!("fps={ANIM_FPS},{scale},split[a][b];[a]palettegen[p];[b][p]paletteuse"),
+            "-loop".into(), "0".into()],
+        ClipFormat::Webp => vec!["-vf".into(), format!("fps={ANIM_FPS},{scale}"), "-c:v".into(), "libwebp".into(),
+            "-q:v".into(), "70".into(), "-loop".into(), "0".into(), "-an".into()],
+    };
+    args.extend(rest.into_iter().map(OsString::from));
+    args.push(req.dst.clone().into());
+    args
+}
+
+/// Render a clip with ffmpeg. Removes partial output on failure.
+pub fn render_clip(req: &ClipRequest, sandbox: &Sandbox) -> Result<(), String>
+{
+    let out_dir = req.dst.parent().ok_or("Invalid destination")?;
+    std::fs::create_dir_all(out_dir).map_err(|e| format!("Failed to create clips dir: {e}"))?;
+    let mut cmd = sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[out_dir]);
+    cmd.args(ffmpeg_args(req));
+    tracing::info!(start=req.start, end=req.end, format=req.format.ext(), "Calling ffmpeg to export clip");
+    tracing::debug!("Exec: {:?}", cmd);
+    let res = match cmd.output() {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            tracing::error!(stderr=%stderr, "ffmpeg clip export failed");
+            Err(format!("FFMPEG exited with error: {}", stderr.lines().last().unwrap_or("")))
+        },
+        Err(e) => Err(format!("Failed to execute ffmpeg: {}", e)),
+    };
+    if res.is_err() && req.dst.exists() {
+        std::fs::remove_file(&req.dst).ok();
+    }
+    res
+}
+
+/// Listen to clip export requests and render them in a thread pool.
+/// Requests are scheduled fairly between users (see `fair_queue::run_fair_pool`).
+///
+/// # Arguments
+/// * `inq` - Channel to receive requests
+/// * `outq` - Channel to send results
+/// * `n_workers` - Number of worker threads
+/// * `depth` - Number of items waiting, for metrics
+/// * `sandbox` - Sandbox to run ffmpeg in
+/// * `priority_of` - Function that returns current processing priority of a user
+pub fn run_forever<P>(inq: Receiver<ClipRequest>, outq: Sender<ClipResult>, n_workers: Arc<AtomicUsize>, depth: Arc<AtomicUsize>, sandbox: Sandbox, terminate_flag: Arc<AtomicBool>, priority_of: P)
+    where P: Fn(&str) -> i32
+{
+    let _span = tracing::info_span!("CLIPS").entered();
+    tracing::info!(n_workers = n_workers.load(Relaxed), "Starting.");
+    fair_queue::run_fair_pool(inq, &n_workers, &depth, |r: &ClipRequest| r.user_id.clone(), priority_of, &terminate_flag, move |req: ClipRequest| {
+        let _span = tracing::info_span!("export_clip", video=%req.video_hash, user=%req.user_id).entered();
+        let error = render_clip(&req, &sandbox).err();
+        outq.send(ClipResult { req, error }).is_ok()
+    });
+    tracing::info!("Exiting.");
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_clip_ffmpeg_args()
+{
+    let mut req = ClipRequest {
+        video_hash: "abc".into(), user_id: "u".into(),
+        src: "/v/abc/video.mp4".into(), dst: "/v/abc/clips/x.gif".into(), url: "".into(),
+        start: 12.5, end: 15.0, format: "GIF".parse().unwrap(), width: 320, job_id: None };
+    let s = |a: Vec<OsString>| a.iter().map(|a| a.to_string_lossy().to_string()).collect::<Vec<_>>().join(" ");
+    assert_eq!(s(ffmpeg_args(&req)), "-y -nostats -ss 12.5 -t 2.5 -i /v/abc/video.mp4 -filter_complex \
+        fps=12,scale=320:-2:flags=lanczos,split[a][b];[a]palettegen[p];[b][p]paletteuse -loop 0 /v/abc/clips/x.gif");
+    req.format = ClipFormat::Mp4;
+    assert!(s(ffmpeg_args(&req)).contains("-c:v libx264"));
+    assert!("mov".parse::<ClipFormat>().is_err());
+    assert_eq!(ClipFormat::Webp.max_seconds(), MAX_ANIM_SECONDS);
+}
@@ -0,0 +1,174 @@
+use std::ffi::OsString;
+use std::path::Path;
+
+use super::sandbox::Sandbox;
+
+/// How to change the frame rate of a video
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConformMode {
+    /// Keep every frame and play them at the new rate. Duration changes; audio is sped up
+    /// or slowed down to match (with pitch correction). E.g. 23.976 as 24, or PAL 25 back to 24.
+    #[default]
+    Interpret,
+    /// Keep duration, drop or duplicate frames to reach the new rate. Audio is untouched.
+    Convert,
+}
+
+impl std::str::FromStr for ConformMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "interpret" | "retime" => Ok(ConformMode::Interpret),
+            "convert" => Ok(ConformMode::Convert),
+            _ => Err(format!("Unknown conform mode '{}'. Use interpret or convert.", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for ConformMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConformMode::Interpret => write!(f, "interpret"),
+            ConformMode::Convert => write!(f, "convert"),
+        }
+    }
+}
+
+/// Parse frame rate from "24", "23.976" or "24000/1001"
+pub fn parse_fps(s: &str) -> Result<f64, String>
+{
+    let s = s.trim();
+    let fps = match s.split_once('/') {
+        Some((n, d)) => n.trim().parse::<f64>().ok().zip(d.trim().parse::<f64>().ok())
+            .filter(|(_, d)| *d != 0.0).map(|(n, d)| n / d),
+        None => s.parse::<f64>().ok(),
+    };
+    match fps {
+        Some(f) if (1.0..=240.0).contains(&f) => Ok(f),
+        _ => Err(format!("Invalid frame rate '{}'", s)),
+    }
+}
+
+/// Check that a conform from `src_fps` to `dst_fps` makes sense
+pub fn validate(src_fps: f64, dst_fps: f64, mode: ConformMode) -> Result<(), String>
+{
+    if (src_fps - dst_fps).abs() < 0.0005 {
+        return Err(format!("Video is already {dst_fps} fps"));
+    }
+    // atempo handles 0.5x - 2x in one pass. Anything beyond that is not a conform.
+    let ratio = dst_fps / src_fps;
+    if mode == ConformMode::Interpret && !(0.5..=2.0).contains(&ratio) {
+        return Err(format!("Can't interpret {src_fps} fps as {dst_fps} fps (speed change too large)"));
+    }
+    Ok(())
+}
+
+/// Frame rate as an exact ffmpeg expression. NTSC rates (23.976, 29.97...) become N*1000/1001,
+/// so that long videos don't drift.
+pub(super) fn fps_expr(fps: f64) -> String
+{
+    let ntsc = (fps * 1.001).round();
+    if (fps - fps.round()).abs() > 0.001 && (fps * 1.001 - ntsc).abs() < 0.01 {
+        format!("{}/1001", ntsc as u64 * 1000)
+    } else {
+        format!("{}", (fps * 1000.0).round() / 1000.0)
+    }
+}
+
+/// Build ffmpeg arguments (after "ffmpeg") for changing frame rate of `src` from `src_fps` to `dst_fps`
+fn ffmpeg_args(src: &Path, src_fps: f64, dst_fps: f64, mode: ConformMode, has_audio: bool, dst: &Path) -> Vec<OsString>
+{
+    let mut args: Vec<OsString> = vec!["-nostats".into(), "-y".into(), "-i".into(), src.as_os_str().into(),
+        "-map".into(), "0:v:0".into()];
+    let rate = fps_expr(dst_fps);
+    match mode {
+        ConformMode::Interpret => {
+            args.extend(["-vf".into(), format!("setpts=PTS*{src_fps}/{dst_fps}").into(), "-r".into(), rate.into()]);
+            if has_audio {
+                args.extend(["-map".into(), "0:a:0".into(), "-af".into(), format!("atempo={}", dst_fps / src_fps).into()]);
+            }
+        },
+        ConformMode::Convert => {
+            args.extend(["-vf".into(), format!("fps={rate}").into()]);
+            if has_audio {
+                args.extend(["-map", "0:a:0"].map(OsString::from));
+            }
+        },
+    }
+    args.extend(["-c:v", "libx264", "-preset", "faster", "-crf", "18", "-pix_fmt", "yuv420p",
+        "-c:a", "aac", "-b:a", "192k",
+        "-movflags", "+faststart"].map(OsString::from));
+    args.push(dst.as_os_str().into());
+    args
+}
+
+/// Make a copy of a video with a different frame rate. Blocks until done.
+///
+/// # Arguments
+/// * `src` - Video file
+/// * `src_fps` - Frame rate to interpret the source as (usually its own)
+/// * `dst_fps` - New frame rate
+/// * `mode` - Interpret (retime) or convert
+/// * `has_audio` - Source has an audio track
+/// * `dst` - Output file (.mp4). Its directory must exist.
+/// * `sandbox` - Sandbox to run ffmpeg in
+pub fn conform(src: &Path, src_fps: f64, dst_fps: f64, mode: ConformMode, has_audio: bool, dst: &Path, sandbox: &Sandbox) -> Result<(), String>
+{
+    validate(src_fps, dst_fps, mode)?;
+    let out_dir = dst.parent().ok_or("Invalid destination")?;
+    let cmd = &mut sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[out_dir]);
+    cmd.args(ffmpeg_args(src, src_fps, dst_fps, mode, has_audio, dst));
+    tracing::info!(src=%src.display(), src_fps, dst_fps, mode=%mode, "Calling ffmpeg to conform frame rate");
+    tracing::debug!("Exec: {:?}", cmd);
+    match cmd.output() {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            tracing::error!(stderr=%stderr, "ffmpeg conform failed");
+            Err(format!("FFMPEG exited with error: {}", stderr.lines().last().unwrap_or("")))
+        },
+        Err(e) => Err(format!("Failed to execute ffmpeg: {}", e)),
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_conform_fps_parsing()
+{
+    assert_eq!(parse_fps("24"), Ok(24.0));
+    assert!((parse_fps("24000/1001").unwrap() - 23.976).abs() < 0.001);
+    assert!(parse_fps("0").is_err());
+    assert!(parse_fps("24/0").is_err());
+    assert!(parse_fps("fast").is_err());
+
+    assert_eq!(fps_expr(23.976), "24000/1001");
+    assert_eq!(fps_expr(29.97), "30000/1001");
+    assert_eq!(fps_expr(24.0), "24");
+    assert_eq!(fps_expr(12.5), "12.5");
+
+    assert_eq!("retime".parse::<ConformMode>(), Ok(ConformMode::Interpret));
+    assert!("stretch".parse::<ConformMode>().is_err());
+
+    assert!(validate(25.0, 24.0, ConformMode::Interpret).is_ok());
+    assert!(validate(24.0, 24.0, ConformMode::Convert).is_err());
+    assert!(validate(24.0, 60.0, ConformMode::Interpret).is_err());
+    assert!(validate(24.0, 60.0, ConformMode::Convert).is_ok());
+}
+
+#[test]
+fn test_conform_ffmpeg_args()
+{
+    let args = |mode, has_audio| ffmpeg_args(Path::new("/v/a.mp4"), 25.0, 24.0, mode, has_audio, Path::new("/up/x/out.mp4"))
+        .iter().map(|a| a.to_string_lossy().to_string()).collect::<Vec<_>>().join(" ");
+
+    let a = args(ConformMode::Interpret, true);
+    assert!(a.contains("-vf setpts=PTS*25/24 -r 24"));
+    assert!(a.contains("-map 0:a:0 -af atempo=0.96"));
+    assert!(!args(ConformMode::Interpret, false).contains("atempo"));
+
+    let a = args(ConformMode::Convert, true);
+    assert!(a.contains("-vf fps=24 -map 0:a:0 -c:v libx264"));
+    assert!(a.ends_with("/up/x/out.mp4"));
+}
@@ -0,0 +1,302 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::time::Duration;
+use crossbeam_channel::{Receiver, never, select, unbounded};
+use threadpool::ThreadPool;
+use tracing;
+
+/// How often `run_fair_pool` checks the terminate flag when idle
+const TERMINATE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Queue that schedules items between users fairly.
+///
+/// Items from the highest priority users are popped first. Between users of
+/// equal priority, items are popped in round-robin order, and each user's own
+/// items in FIFO order. This way one user submitting a large batch doesn't
+/// starve everyone else.
+pub struct FairQueue<T> {
+    rr_order: VecDeque<String>,
+    items: HashMap<String, VecDeque<T>>,
+}
+
+impl<T> FairQueue<T> {
+    pub fn new() -> Self {
+        FairQueue { rr_order: VecDeque::new(), items: HashMap::new() }
+    }
+
+    pub fn push(&mut self, user_id: &str, item: T) {
+        let q = self.items.entry(user_id.to_string()).or_default();
+        if q.is_empty() {
+            self.rr_order.push_back(user_id.to_string());
+        }
+        q.push_back(item);
+    }
+
+    /// Pop next item to process.
+    ///
+    /// # Arguments
+    /// * `priority_of` - Function that returns current priority of a user (higher first).
+    ///   Called on every pop, so priority changes also affect already queued items.
+    pub fn pop<P>(&mut self, priority_of: P) -> Option<T>
+        where P: Fn(&str) -> i32
+    {
+        let prios = self.rr_order.iter().map(|u| priority_of(u)).collect::<Vec<_>>();
+        let max_prio = *prios.iter().max()?;
+        let idx = prios.iter().position(|p| *p == max_prio)?;
+
+        let user_id = self.rr_order.remove(idx)?;
+        let q = self.items.get_mut(&user_id)?;
+        let item = q.pop_front();
+        if q.is_empty() {
+            self.items.remove(&user_id);
+        } else {
+            self.rr_order.push_back(user_id);   // Back of the line
+        }
+        item
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.values().map(|q| q.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rr_order.is_empty()
+    }
+}
+
+impl<T> Default for FairQueue<T> {
+    fn default() -> Self { Self::new() }
+}
+
+
+/// Receive items from `inq` and execute them on a pool of `n_workers` threads,
+/// scheduling between users with a `FairQueue`.
+///
+/// Items are held in the fair queue until a worker is free (instead of being
+/// handed to the thread pool's internal FIFO right away), so priorities
+/// and round-robin order are honored at the time work actually starts.
+///
+/// Exits when `inq` is closed, or when `exec` returns false (unrecoverable error).
+/// When `terminate_flag` is set, no more items are started, and it exits after
+/// the ones in progress have finished (graceful shutdown).
+/// Items still queued at exit are dropped.
+///
+/// If `inq` is bounded, at most its capacity of items are held in the fair queue. After that,
+/// items are left in `inq`, so senders get backpressure when it fills up (see `queues`).
+///
+/// # Arguments
+/// * `inq` - Channel to receive items from
+/// * `n_workers` - Number of worker threads. Can be changed while running: the pool is resized
+///   before next item is started, without interrupting the ones in progress.
+/// * `depth` - Updated with the number of items waiting (in fair queue and `inq`), for metrics
+/// * `user_of` - Function that returns the user ID of an item
+/// * `priority_of` - Function that returns current priority of a user
+/// * `terminate_flag` - Set on server shutdown
+/// * `exec` - Function that processes an item (in a worker thread). Returns false to abort.
+pub fn run_fair_pool<T, U, P, E>(inq: Receiver<T>, n_workers: &AtomicUsize, depth: &AtomicUsize, user_of: U, priority_of: P, terminate_flag: &AtomicBool, exec: E)
+    where T: Send + 'static,
+          U: Fn(&T) -> String,
+          P: Fn(&str) -> i32,
+          E: Fn(T) -> bool + Send + Sync + 'static
+{
+    let mut pool_size = n_workers.load(Relaxed).max(1);
+    let mut pool = ThreadPool::new(pool_size);
+    let exec = std::sync::Arc::new(exec);
+    let (done_tx, done_rx) = unbounded::<bool>();
+    let mut queue = FairQueue::new();
+    let mut n_busy = 0;
+    let max_queued = inq.capacity().unwrap_or(usize::MAX);
+
+    loop {
+        if terminate_flag.load(Relaxed) {
+            tracing::info!(n_running=n_busy, n_dropped=queue.len(), "Terminating. Waiting for items in progress.");
+            for _ in 0..n_busy { done_rx.recv().ok(); }
+            break;
+        }
+        if n_workers.load(Relaxed).max(1) != pool_size {
+            pool_size = n_workers.load(Relaxed).max(1);
+            tracing::info!(n_workers=pool_size, "Resizing worker pool.");
+            pool.set_num_threads(pool_size);
+        }
+        // Hand out work while there are free workers
+        while n_busy < pool_size && !queue.is_empty() {
+            if let Some(item) = queue.pop(&priority_of) {
+                n_busy += 1;
+                let (exec, done_tx) = (exec.clone(), done_tx.clone());
+                pool.execute(move || { done_tx.send(exec(item)).ok(); });
+            }
+        }
+        depth.store(queue.len() + inq.len(), Relaxed);
+        // Fair queue full? Leave new items in `inq` until there's room.
+        let inq_now = if queue.len() < max_queued { inq.clone() } else { never() };
+        select! {
+            recv(inq_now) -> msg => match msg {
+                Ok(item) => {
+                    let user_id = user_of(&item);
+                    queue.push(&user_id, item);
+                    tracing::debug!(user=user_id, queue_len=queue.len(), "Queued item.");
+                },
+                Err(e) => {
+                    tracing::info!(details=%e, "Input queue closed.");
+                    break;
+                }
+            },
+            recv(done_rx) -> res => {
+                n_busy -= 1;
+                if res != Ok(true) {
+                    tracing::error!("Worker reported an unrecoverable error. Aborting.");
+                    break;
+                }
+            },
+            default(TERMINATE_POLL_INTERVAL) => {}
+        }
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_fair_queue_round_robin()
+{
+    let mut q = FairQueue::new();
+    for i in 0..5 { q.push("hog", format!("hog{}", i)); }
+    q.push("alice", "alice0".to_string());
+    q.push("bob", "bob0".to_string());
+    q.push("alice", "alice1".to_string());
+    assert_eq!(q.len(), 8);
+
+    let order = std::iter::from_fn(|| q.pop(|_| 0)).collect::<Vec<_>>();
+    assert_eq!(order, vec!["hog0", "alice0", "bob0", "hog1", "alice1", "hog2", "hog3", "hog4"]);
+    assert!(q.is_empty());
+}
+
+#[test]
+fn test_fair_queue_priority()
+{
+    let mut q = FairQueue::new();
+    q.push("a", 1);
+    q.push("b", 2);
+    q.push("vip", 3);
+    q.push("a", 4);
+    q.push("vip", 5);
+
+    let prio = |u: &str| if u == "vip" { 10 } else { 0 };
+    let order = std::iter::from_fn(|| q.pop(prio)).collect::<Vec<_>>();
+    assert_eq!(order, vec![3, 5, 1, 2, 4]);
+}
+
+#[test]
+fn test_run_fair_pool()
+{
+    let (tx, rx) = unbounded::<(String, i32)>();
+    let (out_tx, out_rx) = unbounded::<i32>();
+    let (gate_tx, gate_rx) = unbounded::<()>();
+
+    // Single worker that waits for the gate before each item
+    let th = std::thread::spawn(move || {
+        run_fair_pool(rx, &AtomicUsize::new(1), &AtomicUsize::new(0), |(u, _)| u.clone(), |_| 0, &AtomicBool::new(false), move |(_, i)| {
+            gate_rx.recv().ok();
+            out_tx.send(i).is_ok()
+        });
+    });
+    for i in 0..4 { tx.send(("hog".into(), i)).unwrap(); }
+    tx.send(("other".into(), 100)).unwrap();
+
+    // Let the pool queue everything while first item is still blocked.
+    // Late comer gets its turn right after hog's next item, not after all of them.
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    for _ in 0..5 { gate_tx.send(()).unwrap(); }
+    let got = (0..5).map(|_| out_rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap()).collect::<Vec<_>>();
+    assert_eq!(got, vec![0, 1, 100, 2, 3]);
+
+    drop(tx);
+    th.join().unwrap();
+}
+
+#[test]
+fn test_run_fair_pool_terminate()
+{
+    let (tx, rx) = unbounded::<(String, i32)>();
+    let (out_tx, out_rx) = unbounded::<i32>();
+    let (gate_tx, gate_rx) = unbounded::<()>();
+    let terminate_flag = std::sync::Arc::new(AtomicBool::new(false));
+
+    let tf = terminate_flag.clone();
+    let th = std::thread::spawn(move || {
+        run_fair_pool(rx, &AtomicUsize::new(1), &AtomicUsize::new(0), |(u, _)| u.clone(), |_| 0, &tf, move |(_, i)| {
+            gate_rx.recv().ok();
+            out_tx.send(i).is_ok()
+        });
+    });
+    for i in 0..3 { tx.send(("user".into(), i)).unwrap(); }
+    std::thread::sleep(std::time::Duration::from_millis(200));
+
+    // Item in progress is finished, queued ones are not started, even though input is still open
+    terminate_flag.store(true, Relaxed);
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert!(!th.is_finished(), "Should wait for the item in progress");
+    gate_tx.send(()).unwrap();
+    th.join().unwrap();
+    assert_eq!(out_rx.try_iter().collect::<Vec<_>>(), vec![0]);
+}
+
+#[test]
+fn test_run_fair_pool_resize()
+{
+    let (tx, rx) = unbounded::<(String, i32)>();
+    let (started_tx, started_rx) = unbounded::<i32>();
+    let (gate_tx, gate_rx) = unbounded::<()>();
+    let n_workers = std::sync::Arc::new(AtomicUsize::new(1));
+
+    let nw = n_workers.clone();
+    let th = std::thread::spawn(move || {
+        run_fair_pool(rx, &nw, &AtomicUsize::new(0), |(u, _)| u.clone(), |_| 0, &AtomicBool::new(false), move |(_, i)| {
+            started_tx.send(i).ok();
+            gate_rx.recv().ok();
+            true
+        });
+    });
+    for i in 0..2 { tx.send(("user".into(), i)).unwrap(); }
+    assert_eq!(started_rx.recv_timeout(std::time::Duration::from_secs(5)), Ok(0));
+    assert!(started_rx.recv_timeout(std::time::Duration::from_millis(500)).is_err(), "Only one worker");
+
+    // Second item starts while the first one is still running
+    n_workers.store(2, Relaxed);
+    assert_eq!(started_rx.recv_timeout(std::time::Duration::from_secs(5)), Ok(1));
+
+    for _ in 0..2 { gate_tx.send(()).unwrap(); }
+    drop(tx);
+    th.join().unwrap();
+}
+
+#[test]
+fn test_run_fair_pool_backpressure()
+{
+    let (tx, rx) = crossbeam_channel::bounded::<(String, i32)>(2);
+    let (gate_tx, gate_rx) = unbounded::<()>();
+    let depth = std::sync::Arc::new(AtomicUsize::new(0));
+
+    let d = depth.clone();
+    let th = std::thread::spawn(move || {
+        run_fair_pool(rx, &AtomicUsize::new(1), &d, |(u, _)| u.clone(), |_| 0, &AtomicBool::new(false), move |_| {
+            gate_rx.recv().ok();
+            true
+        });
+    });
+    // One in progress, two in fair queue, two in channel. Then it's full.
+    for i in 0..5 {
+        tx.send_timeout(("user".into(), i), std::time::Duration::from_secs(5)).unwrap();
+    }
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert!(tx.try_send(("user".into(), 5)).is_err(), "Queue should be full");
+    assert_eq!(depth.load(Relaxed), 4);
+
+    // Room again when work is done
+    gate_tx.send(()).unwrap();
+    tx.send_timeout(("user".into(), 5), std::time::Duration::from_secs(5)).unwrap();
+
+    for _ in 0..5 { gate_tx.send(()).unwrap(); }
+    drop(tx);
+    th.join().unwrap();
+}
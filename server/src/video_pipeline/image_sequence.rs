@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use crossbeam_channel::Sender;
+use serde::Serialize;
+
+use super::{IncomingFile, DetailedMsg};
+use super::sandbox::Sandbox;
+use super::cleanup_rejected::clean_up_rejected_file;
+
+/// File extensions of still images that can form a sequence
+const FRAME_EXTENSIONS: &[&str] = &["exr", "dpx", "png", "tif", "tiff", "jpg", "jpeg"];
+
+/// Numbered image files in a directory, e.g. shot_1001.exr ... shot_1100.exr
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageSequence {
+    pub dir: PathBuf,
+    /// Filename before the frame number, e.g. "shot_"
+    pub prefix: String,
+    /// Extension as in filenames, e.g. "exr"
+    pub ext: String,
+    /// Zero-padded width of the frame number
+    pub digits: usize,
+    pub first_frame: u64,
+    pub last_frame: u64,
+}
+
+/// Reference to the sequence a video was assembled from. Stored (as JSON) with the video.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SequenceRef {
+    /// Where the frames are now. They are moved under the video's orig/ dir on ingest.
+    #[serde(skip)]
+    pub frames_dir: PathBuf,
+    pub pattern: String,
+    pub first_frame: u64,
+    pub last_frame: u64,
+    pub fps: f64,
+}
+
+impl ImageSequence {
+    /// Human readable pattern, e.g. "shot_####.exr"
+    pub fn pattern(&self) -> String {
+        format!("{}{}.{}", self.prefix, "#".repeat(self.digits), self.ext)
+    }
+
+    /// Pattern for ffmpeg's image2 demuxer, e.g. "shot_%04d.exr"
+    fn ffmpeg_pattern(&self) -> String {
+        format!("{}%0{}d.{}", self.prefix.replace('%', "%%"), self.digits, self.ext.replace('%', "%%"))
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.last_frame - self.first_frame + 1
+    }
+
+    /// Filename for the assembled movie, e.g. "shot.mp4"
+    pub fn movie_filename(&self) -> String {
+        let name = self.prefix.trim_end_matches(['_', '.', '-', ' ']);
+        let name = if name.is_empty() {
+            self.dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or("sequence".into())
+        } else { name.to_string() };
+        format!("{name}.mp4")
+    }
+
+    pub fn to_ref(&self, fps: f64) -> SequenceRef {
+        SequenceRef {
+            frames_dir: self.dir.clone(),
+            pattern: self.pattern(),
+            first_frame: self.first_frame,
+            last_frame: self.last_frame,
+            fps,
+        }
+    }
+}
+
+/// Split a filename into (prefix, frame number digits, extension) if it looks like a sequence frame
+fn split_frame_name(path: &Path) -> Option<(String, String, String)>
+{
+    let ext = path.extension()?.to_str()?;
+    if !FRAME_EXTENSIONS.contains(&ext.to_lowercase().as_str()) { return None; }
+    let stem = path.file_stem()?.to_str()?;
+    let prefix = stem.trim_end_matches(|c: char| c.is_ascii_digit());
+    let digits = &stem[prefix.len()..];
+    (!digits.is_empty()).then(|| (prefix.to_string(), digits.to_string(), ext.to_string()))
+}
+
+/// Check if a file looks like a frame of an image sequence (numbered still image)
+pub fn is_sequence_frame(path: &Path) -> bool
+{
+    split_frame_name(path).is_some()
+}
+
+/// Total size of sequence frames in a directory, or None if it doesn't contain a sequence
+/// (at least two numbered still images). Used to check if a dir is still being written to.
+pub fn sequence_dir_size(dir: &Path) -> Option<u64>
+{
+    let (n, size) = dir.read_dir().ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| is_sequence_frame(&e.path()))
+        .filter_map(|e| e.metadata().ok().filter(|m| m.is_file()))
+        .fold((0, 0), |(n, size), m| (n + 1, size + m.len()));
+    (n >= 2).then_some(size)
+}
+
+/// Find the image sequence in a directory. Other files are ignored.
+/// Fails if there is no sequence, more than one, or frames are missing in the middle.
+pub fn find_sequence(dir: &Path) -> Result<ImageSequence, String>
+{
+    let entries = dir.read_dir().map_err(|e| format!("Failed to read dir: {e}"))?;
+
+    // Group frames by (prefix, digits, extension)
+    let mut groups: HashMap<(String, usize, String), Vec<u64>> = HashMap::new();
+    for e in entries.filter_map(|e| e.ok()) {
+        if let Some((prefix, digits, ext)) = split_frame_name(&e.path()) {
+            if let Ok(n) = digits.parse::<u64>() {
+                groups.entry((prefix, digits.len(), ext)).or_default().push(n);
+            }
+        }
+    }
+    let mut groups = groups.into_iter().filter(|(_, frames)| frames.len() >= 2).collect::<Vec<_>>();
+    let ((prefix, digits, ext), mut frames) = match groups.len() {
+        0 => return Err("No image sequence found".into()),
+        1 => groups.remove(0),
+        n => return Err(format!("Found {n} image sequences. Upload one at a time.")),
+    };
+    frames.sort_unstable();
+    let seq = ImageSequence {
+        dir: dir.to_path_buf(), prefix, ext, digits,
+        first_frame: frames[0],
+        last_frame: frames[frames.len() - 1],
+    };
+    if seq.frame_count() != frames.len() as u64 {
+        return Err(format!("Frames missing from sequence '{}' (found {} of {})", seq.pattern(), frames.len(), seq.frame_count()));
+    }
+    Ok(seq)
+}
+
+/// Build ffmpeg arguments (after "ffmpeg") for assembling `seq` into a movie at `fps`
+fn ffmpeg_args(seq: &ImageSequence, fps: f64, dst: &Path) -> Vec<OsString>
+{
+    let mut args: Vec<OsString> = vec!["-nostats".into(), "-y".into(),
+        "-framerate".into(), super::conform::fps_expr(fps).into(),
+        "-start_number".into(), seq.first_frame.to_string().into()];
+    if seq.ext.eq_ignore_ascii_case("exr") {
+        // EXR is scene linear. Show it with sRGB curve, as compositing apps do by default.
+        args.extend(["-apply_trc", "iec61966_2_1"].map(OsString::from));
+    }
+    args.extend(["-i".into(), seq.dir.join(seq.ffmpeg_pattern()).into_os_string(),
+        "-frames:v".into(), seq.frame_count().to_string().into()]);
+    args.extend(["-vf", "scale=trunc(iw/2)*2:trunc(ih/2)*2",
+        "-c:v", "libx264", "-preset", "faster", "-crf", "16", "-pix_fmt", "yuv420p",
+        "-movflags", "+faststart"].map(OsString::from));
+    args.push(dst.as_os_str().into());
+    args
+}
+
+/// Assemble an image sequence into a review movie. Blocks until done.
+///
+/// # Arguments
+/// * `seq` - Sequence to assemble
+/// * `fps` - Frame rate of the movie
+/// * `dst` - Output file (.mp4). Its directory must exist.
+/// * `sandbox` - Sandbox to run ffmpeg in
+pub fn assemble(seq: &ImageSequence, fps: f64, dst: &Path, sandbox: &Sandbox) -> Result<(), String>
+{
+    let out_dir = dst.parent().ok_or("Invalid destination")?;
+    let cmd = &mut sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[out_dir]);
+    cmd.args(ffmpeg_args(seq, fps, dst));
+    tracing::info!(pattern=%seq.pattern(), frames=seq.frame_count(), fps, "Calling ffmpeg to assemble image sequence");
+    tracing::debug!("Exec: {:?}", cmd);
+    match cmd.output() {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            tracing::error!(stderr=%stderr, "ffmpeg sequence assembly failed");
+            Err(format!("FFMPEG exited with error: {}", stderr.lines().last().unwrap_or("")))
+        },
+        Err(e) => Err(format!("Failed to execute ffmpeg: {}", e)),
+    }
+}
+
+/// Assemble a directory of frames into a movie in a background thread. The frames are first moved
+/// to a new dir under upload/ (so that incoming monitor doesn't see them again), and the movie is
+/// written next to them. Result is sent to `res_tx` as a regular incoming file, with a reference
+/// to the sequence. On error, frames are moved to rejected/.
+///
+/// # Arguments
+/// * `file` - Incoming directory of frames
+/// * `data_dir` - Server data dir
+/// * `default_fps` - Frame rate to use if not given in `file`
+/// * `sandbox` - Sandbox to run ffmpeg in
+/// * `res_tx` - Channel to send the result to
+pub fn spawn_assemble(file: IncomingFile, data_dir: PathBuf, default_fps: f64, sandbox: Sandbox, res_tx: Sender<Result<IncomingFile, DetailedMsg>>)
+{
+    std::thread::spawn(move || {
+        let _span = tracing::info_span!("SEQUENCE", user=%file.user_id, dir=%file.file_path.display(), trace_id=file.trace_id.as_deref()).entered();
+        let fps = file.sequence_fps.unwrap_or(default_fps);
+        let new_dir = data_dir.join("upload").join(uuid::Uuid::new_v4().to_string());
+        let frames_dir = new_dir.join(file.file_path.file_name().unwrap_or_default());
+
+        let moved = std::fs::create_dir_all(&new_dir)
+            .and_then(|_| std::fs::rename(&file.file_path, &frames_dir))
+            .map_err(|e| format!("Failed to move frames: {e}"));
+        let res = moved.clone()
+            .and_then(|_| find_sequence(&frames_dir))
+            .and_then(|seq| {
+                let dst = new_dir.join(seq.movie_filename());
+                assemble(&seq, fps, &dst, &sandbox).map(|_| IncomingFile {
+                    file_path: dst,
+                    image_sequence: Some(seq.to_ref(fps)),
+                    ..file.clone()
+                })
+            });
+
+        if let Err(e) = &res {
+            tracing::info!(details=%e, "Image sequence assembly failed.");
+            let frames_now = if moved.is_ok() { &frames_dir } else { &file.file_path };
+            if let Err(e) = clean_up_rejected_file(&data_dir, frames_now, None) {
+                tracing::error!(details=%e, "Cleanup of image sequence failed.");
+            }
+            if let Err(e) = std::fs::remove_dir_all(&new_dir) {
+                tracing::warn!(details=%e, "Failed to remove sequence dir.");
+            }
+        }
+        let res = res.map_err(|e| DetailedMsg {
+            msg: "Image sequence assembly failed".into(),
+            details: e,
+            src_file: file.file_path.clone(),
+            user_id: file.user_id.clone() });
+        if let Err(e) = res_tx.send(res) {
+            tracing::error!(details=%e, "Failed to send assembled sequence to pipeline.");
+        }
+    });
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_image_sequence_detection()
+{
+    assert!(is_sequence_frame(Path::new("shot_1001.exr")));
+    assert!(is_sequence_frame(Path::new("plate.0001.DPX")));
+    assert!(!is_sequence_frame(Path::new("shot_final.exr")));
+    assert!(!is_sequence_frame(Path::new("clip_0001.mov")));
+
+    let dir = assert_fs::TempDir::new().unwrap();
+    let touch = |name: &str| std::fs::write(dir.path().join(name), b"x").unwrap();
+    assert!(find_sequence(dir.path()).is_err());
+
+    for n in 1001..=1010 { touch(&format!("shot_{n:04}.exr")); }
+    touch("notes.txt");
+    touch("slate_01.png");
+    let seq = find_sequence(dir.path()).unwrap();
+    assert_eq!(seq.pattern(), "shot_####.exr");
+    assert_eq!((seq.first_frame, seq.last_frame, seq.frame_count()), (1001, 1010, 10));
+    assert_eq!(seq.movie_filename(), "shot.mp4");
+    assert_eq!(sequence_dir_size(dir.path()), Some(11));
+
+    std::fs::remove_file(dir.path().join("shot_1005.exr")).unwrap();
+    assert!(find_sequence(dir.path()).unwrap_err().contains("found 9 of 10"));
+
+    touch("slate_02.png");
+    assert!(find_sequence(dir.path()).unwrap_err().contains("2 image sequences"));
+}
+
+#[test]
+fn test_image_sequence_ffmpeg_args()
+{
+    let seq = ImageSequence { dir: PathBuf::from("/up/x/plates"), prefix: "bg.".into(), ext: "exr".into(),
+        digits: 4, first_frame: 1001, last_frame: 1100 };
+    let a = ffmpeg_args(&seq, 23.976, Path::new("/up/x/bg.mp4"))
+        .iter().map(|a| a.to_string_lossy().to_string()).collect::<Vec<_>>().join(" ");
+    assert!(a.contains("-framerate 24000/1001 -start_number 1001 -apply_trc iec61966_2_1 -i /up/x/plates/bg.%04d.exr -frames:v 100"));
+    assert!(a.ends_with("/up/x/bg.mp4"));
+
+    let r = seq.to_ref(24.0);
+    assert_eq!(serde_json::to_value(&r).unwrap(), serde_json::json!({
+        "pattern": "bg.####.exr", "first_frame": 1001, "last_frame": 1100, "fps": 24.0 }));
+}
@@ -35,12 +35,9 @@ pub fn run_forever(
     loop {
         // Remove expired submissions
         let now = std::time::Instant::now();
-        submission_time.retain(|_, t| now.duration_since(t.clone()).as_secs_f32() < resubmit_delay);
+        submission_time.retain(|_, t| now.duration_since(*t).as_secs_f32() < resubmit_delay);
 
-        match exit_evt.recv_timeout(Duration::from_secs_f32(poll_interval)) {
-            Err(RecvTimeoutError::Disconnected) => { break; }
-            _ => {}
-        }
+        if let Err(RecvTimeoutError::Disconnected) = exit_evt.recv_timeout(Duration::from_secs_f32(poll_interval)) { break; }
         //tracing::trace!("Polling dir.");
         match incoming_dir.read_dir() {
             Ok(entries) => {
@@ -49,8 +46,16 @@ pub fn run_forever(
                     .filter_map(|entry| {
                         let entry = entry.ok()?;
                         let stat = entry.metadata().ok()?;
-                        stat.is_file().then(|| (entry.path(), stat.len()))
-                    }).collect::<Vec<_>>();
+                        if stat.is_dir() {
+                            // Directory of image sequence frames, e.g. shot_####.exr
+                            super::image_sequence::sequence_dir_size(&entry.path()).map(|sz| (entry.path(), sz))
+                        } else {
+                            stat.is_file().then(|| (entry.path(), stat.len()))
+                        }
+                    })
+                    // Subtitle files are sidecars, picked up when the video they belong to is ingested
+                    .filter(|(path, _)| !super::subtitles::is_subtitle_file(path))
+                    .collect::<Vec<_>>();
 
                 fn get_file_owner_name(path: &Path) -> anyhow::Result<String> {
                     path.owner()?.name()?.ok_or(anyhow!("Unnamed OS user for file {:?}", path))
@@ -75,7 +80,7 @@ pub fn run_forever(
                                         tracing::info!("Submitting for processing.");
                                         submission_time.insert(path.clone(), std::time::Instant::now());
                                         if let Err(e) = incoming_sender.send(
-                                                super::IncomingFile {file_path: path.clone(), user_id: owner}) {
+                                                super::IncomingFile {file_path: path.clone(), user_id: owner, ..Default::default()}) {
                                             tracing::error!(details=%e, "Failed to send incoming file to processing queue.");
                                         }
                                     },
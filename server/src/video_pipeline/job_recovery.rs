@@ -0,0 +1,199 @@
+use std::path::{Path, PathBuf};
+use crossbeam_channel::Sender;
+use tracing;
+
+use super::IncomingFile;
+use super::video_compressor::CmprInput;
+use super::transcode_presets::TranscodePresets;
+use crate::database::{DB, models};
+use crate::database::error::DBError;
+use crate::database::models::{job_stage, job_status};
+
+
+/// Resubmit pipeline jobs that were left pending or running by a previous server run
+/// (crash, restart), after removing any partial outputs they may have left behind.
+///
+/// Metadata jobs for files in the incoming dir are not resubmitted, as the incoming
+/// monitor will pick those files up again anyway.
+///
+/// # Arguments
+/// * `db` - Database
+/// * `incoming_dir` - Path to the incoming directory
+/// * `to_md` - Channel to the metadata reader
+/// * `cmpr_tx` - Channel to the video compressor
+/// * `presets` - Transcode presets, to resolve the one of a video's folder again
+///
+/// # Returns
+/// * Number of resubmitted jobs
+pub fn recover_unfinished_jobs(
+    db: &DB,
+    incoming_dir: &Path,
+    to_md: &Sender<IncomingFile>,
+    cmpr_tx: &Sender<CmprInput>,
+    presets: &TranscodePresets)
+        -> anyhow::Result<usize>
+{
+    let mut n_resubmitted = 0;
+    for job in db.get_unfinished_jobs()? {
+        let _span = tracing::info_span!("RECOVER_JOB", job_id=job.id, stage=%job.stage, video=?job.video_hash).entered();
+        match resubmit_job(db, &job, incoming_dir, to_md, cmpr_tx, presets) {
+            Ok(()) => {
+                tracing::info!("Resubmitted interrupted job.");
+                db.set_job_status(job.id, job_status::RUNNING, "Resubmitted after restart")?;
+                n_resubmitted += 1;
+            },
+            Err(reason) => {
+                tracing::warn!(reason=%reason, "Not resubmitting interrupted job.");
+                db.set_job_status(job.id, job_status::FAILED, &format!("Interrupted by restart. {}", reason))?;
+            }
+        }
+    }
+    Ok(n_resubmitted)
+}
+
+/// Clean up partial outputs of an interrupted job and send it back to the appropriate worker.
+/// Returns a reason string if the job can't (or shouldn't) be resubmitted.
+fn resubmit_job(
+    db: &DB,
+    job: &models::Job,
+    incoming_dir: &Path,
+    to_md: &Sender<IncomingFile>,
+    cmpr_tx: &Sender<CmprInput>,
+    presets: &TranscodePresets)
+        -> Result<(), String>
+{
+    let src = PathBuf::from(&job.src_file);
+    if !src.is_file() {
+        return Err(format!("Source file '{}' is gone.", src.display()));
+    }
+
+    match job.stage.as_str() {
+        job_stage::METADATA => {
+            if src.starts_with(incoming_dir) {
+                return Err("Incoming monitor will resubmit the file.".into());
+            }
+            let loudnorm = job.loudnorm.as_deref().and_then(|s| s.parse().ok()).unwrap_or_default();
+            to_md.send(IncomingFile { file_path: src, user_id: job.user_id.clone(), loudnorm, ..Default::default() })
+                .map_err(|e| format!("Failed to send to metadata reader: {}", e))
+        },
+        job_stage::TRANSCODE | job_stage::REMUX | job_stage::THUMBNAIL => {
+            let video_hash = job.video_hash.clone().ok_or("Job has no video hash.")?;
+            let dst = PathBuf::from(job.dst.clone().ok_or("Job has no destination.")?);
+            let (hdr_format, rotation, cfr_fps, audio_duration, folder_id) = match db.get_video(&video_hash) {
+                Ok(v) => (v.hdr_format, v.raw_metadata_all.as_deref()
+                    .and_then(|m| serde_json::from_str(m).ok())
+                    .map(|j| super::metadata_reader::parse_rotation(&j)).unwrap_or(0),
+                    // For VFR originals, `fps` is the constant rate being converted to
+                    v.vfr_fps_avg.and(v.fps.as_deref().and_then(|f| f.parse().ok())),
+                    v.duration.filter(|_| v.audio_only),
+                    v.folder_id),
+                Err(DBError::NotFound()) => { return Err("Video was deleted.".into()); },
+                Err(e) => { return Err(format!("DB error: {}", e)); },
+            };
+
+            // Remove partial outputs
+            let is_transcode = job.stage != job_stage::THUMBNAIL;
+            if is_transcode {
+                for f in [dst.clone(), dst.with_extension("progress").with_extension("pipe")] {
+                    if f.exists() {
+                        tracing::info!(file=%f.display(), "Removing partial output.");
+                        std::fs::remove_file(&f).map_err(|e| format!("Failed to remove partial output '{}': {}", f.display(), e))?;
+                    }
+                }
+            } else if dst.exists() {
+                tracing::info!(dir=%dst.display(), "Removing partial thumbnails.");
+                std::fs::remove_dir_all(&dst).map_err(|e| format!("Failed to remove partial thumbnails '{}': {}", dst.display(), e))?;
+            }
+
+            cmpr_tx.send(CmprInput {
+                src,
+                video_dst: if is_transcode { Some(dst.clone()) } else { None },
+                thumb_dir: if is_transcode { None } else { Some(dst) },
+                video_bitrate: job.video_bitrate.unwrap_or(0) as u32,
+                loudnorm_target: job.loudnorm.as_deref().and_then(|s| s.parse::<super::LoudnormOpt>().ok()).and_then(|l| l.resolve(None)),
+                hdr_format,
+                rotation,
+                cfr_fps,
+                audio_duration,
+                remux: job.stage == job_stage::REMUX,
+                preset: presets.resolve(db.get_folder_transcode_preset(folder_id).map_err(|e| format!("DB error: {}", e))?.as_deref()),
+                burn_in: job.burn_in.as_deref().map(serde_json::from_str).transpose().map_err(|e| format!("Invalid burn-in: {}", e))?.unwrap_or_default(),
+                video_hash,
+                user_id: job.user_id.clone(),
+                job_id: Some(job.id),
+                trace_id: None,
+            }).map_err(|e| format!("Failed to send to compressor: {}", e))
+        },
+        job_stage::EXPORT => Err("Clip exports are not resumed. User can request it again.".into()),
+        job_stage::PACKAGE => Err("Review package exports are not resumed. User can request it again.".into()),
+        job_stage::ANALYSIS => Err("Analyses are not resumed.".into()),
+        job_stage::SCENES => Err("Scene detections are not resumed.".into()),
+        other => Err(format!("Unknown job stage '{}'.", other)),
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::make_test_db;
+
+    #[test]
+    fn test_recover_unfinished_jobs()
+    {
+        let (db, data_dir, videos, _comments) = make_test_db();
+        let incoming_dir = data_dir.join("incoming");
+        let upload_dir = data_dir.join("upload");
+        let vid_dir = data_dir.join("videos").join(&videos[0].video_hash);
+        for d in [&incoming_dir, &upload_dir, &vid_dir] { std::fs::create_dir_all(d).unwrap(); }
+
+        let uploaded = upload_dir.join("upload.mov");
+        let incoming = incoming_dir.join("incoming.mov");
+        let orig = vid_dir.join("orig.mov");
+        let partial = vid_dir.join("transcoded_partial.mp4");
+        for f in [&uploaded, &incoming, &orig, &partial] { std::fs::write(f, "DATA").unwrap(); }
+
+        let mk_job = |stage: &str, src: &Path, dst: Option<&Path>, status: &str| {
+            db.add_job(&models::JobInsert {
+                stage: stage.into(),
+                status: status.into(),
+                user_id: "user.num1".into(),
+                video_hash: dst.map(|_| videos[0].video_hash.clone()),
+                src_file: src.to_string_lossy().into(),
+                dst: dst.map(|d| d.to_string_lossy().into()),
+                video_bitrate: dst.map(|_| 1000),
+                loudnorm: None,
+                burn_in: None,
+            }).unwrap()
+        };
+        let upload_job = mk_job(job_stage::METADATA, &uploaded, None, job_status::RUNNING);
+        let incoming_job = mk_job(job_stage::METADATA, &incoming, None, job_status::PENDING);
+        let missing_job = mk_job(job_stage::METADATA, &upload_dir.join("gone.mov"), None, job_status::RUNNING);
+        let transcode_job = mk_job(job_stage::TRANSCODE, &orig, Some(&partial), job_status::RUNNING);
+        let done_job = mk_job(job_stage::THUMBNAIL, &orig, Some(&vid_dir.join("thumbs")), job_status::DONE);
+
+        let (md_tx, md_rx) = crossbeam_channel::unbounded();
+        let (cmpr_tx, cmpr_rx) = crossbeam_channel::unbounded();
+        assert_eq!(recover_unfinished_jobs(&db, &incoming_dir, &md_tx, &cmpr_tx, &Default::default()).unwrap(), 2);
+
+        // Uploaded file goes back to metadata reader, incoming file is left for the monitor
+        assert_eq!(md_rx.try_recv().unwrap().file_path, uploaded);
+        assert!(md_rx.try_recv().is_err());
+        assert_eq!(db.get_job(upload_job).unwrap().status, job_status::RUNNING);
+        assert_eq!(db.get_job(incoming_job).unwrap().status, job_status::FAILED);
+        assert_eq!(db.get_job(missing_job).unwrap().status, job_status::FAILED);
+
+        // Partial transcode output was removed and transcode resubmitted
+        assert!(!partial.exists());
+        let req = cmpr_rx.try_recv().unwrap();
+        assert_eq!(req.job_id, Some(transcode_job));
+        assert_eq!(req.video_dst, Some(partial));
+        assert_eq!(req.video_bitrate, 1000);
+        assert!(cmpr_rx.try_recv().is_err());
+
+        // Finished jobs are left alone
+        assert_eq!(db.get_job(done_job).unwrap().status, job_status::DONE);
+    }
+}
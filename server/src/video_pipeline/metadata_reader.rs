@@ -1,17 +1,21 @@
-use std::{process::Command};
-use std::sync::atomic::Ordering;
-use threadpool::ThreadPool;
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed}};
 use serde_json;
-use crossbeam_channel::{Sender, Receiver, RecvError};
+use crossbeam_channel::{Sender, Receiver};
 use tracing;
 use rust_decimal::prelude::*;
-use std::sync::atomic::AtomicBool;
 
-use super::{IncomingFile, DetailedMsg};
+use super::{IncomingFile, DetailedMsg, fair_queue};
+use super::sandbox::Sandbox;
+use crate::database::models;
 
+/// Audio below this level (dB) is considered silence when detecting trim points
+const SILENCE_NOISE_DB: i32 = -50;
+/// Minimum length (seconds) of a silence to be considered for trimming
+const SILENCE_MIN_DURATION: f32 = 0.5;
 
-#[derive(Debug, Clone)]
+
+#[derive(Debug, Clone, Default)]
 pub struct Metadata {
     pub src_file: PathBuf,
     pub user_id: String,
@@ -21,6 +25,44 @@ pub struct Metadata {
     pub fps: Decimal,
     pub bitrate: u32,
     pub metadata_all: String,
+    pub silence_trim: Option<(f32, f32)>,
+    pub loudness_lufs: Option<f32>,
+    pub content_hash: Option<String>,
+    pub subtitles: Vec<super::subtitles::SubtitleTrack>,
+    /// Requested loudness normalization target (LUFS), resolved from upload options and server default
+    pub loudnorm_target: Option<f32>,
+    pub color_primaries: Option<String>,
+    pub color_transfer: Option<String>,
+    /// HDR format (see `models::hdr_format`), None for SDR
+    pub hdr_format: Option<String>,
+    /// Display rotation (clockwise degrees: 0, 90, 180 or 270), e.g. from phones shooting portrait
+    pub rotation: u32,
+    /// (min, max) frame rate if the video has variable frame rate (VFR). `fps` is the average then.
+    pub vfr_fps_range: Option<(f32, f32)>,
+    /// Constant frame rate to convert a VFR video to. Resolved from server config.
+    pub cfr_fps: Option<f32>,
+    /// Image sequence the file was assembled from, if any
+    pub image_sequence: Option<super::image_sequence::SequenceRef>,
+    /// File has no video track. `fps` and `total_frames` refer to the waveform video rendered from it.
+    pub audio_only: bool,
+    /// File is a still image or document (see `models::still_kind`), reviewed page by page
+    pub still_kind: Option<&'static str>,
+    /// Trace of the file's processing (see `telemetry`)
+    pub trace_id: Option<String>,
+    /// Number of pages of a still
+    pub page_count: u32,
+    /// Container format, as mediainfo names it (e.g. "MPEG-4", "QuickTime", "Matroska")
+    pub container: String,
+    /// Codec of the first audio track, if any
+    pub audio_codec: Option<String>,
+    /// Pixel format of the video track in ffmpeg's terms (e.g. "yuv420p", "yuv422p10"), if known
+    pub pixel_format: Option<String>,
+    /// Whether the index of an MP4/MOV file is at its start, so playback can begin before it's all downloaded
+    pub faststart: Option<bool>,
+    /// Folder to put the video in (from upload)
+    pub folder_id: Option<i32>,
+    /// Overlays to burn in (from upload), None for deployment's default
+    pub burn_in: Option<super::burn_in::BurnInFlags>,
 }
 
 pub type MetadataResult = Result<Metadata, DetailedMsg>;
@@ -29,14 +71,15 @@ pub type MetadataResult = Result<Metadata, DetailedMsg>;
 /// 
 /// # Arguments
 /// * `file_path` - Path to the file to be analyzed
-fn run_mediainfo( file: &PathBuf ) -> Result<serde_json::Value, String>
+/// * `sandbox` - Sandbox to run mediainfo in
+fn run_mediainfo( file: &PathBuf, sandbox: &Sandbox ) -> Result<serde_json::Value, String>
 {
     // Link to source file to a temporary file to avoid problems with
     // special characters in the path with mediainfo
     let uuid = uuid::Uuid::new_v4();
     let file_dir = file.parent().ok_or("Failed to get parent directory")?;
     let temp_dir = file_dir.join(uuid.to_string());
-    let link_path = temp_dir.join(format!("tempname"));
+    let link_path = temp_dir.join("tempname");
 
     // (symlink wasn't reliable on Windows WSL, so we'll use hard link instead)
     tracing::debug!("Creating temp hard link from {:?} to {:?}", file, link_path);
@@ -44,7 +87,7 @@ fn run_mediainfo( file: &PathBuf ) -> Result<serde_json::Value, String>
     std::fs::hard_link(file, &link_path).map_err(|e| e.to_string())?;
 
     // Run mediainfo
-    let cmd = &mut Command::new("mediainfo");
+    let cmd = &mut sandbox.command(&["mediainfo"], &[]);
     cmd.arg("--Output=JSON").arg("--").arg(&link_path);
     tracing::info!("Calling mediainfo");
     tracing::debug!("Exec: {:?}", cmd);
@@ -83,6 +126,54 @@ fn run_mediainfo( file: &PathBuf ) -> Result<serde_json::Value, String>
 }
 
 /// Parse mediainfo JSON output and return the metadata object.
+/// Detect HDR format from a mediainfo video track, or None if it looks like SDR.
+/// Dolby Vision is reported as such even if it has an HDR10 or HLG compatible base layer.
+fn detect_hdr_format(video_track: &serde_json::Value) -> Option<String>
+{
+    let field = |key: &str| video_track[key].as_str().unwrap_or_default().to_lowercase();
+    let (hdr, transfer) = (field("HDR_Format"), field("transfer_characteristics"));
+    if hdr.contains("dolby vision") {
+        Some(models::hdr_format::DOLBY_VISION.into())
+    } else if transfer.contains("pq") || transfer.contains("2084") || hdr.contains("2086") || hdr.contains("hdr10") {
+        Some(models::hdr_format::PQ.into())
+    } else if transfer.contains("hlg") || transfer.contains("b67") {
+        Some(models::hdr_format::HLG.into())
+    } else {
+        None
+    }
+}
+
+/// Make ffmpeg's name for the pixel format of a mediainfo video track (e.g. "yuv420p", "yuv422p10")
+fn parse_pixel_format(video_track: &serde_json::Value) -> Option<String>
+{
+    let space = video_track["ColorSpace"].as_str()?.to_lowercase();
+    let subsampling = video_track["ChromaSubsampling"].as_str()
+        .and_then(|s| s.split_whitespace().next()).unwrap_or_default().replace(':', "");
+    let depth = video_track["BitDepth"].as_str().and_then(|d| d.parse::<u32>().ok()).filter(|d| *d > 8);
+    Some(format!("{space}{subsampling}p{}", depth.map(|d| d.to_string()).unwrap_or_default()))
+}
+
+/// Get display rotation of the video track from mediainfo JSON, normalized to 0, 90, 180 or 270 degrees
+pub fn parse_rotation(mediainfo_json: &serde_json::Value) -> u32
+{
+    mediainfo_json["media"]["track"].as_array()
+        .and_then(|tracks| tracks.iter().find(|t| t["@type"] == "Video"))
+        .and_then(|t| t["Rotation"].as_str())
+        .and_then(|r| r.trim().parse::<f64>().ok())
+        .map(|r| ((r / 90.0).round() as i64).rem_euclid(4) as u32 * 90)
+        .unwrap_or(0)
+}
+
+/// Pick constant frame rate for converting a VFR video.
+///
+/// # Arguments
+/// * `avg_fps` - Average frame rate of the video
+/// * `configured` - Server setting, or None for auto (average rounded to a whole number, max 60)
+pub fn resolve_cfr_fps(avg_fps: f32, configured: Option<f32>) -> f32
+{
+    configured.unwrap_or_else(|| avg_fps.round().clamp(1.0, 60.0))
+}
+
 /// Possibly returned error message contains details to be sent to the client
 /// in the DetailedMsg struct.
 /// 
@@ -94,12 +185,40 @@ fn extract_variables<F>(json: serde_json::Value, args: &IncomingFile, get_file_s
     where F: FnOnce() -> Result<u64, String>
 {
     let tracks = json["media"]["track"].as_array().ok_or("No media tracks found")?;
-    let video_track = tracks.iter().find(|t| t["@type"] == "Video").ok_or("No video track found")?;
-    let fps = video_track["FrameRate"].as_str().ok_or("FPS not found")?;
-    let frame_count = video_track["FrameCount"].as_str().ok_or("FrameCount not found")?;
 
+    // Audio-only files (podcasts, music) are rendered as a waveform video at AUDIO_ONLY_FPS.
+    // Rest of the metadata is read from the audio track then.
+    let (video_track, audio_only) = match tracks.iter().find(|t| t["@type"] == "Video") {
+        Some(t) => (t, false),
+        None => (tracks.iter().find(|t| t["@type"] == "Audio").ok_or("No video or audio track found")?, true),
+    };
     let duration_str = video_track["Duration"].as_str().ok_or("Duration not found")?;
-    let duration = Decimal::from_str(duration_str).map_err(|_| format!("Invalid duration: {}", fps))?;
+    let duration = Decimal::from_str(duration_str).map_err(|_| format!("Invalid duration: {}", duration_str))?;
+
+    let (fps, frame_count) = if audio_only {
+        let frames = (duration.to_f32().unwrap_or(0.0) * super::AUDIO_ONLY_FPS).round() as u32;
+        (super::AUDIO_ONLY_FPS.to_string(), frames.to_string())
+    } else {
+        (video_track["FrameRate"].as_str().ok_or("FPS not found")?.to_string(),
+            video_track["FrameCount"].as_str().ok_or("FrameCount not found")?.to_string())
+    };
+    let fps = fps.as_str();
+
+    // Variable frame rate, e.g. screen recordings. "FrameRate" is the average then.
+    let vfr_fps_range = match video_track["FrameRate_Mode"].as_str() {
+        Some("VFR") => {
+            let avg = fps.parse::<f32>().map_err(|_| format!("Invalid FPS: {}", fps))?;
+            let num = |key: &str| video_track[key].as_str().and_then(|s| s.parse::<f32>().ok());
+            Some((num("FrameRate_Minimum").unwrap_or(avg), num("FrameRate_Maximum").unwrap_or(avg)))
+        },
+        _ => None,
+    };
+
+    let general = tracks.iter().find(|t| t["@type"] == "General").unwrap_or(&serde_json::Value::Null);
+    let container = match (general["Format"].as_str(), general["Format_Profile"].as_str()) {
+        (_, Some("QuickTime")) => "QuickTime",
+        (f, _) => f.unwrap_or_default(),
+    };
 
     // Bitrate is tricky. It might be in "BitRate" or "BitRate_Nominal". If it's not in either, we'll estimate it.
     let bitrate = {
@@ -116,63 +235,194 @@ fn extract_variables<F>(json: serde_json::Value, args: &IncomingFile, get_file_s
         src_file: args.file_path.clone(),
         user_id: args.user_id.clone(),
         total_frames: frame_count.parse().map_err(|e| format!("Error parsing frame count: {}", e))?,
-        duration: duration,
+        duration,
         orig_codec: video_track["Format"].as_str().ok_or("No codec found")?.to_string(),
         fps:  Decimal::from_str(fps).map_err(|_| format!("Invalid FPS: {}", fps))?,
-        bitrate: bitrate,
-        metadata_all: json.to_string()
+        bitrate,
+        metadata_all: json.to_string(),
+        silence_trim: None,
+        loudness_lufs: None,
+        content_hash: None,
+        subtitles: vec![],
+        loudnorm_target: None,
+        color_primaries: video_track["colour_primaries"].as_str().map(String::from),
+        color_transfer: video_track["transfer_characteristics"].as_str().map(String::from),
+        hdr_format: detect_hdr_format(video_track),
+        rotation: parse_rotation(&json),
+        vfr_fps_range,
+        cfr_fps: None,
+        image_sequence: args.image_sequence.clone(),
+        audio_only,
+        trace_id: args.trace_id.clone(),
+        container: container.into(),
+        audio_codec: tracks.iter().find(|t| t["@type"] == "Audio").and_then(|t| t["Format"].as_str()).map(String::from),
+        pixel_format: if audio_only { None } else { parse_pixel_format(video_track) },
+        faststart: general["IsStreamable"].as_str().map(|s| s == "Yes"),
+        folder_id: args.folder_id,
+        burn_in: args.burn_in,
+        ..Default::default()
     })
 }
 
-/// Run mediainfo and extract the metadata
-fn read_metadata_from_file(args: &IncomingFile) -> Result<Metadata, String>
+/// Run FFMPEG silencedetect and ebur128 (loudness) filters on the audio track and return their log (stderr)
+///
+/// # Arguments
+/// * `file` - Path to the file to be analyzed
+/// * `sandbox` - Sandbox to run ffmpeg in
+fn run_audio_analysis( file: &Path, sandbox: &Sandbox ) -> Result<String, String>
+{
+    let cmd = &mut sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[]);
+    cmd.arg("-nostats").arg("-i").arg(file)
+        .args(["-vn", "-af", &format!("silencedetect=noise={SILENCE_NOISE_DB}dB:d={SILENCE_MIN_DURATION},ebur128=framelog=verbose"), "-f", "null", "-"]);
+    tracing::info!("Calling ffmpeg for audio analysis");
+    tracing::debug!("Exec: {:?}", cmd);
+    match cmd.output() {
+        Ok(output) => {
+            if output.status.success() {
+                Ok(String::from_utf8_lossy(&output.stderr).to_string())
+            } else {
+                Err(format!("FFMPEG audio analysis exited with error: {}", String::from_utf8_lossy(&output.stderr)))
+            }
+        },
+        Err(e) => Err(format!("Failed to execute ffmpeg: {}", e))
+    }
+}
+
+/// Parse FFMPEG silencedetect log and return non-destructive trim points (in_sec, out_sec)
+/// that skip leading and trailing silence. Returns None if there's nothing to trim,
+/// or if the whole file is silent.
+///
+/// # Arguments
+/// * `log` - FFMPEG stderr output from silencedetect filter
+/// * `duration` - Duration of the media, in seconds
+fn parse_silence_trim(log: &str, duration: f32) -> Option<(f32, f32)>
 {
-    let json = run_mediainfo(&args.file_path)?;
-    extract_variables(json, args, || Ok(args.file_path.metadata().map_err(|e| format!("Failed to get file size: {:?}", e))?.len()))
+    // Silencedetect log lines look like this:
+    //    [silencedetect @ 0x5581a4d0] silence_start: 0
+    //    [silencedetect @ 0x5581a4d0] silence_end: 1.2345 | silence_duration: 1.2345
+    // If the file ends in silence, the last silence_start has no matching end.
+    fn value_after<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+        let rest = &line[line.find(key)? + key.len()..];
+        rest.split_whitespace().next()
+    }
+    let mut intervals: Vec<(f32, f32)> = vec![];
+    let mut cur_start: Option<f32> = None;
+    for line in log.lines() {
+        if let Some(v) = value_after(line, "silence_start:").and_then(|v| v.parse::<f32>().ok()) {
+            cur_start = Some(v.max(0.0));
+        } else if let Some(v) = value_after(line, "silence_end:").and_then(|v| v.parse::<f32>().ok()) {
+            if let Some(start) = cur_start.take() { intervals.push((start, v)); }
+        }
+    }
+    if let Some(start) = cur_start { intervals.push((start, duration)); }
+
+    const EPSILON: f32 = 0.05;
+    let trim_in = intervals.first().filter(|(s, _)| *s <= EPSILON).map(|(_, e)| *e).unwrap_or(0.0);
+    let trim_out = intervals.last().filter(|(_, e)| *e >= duration - EPSILON).map(|(s, _)| *s).unwrap_or(duration);
+
+    if trim_in >= trim_out || (trim_in <= 0.0 && trim_out >= duration) { None }
+    else { Some((trim_in, trim_out)) }
+}
+
+/// Parse integrated loudness (LUFS) from the summary of FFMPEG ebur128 filter log.
+///
+/// # Arguments
+/// * `log` - FFMPEG stderr output from ebur128 filter
+fn parse_integrated_loudness(log: &str) -> Option<f32>
+{
+    // Summary at the end of the log looks like this:
+    //    [Parsed_ebur128_1 @ 0x55d0c2a9c4c0] Summary:
+    //
+    //      Integrated loudness:
+    //        I:         -23.0 LUFS
+    //        Threshold: -33.0 LUFS
+    let mut lines = log.lines().skip_while(|l| !l.contains("Integrated loudness:"));
+    lines.find_map(|l| l.trim().strip_prefix("I:"))
+        .and_then(|v| v.split_whitespace().next())
+        .and_then(|v| v.parse::<f32>().ok())
+        .filter(|v| v.is_finite())
+}
+
+/// Run mediainfo and extract the metadata (stills are handled by `stills::read_metadata`), hash the file contents and extract embedded text subtitles.
+/// If the file has an audio track, and `trim_silence` is set or loudness normalization is requested,
+/// also measure loudness (and detect leading/trailing silence, if `trim_silence`).
+pub fn read_metadata_from_file(args: &IncomingFile, trim_silence: bool, loudness_target: Option<f32>, cfr_fps: Option<f32>, sandbox: &Sandbox) -> Result<Metadata, String>
+{
+    if let Some(kind) = super::stills::still_kind_of(&args.file_path) {
+        return super::stills::read_metadata(args, kind, sandbox);
+    }
+    let json = run_mediainfo(&args.file_path, sandbox)?;
+    let has_audio = json["media"]["track"].as_array()
+        .map(|tracks| tracks.iter().any(|t| t["@type"] == "Audio")).unwrap_or(false);
+    let subtitles = super::subtitles::extract_embedded(&args.file_path, &json, sandbox);
+
+    let mut md = extract_variables(json, args, || Ok(args.file_path.metadata().map_err(|e| format!("Failed to get file size: {:?}", e))?.len()))?;
+    md.subtitles = subtitles;
+    md.loudnorm_target = args.loudnorm.resolve(loudness_target);
+    if md.vfr_fps_range.is_some() {
+        md.cfr_fps = Some(resolve_cfr_fps(md.fps.to_f32().unwrap_or(0.0), cfr_fps));
+    }
+
+    // Content hash is only used for duplicate detection, so don't fail ingest if it can't be calculated
+    match super::calc_content_hash(&args.file_path) {
+        Ok(h) => { md.content_hash = Some(h); },
+        Err(e) => { tracing::warn!(details=%e, "Failed to calculate content hash. Duplicate detection disabled for this file."); }
+    }
+
+    if (trim_silence || md.loudnorm_target.is_some()) && has_audio {
+        // Audio analysis is optional, so don't fail the whole ingest if it doesn't work
+        match run_audio_analysis(&args.file_path, sandbox) {
+            Ok(log) => {
+                if trim_silence {
+                    md.silence_trim = parse_silence_trim(&log, md.duration.to_f32().unwrap_or(0.0));
+                }
+                md.loudness_lufs = parse_integrated_loudness(&log);
+            },
+            Err(e) => { tracing::warn!(details=e, "Audio analysis failed. Not offering trim points or normalizing loudness."); }
+        }
+    }
+    Ok(md)
 }
 
 /// Listens to inq for new videos to scan for metadata with Mediainfo shell command.
 /// When a new file is received, it is processed and the result is sent to outq.
 /// Starts a thread pool of `n_workers` workers to support simultaneous processing of multiple files.
+/// Files are scheduled fairly between users (see `fair_queue::run_fair_pool`).
 /// Exits when inq is closed or outq stops accepting messages.
 /// 
 /// # Arguments
 /// * `inq` - channel to receive new files to process
 /// * `outq` - channel to send results to
 /// * `n_workers` - number of threads to use for processing
-pub fn run_forever(inq: Receiver<IncomingFile>, outq: Sender<MetadataResult>, n_workers: usize)
+/// * `depth` - number of files waiting, for metrics
+/// * `trim_silence` - detect leading/trailing silence in audio and offer trim points (also measures loudness)
+/// * `loudness_target` - server default for audio loudness normalization (LUFS), or None. Can be overridden per file.
+/// * `cfr_fps` - frame rate to convert variable frame rate videos to, or None for auto (see `resolve_cfr_fps`)
+/// * `sandbox` - sandbox to run external tools in
+/// * `priority_of` - function that returns current processing priority of a user
+#[allow(clippy::too_many_arguments)]
+pub fn run_forever<P>(inq: Receiver<IncomingFile>, outq: Sender<MetadataResult>, n_workers: Arc<AtomicUsize>, depth: Arc<AtomicUsize>, trim_silence: bool, loudness_target: Option<f32>, cfr_fps: Option<f32>, sandbox: Sandbox, terminate_flag: Arc<AtomicBool>, priority_of: P)
+    where P: Fn(&str) -> i32
 {
     let _span = tracing::info_span!("MD").entered();
-    tracing::info!(n_workers = n_workers, "Starting.");
-
-    let pool = ThreadPool::new(n_workers);
-    let pool_is_healthy  = std::sync::Arc::new(AtomicBool::new(true));
-
-    while pool_is_healthy.load(Ordering::Relaxed) {
-        match inq.recv() {
-            Ok(args) => {
-                tracing::info!(file=%args.file_path.display(), user=args.user_id, "Scanning file.");
-                let pool_is_healthy = pool_is_healthy.clone();
-                let outq = outq.clone();
-                pool.execute(move || {
-                    if let Err(e) = outq.send(
-                        read_metadata_from_file(&args).map_err(|e| {
-                                DetailedMsg {
-                                    msg: "Metadata read failed".to_string(),
-                                    details: e,
-                                    src_file: args.file_path.clone(),
-                                    user_id: args.user_id.clone() }}))
-                    {
-                        tracing::error!(details=%e, "Result send failed! Aborting.");
-                        pool_is_healthy.store(false, Ordering::Relaxed);
-                    }});
-            },
-            Err(RecvError) => {
-                tracing::info!("Incoming queue closed.");
-                break;
-            }
+    tracing::info!(n_workers = n_workers.load(Relaxed), "Starting.");
+
+    fair_queue::run_fair_pool(inq, &n_workers, &depth, |args| args.user_id.clone(), priority_of, &terminate_flag, move |args| {
+        let _span = tracing::info_span!("read_metadata", trace_id=args.trace_id.as_deref()).entered();
+        tracing::info!(file=%args.file_path.display(), user=args.user_id, "Scanning file.");
+        if let Err(e) = outq.send(
+            read_metadata_from_file(&args, trim_silence, loudness_target, cfr_fps, &sandbox).map_err(|e| {
+                    DetailedMsg {
+                        msg: "Metadata read failed".to_string(),
+                        details: e,
+                        src_file: args.file_path.clone(),
+                        user_id: args.user_id.clone() }}))
+        {
+            tracing::error!(details=%e, "Result send failed! Aborting.");
+            return false;
         }
-    }
+        true
+    });
 
     tracing::info!("Exiting.");
 }
@@ -195,7 +445,8 @@ fn test_fixture(has_bitrate: bool, has_fps: bool) -> (IncomingFile, serde_json::
 
     let args = IncomingFile {
         file_path: PathBuf::from("test.mp4"),
-        user_id: "test_user".to_string()};
+        user_id: "test_user".to_string(),
+        ..Default::default()};
 
     (args, json)
 }
@@ -228,3 +479,133 @@ fn test_extract_variables_fail_missing_fps()
     assert!(metadata.is_err());
     assert!(metadata.unwrap_err().to_lowercase().contains("fps"));
 }
+
+#[test]
+fn test_parse_silence_trim()
+{
+    let log = r#"
+        [silencedetect @ 0x55d0c2a9c4c0] silence_start: 0
+        [silencedetect @ 0x55d0c2a9c4c0] silence_end: 1.5 | silence_duration: 1.5
+        [silencedetect @ 0x55d0c2a9c4c0] silence_start: 4.25
+        [silencedetect @ 0x55d0c2a9c4c0] silence_end: 5.0 | silence_duration: 0.75
+        [silencedetect @ 0x55d0c2a9c4c0] silence_start: 8.5
+        size=N/A time=00:00:10.00 bitrate=N/A speed= 512x"#;
+    assert_eq!(parse_silence_trim(log, 10.0), Some((1.5, 8.5)));
+
+    // Silence only in the middle => nothing to trim
+    let log = "[silencedetect @ 0x1] silence_start: 4.25\n[silencedetect @ 0x1] silence_end: 5.0 | silence_duration: 0.75";
+    assert_eq!(parse_silence_trim(log, 10.0), None);
+}
+
+#[test]
+fn test_parse_silence_trim_all_silent()
+{
+    let log = "[silencedetect @ 0x1] silence_start: 0\n";
+    assert_eq!(parse_silence_trim(log, 10.0), None);
+    assert_eq!(parse_silence_trim("", 10.0), None);
+}
+
+#[test]
+fn test_parse_integrated_loudness()
+{
+    let log = r#"
+        [Parsed_ebur128_1 @ 0x55d0c2a9c4c0] Summary:
+
+          Integrated loudness:
+            I:         -18.7 LUFS
+            Threshold: -28.9 LUFS
+
+          Loudness range:
+            LRA:         6.2 LU"#;
+    assert_eq!(parse_integrated_loudness(log), Some(-18.7));
+
+    // Silent files have no valid loudness
+    let log = "  Integrated loudness:\n    I:         -inf LUFS\n";
+    assert_eq!(parse_integrated_loudness(log), None);
+    assert_eq!(parse_integrated_loudness(""), None);
+}
+
+#[test]
+fn test_detect_hdr_format()
+{
+    let track = |hdr: &str, transfer: &str| serde_json::json!({"@type": "Video", "HDR_Format": hdr, "transfer_characteristics": transfer});
+    assert_eq!(detect_hdr_format(&track("", "PQ")), Some(models::hdr_format::PQ.into()));
+    assert_eq!(detect_hdr_format(&track("SMPTE ST 2086", "")), Some(models::hdr_format::PQ.into()));
+    assert_eq!(detect_hdr_format(&track("", "HLG")), Some(models::hdr_format::HLG.into()));
+    assert_eq!(detect_hdr_format(&track("Dolby Vision / SMPTE ST 2086", "PQ")), Some(models::hdr_format::DOLBY_VISION.into()));
+    assert_eq!(detect_hdr_format(&track("", "BT.709")), None);
+    assert_eq!(detect_hdr_format(&serde_json::json!({"@type": "Video"})), None);
+}
+
+#[test]
+fn test_parse_rotation()
+{
+    let rot = |r: &str| parse_rotation(&serde_json::json!({"media": {"track": [{"@type": "General"}, {"@type": "Video", "Rotation": r}]}}));
+    assert_eq!(rot("90.000"), 90);
+    assert_eq!(rot("-90"), 270);
+    assert_eq!(rot("180.0"), 180);
+    assert_eq!(rot("360"), 0);
+    assert_eq!(rot("garbage"), 0);
+    assert_eq!(parse_rotation(&serde_json::json!({})), 0);
+}
+
+#[test]
+fn test_extract_variables_vfr()
+{
+    let (args, mut json) = test_fixture(true, true);
+    let md = extract_variables(json.clone(), &args, || Ok(1000)).unwrap();
+    assert_eq!(md.vfr_fps_range, None);
+
+    let track = &mut json["media"]["track"][0];
+    track["FrameRate_Mode"] = "VFR".into();
+    track["FrameRate_Minimum"] = "5.000".into();
+    let md = extract_variables(json, &args, || Ok(1000)).unwrap();
+    assert_eq!(md.vfr_fps_range, Some((5.0, 30.0)));
+
+    assert_eq!(resolve_cfr_fps(17.4, None), 17.0);
+    assert_eq!(resolve_cfr_fps(143.9, None), 60.0);
+    assert_eq!(resolve_cfr_fps(17.4, Some(25.0)), 25.0);
+}
+
+#[test]
+fn test_extract_variables_audio_only()
+{
+    let (args, _) = test_fixture(true, true);
+    let json = serde_json::json!({"media": {"track": [
+        {"@type": "General", "Duration": "62.500"},
+        {"@type": "Audio", "Format": "MPEG Audio", "Duration": "62.500", "BitRate": "192000"}]}});
+    let md = extract_variables(json, &args, || Ok(1000)).unwrap();
+    assert!(md.audio_only);
+    assert_eq!(md.orig_codec, "MPEG Audio");
+    assert_eq!(md.bitrate, 192000);
+    assert_eq!(md.total_frames, 1563);
+    assert_eq!(md.fps, Decimal::from_str("25").unwrap());
+    assert_eq!(md.hdr_format, None);
+
+    let json = serde_json::json!({"media": {"track": [{"@type": "General"}, {"@type": "Text"}]}});
+    assert!(extract_variables(json, &args, || Ok(1000)).is_err());
+}
+
+#[test]
+fn test_extract_variables_formats()
+{
+    let (args, _) = test_fixture(true, true);
+    let json = serde_json::json!({"media": {"track": [
+        {"@type": "General", "Format": "MPEG-4", "Format_Profile": "QuickTime", "IsStreamable": "No"},
+        {"@type": "Video", "Format": "AVC", "Duration": "5.0", "FrameRate": "25", "FrameCount": "125", "BitRate": "1000",
+            "ColorSpace": "YUV", "ChromaSubsampling": "4:2:2", "BitDepth": "10"},
+        {"@type": "Audio", "Format": "AAC"}]}});
+    let md = extract_variables(json, &args, || Ok(1000)).unwrap();
+    assert_eq!(md.container, "QuickTime");
+    assert_eq!(md.audio_codec.as_deref(), Some("AAC"));
+    assert_eq!(md.pixel_format.as_deref(), Some("yuv422p10"));
+    assert_eq!(md.faststart, Some(false));
+
+    let track = serde_json::json!({"ColorSpace": "YUV", "ChromaSubsampling": "4:2:0 (Type 2)", "BitDepth": "8"});
+    assert_eq!(parse_pixel_format(&track).as_deref(), Some("yuv420p"));
+    assert_eq!(parse_pixel_format(&serde_json::json!({"BitDepth": "8"})), None);
+
+    let (args, json) = test_fixture(true, true);
+    let md = extract_variables(json, &args, || Ok(1000)).unwrap();
+    assert_eq!((md.container.as_str(), md.audio_codec, md.pixel_format, md.faststart), ("", None, None, None));
+}
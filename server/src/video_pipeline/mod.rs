@@ -6,12 +6,13 @@
 
 use std::io::Read;
 use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::thread;
-use std::path::{PathBuf, Path};
+use std::time::Duration;
+use std::path::{Component, PathBuf, Path};
 
 use crossbeam_channel;
-use crossbeam_channel::{Receiver, unbounded, select};
+use crossbeam_channel::{Receiver, unbounded, select, never};
 use rust_decimal::prelude::ToPrimitive;
 use tracing;
 
@@ -21,26 +22,172 @@ use hex;
 
 pub mod incoming_monitor;
 pub mod metadata_reader;
+pub mod sandbox;
+pub mod subtitles;
+pub mod stills;
+pub mod clip_export;
+pub mod review_package;
+pub mod analysis;
+pub mod scenes;
+pub mod stitcher;
+pub mod audio_mux;
+pub mod conform;
+pub mod image_sequence;
+pub mod preflight;
+pub mod scan;
+pub mod poster;
+pub mod queues;
+pub mod bypass;
+pub mod transcode_presets;
+pub mod burn_in;
 
 mod cleanup_rejected;
 mod video_compressor;
+mod job_recovery;
+mod fair_queue;
 
 use metadata_reader::MetadataResult;
 use crate::api_server::{UserMessage, UserMessageTopic};
 use crate::database::error::DBError;
 use cleanup_rejected::clean_up_rejected_file;
 use crate::database::{DB, models};
+use crate::database::models::{job_stage, job_status};
+use crate::storage::StorageError;
 
 pub const THUMB_SHEET_COLS: u32 = 10;
 pub const THUMB_SHEET_ROWS: u32 = 10;
 pub const THUMB_W: u32 = 160;
 pub const THUMB_H: u32 = 90;
 
+/// Frame rate of the waveform video made from audio-only files. Comments on them are timestamped at this precision.
+pub const AUDIO_ONLY_FPS: f32 = 25.0;
+/// Video bitrate for the waveform video. It's mostly flat color, so this is plenty.
+const AUDIO_ONLY_VIDEO_BITRATE: u32 = 500_000;
 
-#[derive (Clone, Debug)]
+
+/// Loudness target (LUFS) for `loudnorm`, when normalization is requested without a value
+pub const DEFAULT_LOUDNESS_TARGET: f32 = -23.0;
+
+/// Per-upload override of audio loudness normalization (server-wide setting is `--loudness-target`)
+#[derive (Clone, Copy, Debug, PartialEq, Default)]
+pub enum LoudnormOpt {
+    /// Use server setting
+    #[default]
+    ServerDefault,
+    Off,
+    /// Normalize to server target, or DEFAULT_LOUDNESS_TARGET if server has none
+    On,
+    /// Normalize to given integrated loudness (LUFS)
+    Target(f32),
+}
+
+impl LoudnormOpt {
+    /// Resolve to final loudness target (LUFS), or None if not normalizing
+    pub fn resolve(&self, server_target: Option<f32>) -> Option<f32> {
+        match self {
+            LoudnormOpt::ServerDefault => server_target,
+            LoudnormOpt::Off => None,
+            LoudnormOpt::On => Some(server_target.unwrap_or(DEFAULT_LOUDNESS_TARGET)),
+            LoudnormOpt::Target(t) => Some(*t),
+        }
+    }
+}
+
+impl std::str::FromStr for LoudnormOpt {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "default" => Ok(LoudnormOpt::ServerDefault),
+            "off" | "no" | "false" => Ok(LoudnormOpt::Off),
+            "on" | "yes" | "true" => Ok(LoudnormOpt::On),
+            v => match v.parse::<f32>() {
+                Ok(t) if (-70.0..=-5.0).contains(&t) => Ok(LoudnormOpt::Target(t)),
+                _ => Err(format!("Invalid loudness target '{}'. Use on, off or a LUFS value between -70 and -5.", s)),
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for LoudnormOpt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoudnormOpt::ServerDefault => write!(f, "default"),
+            LoudnormOpt::Off => write!(f, "off"),
+            LoudnormOpt::On => write!(f, "on"),
+            LoudnormOpt::Target(t) => write!(f, "{}", t),
+        }
+    }
+}
+
+/// Server-wide processing settings that decide what happens to a new video.
+/// Used by the API server to predict ingest results (see `preflight`).
+#[derive (Clone, Copy, Debug)]
+pub struct IngestPolicy {
+    pub target_bitrate: u32,
+    pub loudness_target: Option<f32>,
+    pub cfr_fps: Option<f32>,
+    /// Link an upload to an identical video shared with the user without asking (see `upload_dedup`)
+    pub auto_link_duplicates: bool,
+    /// Hours an upload identical to a shared video waits for user's choice before it's stored as a copy
+    pub dedup_window_hours: u32,
+}
+
+impl Default for IngestPolicy {
+    fn default() -> Self {
+        IngestPolicy { target_bitrate: 2_500_000, loudness_target: None, cfr_fps: None, auto_link_duplicates: false, dedup_window_hours: 24 }
+    }
+}
+
+#[derive (Clone, Debug, Default)]
 pub struct IncomingFile {
+    /// Video file, or a directory of image sequence frames
     pub file_path: PathBuf,
     pub user_id: String,
+    pub loudnorm: LoudnormOpt,
+    /// Frame rate for an image sequence, overriding server default
+    pub sequence_fps: Option<f64>,
+    /// Set when `file_path` is a movie assembled from an image sequence
+    pub image_sequence: Option<image_sequence::SequenceRef>,
+    /// Trace of the file's processing (see `telemetry`). New one is made if None.
+    pub trace_id: Option<String>,
+    /// Folder (of the user) to put the video in
+    pub folder_id: Option<i32>,
+    /// Overlays to burn in (see `burn_in`), None for deployment's default
+    pub burn_in: Option<burn_in::BurnInFlags>,
+}
+
+/// Export requests from API server, rendered in the background
+#[derive(Debug, Clone)]
+pub enum ExportRequest {
+    Clip(clip_export::ClipRequest),
+    Package(review_package::PackageRequest),
+}
+
+/// API server's end of the pipeline: where new work is sent, and how the pipeline is doing
+#[derive(Clone)]
+pub struct PipelineLink {
+    pub upload_tx: crossbeam_channel::Sender<IncomingFile>,
+    pub export_tx: crossbeam_channel::Sender<ExportRequest>,
+    pub queues: Arc<queues::QueueDepths>,
+    pub storage: Arc<crate::storage::StorageStatus>,
+}
+
+/// Pipeline's end of a `PipelineLink`
+pub struct PipelineIntake {
+    pub upload_rx: Receiver<IncomingFile>,
+    pub export_rx: Receiver<ExportRequest>,
+    pub queues: Arc<queues::QueueDepths>,
+    pub storage: Arc<crate::storage::StorageStatus>,
+}
+
+impl PipelineLink {
+    /// Connect API server to pipeline, with intake queues bounded by `queues` capacity
+    pub fn new(queues: Arc<queues::QueueDepths>, storage: Arc<crate::storage::StorageStatus>) -> (PipelineLink, PipelineIntake) {
+        let (upload_tx, upload_rx) = queues.channel::<IncomingFile>();
+        let (export_tx, export_rx) = queues.channel::<ExportRequest>();
+        (PipelineLink { upload_tx, export_tx, queues: queues.clone(), storage: storage.clone() },
+            PipelineIntake { upload_rx, export_rx, queues, storage })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -52,9 +199,39 @@ pub struct DetailedMsg {
 }
 
 
+/// Check that a file name from a client, or `orig_filename` from DB, is one plain path component,
+/// so joining it to a dir stays in that dir. Backslashes and drive letters (`C:`) are refused on all
+/// platforms, so Windows and UNC paths (`..\clip.mov`, `\\server\share\clip.mov`) are handled
+/// the same regardless of where the server runs.
+pub fn is_plain_file_name(name: &str) -> bool {
+    let mut comps = Path::new(name).components();
+    let drive = name.len() >= 2 && name.as_bytes()[0].is_ascii_alphabetic() && name.as_bytes()[1] == b':';
+    !name.trim().is_empty() && !name.contains('\\') && !drive
+        && matches!((comps.next(), comps.next()), (Some(Component::Normal(c)), None) if c == name)
+}
+
+/// Path of the playable file of an ingested video: transcoded one if there is one, otherwise the original.
+pub fn playable_file(v: &models::Video, videos_dir: &Path) -> Result<PathBuf, String> {
+    let dir = videos_dir.join(&v.video_hash);
+    match (&v.recompression_done, &v.orig_filename) {
+        (Some(_), _) => Ok(dir.join("video.mp4")),
+        (None, Some(f)) if !is_plain_file_name(f) => Err(format!("Video '{}' has a bad original filename {:?}", v.video_hash, f)),
+        (None, Some(f)) => Ok(dir.join("orig").join(f)),
+        (None, None) => Err(format!("Video '{}' has no playable file", v.video_hash)),
+    }
+}
+
+/// Check from stored mediainfo metadata if an ingested video has an audio track
+pub fn has_audio_track(v: &models::Video) -> bool {
+    v.raw_metadata_all.as_deref()
+        .and_then(|m| serde_json::from_str::<serde_json::Value>(m).ok())
+        .and_then(|j| j["media"]["track"].as_array().map(|t| t.iter().any(|t| t["@type"] == "Audio")))
+        .unwrap_or(false)
+}
+
 /// Calculate identifier ("video_hash") for the submitted video,
 /// based on filename, user_id, size and sample of the file contents.
-fn calc_video_hash(file_path: &PathBuf, user_id: &str) -> anyhow::Result<String> {
+pub fn calc_video_hash(file_path: &PathBuf, user_id: &str) -> anyhow::Result<String> {
     let mut file_hash = Sha256::new();
     let fname = file_path.file_name()
         .ok_or(anyhow!("Bad filename: {:?}", file_path))?.to_str()
@@ -62,7 +239,7 @@ fn calc_video_hash(file_path: &PathBuf, user_id: &str) -> anyhow::Result<String>
     
     file_hash.update(fname.as_bytes());
     file_hash.update(user_id.as_bytes());
-    file_hash.update(&file_path.metadata()?.len().to_be_bytes());
+    file_hash.update(file_path.metadata()?.len().to_be_bytes());
     
     // Read max 32k of contents
     let file = std::fs::File::open(file_path)?;
@@ -75,9 +252,310 @@ fn calc_video_hash(file_path: &PathBuf, user_id: &str) -> anyhow::Result<String>
     Ok(hash[0..8].to_string())
 }
 
+/// Calculate SHA-256 of the whole file ("content_hash"), for detecting byte-identical uploads.
+/// Unlike `calc_video_hash`, this doesn't depend on filename or uploader.
+pub fn calc_content_hash(file_path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(file_path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Update job status in the DB. Job persistence is only needed for crash recovery,
+/// so errors are logged but otherwise ignored.
+fn mark_job(db: &DB, job_id: Option<i32>, status: &str, details: &str) {
+    if let Some(job_id) = job_id {
+        if let Err(e) = db.set_job_status(job_id, status, details) {
+            tracing::error!(job_id=job_id, details=%e, "Failed to update job status in DB.");
+        }
+    }
+}
+
+/// Record a transcoding/thumbnailing request as a job in the DB (for crash recovery),
+/// and submit it to the video compressor.
+fn submit_cmpr_job(db: &DB, cmpr_tx: &crossbeam_channel::Sender<video_compressor::CmprInput>, mut req: video_compressor::CmprInput) -> anyhow::Result<()>
+{
+    let (stage, dst) = match (&req.video_dst, &req.thumb_dir) {
+        (Some(dst), _) => (if req.remux { job_stage::REMUX } else { job_stage::TRANSCODE }, dst),
+        (None, Some(dst)) => (job_stage::THUMBNAIL, dst),
+        (None, None) => bail!("BUG: compressor request has no destination"),
+    };
+    req.job_id = db.add_job(&models::JobInsert {
+            stage: stage.into(),
+            status: job_status::PENDING.into(),
+            user_id: req.user_id.clone(),
+            video_hash: Some(req.video_hash.clone()),
+            src_file: req.src.to_string_lossy().into(),
+            dst: Some(dst.to_string_lossy().into()),
+            video_bitrate: Some(req.video_bitrate as i32),
+            loudnorm: req.loudnorm_target.map(|t| LoudnormOpt::Target(t).to_string()),
+            burn_in: (!req.burn_in.is_empty()).then(|| serde_json::to_string(&req.burn_in)).transpose()?,
+        }).map_err(|e| tracing::error!(details=%e, "Failed to persist job. It won't be recovered after restart.")).ok();
+    let job_id = req.job_id;
+    cmpr_tx.send(req)?;
+    mark_job(db, job_id, job_status::RUNNING, "");
+    Ok(())
+}
+
+/// Record a clip export as a job in the DB, and submit it to the clip exporter.
+/// Exports are not resumed after a restart, the job is only for bookkeeping.
+fn submit_export_job(db: &DB, clip_tx: &crossbeam_channel::Sender<clip_export::ClipRequest>, mut req: clip_export::ClipRequest) -> anyhow::Result<()>
+{
+    req.job_id = db.add_job(&models::JobInsert {
+            stage: job_stage::EXPORT.into(),
+            status: job_status::PENDING.into(),
+            user_id: req.user_id.clone(),
+            video_hash: Some(req.video_hash.clone()),
+            src_file: req.src.to_string_lossy().into(),
+            dst: Some(req.dst.to_string_lossy().into()),
+            ..Default::default()
+        }).map_err(|e| tracing::error!(details=%e, "Failed to persist job.")).ok();
+    let job_id = req.job_id;
+    clip_tx.send(req)?;
+    mark_job(db, job_id, job_status::RUNNING, "");
+    Ok(())
+}
+
+/// Record a review package export as a job in the DB, and submit it to the packager.
+/// Packages are not resumed after a restart, the job is only for bookkeeping.
+fn submit_package_job(db: &DB, pkg_tx: &crossbeam_channel::Sender<review_package::PackageRequest>, mut req: review_package::PackageRequest) -> anyhow::Result<()>
+{
+    req.job_id = db.add_job(&models::JobInsert {
+            stage: job_stage::PACKAGE.into(),
+            status: job_status::PENDING.into(),
+            user_id: req.user_id.clone(),
+            video_hash: Some(req.video_hash.clone()),
+            src_file: req.files.first().map(|f| f.0.to_string_lossy().to_string()).unwrap_or_default(),
+            dst: Some(req.zip_path().to_string_lossy().into()),
+            ..Default::default()
+        }).map_err(|e| tracing::error!(details=%e, "Failed to persist job.")).ok();
+    let job_id = req.job_id;
+    pkg_tx.send(req)?;
+    mark_job(db, job_id, job_status::RUNNING, "");
+    Ok(())
+}
+
+/// Record an analysis as a job in the DB, and submit it to the analyzer.
+/// Analyses are not resumed after a restart, the job is only for bookkeeping.
+fn submit_analysis_job(db: &DB, tx: &crossbeam_channel::Sender<analysis::AnalysisRequest>, mut req: analysis::AnalysisRequest) -> anyhow::Result<()>
+{
+    req.job_id = db.add_job(&models::JobInsert {
+            stage: job_stage::ANALYSIS.into(),
+            status: job_status::PENDING.into(),
+            user_id: req.user_id.clone(),
+            video_hash: Some(req.video_hash.clone()),
+            src_file: req.src.to_string_lossy().into(),
+            ..Default::default()
+        }).map_err(|e| tracing::error!(details=%e, "Failed to persist job.")).ok();
+    let job_id = req.job_id;
+    tx.send(req)?;
+    mark_job(db, job_id, job_status::RUNNING, "");
+    Ok(())
+}
+
+/// Record a scene detection as a job in the DB, and submit it to the detector.
+/// Detections are not resumed after a restart, the job is only for bookkeeping.
+fn submit_scenes_job(db: &DB, tx: &crossbeam_channel::Sender<scenes::SceneRequest>, mut req: scenes::SceneRequest) -> anyhow::Result<()>
+{
+    req.job_id = db.add_job(&models::JobInsert {
+            stage: job_stage::SCENES.into(),
+            status: job_status::PENDING.into(),
+            user_id: req.user_id.clone(),
+            video_hash: Some(req.video_hash.clone()),
+            src_file: req.src.to_string_lossy().into(),
+            ..Default::default()
+        }).map_err(|e| tracing::error!(details=%e, "Failed to persist job.")).ok();
+    let job_id = req.job_id;
+    tx.send(req)?;
+    mark_job(db, job_id, job_status::RUNNING, "");
+    Ok(())
+}
+
+/// Record an incoming file as a metadata job in the DB (for crash recovery),
+/// and submit it to the metadata reader.
+fn submit_metadata_job(db: &DB, to_md: &crossbeam_channel::Sender<IncomingFile>, mut file: IncomingFile) -> anyhow::Result<()>
+{
+    file.trace_id.get_or_insert_with(crate::telemetry::new_trace_id);
+    let src = file.file_path.to_string_lossy().to_string();
+
+    // Incoming monitor may resubmit files that are still being processed. Don't duplicate jobs for those.
+    let job_id = match db.get_unfinished_job_ids_for_src(job_stage::METADATA, &src) {
+        Ok(ids) if !ids.is_empty() => ids.first().cloned(),
+        _ => db.add_job(&models::JobInsert {
+                stage: job_stage::METADATA.into(),
+                status: job_status::PENDING.into(),
+                user_id: file.user_id.clone(),
+                src_file: src,
+                loudnorm: Some(file.loudnorm.to_string()),
+                ..Default::default()
+            }).map_err(|e| tracing::error!(details=%e, "Failed to persist job. It won't be recovered after restart.")).ok()
+    };
+    to_md.send(file)?;
+    mark_job(db, job_id, job_status::RUNNING, "");
+    Ok(())
+}
+
+/// Add a finished transcode or thumbnail job to throughput statistics (for capacity planning).
+/// Queue wait is the time from job submission to finish, minus the time spent working on it.
+fn record_throughput(db: &DB, res: &video_compressor::CmprOutput)
+{
+    let Some(job) = res.job_id.and_then(|id| db.get_job(id).ok()) else { return; };
+    let video_secs = db.get_video(&res.video_hash).ok().and_then(|v| v.duration).unwrap_or(0.0) as f64;
+    let now = chrono::Utc::now().naive_utc();
+    let wait_secs = ((now - job.created).num_milliseconds() as f64 / 1000.0 - res.work_secs).max(0.0);
+    if let Err(e) = db.add_throughput(now, &job.stage, video_secs, res.work_secs, wait_secs) {
+        tracing::error!(details=%e, "Failed to record throughput.");
+    }
+}
+
+/// Store transcode attempts (settings, outcome and ffmpeg's stderr) in the job's history
+fn record_attempts(db: &DB, res: &video_compressor::CmprOutput)
+{
+    let Some(job_id) = res.job_id else { return; };
+    for (i, a) in res.attempts.iter().enumerate() {
+        let ins = models::JobAttemptInsert {
+            job_id,
+            attempt: i as i32 + 1,
+            settings: a.settings.clone(),
+            success: a.success,
+            stderr: a.stderr.clone(),
+        };
+        if let Err(e) = db.add_job_attempt(&ins) {
+            tracing::error!(details=%e, "Failed to record transcode attempt.");
+        }
+    }
+}
+
+/// Send a message to all admins
+fn notify_admins(db: &DB, user_msg_tx: &crossbeam_channel::Sender<UserMessage>, topic: UserMessageTopic, msg: &str, details: Option<String>)
+{
+    for admin in crate::storage::admin_ids(db) {
+        user_msg_tx.send(UserMessage { topic: topic.clone(), msg: msg.into(), details: details.clone(), user_id: Some(admin), video_hash: None })
+            .unwrap_or_else(|e| { tracing::error!("Error sending user message: {:?}", e); });
+    }
+}
+
+/// Mark storage full, pausing intake, and alert admins if it wasn't already
+fn report_storage_error(db: &DB, user_msg_tx: &crossbeam_channel::Sender<UserMessage>, storage: &crate::storage::StorageStatus, err: StorageError)
+{
+    if storage.report(err) {
+        notify_admins(db, user_msg_tx, UserMessageTopic::Error(), crate::storage::ADMIN_FULL_MSG, Some(err.to_string()));
+    }
+}
+
+/// Mark all unfinished metadata jobs for given source file as done or failed.
+fn finish_metadata_jobs(db: &DB, src_file: &Path, status: &str, details: &str) {
+    match db.get_unfinished_job_ids_for_src(job_stage::METADATA, &src_file.to_string_lossy()) {
+        Ok(ids) => { for id in ids { mark_job(db, Some(id), status, details); } },
+        Err(e) => { tracing::error!(details=%e, "Failed to get metadata jobs from DB."); }
+    }
+}
+
+/// If the processed file was part of an upload batch, record the result and
+/// notify user of the batch's aggregate progress (and completion, when all files are done).
+fn update_upload_batch(db: &DB, user_msg_tx: &crossbeam_channel::Sender<UserMessage>, src_file: &Path, user_id: &str, res: Result<&str, &str>)
+{
+    match crate::upload_batch::record_processing_result(db, src_file, res) {
+        Ok(None) => {},
+        Ok(Some((summary, just_finished))) => {
+            user_msg_tx.send(UserMessage {
+                topic: UserMessageTopic::Progress(),
+                msg: summary.progress_msg(),
+                details: None,
+                user_id: Some(user_id.to_string()),
+                video_hash: None
+            }).unwrap_or_else(|e| { tracing::error!("Error sending user message: {:?}", e); });
+            if just_finished {
+                let (msg, details) = summary.completion_msg();
+                user_msg_tx.send(UserMessage {
+                    topic: UserMessageTopic::Ok(),
+                    msg,
+                    details: Some(details),
+                    user_id: Some(user_id.to_string()),
+                    video_hash: None
+                }).unwrap_or_else(|e| { tracing::error!("Error sending user message: {:?}", e); });
+            }
+        },
+        Err(e) => { tracing::error!(details=%e, "Failed to update upload batch."); }
+    }
+}
+
+/// Send a new file to content scan (see `scan`), if there's a scanner and the file isn't being scanned
+/// already. Files that already have a metadata job were scanned before it (incoming monitor resubmits
+/// files while they're processed), and aren't scanned again.
+///
+/// # Returns
+/// * `Some(file)` - File doesn't need scanning
+/// * `None` - File is being scanned, result comes from `scan_tx`
+fn start_scan(db: &DB, scanner: Option<&scan::Scanner>, scanning: &mut std::collections::HashSet<PathBuf>, data_dir: &Path,
+    scan_tx: &crossbeam_channel::Sender<(PathBuf, Result<IncomingFile, DetailedMsg>)>, file: IncomingFile) -> Option<IncomingFile>
+{
+    let Some(scanner) = scanner else { return Some(file); };
+    if scanning.contains(&file.file_path) { return None; }
+    if db.get_unfinished_job_ids_for_src(job_stage::METADATA, &file.file_path.to_string_lossy()).is_ok_and(|ids| !ids.is_empty()) {
+        return Some(file);
+    }
+    scanning.insert(file.file_path.clone());
+    scan::spawn_scan(file, data_dir.to_path_buf(), scanner.clone(), scan_tx.clone());
+    None
+}
+
+/// Make a function that looks up user's processing priority from the DB, for worker pool scheduling.
+fn user_priority_lookup(db: &Arc<DB>) -> impl Fn(&str) -> i32 {
+    let db = db.clone();
+    move |user_id| db.get_user_priority(user_id).unwrap_or_else(|e| {
+        tracing::warn!(user=user_id, details=%e, "Failed to get user priority. Using default.");
+        0
+    })
+}
+
+/// Check user's storage quotas before ingesting a new video.
+/// Returns (error message, details) if ingesting would exceed them.
+fn check_ingest_quotas(db: &DB, videos_dir: &Path, quotas: &crate::quota::Quotas, md: &metadata_reader::Metadata) -> Result<(), (&'static str, String)>
+{
+    if quotas.is_unlimited() { return Ok(()); }
+    let usage = crate::quota::get_user_usage(db, videos_dir, &md.user_id)
+        .map_err(|e| ("Quota check failed", e.to_string()))?;
+    let file_size = md.src_file.metadata().map_err(|e| ("Quota check failed", e.to_string()))?.len();
+    quotas.check_new_file(&usage, file_size).map_err(|e| ("Quota exceeded", e))
+}
+
+/// Check if a video needs transcoding or remuxing (see `bypass`). Returns (conversion, reason, new bitrate) if it does.
+pub(crate) fn needs_conversion(md: &metadata_reader::Metadata, target_max_bitrate: u32, loudnorm_target: Option<f32>, burn_in: burn_in::BurnInFlags) -> Option<(bypass::Conversion, String, u32)> {
+    let new_bitrate = bypass::target_bitrate(md.bitrate, target_max_bitrate);
+    if md.still_kind.is_some() {
+        return None;
+    }
+    if md.audio_only {
+        return Some((bypass::Conversion::Transcode, "audio-only file is rendered as a waveform video".into(), AUDIO_ONLY_VIDEO_BITRATE));
+    }
+    let processing = {
+        if let Some(hdr) = &md.hdr_format { Some(format!("HDR ({}) is tone-mapped to SDR", hdr)) }
+        else if md.rotation != 0 { Some(format!("video is rotated {} degrees", md.rotation)) }
+        else if let (Some((min, max)), Some(cfr)) = (md.vfr_fps_range, md.cfr_fps) { Some(format!("variable frame rate ({}-{} fps) is converted to {} fps", min, max, cfr)) }
+        else if !burn_in.is_empty() { Some(format!("overlays are burned in ({})", burn_in)) }
+        else { loudnorm_target.map(|t| format!("audio loudness {:.1} LUFS is normalized to {} LUFS", md.loudness_lufs.unwrap_or(0.0), t)) }
+    };
+    match (bypass::check(md, target_max_bitrate), processing) {
+        (Some((bypass::Conversion::Transcode, reason)), _) => Some((bypass::Conversion::Transcode, reason, new_bitrate)),
+        (_, Some(reason)) => Some((bypass::Conversion::Transcode, reason, new_bitrate)),
+        (conv, None) => conv.map(|(c, reason)| (c, reason, new_bitrate)),
+    }
+}
+
+/// Normalize audio loudness? Only if requested and current loudness is known to be off target.
+pub(crate) fn effective_loudnorm_target(md: &metadata_reader::Metadata) -> Option<f32>
+{
+    const LOUDNORM_TOLERANCE_LU: f32 = 1.0;
+    md.loudnorm_target.filter(|t|
+        md.loudness_lufs.map(|l| (l - t).abs() > LOUDNORM_TOLERANCE_LU).unwrap_or(false))
+}
+
 /// Process new video after metadata reader has finished.
 /// Move the file to the appropriate directory, and update the database.
 /// See if the video is a duplicate, and submit it for transcoding if necessary.
+///
+/// Returns hash of the video the file ended up as. For duplicates, that's the pre-existing video.
+#[allow(clippy::too_many_arguments)]
 fn ingest_video(
         vh: &str,
         md: &metadata_reader::Metadata,
@@ -86,12 +564,17 @@ fn ingest_video(
         target_bitrate: u32,
         db: &DB,
         user_msg_tx: &crossbeam_channel::Sender<UserMessage>,
-        cmpr_tx: &crossbeam_channel::Sender<video_compressor::CmprInput>)
-            -> anyhow::Result<bool>
+        cmpr_tx: &crossbeam_channel::Sender<video_compressor::CmprInput>,
+        analysis_tx: Option<&crossbeam_channel::Sender<analysis::AnalysisRequest>>,
+        scenes_tx: Option<&crossbeam_channel::Sender<scenes::SceneRequest>>,
+        config: &crate::config::ReloadableConfig)
+            -> anyhow::Result<String>
 {
+    let (presets, burn_in_policy) = (&config.transcode_presets, &config.burn_in);
     let _span = tracing::info_span!("INGEST_VIDEO",
         vh = %vh,
         user=md.user_id,
+        trace_id=md.trace_id.as_deref(),
         filename=%md.src_file.file_name().unwrap_or_default().to_string_lossy()).entered();
 
     tracing::info!(file=%md.src_file.display(), "Ingesting file.");
@@ -99,13 +582,29 @@ fn ingest_video(
     let src = PathBuf::from(&md.src_file);
     if !src.is_file() { bail!("Source file not found: {:?}", src) }
 
-    let dir_for_video = videos_dir.join(&vh);
+    let dir_for_video = videos_dir.join(vh);
     tracing::debug!("Video dir = {:?}", dir_for_video);
 
+    // Same video in user's trash? (Its dir is moved away, so check DB.)
+    if db.get_video(vh).is_ok_and(|v| v.trashed.is_some() && v.added_by_userid.as_ref() == Some(&md.user_id)) {
+        tracing::info!("Video is in user's trash.");
+        user_msg_tx.send(UserMessage {
+            topic: UserMessageTopic::Ok(),
+            msg: "This video is in your trash. Restore it instead.".to_string(),
+            details: Some(serde_json::json!({ "trashed": vh }).to_string()),
+            user_id: Some(md.user_id.clone()),
+            video_hash: None  // Don't pass video hash here, otherwise the pre-existing video would be deleted!
+        }).ok();
+        clean_up_rejected_file(data_dir, &src, Some(vh.into())).unwrap_or_else(|e| {
+            tracing::error!(details=?e, "Cleanup failed.");
+        });
+        return Ok(vh.to_string());
+    }
+
     // Video already exists on disk?
     if dir_for_video.exists() {
         tracing::debug!("Video dir already exists.");
-        match db.get_video(&vh) {
+        match db.get_video(vh) {
             Ok(v) => {
                 let new_owner = &md.user_id;
                 if v.added_by_userid == Some(new_owner.clone()) {
@@ -119,11 +618,11 @@ fn ingest_video(
                         video_hash: None  // Don't pass video hash here, otherwise the pre-existing video would be deleted!
                     }).ok();
 
-                    clean_up_rejected_file(&data_dir, &src, Some(vh.into())).unwrap_or_else(|e| {
+                    clean_up_rejected_file(data_dir, &src, Some(vh.into())).unwrap_or_else(|e| {
                         tracing::error!(details=?e, "Cleanup failed.");
                     });
 
-                    return Ok(false);
+                    return Ok(vh.to_string());
                 } else {
                     bail!("Hash collision?!? Video '{vh}' already owned by another user '{new_owner}'.")
                 }
@@ -140,13 +639,32 @@ fn ingest_video(
     }
     assert!(!dir_for_video.exists()); // Should have been deleted above
 
+    // Byte-identical to a video user already has (under a different name)?
+    // Point them to the existing one instead of storing and transcoding it again.
+    if let Some(content_hash) = &md.content_hash {
+        if let Some(existing) = db.get_user_videos_by_content_hash(&md.user_id, content_hash)?.into_iter().next() {
+            tracing::info!(existing=existing.video_hash, "User already has an identical video.");
+            user_msg_tx.send(UserMessage {
+                topic: UserMessageTopic::Ok(),
+                msg: format!("You already have this video, as '{}'", existing.title.as_deref().unwrap_or(&existing.video_hash)),
+                details: Some(serde_json::json!({ "duplicate_of": existing.video_hash }).to_string()),
+                user_id: Some(md.user_id.clone()),
+                video_hash: None  // Don't pass video hash here, otherwise the pre-existing video would be deleted!
+            }).ok();
+            clean_up_rejected_file(data_dir, &src, Some(vh.into())).unwrap_or_else(|e| {
+                tracing::error!(details=?e, "Cleanup failed.");
+            });
+            return Ok(existing.video_hash);
+        }
+    }
+
     // Move src file to orig/
     tracing::debug!(dir=%dir_for_video.display(), "Creating video hash dir.");
     std::fs::create_dir(&dir_for_video)?;
 
     let dir_for_orig = dir_for_video.join("orig");
     std::fs::create_dir(&dir_for_orig)?;
-    let src_moved = dir_for_orig.join(src.file_name().ok_or(anyhow!("Bad filename: {:?}", src))?);
+    let src_moved = dir_for_orig.join(src.file_name().filter(|f| f.to_str().is_some_and(is_plain_file_name)).ok_or(anyhow!("Bad filename: {:?}", src))?);
 
     tracing::debug!("Moving '{}' to '{}'", src.display(), src_moved.display());
     std::fs::rename(&src, &src_moved)?;
@@ -154,6 +672,25 @@ fn ingest_video(
 
     let orig_filename = src.file_name().ok_or(anyhow!("Bad filename: {:?}", src))?.to_string_lossy().into_owned();
 
+    // Keep the frames of an image sequence with the movie assembled from them
+    if let Some(seq) = &md.image_sequence {
+        tracing::debug!("Moving image sequence frames '{}' to orig/", seq.frames_dir.display());
+        std::fs::rename(&seq.frames_dir, dir_for_orig.join("frames"))?;
+        if let Some(p) = seq.frames_dir.parent() {
+            std::fs::remove_dir(p).unwrap_or_else(|e| { tracing::warn!(details=%e, "Failed to remove sequence dir."); });
+        }
+    }
+
+    let loudnorm_target = effective_loudnorm_target(md);
+
+    // VFR videos are converted to constant frame rate, so store the new rate and frame count.
+    // Stills have neither.
+    let (fps, total_frames) = match (md.still_kind, md.cfr_fps) {
+        (Some(_), _) => (None, None),
+        (None, Some(cfr)) => (Some(cfr.to_string()), Some((md.duration.to_f32().unwrap_or(0.0) * cfr).round() as i32)),
+        (None, None) => (Some(md.fps.to_string()), Some(md.total_frames as i32)),
+    };
+
     // Add to DB
     tracing::info!("Adding video to DB.");
     db.add_video(&models::VideoInsert {
@@ -163,57 +700,151 @@ fn ingest_video(
         recompression_done: None,
         thumb_sheet_dims: None,
         orig_filename: Some(orig_filename.clone()),
-        title: Some(orig_filename),
-        total_frames: Some(md.total_frames as i32),
-        duration: md.duration.to_f32(),
-        fps: Some(md.fps.to_string()),
+        title: Some(orig_filename.clone()),
+        total_frames,
+        duration: if md.still_kind.is_some() { None } else { md.duration.to_f32() },
+        fps,
         raw_metadata_all: Some(md.metadata_all.clone()),
+        silence_trim_start: md.silence_trim.map(|(s, _)| s),
+        silence_trim_end: md.silence_trim.map(|(_, e)| e),
+        loudness_lufs: md.loudness_lufs,
+        content_hash: md.content_hash.clone(),
+        loudnorm_target,
+        color_primaries: md.color_primaries.clone(),
+        color_transfer: md.color_transfer.clone(),
+        hdr_format: md.hdr_format.clone(),
+        vfr_fps_min: md.vfr_fps_range.map(|(min, _)| min),
+        vfr_fps_max: md.vfr_fps_range.map(|(_, max)| max),
+        vfr_fps_avg: md.vfr_fps_range.and(md.fps.to_f32()),
+        image_sequence: md.image_sequence.as_ref().map(serde_json::to_string).transpose()?,
+        audio_only: md.audio_only,
+        still_kind: md.still_kind.map(String::from),
+        page_count: md.still_kind.map(|_| md.page_count as i32),
     })?;
+    if let Some(fid) = md.folder_id {
+        if let Err(e) = db.set_video_folder(vh, Some(fid)) {
+            tracing::error!(details=%e, folder_id=fid, "Failed to put video in folder.");
+        }
+    }
+    // Let production tracking rename and file it
+    if let Some(org) = &config.organizer {
+        if let Err(e) = org.apply_video_ingested(db, vh) {
+            tracing::warn!(details=%e, "Organizer failed on new video. Keeping defaults.");
+        }
+    }
 
-    // Check if it needs recompressing
-    fn needs_transcoding(md: &metadata_reader::Metadata, target_max_bitrate: u32) -> Option<(String, u32)> {
-        let new_bitrate = std::cmp::max(md.bitrate/2, std::cmp::min(md.bitrate, target_max_bitrate));
-        let ext = md.src_file.extension().unwrap_or(std::ffi::OsStr::new("")).to_string_lossy().to_lowercase();
-        {
-            let bitrate_fine = (new_bitrate >= md.bitrate || (md.bitrate as f32) <= 1.2 * (target_max_bitrate as f32));
-            let codec_fine = ["h264", "avc", "hevc", "h265"].contains(&md.orig_codec.to_lowercase().as_str());
-            let container_fine = ["mp4", "mkv"].contains(&ext.as_str());        
-    
-            if !container_fine { Some(format!("container '{}' not supported", md.src_file.extension().unwrap_or_default().to_string_lossy())) }
-            else if !codec_fine { Some(format!("codec '{}' not supported", md.orig_codec)) }
-            else if !bitrate_fine { Some(format!("bitrate is too high: old {} > new {}", md.bitrate, new_bitrate)) }
-            else { None }
-        }.map(|reason| (reason, new_bitrate) )
+    // Subtitles: tracks embedded in the video, and sidecar files uploaded with it
+    let in_upload_dir = src.parent().and_then(|p| p.parent()) == Some(data_dir.join("upload").as_path());
+    let mut sub_tracks = md.subtitles.clone();
+    for sidecar in subtitles::find_sidecars(&src, in_upload_dir) {
+        match subtitles::read_subtitle_file(&sidecar) {
+            Ok(t) => sub_tracks.push(t),
+            Err(e) => { tracing::warn!(file=%sidecar.display(), details=%e, "Skipping invalid subtitle file."); }
+        }
+        if let Err(e) = std::fs::remove_file(&sidecar) {
+            tracing::warn!(file=%sidecar.display(), details=%e, "Failed to remove subtitle sidecar.");
+        }
+    }
+    for t in &sub_tracks {
+        if let Err(e) = subtitles::store_subtitle(db, videos_dir, vh, t) {
+            tracing::error!(details=%e, title=t.title, "Failed to store subtitle track.");
+        }
+    }
+
+    // Drawings of comments imported with the video (see `api_server::package_import`)
+    let drawings_dir = src.parent().map(|p| p.join("drawings")).filter(|d| in_upload_dir && d.is_dir());
+    if let Some(d) = drawings_dir {
+        if let Err(e) = std::fs::rename(&d, dir_for_video.join("drawings")) {
+            tracing::error!(details=%e, "Failed to move imported drawings.");
+        }
+    }
+
+    // ML analysis, if configured. Audio-only files have nothing to see.
+    if let (Some(tx), false) = (analysis_tx, md.audio_only) {
+        submit_analysis_job(db, tx, analysis::AnalysisRequest {
+            video_hash: vh.to_string(),
+            user_id: md.user_id.clone(),
+            src: src_moved.clone(),
+            job_id: None,
+        }).unwrap_or_else(|e| { tracing::error!(details=%e, "Failed to submit video for analysis."); });
+    }
+
+    // Shot detection, if enabled. Not for audio-only files and stills, which have no shots.
+    if let (Some(tx), false, None) = (scenes_tx, md.audio_only, md.still_kind) {
+        submit_scenes_job(db, tx, scenes::SceneRequest {
+            video_hash: vh.to_string(),
+            user_id: md.user_id.clone(),
+            src: src_moved.clone(),
+            video_dir: dir_for_video.clone(),
+            duration: md.duration.to_f32().unwrap_or(0.0),
+            hdr_format: md.hdr_format.clone(),
+            job_id: None,
+        }).unwrap_or_else(|e| { tracing::error!(details=%e, "Failed to submit video for scene detection."); });
     }
 
-    let transcode_req = match needs_transcoding(md, target_bitrate) {
-        Some((reason, new_bitrate)) => {
-            let video_dst = dir_for_video.join(format!("transcoded_br{}_{}.mp4", new_bitrate, uuid::Uuid::new_v4()));
-            cmpr_tx.send(video_compressor::CmprInput {
+    // Check if it needs recompressing
+    let burn_in_flags = burn_in_policy.resolve(md.burn_in);
+    let transcode_req = match needs_conversion(md, target_bitrate, loudnorm_target, burn_in_flags) {
+        Some((conversion, reason, new_bitrate)) => {
+            let remux = conversion == bypass::Conversion::Remux;
+            let video_dst = dir_for_video.join(match remux {
+                true => format!("remuxed_{}.mp4", uuid::Uuid::new_v4()),
+                false => format!("transcoded_br{}_{}.mp4", new_bitrate, uuid::Uuid::new_v4()),
+            });
+            let preset = presets.resolve(db.get_folder_transcode_preset(md.folder_id)?.as_deref());
+            tracing::info!(preset=%preset.name, burn_in=%burn_in_flags, "Transcode preset.");
+            let burn_in = match md.audio_only {
+                true => burn_in::BurnIn::default(),
+                false => {
+                    let username = db.get_user(&md.user_id).map(|u| u.username).unwrap_or(md.user_id.clone());
+                    burn_in::BurnIn::new(burn_in_flags, burn_in_policy, md.cfr_fps.or(md.fps.to_f32()).unwrap_or(0.0), &orig_filename, &username)
+                },
+            };
+            submit_cmpr_job(db, cmpr_tx, video_compressor::CmprInput {
                 src: src_moved.clone(),
                 video_dst: Some(video_dst),
                 thumb_dir: None,
                 video_bitrate: new_bitrate,
+                loudnorm_target,
+                hdr_format: md.hdr_format.clone(),
+                rotation: md.rotation,
+                cfr_fps: md.cfr_fps,
+                audio_duration: md.audio_only.then(|| md.duration.to_f32().unwrap_or(0.0)),
+                remux,
+                preset,
+                burn_in,
                 video_hash: vh.to_string(),
                 user_id: md.user_id.clone(),
-            }).map(|_| (true, reason)).context("Error sending file to transcoding")
+                job_id: None,
+                trace_id: md.trace_id.clone(),
+            }).map(|_| (Some(conversion), reason)).context("Error sending file to transcoding")
         },
         None => {
             tracing::info!("Video ok already, not transcoding.");
-            Ok((false, "".to_string()))
+            Ok((None, "".to_string()))
         }
     };
 
     // Also create thumbnails unless there was a problem with the file
-    if let Ok(_) = &transcode_req {
+    if transcode_req.is_ok() {
         let thumbs_dir = dir_for_video.join("thumbs");
-        if let Err(e) = cmpr_tx.send(video_compressor::CmprInput {
+        if let Err(e) = submit_cmpr_job(db, cmpr_tx, video_compressor::CmprInput {
                 src: src_moved,
                 video_dst: None,
                 thumb_dir: Some(thumbs_dir),
                 video_bitrate: 0,
+                loudnorm_target: None,
+                hdr_format: md.hdr_format.clone(),
+                rotation: md.rotation,
+                cfr_fps: md.cfr_fps,
+                audio_duration: md.audio_only.then(|| md.duration.to_f32().unwrap_or(0.0)),
+                remux: false,
+                preset: Default::default(),
+                burn_in: Default::default(),
                 video_hash: vh.to_string(),
                 user_id: md.user_id.clone(),
+                job_id: None,
+                trace_id: md.trace_id.clone(),
             }) {
                 tracing::error!(details=?e, "Failed to send file to thumbnailing");
                 if let Err(e) = user_msg_tx.send(UserMessage {
@@ -228,16 +859,21 @@ fn ingest_video(
 
     // Format results to user readable message
     match transcode_req {
-        Ok((do_transcode, reason)) => {
-            tracing::info!(transcode=do_transcode, reason=reason, "Video added to DB. Transcode");
+        Ok((conversion, reason)) => {
+            tracing::info!(conversion=?conversion, reason=reason, "Video added to DB.");
+            let doing = match conversion {
+                Some(bypass::Conversion::Remux) => Some("Remuxing"),
+                Some(bypass::Conversion::Transcode) => Some("Transcoding"),
+                None => None,
+            };
             user_msg_tx.send(UserMessage {
-                topic: UserMessageTopic::Ok(),
-                msg: "Video added".to_string() + if do_transcode {". Transcoding..."} else {""},
-                details: if do_transcode { Some(format!("Transcoding because {reason}")) } else { None },
+                topic: if conversion.is_none() {UserMessageTopic::VideoReady()} else {UserMessageTopic::Ok()},
+                msg: "Video added".to_string() + &doing.map(|d| format!(". {d}...")).unwrap_or_default(),
+                details: doing.map(|d| format!("{d} because {reason}")),
                 user_id: Some(md.user_id.clone()),
                 video_hash: Some(vh.to_string())
             })?;
-            Ok(do_transcode)
+            Ok(vh.to_string())
         },
         Err(e) => {
             tracing::error!(details=?e, "Video added to DB, but failed to send to transcoding.");
@@ -259,14 +895,16 @@ fn ingest_video(
 pub fn run_forever(
     db: Arc<DB>,
     terminate_flag: Arc<AtomicBool>,
-    data_dir: PathBuf,
+    opts: crate::config::StartupOptions,
+    intake: PipelineIntake,
     user_msg_tx: crossbeam_channel::Sender<UserMessage>,
-    poll_interval: f32,
-    resubmit_delay: f32,
-    target_bitrate: u32,
-    upload_rx: Receiver<IncomingFile>,
-    n_workers: usize)
+    config: Arc<crate::config::LiveConfig>)
 {
+    let crate::config::StartupOptions { data_dir, poll_interval, resubmit_delay, ingest, trim_silence, sequence_fps,
+        analyzer, detect_scenes, scanner, sandbox, poster, shutdown_grace, .. } = opts;
+    let IngestPolicy { target_bitrate, loudness_target, cfr_fps, .. } = ingest;
+    let PipelineIntake { mut upload_rx, mut export_rx, queues, storage } = intake;
+
     tracing::info!("Starting video processing pipeline.");
 
     // Create folder for processed videos
@@ -277,18 +915,21 @@ pub fn run_forever(
     }
 
     // Thread for incoming folder scanner
-    let (_md_thread, from_md, to_md) = {
-            let (arg_sender, arg_recvr) = unbounded::<IncomingFile>();
+    let (md_thread, mut from_md, to_md) = {
+            let (arg_sender, arg_recvr) = queues.channel::<IncomingFile>();
             let (res_sender, res_recvr) = unbounded::<MetadataResult>();
 
+            let priority_of = user_priority_lookup(&db);
+            let terminate_flag = terminate_flag.clone();
+            let depth = queues.gauge("metadata");
             let th = thread::spawn(move || {
-                    metadata_reader::run_forever(arg_recvr, res_sender, 4);
+                    metadata_reader::run_forever(arg_recvr, res_sender, Arc::new(AtomicUsize::new(4)), depth, trim_silence, loudness_target, cfr_fps, sandbox, terminate_flag, priority_of);
                 });
             (th, res_recvr, arg_sender)
         };
 
     // Thread for metadata reader
-    let (mon_thread, from_mon, mon_exit) = {
+    let (mon_thread, mut from_mon, mut mon_exit) = {
         let (incoming_sender, incoming_recvr) = unbounded::<IncomingFile>();
         let (exit_sender, exit_recvr) = unbounded::<incoming_monitor::Void>();
 
@@ -302,21 +943,96 @@ pub fn run_forever(
                         exit_recvr) {
                     tracing::error!(details=?e, "Error from incoming monitor.");
                 }});
-        (th, incoming_recvr, exit_sender)
+        (th, incoming_recvr, Some(exit_sender))
     };
 
+    // Image sequences are assembled into movies in background threads, and come back here as regular files
+    let (seq_tx, mut seq_rx) = unbounded::<Result<IncomingFile, DetailedMsg>>();
+
+    // New files are scanned in background threads too (if there's a scanner), and come back with their original paths
+    let (scan_tx, mut scan_rx) = unbounded::<(PathBuf, Result<IncomingFile, DetailedMsg>)>();
+    let mut scanning = std::collections::HashSet::<PathBuf>::new();
+
+    // Worker pools, waited for (up to `shutdown_grace`) on shutdown
+    let mut workers = vec![md_thread];
+
     // Thread for video compressor
-    let (cmpr_in_tx, cmpr_in_rx) = unbounded::<video_compressor::CmprInput>();
-    let (cmpr_out_tx, cmpr_out_rx) = unbounded::<video_compressor::CmprOutput>();
-    let (cmpr_prog_tx, cmpr_prog_rx) = unbounded::<(String, String, String)>();
-    thread::spawn(move || {
-        video_compressor::run_forever(cmpr_in_rx, cmpr_out_tx, cmpr_prog_tx, n_workers);
-    });
+    let (cmpr_in_tx, cmpr_in_rx) = queues.channel::<video_compressor::CmprInput>();
+    let (cmpr_out_tx, mut cmpr_out_rx) = unbounded::<video_compressor::CmprOutput>();
+    let (cmpr_prog_tx, mut cmpr_prog_rx) = unbounded::<(String, String, String)>();
+    let priority_of = user_priority_lookup(&db);
+    let tf = terminate_flag.clone();
+    let nw = config.n_workers.clone();
+    let depth = queues.gauge("transcode");
+    workers.push(thread::spawn(move || {
+        video_compressor::run_forever(cmpr_in_rx, cmpr_out_tx, cmpr_prog_tx, nw, depth, sandbox, poster, tf, priority_of);
+    }));
+
+    // Thread for clip exports (GIFs and short movies for sharing)
+    let (clip_in_tx, clip_in_rx) = queues.channel::<clip_export::ClipRequest>();
+    let (clip_out_tx, mut clip_out_rx) = unbounded::<clip_export::ClipResult>();
+    let priority_of = user_priority_lookup(&db);
+    let tf = terminate_flag.clone();
+    let nw = config.n_workers.clone();
+    let depth = queues.gauge("export");
+    workers.push(thread::spawn(move || {
+        clip_export::run_forever(clip_in_rx, clip_out_tx, nw, depth, sandbox, tf, priority_of);
+    }));
+
+    // Thread for review package exports (zip for external handoff)
+    let (pkg_in_tx, pkg_in_rx) = queues.channel::<review_package::PackageRequest>();
+    let (pkg_out_tx, mut pkg_out_rx) = unbounded::<review_package::PackageResult>();
+    let priority_of = user_priority_lookup(&db);
+    let tf = terminate_flag.clone();
+    let nw = config.n_workers.clone();
+    let depth = queues.gauge("package");
+    workers.push(thread::spawn(move || {
+        review_package::run_forever(pkg_in_rx, pkg_out_tx, nw, depth, sandbox, tf, priority_of);
+    }));
+
+    // Thread for optional ML analysis. Without an analyzer, the result channel stays open but idle.
+    let (analysis_in_tx, analysis_in_rx) = queues.channel::<analysis::AnalysisRequest>();
+    let (analysis_out_tx, mut analysis_out_rx) = unbounded::<analysis::AnalysisResult>();
+    let analysis_tx = match analyzer {
+        Some(cmd) => {
+            let priority_of = user_priority_lookup(&db);
+            let tf = terminate_flag.clone();
+            let nw = config.n_workers.clone();
+            let depth = queues.gauge("analysis");
+            workers.push(thread::spawn(move || {
+                analysis::run_forever(analysis_in_rx, analysis_out_tx, nw, depth, cmd, tf, priority_of);
+            }));
+            Some(analysis_in_tx)
+        },
+        None => None,
+    };
+
+    // Thread for optional scene detection. Like analysis, result channel stays idle if disabled.
+    let (scenes_in_tx, scenes_in_rx) = queues.channel::<scenes::SceneRequest>();
+    let (scenes_out_tx, mut scenes_out_rx) = unbounded::<scenes::SceneResult>();
+    let scenes_tx = match detect_scenes {
+        true => {
+            let priority_of = user_priority_lookup(&db);
+            let tf = terminate_flag.clone();
+            let nw = config.n_workers.clone();
+            let depth = queues.gauge("scenes");
+            workers.push(thread::spawn(move || {
+                scenes::run_forever(scenes_in_rx, scenes_out_tx, nw, depth, sandbox, tf, priority_of);
+            }));
+            Some(scenes_in_tx)
+        },
+        false => None,
+    };
 
     // Migration from older version: find a video that is missing thumbnail sheet
-    fn legacy_thumnail_next_video(db: &DB, videos_dir: &PathBuf, cmpr_in: &mut crossbeam_channel::Sender<video_compressor::CmprInput>) -> Option<String> {
+    fn legacy_thumnail_next_video(db: &DB, videos_dir: &Path, cmpr_in: &mut crossbeam_channel::Sender<video_compressor::CmprInput>) -> Option<String> {
+        // Skip videos that already have a thumbnailing job (recovered after restart)
+        let being_thumbnailed = db.get_unfinished_jobs().unwrap_or_default().into_iter()
+            .filter(|j| j.stage == job_stage::THUMBNAIL)
+            .filter_map(|j| j.video_hash).collect::<Vec<_>>();
+
         let next = match db.get_all_videos_without_thumbnails() {
-            Ok(videos) => videos.first().cloned(),
+            Ok(videos) => videos.into_iter().find(|v| !being_thumbnailed.contains(&v.video_hash)),
             Err(e) => {
                 tracing::error!(details=?e, "DB: Failed to get videos without thumbnails.");
                 return None;
@@ -329,9 +1045,9 @@ pub fn run_forever(
                     Some(videos_dir.join(&v.video_hash).join("video.mp4"))
                 } else {
                     match v.orig_filename {
-                        Some(ref orig_filename) => Some(videos_dir.join(&v.video_hash).join("orig").join(orig_filename)),
-                        None => {
-                            tracing::error!(video_hash=%v.video_hash, "Legacy thumbnailing failed. Original filename missing and not recompressed.");
+                        Some(ref orig_filename) if is_plain_file_name(orig_filename) => Some(videos_dir.join(&v.video_hash).join("orig").join(orig_filename)),
+                        _ => {
+                            tracing::error!(video_hash=%v.video_hash, "Legacy thumbnailing failed. Original filename missing (or bad) and not recompressed.");
                             None
                         }}
                 };
@@ -343,10 +1059,20 @@ pub fn run_forever(
                         video_dst: None,
                         thumb_dir: Some(videos_dir.join(&v.video_hash).join("thumbs")),
                         video_bitrate: 0,
+                        loudnorm_target: None,
+                        hdr_format: v.hdr_format.clone(),
+                        rotation: 0,
+                        cfr_fps: None,
+                        audio_duration: v.duration.filter(|_| v.audio_only),
+                        remux: false,
+                        preset: Default::default(),
+                        burn_in: Default::default(),
                         video_hash: v.video_hash.clone(),
                         user_id: user_id.clone(),
+                        job_id: None,
+                        trace_id: None,
                     };
-                    cmpr_in.send(req).unwrap_or_else(|e| {
+                    submit_cmpr_job(db, cmpr_in, req).unwrap_or_else(|e| {
                             tracing::error!(details=?e, "Error sending legacy thumbnailing request to compressor.");
                         });
                     return Some(v.video_hash.clone());
@@ -358,47 +1084,73 @@ pub fn run_forever(
         }
         None
     }
+    // Resume jobs that were interrupted by a crash or restart
+    match job_recovery::recover_unfinished_jobs(&db, &data_dir.join("incoming"), &to_md, &cmpr_in_tx, &config.transcode_presets()) {
+        Ok(0) => {},
+        Ok(n) => { tracing::info!(n_jobs=n, "Resubmitted unfinished jobs from previous run."); },
+        Err(e) => { tracing::error!(details=%e, "Failed to recover unfinished jobs."); }
+    }
+
     let mut legacy_video_now_thumnailing = legacy_thumnail_next_video(&db, &videos_dir, &mut cmpr_in_tx.clone());
 
 
+    // Set on termination. Until then, results from jobs still in progress are handled as usual,
+    // and channels closed by exiting workers are replaced with `never()` instead of aborting.
+    let mut drain_deadline: Option<std::time::Instant> = None;
+
+    // While storage is full (see `storage`), new files are left waiting in the intake queues
+    let paused_rx = never::<IncomingFile>();
+    let mut last_storage_check = std::time::Instant::now();
+
     let _span = tracing::info_span!("PIPELINE").entered();
     loop {
+        let intake_paused = storage.full().is_some();
         select! {
             // Pass HTTP upload results to metadata reader
-            recv(upload_rx) -> msg => {
-                match msg {
-                    Ok(msg) => {
+            recv(if intake_paused { &paused_rx } else { &upload_rx }) -> msg => {
+                match msg.map(|f| start_scan(&db, scanner.as_ref(), &mut scanning, &data_dir, &scan_tx, f)) {
+                    Ok(None) => {},
+                    Ok(Some(msg)) if msg.file_path.is_dir() => {
+                        tracing::info!("Got image sequence upload. Assembling it. {:?}", msg);
+                        image_sequence::spawn_assemble(msg, data_dir.clone(), sequence_fps, sandbox, seq_tx.clone());
+                    },
+                    Ok(Some(msg)) => {
                         tracing::info!("Got upload result. Submitting it for processing. {:?}", msg);
-                        to_md.send(IncomingFile { 
-                            file_path: msg.file_path.clone(),
-                            user_id: msg.user_id}).unwrap_or_else(|e| {
+                        submit_metadata_job(&db, &to_md, msg.clone()).unwrap_or_else(|e| {
                                 tracing::error!("Error sending file to metadata reader: {:?}", e);
+                                update_upload_batch(&db, &user_msg_tx, &msg.file_path, &msg.user_id, Err("Internal error: failed to start processing"));
                                 clean_up_rejected_file(&data_dir, &msg.file_path, None).unwrap_or_else(|e| {
                                     tracing::error!("Cleanup of '{:?}' failed: {:?}", &msg.file_path, e);
                                 });
                             });
                     },
+                    Err(_) if drain_deadline.is_some() => { upload_rx = never(); },
                     Err(_) => { break; }
                 }
             }
             // Metadata reader results
             recv(from_md) -> msg => {
                 match msg {
-                    Ok(md_res) => { 
+                    Ok(md_res) => {
+                        let (src_file, user_id) = match &md_res {
+                            MetadataResult::Ok(md) => (md.src_file.clone(), md.user_id.clone()),
+                            MetadataResult::Err(e) => (e.src_file.clone(), e.user_id.clone()),
+                        };
                         let (vh, ing_res) = match md_res {
                             MetadataResult::Ok(md) => {
                                 tracing::debug!("Got metadata for {:?}", md.src_file);
-                                match calc_video_hash(&md.src_file, &md.user_id) {
-                                    Err(e) => {
+                                match check_ingest_quotas(&db, &videos_dir, &config.quotas(), &md).and_then(|_|
+                                        calc_video_hash(&md.src_file, &md.user_id).map_err(|e| ("Video hashing error", e.to_string()))) {
+                                    Err((msg, details)) => {
                                         (None, Err(DetailedMsg {
-                                            msg: "Video hashing error".into(),
-                                            details: e.to_string(),
+                                            msg: msg.into(),
+                                            details,
                                             src_file: md.src_file.clone(),
                                             user_id: md.user_id.clone(),
                                         }))
                                     },
                                     Ok(vh) => {
-                                        let ing_res = ingest_video(&vh, &md, &data_dir, &videos_dir, target_bitrate, &db, &user_msg_tx, &cmpr_in_tx).map_err(|e| {
+                                        let ing_res = ingest_video(&vh, &md, &data_dir, &videos_dir, target_bitrate, &db, &user_msg_tx, &cmpr_in_tx, analysis_tx.as_ref(), scenes_tx.as_ref(), &config.get()).map_err(|e| {
                                             DetailedMsg {
                                                 msg: "Video ingestion failed".into(),
                                                 details: e.to_string(),
@@ -411,58 +1163,285 @@ pub fn run_forever(
                             }
                             MetadataResult::Err(e) => (None, Err(e))
                         };
+                        match &ing_res {
+                            Ok(final_vh) => {
+                                finish_metadata_jobs(&db, &src_file, job_status::DONE, "");
+                                update_upload_batch(&db, &user_msg_tx, &src_file, &user_id, Ok(final_vh));
+                            },
+                            Err(e) => {
+                                finish_metadata_jobs(&db, &src_file, job_status::FAILED, &format!("{}: {}", e.msg, e.details));
+                                update_upload_batch(&db, &user_msg_tx, &src_file, &user_id, Err(&e.msg));
+                            }
+                        }
                         // Relay errors, if any.
                         // No need to send ok message here, variations of it are sent from ingest_video().
                         if let Err(e) = ing_res {
                             tracing::error!("Error ingesting file '{:?}' (owner '{:?}', hash '{:?}'): {:?}", e.src_file, e.user_id, vh, e.msg);
+                            if let Some(se) = StorageError::from_message(&e.details) {
+                                report_storage_error(&db, &user_msg_tx, &storage, se);
+                            }
                             let cleanup_err = match clean_up_rejected_file(&data_dir, &e.src_file, None) {
                                     Err(e) => { format!(" Cleanup also failed: {:?}", e) },
                                     Ok(()) => { "".into() } };
                             user_msg_tx.send(UserMessage {
                                     topic: UserMessageTopic::Error(),
-                                    msg: "Error reading video metadata.".into(),
+                                    msg: e.msg.clone(),
                                     details: Some(format!("'{}': ", e.src_file.file_name().unwrap_or_default().to_string_lossy()) + &e.details + &cleanup_err),
                                     user_id: Some(e.user_id),
                                     video_hash: vh
                                 }).unwrap_or_else(|e| { tracing::error!("Error sending user message: {:?}", e); });
                         }
                     },
+                    Err(_) if drain_deadline.is_some() => { from_md = never(); },
                     Err(e) => { tracing::warn!("Metadata reader is dead ('{:?}'). Exit.", e); break; },
                 }
             },
             // Incoming file from monitor
-            recv(from_mon) -> msg => {
-                match msg {
-                    Ok(new_file) => {
+            recv(if intake_paused { &paused_rx } else { &from_mon }) -> msg => {
+                match msg.map(|f| start_scan(&db, scanner.as_ref(), &mut scanning, &data_dir, &scan_tx, f)) {
+                    Ok(None) => {},
+                    Ok(Some(new_dir)) if new_dir.file_path.is_dir() => {
+                        image_sequence::spawn_assemble(new_dir, data_dir.clone(), sequence_fps, sandbox, seq_tx.clone());
+                    },
+                    Ok(Some(new_file)) => {
                         // Relay to metadata reader
-                        to_md.send(new_file).unwrap_or_else(|e| {
+                        submit_metadata_job(&db, &to_md, new_file).unwrap_or_else(|e| {
                             tracing::error!("FATAL. Error sending file to metadata reader: {:?}", e);
                             terminate_flag.store(true, std::sync::atomic::Ordering::Relaxed);
                         });
                     },
+                    Err(_) if drain_deadline.is_some() => { from_mon = never(); },
                     Err(e) => { tracing::warn!("Metadata reader is dead ('{:?}'). Exit.", e); break; },
                 }
             },
+            // Content scan results
+            recv(scan_rx) -> msg => {
+                match msg {
+                    Ok((path, res)) => {
+                        scanning.remove(&path);
+                        match res {
+                            Ok(file) if file.file_path.is_dir() => {
+                                image_sequence::spawn_assemble(file, data_dir.clone(), sequence_fps, sandbox, seq_tx.clone());
+                            },
+                            Ok(file) => {
+                                submit_metadata_job(&db, &to_md, file.clone()).unwrap_or_else(|e| {
+                                    tracing::error!("Error sending file to metadata reader: {:?}", e);
+                                    update_upload_batch(&db, &user_msg_tx, &file.file_path, &file.user_id, Err("Internal error: failed to start processing"));
+                                    clean_up_rejected_file(&data_dir, &file.file_path, None).unwrap_or_else(|e| {
+                                        tracing::error!("Cleanup of '{:?}' failed: {:?}", &file.file_path, e);
+                                    });
+                                });
+                            },
+                            Err(e) => {
+                                let filename = e.src_file.file_name().unwrap_or_default().to_string_lossy().to_string();
+                                if e.msg == scan::REJECTED_MSG {
+                                    if let Err(e) = db.add_audit_event(&models::AuditEventInsert {
+                                            user_id: e.user_id.clone(),
+                                            action: models::audit_action::UPLOAD_QUARANTINED.into(),
+                                            ref_video_hash: None,
+                                            details: format!("'{}': {}", filename, e.details),
+                                        }) {
+                                        tracing::error!(details=%e, "Failed to write audit log.");
+                                    }
+                                }
+                                update_upload_batch(&db, &user_msg_tx, &e.src_file, &e.user_id, Err(&e.msg));
+                                user_msg_tx.send(UserMessage {
+                                        topic: UserMessageTopic::Error(),
+                                        msg: e.msg.clone(),
+                                        details: Some(format!("'{}': {}", filename, e.details)),
+                                        user_id: Some(e.user_id),
+                                        video_hash: None
+                                    }).unwrap_or_else(|e| { tracing::error!("Error sending user message: {:?}", e); });
+                            },
+                        }
+                    },
+                    Err(_) if drain_deadline.is_some() => { scan_rx = never(); },
+                    Err(e) => { tracing::warn!("Scan channel closed ('{:?}'). Exit.", e); break; },
+                }
+            },
+            // Movies assembled from image sequences
+            recv(seq_rx) -> msg => {
+                match msg {
+                    Ok(Ok(file)) => {
+                        submit_metadata_job(&db, &to_md, file).unwrap_or_else(|e| {
+                            tracing::error!("FATAL. Error sending file to metadata reader: {:?}", e);
+                            terminate_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+                        });
+                    },
+                    Ok(Err(e)) => {
+                        // Frames were already moved to rejected/ by the assembler
+                        user_msg_tx.send(UserMessage {
+                                topic: UserMessageTopic::Error(),
+                                msg: e.msg.clone(),
+                                details: Some(format!("'{}': {}", e.src_file.file_name().unwrap_or_default().to_string_lossy(), e.details)),
+                                user_id: Some(e.user_id),
+                                video_hash: None
+                            }).unwrap_or_else(|e| { tracing::error!("Error sending user message: {:?}", e); });
+                    },
+                    Err(_) if drain_deadline.is_some() => { seq_rx = never(); },
+                    Err(e) => { tracing::warn!("Sequence channel closed ('{:?}'). Exit.", e); break; },
+                }
+            },
+            // Export requests from API server
+            recv(export_rx) -> msg => {
+                match msg {
+                    Ok(req) => {
+                        match req {
+                            ExportRequest::Clip(req) => submit_export_job(&db, &clip_in_tx, req),
+                            ExportRequest::Package(req) => submit_package_job(&db, &pkg_in_tx, req),
+                        }.unwrap_or_else(|e| {
+                            tracing::error!("FATAL. Error sending request to exporter: {:?}", e);
+                            terminate_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+                        });
+                    },
+                    Err(_) if drain_deadline.is_some() => { export_rx = never(); },
+                    Err(e) => { tracing::warn!("Export channel closed ('{:?}'). Exit.", e); break; },
+                }
+            },
+            // Clip export results
+            recv(clip_out_rx) -> msg => {
+                match msg {
+                    Ok(res) => {
+                        let req = res.req;
+                        mark_job(&db, req.job_id, if res.error.is_none() { job_status::DONE } else { job_status::FAILED }, res.error.as_deref().unwrap_or(""));
+                        user_msg_tx.send(match res.error {
+                            None => UserMessage {
+                                topic: UserMessageTopic::Ok(),
+                                msg: format!("Clip ready ({})", req.format.ext()),
+                                details: Some(serde_json::json!({ "clip_url": req.url, "start": req.start, "end": req.end }).to_string()),
+                                user_id: Some(req.user_id),
+                                video_hash: None,
+                            },
+                            Some(e) => UserMessage {
+                                topic: UserMessageTopic::Error(),
+                                msg: "Clip export failed".into(),
+                                details: Some(e),
+                                user_id: Some(req.user_id),
+                                video_hash: None,
+                            }}).unwrap_or_else(|e| { tracing::error!("Error sending user message: {:?}", e); });
+                    },
+                    Err(_) if drain_deadline.is_some() => { clip_out_rx = never(); },
+                    Err(e) => { tracing::warn!("Clip exporter is dead ('{:?}'). Exit.", e); break; },
+                }
+            },
+            // Review package results
+            recv(pkg_out_rx) -> msg => {
+                match msg {
+                    Ok(res) => {
+                        let req = res.req;
+                        mark_job(&db, req.job_id, if res.error.is_none() { job_status::DONE } else { job_status::FAILED }, res.error.as_deref().unwrap_or(""));
+                        user_msg_tx.send(match res.error {
+                            None => UserMessage {
+                                topic: UserMessageTopic::Ok(),
+                                msg: "Review package ready".into(),
+                                details: Some(serde_json::json!({ "package_url": req.url }).to_string()),
+                                user_id: Some(req.user_id),
+                                video_hash: Some(req.video_hash),
+                            },
+                            Some(e) => UserMessage {
+                                topic: UserMessageTopic::Error(),
+                                msg: "Review package export failed".into(),
+                                details: Some(e),
+                                user_id: Some(req.user_id),
+                                video_hash: Some(req.video_hash),
+                            }}).unwrap_or_else(|e| { tracing::error!("Error sending user message: {:?}", e); });
+                    },
+                    Err(_) if drain_deadline.is_some() => { pkg_out_rx = never(); },
+                    Err(e) => { tracing::warn!("Packager is dead ('{:?}'). Exit.", e); break; },
+                }
+            },
+            // ML analysis results
+            recv(analysis_out_rx) -> msg => {
+                match msg {
+                    Ok(res) => {
+                        let req = res.req;
+                        let stored = res.labels.and_then(|labels| db.set_video_labels(&req.video_hash, &labels)
+                            .map(|_| labels.len()).map_err(|e| format!("DB error: {e}")));
+                        mark_job(&db, req.job_id, if stored.is_ok() { job_status::DONE } else { job_status::FAILED }, stored.as_ref().err().map_or("", |e| e.as_str()));
+                        user_msg_tx.send(match stored {
+                            Ok(n) => UserMessage {
+                                topic: UserMessageTopic::VideoUpdated(),
+                                msg: format!("Analysis complete. {n} labels found."),
+                                details: None,
+                                user_id: Some(req.user_id),
+                                video_hash: Some(req.video_hash),
+                            },
+                            Err(e) => {
+                                tracing::error!(video=%req.video_hash, details=%e, "Video analysis failed.");
+                                UserMessage {
+                                    topic: UserMessageTopic::Error(),
+                                    msg: "Video analysis failed".into(),
+                                    details: Some(e),
+                                    user_id: Some(req.user_id),
+                                    video_hash: Some(req.video_hash),
+                                }
+                            }}).unwrap_or_else(|e| { tracing::error!("Error sending user message: {:?}", e); });
+                    },
+                    Err(_) if drain_deadline.is_some() => { analysis_out_rx = never(); },
+                    Err(e) => { tracing::warn!("Analyzer is dead ('{:?}'). Exit.", e); break; },
+                }
+            },
+            // Scene detection results
+            recv(scenes_out_rx) -> msg => {
+                match msg {
+                    Ok(res) => {
+                        let req = res.req;
+                        let stored = res.shots.and_then(|shots| db.set_video_shots(&req.video_hash, &shots)
+                            .map(|_| shots.len()).map_err(|e| format!("DB error: {e}")));
+                        mark_job(&db, req.job_id, if stored.is_ok() { job_status::DONE } else { job_status::FAILED }, stored.as_ref().err().map_or("", |e| e.as_str()));
+                        user_msg_tx.send(match stored {
+                            Ok(n) => UserMessage {
+                                topic: UserMessageTopic::VideoUpdated(),
+                                msg: format!("Scene detection complete. {n} shots found."),
+                                details: None,
+                                user_id: Some(req.user_id),
+                                video_hash: Some(req.video_hash),
+                            },
+                            Err(e) => {
+                                tracing::error!(video=%req.video_hash, details=%e, "Scene detection failed.");
+                                UserMessage {
+                                    topic: UserMessageTopic::Error(),
+                                    msg: "Scene detection failed".into(),
+                                    details: Some(e),
+                                    user_id: Some(req.user_id),
+                                    video_hash: Some(req.video_hash),
+                                }
+                            }}).unwrap_or_else(|e| { tracing::error!("Error sending user message: {:?}", e); });
+                    },
+                    Err(_) if drain_deadline.is_some() => { scenes_out_rx = never(); },
+                    Err(e) => { tracing::warn!("Scene detector is dead ('{:?}'). Exit.", e); break; },
+                }
+            },
             // Video compressor progress
             recv(cmpr_prog_rx) -> msg => {
                 match msg {
                     Ok((vh, user_id, msg)) => {
                         user_msg_tx.send(UserMessage {
                                 topic: UserMessageTopic::Progress(),
-                                msg: msg,
+                                msg,
                                 details: None,
                                 user_id: Some(user_id),
                                 video_hash: Some(vh)
                             }).unwrap_or_else(|e| { tracing::error!("Error sending user message: {:?}", e); });
                     },
+                    Err(_) if drain_deadline.is_some() => { cmpr_prog_rx = never(); },
                     Err(e) => { tracing::warn!("Video compressor is dead ('{:?}'). Exit.", e); break; },
                 }
             },
             // Video compressor output
             recv(cmpr_out_rx) -> msg => {
                 match msg {
+                    Err(_) if drain_deadline.is_some() => { cmpr_out_rx = never(); },
                     Err(e) => { tracing::warn!("Video compressor is dead ('{:?}'). Exit.", e); break; },
                     Ok(res) => {
+                        let _span = tracing::info_span!("cmpr_result", video=%res.video_hash, job_id=res.job_id, trace_id=res.trace_id.as_deref()).entered();
+                        record_attempts(&db, &res);
+                        if res.success {
+                            mark_job(&db, res.job_id, job_status::DONE, "");
+                            record_throughput(&db, &res);
+                        } else {
+                            mark_job(&db, res.job_id, job_status::FAILED, &res.dmsg.details);
+                        }
                         if res.success {
                             let videos_dir = videos_dir.clone();
                             let db = db.clone();
@@ -511,11 +1490,12 @@ pub fn run_forever(
                                 }}}
 
                                 // Get filename from path
-                                fn get_filename(p: &PathBuf) -> anyhow::Result<String> {
+                                fn get_filename(p: &Path) -> anyhow::Result<String> {
                                     Ok(p.file_name().ok_or(anyhow!("bad filename: {}", p.to_string_lossy()))?.to_str().ok_or(anyhow!("bad encoding"))?.to_string())
                                 }
 
                                 // Symlink to transcoded file
+                                let encoding = res.encoding.clone();
                                 let linked_ok = (move || {
                                     let vh_dir = videos_dir.join(&vh);
                                     if !vh_dir.exists() {
@@ -532,7 +1512,7 @@ pub fn run_forever(
                                         tracing::error!(details=%e, "Failed to create symlink {:?} -> {:?}", symlink_path, res.video_dst);
                                         return false;
                                     }
-                                    if let Err(e) = db.set_video_recompressed(&vh) {
+                                    if let Err(e) = db.set_video_recompressed(&vh, encoding.as_deref()) {
                                         tracing::error!(details=%e, "Error marking video as recompressed in DB");
                                         return false;
                                     }
@@ -541,7 +1521,7 @@ pub fn run_forever(
 
                                 // Send success message
                                 user_msg_tx.send(UserMessage {
-                                        topic: if linked_ok {UserMessageTopic::Ok()} else {UserMessageTopic::Error()},
+                                        topic: if linked_ok {UserMessageTopic::VideoReady()} else {UserMessageTopic::Error()},
                                         msg: "Video transcoded.".to_string() + if linked_ok {""} else {" But linking or DB failed."},
                                         details: None,
                                         user_id: Some(res.dmsg.user_id),
@@ -550,11 +1530,19 @@ pub fn run_forever(
                             }
                         }
                         else {
-                            let msg = format!("Video {} failed", if res.video_dst.is_some() {"transcoding"} else {"thumbnailing"});
+                            let mut msg = format!("Video {} failed", if res.video_dst.is_some() {"transcoding"} else {"thumbnailing"});
+                            if res.attempts.len() > 1 {
+                                msg += &format!(" after {} attempts", res.attempts.len());
+                            }
                             tracing::error!(video=res.video_hash, details=?res.dmsg, msg);
+                            let storage_err = StorageError::from_message(&res.stderr).or(StorageError::from_message(&res.dmsg.details));
+                            if let Some(se) = storage_err {
+                                report_storage_error(&db, &user_msg_tx, &storage, se);
+                            }
+                            let msg = match storage_err { Some(se) => format!("{msg}: {se}"), None => msg };
                             user_msg_tx.send(UserMessage {
                                     topic: UserMessageTopic::Error(),
-                                    msg: msg,
+                                    msg,
                                     details: Some(res.dmsg.details),
                                     user_id: Some(res.dmsg.user_id),
                                     video_hash: Some(res.video_hash)
@@ -562,16 +1550,39 @@ pub fn run_forever(
                         }
                     }
                 }
-            }
+            },
+            default(Duration::from_millis(500)) => {
+                if drain_deadline.is_some() && workers.iter().all(|w| w.is_finished()) {
+                    tracing::info!("All jobs in progress finished. Exit.");
+                    break;
+                }
+            },
         }
 
-        if mon_thread.is_finished() {
-            tracing::error!("Incoming monitor finished. Exit.");
-            break;
+        if intake_paused && last_storage_check.elapsed() > Duration::from_secs(crate::storage::CHECK_INTERVAL_SECS) {
+            last_storage_check = std::time::Instant::now();
+            if storage.try_resume(crate::doctor::free_disk_bytes) {
+                notify_admins(&db, &user_msg_tx, UserMessageTopic::Ok(), crate::storage::ADMIN_RESUMED_MSG, None);
+            }
         }
-        if terminate_flag.load(std::sync::atomic::Ordering::Relaxed) {
-            tracing::info!("Termination flag set. Exit.");
-            break;
+
+        match drain_deadline {
+            None => {
+                if mon_thread.is_finished() {
+                    tracing::error!("Incoming monitor finished. Exit.");
+                    break;
+                }
+                if terminate_flag.load(std::sync::atomic::Ordering::Relaxed) {
+                    tracing::info!(grace_secs=shutdown_grace.as_secs_f32(), "Termination flag set. Waiting for jobs in progress to finish.");
+                    drop(mon_exit.take());  // Stop picking up new files from incoming/
+                    drain_deadline = Some(std::time::Instant::now() + shutdown_grace);
+                }
+            },
+            Some(deadline) if std::time::Instant::now() > deadline => {
+                tracing::warn!("Shutdown grace period exceeded. Unfinished jobs will be resumed on next start.");
+                break;
+            },
+            Some(_) => {},
         }
     }
 
@@ -586,3 +1597,57 @@ pub fn run_forever(
 
     tracing::info!("Exiting.");
 }
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_calc_content_hash()
+{
+    let dir = assert_fs::TempDir::new().unwrap();
+    std::fs::write(dir.join("a.mp4"), "same content").unwrap();
+    std::fs::write(dir.join("renamed.mov"), "same content").unwrap();
+    std::fs::write(dir.join("other.mp4"), "other content").unwrap();
+
+    let a = calc_content_hash(&dir.join("a.mp4")).unwrap();
+    assert_eq!(a.len(), 64);
+    assert_eq!(a, calc_content_hash(&dir.join("renamed.mov")).unwrap());
+    assert_ne!(a, calc_content_hash(&dir.join("other.mp4")).unwrap());
+    assert_ne!(calc_video_hash(&dir.join("a.mp4"), "u").unwrap(), calc_video_hash(&dir.join("renamed.mov"), "u").unwrap());
+}
+
+#[test]
+fn test_is_plain_file_name()
+{
+    for ok in ["clip.mov", "take 2: final.mp4", ".hidden.mp4", "你.mov"] {
+        assert!(is_plain_file_name(ok), "{ok}");
+    }
+    for bad in ["", " ", ".", "..", "/clip.mov", "dir/clip.mov", "../clip.mov", "dir\\clip.mov", "..\\clip.mov",
+            "\\\\server\\share\\clip.mov", "C:clip.mov", "C:\\Users\\me\\clip.mov", "clip.mov/"] {
+        assert!(!is_plain_file_name(bad), "{bad}");
+    }
+}
+
+#[test]
+fn test_loudnorm_opt()
+{
+    let p = |s: &str| s.parse::<LoudnormOpt>();
+    assert_eq!(p(""), Ok(LoudnormOpt::ServerDefault));
+    assert_eq!(p("OFF"), Ok(LoudnormOpt::Off));
+    assert_eq!(p("on"), Ok(LoudnormOpt::On));
+    assert_eq!(p("-16"), Ok(LoudnormOpt::Target(-16.0)));
+    assert!(p("3").is_err());
+    assert!(p("loud").is_err());
+
+    // Round trip through job DB string
+    for l in [LoudnormOpt::ServerDefault, LoudnormOpt::Off, LoudnormOpt::On, LoudnormOpt::Target(-23.5)] {
+        assert_eq!(p(&l.to_string()), Ok(l));
+    }
+
+    assert_eq!(LoudnormOpt::ServerDefault.resolve(Some(-16.0)), Some(-16.0));
+    assert_eq!(LoudnormOpt::ServerDefault.resolve(None), None);
+    assert_eq!(LoudnormOpt::Off.resolve(Some(-16.0)), None);
+    assert_eq!(LoudnormOpt::On.resolve(None), Some(DEFAULT_LOUDNESS_TARGET));
+    assert_eq!(LoudnormOpt::On.resolve(Some(-16.0)), Some(-16.0));
+    assert_eq!(LoudnormOpt::Target(-14.0).resolve(Some(-16.0)), Some(-14.0));
+}
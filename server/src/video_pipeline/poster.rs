@@ -0,0 +1,230 @@
+use std::path::Path;
+use super::sandbox::Sandbox;
+
+// Poster thumbnail (thumb.webp) is picked from candidate frames spread over the video, instead
+// of letting ffmpeg choose from the first few frames, which are often black (fade in) or a
+// slate. Candidates are scored on small grayscale versions of the frames: black, blown out and
+// flat frames (fades, blank title cards) are rejected, and of the rest the sharpest one (variance
+// of Laplacian) wins, with contrast (luma standard deviation) weighted in.
+
+/// Size of the grayscale frames candidates are scored on
+const SCORE_W: usize = 160;
+const SCORE_H: usize = 90;
+
+/// Poster frame selection settings (`--poster-scoring`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PosterScoring {
+    /// Number of candidate frames. 0 = let ffmpeg's `thumbnail` filter pick from the first frames.
+    pub candidates: u32,
+    /// Frames with mean luma (0-255) below this are considered black
+    pub min_luma: f64,
+    /// Frames with mean luma above this are considered blown out (e.g. fade to white)
+    pub max_luma: f64,
+    /// Frames with luma standard deviation below this are considered flat
+    pub min_contrast: f64,
+    /// Weight of contrast in the score, relative to sharpness
+    pub contrast_weight: f64,
+}
+
+impl Default for PosterScoring {
+    fn default() -> Self {
+        PosterScoring { candidates: 8, min_luma: 20.0, max_luma: 235.0, min_contrast: 10.0, contrast_weight: 0.5 }
+    }
+}
+
+impl std::str::FromStr for PosterScoring {
+    type Err = String;
+
+    /// Parse a comma separated list of `key=value` (keys as in struct fields), or "off".
+    /// Keys not given keep their defaults.
+    fn from_str(s: &str) -> Result<PosterScoring, String> {
+        let mut ps = PosterScoring::default();
+        if s.trim() == "off" {
+            return Ok(PosterScoring { candidates: 0, ..ps });
+        }
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (key, value) = item.split_once('=').ok_or(format!("Expected key=value, got '{item}'"))?;
+            let (key, value) = (key.trim(), value.trim());
+            let num = || value.parse::<f64>().ok().filter(|v| v.is_finite() && *v >= 0.0)
+                .ok_or(format!("Invalid value for '{key}': '{value}'"));
+            match key {
+                "candidates" => ps.candidates = value.parse().map_err(|_| format!("Invalid value for '{key}': '{value}'"))?,
+                "min_luma" => ps.min_luma = num()?,
+                "max_luma" => ps.max_luma = num()?,
+                "min_contrast" => ps.min_contrast = num()?,
+                "contrast_weight" => ps.contrast_weight = num()?,
+                _ => return Err(format!("Unknown poster scoring setting '{key}'")),
+            }
+        }
+        if ps.min_luma >= ps.max_luma {
+            return Err("min_luma must be less than max_luma".into());
+        }
+        Ok(ps)
+    }
+}
+
+/// 8-bit grayscale image
+#[derive(Debug, Clone)]
+pub struct GrayFrame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+/// Mean and standard deviation of luma
+pub fn luma_stats(f: &GrayFrame) -> (f64, f64)
+{
+    if f.pixels.is_empty() {
+        return (0.0, 0.0);
+    }
+    let n = f.pixels.len() as f64;
+    let mean = f.pixels.iter().map(|p| *p as f64).sum::<f64>() / n;
+    let var = f.pixels.iter().map(|p| (*p as f64 - mean).powi(2)).sum::<f64>() / n;
+    (mean, var.sqrt())
+}
+
+/// Sharpness as variance of the (4-neighbour) Laplacian. Blurred frames have few edges, so low variance.
+pub fn sharpness(f: &GrayFrame) -> f64
+{
+    if f.width < 3 || f.height < 3 {
+        return 0.0;
+    }
+    let px = |x: usize, y: usize| f.pixels[y * f.width + x] as f64;
+    let lap = (1..f.height - 1).flat_map(|y| (1..f.width - 1).map(move |x| (x, y)))
+        .map(|(x, y)| px(x - 1, y) + px(x + 1, y) + px(x, y - 1) + px(x, y + 1) - 4.0 * px(x, y))
+        .collect::<Vec<_>>();
+    let n = lap.len() as f64;
+    let mean = lap.iter().sum::<f64>() / n;
+    lap.iter().map(|l| (l - mean).powi(2)).sum::<f64>() / n
+}
+
+impl PosterScoring {
+    /// Score a candidate frame, higher is better.
+    ///
+    /// # Returns
+    /// None if frame is not acceptable as poster (black, blown out or flat)
+    pub fn score(&self, f: &GrayFrame) -> Option<f64>
+    {
+        let (mean, contrast) = luma_stats(f);
+        if mean < self.min_luma || mean > self.max_luma || contrast < self.min_contrast {
+            return None;
+        }
+        // Std dev of Laplacian, to be on the same scale as contrast
+        Some(sharpness(f).sqrt() + self.contrast_weight * contrast)
+    }
+
+    /// Index of the best acceptable candidate (missing ones are skipped), if any
+    pub fn pick(&self, frames: &[Option<GrayFrame>]) -> Option<usize>
+    {
+        frames.iter().enumerate()
+            .filter_map(|(i, f)| f.as_ref().and_then(|f| self.score(f)).map(|s| (i, s)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+    }
+
+    /// Timestamps (seconds) of candidate frames: evenly spread, but not at the very start or end (fades)
+    pub fn candidate_times(&self, duration: f64) -> Vec<f64>
+    {
+        let n = self.candidates as f64;
+        (0..self.candidates).map(|i| duration * (i as f64 + 0.5) / n).collect()
+    }
+}
+
+/// Get duration of a video (seconds) with ffprobe
+fn probe_duration(src: &Path, sandbox: &Sandbox) -> Option<f64>
+{
+    let res = sandbox.command(&["ffprobe"], &[])
+        .args(["-v", "error", "-show_entries", "format=duration", "-of", "csv=p=0"])
+        .arg(src).output();
+    match res {
+        Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout).trim().parse::<f64>().ok().filter(|d| *d > 0.0),
+        Ok(out) => { tracing::warn!(details=%String::from_utf8_lossy(&out.stderr), "ffprobe failed to get duration."); None },
+        Err(e) => { tracing::warn!(details=%e, "ffprobe exec failed."); None },
+    }
+}
+
+/// Grab the frame at `t` seconds, scaled to SCORE_W x SCORE_H grayscale
+///
+/// # Arguments
+/// * `tonemap` - Tone mapping filter prefix (ends with ','), or empty (see `tonemap_filter`)
+fn grab_frame(src: &Path, t: f64, tonemap: &str, sandbox: &Sandbox) -> Option<GrayFrame>
+{
+    let res = sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[])
+        .args(["-v", "error", "-ss", &format!("{t:.3}")]).arg("-i").arg(src)
+        .args(["-frames:v", "1", "-vf", &format!("{tonemap}scale={SCORE_W}:{SCORE_H},format=gray"), "-f", "rawvideo", "-"])
+        .output();
+    match res {
+        Ok(out) if out.status.success() && out.stdout.len() == SCORE_W * SCORE_H =>
+            Some(GrayFrame { width: SCORE_W, height: SCORE_H, pixels: out.stdout }),
+        Ok(out) => { tracing::debug!(t, details=%String::from_utf8_lossy(&out.stderr), "Failed to grab candidate frame."); None },
+        Err(e) => { tracing::warn!(details=%e, "ffmpeg exec failed."); None },
+    }
+}
+
+/// Pick a poster frame for a video.
+///
+/// # Returns
+/// Timestamp (seconds) of the best candidate, or None if none was acceptable
+/// (or selection is off), in which case ffmpeg should choose.
+pub fn pick_poster_time(src: &Path, tonemap: &str, scoring: &PosterScoring, sandbox: &Sandbox) -> Option<f64>
+{
+    if scoring.candidates == 0 {
+        return None;
+    }
+    let times = scoring.candidate_times(probe_duration(src, sandbox)?);
+    let frames = times.iter().map(|t| grab_frame(src, *t, tonemap, sandbox)).collect::<Vec<_>>();
+    let best = scoring.pick(&frames).map(|i| times[i]);
+    tracing::info!(n_candidates=times.len(), n_grabbed=frames.iter().flatten().count(), picked=?best, "Poster frame selected.");
+    best
+}
+
+
+// Unit tests =====================================================================================
+
+#[cfg(test)]
+fn test_frame(f: impl Fn(usize, usize) -> u8) -> GrayFrame {
+    let (w, h) = (32, 18);
+    GrayFrame { width: w, height: h, pixels: (0..h).flat_map(|y| (0..w).map(move |x| (x, y))).map(|(x, y)| f(x, y)).collect() }
+}
+
+#[test]
+fn test_poster_scoring()
+{
+    let ps = PosterScoring::default();
+    let black = test_frame(|x, _| (x % 2) as u8 * 8);
+    let flat = test_frame(|_, _| 128);
+    let sharp = test_frame(|x, y| if (x / 2 + y / 2) % 2 == 0 { 40 } else { 200 });
+    // Same pattern, blurred: smooth ramps instead of hard edges
+    let blurred = test_frame(|x, _| (120.0 + 80.0 * (x as f64 * 0.4).sin()) as u8);
+
+    assert_eq!(ps.score(&black), None);
+    assert_eq!(ps.score(&flat), None);
+    assert!(ps.score(&sharp).unwrap() > ps.score(&blurred).unwrap());
+    assert!(sharpness(&sharp) > 10.0 * sharpness(&blurred));
+    assert_eq!(luma_stats(&flat), (128.0, 0.0));
+
+    assert_eq!(ps.pick(&[Some(black.clone()), Some(blurred.clone()), None, Some(sharp.clone()), Some(flat.clone())]), Some(3));
+    assert_eq!(ps.pick(&[Some(black), None, Some(flat)]), None);
+    assert_eq!(ps.pick(&[]), None);
+
+    // Blurred frame is acceptable if it's the best there is
+    assert_eq!(ps.pick(&[None, Some(blurred)]), Some(1));
+}
+
+#[test]
+fn test_poster_scoring_config()
+{
+    let ps = "candidates=4, min_luma=10".parse::<PosterScoring>().unwrap();
+    assert_eq!(ps.candidates, 4);
+    assert_eq!(ps.min_luma, 10.0);
+    assert_eq!(ps.max_luma, PosterScoring::default().max_luma);
+    assert_eq!(ps.candidate_times(8.0), vec![1.0, 3.0, 5.0, 7.0]);
+    assert_eq!("off".parse::<PosterScoring>().unwrap().candidates, 0);
+    assert_eq!("".parse::<PosterScoring>(), Ok(PosterScoring::default()));
+
+    assert!("candidates=many".parse::<PosterScoring>().is_err());
+    assert!("min_luma=-1".parse::<PosterScoring>().is_err());
+    assert!("min_luma=200, max_luma=100".parse::<PosterScoring>().is_err());
+    assert!("sharpness=1".parse::<PosterScoring>().is_err());
+    assert!("candidates".parse::<PosterScoring>().is_err());
+}
@@ -0,0 +1,137 @@
+use std::path::Path;
+use rust_decimal::prelude::ToPrimitive;
+use serde_json::json;
+
+use super::{IncomingFile, IngestPolicy, metadata_reader, needs_conversion, effective_loudnorm_target};
+use super::bypass::Conversion;
+use super::sandbox::Sandbox;
+use crate::database::DB;
+use crate::database::models::job_stage;
+use crate::config::ReloadableConfig;
+
+/// Transcoding time per second of video, when there's no history to go by
+const DEFAULT_TRANSCODE_SPEED: f32 = 1.0;
+/// How many past transcodes to estimate speed from
+const SPEED_SAMPLES: i64 = 20;
+
+/// Median of (wall clock seconds / video seconds) over past transcodes
+fn transcode_speed(samples: &[(f32, f32)]) -> f32
+{
+    let mut ratios = samples.iter()
+        .filter(|(_, dur)| *dur > 0.0)
+        .map(|(wall, dur)| wall / dur)
+        .collect::<Vec<_>>();
+    if ratios.is_empty() { return DEFAULT_TRANSCODE_SPEED; }
+    ratios.sort_by(|a, b| a.total_cmp(b));
+    ratios[ratios.len() / 2]
+}
+
+/// Look up how long recent transcodes took compared to video duration
+fn recent_transcode_speed(db: &DB) -> f32
+{
+    let samples = db.get_recent_done_jobs(job_stage::TRANSCODE, SPEED_SAMPLES).unwrap_or_default().into_iter()
+        .filter_map(|j| {
+            let dur = db.get_video(j.video_hash.as_deref()?).ok()?.duration?;
+            Some(((j.updated - j.created).num_milliseconds() as f32 / 1000.0, dur))
+        }).collect::<Vec<_>>();
+    transcode_speed(&samples)
+}
+
+/// Read metadata of a file and report what ingesting it would do, without ingesting it.
+/// The file is left as is.
+///
+/// # Arguments
+/// * `file` - File to check (user_id and loudnorm are used as in a real ingest)
+/// * `policy` - Server processing settings
+/// * `db` - Database, for duplicate detection and transcode speed history
+/// * `videos_dir` - Videos dir, for quota usage
+/// * `sandbox` - Sandbox to run mediainfo in
+/// * `config` - Current settings: user quotas, transcode presets and burn-in settings, to report what they'd do
+///
+/// # Returns
+/// JSON report, or error message if the file can't be read as a video
+pub fn preflight(file: &IncomingFile, policy: &IngestPolicy, db: &DB, videos_dir: &Path, sandbox: &Sandbox, config: &ReloadableConfig) -> Result<serde_json::Value, String>
+{
+    let (quotas, presets) = (&config.quotas, &config.transcode_presets);
+    let md = metadata_reader::read_metadata_from_file(file, false, policy.loudness_target, policy.cfr_fps, sandbox)?;
+    let file_size = file.file_path.metadata().map_err(|e| format!("Failed to get file size: {e}"))?.len();
+    let duration = md.duration.to_f32().unwrap_or(0.0);
+
+    let burn_in = if md.audio_only || md.still_kind.is_some() { Default::default() } else { config.burn_in.resolve(file.burn_in) };
+    let conversion = needs_conversion(&md, policy.target_bitrate, effective_loudnorm_target(&md), burn_in);
+    let remux = matches!(conversion, Some((Conversion::Remux, _, _)));
+    let transcode = conversion.as_ref().filter(|_| !remux).map(|(_, reason, br)| (reason, br));
+    let preset = presets.resolve(db.get_folder_transcode_preset(file.folder_id).map_err(|e| format!("Preset lookup failed: {e}"))?.as_deref());
+    let passes = if preset.two_pass { 2.0 } else { 1.0 };
+    let est_transcode_secs = transcode.as_ref().map(|_| (passes * duration * recent_transcode_speed(db)).round());
+    let est_transcoded_bytes = match (remux, &transcode) {
+        (true, _) => file_size,
+        (false, Some((_, br))) => (preset.capped_bitrate(**br) as f64 * duration as f64 / 8.0) as u64,
+        (false, None) => 0,
+    };
+
+    let duplicate_of = md.content_hash.as_ref()
+        .and_then(|h| db.get_user_videos_by_content_hash(&file.user_id, h).ok())
+        .and_then(|v| v.into_iter().next()).map(|v| v.video_hash);
+
+    let usage = crate::quota::get_user_usage(db, videos_dir, &file.user_id).map_err(|e| format!("Quota check failed: {e}"))?;
+    let quota_problem = quotas.check_new_file(&usage, file_size + est_transcoded_bytes)
+        .and_then(|_| quotas.check_new_job(&usage)).err();
+
+
+    Ok(json!({
+        "filename": file.file_path.file_name().map(|f| f.to_string_lossy().to_string()),
+        "file_size": file_size,
+        "metadata": {
+            "codec": md.orig_codec,
+            "container": md.container,
+            "audio_codec": md.audio_codec,
+            "pixel_format": md.pixel_format,
+            "audio_only": md.audio_only,
+            "still_kind": md.still_kind,
+            "page_count": md.still_kind.map(|_| md.page_count),
+            "duration": duration,
+            "fps": md.fps.to_string(),
+            "total_frames": md.total_frames,
+            "bitrate": md.bitrate,
+            "hdr_format": md.hdr_format,
+            "rotation": md.rotation,
+            "vfr_fps_range": md.vfr_fps_range,
+            "loudness_lufs": md.loudness_lufs,
+            "subtitle_tracks": md.subtitles.len(),
+        },
+        "transcode": {
+            "needed": transcode.is_some(),
+            "remux": remux,
+            "reason": conversion.as_ref().map(|(_, r, _)| r),
+            "preset": transcode.as_ref().map(|(_, br)| json!({
+                "name": preset.name, "video_codec": preset.video_codec, "crf": preset.crf,
+                "video_bitrate": preset.crf.is_none().then(|| preset.capped_bitrate(**br)),
+                "max_bitrate": preset.max_bitrate, "two_pass": preset.two_pass, "max_size": preset.max_size,
+                "audio_codec": preset.audio_codec, "audio_bitrate": preset.audio_bitrate,
+                "loudnorm_target": effective_loudnorm_target(&md), "cfr_fps": md.cfr_fps,
+                "burn_in": (!burn_in.is_empty()).then(|| burn_in.to_string()) })),
+            "estimated_seconds": est_transcode_secs,
+        },
+        "duplicate_of": duplicate_of,
+        "quota": {
+            "used_bytes": usage.used_bytes,
+            "max_total_bytes": quotas.max_total_bytes,
+            "estimated_new_bytes": file_size + est_transcoded_bytes,
+            "ok": quota_problem.is_none(),
+            "problem": quota_problem,
+        },
+    }))
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_preflight_transcode_speed()
+{
+    assert_eq!(transcode_speed(&[]), DEFAULT_TRANSCODE_SPEED);
+    assert_eq!(transcode_speed(&[(10.0, 0.0)]), DEFAULT_TRANSCODE_SPEED);
+    assert_eq!(transcode_speed(&[(30.0, 60.0), (200.0, 10.0), (60.0, 60.0)]), 1.0);
+    assert_eq!(transcode_speed(&[(30.0, 60.0)]), 0.5);
+}
@@ -0,0 +1,87 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use crossbeam_channel::{Receiver, Sender};
+
+// Queues between pipeline stages are bounded (`--queue-capacity`), so a mass upload can't
+// balloon memory. When a stage falls behind, its input queue fills up and the stage before it
+// waits. Eventually the intake queue (API server -> pipeline) fills up too, and new uploads are
+// refused with HTTP 429 ("server busy") until there's room again.
+//
+// Worker pools hold up to `capacity` items for fair scheduling (see `fair_queue::run_fair_pool`)
+// in addition to the ones in their input channel, so a stage's depth can reach twice the capacity.
+
+/// Default for `--queue-capacity`
+pub const DEFAULT_CAPACITY: usize = 100;
+
+/// Message for clients when the pipeline intake queue is full
+pub const BUSY_MSG: &str = "Server is busy processing other videos. Try again in a few minutes.";
+
+/// Pipeline stages with a bounded input queue (intake queue not included)
+pub const STAGES: &[&str] = &["metadata", "transcode", "export", "package", "analysis"];
+
+/// Capacity of pipeline queues, and number of items waiting in each stage, for metrics
+#[derive(Debug)]
+pub struct QueueDepths {
+    capacity: usize,
+    depths: Vec<(&'static str, Arc<AtomicUsize>)>,
+}
+
+impl QueueDepths {
+    pub fn new(capacity: usize) -> QueueDepths {
+        QueueDepths {
+            capacity: capacity.max(1),
+            depths: STAGES.iter().map(|s| (*s, Arc::new(AtomicUsize::new(0)))).collect(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// New bounded channel for a queue
+    pub fn channel<T>(&self) -> (Sender<T>, Receiver<T>) {
+        crossbeam_channel::bounded(self.capacity)
+    }
+
+    /// Depth gauge of a stage, kept up to date by its worker pool
+    pub fn gauge(&self, stage: &str) -> Arc<AtomicUsize> {
+        self.depths.iter().find(|(s, _)| *s == stage).map(|(_, d)| d.clone())
+            .unwrap_or_else(|| panic!("BUG: unknown pipeline stage '{stage}'"))
+    }
+
+    /// Capacity and current depth of each queue, for admin
+    ///
+    /// # Arguments
+    /// * `intake_depth` - Items in the intake queue (length of its channel)
+    pub fn to_json(&self, intake_depth: usize) -> serde_json::Value {
+        let mut depths = serde_json::Map::new();
+        depths.insert("intake".into(), intake_depth.into());
+        for (stage, d) in &self.depths {
+            depths.insert(stage.to_string(), d.load(Relaxed).into());
+        }
+        serde_json::json!({ "capacity": self.capacity, "depths": depths })
+    }
+}
+
+impl Default for QueueDepths {
+    fn default() -> Self { QueueDepths::new(DEFAULT_CAPACITY) }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_queue_depths()
+{
+    let q = QueueDepths::new(0);
+    assert_eq!(q.capacity(), 1);
+    let (tx, _rx) = q.channel::<i32>();
+    tx.try_send(1).unwrap();
+    assert!(tx.try_send(2).is_err());
+
+    q.gauge("transcode").store(7, Relaxed);
+    let j = q.to_json(3);
+    assert_eq!(j["depths"]["intake"], 3);
+    assert_eq!(j["depths"]["transcode"], 7);
+    assert_eq!(j["depths"]["metadata"], 0);
+}
@@ -0,0 +1,338 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed}};
+use crossbeam_channel::{Sender, Receiver};
+
+use super::fair_queue;
+use super::sandbox::Sandbox;
+use crate::database::{DB, models};
+
+/// How long (seconds) a comment stays on screen in the burned-in version
+const NOTE_SECONDS: f32 = 4.0;
+/// Package format identifier and version, in `metadata.json`. Checked on import (see `api_server::package_import`).
+pub const PACKAGE_FORMAT: &str = "clapshot-review-package";
+pub const PACKAGE_FORMAT_VERSION: u64 = 1;
+/// Files that are already compressed. Stored in zip as-is.
+const NO_DEFLATE_SUFFIXES: &str = ".mp4:.mov:.mkv:.webm:.webp:.png:.jpg:.jpeg:.gif:.pdf";
+
+/// Request to build a review package (zip) of a video for external handoff
+#[derive(Debug, Clone)]
+pub struct PackageRequest {
+    pub video_hash: String,
+    pub user_id: String,
+    /// Dir to write `<name>.zip` into
+    pub dst_dir: PathBuf,
+    /// Name of the zip file (without extension) and its root folder
+    pub name: String,
+    /// Download URL of the zip, for notifying the user
+    pub url: String,
+    /// Existing files to include: (source, path in package)
+    pub files: Vec<(PathBuf, String)>,
+    /// Generated files to include: (path in package, contents)
+    pub texts: Vec<(String, String)>,
+    /// Render a version with timecode and comments (from `comments.srt`) burned in, from this file
+    pub burn_in_src: Option<PathBuf>,
+    pub job_id: Option<i32>,
+}
+
+impl PackageRequest {
+    pub fn zip_path(&self) -> PathBuf {
+        self.dst_dir.join(format!("{}.zip", self.name))
+    }
+}
+
+/// Result of building a package. `error` is None on success.
+#[derive(Debug, Clone)]
+pub struct PackageResult {
+    pub req: PackageRequest,
+    pub error: Option<String>,
+}
+
+/// Parse a comment timecode into seconds. Accepts "HH:MM:SS:FF" (frames, needs `fps`),
+/// "HH:MM:SS(.mmm)" and "MM:SS(.mmm)".
+pub fn parse_comment_timecode(tc: &str, fps: Option<f32>) -> Option<f32>
+{
+    let parts = tc.trim().split(':').map(|p| p.parse::<f32>().ok()).collect::<Option<Vec<_>>>()?;
+    let secs = match parts[..] {
+        [h, m, s, f] => h * 3600.0 + m * 60.0 + s + f / fps.filter(|f| *f > 0.0)?,
+        [h, m, s] => h * 3600.0 + m * 60.0 + s,
+        [m, s] => m * 60.0 + s,
+        _ => return None,
+    };
+    (secs.is_finite() && secs >= 0.0).then_some(secs)
+}
+
+/// Format seconds as an SRT timestamp, "HH:MM:SS,mmm"
+fn srt_time(secs: f32) -> String {
+    let ms = (secs.max(0.0) * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02},{:03}", ms / 3_600_000, (ms / 60_000) % 60, (ms / 1000) % 60, ms % 1000)
+}
+
+/// Timecoded comments as SRT captions, "#number username: comment", each shown for its time range
+/// (`timecode_end`), or for `NOTE_SECONDS` if it has none. Comments without a (parseable) timecode are skipped.
+pub fn comments_srt(comments: &[models::Comment], fps: Option<f32>) -> String
+{
+    let mut notes = comments.iter()
+        .filter_map(|c| c.timecode.as_deref().and_then(|tc| parse_comment_timecode(tc, fps)).map(|t| (t, c)))
+        .collect::<Vec<_>>();
+    notes.sort_by(|a, b| a.0.total_cmp(&b.0));
+    notes.iter().enumerate().map(|(i, (t, c))| {
+        let text = c.comment.lines().filter(|l| !l.trim().is_empty()).collect::<Vec<_>>().join("\n");
+        let end = c.timecode_end.as_deref().and_then(|tc| parse_comment_timecode(tc, fps)).filter(|e| e > t).unwrap_or(t + NOTE_SECONDS);
+        format!("{}\n{} --> {}\n#{} {}: {}\n\n", i + 1, srt_time(*t), srt_time(end), c.number, c.username, text)
+    }).collect()
+}
+
+/// All comments of a video as CSV (RFC 4180), with a header row
+pub fn comments_csv(comments: &[models::Comment]) -> String
+{
+    fn esc(s: &str) -> String {
+        if s.contains([',', '"', '\n', '\r']) { format!("\"{}\"", s.replace('"', "\"\"")) }
+        else { s.to_string() }
+    }
+    let opt = |v: Option<i32>| v.map(|v| v.to_string()).unwrap_or_default();
+    let mut out = String::from("id,number,parent_id,timecode,timecode_end,page,user_id,username,created,comment,drawing\r\n");
+    for c in comments {
+        out += &[c.id.to_string(), c.number.to_string(), opt(c.parent_id), esc(c.timecode.as_deref().unwrap_or("")),
+            esc(c.timecode_end.as_deref().unwrap_or("")), opt(c.page),
+            esc(&c.user_id), esc(&c.username), c.created.format("%Y-%m-%d %H:%M:%S").to_string(),
+            esc(&c.comment), esc(c.drawing.as_deref().unwrap_or(""))].join(",");
+        out += "\r\n";
+    }
+    out
+}
+
+/// Make a file or title safe to use as a file name
+pub fn safe_filename(s: &str) -> String {
+    let s = s.chars().map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' }).collect::<String>();
+    s.trim_matches(['_', '.']).chars().take(60).collect()
+}
+
+/// Gather contents of a review package for a video:
+/// playable file (proxy) or the original, optional burned-in version, comment report (CSV and SRT),
+/// drawings, caption tracks and metadata JSON. Comments (JSON) and provenance are included
+/// for importing the package into another instance. Internal comments are left out, as packages
+/// are typically given to clients.
+///
+/// # Arguments
+/// * `db` - Database
+/// * `videos_dir` - Dir of ingested videos
+/// * `url_base` - Base URL of the server, for the download link
+/// * `v` - Video to package
+/// * `user_id` - User requesting the package
+/// * `burn_in` - Render a version with timecode and comments burned in (videos only)
+/// * `original` - Include the original file instead of the proxy (e.g. for handoff to another studio)
+pub fn make_request(db: &DB, videos_dir: &Path, url_base: &str, v: &models::Video, user_id: &str, burn_in: bool, original: bool) -> Result<PackageRequest, String>
+{
+    let vh = &v.video_hash;
+    let video_dir = videos_dir.join(vh);
+    let proxy = super::playable_file(v, videos_dir)?;
+    let orig = v.orig_filename.as_ref().filter(|f| super::is_plain_file_name(f)).map(|f| video_dir.join("orig").join(f)).filter(|f| f.is_file());
+    let (media, is_orig) = match orig {
+        Some(f) if original || v.still_kind.is_some() => (f, true),
+        _ if original => return Err("Original file of the video is not available".into()),
+        _ => (proxy.clone(), false),
+    };
+    let media_ext = media.extension().map(|e| e.to_string_lossy().to_string()).unwrap_or("mp4".into());
+    let fps = v.fps.as_deref().and_then(|f| f.parse::<f32>().ok());
+    let comments = db.get_video_comments(vh).map_err(|e| format!("DB error: {e}"))?
+        .into_iter().filter(|c| !c.is_internal()).collect::<Vec<_>>();
+    let subtitles = db.get_video_subtitles(vh).map_err(|e| format!("DB error: {e}"))?;
+
+    let mut files = vec![(media, format!("{}.{}", if is_orig { "original" } else { "proxy" }, media_ext))];
+    for c in &comments {
+        if let Some(d) = &c.drawing {
+            let path = video_dir.join("drawings").join(d);
+            if path.is_file() { files.push((path, format!("drawings/{}", safe_filename(d)))); }
+        }
+    }
+    let mut caption_names = vec![];
+    for s in &subtitles {
+        let name = format!("captions/{}_{}.vtt", s.id, safe_filename(s.language.as_deref().unwrap_or(&s.title)));
+        files.push((video_dir.join("subs").join(format!("{}.vtt", s.id)), name.clone()));
+        caption_names.push(name);
+    }
+
+    let mut metadata = v.to_json().map_err(|e| e.to_string())?;
+    metadata["raw_metadata_all"] = v.raw_metadata_all.as_deref().and_then(|m| serde_json::from_str(m).ok()).unwrap_or_default();
+    metadata["captions"] = serde_json::json!(caption_names);
+    metadata["media"] = serde_json::json!(if is_orig { "original" } else { "proxy" });
+    metadata["comment_count"] = serde_json::json!(comments.len());
+    metadata["exported_by"] = serde_json::json!(user_id);
+    metadata["exported_at"] = serde_json::json!(chrono::Utc::now().to_rfc3339());
+    metadata["format"] = serde_json::json!(PACKAGE_FORMAT);
+    metadata["format_version"] = serde_json::json!(PACKAGE_FORMAT_VERSION);
+    metadata["origin"] = serde_json::json!(url_base);
+    metadata["sources"] = serde_json::json!(db.get_video_sources(vh).map_err(|e| format!("DB error: {e}"))?);
+    metadata["operation"] = serde_json::json!(db.get_video_operation(vh).map_err(|e| format!("DB error: {e}"))?);
+
+    let texts = vec![
+        ("comments.csv".to_string(), comments_csv(&comments)),
+        ("comments.srt".to_string(), comments_srt(&comments, fps)),
+        ("comments.json".to_string(), serde_json::to_string_pretty(&comments).map_err(|e| e.to_string())?),
+        ("metadata.json".to_string(), serde_json::to_string_pretty(&metadata).map_err(|e| e.to_string())?),
+    ];
+
+    let title = safe_filename(v.title.as_deref().unwrap_or(vh));
+    let name = format!("{}_review_{}", if title.is_empty() { vh.as_str() } else { &title }, &uuid::Uuid::new_v4().simple().to_string()[..8]);
+    Ok(PackageRequest {
+        video_hash: vh.clone(),
+        user_id: user_id.into(),
+        dst_dir: video_dir.join("packages"),
+        url: format!("{}/videos/{}/packages/{}.zip", url_base, vh, name),
+        name,
+        files,
+        texts,
+        burn_in_src: (burn_in && v.still_kind.is_none()).then_some(proxy),
+        job_id: None,
+    })
+}
+
+/// Build ffmpeg arguments for the burned-in version: comments as subtitles, running timecode top right
+fn burn_in_args(src: &Path, srt: &Path, dst: &Path) -> Vec<OsString>
+{
+    let filter = format!("subtitles='{}',drawtext=text='%{{pts\\:hms}}':x=w-tw-20:y=20:fontsize=28:fontcolor=white:box=1:boxcolor=black@0.6:boxborderw=6",
+        super::burn_in::escape_filter_value(&srt.to_string_lossy()));
+    let mut args: Vec<OsString> = vec!["-y".into(), "-nostats".into(), "-i".into(), src.into(), "-vf".into(), filter.into()];
+    args.extend(["-c:v", "libx264", "-preset", "fast", "-crf", "23", "-pix_fmt", "yuv420p", "-c:a", "aac", "-movflags", "+faststart"].map(OsString::from));
+    args.push(dst.into());
+    args
+}
+
+/// Run a command, mapping failures to an error message with last line of stderr
+fn run(mut cmd: std::process::Command, what: &str) -> Result<(), String>
+{
+    tracing::debug!("Exec: {:?}", cmd);
+    match cmd.output() {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            tracing::error!(stderr=%stderr, "{} failed", what);
+            Err(format!("{} exited with error: {}", what, stderr.lines().last().unwrap_or("")))
+        },
+        Err(e) => Err(format!("Failed to execute {}: {}", what, e)),
+    }
+}
+
+/// Assemble package contents in a staging dir and zip it.
+fn assemble(req: &PackageRequest, staging: &Path, sandbox: &Sandbox) -> Result<(), String>
+{
+    let in_pkg = |rel: &str| -> Result<PathBuf, String> {
+        let p = staging.join(rel);
+        std::fs::create_dir_all(p.parent().unwrap_or(staging)).map_err(|e| format!("Failed to create dir: {e}"))?;
+        Ok(p)
+    };
+    for (src, rel) in &req.files {
+        let dst = in_pkg(rel)?;
+        // Hard link big files if possible, they are on the same filesystem
+        std::fs::hard_link(src, &dst).or_else(|_| std::fs::copy(src, &dst).map(|_| ()))
+            .map_err(|e| format!("Failed to add '{}': {}", rel, e))?;
+    }
+    for (rel, contents) in &req.texts {
+        std::fs::write(in_pkg(rel)?, contents).map_err(|e| format!("Failed to write '{}': {}", rel, e))?;
+    }
+    if let Some(src) = &req.burn_in_src {
+        tracing::info!("Rendering burned-in version");
+        let mut cmd = sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[staging]);
+        cmd.args(burn_in_args(src, &staging.join("comments.srt"), &staging.join("burned_in.mp4")));
+        run(cmd, "ffmpeg")?;
+    }
+    let mut cmd = sandbox.command(&["nice", "-n", "10", "--", "zip", "-q", "-r", "-n", NO_DEFLATE_SUFFIXES], &[&req.dst_dir]);
+    cmd.current_dir(&req.dst_dir).arg(req.zip_path()).arg(&req.name);
+    run(cmd, "zip")
+}
+
+/// Build a review package zip. Removes the staging dir afterwards, and partial output on failure.
+pub fn build_package(req: &PackageRequest, sandbox: &Sandbox) -> Result<(), String>
+{
+    let staging = req.dst_dir.join(&req.name);
+    std::fs::create_dir_all(&staging).map_err(|e| format!("Failed to create package dir: {e}"))?;
+    tracing::info!(name=%req.name, n_files=req.files.len(), burn_in=req.burn_in_src.is_some(), "Building review package");
+    let res = assemble(req, &staging, sandbox);
+    std::fs::remove_dir_all(&staging).unwrap_or_else(|e| tracing::warn!(details=%e, "Failed to remove package staging dir."));
+    if res.is_err() && req.zip_path().exists() {
+        std::fs::remove_file(req.zip_path()).ok();
+    }
+    res
+}
+
+/// Listen to package requests and build them in a thread pool.
+/// Requests are scheduled fairly between users (see `fair_queue::run_fair_pool`).
+///
+/// # Arguments
+/// * `inq` - Channel to receive requests
+/// * `outq` - Channel to send results
+/// * `n_workers` - Number of worker threads
+/// * `depth` - Number of items waiting, for metrics
+/// * `sandbox` - Sandbox to run ffmpeg and zip in
+/// * `priority_of` - Function that returns current processing priority of a user
+pub fn run_forever<P>(inq: Receiver<PackageRequest>, outq: Sender<PackageResult>, n_workers: Arc<AtomicUsize>, depth: Arc<AtomicUsize>, sandbox: Sandbox, terminate_flag: Arc<AtomicBool>, priority_of: P)
+    where P: Fn(&str) -> i32
+{
+    let _span = tracing::info_span!("PACKAGES").entered();
+    tracing::info!(n_workers = n_workers.load(Relaxed), "Starting.");
+    fair_queue::run_fair_pool(inq, &n_workers, &depth, |r: &PackageRequest| r.user_id.clone(), priority_of, &terminate_flag, move |req: PackageRequest| {
+        let _span = tracing::info_span!("review_package", video=%req.video_hash, user=%req.user_id).entered();
+        let error = build_package(&req, &sandbox).err();
+        outq.send(PackageResult { req, error }).is_ok()
+    });
+    tracing::info!("Exiting.");
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_review_package_texts()
+{
+    assert_eq!(parse_comment_timecode("00:01:02:12", Some(24.0)), Some(62.5));
+    assert_eq!(parse_comment_timecode("00:01:02:12", None), None);
+    assert_eq!(parse_comment_timecode("01:00:00.5", None), Some(3600.5));
+    assert_eq!(parse_comment_timecode("2:30", None), Some(150.0));
+    assert_eq!(parse_comment_timecode("abc", None), None);
+    assert_eq!(srt_time(3661.25), "01:01:01,250");
+
+    let mkcom = |id: i32, tc: Option<&str>, text: &str| models::Comment {
+        id, video_hash: "vh".into(), parent_id: None, created: chrono::NaiveDateTime::default(), edited: None,
+        user_id: "u1".into(), username: "Alice".into(), comment: text.into(), timecode: tc.map(String::from),
+        drawing: None, page: None, region: None, visibility: models::comment_visibility::EXTERNAL.into(), number: id + 10, timecode_end: None };
+    let mut comments = vec![mkcom(1, Some("00:00:10:00"), "Too dark,\nfix \"grade\""), mkcom(2, Some("00:00:02.000"), "Logo"), mkcom(3, None, "General")];
+    comments[1].timecode_end = Some("00:00:03.5".into());
+    assert_eq!(comments_srt(&comments, Some(25.0)),
+        "1\n00:00:02,000 --> 00:00:03,500\n#12 Alice: Logo\n\n2\n00:00:10,000 --> 00:00:14,000\n#11 Alice: Too dark,\nfix \"grade\"\n\n");
+    let csv = comments_csv(&comments);
+    let mut lines = csv.split("\r\n");
+    assert_eq!(lines.next(), Some("id,number,parent_id,timecode,timecode_end,page,user_id,username,created,comment,drawing"));
+    assert_eq!(lines.next(), Some("1,11,,00:00:10:00,,,u1,Alice,1970-01-01 00:00:00,\"Too dark,\nfix \"\"grade\"\"\","));
+
+    assert_eq!(safe_filename("Final cut: v2/3.mov"), "Final_cut__v2_3.mov");
+    let args = burn_in_args(Path::new("/v/it's:here/video.mp4"), Path::new("/p/it's:here/comments.srt"), Path::new("/p/out.mp4"));
+    assert!(args[5].to_string_lossy().starts_with(r"subtitles='/p/it'\\\''s\:here/comments.srt',drawtext="));
+}
+
+#[test]
+fn test_build_review_package()
+{
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("video.mp4");
+    std::fs::write(&src, b"not really a video").unwrap();
+    let req = PackageRequest {
+        video_hash: "vh".into(), user_id: "u".into(), dst_dir: dir.path().join("packages"), name: "pkg".into(), url: "".into(),
+        files: vec![(src, "proxy.mp4".into())],
+        texts: vec![("comments.csv".into(), "id\r\n".into()), ("captions/1_en.vtt".into(), "WEBVTT\n".into())],
+        burn_in_src: None, job_id: None };
+    build_package(&req, &Sandbox::default()).unwrap();
+    assert!(req.zip_path().is_file());
+    assert!(!req.dst_dir.join("pkg").exists(), "staging dir should be removed");
+
+    let out = std::process::Command::new("unzip").arg("-Z1").arg(req.zip_path()).output().unwrap();
+    let mut listing = String::from_utf8_lossy(&out.stdout).lines().map(String::from).collect::<Vec<_>>();
+    listing.sort();
+    assert_eq!(listing, vec!["pkg/", "pkg/captions/", "pkg/captions/1_en.vtt", "pkg/comments.csv", "pkg/proxy.mp4"]);
+
+    // Missing source file fails and leaves nothing behind
+    let bad = PackageRequest { files: vec![(dir.path().join("nonexisting"), "x".into())], ..req.clone() };
+    std::fs::remove_file(req.zip_path()).unwrap();
+    assert!(build_package(&bad, &Sandbox::default()).is_err());
+    assert!(!bad.zip_path().exists() && !bad.dst_dir.join("pkg").exists());
+}
@@ -0,0 +1,168 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tracing;
+
+/// How external tools (ffmpeg, ffprobe, mediainfo) are isolated.
+/// They parse untrusted uploads, so a parser bug shouldn't give access to the whole server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SandboxMode {
+    /// Run tools directly
+    Off,
+    /// Resource limits only (memory, CPU time), using `prlimit`
+    Rlimit,
+    /// Resource limits + bubblewrap: read-only filesystem except output dir, no network
+    Bwrap,
+    /// Resource limits + firejail: read-only filesystem except output dir, no network
+    Firejail,
+}
+
+impl std::str::FromStr for SandboxMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" | "none" => Ok(SandboxMode::Off),
+            "rlimit" => Ok(SandboxMode::Rlimit),
+            "bwrap" | "bubblewrap" => Ok(SandboxMode::Bwrap),
+            "firejail" => Ok(SandboxMode::Firejail),
+            _ => Err(format!("Unknown sandbox mode '{}'. Use off, rlimit, bwrap or firejail.", s)),
+        }
+    }
+}
+
+/// Sandbox configuration for running external tools
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sandbox {
+    pub mode: SandboxMode,
+    /// Max virtual memory per process (bytes), None = unlimited
+    pub max_mem_bytes: Option<u64>,
+    /// Max CPU time per process (seconds), None = unlimited
+    pub max_cpu_secs: Option<u64>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox { mode: SandboxMode::Off, max_mem_bytes: None, max_cpu_secs: None }
+    }
+}
+
+/// Check that a sandbox wrapper actually works here, by running `true` in it
+fn wrapper_works(sb: &Sandbox) -> bool {
+    sb.command(&["true"], &[])
+        .stdout(Stdio::null()).stderr(Stdio::null())
+        .status().map(|s| s.success()).unwrap_or(false)
+}
+
+impl Sandbox {
+    /// Check that the configured sandbox works on this system, and fall back to
+    /// a weaker one if it doesn't (e.g. bwrap not installed, or user namespaces disabled).
+    /// Logs a warning on fallback.
+    pub fn probe(self) -> Sandbox
+    {
+        let mut sb = self;
+        while sb.mode != SandboxMode::Off && !wrapper_works(&sb) {
+            let fallback = match sb.mode {
+                SandboxMode::Bwrap | SandboxMode::Firejail => SandboxMode::Rlimit,
+                _ => SandboxMode::Off,
+            };
+            tracing::warn!(mode=?sb.mode, fallback=?fallback, "Sandbox not available on this system. Falling back.");
+            sb.mode = fallback;
+        }
+        tracing::info!(mode=?sb.mode, max_mem_bytes=?sb.max_mem_bytes, max_cpu_secs=?sb.max_cpu_secs, "Sandbox for external tools.");
+        sb
+    }
+
+    /// Make a Command that runs `argv` in the sandbox.
+    ///
+    /// # Arguments
+    /// * `argv` - Program and its arguments, e.g. `["nice", "-n", "10", "--", "ffmpeg"]`. More args can be added to the returned Command.
+    /// * `writable_dirs` - Directories the program may write to. Everything else is read-only (in bwrap/firejail modes).
+    pub fn command<S: AsRef<OsStr>>(&self, argv: &[S], writable_dirs: &[&Path]) -> Command
+    {
+        let mut wrapper: Vec<std::ffi::OsString> = vec![];
+        match self.mode {
+            SandboxMode::Off | SandboxMode::Rlimit => {},
+            SandboxMode::Bwrap => {
+                wrapper.extend(["bwrap", "--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc",
+                    "--unshare-all", "--die-with-parent", "--new-session"].map(|s| s.into()));
+                for d in writable_dirs {
+                    wrapper.extend(["--bind".into(), d.as_os_str().into(), d.as_os_str().into()]);
+                }
+                wrapper.push("--".into());
+            },
+            SandboxMode::Firejail => {
+                wrapper.extend(["firejail", "--quiet", "--noprofile", "--net=none", "--read-only=/"].map(|s| s.into()));
+                for d in writable_dirs {
+                    let mut arg = std::ffi::OsString::from("--read-write=");
+                    arg.push(d.as_os_str());
+                    wrapper.push(arg);
+                }
+                wrapper.push("--".into());
+            },
+        }
+        if self.mode != SandboxMode::Off && (self.max_mem_bytes.is_some() || self.max_cpu_secs.is_some()) {
+            wrapper.push("prlimit".into());
+            if let Some(m) = self.max_mem_bytes { wrapper.push(format!("--as={m}").into()); }
+            if let Some(c) = self.max_cpu_secs { wrapper.push(format!("--cpu={c}").into()); }
+            wrapper.push("--".into());
+        }
+        let mut all = wrapper.into_iter().chain(argv.iter().map(|a| a.as_ref().to_os_string()));
+        let mut cmd = Command::new(all.next().expect("empty argv"));
+        cmd.args(all);
+        cmd
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[cfg(test)]
+fn argv_of(cmd: &Command) -> Vec<String> {
+    std::iter::once(cmd.get_program()).chain(cmd.get_args()).map(|s| s.to_string_lossy().to_string()).collect()
+}
+
+#[test]
+fn test_sandbox_command_wrapping()
+{
+    let out = Path::new("/data/videos/abc");
+    let limits = |mode| Sandbox { mode, max_mem_bytes: Some(1000), max_cpu_secs: Some(60) };
+
+    let cmd = Sandbox::default().command(&["ffmpeg", "-i"], &[out]);
+    assert_eq!(argv_of(&cmd), vec!["ffmpeg", "-i"]);
+
+    let cmd = limits(SandboxMode::Off).command(&["ffmpeg"], &[out]);
+    assert_eq!(argv_of(&cmd), vec!["ffmpeg"]);
+
+    let cmd = limits(SandboxMode::Rlimit).command(&["ffmpeg"], &[out]);
+    assert_eq!(argv_of(&cmd), vec!["prlimit", "--as=1000", "--cpu=60", "--", "ffmpeg"]);
+
+    let cmd = limits(SandboxMode::Bwrap).command(&["ffmpeg"], &[out]);
+    let argv = argv_of(&cmd);
+    assert_eq!(argv[0], "bwrap");
+    assert!(argv.windows(3).any(|w| w == ["--bind", "/data/videos/abc", "/data/videos/abc"]));
+    assert!(argv.contains(&"--unshare-all".to_string()));
+    assert_eq!(&argv[argv.len()-5..], ["prlimit", "--as=1000", "--cpu=60", "--", "ffmpeg"]);
+
+    let cmd = Sandbox { max_cpu_secs: None, ..limits(SandboxMode::Firejail) }.command(&["mediainfo"], &[out]);
+    let argv = argv_of(&cmd);
+    assert_eq!(argv[0], "firejail");
+    assert!(argv.contains(&"--read-write=/data/videos/abc".to_string()));
+    assert_eq!(&argv[argv.len()-4..], ["prlimit", "--as=1000", "--", "mediainfo"]);
+}
+
+#[test]
+fn test_sandbox_mode_parse()
+{
+    assert_eq!("bwrap".parse::<SandboxMode>(), Ok(SandboxMode::Bwrap));
+    assert_eq!("OFF".parse::<SandboxMode>(), Ok(SandboxMode::Off));
+    assert!("docker".parse::<SandboxMode>().is_err());
+}
+
+#[test]
+fn test_sandbox_probe_fallback()
+{
+    // Unusable sandbox (here: too little memory to even start) must degrade instead of breaking all processing
+    let sb = Sandbox { mode: SandboxMode::Rlimit, max_mem_bytes: Some(1), max_cpu_secs: None }.probe();
+    assert_eq!(sb.mode, SandboxMode::Off);
+    assert_eq!(Sandbox::default().probe().mode, SandboxMode::Off);
+}
@@ -0,0 +1,291 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crossbeam_channel::Sender;
+
+use super::{IncomingFile, DetailedMsg};
+use super::cleanup_rejected::clean_up_rejected_file;
+
+// With `--scanner`, new files (uploads and files dropped in incoming/) are scanned for malware or
+// against content policies before anything else reads them. Scanner is either ClamAV's daemon
+// ("clamd:<socket path>", files are streamed to it with INSTREAM) or a command, called as
+// "CMD <file>", whose exit code tells the verdict: 0 = clean, 1 = rejected (reason on stdout),
+// anything else = scan failed. Image sequences are scanned frame by frame.
+//
+// Rejected files are moved to quarantine/, out of reach of the pipeline, and the uploader gets
+// the reason. If the scan itself fails (e.g. clamd is down), the file isn't let through either,
+// but moved to rejected/ like other files that failed processing, to be retried by an admin.
+
+/// Messages for the uploader, see `spawn_scan`
+pub const REJECTED_MSG: &str = "File rejected by content scan";
+pub const FAILED_MSG: &str = "Content scan failed";
+
+/// Max time to wait for clamd to answer
+const CLAMD_TIMEOUT: Duration = Duration::from_secs(600);
+/// Size of INSTREAM chunks
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scanner {
+    /// ClamAV daemon, at this Unix socket
+    Clamd(PathBuf),
+    /// Command with arguments, called with the file to scan appended
+    Command(String),
+}
+
+impl std::str::FromStr for Scanner {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err("Scanner is empty".into()),
+            s => match s.strip_prefix("clamd:") {
+                Some("") => Err("Missing clamd socket path, e.g. 'clamd:/run/clamav/clamd.ctl'".into()),
+                Some(sock) => Ok(Scanner::Clamd(sock.into())),
+                None => Ok(Scanner::Command(s.into())),
+            },
+        }
+    }
+}
+
+/// Result of a successful scan
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Clean,
+    /// Rejected, with reason (e.g. signature name) to tell the uploader
+    Rejected(String),
+}
+
+/// Parse clamd's answer to INSTREAM, e.g. "stream: OK" or "stream: Eicar-Signature FOUND"
+fn parse_clamd_reply(reply: &str) -> Result<Verdict, String>
+{
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let res = reply.strip_prefix("stream:").map(str::trim).unwrap_or(reply);
+    if res == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(sig) = res.strip_suffix(" FOUND") {
+        Ok(Verdict::Rejected(format!("Malware detected: {}", sig.trim())))
+    } else {
+        Err(format!("clamd error: {}", res))
+    }
+}
+
+/// Stream a file to clamd for scanning
+fn scan_clamd(socket: &Path, file: &Path) -> Result<Verdict, String>
+{
+    let io_err = |e: std::io::Error| format!("clamd connection failed ({}): {}", socket.display(), e);
+    let mut f = std::fs::File::open(file).map_err(|e| format!("Failed to open file: {e}"))?;
+    let mut conn = std::os::unix::net::UnixStream::connect(socket).map_err(io_err)?;
+    conn.set_read_timeout(Some(CLAMD_TIMEOUT)).map_err(io_err)?;
+    conn.set_write_timeout(Some(CLAMD_TIMEOUT)).map_err(io_err)?;
+
+    conn.write_all(b"zINSTREAM\0").map_err(io_err)?;
+    let mut buf = vec![0u8; CLAMD_CHUNK_SIZE];
+    loop {
+        let n = f.read(&mut buf).map_err(|e| format!("Failed to read file: {e}"))?;
+        // clamd stops reading when a stream is over its size limit, and answers with an error
+        if let Err(e) = conn.write_all(&(n as u32).to_be_bytes()).and_then(|_| conn.write_all(&buf[..n])) {
+            tracing::debug!(details=%e, "clamd stopped reading stream.");
+            break;
+        }
+        if n == 0 { break; }
+    }
+    let mut reply = String::new();
+    conn.read_to_string(&mut reply).map_err(io_err)?;
+    parse_clamd_reply(&reply)
+}
+
+/// Run the scanner command (`<cmd> [args] <file>`), and tell the verdict from its exit code.
+/// Runs outside the sandbox, like the analyzer: scanners are configured by the admin.
+fn scan_command(cmd: &str, file: &Path) -> Result<Verdict, String>
+{
+    let mut argv = cmd.split_whitespace();
+    let prog = argv.next().ok_or("Scanner command is empty")?;
+    let mut c = std::process::Command::new(prog);
+    c.args(argv).arg(file);
+    tracing::debug!("Exec: {:?}", c);
+    let out = c.output().map_err(|e| format!("Failed to execute scanner '{}': {}", prog, e))?;
+    let last_line = |s: &[u8]| String::from_utf8_lossy(s).lines().rfind(|l| !l.trim().is_empty()).unwrap_or("").trim().to_string();
+    match out.status.code() {
+        Some(0) => Ok(Verdict::Clean),
+        Some(1) => Ok(Verdict::Rejected(Some(last_line(&out.stdout)).filter(|r| !r.is_empty()).unwrap_or("Rejected by content scan".into()))),
+        _ => Err(format!("Scanner exited with error: {}", last_line(&out.stderr))),
+    }
+}
+
+/// Scan a file, or each file in a directory (image sequence). First rejected file rejects all.
+pub fn scan(scanner: &Scanner, path: &Path) -> Result<Verdict, String>
+{
+    let files = match path.is_dir() {
+        false => vec![path.to_path_buf()],
+        true => {
+            let mut files = path.read_dir().map_err(|e| format!("Failed to list dir: {e}"))?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.is_file())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        }
+    };
+    for f in files {
+        let verdict = match scanner {
+            Scanner::Clamd(socket) => scan_clamd(socket, &f),
+            Scanner::Command(cmd) => scan_command(cmd, &f),
+        }?;
+        if let Verdict::Rejected(reason) = verdict {
+            let name = f.file_name().unwrap_or_default().to_string_lossy();
+            return Ok(Verdict::Rejected(if f == path { reason } else { format!("{name}: {reason}") }));
+        }
+    }
+    Ok(Verdict::Clean)
+}
+
+/// Move a rejected file (or dir) to quarantine/, in a new subdir so names don't clash
+///
+/// # Returns
+/// * New path of the file
+pub fn quarantine(data_dir: &Path, path: &Path) -> std::io::Result<PathBuf>
+{
+    let dir = data_dir.join("quarantine").join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&dir)?;
+    let dst = dir.join(path.file_name().unwrap_or_default());
+    std::fs::rename(path, &dst)?;
+    Ok(dst)
+}
+
+/// Scan a new file in a background thread. Clean files are sent to `res_tx` as they are.
+/// Rejected ones are moved to quarantine/, and ones that couldn't be scanned to rejected/.
+///
+/// # Arguments
+/// * `file` - New file or image sequence dir
+/// * `data_dir` - Server data dir
+/// * `scanner` - Scanner to use
+/// * `res_tx` - Channel to send the result to, with the file's original path
+pub fn spawn_scan(file: IncomingFile, data_dir: PathBuf, scanner: Scanner, res_tx: Sender<(PathBuf, Result<IncomingFile, DetailedMsg>)>)
+{
+    std::thread::spawn(move || {
+        let _span = tracing::info_span!("SCAN", user=%file.user_id, file=%file.file_path.display(), trace_id=file.trace_id.as_deref()).entered();
+        let err = |msg: &str, details: String| DetailedMsg {
+            msg: msg.into(),
+            details,
+            src_file: file.file_path.clone(),
+            user_id: file.user_id.clone() };
+        let res = match scan(&scanner, &file.file_path) {
+            Ok(Verdict::Clean) => {
+                tracing::info!("Content scan passed.");
+                Ok(file.clone())
+            },
+            Ok(Verdict::Rejected(reason)) => {
+                tracing::warn!(reason=%reason, "File rejected by content scan.");
+                match quarantine(&data_dir, &file.file_path) {
+                    Ok(dst) => { tracing::info!(quarantined=%dst.display(), "File quarantined."); },
+                    Err(e) => {
+                        tracing::error!(details=%e, "Failed to quarantine file. Deleting it.");
+                        let rm = if file.file_path.is_dir() { std::fs::remove_dir_all(&file.file_path) } else { std::fs::remove_file(&file.file_path) };
+                        if let Err(e) = rm { tracing::error!(details=%e, "Failed to delete rejected file."); }
+                    }
+                };
+                Err(err(REJECTED_MSG, reason))
+            },
+            Err(e) => {
+                tracing::error!(details=%e, "Content scan failed.");
+                let cleanup_err = match clean_up_rejected_file(&data_dir, &file.file_path, None) {
+                    Err(e) => format!(" Cleanup also failed: {:?}", e),
+                    Ok(()) => "".into() };
+                Err(err(FAILED_MSG, e + &cleanup_err))
+            },
+        };
+        if let Err(e) = res_tx.send((file.file_path.clone(), res)) {
+            tracing::error!(details=%e, "Failed to send scan result to pipeline.");
+        }
+    });
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_parse_scanner()
+{
+    assert_eq!("clamd:/run/clamav/clamd.ctl".parse::<Scanner>(), Ok(Scanner::Clamd("/run/clamav/clamd.ctl".into())));
+    assert_eq!(" /usr/bin/scan --strict ".parse::<Scanner>(), Ok(Scanner::Command("/usr/bin/scan --strict".into())));
+    assert!("clamd:".parse::<Scanner>().is_err());
+    assert!("  ".parse::<Scanner>().is_err());
+}
+
+#[test]
+fn test_parse_clamd_reply()
+{
+    assert_eq!(parse_clamd_reply("stream: OK\0"), Ok(Verdict::Clean));
+    assert_eq!(parse_clamd_reply("stream: Win.Test.EICAR_HDB-1 FOUND\0"), Ok(Verdict::Rejected("Malware detected: Win.Test.EICAR_HDB-1".into())));
+    assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    assert!(parse_clamd_reply("").is_err());
+}
+
+#[test]
+fn test_scan_clamd()
+{
+    let dir = assert_fs::TempDir::new().unwrap();
+    let socket = dir.path().join("clamd.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+
+    // Fake clamd that reads the stream and flags files containing "EVIL"
+    let server = std::thread::spawn(move || {
+        for _ in 0..2 {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut cmd = [0u8; 10];
+            conn.read_exact(&mut cmd).unwrap();
+            assert_eq!(&cmd, b"zINSTREAM\0");
+            let mut data = vec![];
+            loop {
+                let mut len = [0u8; 4];
+                conn.read_exact(&mut len).unwrap();
+                let len = u32::from_be_bytes(len) as usize;
+                if len == 0 { break; }
+                let mut chunk = vec![0u8; len];
+                conn.read_exact(&mut chunk).unwrap();
+                data.extend(chunk);
+            }
+            let evil = data.windows(4).any(|w| w == b"EVIL");
+            conn.write_all(if evil { b"stream: Test.Evil FOUND\0" } else { b"stream: OK\0" }).unwrap();
+        }
+    });
+
+    let file = dir.path().join("clip.mp4");
+    std::fs::write(&file, vec![b'x'; CLAMD_CHUNK_SIZE * 2 + 10]).unwrap();
+    assert_eq!(scan(&Scanner::Clamd(socket.clone()), &file), Ok(Verdict::Clean));
+    std::fs::write(&file, [vec![b'x'; CLAMD_CHUNK_SIZE - 2], b"EVIL".to_vec()].concat()).unwrap();
+    assert_eq!(scan(&Scanner::Clamd(socket.clone()), &file), Ok(Verdict::Rejected("Malware detected: Test.Evil".into())));
+    server.join().unwrap();
+
+    // clamd not running
+    assert!(scan(&Scanner::Clamd(dir.path().join("nosuch.sock")), &file).is_err());
+}
+
+#[test]
+fn test_scan_command_and_quarantine()
+{
+    use std::os::unix::fs::PermissionsExt;
+    let dir = assert_fs::TempDir::new().unwrap();
+    let script = dir.path().join("scan.sh");
+    std::fs::write(&script, "#!/bin/sh\ncase \"$1\" in\n  *evil*) echo \"Policy: no evil\"; exit 1;;\n  *broken*) echo \"out of licenses\" >&2; exit 2;;\nesac\nexit 0\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let scanner = Scanner::Command(script.to_string_lossy().into());
+
+    let file = |name: &str| { let p = dir.path().join(name); std::fs::write(&p, b"data").unwrap(); p };
+    assert_eq!(scan(&scanner, &file("good.mp4")), Ok(Verdict::Clean));
+    assert_eq!(scan(&scanner, &file("evil.mp4")), Ok(Verdict::Rejected("Policy: no evil".into())));
+    assert_eq!(scan(&scanner, &file("broken.mp4")), Err("Scanner exited with error: out of licenses".into()));
+
+    // Image sequence: one bad frame rejects it
+    let seq = dir.path().join("shot");
+    std::fs::create_dir(&seq).unwrap();
+    std::fs::write(seq.join("shot_0001.exr"), b"x").unwrap();
+    assert_eq!(scan(&scanner, &seq), Ok(Verdict::Clean));
+    std::fs::write(seq.join("shot_0002_evil.exr"), b"x").unwrap();
+    assert_eq!(scan(&scanner, &seq), Ok(Verdict::Rejected("shot_0002_evil.exr: Policy: no evil".into())));
+
+    let data_dir = dir.path().join("data");
+    let dst = quarantine(&data_dir, &dir.path().join("evil.mp4")).unwrap();
+    assert!(dst.starts_with(data_dir.join("quarantine")) && dst.ends_with("evil.mp4") && dst.is_file());
+    assert!(!dir.path().join("evil.mp4").exists());
+}
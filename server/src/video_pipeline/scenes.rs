@@ -0,0 +1,183 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed}};
+use crossbeam_channel::{Sender, Receiver};
+
+use super::{fair_queue, THUMB_W, THUMB_H};
+use super::sandbox::Sandbox;
+use crate::database::models;
+
+// Scene change detection (`--detect-scenes`) splits a video into shots, for "next/previous shot"
+// navigation in the player. ffmpeg's scene score picks frames that differ a lot from the previous
+// one (cuts, but also flashes and fast pans, hence the minimum shot length). The first frame of each
+// shot is saved as a thumbnail in the video's shots/ dir. Runs after ingest, on the original file.
+
+/// Scene change score (0..1) above which a frame starts a new shot
+const SCENE_THRESHOLD: f32 = 0.4;
+/// Shorter shots are merged into the previous one (seconds)
+const MIN_SHOT_SECONDS: f32 = 0.5;
+/// Max number of shots stored per video. Rest are merged into the last one.
+pub const MAX_SHOTS_PER_VIDEO: usize = 2000;
+/// Dir for shot thumbnails, in video's dir
+pub const SHOTS_DIR: &str = "shots";
+
+/// Request to detect shots of a video
+#[derive(Debug, Clone)]
+pub struct SceneRequest {
+    pub video_hash: String,
+    pub user_id: String,
+    pub src: PathBuf,
+    /// Video's dir (see `SHOTS_DIR`)
+    pub video_dir: PathBuf,
+    /// Duration of the video (seconds), for the end of the last shot
+    pub duration: f32,
+    /// HDR format of the source, for tone-mapping thumbnails
+    pub hdr_format: Option<String>,
+    pub job_id: Option<i32>,
+}
+
+/// Result of scene detection: shots, or error message
+#[derive(Debug, Clone)]
+pub struct SceneResult {
+    pub req: SceneRequest,
+    pub shots: Result<Vec<models::VideoShotInsert>, String>,
+}
+
+/// Parse scene changes (frames picked by the select filter) from ffmpeg showinfo log.
+/// Returns (output frame number, seconds from start), in order.
+fn parse_scene_changes(log: &str) -> Vec<(u32, f32)>
+{
+    // Lines look like this:
+    //    [Parsed_showinfo_1 @ 0x55d0c2a9c4c0] n:   3 pts: 128128 pts_time:10.677  duration:512 ...
+    // Frame data continues on more lines (side data, color info), which are skipped.
+    fn value_after<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+        line.split(key).nth(1).and_then(|v| v.split_whitespace().next())
+    }
+    log.lines().filter(|l| l.contains("Parsed_showinfo")).filter_map(|l| {
+        let n = value_after(l, " n:")?.parse::<u32>().ok()?;
+        let t = value_after(l, "pts_time:")?.parse::<f32>().ok()?;
+        t.is_finite().then_some((n, t.max(0.0)))
+    }).collect()
+}
+
+/// Turn scene changes into shots: (output frame number of the first frame, start, end).
+/// First shot starts at 0, short shots are merged into the previous one, and the last one ends at `duration`.
+fn changes_to_shots(changes: &[(u32, f32)], duration: f32) -> Vec<(u32, f32, f32)>
+{
+    let mut starts: Vec<(u32, f32)> = vec![];
+    for &(n, t) in changes {
+        match starts.last() {
+            None => starts.push((n, 0.0)),
+            Some(&(_, prev)) if t - prev >= MIN_SHOT_SECONDS && duration - t >= MIN_SHOT_SECONDS => starts.push((n, t)),
+            _ => {},
+        }
+    }
+    starts.truncate(MAX_SHOTS_PER_VIDEO);
+    let ends = starts.iter().skip(1).map(|(_, t)| *t).chain([duration.max(starts.last().map_or(0.0, |(_, t)| *t))]).collect::<Vec<_>>();
+    starts.into_iter().zip(ends).map(|((n, s), e)| (n, s, e)).collect()
+}
+
+fn thumb_filename(n: u32) -> String {
+    format!("{n:04}.webp")
+}
+
+/// Run ffmpeg scene detection on the video, saving the first frame of every candidate shot, and return its log (stderr)
+fn run_scene_detection(src: &Path, shots_dir: &Path, hdr_format: Option<&str>, sandbox: &Sandbox) -> Result<String, String>
+{
+    let tonemap = super::video_compressor::tonemap_filter(hdr_format).map(|f| f + ",").unwrap_or_default();
+    let cmd = &mut sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[shots_dir]);
+    cmd.arg("-nostats").arg("-i").arg(src)
+        .args(["-an", "-sn", "-dn",
+            "-vf", &format!("select=eq(n\\,0)+gt(scene\\,{SCENE_THRESHOLD}),showinfo,{tonemap}scale={THUMB_W}:{THUMB_H}:force_original_aspect_ratio=decrease,pad={THUMB_W}:{THUMB_H}:(ow-iw)/2:(oh-ih)/2"),
+            "-c:v", "libwebp", "-vsync", "vfr", "-start_number", "0"])
+        .arg(shots_dir.join("%04d.webp"));
+    tracing::info!("Calling ffmpeg for scene detection");
+    tracing::debug!("Exec: {:?}", cmd);
+    match cmd.output() {
+        Ok(out) if out.status.success() => Ok(String::from_utf8_lossy(&out.stderr).to_string()),
+        Ok(out) => Err(format!("FFMPEG scene detection exited with error: {}", String::from_utf8_lossy(&out.stderr).lines().last().unwrap_or(""))),
+        Err(e) => Err(format!("Failed to execute ffmpeg: {}", e)),
+    }
+}
+
+/// Detect shots of a video and make their thumbnails. Replaces old thumbnails, if any.
+pub fn detect_shots(req: &SceneRequest, sandbox: &Sandbox) -> Result<Vec<models::VideoShotInsert>, String>
+{
+    let shots_dir = req.video_dir.join(SHOTS_DIR);
+    if shots_dir.exists() {
+        std::fs::remove_dir_all(&shots_dir).map_err(|e| format!("Failed to remove old shots dir: {e}"))?;
+    }
+    std::fs::create_dir_all(&shots_dir).map_err(|e| format!("Failed to create shots dir: {e}"))?;
+
+    let log = match run_scene_detection(&req.src, &shots_dir, req.hdr_format.as_deref(), sandbox) {
+        Ok(log) => log,
+        Err(e) => { std::fs::remove_dir_all(&shots_dir).ok(); return Err(e); }
+    };
+    let shots = changes_to_shots(&parse_scene_changes(&log), req.duration);
+
+    // Thumbnails of merged shots are not needed
+    let kept = shots.iter().map(|(n, _, _)| thumb_filename(*n)).collect::<std::collections::HashSet<_>>();
+    for f in std::fs::read_dir(&shots_dir).map_err(|e| format!("Failed to list shots dir: {e}"))?.flatten() {
+        if !kept.contains(&f.file_name().to_string_lossy().to_string()) {
+            std::fs::remove_file(f.path()).ok();
+        }
+    }
+    Ok(shots.into_iter().map(|(n, start, end)| models::VideoShotInsert {
+        video_hash: req.video_hash.clone(),
+        start_time: start,
+        end_time: end,
+        thumb: shots_dir.join(thumb_filename(n)).is_file().then(|| format!("{SHOTS_DIR}/{}", thumb_filename(n))),
+    }).collect())
+}
+
+/// Listen to scene detection requests and run them in a thread pool.
+/// Requests are scheduled fairly between users (see `fair_queue::run_fair_pool`).
+///
+/// # Arguments
+/// * `inq` - Channel to receive requests
+/// * `outq` - Channel to send results
+/// * `n_workers` - Number of worker threads
+/// * `depth` - Number of items waiting, for metrics
+/// * `sandbox` - Sandbox to run ffmpeg in
+/// * `priority_of` - Function that returns current processing priority of a user
+pub fn run_forever<P>(inq: Receiver<SceneRequest>, outq: Sender<SceneResult>, n_workers: Arc<AtomicUsize>, depth: Arc<AtomicUsize>, sandbox: Sandbox, terminate_flag: Arc<AtomicBool>, priority_of: P)
+    where P: Fn(&str) -> i32
+{
+    let _span = tracing::info_span!("SCENES").entered();
+    tracing::info!(n_workers = n_workers.load(Relaxed), "Starting.");
+    fair_queue::run_fair_pool(inq, &n_workers, &depth, |r: &SceneRequest| r.user_id.clone(), priority_of, &terminate_flag, move |req: SceneRequest| {
+        let _span = tracing::info_span!("detect_scenes", video=%req.video_hash, user=%req.user_id).entered();
+        let shots = detect_shots(&req, &sandbox);
+        outq.send(SceneResult { req, shots }).is_ok()
+    });
+    tracing::info!("Exiting.");
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_scene_changes_to_shots()
+{
+    let log = r#"
+        [Parsed_showinfo_1 @ 0x55d0c2a9c4c0] config in time_base: 1/12800, frame_rate: 25/1
+        [Parsed_showinfo_1 @ 0x55d0c2a9c4c0] n:   0 pts:    512 pts_time:0.04    duration:512 pos:48 fmt:yuv420p sar:1/1 s:1920x1080 i:P iskey:1 type:I
+        [Parsed_showinfo_1 @ 0x55d0c2a9c4c0]   color_range:tv color_space:bt709 color_primaries:bt709 color_trc:bt709
+        [Parsed_showinfo_1 @ 0x55d0c2a9c4c0] n:   1 pts:  51200 pts_time:4       duration:512 pos:1234 fmt:yuv420p sar:1/1 s:1920x1080 i:P iskey:0 type:P
+        [Parsed_showinfo_1 @ 0x55d0c2a9c4c0] n:   2 pts:  53760 pts_time:4.2     duration:512 pos:2345 fmt:yuv420p sar:1/1 s:1920x1080 i:P iskey:0 type:P
+        [Parsed_showinfo_1 @ 0x55d0c2a9c4c0] n:   3 pts: 128000 pts_time:10      duration:512 pos:3456 fmt:yuv420p sar:1/1 s:1920x1080 i:P iskey:0 type:B
+        [Parsed_showinfo_1 @ 0x55d0c2a9c4c0] n:   4 pts: 152320 pts_time:11.9    duration:512 pos:4567 fmt:yuv420p sar:1/1 s:1920x1080 i:P iskey:0 type:P
+        [out#0/image2 @ 0x55d0c2a9d100] video:12kB audio:0kB subtitle:0kB other streams:0kB global headers:0kB muxing overhead: unknown
+    "#;
+    let changes = parse_scene_changes(log);
+    assert_eq!(changes, vec![(0, 0.04), (1, 4.0), (2, 4.2), (3, 10.0), (4, 11.9)]);
+
+    // Flash at 4.2 and change right before the end are merged into previous shots
+    assert_eq!(changes_to_shots(&changes, 12.0), vec![(0, 0.0, 4.0), (1, 4.0, 10.0), (3, 10.0, 12.0)]);
+    assert_eq!(changes_to_shots(&changes[..1], 12.0), vec![(0, 0.0, 12.0)]);
+    assert!(changes_to_shots(&[], 12.0).is_empty());
+
+    let many = (0..MAX_SHOTS_PER_VIDEO as u32 + 10).map(|n| (n, n as f32)).collect::<Vec<_>>();
+    let shots = changes_to_shots(&many, 5000.0);
+    assert_eq!(shots.len(), MAX_SHOTS_PER_VIDEO);
+    assert_eq!(shots.last().unwrap().2, 5000.0);
+}
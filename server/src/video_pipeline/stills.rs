@@ -0,0 +1,148 @@
+use std::path::{Path, PathBuf};
+use rust_decimal::Decimal;
+
+use super::IncomingFile;
+use super::metadata_reader::Metadata;
+use super::sandbox::Sandbox;
+use crate::database::models::still_kind;
+
+/// File extensions of single still images accepted for review
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "tif", "tiff"];
+/// Resolution (DPI) PDF pages are rendered at for review
+const PDF_RENDER_DPI: u32 = 110;
+/// Max number of PDF pages rendered. Rest of the document is not reviewable.
+pub const MAX_PDF_PAGES: u32 = 500;
+
+/// Kind of still (see `models::still_kind`) by file extension, or None if the file is not one
+pub fn still_kind_of(path: &Path) -> Option<&'static str>
+{
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+    if IMAGE_EXTENSIONS.contains(&ext.as_str()) { Some(still_kind::IMAGE) }
+    else if ext == "pdf" { Some(still_kind::PDF) }
+    else { None }
+}
+
+/// Dir for rendered pages of a still, `<video dir>/pages`
+pub fn pages_dir(video_dir: &Path) -> PathBuf {
+    video_dir.join("pages")
+}
+
+/// Filename of a rendered page (1-based) in `pages_dir`
+pub fn page_filename(page: u32) -> String {
+    format!("page-{page}.png")
+}
+
+/// Parse page count from `pdfinfo` output ("Pages:          12")
+fn parse_pdfinfo_pages(out: &str) -> Option<u32>
+{
+    out.lines().find_map(|l| l.strip_prefix("Pages:")).and_then(|n| n.trim().parse().ok())
+}
+
+/// Count pages of a PDF document with `pdfinfo` (poppler-utils)
+fn count_pdf_pages(file: &Path, sandbox: &Sandbox) -> Result<u32, String>
+{
+    let cmd = &mut sandbox.command(&["pdfinfo"], &[]);
+    cmd.arg(file);
+    tracing::debug!("Exec: {:?}", cmd);
+    match cmd.output() {
+        Ok(out) if out.status.success() => parse_pdfinfo_pages(&String::from_utf8_lossy(&out.stdout))
+            .filter(|n| *n > 0).ok_or("No pages found in PDF".into()),
+        Ok(out) => Err(format!("pdfinfo exited with error: {}", String::from_utf8_lossy(&out.stderr).trim())),
+        Err(e) => Err(format!("Failed to execute pdfinfo: {}", e)),
+    }
+}
+
+/// Metadata for a still image or PDF. Stills have no duration, frame rate or tracks,
+/// so mediainfo is not used. Content hash is calculated as for videos, for duplicate detection.
+pub fn read_metadata(args: &IncomingFile, kind: &'static str, sandbox: &Sandbox) -> Result<Metadata, String>
+{
+    let page_count = match kind {
+        still_kind::PDF => count_pdf_pages(&args.file_path, sandbox)?.min(MAX_PDF_PAGES),
+        _ => 1,
+    };
+    let codec = args.file_path.extension().unwrap_or_default().to_string_lossy().to_uppercase();
+    Ok(Metadata {
+        src_file: args.file_path.clone(),
+        user_id: args.user_id.clone(),
+        total_frames: 0,
+        duration: Decimal::ZERO,
+        orig_codec: codec,
+        fps: Decimal::ZERO,
+        bitrate: 0,
+        metadata_all: serde_json::json!({ "still_kind": kind, "page_count": page_count }).to_string(),
+        content_hash: super::calc_content_hash(&args.file_path).map_err(|e| tracing::warn!(details=%e, "Failed to calculate content hash.")).ok(),
+        still_kind: Some(kind),
+        page_count,
+        trace_id: args.trace_id.clone(),
+        folder_id: args.folder_id,
+        ..Default::default()
+    })
+}
+
+/// Number from a `pdftoppm` output filename, e.g. "p-007.png" -> 7
+fn pdftoppm_page_number(filename: &str) -> Option<u32>
+{
+    filename.strip_suffix(".png")?.rsplit_once('-')?.1.parse().ok()
+}
+
+/// Render a still into browser-viewable PNG pages (see `page_filename`) in `dst_dir`.
+/// Images are converted with ffmpeg (TIFF isn't viewable in browsers), PDFs rendered with `pdftoppm`.
+///
+/// # Returns
+/// Number of pages rendered
+pub fn render_pages(src: &Path, kind: &str, dst_dir: &Path, sandbox: &Sandbox) -> Result<u32, String>
+{
+    std::fs::create_dir_all(dst_dir).map_err(|e| format!("Failed to create pages dir: {e}"))?;
+    let mut cmd = match kind {
+        still_kind::PDF => {
+            let mut cmd = sandbox.command(&["nice", "-n", "10", "--", "pdftoppm"], &[dst_dir]);
+            cmd.args(["-png", "-r", &PDF_RENDER_DPI.to_string(), "-l", &MAX_PDF_PAGES.to_string()])
+                .arg(src).arg(dst_dir.join("p"));
+            cmd
+        },
+        _ => {
+            let mut cmd = sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[dst_dir]);
+            cmd.arg("-y").arg("-i").arg(src).args(["-nostats", "-frames:v", "1"]).arg(dst_dir.join(page_filename(1)));
+            cmd
+        },
+    };
+    tracing::info!(src=%src.display(), kind, "Rendering pages of a still");
+    tracing::debug!("Exec: {:?}", cmd);
+    match cmd.output() {
+        Ok(out) if out.status.success() => {},
+        Ok(out) => return Err(format!("Page rendering failed: {}", String::from_utf8_lossy(&out.stderr).lines().last().unwrap_or(""))),
+        Err(e) => return Err(format!("Failed to execute page renderer: {e}")),
+    }
+
+    // pdftoppm zero-pads page numbers by document length. Rename to fixed names.
+    if kind == still_kind::PDF {
+        for entry in std::fs::read_dir(dst_dir).map_err(|e| e.to_string())?.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let (true, Some(n)) = (name.starts_with("p-"), pdftoppm_page_number(&name)) {
+                std::fs::rename(entry.path(), dst_dir.join(page_filename(n))).map_err(|e| format!("Failed to rename page: {e}"))?;
+            }
+        }
+    }
+    let pages = (1..).take_while(|n| dst_dir.join(page_filename(*n)).is_file()).count() as u32;
+    if pages == 0 { return Err("No pages rendered".into()); }
+    Ok(pages)
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_still_kind_and_pages()
+{
+    assert_eq!(still_kind_of(Path::new("/a/Poster.PNG")), Some(still_kind::IMAGE));
+    assert_eq!(still_kind_of(Path::new("scan.tif")), Some(still_kind::IMAGE));
+    assert_eq!(still_kind_of(Path::new("script.pdf")), Some(still_kind::PDF));
+    assert_eq!(still_kind_of(Path::new("clip.mov")), None);
+    assert_eq!(still_kind_of(Path::new("noext")), None);
+
+    assert_eq!(parse_pdfinfo_pages("Title:  x\nPages:          12\nEncrypted: no\n"), Some(12));
+    assert_eq!(parse_pdfinfo_pages("Title:  x\n"), None);
+    assert_eq!(pdftoppm_page_number("p-007.png"), Some(7));
+    assert_eq!(pdftoppm_page_number("p-1.png"), Some(1));
+    assert_eq!(pdftoppm_page_number("p.png"), None);
+}
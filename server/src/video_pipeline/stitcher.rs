@@ -0,0 +1,144 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::database::models;
+use super::sandbox::Sandbox;
+
+/// Output frame size of stitched videos. Sources are scaled to fit and letterboxed.
+const OUT_W: u32 = 1920;
+const OUT_H: u32 = 1080;
+const MAX_FPS: f32 = 60.0;
+const DEFAULT_FPS: f32 = 30.0;
+
+/// Max number of videos in one stitch
+pub const MAX_SOURCES: usize = 50;
+
+/// One input clip of a stitch
+#[derive(Debug, Clone)]
+pub struct StitchSource {
+    pub video_hash: String,
+    pub file: PathBuf,
+    pub duration: f32,
+    pub fps: f32,
+    pub has_audio: bool,
+}
+
+impl StitchSource {
+    /// Describe an ingested video as stitch input
+    pub fn from_video(v: &models::Video, videos_dir: &Path) -> Result<StitchSource, String>
+    {
+        let file = super::playable_file(v, videos_dir)?;
+        Ok(StitchSource {
+            video_hash: v.video_hash.clone(),
+            file,
+            duration: v.duration.unwrap_or(0.0),
+            fps: v.fps.as_deref().and_then(|f| f.parse().ok()).unwrap_or(DEFAULT_FPS),
+            has_audio: super::has_audio_track(v),
+        })
+    }
+}
+
+/// Build ffmpeg arguments (after "ffmpeg") for concatenating sources into `dst`.
+/// All clips are normalized to the same frame size, frame rate (highest of the sources),
+/// pixel format and audio layout, so sources with different formats can be joined.
+/// Silent audio is generated for clips that have none.
+fn ffmpeg_args(sources: &[StitchSource], dst: &Path) -> Vec<OsString>
+{
+    let fps = sources.iter().map(|s| s.fps).fold(0.0, f32::max);
+    let fps = if fps > 0.0 { fps.min(MAX_FPS) } else { DEFAULT_FPS };
+
+    let mut args: Vec<OsString> = vec!["-nostats".into(), "-y".into()];
+    for s in sources {
+        args.extend(["-i".into(), s.file.as_os_str().into()]);
+    }
+
+    let mut filters = vec![];
+    let mut concat_in = String::new();
+    for (i, s) in sources.iter().enumerate() {
+        filters.push(format!("[{i}:v:0]scale={OUT_W}:{OUT_H}:force_original_aspect_ratio=decrease,\
+            pad={OUT_W}:{OUT_H}:(ow-iw)/2:(oh-ih)/2,setsar=1,fps={fps},format=yuv420p[v{i}]"));
+        if s.has_audio {
+            filters.push(format!("[{i}:a:0]aresample=48000,aformat=sample_fmts=fltp:channel_layouts=stereo[a{i}]"));
+        } else {
+            filters.push(format!("anullsrc=r=48000:cl=stereo,atrim=duration={},aformat=sample_fmts=fltp[a{i}]", s.duration.max(0.01)));
+        }
+        concat_in += &format!("[v{i}][a{i}]");
+    }
+    filters.push(format!("{concat_in}concat=n={}:v=1:a=1[v][a]", sources.len()));
+
+    args.extend(["-filter_complex", &filters.join(";"),
+        "-map", "[v]", "-map", "[a]",
+        "-c:v", "libx264", "-preset", "faster", "-crf", "18",
+        "-c:a", "aac", "-b:a", "192k",
+        "-movflags", "+faststart"].map(OsString::from));
+    args.push(dst.as_os_str().into());
+    args
+}
+
+/// Concatenate sources into a new video file. Blocks until done.
+///
+/// # Arguments
+/// * `sources` - Clips, in output order
+/// * `dst` - Output file (.mp4). Its directory must exist.
+/// * `sandbox` - Sandbox to run ffmpeg in
+pub fn stitch(sources: &[StitchSource], dst: &Path, sandbox: &Sandbox) -> Result<(), String>
+{
+    if sources.len() < 2 { return Err("Need at least two videos to stitch".into()); }
+    let out_dir = dst.parent().ok_or("Invalid destination")?;
+    let cmd = &mut sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[out_dir]);
+    cmd.args(ffmpeg_args(sources, dst));
+    tracing::info!(n_sources=sources.len(), dst=%dst.display(), "Calling ffmpeg to stitch videos");
+    tracing::debug!("Exec: {:?}", cmd);
+    match cmd.output() {
+        Ok(out) if out.status.success() => Ok(()),
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            tracing::error!(stderr=%stderr, "ffmpeg stitch failed");
+            Err(format!("FFMPEG exited with error: {}", stderr.lines().last().unwrap_or("")))
+        },
+        Err(e) => Err(format!("Failed to execute ffmpeg: {}", e)),
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_stitch_ffmpeg_args()
+{
+    let src = |vh: &str, fps: f32, has_audio: bool| StitchSource {
+        video_hash: vh.into(), file: PathBuf::from(format!("/v/{vh}/video.mp4")), duration: 2.5, fps, has_audio };
+    let args = ffmpeg_args(&[src("a", 25.0, true), src("b", 29.97, false), src("c", 120.0, true)], Path::new("/up/x/out.mp4"));
+    let args = args.iter().map(|a| a.to_string_lossy().to_string()).collect::<Vec<_>>();
+
+    assert_eq!(args.iter().filter(|a| *a == "-i").count(), 3);
+    assert_eq!(args.last().unwrap(), "/up/x/out.mp4");
+    let fc = &args[args.iter().position(|a| a == "-filter_complex").unwrap() + 1];
+    assert!(fc.contains("fps=60"));  // capped
+    assert!(fc.contains("[0:a:0]aresample"));
+    assert!(!fc.contains("[1:a:0]"));
+    assert!(fc.contains("anullsrc=r=48000:cl=stereo,atrim=duration=2.5"));
+    assert!(fc.ends_with("[v0][a0][v1][a1][v2][a2]concat=n=3:v=1:a=1[v][a]"));
+}
+
+#[test]
+fn test_stitch_source_from_video()
+{
+    let v = models::Video {
+        id: 1, video_hash: "abc".into(), added_by_userid: None, added_by_username: None,
+        added_time: chrono::NaiveDateTime::default(), recompression_done: None, thumb_sheet_dims: None,
+        orig_filename: Some("clip.mov".into()), title: None, total_frames: None, duration: Some(3.0),
+        fps: Some("23.976".into()), raw_metadata_all: Some(r#"{"media": {"track": [{"@type": "Video"}]}}"#.into()),
+        silence_trim_start: None, silence_trim_end: None, loudness_lufs: None, legal_hold: false,
+        content_hash: None, loudnorm_target: None,
+        color_primaries: None, color_transfer: None, hdr_format: None,
+        vfr_fps_min: None, vfr_fps_max: None, vfr_fps_avg: None, image_sequence: None, audio_only: false, still_kind: None, page_count: None,
+        allow_download: true, folder_id: None, trashed: None };
+    let s = StitchSource::from_video(&v, Path::new("/videos")).unwrap();
+    assert_eq!(s.file, PathBuf::from("/videos/abc/orig/clip.mov"));
+    assert!(!s.has_audio);
+    assert!((s.fps - 23.976).abs() < 0.001);
+
+    let v = models::Video { recompression_done: Some("2023-01-01".into()), ..v };
+    assert_eq!(StitchSource::from_video(&v, Path::new("/videos")).unwrap().file, PathBuf::from("/videos/abc/video.mp4"));
+}
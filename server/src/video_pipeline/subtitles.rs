@@ -0,0 +1,241 @@
+use std::path::{Path, PathBuf};
+use anyhow::bail;
+
+use crate::database::{DB, models};
+use super::sandbox::Sandbox;
+
+/// Subtitle file extensions accepted as sidecars / attachments
+const SUBTITLE_EXTENSIONS: [&str; 2] = ["srt", "vtt"];
+
+/// Subtitle track read from a sidecar file or extracted from the video, converted to WebVTT
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubtitleTrack {
+    pub language: Option<String>,
+    pub title: String,
+    pub origin: &'static str,
+    pub vtt: String,
+}
+
+/// Check if file looks like a subtitle file (by extension)
+pub fn is_subtitle_file(path: &Path) -> bool
+{
+    path.extension().map(|e| e.to_string_lossy().to_lowercase())
+        .map(|e| SUBTITLE_EXTENSIONS.contains(&e.as_str())).unwrap_or(false)
+}
+
+/// Convert SubRip (.srt) or WebVTT (.vtt) subtitles to WebVTT.
+///
+/// # Arguments
+/// * `text` - Contents of the subtitle file
+/// * `filename` - Name of the file, for detecting format from extension
+pub fn to_webvtt(text: &str, filename: &str) -> anyhow::Result<String>
+{
+    let text = text.trim_start_matches('\u{feff}').replace("\r\n", "\n").replace('\r', "\n");
+    if !text.contains("-->") { bail!("No subtitle cues found in '{}'", filename); }
+
+    let ext = Path::new(filename).extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    match ext.as_str() {
+        "vtt" => {
+            if !text.starts_with("WEBVTT") { bail!("'{}' is not a WebVTT file (missing WEBVTT header)", filename); }
+            Ok(text)
+        },
+        "srt" => {
+            // SRT timing lines look like "00:00:01,600 --> 00:00:04,200". WebVTT uses '.' for milliseconds.
+            let body = text.trim().lines().map(|l| {
+                    if l.contains("-->") { l.replace(',', ".") } else { l.to_string() }
+                }).collect::<Vec<_>>().join("\n");
+            Ok(format!("WEBVTT\n\n{}\n", body))
+        },
+        _ => bail!("Unsupported subtitle format '{}'. Use .srt or .vtt.", filename),
+    }
+}
+
+/// Guess subtitle language from filename, e.g. "clip.en.srt" -> "en", "clip.pt-BR.vtt" -> "pt-BR"
+pub fn language_from_filename(filename: &str) -> Option<String>
+{
+    let stem = Path::new(filename).file_stem()?.to_string_lossy().to_string();
+    let (_, lang) = stem.rsplit_once('.')?;
+    let valid = (2..=3).contains(&lang.split('-').next()?.len())
+        && lang.len() <= 8 && lang.chars().all(|c| c.is_ascii_alphabetic() || c == '-');
+    valid.then(|| lang.to_string())
+}
+
+/// Read a subtitle file and convert it to WebVTT
+pub fn read_subtitle_file(path: &Path) -> anyhow::Result<SubtitleTrack>
+{
+    let filename = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let bytes = std::fs::read(path)?;
+    Ok(SubtitleTrack {
+        language: language_from_filename(&filename),
+        vtt: to_webvtt(&String::from_utf8_lossy(&bytes), &filename)?,
+        title: filename,
+        origin: models::subtitle_origin::UPLOAD,
+    })
+}
+
+/// Find sidecar subtitle files for a video file.
+/// If `whole_dir` is set (dir is a private per-upload dir), all subtitle files in the same dir
+/// are sidecars. Otherwise only ones whose name starts with the video's file stem (e.g. "clip.en.srt" for "clip.mp4").
+pub fn find_sidecars(video: &Path, whole_dir: bool) -> Vec<PathBuf>
+{
+    let (Some(dir), Some(stem)) = (video.parent(), video.file_stem()) else { return vec![] };
+    let stem = stem.to_string_lossy().to_string() + ".";
+    let mut res = match dir.read_dir() {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path())
+            .filter(|p| p.is_file() && is_subtitle_file(p))
+            .filter(|p| whole_dir || p.file_name().map(|n| n.to_string_lossy().starts_with(&stem)).unwrap_or(false))
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            tracing::warn!(details=%e, "Failed to list dir for subtitle sidecars.");
+            vec![]
+        }
+    };
+    res.sort();
+    res
+}
+
+/// Extract text subtitle tracks embedded in a video file, as reported by mediainfo.
+/// Bitmap subtitles (DVD, PGS) can't be converted to WebVTT and are skipped.
+///
+/// # Arguments
+/// * `file` - Video file
+/// * `mediainfo_json` - Mediainfo output for the file
+/// * `sandbox` - Sandbox to run ffmpeg in
+pub fn extract_embedded(file: &Path, mediainfo_json: &serde_json::Value, sandbox: &Sandbox) -> Vec<SubtitleTrack>
+{
+    let text_tracks = mediainfo_json["media"]["track"].as_array()
+        .map(|tracks| tracks.iter().filter(|t| t["@type"] == "Text").cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let mut res = vec![];
+    for (i, t) in text_tracks.iter().enumerate() {
+        let cmd = &mut sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[]);
+        cmd.arg("-nostats").arg("-i").arg(file).args(["-map", &format!("0:s:{i}"), "-f", "webvtt", "-"]);
+        tracing::info!(track=i, "Calling ffmpeg to extract subtitles");
+        tracing::debug!("Exec: {:?}", cmd);
+        match cmd.output() {
+            Ok(out) if out.status.success() => {
+                let vtt = String::from_utf8_lossy(&out.stdout).to_string();
+                if !vtt.contains("-->") { continue; }
+                res.push(SubtitleTrack {
+                    language: t["Language"].as_str().map(String::from),
+                    title: t["Title"].as_str().map(String::from).unwrap_or_else(|| format!("Track {}", i + 1)),
+                    origin: models::subtitle_origin::EMBEDDED,
+                    vtt,
+                });
+            },
+            Ok(out) => { tracing::warn!(track=i, details=%String::from_utf8_lossy(&out.stderr), "Subtitle extraction failed. Skipping track."); },
+            Err(e) => { tracing::warn!(track=i, details=%e, "Failed to execute ffmpeg for subtitle extraction."); },
+        }
+    }
+    res
+}
+
+/// Parse WebVTT timestamp ("hh:mm:ss.mmm" or "mm:ss.mmm") into seconds
+fn parse_vtt_time(ts: &str) -> Option<f32>
+{
+    let ts = ts.split_whitespace().next()?;
+    let mut secs = 0.0f32;
+    for part in ts.split(':') {
+        secs = secs * 60.0 + part.parse::<f32>().ok()?;
+    }
+    Some(secs)
+}
+
+/// Parse cues from WebVTT text, for transcript search.
+///
+/// # Returns
+/// List of (start seconds, end seconds, text) with multi-line cue text joined by spaces and tags removed
+pub fn parse_cues(vtt: &str) -> Vec<(f32, f32, String)>
+{
+    let mut res = Vec::new();
+    let mut lines = vtt.lines().map(str::trim);
+    while let Some(l) = lines.next() {
+        let Some((start, end)) = l.split_once("-->") else { continue };
+        let (Some(start), Some(end)) = (parse_vtt_time(start.trim()), parse_vtt_time(end.trim())) else { continue };
+        let text = lines.by_ref().take_while(|l| !l.is_empty()).collect::<Vec<_>>().join(" ");
+        let mut in_tag = false;
+        let text = text.chars().filter(|c| {
+                match c { '<' => in_tag = true, '>' if in_tag => { in_tag = false; return false; }, _ => {} }
+                !in_tag
+            }).collect::<String>().trim().to_string();
+        if !text.is_empty() { res.push((start, end, text)); }
+    }
+    res
+}
+
+/// Store a subtitle track in DB and as a file in `<videos_dir>/<video_hash>/subs/<id>.vtt`.
+/// Cues are also indexed for transcript search.
+pub fn store_subtitle(db: &DB, videos_dir: &Path, video_hash: &str, track: &SubtitleTrack) -> anyhow::Result<models::Subtitle>
+{
+    let subs_dir = videos_dir.join(video_hash).join("subs");
+    std::fs::create_dir_all(&subs_dir)?;
+    let sub = db.add_subtitle(&models::SubtitleInsert {
+        video_hash: video_hash.into(),
+        language: track.language.clone(),
+        title: track.title.clone(),
+        origin: track.origin.into(),
+    })?;
+    if let Err(e) = std::fs::write(subs_dir.join(format!("{}.vtt", sub.id)), &track.vtt) {
+        db.del_subtitle(sub.id).ok();
+        return Err(e.into());
+    }
+    let cues = parse_cues(&track.vtt).into_iter().map(|(start, end, text)| models::TranscriptCueInsert {
+            subtitle_id: sub.id,
+            video_hash: video_hash.into(),
+            language: track.language.clone(),
+            start_time: start,
+            end_time: end,
+            text,
+        }).collect::<Vec<_>>();
+    if let Err(e) = db.add_transcript_cues(&cues) {
+        tracing::warn!(details=%e, video_hash, subtitle_id=sub.id, "Failed to index subtitle cues for search");
+    }
+    Ok(sub)
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_srt_to_webvtt()
+{
+    let srt = "\u{feff}1\r\n00:00:01,600 --> 00:00:04,200\r\nHello, world\r\n\r\n2\r\n00:00:05,000 --> 00:00:06,000\r\nBye\r\n";
+    let vtt = to_webvtt(srt, "clip.en.SRT").unwrap();
+    assert_eq!(vtt, "WEBVTT\n\n1\n00:00:01.600 --> 00:00:04.200\nHello, world\n\n2\n00:00:05.000 --> 00:00:06.000\nBye\n");
+
+    let vtt_in = "WEBVTT\n\n00:01.000 --> 00:02.000\nHi\n";
+    assert_eq!(to_webvtt(vtt_in, "a.vtt").unwrap(), vtt_in);
+    assert!(to_webvtt("00:01.000 --> 00:02.000\nHi\n", "a.vtt").is_err());
+    assert!(to_webvtt("just text", "a.srt").is_err());
+    assert!(to_webvtt(srt, "a.ass").is_err());
+}
+
+#[test]
+fn test_subtitle_filenames()
+{
+    assert_eq!(language_from_filename("clip.en.srt"), Some("en".into()));
+    assert_eq!(language_from_filename("clip.pt-BR.vtt"), Some("pt-BR".into()));
+    assert_eq!(language_from_filename("clip.srt"), None);
+    assert_eq!(language_from_filename("my.clip.final.srt"), None);
+    assert!(is_subtitle_file(Path::new("/a/b.VTT")));
+    assert!(!is_subtitle_file(Path::new("/a/b.mp4")));
+
+    let dir = assert_fs::TempDir::new().unwrap();
+    for f in ["clip.mp4", "clip.en.srt", "clip.vtt", "other.srt", "clip.txt"] {
+        std::fs::write(dir.join(f), "x").unwrap();
+    }
+    let names = |v: Vec<PathBuf>| v.iter().map(|p| p.file_name().unwrap().to_string_lossy().to_string()).collect::<Vec<_>>();
+    assert_eq!(names(find_sidecars(&dir.join("clip.mp4"), false)), vec!["clip.en.srt", "clip.vtt"]);
+    assert_eq!(names(find_sidecars(&dir.join("clip.mp4"), true)), vec!["clip.en.srt", "clip.vtt", "other.srt"]);
+}
+
+#[test]
+fn test_parse_vtt_cues()
+{
+    let vtt = "WEBVTT\n\n1\n00:00:01.600 --> 00:00:04.200\nHello,\n<i>world</i>\n\n01:02:03.500 --> 01:02:04.000 align:start\nFinal render\n\n00:09.000 --> 00:10.000\n\nbad --> 00:01.000\nx\n";
+    assert_eq!(parse_cues(vtt), vec![
+        (1.6, 4.2, "Hello, world".to_string()),
+        (3723.5, 3724.0, "Final render".to_string()),
+    ]);
+}
@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+// Transcode presets are named ffmpeg output recipes, read from a JSON file (`--transcode-presets`):
+//
+//   {"archive": {"crf": 18, "max_size": 3840, "audio_bitrate": 256000},
+//    "nvenc": {"video_codec": "h264_nvenc", "speed": "p5", "extra_args": ["-rc", "vbr"]},
+//    "stream": {"crf": 23, "max_bitrate": 3000000}}
+//
+// Fields left out are as in the built-in "default" preset, which is what's used without a file.
+// `--transcode-preset` picks the preset for all videos, and folder owners can pick another one for
+// videos uploaded into a folder (and its subfolders). Only the first transcode attempt uses the
+// preset as is. Safer retries (see `video_compressor::TRANSCODE_ATTEMPTS`) switch to the software
+// H.264 encoder and drop extra args and two-pass encoding, but keep quality, bitrate cap, size and
+// audio settings.
+//
+// For deployments that stream over constrained links, bitrate can be kept in check either with
+// `two_pass` (encode at target bitrate, with an analysis pass first) or with `max_bitrate` on top of
+// `crf` (constant quality, but capped peaks).
+
+/// Name of the built-in preset
+pub const DEFAULT_PRESET: &str = "default";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TranscodePreset {
+    #[serde(skip)]
+    pub name: String,
+    /// ffmpeg video encoder
+    pub video_codec: String,
+    /// Encoder's speed preset (`-preset`), if it has one
+    pub speed: Option<String>,
+    /// Constant quality (`-crf`). Without it, video is encoded at target bitrate (`--bitrate`).
+    pub crf: Option<u32>,
+    /// Cap video bitrate (`-maxrate`, with a 2 second buffer), bits per second
+    pub max_bitrate: Option<u32>,
+    /// Encode at target bitrate in two passes, for more even quality. Can't be used with `crf`.
+    pub two_pass: bool,
+    /// Max width of landscape video (height of portrait), in pixels. Bigger video is scaled down.
+    pub max_size: u32,
+    /// ffmpeg audio encoder
+    pub audio_codec: String,
+    /// Audio bits per second
+    pub audio_bitrate: u32,
+    pub audio_channels: u32,
+    /// More ffmpeg output options, e.g. `["-tune", "film"]`
+    pub extra_args: Vec<String>,
+}
+
+impl Default for TranscodePreset {
+    fn default() -> Self {
+        TranscodePreset {
+            name: DEFAULT_PRESET.into(),
+            video_codec: "libx264".into(),
+            speed: Some("faster".into()),
+            crf: None,
+            max_bitrate: None,
+            two_pass: false,
+            max_size: 1920,
+            audio_codec: "aac".into(),
+            audio_bitrate: 128000,
+            audio_channels: 2,
+            extra_args: vec![],
+        }
+    }
+}
+
+impl TranscodePreset {
+    /// Bitrate to encode at (when not using crf), given the target: no more than `max_bitrate`
+    pub fn capped_bitrate(&self, target: u32) -> u32 {
+        self.max_bitrate.map_or(target, |max| max.min(target))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.video_codec.trim().is_empty() || self.audio_codec.trim().is_empty() { bail!("codec can't be empty"); }
+        if !(16..=8192).contains(&self.max_size) { bail!("max_size must be 16-8192"); }
+        if self.crf.map(|c| c > 63).unwrap_or(false) { bail!("crf must be 0-63"); }
+        if self.max_bitrate == Some(0) { bail!("max_bitrate must be > 0"); }
+        if self.two_pass && self.crf.is_some() { bail!("two_pass can't be used with crf"); }
+        if self.audio_bitrate == 0 { bail!("audio_bitrate must be > 0"); }
+        if !(1..=8).contains(&self.audio_channels) { bail!("audio_channels must be 1-8"); }
+        Ok(())
+    }
+}
+
+/// Presets of the deployment, and which one is used unless a folder picks another
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscodePresets {
+    presets: BTreeMap<String, TranscodePreset>,
+    pub default: String,
+}
+
+impl Default for TranscodePresets {
+    fn default() -> Self {
+        TranscodePresets {
+            presets: BTreeMap::from([(DEFAULT_PRESET.to_string(), TranscodePreset::default())]),
+            default: DEFAULT_PRESET.into(),
+        }
+    }
+}
+
+impl TranscodePresets {
+    /// Parse presets from JSON (object of name -> preset), in addition to the built-in one.
+    ///
+    /// # Arguments
+    /// * `json` - Presets, or None for only the built-in one
+    /// * `default` - Name of the preset to use unless a folder picks another
+    pub fn parse(json: Option<&str>, default: &str) -> anyhow::Result<TranscodePresets>
+    {
+        let mut res = TranscodePresets::default();
+        if let Some(json) = json {
+            let presets: BTreeMap<String, TranscodePreset> = serde_json::from_str(json)?;
+            for (name, mut p) in presets {
+                if name.trim().is_empty() { bail!("Preset name can't be empty"); }
+                p.validate().map_err(|e| anyhow!("Preset '{name}': {e}"))?;
+                p.name = name.clone();
+                res.presets.insert(name, p);
+            }
+        }
+        if !res.presets.contains_key(default) {
+            bail!("No such transcode preset: '{default}'");
+        }
+        res.default = default.into();
+        Ok(res)
+    }
+
+    /// Read presets from a JSON file, see `parse`
+    pub fn from_file(file: Option<&Path>, default: &str) -> anyhow::Result<TranscodePresets>
+    {
+        let json = file.map(|f| std::fs::read_to_string(f)
+            .map_err(|e| anyhow!("Failed to read '{}': {}", f.display(), e))).transpose()?;
+        Self::parse(json.as_deref(), default)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.presets.contains_key(name)
+    }
+
+    /// Preset to use, given the one a folder picked (if any). Falls back to default if
+    /// folder's preset isn't there anymore (e.g. removed from config).
+    pub fn resolve(&self, folder_preset: Option<&str>) -> TranscodePreset
+    {
+        folder_preset.and_then(|n| {
+            let p = self.presets.get(n);
+            if p.is_none() { tracing::warn!(preset=n, "Folder's transcode preset not found. Using default."); }
+            p
+        }).or_else(|| self.presets.get(&self.default)).cloned().unwrap_or_default()
+    }
+
+    /// Presets as name -> settings, and the default, for clients
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({ "default": self.default, "presets": self.presets })
+    }
+}
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_transcode_presets()
+{
+    let def = TranscodePresets::parse(None, DEFAULT_PRESET).unwrap();
+    assert_eq!(def, TranscodePresets::default());
+    assert!(TranscodePresets::parse(None, "archive").is_err());
+
+    let json = r#"{"archive": {"crf": 18, "max_size": 3840}, "nvenc": {"video_codec": "h264_nvenc", "speed": null, "extra_args": ["-rc", "vbr"]}}"#;
+    let presets = TranscodePresets::parse(Some(json), "archive").unwrap();
+    assert!(presets.contains("default") && presets.contains("nvenc"));
+
+    let archive = presets.resolve(None);
+    assert_eq!((archive.name.as_str(), archive.crf, archive.max_size), ("archive", Some(18), 3840));
+    assert_eq!(archive.video_codec, "libx264");  // From built-in
+    let nvenc = presets.resolve(Some("nvenc"));
+    assert_eq!((nvenc.video_codec.as_str(), nvenc.speed, nvenc.extra_args.len()), ("h264_nvenc", None, 2));
+    assert_eq!(presets.resolve(Some("removed")).name, "archive");
+    assert_eq!(presets.to_json()["presets"]["archive"]["crf"], 18);
+
+    // Built-in can be overridden
+    let presets = TranscodePresets::parse(Some(r#"{"default": {"audio_bitrate": 192000}}"#), DEFAULT_PRESET).unwrap();
+    assert_eq!(presets.resolve(None).audio_bitrate, 192000);
+
+    let presets = TranscodePresets::parse(Some(r#"{"stream": {"crf": 23, "max_bitrate": 3000000}, "2pass": {"two_pass": true}}"#), DEFAULT_PRESET).unwrap();
+    assert_eq!(presets.resolve(Some("stream")).max_bitrate, Some(3000000));
+    assert!(presets.resolve(Some("2pass")).two_pass);
+
+    for bad in [r#"{"x": {"max_size": 0}}"#, r#"{"x": {"crf": 99}}"#, r#"{"x": {"crf": 20, "two_pass": true}}"#, r#"{"x": {"max_bitrate": 0}}"#, r#"{"x": {"codec": "vp9"}}"#, r#"{"": {}}"#, "[]"] {
+        assert!(TranscodePresets::parse(Some(bad), DEFAULT_PRESET).is_err(), "{bad}");
+    }
+}
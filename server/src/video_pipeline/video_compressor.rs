@@ -1,10 +1,15 @@
-use std::{process::Command, io::BufRead};
-use std::path::{PathBuf};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed}};
 use crossbeam_channel::{Sender, Receiver};
 use tracing;
-use threadpool::ThreadPool;
 
-use super::DetailedMsg;
+use super::{DetailedMsg, fair_queue};
+use super::sandbox::Sandbox;
+use super::poster::PosterScoring;
+use super::transcode_presets::TranscodePreset;
+use super::burn_in::BurnIn;
+use crate::database::models;
 
 pub type ProgressSender = crossbeam_channel::Sender<(String, String, String)>;
 
@@ -18,8 +23,27 @@ pub struct CmprInput {
     pub video_dst: Option<PathBuf>,
     pub thumb_dir: Option<PathBuf>,
     pub video_bitrate: u32,
+    /// Normalize audio to this integrated loudness (LUFS), if set
+    pub loudnorm_target: Option<f32>,
+    /// HDR format of the source (see `models::hdr_format`). HDR is tone-mapped to SDR for video and thumbnails.
+    pub hdr_format: Option<String>,
+    /// Display rotation of the source (clockwise degrees). Transcoded video is physically rotated.
+    pub rotation: u32,
+    /// Convert variable frame rate source to this constant frame rate
+    pub cfr_fps: Option<f32>,
+    /// Duration (seconds) of an audio-only source. Video and thumbnails are rendered from its waveform.
+    pub audio_duration: Option<f32>,
+    /// Copy streams into MP4 instead of re-encoding (see `bypass`). Transcoded if that fails.
+    pub remux: bool,
+    /// Output recipe for transcoding (see `transcode_presets`)
+    pub preset: TranscodePreset,
+    /// Overlays to burn into transcoded video (see `burn_in`)
+    pub burn_in: BurnIn,
     pub video_hash: String,
     pub user_id: String,
+    pub job_id: Option<i32>,
+    /// Trace of the video's processing (see `telemetry`)
+    pub trace_id: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -32,6 +56,212 @@ pub struct CmprOutput {
     pub stderr: String,
     pub dmsg: DetailedMsg,
     pub user_id: String,
+    pub job_id: Option<i32>,
+    pub trace_id: Option<String>,
+    /// Time the worker spent on the job, in seconds (for throughput statistics)
+    pub work_secs: f64,
+    /// Transcode attempts made, in order (empty for thumbnailing)
+    pub attempts: Vec<TranscodeAttempt>,
+    /// How the transcoded video was encoded, as JSON (see `TranscodeSettings::encoding_json`)
+    pub encoding: Option<String>,
+}
+
+/// Encoder settings for one transcode attempt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TranscodeSettings {
+    /// Use libx264 with this preset, instead of the encoder of the transcode preset (and its extra args)
+    pub x264_speed: Option<&'static str>,
+    /// Keep only the first video and audio streams, force 8-bit 4:2:0 and allow a longer muxing queue
+    pub safe: bool,
+    /// Copy audio as is, instead of re-encoding (and normalizing) it
+    pub copy_audio: bool,
+    /// Copy video as is (remux), ignoring encoders and filters
+    pub copy_video: bool,
+}
+
+impl TranscodeSettings {
+    /// Encode in two passes? Only with the preset's own encoder, safer attempts skip it.
+    fn two_pass(&self, p: &TranscodePreset) -> bool {
+        p.two_pass && self.x264_speed.is_none() && !self.copy_video
+    }
+
+    /// Describe the attempt for job history
+    fn describe(&self, p: &TranscodePreset) -> String {
+        if self.copy_video {
+            return "remux (stream copy)".into();
+        }
+        let mut res = match self.x264_speed {
+            None => format!("preset '{}': {}{}", p.name, p.video_codec, p.speed.as_ref().map(|s| format!(" preset={s}")).unwrap_or_default()),
+            Some(speed) => format!("libx264 preset={speed}"),
+        };
+        if let Some(crf) = p.crf { res += &format!(", crf={crf}"); }
+        if let Some(max) = p.max_bitrate { res += &format!(", maxrate={max}"); }
+        if self.two_pass(p) { res += ", 2-pass"; }
+        if self.safe { res += ", first streams only, yuv420p"; }
+        if self.copy_audio { res += ", copy audio"; }
+        res
+    }
+
+    /// Describe how the output is encoded, for storing with the video (`models::Video::proxy_encoding`)
+    pub fn encoding_json(&self, p: &TranscodePreset, video_bitrate: u32) -> serde_json::Value {
+        if self.copy_video {
+            return serde_json::json!({ "rate_control": "copy" });
+        }
+        let rate_control = match (p.crf, p.max_bitrate, self.two_pass(p)) {
+            (Some(_), Some(_), _) => "capped_crf",
+            (Some(_), None, _) => "crf",
+            (None, _, true) => "two_pass",
+            (None, _, false) => "bitrate",
+        };
+        serde_json::json!({
+            "preset": p.name,
+            "video_codec": if self.x264_speed.is_some() { "libx264" } else { &p.video_codec },
+            "rate_control": rate_control,
+            "crf": p.crf,
+            "video_bitrate": p.crf.is_none().then(|| p.capped_bitrate(video_bitrate)),
+            "max_bitrate": p.max_bitrate,
+            "max_size": p.max_size,
+            "audio_codec": if self.copy_audio { "copy" } else { &p.audio_codec },
+            "audio_bitrate": (!self.copy_audio).then_some(p.audio_bitrate),
+        })
+    }
+}
+
+/// Settings to try, in order, until a transcode succeeds. Later ones give up on speed,
+/// extra streams and audio processing to get odd sources through. All use the software encoder.
+pub const TRANSCODE_ATTEMPTS: &[TranscodeSettings] = &[
+    TranscodeSettings { x264_speed: None, safe: false, copy_audio: false, copy_video: false },
+    TranscodeSettings { x264_speed: Some("veryfast"), safe: true, copy_audio: false, copy_video: false },
+    TranscodeSettings { x264_speed: Some("ultrafast"), safe: true, copy_audio: true, copy_video: false },
+];
+
+/// Settings for remuxing a browser-compatible source (see `bypass`). Tried before `TRANSCODE_ATTEMPTS`.
+pub const REMUX: TranscodeSettings = TranscodeSettings { x264_speed: None, safe: true, copy_audio: true, copy_video: true };
+
+/// Keep at most this much (the end) of ffmpeg's stderr per attempt
+const ATTEMPT_STDERR_MAX: usize = 16 * 1024;
+
+/// Result of one transcode attempt, for job history
+#[derive(Debug, Clone)]
+pub struct TranscodeAttempt {
+    pub settings: String,
+    pub success: bool,
+    /// End of ffmpeg's stderr
+    pub stderr: String,
+}
+
+/// Make an ffmpeg filter chain that tone-maps HDR video to SDR (BT.709), or None if source is SDR.
+/// Without this, HDR sources look washed out on regular displays and in browsers.
+/// Requires ffmpeg built with zimg (zscale filter).
+pub fn tonemap_filter(hdr_format: Option<&str>) -> Option<String>
+{
+    let transfer_in = match hdr_format? {
+        models::hdr_format::HLG => "arib-std-b67",
+        _ => "smpte2084",
+    };
+    Some(format!("zscale=tin={transfer_in}:pin=bt2020:min=bt2020nc:t=linear:npl=100,format=gbrpf32le,\
+        zscale=p=bt709,tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv,format=yuv420p"))
+}
+
+/// Make the "-vf" filter chain for transcoding: constant frame rate (if VFR), tone-mapping (if HDR), rotation and scaling
+/// (down to `max_size`). Rotation is applied explicitly (and ffmpeg's autorotate disabled) so that the output has
+/// no rotation metadata left for players to interpret differently.
+fn transcode_video_filter(hdr_format: Option<&str>, rotation: u32, cfr_fps: Option<f32>, max_size: u32) -> String
+{
+    let mut filters = vec![];
+    filters.extend(cfr_fps.map(|fps| format!("fps={fps}")));
+    filters.extend(tonemap_filter(hdr_format));
+    match rotation {
+        90 => filters.push("transpose=clock".to_string()),
+        180 => filters.push("hflip,vflip".to_string()),
+        270 => filters.push("transpose=cclock".to_string()),
+        _ => {},
+    }
+    // Portrait videos are limited by height instead of width
+    filters.push(if rotation == 90 || rotation == 270 { format!("scale=-8:'min({max_size},ih)'") } else { format!("scale='min({max_size},iw)':-8") });
+    filters.join(",")
+}
+
+/// Make a "-filter_complex" chain that renders the first audio track as a waveform video
+fn waveform_filter(width: u32, height: u32, rate: &str) -> String
+{
+    format!("[0:a:0]showwaves=s={width}x{height}:mode=cline:rate={rate}:colors=0x9ecfff,format=yuv420p")
+}
+
+/// Make ffmpeg's stream mapping and codec args for a transcode (everything between the input and the output file).
+/// Video filter is None for audio-only sources, whose video is rendered from the waveform.
+/// `pass` is (pass number, log file prefix) for two-pass encoding. The first pass only analyzes video and
+/// writes to the null muxer (output file "-").
+fn transcode_output_args(s: &TranscodeSettings, p: &TranscodePreset, video_filter: Option<&str>, video_bitrate: u32, loudnorm_target: Option<f32>, rotated: bool, pass: Option<(u32, &str)>) -> Vec<String>
+{
+    let first_pass = matches!(pass, Some((1, _)));
+    let mut res: Vec<String> = vec![];
+    let mut add = |args: &[&str]| res.extend(args.iter().map(|a| a.to_string()));
+    if s.copy_video {
+        add(&["-map", "0:v:0", "-map", "0:a:0?", "-nostats", "-c", "copy", "-movflags", "+faststart"]);
+        return res;
+    }
+    match video_filter {
+        None => add(&["-filter_complex", &(waveform_filter(1280, 720, &super::AUDIO_ONLY_FPS.to_string()) + "[v]"),
+                    "-map", "[v]", "-map", "0:a:0"]),
+        Some(vf) if s.safe => add(&["-vf", vf, "-map", "0:v:0", "-map", "0:a:0?"]),
+        Some(vf) => add(&[
+            "-vf", vf,
+            "-map", "0",  // copy all streams...
+            "-dn", // ...but remove data stream
+        ]),
+    }
+    add(&["-nostats"]);
+    match s.x264_speed {
+        None => {
+            add(&["-vcodec", &p.video_codec]);
+            if let Some(speed) = &p.speed { add(&["-preset", speed]); }
+        },
+        Some(speed) => add(&["-vcodec", "libx264", "-preset", speed]),
+    }
+    if s.safe {
+        add(&["-pix_fmt", "yuv420p", "-max_muxing_queue_size", "4096"]);
+    }
+    match p.crf {
+        Some(crf) => add(&["-crf", &crf.to_string()]),
+        None => add(&["-b:v", &p.capped_bitrate(video_bitrate).to_string()]),
+    }
+    if let Some(max) = p.max_bitrate {
+        add(&["-maxrate", &max.to_string(), "-bufsize", &(2 * max as u64).to_string()]);
+    }
+    if let Some((n, passlog)) = pass {
+        add(&["-pass", &n.to_string(), "-passlogfile", passlog]);
+    }
+    if first_pass {
+        add(&["-an", "-sn"]);
+    } else if s.copy_audio {
+        add(&["-acodec", "copy"]);
+    } else {
+        add(&["-acodec", &p.audio_codec, "-ac", &p.audio_channels.to_string(), "-strict", "experimental", "-b:a", &p.audio_bitrate.to_string()]);
+        if let Some(target) = loudnorm_target {
+            add(&["-af", &format!("loudnorm=I={target}:TP=-1.5:LRA=11")]);
+        }
+    }
+    if rotated {
+        add(&["-metadata:s:v:0", "rotate=0"]);
+    }
+    if s.x264_speed.is_none() {
+        res.extend(p.extra_args.iter().cloned());
+    }
+    if first_pass {
+        res.extend(["-f".to_string(), "null".to_string()]);
+    }
+    res
+}
+
+/// Keep the end of a (long) text, cut at a char boundary
+fn tail(s: &str, max_len: usize) -> &str
+{
+    let mut start = s.len().saturating_sub(max_len);
+    while !s.is_char_boundary(start) {
+        start += 1;
+    }
+    &s[start..]
 }
 
 fn err2cout<E: std::fmt::Debug>(msg_txt: &str, err: E, args: &CmprInput) -> CmprOutput {
@@ -51,7 +281,12 @@ fn err2cout<E: std::fmt::Debug>(msg_txt: &str, err: E, args: &CmprInput) -> Cmpr
             src_file: args.src.clone(),
             user_id: args.user_id.clone()
         },
-        user_id: args.user_id.clone()
+        user_id: args.user_id.clone(),
+        job_id: args.job_id,
+        trace_id: args.trace_id.clone(),
+        work_secs: 0.0,
+        attempts: vec![],
+        encoding: None,
     }
 }
 
@@ -61,8 +296,10 @@ fn err2cout<E: std::fmt::Debug>(msg_txt: &str, err: E, args: &CmprInput) -> Cmpr
 /// # Arguments
 /// * `args` - what to compress and where to put the result
 /// * `progress` - channel to send progress updates to
+/// * `sandbox` - sandbox to run ffmpeg in
+/// * `settings` - encoder settings to use
 ///
-fn run_ffmpeg_transcode( args: CmprInput, progress: ProgressSender ) -> CmprOutput
+fn run_ffmpeg_transcode( args: CmprInput, progress: ProgressSender, sandbox: Sandbox, settings: TranscodeSettings ) -> CmprOutput
 {
     let _span = tracing::info_span!("run_ffmpeg_transcode",
         video = %args.video_hash,
@@ -74,7 +311,7 @@ fn run_ffmpeg_transcode( args: CmprInput, progress: ProgressSender ) -> CmprOutp
         None => return err2cout("BUG: transcode called with no video destination", "", &args)
     };
 
-    tracing::info!(src=%args.src.display(), dst=%video_dst.display(), bitrate=%args.video_bitrate, "Compressing video");
+    tracing::info!(src=%args.src.display(), dst=%video_dst.display(), bitrate=%args.video_bitrate, loudnorm=?args.loudnorm_target, settings=settings.describe(&args.preset), "Compressing video");
 
     // Open a named pipe for ffmpeg to write progress reports to.
     // If this fails, ignore it and just don't show progress.
@@ -82,41 +319,71 @@ fn run_ffmpeg_transcode( args: CmprInput, progress: ProgressSender ) -> CmprOutp
         let fname = video_dst.with_extension("progress").with_extension("pipe");
         match fname.to_str() {
             None => { Err("Invalid dst path".to_string()) }
-            Some(fname) => unix_named_pipe::create(&fname, None)
+            Some(fname) => unix_named_pipe::create(fname, None)
                 .map(|_| fname.to_string())
                 .map_err(|e| e.to_string())
-        }.map_or_else(|e| { tracing::warn!(details=e, "Won't track FFMPEG progress; failed to create pipe file."); None}, |f| Some(f))
+        }.map_or_else(|e| { tracing::warn!(details=e, "Won't track FFMPEG progress; failed to create pipe file."); None}, Some)
     };
 
+    let video_filter = transcode_video_filter(args.hdr_format.as_deref(), args.rotation, args.cfr_fps, args.preset.max_size);
+    let video_filter = args.audio_duration.is_none().then(|| args.burn_in.apply(&video_filter));
+    let rotated = args.rotation != 0;
+    let out_dir = video_dst.parent().unwrap_or(Path::new("/")).to_path_buf();
+
+    // Two-pass encoding: run the analysis pass first (without progress tracking), then encode below
+    let passlog = settings.two_pass(&args.preset).then(|| video_dst.with_extension("passlog").to_string_lossy().to_string());
+    if let Some(passlog) = &passlog {
+        progress.send((args.video_hash.clone(), args.user_id.clone(), "Transcoding, analysis pass...".into())).ok();
+        let mut cmd = sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[&out_dir]);
+        cmd.arg("-y");
+        if rotated {
+            cmd.arg("-noautorotate");
+        }
+        cmd.arg("-i").arg(&args.src)
+            .args(transcode_output_args(&settings, &args.preset, video_filter.as_deref(), args.video_bitrate, args.loudnorm_target, rotated, Some((1, passlog))))
+            .arg("-");
+        tracing::info!("Calling ffmpeg for analysis pass");
+        tracing::debug!("Exec: {:?}", cmd);
+        let failure = match cmd.output() {
+            Ok(res) if res.status.success() => None,
+            Ok(res) => Some(("FFMPEG exited with error in analysis pass".to_string(), String::from_utf8_lossy(&res.stderr).to_string())),
+            Err(e) => Some((e.to_string(), "".into())),
+        };
+        if let Some((details, stderr)) = failure {
+            remove_passlogs(passlog);
+            let mut res = err2cout("Transcoding failed", details, &args);
+            res.stderr = stderr;
+            return res;
+        }
+    }
+
     // Start encoder thread
     let ffmpeg_thread = {
         let src = args.src.clone();
         let dst = video_dst.clone();
         let ppipe_fname = ppipe_fname.clone();
+        let loudnorm_target = args.loudnorm_target;
+        let video_filter = video_filter.clone();
+        let preset = args.preset.clone();
+        let video_bitrate = args.video_bitrate;
+        let passlog = passlog.clone();
         std::thread::spawn(move || {
             let _span = tracing::info_span!("ffmpeg_transcode_thread",
                 thread = ?std::thread::current().id()).entered();
 
-            let mut cmd = &mut Command::new("nice");
-            cmd = cmd.arg("-n").arg("10").arg("--")
-                .arg("ffmpeg").arg("-y").arg("-i").arg(&src);
+            let mut cmd = &mut sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[&out_dir]);
+            cmd = cmd.arg("-y");
+            if rotated {
+                cmd = cmd.arg("-noautorotate");
+            }
+            cmd = cmd.arg("-i").arg(&src);
 
             if let Some(pfn) = ppipe_fname {
-                cmd = cmd.args(&["-progress", &pfn]);
+                cmd = cmd.args(["-progress", &pfn]);
             }
-            cmd = cmd.args(&[
-                "-nostats",
-                "-vcodec", "libx264",
-                "-vf", &format!("scale={}:{}", 1920, -8),
-                "-map", "0",  // copy all streams...
-                "-dn", // ...but remove data stream
-                "-preset", "faster",
-                "-acodec", "aac",
-                "-ac", "2",
-                "-strict", "experimental",
-                "-b:v", &format!("{}", args.video_bitrate),
-                "-b:a", &format!("{}", 128000),
-            ]).arg(&dst);
+            cmd = cmd.args(transcode_output_args(&settings, &preset, video_filter.as_deref(),
+                video_bitrate, loudnorm_target, rotated, passlog.as_deref().map(|pl| (2, pl))));
+            cmd = cmd.arg(&dst);
 
             tracing::info!("Calling ffmpeg");
             tracing::debug!("Exec: {:?}", cmd);
@@ -150,7 +417,7 @@ fn run_ffmpeg_transcode( args: CmprInput, progress: ProgressSender ) -> CmprOutp
                     let _span = tracing::info_span!("progress_thread",
                         thread = ?std::thread::current().id()).entered();
 
-                    let total_frames = count_frames(&src);
+                    let total_frames = count_frames(&src, &sandbox);
 
                     let f = match unix_named_pipe::open_read(&pfn) {
                         Ok(f) => f,
@@ -240,14 +507,17 @@ fn run_ffmpeg_transcode( args: CmprInput, progress: ProgressSender ) -> CmprOutp
         tracing::warn!(details=?e, "FFMPEG progress reporter thread panicked (ignoring).");
     }
     tracing::debug!("FFMPEG progress thread joined.");
+    if let Some(passlog) = &passlog {
+        remove_passlogs(passlog);
+    }
 
     CmprOutput {
         success: err_msg.is_none(),
         video_dst: Some(video_dst),
         thumb_dir: None,
         video_hash: args.video_hash.clone(),
-        stdout: stdout,
-        stderr: stderr,
+        stdout,
+        stderr,
         dmsg: DetailedMsg {
             msg: if err_msg.is_some() { "Transcoding failed" } else { "Transcoding complete" }.to_string(),
             details: format!("Error in FFMPEG: {:?}", err_msg),
@@ -255,6 +525,26 @@ fn run_ffmpeg_transcode( args: CmprInput, progress: ProgressSender ) -> CmprOutp
             user_id: args.user_id.clone()
         },
         user_id: args.user_id.clone(),
+        job_id: args.job_id,
+        trace_id: args.trace_id.clone(),
+        work_secs: 0.0,
+        attempts: vec![],
+        encoding: None,
+    }
+}
+
+/// Remove two-pass log files (ffmpeg adds suffixes like "-0.log" and "-0.log.mbtree" to the prefix)
+fn remove_passlogs(prefix: &str)
+{
+    let prefix = Path::new(prefix);
+    let (Some(dir), Some(name)) = (prefix.parent(), prefix.file_name()) else { return };
+    let name = name.to_string_lossy();
+    for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+        if entry.file_name().to_string_lossy().starts_with(name.as_ref()) {
+            if let Err(e) = std::fs::remove_file(entry.path()) {
+                tracing::warn!(details=%e, file=?entry.path(), "Failed to remove two-pass log file.");
+            }
+        }
     }
 }
 
@@ -264,12 +554,12 @@ fn run_ffmpeg_transcode( args: CmprInput, progress: ProgressSender ) -> CmprOutp
 /// * `file_path` - Path to the file to be analyzed
 /// # Returns
 /// * Number of frames in the video
-fn count_frames( src: &PathBuf ) -> Option<i32>
+fn count_frames( src: &PathBuf, sandbox: &Sandbox ) -> Option<i32>
 {
     // Equiv to: ffprobe -v error -select_streams v:0 -count_packets -show_entries stream=nb_read_packets -of csv=p=0 <INPUT-FILE>
-    let cmd_res = Command::new("ffprobe")
-        .args(&["-v", "error", "-select_streams", "v:0", "-count_packets", "-show_entries", "stream=nb_read_packets", "-of", "csv=p=0"])
-        .arg(&src).output();
+    let cmd_res = sandbox.command(&["ffprobe"], &[])
+        .args(["-v", "error", "-select_streams", "v:0", "-count_packets", "-show_entries", "stream=nb_read_packets", "-of", "csv=p=0"])
+        .arg(src).output();
     match cmd_res {
         Ok(output) => {
             if output.status.success() {
@@ -287,12 +577,14 @@ fn count_frames( src: &PathBuf ) -> Option<i32>
 
 /// Extract exactly THUMB_COUNT frames (THUMB_W x THUMB_H, letterboxed) that cover the whole video
 /// and save them as WEBP files (thumb_NN.webp) in the given directory.
-/// Copy the first frame also as thumb.webp (for fast preview without seeking).
+/// Save a representative frame also as thumb.webp (poster, for fast preview without seeking).
 ///
 /// # Arguments
 /// * `args` - what to process and where to put the result
+/// * `sandbox` - sandbox to run ffmpeg in
+/// * `poster` - how to pick the poster frame (see `poster::PosterScoring`)
 ///
-fn run_ffmpeg_thumbnailer( args: CmprInput ) -> CmprOutput
+fn run_ffmpeg_thumbnailer( args: CmprInput, sandbox: Sandbox, poster: PosterScoring ) -> CmprOutput
 {
     let _span = tracing::info_span!("run_ffmpeg_thumbnailer",
         video = %args.video_hash,
@@ -308,26 +600,48 @@ fn run_ffmpeg_thumbnailer( args: CmprInput ) -> CmprOutput
 
     if !thumb_dir.exists() {
         if let Err(e) = std::fs::create_dir_all(&thumb_dir) {
-            return err2cout("Failed to create thumbnail directory", &e.to_string(), &args);
+            return err2cout("Failed to create thumbnail directory", e.to_string(), &args);
         }
     }
 
-    // Create "poster" thumbnail (probably first frame, but ffmpeg can choose any)
+    if let Some(kind) = super::stills::still_kind_of(&args.src) {
+        return run_still_thumbnailer(args, kind, thumb_dir, sandbox);
+    }
+
+    // Prefix for thumbnail filter chains
+    let tonemap = tonemap_filter(args.hdr_format.as_deref()).map(|f| f + ",").unwrap_or_default();
+    let audio_duration = args.audio_duration;
+
+    // Create "poster" thumbnail (best scoring candidate frame, or ffmpeg's choice from the first frames)
     let single_thumb_thread = {
         let src = args.src.clone();
+        let tonemap = tonemap.clone();
         let thumb_dir = thumb_dir.clone();
         std::thread::spawn(move || {
             let _span = tracing::info_span!("ffmpeg_thumb_poster_thread",
                 thread = ?std::thread::current().id()).entered();
 
-            let img_reshape = format!("scale={THUMB_W}:{THUMB_H}:force_original_aspect_ratio=decrease,pad={THUMB_W}:{THUMB_H}:(ow-iw)/2:(oh-ih)/2");
+            let img_reshape = format!("{tonemap}scale={THUMB_W}:{THUMB_H}:force_original_aspect_ratio=decrease,pad={THUMB_W}:{THUMB_H}:(ow-iw)/2:(oh-ih)/2");
+            let poster_time = match audio_duration {
+                Some(_) => None,
+                None => super::poster::pick_poster_time(&src, &tonemap, &poster, &sandbox),
+            };
 
-            let mut cmd = &mut Command::new("nice");
-            cmd = cmd.arg("-n").arg("10").arg("--")
-                .arg("ffmpeg").arg("-y").arg("-i").arg(&src).args(&[
+            let mut cmd = &mut sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[&thumb_dir]);
+            cmd = cmd.arg("-y");
+            if let Some(t) = poster_time {
+                cmd = cmd.args(["-ss", &format!("{t:.3}")]);
+            }
+            cmd = cmd.arg("-i").arg(&src);
+            cmd = match (audio_duration, poster_time) {
+                // Waveform of the whole file
+                (Some(_), _) => cmd.args(["-filter_complex", &format!("[0:a:0]showwavespic=s={THUMB_W}x{THUMB_H}:colors=0x9ecfff")]),
+                (None, Some(_)) => cmd.args(["-vf", img_reshape.as_str()]),
+                (None, None) => cmd.args(["-vf", format!("thumbnail,{img_reshape}",).as_str()]),
+            };
+            cmd = cmd.args([
                 "-nostats",
                 "-vcodec", "libwebp",
-                "-vf", format!("thumbnail,{img_reshape}",).as_str(),
                 "-frames:v", "1",
                 "-strict", "experimental",
                 "-c:v", "libwebp",
@@ -352,30 +666,38 @@ fn run_ffmpeg_thumbnailer( args: CmprInput ) -> CmprOutput
     // Create thumbnail sheet (preview of the whole video)
     let sheet_thread = {
         let src = args.src.clone();
+        let tonemap = tonemap.clone();
         let thumb_dir = thumb_dir.clone();
         std::thread::spawn(move || {
             let _span = tracing::info_span!("ffmpeg_thumbsheet_thread",
                 thread = ?std::thread::current().id()).entered();
 
-                let img_reshape = format!("scale={THUMB_W}:{THUMB_H}:force_original_aspect_ratio=decrease,pad={THUMB_W}:{THUMB_H}:(ow-iw)/2:(oh-ih)/2");
+                let img_reshape = format!("{tonemap}scale={THUMB_W}:{THUMB_H}:force_original_aspect_ratio=decrease,pad={THUMB_W}:{THUMB_H}:(ow-iw)/2:(oh-ih)/2");
 
-                let total_frames = match count_frames(&src) {
+            let mut cmd = &mut sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[&thumb_dir]);
+            cmd = cmd.arg("-y").arg("-i").arg(&src);
+
+            if let Some(duration) = audio_duration {
+                // Waveform rendered at a rate that gives exactly THUMB_COUNT frames
+                let rate = format!("{THUMB_COUNT}/{}", duration.max(0.1));
+                cmd = cmd.args(["-filter_complex", &format!("{},tile={THUMB_SHEET_COLS}x{THUMB_SHEET_ROWS}", waveform_filter(THUMB_W, THUMB_H, &rate)),
+                    "-frames:v", "1"]);
+            } else {
+                let total_frames = match count_frames(&src, &sandbox) {
                     Some(d) => d,
                     None => return (Some("ffprobe count_frames failed".to_string()), "".into(), "".into())
                 };
 
-            // Make a "-vf" filter that selects exactly THUMB_COUNT frames from the video
-            let frame_select_filter = (0..THUMB_COUNT).map(|pos| {
-                    let frame = (pos as f64 * (total_frames as f64 / (THUMB_COUNT as f64))) as i32;
-                    format!("eq(n\\,{})", frame)
-                }).collect::<Vec<String>>().join("+");
-
+                // Make a "-vf" filter that selects exactly THUMB_COUNT frames from the video
+                let frame_select_filter = (0..THUMB_COUNT).map(|pos| {
+                        let frame = (pos as f64 * (total_frames as f64 / (THUMB_COUNT as f64))) as i32;
+                        format!("eq(n\\,{})", frame)
+                    }).collect::<Vec<String>>().join("+");
 
-            let mut cmd = &mut Command::new("nice");
-            cmd = cmd.arg("-n").arg("10").arg("--")
-                .arg("ffmpeg").arg("-y").arg("-i").arg(&src).args(&[
+                cmd = cmd.args(["-vf", &format!("select={frame_select_filter},{img_reshape},tile={THUMB_SHEET_COLS}x{THUMB_SHEET_ROWS}")]);
+            }
+            cmd = cmd.args([
                 "-nostats",
-                "-vf", &format!("select={frame_select_filter},{img_reshape},tile={THUMB_SHEET_COLS}x{THUMB_SHEET_ROWS}"),
                 "-strict", "experimental",
                 "-c:v", "libwebp",
                 "-vsync", "vfr",
@@ -436,54 +758,261 @@ fn run_ffmpeg_thumbnailer( args: CmprInput ) -> CmprOutput
             src_file: args.src.clone(),
             user_id: args.user_id.clone()
         },
-        user_id: args.user_id.clone()
+        user_id: args.user_id.clone(),
+        job_id: args.job_id,
+        trace_id: args.trace_id.clone(),
+        work_secs: 0.0,
+        attempts: vec![],
+        encoding: None,
+    }
+}
+
+
+/// Thumbnailer for stills (images, PDFs): render reviewable pages into `<video dir>/pages`,
+/// then make poster from the first page and thumbnail sheet from (up to THUMB_COUNT) pages.
+fn run_still_thumbnailer( args: CmprInput, kind: &str, thumb_dir: PathBuf, sandbox: Sandbox ) -> CmprOutput
+{
+    let pages_dir = super::stills::pages_dir(thumb_dir.parent().unwrap_or(&thumb_dir));
+    let n_pages = match super::stills::render_pages(&args.src, kind, &pages_dir, &sandbox) {
+        Ok(n) => n,
+        Err(e) => return err2cout("Page rendering failed", e, &args),
+    };
+    let img_reshape = format!("scale={THUMB_W}:{THUMB_H}:force_original_aspect_ratio=decrease,pad={THUMB_W}:{THUMB_H}:(ow-iw)/2:(oh-ih)/2");
+    let page_pattern = pages_dir.join("page-%d.png");
+
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    let mut err = None;
+    for (name, input, filter, dst) in [
+            ("poster", pages_dir.join(super::stills::page_filename(1)), img_reshape.clone(), thumb_dir.join("thumb.webp")),
+            ("sheet", page_pattern, format!("{img_reshape},tile={THUMB_SHEET_COLS}x{THUMB_SHEET_ROWS}"),
+                thumb_dir.join(format!("sheet-{THUMB_SHEET_COLS}x{THUMB_SHEET_ROWS}.webp"))) ] {
+        let mut cmd = sandbox.command(&["nice", "-n", "10", "--", "ffmpeg"], &[&thumb_dir]);
+        cmd.arg("-y").args(["-start_number", "1"]).arg("-i").arg(&input)
+            .args(["-vf", &filter, "-frames:v", "1", "-nostats", "-c:v", "libwebp"]).arg(&dst);
+        tracing::info!(pages=n_pages, "Creating still {name} thumbnail");
+        tracing::debug!("Exec: {:?}", cmd);
+        match cmd.output() {
+            Ok(res) => {
+                if !res.status.success() { err = Some(format!("FFMPEG exited with error ({name})")); }
+                stdout.push_str(&format!("--- {name} ---\n{}\n\n", String::from_utf8_lossy(&res.stdout)));
+                stderr.push_str(&format!("--- {name} ---\n{}\n\n", String::from_utf8_lossy(&res.stderr)));
+            },
+            Err(e) => { err = Some(e.to_string()); }
+        }
+    }
+    CmprOutput {
+        success: err.is_none(),
+        video_dst: None,
+        thumb_dir: Some(thumb_dir),
+        video_hash: args.video_hash.clone(),
+        stdout,
+        stderr,
+        dmsg: DetailedMsg {
+            msg: if err.is_some() { "Thumbnailing failed" } else { "Thumbnailing complete" }.to_string(),
+            details: format!("Error in FFMPEG: {:?}", err),
+            src_file: args.src.clone(),
+            user_id: args.user_id.clone()
+        },
+        user_id: args.user_id.clone(),
+        job_id: args.job_id,
+        trace_id: args.trace_id.clone(),
+        work_secs: 0.0,
+        attempts: vec![],
+        encoding: None,
+    }
+}
+
+
+/// Transcode with each of `TRANSCODE_ATTEMPTS` in turn (after `REMUX`, if requested), until one succeeds.
+/// Running out of disk space isn't retried, as safer settings won't help with that.
+fn transcode_with_retries( args: CmprInput, progress: ProgressSender, sandbox: Sandbox ) -> CmprOutput
+{
+    let ladder = args.remux.then_some(REMUX).into_iter().chain(TRANSCODE_ATTEMPTS.iter().copied()).collect::<Vec<_>>();
+    let mut attempts = vec![];
+    let mut n = 0;
+    loop {
+        let settings = ladder[n];
+        n += 1;
+        let mut res = run_ffmpeg_transcode(args.clone(), progress.clone(), sandbox, settings);
+        attempts.push(TranscodeAttempt {
+            settings: settings.describe(&args.preset),
+            success: res.success,
+            stderr: tail(&res.stderr, ATTEMPT_STDERR_MAX).to_string(),
+        });
+        let out_of_space = crate::storage::StorageError::from_message(&res.stderr).is_some();
+        if res.success || out_of_space || n == ladder.len() {
+            if !res.success && n > 1 {
+                res.dmsg.details = format!("Failed {n} attempts, last with {}. {}", settings.describe(&args.preset), res.dmsg.details);
+            }
+            if res.success {
+                let mut enc = settings.encoding_json(&args.preset, args.video_bitrate);
+                if !args.burn_in.is_empty() && !settings.copy_video {
+                    enc["burn_in"] = args.burn_in.flags().to_string().into();
+                }
+                res.encoding = Some(enc.to_string());
+            }
+            res.attempts = attempts;
+            return res;
+        }
+        tracing::warn!(attempt=n, settings=settings.describe(&args.preset), "Transcoding failed. Retrying with safer settings.");
+        let msg = match settings.copy_video {
+            true => "Remuxing failed. Transcoding instead...".to_string(),
+            false => format!("Transcoding failed. Retrying with safer settings ({}/{})...", n + 1, ladder.len()),
+        };
+        progress.send((args.video_hash.clone(), args.user_id.clone(), msg)).ok();
     }
 }
 
 
 /// Listen to incoming transcoding/thumbnailing requests and spawn a thread (from a pool) to handle each one.
 /// Calls FFMpeg CLI to do the actual work, and sends progress updates to the given channel.
+/// Requests are scheduled fairly between users (see `fair_queue::run_fair_pool`).
 ///
 /// # Arguments
 /// * `inq` - Channel to receive incoming requests
 /// * `outq` - Channel to send results
 /// * `progress` - Channel to send transcoding progress updates. Tuple: (video_hash, progress_msg)
 /// * `n_workers` - Number of worker threads to spawn for processing. This should be at most the number of CPU cores.
-pub fn run_forever(
+/// * `depth` - Number of items waiting, for metrics
+/// * `sandbox` - Sandbox to run ffmpeg in
+/// * `poster` - How to pick poster frames for thumbnails
+/// * `priority_of` - Function that returns current processing priority of a user
+#[allow(clippy::too_many_arguments)]
+pub fn run_forever<P>(
     inq: Receiver<CmprInput>,
     outq: Sender<CmprOutput>,
     progress: ProgressSender,
-    n_workers: usize)
+    n_workers: Arc<AtomicUsize>,
+    depth: Arc<AtomicUsize>,
+    sandbox: Sandbox,
+    poster: PosterScoring,
+    terminate_flag: Arc<AtomicBool>,
+    priority_of: P)
+        where P: Fn(&str) -> i32
 {
     let _span = tracing::info_span!("COMPR").entered();
-    tracing::info!(n_workers = n_workers, "Starting.");
-
-    let pool = ThreadPool::new(n_workers);
-    loop {
-        match inq.recv() {
-            Ok(args) => {
-                tracing::info!("Got message: {:?}", args);
-                let outq = outq.clone();
-                let prgr_sender = progress.clone();
-                pool.execute(move || {
-                    if let Some(_) = args.video_dst {
-                        if let Err(e) = outq.send(
-                            run_ffmpeg_transcode(args.clone(), prgr_sender)) {
-                            tracing::error!("Transcode result send failed! Aborting. -- {:?}", e);
-                    }};
-                    if let Some(_) = args.thumb_dir {
-                        if let Err(e) = outq.send(
-                            run_ffmpeg_thumbnailer(args.clone())) {
-                            tracing::error!("Thumbnail result send failed! Aborting. -- {:?}", e);
-                    }};
-                });
-            },
-            Err(e) => {
-                tracing::info!(details=%e, "Input queue closed.");
-                break;
-            }
-        }
-    }
+    tracing::info!(n_workers = n_workers.load(Relaxed), "Starting.");
+
+    fair_queue::run_fair_pool(inq, &n_workers, &depth, |args| args.user_id.clone(), priority_of, &terminate_flag, move |args| {
+        let _span = tracing::info_span!("compress", video=%args.video_hash, job_id=args.job_id, trace_id=args.trace_id.as_deref()).entered();
+        tracing::info!("Got message: {:?}", args);
+        let timed = |run: &dyn Fn() -> CmprOutput| {
+            let t0 = std::time::Instant::now();
+            let mut res = run();
+            res.work_secs = t0.elapsed().as_secs_f64();
+            res
+        };
+        if args.video_dst.is_some() {
+            if let Err(e) = outq.send(
+                timed(&|| transcode_with_retries(args.clone(), progress.clone(), sandbox))) {
+                tracing::error!("Transcode result send failed! Aborting. -- {:?}", e);
+                return false;
+        }};
+        if args.thumb_dir.is_some() {
+            if let Err(e) = outq.send(
+                timed(&|| run_ffmpeg_thumbnailer(args.clone(), sandbox, poster))) {
+                tracing::error!("Thumbnail result send failed! Aborting. -- {:?}", e);
+                return false;
+        }};
+        true
+    });
 
     tracing::info!("Exiting.");
 }
+
+
+// Unit tests =====================================================================================
+
+#[test]
+fn test_tonemap_filter()
+{
+    assert_eq!(tonemap_filter(None), None);
+    let pq = tonemap_filter(Some(models::hdr_format::PQ)).unwrap();
+    assert!(pq.starts_with("zscale=tin=smpte2084:"));
+    assert!(pq.ends_with("format=yuv420p"));
+    assert!(!pq.contains(' '));
+    assert_eq!(tonemap_filter(Some(models::hdr_format::DOLBY_VISION)), Some(pq));
+    assert!(tonemap_filter(Some(models::hdr_format::HLG)).unwrap().starts_with("zscale=tin=arib-std-b67:"));
+}
+
+#[test]
+fn test_transcode_video_filter()
+{
+    assert_eq!(transcode_video_filter(None, 0, None, 1920), "scale='min(1920,iw)':-8");
+    assert_eq!(transcode_video_filter(None, 90, None, 1920), "transpose=clock,scale=-8:'min(1920,ih)'");
+    assert_eq!(transcode_video_filter(None, 180, None, 1280), "hflip,vflip,scale='min(1280,iw)':-8");
+    assert_eq!(transcode_video_filter(None, 270, None, 1920), "transpose=cclock,scale=-8:'min(1920,ih)'");
+    assert_eq!(transcode_video_filter(None, 0, Some(25.0), 1920), "fps=25,scale='min(1920,iw)':-8");
+    let hdr = transcode_video_filter(Some(models::hdr_format::HLG), 90, None, 1920);
+    assert!(hdr.starts_with("zscale=") && hdr.ends_with("format=yuv420p,transpose=clock,scale=-8:'min(1920,ih)'"));
+}
+
+#[test]
+fn test_waveform_filter()
+{
+    let f = waveform_filter(160, 90, "100/62.5");
+    assert!(f.starts_with("[0:a:0]showwaves=s=160x90:"));
+    assert!(f.contains(":rate=100/62.5:"));
+    assert!(f.ends_with("format=yuv420p"));
+}
+
+#[test]
+fn test_transcode_output_args()
+{
+    let def = TranscodePreset::default();
+    let join = |s: &TranscodeSettings, vf: Option<&str>, loudnorm: Option<f32>| transcode_output_args(s, &def, vf, 2500000, loudnorm, false, None).join(" ");
+    let first = join(&TRANSCODE_ATTEMPTS[0], Some("scale=1920:-8"), Some(-16.0));
+    assert!(first.starts_with("-vf scale=1920:-8 -map 0 -dn "));
+    assert!(first.contains("-vcodec libx264 -preset faster") && first.contains("-b:v 2500000"));
+    assert!(first.contains("-acodec aac -ac 2") && first.contains("-af loudnorm=I=-16:"));
+    assert!(!first.contains("-pix_fmt"));
+
+    let safe = join(&TRANSCODE_ATTEMPTS[1], Some("scale=1920:-8"), Some(-16.0));
+    assert!(safe.contains("-map 0:v:0 -map 0:a:0? ") && !safe.contains("-dn"));
+    assert!(safe.contains("-pix_fmt yuv420p") && safe.contains("-acodec aac"));
+
+    let last = join(TRANSCODE_ATTEMPTS.last().unwrap(), Some("scale=1920:-8"), Some(-16.0));
+    assert!(last.contains("-preset ultrafast") && last.contains("-acodec copy"));
+    assert!(!last.contains("loudnorm") && !last.contains("-b:a"));
+
+    let remux = join(&REMUX, Some("scale=1920:-8"), Some(-16.0));
+    assert_eq!(remux, "-map 0:v:0 -map 0:a:0? -nostats -c copy -movflags +faststart");
+
+    assert!(join(&TRANSCODE_ATTEMPTS[0], None, None).starts_with("-filter_complex [0:a:0]showwaves="));
+    assert!(transcode_output_args(&TRANSCODE_ATTEMPTS[0], &def, Some("x"), 1, None, true, None).ends_with(&["-metadata:s:v:0".into(), "rotate=0".into()]));
+
+    // Custom preset is used as is on first attempt, and safer attempts fall back to libx264 without extra args
+    let custom = TranscodePreset { name: "hw".into(), video_codec: "h264_nvenc".into(), speed: None, crf: Some(20),
+        audio_codec: "libopus".into(), audio_bitrate: 96000, extra_args: vec!["-rc".into(), "vbr".into()], ..Default::default() };
+    let args = transcode_output_args(&TRANSCODE_ATTEMPTS[0], &custom, Some("x"), 2500000, None, false, None).join(" ");
+    assert!(args.contains("-vcodec h264_nvenc -crf 20 -acodec libopus") && args.contains("-b:a 96000") && args.ends_with("-rc vbr"));
+    assert!(!args.contains("-preset") && !args.contains("-b:v"));
+    let args = transcode_output_args(&TRANSCODE_ATTEMPTS[1], &custom, Some("x"), 2500000, None, false, None).join(" ");
+    assert!(args.contains("-vcodec libx264 -preset veryfast") && args.contains("-crf 20") && !args.contains("vbr"));
+    assert_eq!(TRANSCODE_ATTEMPTS[0].describe(&custom), "preset 'hw': h264_nvenc, crf=20");
+    assert_eq!(TRANSCODE_ATTEMPTS[2].describe(&def), "libx264 preset=ultrafast, first streams only, yuv420p, copy audio");
+
+    // Two-pass: analysis pass has no audio and no output, and retries encode in one pass
+    let two_pass = TranscodePreset { name: "2pass".into(), two_pass: true, max_bitrate: Some(2000000), ..Default::default() };
+    let pass1 = transcode_output_args(&TRANSCODE_ATTEMPTS[0], &two_pass, Some("x"), 2500000, Some(-16.0), false, Some((1, "/tmp/v.passlog"))).join(" ");
+    assert!(pass1.contains("-b:v 2000000 -maxrate 2000000 -bufsize 4000000 -pass 1 -passlogfile /tmp/v.passlog -an -sn"));
+    assert!(pass1.ends_with("-f null") && !pass1.contains("-acodec") && !pass1.contains("loudnorm"));
+    let pass2 = transcode_output_args(&TRANSCODE_ATTEMPTS[0], &two_pass, Some("x"), 2500000, Some(-16.0), false, Some((2, "/tmp/v.passlog"))).join(" ");
+    assert!(pass2.contains("-pass 2 -passlogfile /tmp/v.passlog -acodec aac") && pass2.contains("loudnorm") && !pass2.contains("null"));
+    assert!(TRANSCODE_ATTEMPTS[0].two_pass(&two_pass) && !TRANSCODE_ATTEMPTS[1].two_pass(&two_pass) && !REMUX.two_pass(&two_pass));
+    assert_eq!(TRANSCODE_ATTEMPTS[0].describe(&two_pass), "preset '2pass': libx264 preset=faster, maxrate=2000000, 2-pass");
+
+    // Encoding is stored with the video
+    let enc = TRANSCODE_ATTEMPTS[0].encoding_json(&two_pass, 2500000);
+    assert_eq!((enc["rate_control"].as_str(), enc["video_bitrate"].as_u64()), (Some("two_pass"), Some(2000000)));
+    let capped = TranscodePreset { crf: Some(23), max_bitrate: Some(3000000), ..Default::default() };
+    let enc = TRANSCODE_ATTEMPTS[2].encoding_json(&capped, 2500000);
+    assert_eq!((enc["rate_control"].as_str(), enc["crf"].as_u64(), enc["video_bitrate"].is_null()), (Some("capped_crf"), Some(23), true));
+    assert_eq!((enc["video_codec"].as_str(), enc["audio_codec"].as_str()), (Some("libx264"), Some("copy")));
+    assert_eq!(REMUX.encoding_json(&capped, 2500000)["rate_control"], "copy");
+
+    assert_eq!(tail("abcdef", 3), "def");
+    assert_eq!(tail("abc", 10), "abc");
+    assert_eq!(tail("aäö", 3), "ö");
+}